@@ -0,0 +1,1616 @@
+//! Implements the band processing.
+
+use crate::celt::mode::{E_BANDS, LOG_N, NB_E_BANDS, SHORT_MDCT_SIZE};
+use crate::celt::quant_bands::E_MEANS;
+use crate::celt::rate::{
+    bits2pulses, bits_cache_max, celt_udiv, get_pulses, pulses2bits, QTHETA_OFFSET,
+    QTHETA_OFFSET_TWOPHASE,
+};
+use crate::celt::vq::{
+    alg_quant, alg_unquant, inner_prod, renormalise_vector, stereo_itheta, EPSILON,
+    SPREAD_AGGRESSIVE, SPREAD_LIGHT, SPREAD_NONE, SPREAD_NORMAL,
+};
+use crate::math::{bitexact_cos, bitexact_log2tan, fast_exp2, frac_mul16, isqrt32};
+use crate::range_coder::{RangeCoder, Tell, BITRES};
+use crate::OpusError;
+
+const ORDERY_TABLE: &[usize; 30] = &[
+    1, 0, 3, 0, 2, 1, 7, 0, 4, 3, 6, 1, 5, 2, 15, 0, 8, 7, 12, 3, 11, 4, 14, 1, 9, 6, 13, 2, 10, 5,
+];
+
+const BIT_INTERLEAVE_TABLE: &[u32; 16] = &[0, 1, 1, 1, 2, 3, 3, 3, 2, 3, 3, 3, 2, 3, 3, 3];
+
+const BIT_DEINTERLEAVE_TABLE: &[u32; 16] = &[
+    0x00, 0x03, 0x0C, 0x0F, 0x30, 0x33, 0x3C, 0x3F, 0xC0, 0xC3, 0xCC, 0xCF, 0xF0, 0xF3, 0xFC, 0xFF,
+];
+
+const EXP2_TABLE8: &[i32; 8] = &[16384, 17866, 19483, 21247, 23170, 25267, 27554, 30048];
+
+/// The size of the largest band.
+const MAX_BAND_SIZE: usize = 176;
+
+/// Decides on a value with hysteresis to avoid flapping between two states.
+///
+/// Returns the index of the first threshold that is larger than `val`, unless
+/// `val` is still within the hysteresis of the previous decision `prev`.
+pub(crate) fn hysteresis_decision(
+    val: f32,
+    thresholds: &[f32],
+    hysteresis: &[f32],
+    prev: usize,
+) -> usize {
+    let n = thresholds.len();
+    let mut i = thresholds.iter().position(|t| val < *t).unwrap_or(n);
+    if i > prev && val < thresholds[prev] + hysteresis[prev] {
+        i = prev;
+    }
+    if i < prev && val > thresholds[prev - 1] - hysteresis[prev - 1] {
+        i = prev;
+    }
+    i
+}
+
+/// Linear congruential generator used for noise filling.
+#[inline(always)]
+pub(crate) fn lcg_rand(seed: u32) -> u32 {
+    seed.wrapping_mul(1664525).wrapping_add(1013904223)
+}
+
+/// Computes the amplitude (sqrt energy) of each band.
+pub(crate) fn compute_band_energies(
+    x: &[f32],
+    band_e: &mut [f32],
+    end: usize,
+    channels: usize,
+    lm: usize,
+) {
+    let n = SHORT_MDCT_SIZE << lm;
+    (0..channels).for_each(|c| {
+        (0..end).for_each(|i| {
+            let band_start = c * n + (usize::from(E_BANDS[i]) << lm);
+            let band_len = usize::from(E_BANDS[i + 1] - E_BANDS[i]) << lm;
+            let x = &x[band_start..];
+            let sum = 1e-27 + inner_prod(x, x, band_len);
+            band_e[i + c * NB_E_BANDS] = sum.sqrt();
+        });
+    });
+}
+
+/// Normalises each band such that the energy is one.
+pub(crate) fn normalise_bands(
+    freq: &[f32],
+    x: &mut [f32],
+    band_e: &[f32],
+    end: usize,
+    channels: usize,
+    m: usize,
+) {
+    let n = m * SHORT_MDCT_SIZE;
+    (0..channels).for_each(|c| {
+        (0..end).for_each(|i| {
+            let g = 1.0 / (1e-27 + band_e[i + c * NB_E_BANDS]);
+            let band_start = c * n + m * usize::from(E_BANDS[i]);
+            let band_end = c * n + m * usize::from(E_BANDS[i + 1]);
+            x[band_start..band_end]
+                .iter_mut()
+                .zip(freq[band_start..band_end].iter())
+                .for_each(|(x, f)| *x = *f * g);
+        });
+    });
+}
+
+/// Converts the normalized band shapes back into the MDCT domain by applying the band energies.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn denormalise_bands(
+    x: &[f32],
+    freq: &mut [f32],
+    band_log_e: &[f32],
+    mut start: usize,
+    mut end: usize,
+    m: usize,
+    downsample: usize,
+    silence: bool,
+) {
+    let n = m * SHORT_MDCT_SIZE;
+    let mut bound = m * usize::from(E_BANDS[end]);
+    if downsample != 1 {
+        bound = usize::min(bound, n / downsample);
+    }
+    if silence {
+        bound = 0;
+        start = 0;
+        end = 0;
+    }
+
+    freq[..m * usize::from(E_BANDS[start])]
+        .iter_mut()
+        .for_each(|x| *x = 0.0);
+
+    (start..end).for_each(|i| {
+        let band_start = m * usize::from(E_BANDS[i]);
+        let band_end = m * usize::from(E_BANDS[i + 1]);
+        let lg = band_log_e[i] + E_MEANS[i];
+        let g = fast_exp2(f32::min(32.0, lg));
+        freq[band_start..band_end]
+            .iter_mut()
+            .zip(x[band_start..band_end].iter())
+            .for_each(|(f, x)| *f = *x * g);
+    });
+
+    debug_assert!(start <= end);
+    freq[bound..n].iter_mut().for_each(|x| *x = 0.0);
+}
+
+/// Prevents energy collapse for transients with multiple short MDCTs.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn anti_collapse(
+    x: &mut [f32],
+    collapse_masks: &[u8],
+    lm: usize,
+    channels: usize,
+    size: usize,
+    start: usize,
+    end: usize,
+    log_e: &[f32],
+    prev1_log_e: &[f32],
+    prev2_log_e: &[f32],
+    pulses: &[i32; NB_E_BANDS],
+    mut seed: u32,
+) {
+    (start..end).for_each(|i| {
+        let n0 = usize::from(E_BANDS[i + 1] - E_BANDS[i]);
+        // Depth in 1/8 bits.
+        debug_assert!(pulses[i] >= 0);
+        let depth = celt_udiv(1 + pulses[i], n0 as i32) >> lm;
+
+        let thresh = 0.5 * fast_exp2(-0.125 * depth as f32);
+        let sqrt_1 = 1.0 / ((n0 << lm) as f32).sqrt();
+
+        (0..channels).for_each(|c| {
+            let mut prev1 = prev1_log_e[c * NB_E_BANDS + i];
+            let mut prev2 = prev2_log_e[c * NB_E_BANDS + i];
+            if channels == 1 {
+                prev1 = f32::max(prev1, prev1_log_e[NB_E_BANDS + i]);
+                prev2 = f32::max(prev2, prev2_log_e[NB_E_BANDS + i]);
+            }
+            let ediff = f32::max(0.0, log_e[c * NB_E_BANDS + i] - f32::min(prev1, prev2));
+
+            // r needs to be multiplied by 2 or 2*sqrt(2) depending on LM because
+            // short blocks don't have the same energy as long.
+            let mut r = 2.0 * fast_exp2(-ediff);
+            if lm == 3 {
+                r *= std::f32::consts::SQRT_2;
+            }
+            r = f32::min(thresh, r);
+            r *= sqrt_1;
+
+            let x = &mut x[c * size + (usize::from(E_BANDS[i]) << lm)..];
+            let mut renormalize = false;
+            (0..1 << lm).for_each(|k| {
+                // Detect collapse.
+                if u32::from(collapse_masks[i * channels + c]) & 1 << k == 0 {
+                    // Fill with noise.
+                    (0..n0).for_each(|j| {
+                        seed = lcg_rand(seed);
+                        x[(j << lm) + k] = if seed & 0x8000 != 0 { r } else { -r };
+                    });
+                    renormalize = true;
+                }
+            });
+
+            // We just added some energy, so we need to renormalise.
+            if renormalize {
+                renormalise_vector(x, n0 << lm, 1.0);
+            }
+        });
+    });
+}
+
+fn stereo_merge(x: &mut [f32], y: &mut [f32], mid: f32, n: usize) {
+    // Compute the norm of X+Y and X-Y as |X|^2 + |Y|^2 +/- sum(xy).
+    let mut xp = inner_prod(y, x, n);
+    let side = inner_prod(y, y, n);
+
+    // Compensating for the mid normalization.
+    xp *= mid;
+    let el = (mid * mid) + side - 2.0 * xp;
+    let er = (mid * mid) + side + 2.0 * xp;
+    if er < 6e-4 || el < 6e-4 {
+        y[..n].copy_from_slice(&x[..n]);
+        return;
+    }
+
+    let lgain = 1.0 / el.sqrt();
+    let rgain = 1.0 / er.sqrt();
+
+    x[..n].iter_mut().zip(y[..n].iter_mut()).for_each(|(x, y)| {
+        // Apply mid scaling (side is already scaled).
+        let l = mid * *x;
+        let r = *y;
+        *x = lgain * (l - r);
+        *y = rgain * (l + r);
+    });
+}
+
+fn deinterleave_hadamard(x: &mut [f32], n0: usize, stride: usize, hadamard: bool) {
+    let n = n0 * stride;
+    let mut tmp = [0_f32; MAX_BAND_SIZE];
+    if hadamard {
+        let ordery = &ORDERY_TABLE[stride - 2..];
+        (0..stride).for_each(|i| {
+            (0..n0).for_each(|j| {
+                tmp[ordery[i] * n0 + j] = x[j * stride + i];
+            });
+        });
+    } else {
+        (0..stride).for_each(|i| {
+            (0..n0).for_each(|j| {
+                tmp[i * n0 + j] = x[j * stride + i];
+            });
+        });
+    }
+    x[..n].copy_from_slice(&tmp[..n]);
+}
+
+fn interleave_hadamard(x: &mut [f32], n0: usize, stride: usize, hadamard: bool) {
+    let n = n0 * stride;
+    let mut tmp = [0_f32; MAX_BAND_SIZE];
+    if hadamard {
+        let ordery = &ORDERY_TABLE[stride - 2..];
+        (0..stride).for_each(|i| {
+            (0..n0).for_each(|j| {
+                tmp[j * stride + i] = x[ordery[i] * n0 + j];
+            });
+        });
+    } else {
+        (0..stride).for_each(|i| {
+            (0..n0).for_each(|j| {
+                tmp[j * stride + i] = x[i * n0 + j];
+            });
+        });
+    }
+    x[..n].copy_from_slice(&tmp[..n]);
+}
+
+/// Applies a Haar wavelet transformation.
+pub(crate) fn haar1(x: &mut [f32], n0: usize, stride: usize) {
+    let n0 = n0 >> 1;
+    (0..stride).for_each(|i| {
+        (0..n0).for_each(|j| {
+            let tmp1 = std::f32::consts::FRAC_1_SQRT_2 * x[stride * 2 * j + i];
+            let tmp2 = std::f32::consts::FRAC_1_SQRT_2 * x[stride * (2 * j + 1) + i];
+            x[stride * 2 * j + i] = tmp1 + tmp2;
+            x[stride * (2 * j + 1) + i] = tmp1 - tmp2;
+        });
+    });
+}
+
+/// Decides how much spreading to apply, based on how peaky the normalized bands are.
+///
+/// Also updates the tapset decision of the pre-filter from the high frequency
+/// bands if `update_hf` is set.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn spreading_decision(
+    x: &[f32],
+    average: &mut i32,
+    last_decision: u32,
+    hf_average: &mut i32,
+    tapset_decision: &mut usize,
+    update_hf: bool,
+    end: usize,
+    channels: usize,
+    m: usize,
+    spread_weight: &[i32; NB_E_BANDS],
+) -> u32 {
+    let n0 = m * SHORT_MDCT_SIZE;
+
+    if m * usize::from(E_BANDS[end] - E_BANDS[end - 1]) <= 8 {
+        return SPREAD_NONE;
+    }
+
+    let mut sum = 0;
+    let mut nb_bands = 0;
+    let mut hf_sum = 0;
+    (0..channels).for_each(|c| {
+        (0..end).for_each(|i| {
+            let n = m * usize::from(E_BANDS[i + 1] - E_BANDS[i]);
+            if n <= 8 {
+                return;
+            }
+            let x = &x[m * usize::from(E_BANDS[i]) + c * n0..];
+
+            // Compute rough CDF of |x[j]|.
+            let mut tcount = [0_i32; 3];
+            x[..n].iter().for_each(|x| {
+                let x2n = (x * x) * n as f32;
+                if x2n < 0.25 {
+                    tcount[0] += 1;
+                }
+                if x2n < 0.0625 {
+                    tcount[1] += 1;
+                }
+                if x2n < 0.015625 {
+                    tcount[2] += 1;
+                }
+            });
+
+            // Only include four last bands (8 kHz and up).
+            if i > NB_E_BANDS - 4 {
+                hf_sum += celt_udiv(32 * (tcount[1] + tcount[0]), n as i32);
+            }
+            let tmp = (2 * tcount[2] >= n as i32) as i32
+                + (2 * tcount[1] >= n as i32) as i32
+                + (2 * tcount[0] >= n as i32) as i32;
+            sum += tmp * spread_weight[i];
+            nb_bands += spread_weight[i];
+        });
+    });
+
+    if update_hf {
+        if hf_sum != 0 {
+            hf_sum = celt_udiv(hf_sum, (channels * (4 + end - NB_E_BANDS)) as i32);
+        }
+        *hf_average = (*hf_average + hf_sum) >> 1;
+        hf_sum = *hf_average;
+        if *tapset_decision == 2 {
+            hf_sum += 4;
+        } else if *tapset_decision == 0 {
+            hf_sum -= 4;
+        }
+        *tapset_decision = if hf_sum > 22 {
+            2
+        } else if hf_sum > 18 {
+            1
+        } else {
+            0
+        };
+    }
+
+    sum = celt_udiv(sum << 8, nb_bands);
+    // Recursive averaging.
+    sum = (sum + *average) >> 1;
+    *average = sum;
+    // Hysteresis.
+    sum = (3 * sum + (((3 - last_decision as i32) << 7) + 64) + 2) >> 2;
+    if sum < 80 {
+        SPREAD_AGGRESSIVE
+    } else if sum < 256 {
+        SPREAD_NORMAL
+    } else if sum < 384 {
+        SPREAD_LIGHT
+    } else {
+        SPREAD_NONE
+    }
+}
+
+fn compute_qn(n: usize, b: i32, offset: i32, pulse_cap: i32, stereo: bool) -> i32 {
+    let mut n2 = 2 * n as i32 - 1;
+    if stereo && n == 2 {
+        n2 -= 1;
+    }
+
+    // The upper limit ensures that in a stereo split with itheta==16384, we'll
+    // always have enough bits left over to code at least one pulse in the
+    // side; otherwise it would collapse, since it doesn't get folded.
+    let mut qb = (b + n2 * offset) / n2;
+    qb = i32::min(b - pulse_cap - (4 << BITRES), qb);
+    qb = i32::min(8 << BITRES, qb);
+
+    if qb < (1 << BITRES >> 1) {
+        1
+    } else {
+        let qn = EXP2_TABLE8[(qb & 0x7) as usize] >> (14 - (qb >> BITRES));
+        (qn + 1) >> 1 << 1
+    }
+}
+
+/// Context that is shared between the band coding functions.
+struct BandCtx<'a, 'b, 'c> {
+    rc: &'a mut RangeCoder<'b, 'c>,
+    band_e: &'a [f32],
+    i: usize,
+    intensity: usize,
+    spread: u32,
+    tf_change: i32,
+    remaining_bits: i32,
+    seed: u32,
+    disable_inv: bool,
+    avoid_split_noise: bool,
+    theta_round: i32,
+}
+
+impl<'a, 'b, 'c> BandCtx<'a, 'b, 'c> {
+    /// Returns true if the bands are encoded.
+    fn encode(&self) -> bool {
+        matches!(self.rc, RangeCoder::Encoder(_))
+    }
+}
+
+/// The result of the split parameter coding.
+struct SplitCtx {
+    inv: bool,
+    imid: i32,
+    iside: i32,
+    delta: i32,
+    itheta: i32,
+    qalloc: i32,
+}
+
+/// Computes the weights of the channels used for the distortion measure in the stereo RDO.
+///
+/// We use the amplitude to weight square distortion, which means that we use the square root
+/// of the value we would have been using if we wanted to minimize the MSE in the
+/// non-normalized domain.
+fn compute_channel_weights(ex: f32, ey: f32) -> [f32; 2] {
+    let min_e = f32::min(ex, ey);
+    // Adjustment to make the weights a bit more conservative.
+    [ex + min_e / 3.0, ey + min_e / 3.0]
+}
+
+/// Mixes the two channels into the first channel with the energy ratio of the band.
+fn intensity_stereo(x: &mut [f32], y: &[f32], band_e: &[f32], i: usize, n: usize) {
+    let left = band_e[i];
+    let right = band_e[i + NB_E_BANDS];
+    let norm = EPSILON + (EPSILON + left * left + right * right).sqrt();
+    let a1 = left / norm;
+    let a2 = right / norm;
+    x[..n].iter_mut().zip(y[..n].iter()).for_each(|(x, y)| {
+        // Side is not encoded, no need to calculate.
+        *x = a1 * *x + a2 * *y;
+    });
+}
+
+/// Converts left / right into mid / side.
+fn stereo_split(x: &mut [f32], y: &mut [f32], n: usize) {
+    x[..n].iter_mut().zip(y[..n].iter_mut()).for_each(|(x, y)| {
+        let l = std::f32::consts::FRAC_1_SQRT_2 * *x;
+        let r = std::f32::consts::FRAC_1_SQRT_2 * *y;
+        *x = l + r;
+        *y = r - l;
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
+fn compute_theta(
+    ctx: &mut BandCtx,
+    x: &mut [f32],
+    y: &mut [f32],
+    n: usize,
+    b: &mut i32,
+    blocks: usize,
+    blocks0: usize,
+    lm: i32,
+    stereo: bool,
+    fill: &mut u32,
+) -> Result<SplitCtx, OpusError> {
+    let mut itheta = 0;
+    let mut inv = false;
+    let i = ctx.i;
+
+    // Decide on the resolution to give to the split parameter theta.
+    let pulse_cap = i32::from(LOG_N[i]) + lm * (1 << BITRES);
+    let offset = (pulse_cap >> 1)
+        - if stereo && n == 2 {
+            QTHETA_OFFSET_TWOPHASE
+        } else {
+            QTHETA_OFFSET
+        };
+    let mut qn = compute_qn(n, *b, offset, pulse_cap, stereo);
+    if stereo && i >= ctx.intensity {
+        qn = 1;
+    }
+
+    if ctx.encode() {
+        // theta is the atan() of the ratio between the (normalized)
+        // side and mid. With just that parameter, we can re-scale both
+        // mid and side because we know that 1) they have unit norm and
+        // 2) they are orthogonal.
+        itheta = stereo_itheta(x, y, stereo, n);
+    }
+
+    let tell = ctx.rc.tell_frac() as i32;
+    if qn != 1 {
+        if ctx.encode() {
+            if !stereo || ctx.theta_round == 0 {
+                itheta = (itheta * qn + 8192) >> 14;
+                if !stereo && ctx.avoid_split_noise && itheta > 0 && itheta < qn {
+                    // Check if the selected value of theta will cause the bit allocation
+                    // to inject noise on one side. If so, make sure the energy of that side
+                    // is zero.
+                    let unquantized = celt_udiv(itheta * 16384, qn);
+                    let imid = i32::from(bitexact_cos(unquantized as i16));
+                    let iside = i32::from(bitexact_cos((16384 - unquantized) as i16));
+                    let delta = i32::from(frac_mul16(
+                        ((n as i32 - 1) << 7) as i16,
+                        bitexact_log2tan(iside, imid) as i16,
+                    ));
+                    if delta > *b {
+                        itheta = qn;
+                    } else if delta < -*b {
+                        itheta = 0;
+                    }
+                }
+            } else {
+                // Bias quantization towards itheta=0 and itheta=16384.
+                let bias = if itheta > 8192 {
+                    32767 / qn
+                } else {
+                    -32767 / qn
+                };
+                let down = i32::min(qn - 1, i32::max(0, (itheta * qn + bias) >> 14));
+                itheta = if ctx.theta_round < 0 { down } else { down + 1 };
+            }
+        }
+
+        // Entropy coding of the angle. We use a uniform pdf for the
+        // time split, a step for stereo, and a triangular one for the rest.
+        if stereo && n > 2 {
+            let p0 = 3;
+            let x0 = qn / 2;
+            let ft = p0 * (x0 + 1) + x0;
+            // Use a probability of p0 up to itheta=8192 and then use 1 after.
+            let x = match ctx.rc {
+                RangeCoder::Encoder(_) => itheta,
+                RangeCoder::Decoder(dec) => {
+                    let fs = dec.decode(ft as u32) as i32;
+                    if fs < (x0 + 1) * p0 {
+                        fs / p0
+                    } else {
+                        x0 + 1 + (fs - (x0 + 1) * p0)
+                    }
+                }
+            };
+            let (fl, fh) = if x <= x0 {
+                (p0 * x, p0 * (x + 1))
+            } else {
+                ((x - 1 - x0) + (x0 + 1) * p0, (x - x0) + (x0 + 1) * p0)
+            };
+            match ctx.rc {
+                RangeCoder::Encoder(enc) => enc.encode(fl as u32, fh as u32, ft as u32)?,
+                RangeCoder::Decoder(dec) => dec.update(fl as u32, fh as u32, ft as u32),
+            }
+            itheta = x;
+        } else if blocks0 > 1 || stereo {
+            // Uniform pdf.
+            match ctx.rc {
+                RangeCoder::Encoder(enc) => enc.encode_uint(itheta as u32, qn as u32 + 1)?,
+                RangeCoder::Decoder(dec) => itheta = dec.decode_uint(qn as u32 + 1) as i32,
+            }
+        } else {
+            // Triangular pdf.
+            let ft = ((qn >> 1) + 1) * ((qn >> 1) + 1);
+            match ctx.rc {
+                RangeCoder::Encoder(enc) => {
+                    let (fl, fs) = if itheta <= (qn >> 1) {
+                        ((itheta * (itheta + 1)) >> 1, itheta + 1)
+                    } else {
+                        (
+                            ft - (((qn + 1 - itheta) * (qn + 2 - itheta)) >> 1),
+                            qn + 1 - itheta,
+                        )
+                    };
+                    enc.encode(fl as u32, (fl + fs) as u32, ft as u32)?;
+                }
+                RangeCoder::Decoder(dec) => {
+                    let fm = dec.decode(ft as u32) as i32;
+                    let (fl, fs) = if fm < (((qn >> 1) * ((qn >> 1) + 1)) >> 1) {
+                        itheta = (isqrt32(8 * fm as u32 + 1) as i32 - 1) >> 1;
+                        ((itheta * (itheta + 1)) >> 1, itheta + 1)
+                    } else {
+                        itheta = (2 * (qn + 1) - isqrt32(8 * (ft - fm - 1) as u32 + 1) as i32) >> 1;
+                        (
+                            ft - (((qn + 1 - itheta) * (qn + 2 - itheta)) >> 1),
+                            qn + 1 - itheta,
+                        )
+                    };
+                    dec.update(fl as u32, (fl + fs) as u32, ft as u32);
+                }
+            }
+        }
+        debug_assert!(itheta >= 0);
+        itheta = celt_udiv(itheta * 16384, qn);
+        if ctx.encode() && stereo {
+            if itheta == 0 {
+                intensity_stereo(x, y, ctx.band_e, i, n);
+            } else {
+                stereo_split(x, y, n);
+            }
+        }
+    } else if stereo {
+        if ctx.encode() {
+            inv = itheta > 8192 && !ctx.disable_inv;
+            if inv {
+                y[..n].iter_mut().for_each(|y| *y = -*y);
+            }
+            intensity_stereo(x, y, ctx.band_e, i, n);
+        }
+        if *b > 2 << BITRES && ctx.remaining_bits > 2 << BITRES {
+            match ctx.rc {
+                RangeCoder::Encoder(enc) => enc.encode_bit_logp(inv as u32, 2)?,
+                RangeCoder::Decoder(dec) => inv = dec.decode_bit_logp(2),
+            }
+        } else {
+            inv = false;
+        }
+        // inv flag override to avoid problems with downmixing.
+        if ctx.disable_inv {
+            inv = false;
+        }
+        itheta = 0;
+    }
+    let qalloc = ctx.rc.tell_frac() as i32 - tell;
+    *b -= qalloc;
+
+    let (imid, iside, delta) = if itheta == 0 {
+        *fill &= (1 << blocks) - 1;
+        (32767, 0, -16384)
+    } else if itheta == 16384 {
+        *fill &= ((1 << blocks) - 1) << blocks;
+        (0, 32767, 16384)
+    } else {
+        let imid = i32::from(bitexact_cos(itheta as i16));
+        let iside = i32::from(bitexact_cos((16384 - itheta) as i16));
+        // This is the mid vs side allocation that minimizes squared error
+        // in that band.
+        let delta = i32::from(frac_mul16(
+            ((n as i32 - 1) << 7) as i16,
+            bitexact_log2tan(iside, imid) as i16,
+        ));
+        (imid, iside, delta)
+    };
+
+    Ok(SplitCtx {
+        inv,
+        imid,
+        iside,
+        delta,
+        itheta,
+        qalloc,
+    })
+}
+
+fn quant_band_n1(
+    ctx: &mut BandCtx,
+    x: &mut [f32],
+    y: Option<&mut [f32]>,
+    lowband_out: Option<&mut [f32]>,
+) -> Result<u32, OpusError> {
+    let mut code_sign = |x: &mut [f32]| -> Result<(), OpusError> {
+        let mut sign = 0;
+        if ctx.remaining_bits >= 1 << BITRES {
+            match ctx.rc {
+                RangeCoder::Encoder(enc) => {
+                    sign = (x[0] < 0.0) as u32;
+                    enc.encode_bits(sign, 1)?;
+                }
+                RangeCoder::Decoder(dec) => sign = dec.decode_bits(1),
+            }
+            ctx.remaining_bits -= 1 << BITRES;
+        }
+        x[0] = if sign != 0 { -1.0 } else { 1.0 };
+        Ok(())
+    };
+
+    code_sign(x)?;
+    if let Some(y) = y {
+        code_sign(y)?;
+    }
+
+    if let Some(lowband_out) = lowband_out {
+        lowband_out[0] = x[0];
+    }
+
+    Ok(1)
+}
+
+/// Codes a mono partition.
+///
+/// It can split the band in two and transmit the energy difference with
+/// the two half-bands. It can be called recursively so bands can end up being
+/// split in 8 parts.
+#[allow(clippy::too_many_arguments)]
+fn quant_partition(
+    ctx: &mut BandCtx,
+    x: &mut [f32],
+    mut n: usize,
+    mut b: i32,
+    mut blocks: usize,
+    lowband: Option<&[f32]>,
+    mut lm: i32,
+    gain: f32,
+    mut fill: u32,
+) -> Result<u32, OpusError> {
+    let blocks0 = blocks;
+    let i = ctx.i;
+
+    // If we need 1.5 more bit than we can produce, split the band in two.
+    if lm != -1 && b > bits_cache_max(i, lm) + 12 && n > 2 {
+        n >>= 1;
+        let (x, y) = x.split_at_mut(n);
+        lm -= 1;
+        if blocks == 1 {
+            fill = (fill & 1) | (fill << 1);
+        }
+        blocks = (blocks + 1) >> 1;
+
+        let sctx = compute_theta(ctx, x, y, n, &mut b, blocks, blocks0, lm, false, &mut fill)?;
+        let itheta = sctx.itheta;
+        let mut delta = sctx.delta;
+        let mid = (1.0 / 32768.0) * sctx.imid as f32;
+        let side = (1.0 / 32768.0) * sctx.iside as f32;
+
+        // Give more bits to low-energy MDCTs than they would otherwise deserve.
+        if blocks0 > 1 && (itheta & 0x3fff) != 0 {
+            if itheta > 8192 {
+                // Rough approximation for pre-echo masking.
+                delta -= delta >> (4 - lm);
+            } else {
+                // Corresponds to a forward-masking slope of 1.5 dB per 10 ms.
+                delta = i32::min(0, delta + ((n as i32) << BITRES >> (5 - lm)));
+            }
+        }
+        let mut mbits = i32::max(0, i32::min(b, (b - delta) / 2));
+        let mut sbits = b - mbits;
+        ctx.remaining_bits -= sctx.qalloc;
+
+        let next_lowband2 = lowband.map(|lowband| &lowband[n..]);
+
+        let mut rebalance = ctx.remaining_bits;
+        if mbits >= sbits {
+            let mut cm = quant_partition(ctx, x, n, mbits, blocks, lowband, lm, gain * mid, fill)?;
+            rebalance = mbits - (rebalance - ctx.remaining_bits);
+            if rebalance > 3 << BITRES && itheta != 0 {
+                sbits += rebalance - (3 << BITRES);
+            }
+            cm |= quant_partition(
+                ctx,
+                y,
+                n,
+                sbits,
+                blocks,
+                next_lowband2,
+                lm,
+                gain * side,
+                fill >> blocks,
+            )? << (blocks0 >> 1);
+            Ok(cm)
+        } else {
+            let mut cm = quant_partition(
+                ctx,
+                y,
+                n,
+                sbits,
+                blocks,
+                next_lowband2,
+                lm,
+                gain * side,
+                fill >> blocks,
+            )? << (blocks0 >> 1);
+            rebalance = sbits - (rebalance - ctx.remaining_bits);
+            if rebalance > 3 << BITRES && itheta != 16384 {
+                mbits += rebalance - (3 << BITRES);
+            }
+            cm |= quant_partition(ctx, x, n, mbits, blocks, lowband, lm, gain * mid, fill)?;
+            Ok(cm)
+        }
+    } else {
+        // This is the basic no-split case.
+        let mut q = bits2pulses(i, lm, b);
+        let mut curr_bits = pulses2bits(i, lm, q);
+        ctx.remaining_bits -= curr_bits;
+
+        // Ensures we can never bust the budget.
+        while ctx.remaining_bits < 0 && q > 0 {
+            ctx.remaining_bits += curr_bits;
+            q -= 1;
+            curr_bits = pulses2bits(i, lm, q);
+            ctx.remaining_bits -= curr_bits;
+        }
+
+        if q != 0 {
+            let k = get_pulses(q) as u32;
+            // Finally do the actual quantization.
+            match ctx.rc {
+                RangeCoder::Encoder(enc) => alg_quant(x, n, k, ctx.spread, blocks, enc, gain),
+                RangeCoder::Decoder(dec) => Ok(alg_unquant(x, n, k, ctx.spread, blocks, dec, gain)),
+            }
+        } else {
+            // If there's no pulse, fill the band anyway.
+            let cm_mask = ((1_u64 << blocks) - 1) as u32;
+            fill &= cm_mask;
+            if fill == 0 {
+                x[..n].iter_mut().for_each(|x| *x = 0.0);
+                Ok(0)
+            } else {
+                let cm = if let Some(lowband) = lowband {
+                    // Folded spectrum.
+                    x[..n]
+                        .iter_mut()
+                        .zip(lowband.iter())
+                        .for_each(|(x, lowband)| {
+                            ctx.seed = lcg_rand(ctx.seed);
+                            // About 48 dB below the "normal" folding level.
+                            let tmp = if ctx.seed & 0x8000 != 0 {
+                                1.0 / 256.0
+                            } else {
+                                -1.0 / 256.0
+                            };
+                            *x = *lowband + tmp;
+                        });
+                    fill
+                } else {
+                    // Noise.
+                    x[..n].iter_mut().for_each(|x| {
+                        ctx.seed = lcg_rand(ctx.seed);
+                        *x = ((ctx.seed as i32) >> 20) as f32;
+                    });
+                    cm_mask
+                };
+                renormalise_vector(x, n, gain);
+                Ok(cm)
+            }
+        }
+    }
+}
+
+/// Codes a band for the mono case.
+#[allow(clippy::too_many_arguments)]
+fn quant_band(
+    ctx: &mut BandCtx,
+    x: &mut [f32],
+    n: usize,
+    b: i32,
+    mut blocks: usize,
+    mut lowband: Option<&mut [f32]>,
+    lm: i32,
+    lowband_out: Option<&mut [f32]>,
+    gain: f32,
+    mut fill: u32,
+) -> Result<u32, OpusError> {
+    let n0 = n;
+    let mut n_b = n / blocks;
+    let long_blocks = blocks == 1;
+    let mut time_divide = 0;
+    let mut recombine = 0;
+    let mut tf_change = ctx.tf_change;
+    let encode = ctx.encode();
+
+    // Special case for one sample.
+    if n == 1 {
+        return quant_band_n1(ctx, x, None, lowband_out);
+    }
+
+    if tf_change > 0 {
+        recombine = tf_change;
+    }
+
+    // Band recombining to increase frequency resolution.
+    (0..recombine).for_each(|k| {
+        if encode {
+            haar1(x, n >> k, 1 << k);
+        }
+        if let Some(lowband) = lowband.as_deref_mut() {
+            haar1(lowband, n >> k, 1 << k);
+        }
+        fill = BIT_INTERLEAVE_TABLE[(fill & 0xF) as usize]
+            | BIT_INTERLEAVE_TABLE[(fill >> 4) as usize] << 2;
+    });
+    blocks >>= recombine;
+    n_b <<= recombine;
+
+    // Increasing the time resolution.
+    while (n_b & 1) == 0 && tf_change < 0 {
+        if encode {
+            haar1(x, n_b, blocks);
+        }
+        if let Some(lowband) = lowband.as_deref_mut() {
+            haar1(lowband, n_b, blocks);
+        }
+        fill |= fill << blocks;
+        blocks <<= 1;
+        n_b >>= 1;
+        time_divide += 1;
+        tf_change += 1;
+    }
+    let blocks0 = blocks;
+    let n_b0 = n_b;
+
+    // Reorganize the samples in time order instead of frequency order.
+    if blocks0 > 1 {
+        if encode {
+            deinterleave_hadamard(x, n_b >> recombine, blocks0 << recombine, long_blocks);
+        }
+        if let Some(lowband) = lowband.as_deref_mut() {
+            deinterleave_hadamard(lowband, n_b >> recombine, blocks0 << recombine, long_blocks);
+        }
+    }
+
+    let mut cm = quant_partition(ctx, x, n, b, blocks, lowband.as_deref(), lm, gain, fill)?;
+
+    // Undo the sample reorganization going from time order to frequency order.
+    if blocks0 > 1 {
+        interleave_hadamard(x, n_b >> recombine, blocks0 << recombine, long_blocks);
+    }
+
+    // Undo time-freq changes that we did earlier.
+    n_b = n_b0;
+    blocks = blocks0;
+    (0..time_divide).for_each(|_| {
+        blocks >>= 1;
+        n_b <<= 1;
+        cm |= cm >> blocks;
+        haar1(x, n_b, blocks);
+    });
+
+    (0..recombine).for_each(|k| {
+        cm = BIT_DEINTERLEAVE_TABLE[cm as usize];
+        haar1(x, n0 >> k, 1 << k);
+    });
+    blocks <<= recombine;
+
+    // Scale output for later folding.
+    if let Some(lowband_out) = lowband_out {
+        let n = (n0 as f32).sqrt();
+        lowband_out[..n0]
+            .iter_mut()
+            .zip(x.iter())
+            .for_each(|(out, x)| *out = n * *x);
+    }
+
+    Ok(cm & ((1 << blocks) - 1))
+}
+
+/// Codes a band for the stereo case.
+#[allow(clippy::too_many_arguments)]
+fn quant_band_stereo(
+    ctx: &mut BandCtx,
+    x: &mut [f32],
+    y: &mut [f32],
+    n: usize,
+    mut b: i32,
+    blocks: usize,
+    lowband: Option<&mut [f32]>,
+    lm: i32,
+    lowband_out: Option<&mut [f32]>,
+    mut fill: u32,
+) -> Result<u32, OpusError> {
+    // Special case for one sample.
+    if n == 1 {
+        return quant_band_n1(ctx, x, Some(y), lowband_out);
+    }
+
+    let orig_fill = fill;
+
+    let sctx = compute_theta(ctx, x, y, n, &mut b, blocks, blocks, lm, true, &mut fill)?;
+    let itheta = sctx.itheta;
+    let mid = (1.0 / 32768.0) * sctx.imid as f32;
+    let side = (1.0 / 32768.0) * sctx.iside as f32;
+
+    let cm = if n == 2 {
+        // This is a special case for N=2 that only works for stereo and takes
+        // advantage of the fact that mid and side are orthogonal to encode
+        // the side with just one bit.
+        let mut mbits = b;
+        let mut sbits = 0;
+        // Only need one bit for the side.
+        if itheta != 0 && itheta != 16384 {
+            sbits = 1 << BITRES;
+        }
+        mbits -= sbits;
+        let c = itheta > 8192;
+        ctx.remaining_bits -= sctx.qalloc + sbits;
+
+        let (x2, y2) = if c {
+            (&mut *y, &mut *x)
+        } else {
+            (&mut *x, &mut *y)
+        };
+
+        let mut sign = 0;
+        if sbits != 0 {
+            match ctx.rc {
+                RangeCoder::Encoder(enc) => {
+                    // Here we only need to encode a sign for the side.
+                    sign = (x2[0] * y2[1] - x2[1] * y2[0] < 0.0) as i32;
+                    enc.encode_bits(sign as u32, 1)?;
+                }
+                RangeCoder::Decoder(dec) => sign = dec.decode_bits(1) as i32,
+            }
+        }
+        let sign = (1 - 2 * sign) as f32;
+
+        // We use orig_fill here because we want to fold the side, but if
+        // itheta==16384, we'll have cleared the low bits of fill.
+        let cm = quant_band(
+            ctx,
+            x2,
+            n,
+            mbits,
+            blocks,
+            lowband,
+            lm,
+            lowband_out,
+            1.0,
+            orig_fill,
+        )?;
+
+        // We don't split N=2 bands, so cm is either 1 or 0 (for a fold-collapse),
+        // and there's no need to worry about mixing with the other channel.
+        y2[0] = -sign * x2[1];
+        y2[1] = sign * x2[0];
+
+        x[0] *= mid;
+        x[1] *= mid;
+        y[0] *= side;
+        y[1] *= side;
+        let tmp = x[0];
+        x[0] = tmp - y[0];
+        y[0] += tmp;
+        let tmp = x[1];
+        x[1] = tmp - y[1];
+        y[1] += tmp;
+
+        cm
+    } else {
+        // "Normal" split code.
+        let mut mbits = i32::max(0, i32::min(b, (b - sctx.delta) / 2));
+        let mut sbits = b - mbits;
+        ctx.remaining_bits -= sctx.qalloc;
+
+        let mut rebalance = ctx.remaining_bits;
+        if mbits >= sbits {
+            // In stereo mode, we do not apply a scaling to the mid because we need the normalized
+            // mid for folding later.
+            let mut cm = quant_band(
+                ctx,
+                x,
+                n,
+                mbits,
+                blocks,
+                lowband,
+                lm,
+                lowband_out,
+                1.0,
+                fill,
+            )?;
+            rebalance = mbits - (rebalance - ctx.remaining_bits);
+            if rebalance > 3 << BITRES && itheta != 0 {
+                sbits += rebalance - (3 << BITRES);
+            }
+
+            // For a stereo split, the high bits of fill are always zero, so no
+            // folding will be done to the side.
+            cm |= quant_band(
+                ctx,
+                y,
+                n,
+                sbits,
+                blocks,
+                None,
+                lm,
+                None,
+                side,
+                fill >> blocks,
+            )?;
+            cm
+        } else {
+            // For a stereo split, the high bits of fill are always zero, so no
+            // folding will be done to the side.
+            let mut cm = quant_band(
+                ctx,
+                y,
+                n,
+                sbits,
+                blocks,
+                None,
+                lm,
+                None,
+                side,
+                fill >> blocks,
+            )?;
+            rebalance = sbits - (rebalance - ctx.remaining_bits);
+            if rebalance > 3 << BITRES && itheta != 16384 {
+                mbits += rebalance - (3 << BITRES);
+            }
+
+            // In stereo mode, we do not apply a scaling to the mid because we need the normalized
+            // mid for folding later.
+            cm |= quant_band(
+                ctx,
+                x,
+                n,
+                mbits,
+                blocks,
+                lowband,
+                lm,
+                lowband_out,
+                1.0,
+                fill,
+            )?;
+            cm
+        }
+    };
+
+    if n != 2 {
+        stereo_merge(x, y, mid, n);
+    }
+    if sctx.inv {
+        y[..n].iter_mut().for_each(|y| *y = -*y);
+    }
+
+    Ok(cm)
+}
+
+/// Duplicate enough of the first band folding data to be able to fold the second band.
+/// Copies no data for CELT-only mode.
+fn special_hybrid_folding(
+    norm: &mut [f32],
+    norm2: &mut [f32],
+    start: usize,
+    m: usize,
+    dual_stereo: bool,
+) {
+    let n1 = m * usize::from(E_BANDS[start + 1] - E_BANDS[start]);
+    let n2 = m * usize::from(E_BANDS[start + 2] - E_BANDS[start + 1]);
+    if n2 > n1 {
+        norm.copy_within(2 * n1 - n2..n1, n1);
+        if dual_stereo {
+            norm2.copy_within(2 * n1 - n2..n1, n1);
+        }
+    }
+}
+
+/// Codes the normalized shapes of all bands.
+///
+/// When encoding, `x` and `y` are replaced by their quantized versions and `band_e`
+/// needs to contain the band amplitudes of both channels. The decoder ignores `band_e`
+/// and `complexity`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn quant_all_bands(
+    start: usize,
+    end: usize,
+    x: &mut [f32],
+    mut y: Option<&mut [f32]>,
+    collapse_masks: &mut [u8],
+    band_e: &[f32],
+    pulses: &[i32; NB_E_BANDS],
+    short_blocks: bool,
+    spread: u32,
+    mut dual_stereo: bool,
+    intensity: usize,
+    tf_res: &[i32; NB_E_BANDS],
+    total_bits: i32,
+    mut balance: i32,
+    rc: &mut RangeCoder,
+    lm: usize,
+    coded_bands: usize,
+    seed: &mut u32,
+    complexity: i32,
+    disable_inv: bool,
+) -> Result<(), OpusError> {
+    let m = 1 << lm;
+    let blocks = if short_blocks { m } else { 1 };
+    let channels = if y.is_some() { 2 } else { 1 };
+    let norm_offset = m * usize::from(E_BANDS[start]);
+    let theta_rdo =
+        matches!(rc, RangeCoder::Encoder(_)) && y.is_some() && !dual_stereo && complexity >= 8;
+
+    // No need to allocate norm for the last band because we don't need an
+    // output in that band.
+    let norm_size = m * usize::from(E_BANDS[NB_E_BANDS - 1]) - norm_offset;
+    let mut norm_buffer = vec![0_f32; channels * norm_size];
+    let (norm, norm2) = norm_buffer.split_at_mut(norm_size);
+    let mut lowband_scratch = [0_f32; MAX_BAND_SIZE];
+
+    let mut lowband_offset = 0;
+    let mut update_lowband = true;
+
+    let mut ctx = BandCtx {
+        rc,
+        band_e,
+        i: 0,
+        intensity,
+        spread,
+        tf_change: 0,
+        remaining_bits: 0,
+        seed: *seed,
+        disable_inv,
+        // Avoid injecting noise in the first band on transients.
+        avoid_split_noise: blocks > 1,
+        theta_round: 0,
+    };
+
+    (start..end).try_for_each(|i| {
+        ctx.i = i;
+        let last = i == end - 1;
+
+        let band_start = m * usize::from(E_BANDS[i]);
+        let n = m * usize::from(E_BANDS[i + 1]) - band_start;
+        debug_assert!(n > 0);
+        let tell = ctx.rc.tell_frac() as i32;
+
+        // Compute how many bits we want to allocate to this band.
+        if i != start {
+            balance -= tell;
+        }
+        let remaining_bits = total_bits - tell - 1;
+        ctx.remaining_bits = remaining_bits;
+        let b = if i < coded_bands {
+            let curr_balance = balance / i32::min(3, (coded_bands - i) as i32);
+            i32::max(
+                0,
+                i32::min(
+                    16383,
+                    i32::min(remaining_bits + 1, pulses[i] + curr_balance),
+                ),
+            )
+        } else {
+            0
+        };
+
+        if (band_start as i32 - n as i32 >= norm_offset as i32 || i == start + 1)
+            && (update_lowband || lowband_offset == 0)
+        {
+            lowband_offset = i;
+        }
+        if i == start + 1 {
+            special_hybrid_folding(norm, norm2, start, m, dual_stereo);
+        }
+
+        let tf_change = tf_res[i];
+        ctx.tf_change = tf_change;
+
+        // Get a conservative estimate of the collapse_mask's for the bands we're
+        // going to be folding from.
+        let mut effective_lowband = None;
+        let (mut x_cm, mut y_cm) = if lowband_offset != 0
+            && (spread != SPREAD_AGGRESSIVE || blocks > 1 || tf_change < 0)
+        {
+            // This ensures we never repeat spectral content within one band.
+            let lowband = usize::max(
+                0,
+                (m * usize::from(E_BANDS[lowband_offset])).saturating_sub(norm_offset + n),
+            );
+            effective_lowband = Some(lowband);
+
+            let mut fold_start = lowband_offset;
+            loop {
+                fold_start -= 1;
+                if m * usize::from(E_BANDS[fold_start]) <= lowband + norm_offset {
+                    break;
+                }
+            }
+            let mut fold_end = lowband_offset;
+            while fold_end < i && m * usize::from(E_BANDS[fold_end]) < lowband + norm_offset + n {
+                fold_end += 1;
+            }
+
+            let mut x_cm = 0;
+            let mut y_cm = 0;
+            let mut fold_i = fold_start;
+            loop {
+                x_cm |= u32::from(collapse_masks[fold_i * channels]);
+                y_cm |= u32::from(collapse_masks[fold_i * channels + channels - 1]);
+                fold_i += 1;
+                if fold_i >= fold_end {
+                    break;
+                }
+            }
+            (x_cm, y_cm)
+        } else {
+            // Otherwise, we'll be using the LCG to fold, so all blocks will (almost
+            // always) be non-zero.
+            ((1 << blocks) - 1, (1 << blocks) - 1)
+        };
+
+        if dual_stereo && i == intensity {
+            // Switch off dual stereo to do intensity.
+            dual_stereo = false;
+            norm[..band_start - norm_offset]
+                .iter_mut()
+                .zip(norm2.iter())
+                .for_each(|(norm, norm2)| *norm = 0.5 * (*norm + *norm2));
+        }
+
+        let x = &mut x[band_start..band_start + n];
+        let out_offset = band_start - norm_offset;
+
+        if dual_stereo {
+            let lowband = effective_lowband.map(|lowband| {
+                lowband_scratch[..n].copy_from_slice(&norm[lowband..lowband + n]);
+                &mut lowband_scratch[..n]
+            });
+            let lowband_out = if last {
+                None
+            } else {
+                Some(&mut norm[out_offset..out_offset + n])
+            };
+            x_cm = quant_band(
+                &mut ctx,
+                x,
+                n,
+                b / 2,
+                blocks,
+                lowband,
+                lm as i32,
+                lowband_out,
+                1.0,
+                x_cm,
+            )?;
+
+            let y = y.as_deref_mut().map(|y| &mut y[band_start..band_start + n]);
+            if let Some(y) = y {
+                let lowband = effective_lowband.map(|lowband| {
+                    lowband_scratch[..n].copy_from_slice(&norm2[lowband..lowband + n]);
+                    &mut lowband_scratch[..n]
+                });
+                let lowband_out = if last {
+                    None
+                } else {
+                    Some(&mut norm2[out_offset..out_offset + n])
+                };
+                y_cm = quant_band(
+                    &mut ctx,
+                    y,
+                    n,
+                    b / 2,
+                    blocks,
+                    lowband,
+                    lm as i32,
+                    lowband_out,
+                    1.0,
+                    y_cm,
+                )?;
+            }
+        } else {
+            let y = y.as_deref_mut().map(|y| &mut y[band_start..band_start + n]);
+            x_cm = if let Some(y) = y {
+                if theta_rdo && i < intensity {
+                    let w = compute_channel_weights(band_e[i], band_e[i + NB_E_BANDS]);
+                    let enc_save = match ctx.rc {
+                        RangeCoder::Encoder(enc) => enc.state(),
+                        RangeCoder::Decoder(_) => {
+                            return Err(OpusError::InternalError("theta RDO while decoding"))
+                        }
+                    };
+                    let ctx_save = (ctx.remaining_bits, ctx.seed);
+                    let mut x_save = [0_f32; MAX_BAND_SIZE];
+                    let mut y_save = [0_f32; MAX_BAND_SIZE];
+                    x_save[..n].copy_from_slice(x);
+                    y_save[..n].copy_from_slice(y);
+
+                    // Encode and round down.
+                    ctx.theta_round = -1;
+                    let lowband = effective_lowband.map(|lowband| {
+                        lowband_scratch[..n].copy_from_slice(&norm[lowband..lowband + n]);
+                        &mut lowband_scratch[..n]
+                    });
+                    let lowband_out = if last {
+                        None
+                    } else {
+                        Some(&mut norm[out_offset..out_offset + n])
+                    };
+                    let cm0 = quant_band_stereo(
+                        &mut ctx,
+                        x,
+                        y,
+                        n,
+                        b,
+                        blocks,
+                        lowband,
+                        lm as i32,
+                        lowband_out,
+                        x_cm | y_cm,
+                    )?;
+                    let dist0 = w[0] * inner_prod(&x_save, x, n) + w[1] * inner_prod(&y_save, y, n);
+
+                    // Save the first result.
+                    let enc_save2 = match ctx.rc {
+                        RangeCoder::Encoder(enc) => enc.state(),
+                        RangeCoder::Decoder(_) => unreachable!(),
+                    };
+                    let ctx_save2 = (ctx.remaining_bits, ctx.seed);
+                    let mut x_save2 = [0_f32; MAX_BAND_SIZE];
+                    let mut y_save2 = [0_f32; MAX_BAND_SIZE];
+                    let mut norm_save2 = [0_f32; MAX_BAND_SIZE];
+                    x_save2[..n].copy_from_slice(x);
+                    y_save2[..n].copy_from_slice(y);
+                    if !last {
+                        norm_save2[..n].copy_from_slice(&norm[out_offset..out_offset + n]);
+                    }
+                    let bytes_save = match ctx.rc {
+                        RangeCoder::Encoder(enc) => enc.bytes_since(&enc_save).to_vec(),
+                        RangeCoder::Decoder(_) => unreachable!(),
+                    };
+
+                    // Restore.
+                    if let RangeCoder::Encoder(enc) = ctx.rc {
+                        enc.set_state(&enc_save);
+                    }
+                    ctx.remaining_bits = ctx_save.0;
+                    ctx.seed = ctx_save.1;
+                    x.copy_from_slice(&x_save[..n]);
+                    y.copy_from_slice(&y_save[..n]);
+                    if i == start + 1 {
+                        special_hybrid_folding(norm, norm2, start, m, dual_stereo);
+                    }
+
+                    // Encode and round up.
+                    ctx.theta_round = 1;
+                    let lowband = effective_lowband.map(|lowband| {
+                        lowband_scratch[..n].copy_from_slice(&norm[lowband..lowband + n]);
+                        &mut lowband_scratch[..n]
+                    });
+                    let lowband_out = if last {
+                        None
+                    } else {
+                        Some(&mut norm[out_offset..out_offset + n])
+                    };
+                    let mut cm = quant_band_stereo(
+                        &mut ctx,
+                        x,
+                        y,
+                        n,
+                        b,
+                        blocks,
+                        lowband,
+                        lm as i32,
+                        lowband_out,
+                        x_cm | y_cm,
+                    )?;
+                    let dist1 = w[0] * inner_prod(&x_save, x, n) + w[1] * inner_prod(&y_save, y, n);
+
+                    if dist0 >= dist1 {
+                        cm = cm0;
+                        if let RangeCoder::Encoder(enc) = ctx.rc {
+                            enc.set_state(&enc_save2);
+                            enc.restore_bytes(&enc_save, &bytes_save);
+                        }
+                        ctx.remaining_bits = ctx_save2.0;
+                        ctx.seed = ctx_save2.1;
+                        x.copy_from_slice(&x_save2[..n]);
+                        y.copy_from_slice(&y_save2[..n]);
+                        if !last {
+                            norm[out_offset..out_offset + n].copy_from_slice(&norm_save2[..n]);
+                        }
+                    }
+                    ctx.theta_round = 0;
+                    cm
+                } else {
+                    let lowband = effective_lowband.map(|lowband| {
+                        lowband_scratch[..n].copy_from_slice(&norm[lowband..lowband + n]);
+                        &mut lowband_scratch[..n]
+                    });
+                    let lowband_out = if last {
+                        None
+                    } else {
+                        Some(&mut norm[out_offset..out_offset + n])
+                    };
+                    quant_band_stereo(
+                        &mut ctx,
+                        x,
+                        y,
+                        n,
+                        b,
+                        blocks,
+                        lowband,
+                        lm as i32,
+                        lowband_out,
+                        x_cm | y_cm,
+                    )?
+                }
+            } else {
+                let lowband = effective_lowband.map(|lowband| {
+                    lowband_scratch[..n].copy_from_slice(&norm[lowband..lowband + n]);
+                    &mut lowband_scratch[..n]
+                });
+                let lowband_out = if last {
+                    None
+                } else {
+                    Some(&mut norm[out_offset..out_offset + n])
+                };
+                quant_band(
+                    &mut ctx,
+                    x,
+                    n,
+                    b,
+                    blocks,
+                    lowband,
+                    lm as i32,
+                    lowband_out,
+                    1.0,
+                    x_cm | y_cm,
+                )?
+            };
+            y_cm = x_cm;
+        }
+
+        collapse_masks[i * channels] = x_cm as u8;
+        collapse_masks[i * channels + channels - 1] = y_cm as u8;
+        balance += pulses[i] + tell;
+
+        // Update the folding position only as long as we have 1 bit/sample depth.
+        update_lowband = b > (n as i32) << BITRES;
+        // We only need to avoid noise on a split for the first band. After that, we
+        // have folding.
+        ctx.avoid_split_noise = false;
+
+        Ok(())
+    })?;
+
+    *seed = ctx.seed;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_haar1_roundtrip() {
+        let input: Vec<f32> = (0..16).map(|i| i as f32 * 0.25 - 1.0).collect();
+        let mut x = input.clone();
+        haar1(&mut x, 16, 1);
+        haar1(&mut x, 16, 1);
+        x.iter().zip(input.iter()).for_each(|(a, b)| {
+            assert!((a - b).abs() < 1e-5);
+        });
+    }
+
+    #[test]
+    fn test_hadamard_roundtrip() {
+        (1..4).for_each(|shift| {
+            let stride = 1 << shift;
+            let n0 = 3;
+            let input: Vec<f32> = (0..n0 * stride).map(|i| i as f32).collect();
+            [false, true].iter().for_each(|hadamard| {
+                let mut x = input.clone();
+                deinterleave_hadamard(&mut x, n0, stride, *hadamard);
+                interleave_hadamard(&mut x, n0, stride, *hadamard);
+                assert_eq!(x, input);
+            });
+        });
+    }
+
+    #[test]
+    fn test_compute_qn() {
+        assert_eq!(compute_qn(4, 0, 0, 0, false), 1);
+        assert_eq!(compute_qn(8, 1000, 8, 40, false), 256);
+        assert_eq!(compute_qn(8, 400, 8, 40, false), 20);
+        assert_eq!(compute_qn(8, 100, 8, 40, false), 4);
+    }
+
+    #[test]
+    fn test_hysteresis_decision() {
+        let thresholds = [1.0, 2.0, 3.0];
+        let hysteresis = [0.5, 0.5, 0.5];
+        assert_eq!(hysteresis_decision(0.5, &thresholds, &hysteresis, 0), 0);
+        assert_eq!(hysteresis_decision(3.5, &thresholds, &hysteresis, 0), 3);
+        // Stays in the previous decision inside of the hysteresis.
+        assert_eq!(hysteresis_decision(2.3, &thresholds, &hysteresis, 2), 2);
+        assert_eq!(hysteresis_decision(1.7, &thresholds, &hysteresis, 2), 2);
+        assert_eq!(hysteresis_decision(1.4, &thresholds, &hysteresis, 2), 1);
+        assert_eq!(hysteresis_decision(3.2, &thresholds, &hysteresis, 2), 2);
+        assert_eq!(hysteresis_decision(3.6, &thresholds, &hysteresis, 2), 3);
+    }
+
+    #[test]
+    fn test_normalise_bands() {
+        let lm = 0;
+        let end = 21;
+        let x: Vec<f32> = (0..SHORT_MDCT_SIZE)
+            .map(|i| (i as f32 * 0.37).sin() + 1.5)
+            .collect();
+        let mut band_e = [0_f32; NB_E_BANDS];
+        compute_band_energies(&x, &mut band_e, end, 1, lm);
+
+        let mut norm = vec![0_f32; SHORT_MDCT_SIZE];
+        normalise_bands(&x, &mut norm, &band_e, end, 1, 1 << lm);
+        (0..end).for_each(|i| {
+            let band = &norm[usize::from(E_BANDS[i])..usize::from(E_BANDS[i + 1])];
+            let energy: f32 = band.iter().map(|x| x * x).sum();
+            assert!((energy - 1.0).abs() < 1e-4);
+        });
+    }
+}
@@ -26,7 +26,7 @@ pub(crate) fn comb_filter_const(
         let g12v = _mm256_set1_ps(g12);
 
         let mut j = 0;
-        (0..n - 7).into_iter().step_by(8).for_each(|i| {
+        (0..n.saturating_sub(7)).step_by(8).for_each(|i| {
             let mut yi = _mm256_loadu_ps(x[x_offset + i..].as_ptr());
 
             let x0v = _mm256_loadu_ps(x[x_offset + i - t - 2..].as_ptr());
@@ -106,7 +106,7 @@ pub(crate) fn comb_filter_const_inplace(
         let g12v = _mm256_set1_ps(g12);
 
         let mut j = 0;
-        (0..n - 7).into_iter().step_by(8).for_each(|i| {
+        (0..n.saturating_sub(7)).step_by(8).for_each(|i| {
             let mut yi = _mm256_loadu_ps(y[y_offset + i..].as_ptr());
 
             let x0v = _mm256_loadu_ps(y[y_offset + i - t - 2..].as_ptr());
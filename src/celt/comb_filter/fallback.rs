@@ -18,7 +18,7 @@ pub(crate) fn comb_filter_const(
     let mut x3 = x[x_offset - t - 1];
     let mut x2 = x[x_offset - t];
     let mut x1 = x[x_offset - t + 1];
-    (0..n).into_iter().for_each(|i| {
+    (0..n).for_each(|i| {
         let x0 = x[x_offset + i - t + 2];
         y[y_offset + i] = x[x_offset + i] + (g10 * x2) + (g11 * (x1 + x3)) + (g12 * (x0 + x4));
         x4 = x3;
@@ -42,7 +42,7 @@ pub(crate) fn comb_filter_const_inplace(
     let mut x3 = y[y_offset - t - 1];
     let mut x2 = y[y_offset - t];
     let mut x1 = y[y_offset - t + 1];
-    (0..n).into_iter().for_each(|i| {
+    (0..n).for_each(|i| {
         let x0 = y[y_offset + i - t + 2];
         y[y_offset + i] = y[y_offset + i] + (g10 * x2) + (g11 * (x1 + x3)) + (g12 * (x0 + x4));
         x4 = x3;
@@ -98,7 +98,7 @@ pub(crate) fn comb_filter(
     }
 
     let mut j = 0;
-    (0..overlap).into_iter().for_each(|i| {
+    (0..overlap).for_each(|i| {
         let x0 = x[x_offset + i - t1 + 2];
         let f = mode::WINDOW[i] * mode::WINDOW[i];
         y[y_offset + i] = x[x_offset + i]
@@ -166,7 +166,7 @@ pub(crate) fn comb_filter_inplace(
     }
 
     let mut j = 0;
-    (0..overlap).into_iter().for_each(|i| {
+    (0..overlap).for_each(|i| {
         let x0 = y[y_offset + i - t1 + 2];
         let f = mode::WINDOW[i] * mode::WINDOW[i];
         y[y_offset + i] = y[y_offset + i]
@@ -248,7 +248,7 @@ mod tests {
             OVERLAP,
         );
 
-        (0..N).into_iter().for_each(|i| {
+        (0..N).for_each(|i| {
             assert!((1.0 - (output[offset + i] / TEST_VECTOR1[i])).abs() < 0.00001);
         });
     }
@@ -264,7 +264,7 @@ mod tests {
         let offset = SIZE - N;
         comb_filter_inplace(&mut output, offset, T0, T1, N, G0, G1, 0, 0, OVERLAP);
 
-        (0..N).into_iter().for_each(|i| {
+        (0..N).for_each(|i| {
             assert!((1.0 - (output[offset + i] / TEST_VECTOR2[i])).abs() < 0.00001);
         });
     }
@@ -27,7 +27,7 @@ pub(crate) fn comb_filter_const(
         let g12v = vld1q_dup_f32(&g12 as *const f32);
         let mut x0v = vld1q_f32(x[x_offset - t - 2..].as_ptr());
 
-        (0..n - 3).into_iter().step_by(4).for_each(|i| {
+        (0..n.saturating_sub(3)).step_by(4).for_each(|i| {
             let yi = vld1q_f32(x[x_offset + i..].as_ptr());
 
             let xp = x_offset + i - t - 2;
@@ -67,7 +67,7 @@ pub(crate) fn comb_filter_const_inplace(
         let g12v = vld1q_dup_f32(&g12 as *const f32);
         let mut x0v = vld1q_f32(y[y_offset - t - 2..].as_ptr());
 
-        (0..n - 3).into_iter().step_by(4).for_each(|i| {
+        (0..n.saturating_sub(3)).step_by(4).for_each(|i| {
             let yi = vld1q_f32(y[y_offset + i..].as_ptr());
 
             let yp = y_offset + i - t - 2;
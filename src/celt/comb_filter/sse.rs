@@ -25,7 +25,7 @@ pub(crate) fn comb_filter_const(
 
         let mut x0v = _mm_loadu_ps(x[x_offset - t - 2..].as_ptr());
 
-        (0..n - 3).into_iter().step_by(4).for_each(|i| {
+        (0..n.saturating_sub(3)).step_by(4).for_each(|i| {
             let yi = _mm_loadu_ps(x[x_offset + i..].as_ptr());
             let x4v = _mm_loadu_ps(x[x_offset + i - t + 2..].as_ptr());
 
@@ -61,7 +61,7 @@ pub(crate) fn comb_filter_const_inplace(
 
         let mut x0v = _mm_loadu_ps(y[y_offset - t - 2..].as_ptr());
 
-        (0..n - 3).into_iter().step_by(4).for_each(|i| {
+        (0..n.saturating_sub(3)).step_by(4).for_each(|i| {
             let yi = _mm_loadu_ps(y[y_offset + i..].as_ptr());
             let x4v = _mm_loadu_ps(y[y_offset + i - t + 2..].as_ptr());
 
@@ -1,58 +1,870 @@
 //! Implements the Celt decoder.
 
-use crate::range_coder::RangeDecoder;
+use crate::celt::bands::{anti_collapse, denormalise_bands, lcg_rand, quant_all_bands};
+use crate::celt::lpc::{autocorr, fir, iir, lpc};
+use crate::celt::mdct::Mdct;
+use crate::celt::mode::{E_BANDS, MAX_LM, NB_E_BANDS, OVERLAP, PREEMPH, SHORT_MDCT_SIZE, WINDOW};
+use crate::celt::pitch::{pitch_downsample, pitch_search};
+use crate::celt::quant_bands::{
+    unquant_coarse_energy, unquant_energy_finalise, unquant_fine_energy,
+};
+use crate::celt::rate::{compute_allocation, init_caps};
+use crate::celt::vq::{renormalise_vector, SPREAD_NORMAL};
+use crate::celt::{
+    comb_filter, comb_filter_inplace, COMBFILTER_MINPERIOD, SPREAD_ICDF, TAPSET_ICDF,
+    TF_SELECT_TABLE, TRIM_ICDF, VERY_SMALL,
+};
+use crate::range_coder::{RangeCoder, RangeDecoder, Tell, BITRES};
 use crate::{Channels, OpusError, SamplingRate};
 
+/// Size of the decoding memory per channel (without the overlap).
+const DECODE_BUFFER_SIZE: usize = 2048;
+/// Order of the LPC filter used by the pitch-based PLC.
+const LPC_ORDER: usize = 24;
+/// Maximal length of the excitation used by the pitch-based PLC.
+const MAX_PERIOD: usize = 1024;
+const PLC_PITCH_LAG_MAX: usize = 720;
+const PLC_PITCH_LAG_MIN: usize = 100;
+
 /// The Celt decoder.
 #[derive(Clone, Debug)]
 pub(crate) struct CeltDecoder {
-    // Signaling is only used for custom decoder mode.
+    channels: usize,
+    stream_channels: usize,
+    downsample: usize,
     // Startband
-    start: u32,
+    start: usize,
     // Endband
-    end: u32,
-    stream_channels: Channels,
+    end: usize,
+    disable_inv: bool,
 
     rng: u32,
+    last_pitch_index: usize,
+    loss_count: usize,
+    skip_plc: bool,
+    postfilter_period: usize,
+    postfilter_period_old: usize,
+    postfilter_gain: f32,
+    postfilter_gain_old: f32,
+    postfilter_tapset: usize,
+    postfilter_tapset_old: usize,
+    preemph_mem: [f32; 2],
+
+    /// Decoding memory for each channel (DECODE_BUFFER_SIZE + OVERLAP per channel).
+    decode_mem: Vec<f32>,
+    /// LPC coefficients of the pitch-based PLC for each channel.
+    lpc: [f32; 2 * LPC_ORDER],
+    old_ebands: [f32; 2 * NB_E_BANDS],
+    old_log_e: [f32; 2 * NB_E_BANDS],
+    old_log_e2: [f32; 2 * NB_E_BANDS],
+    background_log_e: [f32; 2 * NB_E_BANDS],
+
+    mdct: Mdct,
 }
 
 impl CeltDecoder {
     /// Creates a new Celt decoder.
-    pub(crate) fn new(_sampling_rate: SamplingRate, channels: Channels) -> Result<Self, OpusError> {
-        // TODO Port opus_custom_decoder_init
-        // TODO calculate and set downsample
-
-        todo!();
-
-        Ok(Self {
-            start: 0,
-            end: 21,
+    pub(crate) fn new(sampling_rate: SamplingRate, channels: Channels) -> Result<Self, OpusError> {
+        let channels = channels as usize;
+        let mut dec = Self {
+            channels,
             stream_channels: channels,
+            downsample: sampling_rate.resampling_factor() as usize,
+            start: 0,
+            end: NB_E_BANDS,
+            disable_inv: channels == 1,
             rng: 0,
-        })
+            last_pitch_index: 0,
+            loss_count: 0,
+            skip_plc: true,
+            postfilter_period: 0,
+            postfilter_period_old: 0,
+            postfilter_gain: 0.0,
+            postfilter_gain_old: 0.0,
+            postfilter_tapset: 0,
+            postfilter_tapset_old: 0,
+            preemph_mem: [0.0; 2],
+            decode_mem: vec![0.0; channels * (DECODE_BUFFER_SIZE + OVERLAP)],
+            lpc: [0.0; 2 * LPC_ORDER],
+            old_ebands: [0.0; 2 * NB_E_BANDS],
+            old_log_e: [0.0; 2 * NB_E_BANDS],
+            old_log_e2: [0.0; 2 * NB_E_BANDS],
+            background_log_e: [0.0; 2 * NB_E_BANDS],
+            mdct: Mdct::default(),
+        };
+        dec.reset()?;
+
+        Ok(dec)
     }
 
     /// Resets the Celt decoder.
     pub(crate) fn reset(&mut self) -> Result<(), OpusError> {
-        // TODO this shouldn't reset any buffers, if we allocate any, since we could reset every packet.
-        todo!()
+        self.rng = 0;
+        self.last_pitch_index = 0;
+        self.loss_count = 0;
+        self.skip_plc = true;
+        self.postfilter_period = 0;
+        self.postfilter_period_old = 0;
+        self.postfilter_gain = 0.0;
+        self.postfilter_gain_old = 0.0;
+        self.postfilter_tapset = 0;
+        self.postfilter_tapset_old = 0;
+        self.preemph_mem = [0.0; 2];
+        self.decode_mem.iter_mut().for_each(|x| *x = 0.0);
+        self.lpc = [0.0; 2 * LPC_ORDER];
+        self.old_ebands = [0.0; 2 * NB_E_BANDS];
+        self.old_log_e = [-28.0; 2 * NB_E_BANDS];
+        self.old_log_e2 = [-28.0; 2 * NB_E_BANDS];
+        self.background_log_e = [0.0; 2 * NB_E_BANDS];
+
+        Ok(())
     }
 
     /// Gets the pitch of the last decoded frame.
     pub(crate) fn pitch(&self) -> u32 {
-        todo!()
+        self.postfilter_period as u32
     }
 
-    /// TODO documentation
+    /// Decodes a Celt frame into `pcm`.
+    ///
+    /// If `dec` is `None`, a new range decoder is created for the given data.
+    /// A missing packet or a packet with a length of 1 or less triggers the PLC.
+    ///
+    /// Returns the number of decoded samples per channel.
     pub(crate) fn decode(
-        &self,
+        &mut self,
         data: &Option<&[u8]>,
         len: usize,
         pcm: &mut [f32],
         frame_size: usize,
         dec: &mut Option<RangeDecoder>,
-    ) -> usize {
-        todo!()
+    ) -> Result<usize, OpusError> {
+        let frame_size = frame_size * self.downsample;
+
+        let lm = (0..=MAX_LM)
+            .find(|lm| SHORT_MDCT_SIZE << lm == frame_size)
+            .ok_or(OpusError::BadArguments(
+                "frame_size is not a valid Celt frame size",
+            ))?;
+
+        if len > 1275 {
+            return Err(OpusError::BadArguments("packet is too large"));
+        }
+
+        let n = SHORT_MDCT_SIZE << lm;
+
+        match data {
+            Some(data) if len > 1 => {
+                if let Some(dec) = dec.as_mut() {
+                    self.decode_with_ec(len, pcm, lm, dec)?;
+                } else {
+                    let mut dec = RangeDecoder::new(&data[..len]);
+                    self.decode_with_ec(len, pcm, lm, &mut dec)?;
+                }
+            }
+            _ => {
+                self.decode_lost(n, lm);
+                self.deemphasis(pcm, n);
+            }
+        }
+
+        Ok(frame_size / self.downsample)
+    }
+
+    fn decode_with_ec(
+        &mut self,
+        len: usize,
+        pcm: &mut [f32],
+        lm: usize,
+        dec: &mut RangeDecoder,
+    ) -> Result<(), OpusError> {
+        let cc = self.channels;
+        let c = self.stream_channels;
+        let start = self.start;
+        let end = self.end;
+        let eff_end = usize::min(end, NB_E_BANDS);
+        let m = 1 << lm;
+        let n = m * SHORT_MDCT_SIZE;
+
+        // Check if there are at least two packets received consecutively before
+        // turning on the pitch-based PLC.
+        self.skip_plc = self.loss_count != 0;
+
+        if c == 1 {
+            (0..NB_E_BANDS).for_each(|i| {
+                self.old_ebands[i] = f32::max(self.old_ebands[i], self.old_ebands[NB_E_BANDS + i]);
+            });
+        }
+
+        let mut total_bits = len as i32 * 8;
+        let mut tell = dec.tell() as i32;
+
+        let silence = if tell >= total_bits {
+            true
+        } else if tell == 1 {
+            dec.decode_bit_logp(15)
+        } else {
+            false
+        };
+        if silence {
+            // Pretend we've read all the remaining bits.
+            tell = len as i32 * 8;
+            dec.skip_to(tell as u32);
+        }
+
+        let mut postfilter_gain = 0.0;
+        let mut postfilter_pitch = 0;
+        let mut postfilter_tapset = 0;
+        if start == 0 && tell + 16 <= total_bits {
+            if dec.decode_bit_logp(1) {
+                let octave = dec.decode_uint(6);
+                postfilter_pitch = ((16 << octave) + dec.decode_bits(4 + octave) - 1) as usize;
+                let qg = dec.decode_bits(3);
+                if dec.tell() as i32 + 2 <= total_bits {
+                    postfilter_tapset = dec.decode_icdf(TAPSET_ICDF, 2) as usize;
+                }
+                postfilter_gain = 0.09375 * (qg + 1) as f32;
+            }
+            tell = dec.tell() as i32;
+        }
+
+        let is_transient = if lm > 0 && tell + 3 <= total_bits {
+            let is_transient = dec.decode_bit_logp(3);
+            tell = dec.tell() as i32;
+            is_transient
+        } else {
+            false
+        };
+
+        // Decode the global flags (first symbols in the stream).
+        let intra_ener = if tell + 3 <= total_bits {
+            dec.decode_bit_logp(3)
+        } else {
+            false
+        };
+
+        // Get band energies.
+        unquant_coarse_energy(start, end, &mut self.old_ebands, intra_ener, dec, c, lm);
+
+        let mut tf_res = [0_i32; NB_E_BANDS];
+        tf_decode(start, end, is_transient, &mut tf_res, lm, dec);
+
+        tell = dec.tell() as i32;
+        let mut spread_decision = SPREAD_NORMAL;
+        if tell + 4 <= total_bits {
+            spread_decision = dec.decode_icdf(SPREAD_ICDF, 5);
+        }
+
+        let mut cap = [0_i32; NB_E_BANDS];
+        init_caps(&mut cap, lm as i32, c as i32);
+
+        let mut offsets = [0_i32; NB_E_BANDS];
+        let mut dynalloc_logp = 6;
+        total_bits <<= BITRES;
+        tell = dec.tell_frac() as i32;
+        (start..end).for_each(|i| {
+            let width = (c as i32 * i32::from(E_BANDS[i + 1] - E_BANDS[i])) << lm;
+            // quanta is 6 bits, but no more than 1 bit/sample
+            // and no less than 1/8 bit/sample.
+            let quanta = i32::min(width << BITRES, i32::max(6 << BITRES, width));
+            let mut dynalloc_loop_logp = dynalloc_logp;
+            let mut boost = 0;
+            while tell + (dynalloc_loop_logp << BITRES) < total_bits && boost < cap[i] {
+                let flag = dec.decode_bit_logp(dynalloc_loop_logp as u32);
+                tell = dec.tell_frac() as i32;
+                if !flag {
+                    break;
+                }
+                boost += quanta;
+                total_bits -= quanta;
+                dynalloc_loop_logp = 1;
+            }
+            offsets[i] = boost;
+            // Making dynalloc more likely.
+            if boost > 0 {
+                dynalloc_logp = i32::max(2, dynalloc_logp - 1);
+            }
+        });
+
+        let alloc_trim = if tell + (6 << BITRES) <= total_bits {
+            dec.decode_icdf(TRIM_ICDF, 7) as i32
+        } else {
+            5
+        };
+
+        let mut bits = ((len as i32 * 8) << BITRES) - dec.tell_frac() as i32 - 1;
+        let anti_collapse_rsv = if is_transient && lm >= 2 && bits >= (lm as i32 + 2) << BITRES {
+            1 << BITRES
+        } else {
+            0
+        };
+        bits -= anti_collapse_rsv;
+
+        let allocation = compute_allocation(
+            start,
+            end,
+            &offsets,
+            &cap,
+            alloc_trim,
+            0,
+            false,
+            bits,
+            c as i32,
+            lm as i32,
+            &mut RangeCoder::Decoder(dec),
+            0,
+            0,
+        )?;
+
+        unquant_fine_energy(
+            start,
+            end,
+            &mut self.old_ebands,
+            &allocation.fine_quant,
+            dec,
+            c,
+        );
+
+        self.shift_decode_mem(n);
+
+        // Decode fixed codebook.
+        let mut collapse_masks = [0_u8; 2 * NB_E_BANDS];
+        let mut x = vec![0_f32; c * n];
+        {
+            let (x, y) = x.split_at_mut(n);
+            let y = if c == 2 { Some(y) } else { None };
+            quant_all_bands(
+                start,
+                end,
+                x,
+                y,
+                &mut collapse_masks,
+                &[],
+                &allocation.pulses,
+                is_transient,
+                spread_decision,
+                allocation.dual_stereo,
+                allocation.intensity,
+                &tf_res,
+                len as i32 * (8 << BITRES) - anti_collapse_rsv,
+                allocation.balance,
+                &mut RangeCoder::Decoder(dec),
+                lm,
+                allocation.coded_bands,
+                &mut self.rng,
+                0,
+                self.disable_inv,
+            )?;
+        }
+
+        let anti_collapse_on = anti_collapse_rsv > 0 && dec.decode_bits(1) != 0;
+
+        unquant_energy_finalise(
+            start,
+            end,
+            &mut self.old_ebands,
+            &allocation.fine_quant,
+            &allocation.fine_priority,
+            len as i32 * 8 - dec.tell() as i32,
+            dec,
+            c,
+        );
+
+        if anti_collapse_on {
+            anti_collapse(
+                &mut x,
+                &collapse_masks,
+                lm,
+                c,
+                n,
+                start,
+                end,
+                &self.old_ebands,
+                &self.old_log_e,
+                &self.old_log_e2,
+                &allocation.pulses,
+                self.rng,
+            );
+        }
+
+        if silence {
+            self.old_ebands[..c * NB_E_BANDS]
+                .iter_mut()
+                .for_each(|x| *x = -28.0);
+        }
+
+        self.synthesis(&x, start, eff_end, c, is_transient, lm, silence);
+
+        self.postfilter_period = usize::max(self.postfilter_period, COMBFILTER_MINPERIOD);
+        self.postfilter_period_old = usize::max(self.postfilter_period_old, COMBFILTER_MINPERIOD);
+        (0..cc).for_each(|ch| {
+            let decode_mem = &mut self.decode_mem[ch * (DECODE_BUFFER_SIZE + OVERLAP)..];
+            let out_syn = DECODE_BUFFER_SIZE - n;
+            comb_filter_inplace(
+                decode_mem,
+                out_syn,
+                self.postfilter_period_old,
+                self.postfilter_period,
+                SHORT_MDCT_SIZE,
+                self.postfilter_gain_old,
+                self.postfilter_gain,
+                self.postfilter_tapset_old,
+                self.postfilter_tapset,
+                OVERLAP,
+            );
+            if lm != 0 {
+                comb_filter_inplace(
+                    decode_mem,
+                    out_syn + SHORT_MDCT_SIZE,
+                    self.postfilter_period,
+                    postfilter_pitch,
+                    n - SHORT_MDCT_SIZE,
+                    self.postfilter_gain,
+                    postfilter_gain,
+                    self.postfilter_tapset,
+                    postfilter_tapset,
+                    OVERLAP,
+                );
+            }
+        });
+        self.postfilter_period_old = self.postfilter_period;
+        self.postfilter_gain_old = self.postfilter_gain;
+        self.postfilter_tapset_old = self.postfilter_tapset;
+        self.postfilter_period = postfilter_pitch;
+        self.postfilter_gain = postfilter_gain;
+        self.postfilter_tapset = postfilter_tapset;
+        if lm != 0 {
+            self.postfilter_period_old = self.postfilter_period;
+            self.postfilter_gain_old = self.postfilter_gain;
+            self.postfilter_tapset_old = self.postfilter_tapset;
+        }
+
+        if c == 1 {
+            self.old_ebands.copy_within(0..NB_E_BANDS, NB_E_BANDS);
+        }
+
+        // In case start or end were to change.
+        if !is_transient {
+            self.old_log_e2 = self.old_log_e;
+            self.old_log_e = self.old_ebands;
+            // In normal circumstances, we only allow the noise floor to increase by
+            // up to 2.4 dB/second, but when we're in DTX, we allow up to 6 dB
+            // increase for each update.
+            let max_background_increase = if self.loss_count < 10 {
+                m as f32 * 0.001
+            } else {
+                1.0
+            };
+            self.background_log_e
+                .iter_mut()
+                .zip(self.old_ebands.iter())
+                .for_each(|(background, old)| {
+                    *background = f32::min(*background + max_background_increase, *old);
+                });
+        } else {
+            self.old_log_e
+                .iter_mut()
+                .zip(self.old_ebands.iter())
+                .for_each(|(log_e, old)| *log_e = f32::min(*log_e, *old));
+        }
+
+        (0..2).for_each(|ch| {
+            (0..start).chain(end..NB_E_BANDS).for_each(|i| {
+                self.old_ebands[ch * NB_E_BANDS + i] = 0.0;
+                self.old_log_e[ch * NB_E_BANDS + i] = -28.0;
+                self.old_log_e2[ch * NB_E_BANDS + i] = -28.0;
+            });
+        });
+        self.rng = dec.range();
+
+        self.deemphasis(pcm, n);
+        self.loss_count = 0;
+
+        if dec.tell() as usize > 8 * len {
+            return Err(OpusError::InternalError(
+                "the decoder read more bits than available",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Conceals a lost frame.
+    fn decode_lost(&mut self, n: usize, lm: usize) {
+        let noise_based = self.loss_count >= 5 || self.start != 0 || self.skip_plc;
+        if noise_based {
+            self.decode_lost_noise(n, lm);
+        } else {
+            self.decode_lost_pitch(n);
+        }
+
+        self.loss_count += 1;
+    }
+
+    /// Conceals a lost frame by generating noise with the energy of the last frame.
+    fn decode_lost_noise(&mut self, n: usize, lm: usize) {
+        let cc = self.channels;
+        let start = self.start;
+        let end = self.end;
+        let eff_end = usize::max(start, usize::min(end, NB_E_BANDS));
+
+        let mut x = vec![0_f32; cc * n];
+
+        // Energy decay.
+        let decay = if self.loss_count == 0 { 1.5 } else { 0.5 };
+        (0..cc).for_each(|c| {
+            (start..end).for_each(|i| {
+                let idx = c * NB_E_BANDS + i;
+                self.old_ebands[idx] =
+                    f32::max(self.background_log_e[idx], self.old_ebands[idx] - decay);
+            });
+        });
+
+        let mut seed = self.rng;
+        (0..cc).for_each(|c| {
+            (start..eff_end).for_each(|i| {
+                let boffs = n * c + (usize::from(E_BANDS[i]) << lm);
+                let blen = usize::from(E_BANDS[i + 1] - E_BANDS[i]) << lm;
+                x[boffs..boffs + blen].iter_mut().for_each(|x| {
+                    seed = lcg_rand(seed);
+                    *x = ((seed as i32) >> 20) as f32;
+                });
+                renormalise_vector(&mut x[boffs..], blen, 1.0);
+            });
+        });
+        self.rng = seed;
+
+        self.shift_decode_mem(n);
+
+        self.synthesis(&x, start, eff_end, cc, false, lm, false);
+    }
+
+    /// Conceals a lost frame by periodically extending the excitation of the last pitch
+    /// period and applying the LPC synthesis filter.
+    fn decode_lost_pitch(&mut self, n: usize) {
+        let cc = self.channels;
+
+        let (pitch_index, fade) = if self.loss_count == 0 {
+            self.last_pitch_index = self.plc_pitch_search();
+            (self.last_pitch_index, 1.0)
+        } else {
+            (self.last_pitch_index, 0.8)
+        };
+
+        // We want the excitation for 2 pitch periods in order to look for a
+        // decaying signal, but we can't get more than MAX_PERIOD.
+        let exc_length = usize::min(2 * pitch_index, MAX_PERIOD);
+
+        let mut etmp = [0_f32; OVERLAP];
+        let mut exc = [0_f32; MAX_PERIOD + LPC_ORDER];
+        let mut fir_tmp = vec![0_f32; exc_length];
+
+        (0..cc).for_each(|c| {
+            let buf = &mut self.decode_mem[c * (DECODE_BUFFER_SIZE + OVERLAP)..];
+            let lpc_coef = &mut self.lpc[c * LPC_ORDER..(c + 1) * LPC_ORDER];
+
+            exc.copy_from_slice(
+                &buf[DECODE_BUFFER_SIZE - MAX_PERIOD - LPC_ORDER..DECODE_BUFFER_SIZE],
+            );
+
+            if self.loss_count == 0 {
+                // Compute LPC coefficients for the last MAX_PERIOD samples before
+                // the first loss so we can work in the excitation-filter domain.
+                let mut ac = [0_f32; LPC_ORDER + 1];
+                autocorr(
+                    &exc[LPC_ORDER..],
+                    &mut ac,
+                    Some(WINDOW),
+                    OVERLAP,
+                    LPC_ORDER,
+                    MAX_PERIOD,
+                );
+                // Add a noise floor of -40 dB.
+                ac[0] *= 1.0001;
+                // Use lag windowing to stabilize the Levinson-Durbin recursion.
+                (1..=LPC_ORDER).for_each(|i| {
+                    ac[i] -= ac[i] * (0.008 * 0.008) * i as f32 * i as f32;
+                });
+                lpc(lpc_coef, &ac, LPC_ORDER);
+            }
+
+            // Compute the excitation for exc_length samples before the loss.
+            fir(
+                &exc[MAX_PERIOD - exc_length..],
+                lpc_coef,
+                &mut fir_tmp,
+                exc_length,
+                LPC_ORDER,
+            );
+            exc[LPC_ORDER + MAX_PERIOD - exc_length..].copy_from_slice(&fir_tmp);
+
+            // Check if the waveform is decaying, and if so how fast.
+            // We do this to avoid adding energy when concealing in a segment
+            // with decaying energy.
+            let exc = &exc[LPC_ORDER..];
+            let decay_length = exc_length >> 1;
+            let mut e1 = 1.0;
+            let mut e2 = 1.0;
+            (0..decay_length).for_each(|i| {
+                let e = exc[MAX_PERIOD - decay_length + i];
+                e1 += e * e;
+                let e = exc[MAX_PERIOD - 2 * decay_length + i];
+                e2 += e * e;
+            });
+            let e1 = f32::min(e1, e2);
+            let decay = (e1 / e2).sqrt();
+
+            // Move the decoder memory one frame to the left to give us room to
+            // add the data for the new frame. We ignore the overlap that extends
+            // past the end of the buffer, because we aren't going to use it.
+            buf.copy_within(n..DECODE_BUFFER_SIZE, 0);
+
+            // Extrapolate from the end of the excitation with a period of
+            // "pitch_index", scaling down each period by an additional factor of
+            // "decay".
+            let extrapolation_offset = MAX_PERIOD - pitch_index;
+            // We need to extrapolate enough samples to cover a complete MDCT
+            // window (including overlap/2 samples on both sides).
+            let extrapolation_len = n + OVERLAP;
+            // We also apply fading if this is not the first loss.
+            let mut attenuation = fade * decay;
+            let mut s1 = 0.0;
+            let mut j = 0;
+            (0..extrapolation_len).for_each(|i| {
+                if j >= pitch_index {
+                    j -= pitch_index;
+                    attenuation *= decay;
+                }
+                buf[DECODE_BUFFER_SIZE - n + i] = attenuation * exc[extrapolation_offset + j];
+                // Compute the energy of the previously decoded signal whose
+                // excitation we're copying.
+                let tmp = buf[DECODE_BUFFER_SIZE - MAX_PERIOD - n + extrapolation_offset + j];
+                s1 += tmp * tmp;
+                j += 1;
+            });
+
+            // Copy the last decoded samples (prior to the overlap region) to
+            // synthesis filter memory so we can have a continuous signal.
+            let mut lpc_mem = [0_f32; LPC_ORDER];
+            lpc_mem
+                .iter_mut()
+                .enumerate()
+                .for_each(|(i, x)| *x = buf[DECODE_BUFFER_SIZE - n - 1 - i]);
+            // Apply the synthesis filter to convert the excitation back into
+            // the signal domain.
+            iir(
+                &mut buf[DECODE_BUFFER_SIZE - n..],
+                lpc_coef,
+                extrapolation_len,
+                LPC_ORDER,
+                &lpc_mem,
+            );
+
+            // Check if the synthesis energy is higher than expected, which can
+            // happen with the signal changes during our window. If so, attenuate.
+            let out = &mut buf[DECODE_BUFFER_SIZE - n..DECODE_BUFFER_SIZE - n + extrapolation_len];
+            let s2 = out.iter().fold(0.0, |s2, x| s2 + (x * x));
+            // This checks for an "explosion" in the synthesis. The test is written
+            // this way to catch NaNs in the output of the IIR filter at the same time.
+            #[allow(clippy::neg_cmp_op_on_partial_ord)]
+            if !(s1 > 0.2 * s2) {
+                out.iter_mut().for_each(|x| *x = 0.0);
+            } else if s1 < s2 {
+                let ratio = ((s1 + 1.0) / (s2 + 1.0)).sqrt();
+                out.iter_mut().enumerate().for_each(|(i, x)| {
+                    if i < OVERLAP {
+                        let tmp_g = 1.0 - (WINDOW[i] * (1.0 - ratio));
+                        *x *= tmp_g;
+                    } else {
+                        *x *= ratio;
+                    }
+                });
+            }
+
+            // Apply the pre-filter to the MDCT overlap for the next frame because
+            // the post-filter will be re-applied in the decoder after the MDCT
+            // overlap.
+            comb_filter(
+                &mut etmp,
+                0,
+                buf,
+                DECODE_BUFFER_SIZE,
+                self.postfilter_period,
+                self.postfilter_period,
+                OVERLAP,
+                -self.postfilter_gain,
+                -self.postfilter_gain,
+                self.postfilter_tapset,
+                self.postfilter_tapset,
+                0,
+            );
+
+            // Simulate TDAC on the concealed audio so that it blends with the
+            // MDCT of the next frame.
+            (0..OVERLAP / 2).for_each(|i| {
+                buf[DECODE_BUFFER_SIZE + i] =
+                    (WINDOW[i] * etmp[OVERLAP - 1 - i]) + (WINDOW[OVERLAP - i - 1] * etmp[i]);
+            });
+        });
+    }
+
+    /// Searches the pitch period of the decoded signal for the pitch-based PLC.
+    fn plc_pitch_search(&self) -> usize {
+        let cc = self.channels;
+
+        let mut x = vec![0_f32; cc * DECODE_BUFFER_SIZE];
+        x.chunks_exact_mut(DECODE_BUFFER_SIZE)
+            .enumerate()
+            .for_each(|(c, x)| {
+                let offset = c * (DECODE_BUFFER_SIZE + OVERLAP);
+                x.copy_from_slice(&self.decode_mem[offset..offset + DECODE_BUFFER_SIZE]);
+            });
+
+        let mut lp_pitch_buf = vec![0_f32; DECODE_BUFFER_SIZE >> 1];
+        pitch_downsample(&x, &mut lp_pitch_buf, DECODE_BUFFER_SIZE, cc);
+        let pitch_index = pitch_search(
+            &lp_pitch_buf[PLC_PITCH_LAG_MAX >> 1..],
+            &lp_pitch_buf,
+            DECODE_BUFFER_SIZE - PLC_PITCH_LAG_MAX,
+            PLC_PITCH_LAG_MAX - PLC_PITCH_LAG_MIN,
+        );
+
+        PLC_PITCH_LAG_MAX - pitch_index
+    }
+
+    /// Moves the decoding memory by N samples to make room for the new frame.
+    fn shift_decode_mem(&mut self, n: usize) {
+        (0..self.channels).for_each(|c| {
+            let decode_mem = &mut self.decode_mem[c * (DECODE_BUFFER_SIZE + OVERLAP)..];
+            decode_mem.copy_within(n..DECODE_BUFFER_SIZE + OVERLAP / 2, 0);
+        });
+    }
+
+    /// Converts the normalized bands back into the time domain.
+    #[allow(clippy::too_many_arguments)]
+    fn synthesis(
+        &mut self,
+        x: &[f32],
+        start: usize,
+        eff_end: usize,
+        c: usize,
+        is_transient: bool,
+        lm: usize,
+        silence: bool,
+    ) {
+        let cc = self.channels;
+        let m = 1 << lm;
+        let n = SHORT_MDCT_SIZE << lm;
+
+        let (b, nb, shift) = if is_transient {
+            (m, SHORT_MDCT_SIZE, MAX_LM)
+        } else {
+            (1, SHORT_MDCT_SIZE << lm, MAX_LM - lm)
+        };
+
+        let mut freq = vec![0_f32; cc * n];
+        if cc == 2 && c == 1 {
+            // Copying a mono streams to two channels.
+            denormalise_bands(
+                x,
+                &mut freq,
+                &self.old_ebands,
+                start,
+                eff_end,
+                m,
+                self.downsample,
+                silence,
+            );
+            freq.copy_within(0..n, n);
+        } else if cc == 1 && c == 2 {
+            // Downmixing a stereo stream to mono.
+            let mut freq2 = vec![0_f32; n];
+            denormalise_bands(
+                x,
+                &mut freq,
+                &self.old_ebands,
+                start,
+                eff_end,
+                m,
+                self.downsample,
+                silence,
+            );
+            denormalise_bands(
+                &x[n..],
+                &mut freq2,
+                &self.old_ebands[NB_E_BANDS..],
+                start,
+                eff_end,
+                m,
+                self.downsample,
+                silence,
+            );
+            freq.iter_mut()
+                .zip(freq2.iter())
+                .for_each(|(x, y)| *x = (0.5 * *x) + (0.5 * *y));
+        } else {
+            // Normal case (mono or stereo).
+            (0..cc).for_each(|ch| {
+                denormalise_bands(
+                    &x[ch * n..],
+                    &mut freq[ch * n..],
+                    &self.old_ebands[ch * NB_E_BANDS..],
+                    start,
+                    eff_end,
+                    m,
+                    self.downsample,
+                    silence,
+                );
+            });
+        }
+
+        let mdct = &mut self.mdct;
+        let decode_mem = &mut self.decode_mem;
+        (0..cc).for_each(|ch| {
+            let out_syn =
+                &mut decode_mem[ch * (DECODE_BUFFER_SIZE + OVERLAP) + DECODE_BUFFER_SIZE - n..];
+            (0..b).for_each(|b_i| {
+                mdct.backward(
+                    &freq[ch * n + b_i..],
+                    &mut out_syn[nb * b_i..],
+                    WINDOW,
+                    OVERLAP,
+                    shift,
+                    b,
+                );
+            });
+        });
+    }
+
+    /// Applies the de-emphasis filter and writes the interleaved output.
+    fn deemphasis(&mut self, pcm: &mut [f32], n: usize) {
+        let cc = self.channels;
+        let coef0 = PREEMPH[0];
+        let downsample = self.downsample;
+        let nd = n / downsample;
+
+        (0..cc).for_each(|c| {
+            let x = &self.decode_mem[c * (DECODE_BUFFER_SIZE + OVERLAP) + DECODE_BUFFER_SIZE - n..];
+            let mut m = self.preemph_mem[c];
+            if downsample > 1 {
+                let mut scratch = vec![0_f32; n];
+                scratch.iter_mut().zip(x[..n].iter()).for_each(|(s, x)| {
+                    let tmp = *x + VERY_SMALL + m;
+                    m = coef0 * tmp;
+                    *s = tmp;
+                });
+                (0..nd).for_each(|j| {
+                    pcm[j * cc + c] = scratch[j * downsample] * (1.0 / 32768.0);
+                });
+            } else {
+                x[..n].iter().enumerate().for_each(|(j, x)| {
+                    let tmp = *x + VERY_SMALL + m;
+                    m = coef0 * tmp;
+                    pcm[j * cc + c] = tmp * (1.0 / 32768.0);
+                });
+            }
+            self.preemph_mem[c] = m;
+        });
     }
 
     /// Get the final range.
@@ -61,17 +873,236 @@ impl CeltDecoder {
     }
 
     /// Sets the end band.
-    pub(crate) fn set_end_band(&mut self, end_band: u32) {
+    pub(crate) fn set_end_band(&mut self, end_band: usize) {
         self.end = end_band;
     }
 
     /// Sets the start band.
-    pub(crate) fn set_start_band(&mut self, start_band: u32) {
+    pub(crate) fn set_start_band(&mut self, start_band: usize) {
         self.start = start_band;
     }
 
     /// Sets the stream channels.
     pub(crate) fn set_stream_channels(&mut self, channels: Channels) {
-        self.stream_channels = channels;
+        self.stream_channels = channels as usize;
+    }
+}
+
+/// Decodes the time-frequency resolution changes of the bands.
+fn tf_decode(
+    start: usize,
+    end: usize,
+    is_transient: bool,
+    tf_res: &mut [i32; NB_E_BANDS],
+    lm: usize,
+    dec: &mut RangeDecoder,
+) {
+    let mut budget = dec.storage() as u32 * 8;
+    let mut tell = dec.tell();
+    let mut logp = if is_transient { 2 } else { 4 };
+    let tf_select_rsv = lm > 0 && tell + logp < budget;
+    budget -= tf_select_rsv as u32;
+
+    let mut tf_changed = 0;
+    let mut curr = 0;
+    (start..end).for_each(|i| {
+        if tell + logp <= budget {
+            curr ^= dec.decode_bit_logp(logp) as i32;
+            tell = dec.tell();
+            tf_changed |= curr;
+        }
+        tf_res[i] = curr;
+        logp = if is_transient { 4 } else { 5 };
+    });
+
+    let table = &TF_SELECT_TABLE[lm];
+    let offset = 4 * is_transient as usize;
+    let mut tf_select = 0;
+    if tf_select_rsv
+        && table[offset + tf_changed as usize] != table[offset + 2 + tf_changed as usize]
+    {
+        tf_select = dec.decode_bit_logp(1) as usize;
+    }
+    (start..end).for_each(|i| {
+        tf_res[i] = i32::from(table[offset + 2 * tf_select + tf_res[i] as usize]);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+    use crate::{Channels, Decoder, DecoderConfiguration, SamplingRate};
+    use std::num::NonZeroUsize;
+
+    // CELT-only packets and their final ranges, as produced and decoded by libopus 1.3.1.
+
+    // Fullband, mono, 20, 10, 5, 2.5, 5, 10 and 20 ms, with transients.
+    const TEST_PACKETS_MONO: &[&[u8]] = &[
+        &[
+            0xF8, 0x77, 0xC5, 0x55, 0x47, 0x9A, 0xB6, 0xC5, 0x64, 0x57, 0x31, 0x7C, 0x38, 0xB1,
+            0x0A, 0xA2, 0xC7, 0x2C, 0x90, 0xD8, 0xB5, 0xF5, 0x3D, 0xB5, 0x72, 0xF8, 0x93, 0x89,
+            0xAF, 0xE4, 0x8F, 0x3A, 0x1D, 0x5D, 0x5A, 0x1C, 0x14, 0xE6, 0xA0, 0xEC, 0x51, 0x05,
+            0x89, 0x0A, 0x8D, 0x62, 0x40, 0x9F, 0x85,
+        ],
+        &[
+            0xF0, 0x75, 0xC5, 0xFF, 0xF9, 0x0F, 0x48, 0xB7, 0xDB, 0xCC, 0x73, 0x3C, 0x38, 0xB3,
+            0xC1, 0x1A, 0xF7, 0xE2, 0xDC, 0x13, 0x20, 0x7E, 0x31, 0xB6, 0xC7, 0x52,
+        ],
+        &[0xE8, 0x75, 0xE0, 0x2D, 0xE1, 0x15, 0x35, 0x80],
+        &[0xE0, 0x94, 0xC0],
+        &[0xE8, 0x33, 0x39, 0x26, 0x7A, 0xD7, 0x0D, 0xC8],
+        &[
+            0xF0, 0x7D, 0x83, 0x63, 0x42, 0x01, 0x43, 0x61, 0xA8, 0x55, 0x4A, 0x51, 0x7C, 0xE9,
+            0xC7, 0x06, 0x59, 0x59, 0x32, 0xCD, 0x2F, 0x55, 0xF1, 0xF1, 0x4D, 0x43, 0x5B, 0x60,
+            0xBD,
+        ],
+        &[
+            0xF8, 0x7E, 0x5C, 0xF4, 0x96, 0x2C, 0x33, 0x11, 0x1F, 0x84, 0x1B, 0xE6, 0x49, 0x71,
+            0xE2, 0xCD, 0x54, 0xE1, 0x28, 0x3E, 0x07, 0x4F, 0xFD, 0x19, 0x99, 0x35, 0xDD, 0x52,
+            0x26, 0xF6, 0x6F, 0x63, 0xFA, 0xD1, 0x2C, 0xBF, 0x4A, 0xD5, 0xF5, 0x73, 0xF4, 0xD9,
+            0xA2, 0x78, 0x1C, 0xAA, 0xFB, 0x65, 0x04, 0x98, 0x88, 0x04, 0xD1, 0xC8, 0x08, 0x12,
+            0x61, 0xC1, 0x7B, 0xB8, 0x19, 0xB6,
+        ],
+    ];
+    const TEST_RANGES_MONO: &[u32] = &[
+        0x014C_3B00,
+        0x074D_3500,
+        0x0084_43AD,
+        0x01BF_FC80,
+        0x21F2_3500,
+        0x031F_F100,
+        0x0108_2000,
+    ];
+
+    // Fullband, stereo, 20, 10, 5, 2.5, 5, 10 and 20 ms, with intensity and dual stereo.
+    const TEST_PACKETS_STEREO: &[&[u8]] = &[
+        &[
+            0xFC, 0x7E, 0x94, 0x86, 0x58, 0x64, 0x80, 0x85, 0xFF, 0xDE, 0x71, 0xD5, 0x0D, 0x11,
+            0x87, 0xB3, 0x44, 0x62, 0xE3, 0x47, 0x3E, 0xE0, 0x0C, 0x10, 0xDF, 0x3B, 0xB3, 0x57,
+            0xE7, 0xD5, 0x57, 0xC3, 0xB6, 0x97, 0xD2, 0x5A, 0x13, 0x21, 0xDB, 0x11, 0xAB, 0x76,
+            0xF4, 0x64, 0x8A, 0x0D, 0xFC, 0xB4, 0x53, 0x1A, 0xA9, 0x79, 0x17, 0xD4, 0x10, 0x1E,
+            0xB8, 0x96, 0xE4, 0xF7, 0xA5, 0xA8, 0xD0, 0xEF, 0x33, 0x06, 0xF9, 0xFB, 0x9E, 0xF1,
+            0xF2, 0x7D, 0x5D, 0x9A, 0xCF, 0x39, 0x7A, 0xB1, 0x11, 0x9F, 0x21, 0x9D, 0x0A, 0xBB,
+            0x3E, 0x18, 0xEE, 0x41, 0xEE, 0x1F, 0x78, 0x16, 0x6F, 0x33, 0xF0, 0xA9, 0x2A, 0xF2,
+            0x61, 0x1E, 0xAF, 0x8A, 0xE4, 0xA5, 0x3E, 0x81, 0x4F, 0x65, 0x7C, 0x8E, 0x28, 0xF0,
+            0x84, 0xC9, 0x09, 0xA0, 0xBF, 0x29, 0x96, 0x7B, 0xA6,
+        ],
+        &[
+            0xF4, 0x61, 0x23, 0x66, 0x72, 0xD8, 0xDA, 0x50, 0x84, 0xD0, 0xD4, 0x88, 0x2D, 0x51,
+            0x26, 0xDC, 0xAB, 0x57, 0x63, 0xE9, 0x9D, 0xE5, 0x0F, 0x49, 0xA8, 0xA8, 0x8B, 0xEE,
+            0x91, 0x02,
+        ],
+        &[
+            0xEC, 0x29, 0x87, 0xE6, 0x15, 0x02, 0x7D, 0x57, 0x9F, 0x4B, 0x01, 0xED,
+        ],
+        &[0xE4, 0x20, 0xAA],
+        &[
+            0xEC, 0x5D, 0xDF, 0x28, 0x1B, 0x93, 0x7B, 0x7D, 0x99, 0x95, 0xBF, 0xB6, 0xC7, 0xF9,
+            0x22, 0xF5, 0x94, 0xDC, 0xD5, 0x41, 0xF8,
+        ],
+        &[
+            0xF4, 0x02, 0x9F, 0xE5, 0xD3, 0x8A, 0x20, 0xD4, 0x3B, 0x59, 0xC4, 0x5E, 0xD4, 0x43,
+            0x36, 0xD9, 0xBB, 0xDC, 0xD2, 0x81, 0xD8, 0x61, 0xC2, 0x63, 0xA0, 0x74, 0x2B, 0x78,
+            0xE2, 0x2D, 0xBF, 0x8E, 0x32, 0x37, 0x12, 0x13,
+        ],
+        &[
+            0xFC, 0x53, 0xA6, 0x80, 0x87, 0x0A, 0x39, 0x6F, 0x4E, 0x5B, 0x23, 0x4F, 0x25, 0xED,
+            0x2E, 0xCC, 0x7A, 0x9C, 0x2C, 0x33, 0xA2, 0x4E, 0x23, 0x6C, 0x1D, 0xCC, 0xB1, 0x3E,
+            0xC5, 0xB8, 0x12, 0x17, 0x1F, 0x2B, 0x02, 0x8F, 0xC4, 0x6E, 0xA5, 0x8A, 0x29, 0xA8,
+            0x30, 0x7A, 0x58, 0x9F, 0xE6, 0x7A, 0x2B, 0x1F, 0x23, 0x00, 0x6D, 0x44, 0x2F, 0xC8,
+            0x80, 0x44, 0x44, 0x47, 0xC0, 0xF4, 0x9B, 0x18, 0xF9, 0xF2, 0xCE, 0x0D, 0x03, 0xC9,
+            0x2D, 0x5A, 0xF3, 0xFB,
+        ],
+    ];
+    const TEST_RANGES_STEREO: &[u32] = &[
+        0x08C4_6A00,
+        0x154C_20C0,
+        0x014A_CAE0,
+        0x01BF_FC80,
+        0x4CCD_9000,
+        0x01BD_3EC0,
+        0x0168_D300,
+    ];
+
+    const TEST_FRAME_SIZES: &[usize] = &[960, 480, 240, 120, 240, 480, 960];
+
+    fn new_decoder(channels: Channels) -> Decoder {
+        Decoder::new(&DecoderConfiguration {
+            sampling_rate: SamplingRate::Hz48000,
+            channels,
+            gain: 0,
+        })
+        .unwrap()
+    }
+
+    fn decode_and_check_range(channels: Channels, packets: &[&[u8]], ranges: &[u32]) {
+        let mut decoder = new_decoder(channels);
+        let mut samples = vec![0_f32; 960 * channels as usize];
+
+        packets
+            .iter()
+            .zip(ranges.iter())
+            .zip(TEST_FRAME_SIZES.iter())
+            .for_each(|((packet, &range), &frame_size)| {
+                let count = decoder
+                    .decode_float(
+                        Some(packet),
+                        &mut samples,
+                        NonZeroUsize::new(960).unwrap(),
+                        false,
+                    )
+                    .unwrap();
+                assert_eq!(count, frame_size);
+                assert_eq!(decoder.final_range(), range);
+                assert!(samples[..count * channels as usize]
+                    .iter()
+                    .any(|x| *x != 0.0));
+            });
+    }
+
+    #[test]
+    fn test_decode_mono() {
+        decode_and_check_range(Channels::Mono, TEST_PACKETS_MONO, TEST_RANGES_MONO);
+    }
+
+    #[test]
+    fn test_decode_stereo() {
+        decode_and_check_range(Channels::Stereo, TEST_PACKETS_STEREO, TEST_RANGES_STEREO);
+    }
+
+    #[test]
+    fn test_decode_lost_packet() {
+        let mut decoder = new_decoder(Channels::Mono);
+        let mut samples = vec![0_f32; 960];
+
+        TEST_PACKETS_MONO[..5].iter().for_each(|packet| {
+            decoder
+                .decode_float(
+                    Some(packet),
+                    &mut samples,
+                    NonZeroUsize::new(960).unwrap(),
+                    false,
+                )
+                .unwrap();
+        });
+
+        // Like libopus, the concealed frame reports a final range of 0.
+        let count = decoder
+            .decode_float(None, &mut samples, NonZeroUsize::new(960).unwrap(), false)
+            .unwrap();
+        assert_eq!(count, 960);
+        assert_eq!(decoder.final_range(), 0);
+        assert!(samples.iter().any(|x| *x != 0.0));
+
+        let count = decoder
+            .decode_float(
+                Some(TEST_PACKETS_MONO[6]),
+                &mut samples,
+                NonZeroUsize::new(960).unwrap(),
+                false,
+            )
+            .unwrap();
+        assert_eq!(count, 960);
+        assert_eq!(decoder.final_range(), TEST_RANGES_MONO[6]);
     }
 }
@@ -0,0 +1,2124 @@
+//! Implements the Celt encoder.
+
+use crate::celt::bands::{
+    compute_band_energies, haar1, hysteresis_decision, normalise_bands, quant_all_bands,
+    spreading_decision,
+};
+use crate::celt::mdct::Mdct;
+use crate::celt::mode::{
+    EFF_E_BANDS, E_BANDS, LOG_N, MAX_LM, NB_E_BANDS, OVERLAP, PREEMPH, SAMPLING_RATE,
+    SHORT_MDCT_SIZE, WINDOW,
+};
+use crate::celt::pitch::{pitch_downsample, pitch_search, remove_doubling};
+use crate::celt::quant_bands::{
+    amp2_log2, quant_coarse_energy, quant_energy_finalise, quant_fine_energy, E_MEANS,
+};
+use crate::celt::rate::{compute_allocation, init_caps};
+use crate::celt::vq::{inner_prod, EPSILON, SPREAD_AGGRESSIVE, SPREAD_NONE, SPREAD_NORMAL};
+use crate::celt::{
+    comb_filter, COMBFILTER_MAXPERIOD, COMBFILTER_MINPERIOD, SPREAD_ICDF, TAPSET_ICDF,
+    TF_SELECT_TABLE, TRIM_ICDF,
+};
+use crate::math::{fast_exp2, fast_log2, ilog};
+use crate::range_coder::{RangeCoder, RangeEncoder, Tell, BITRES};
+use crate::{Channels, OpusError, SamplingRate};
+
+/// Equivalent rates (in kb/s) above which the next band is coded with intensity stereo.
+const INTENSITY_THRESHOLDS: &[f32; 21] = &[
+    1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 16.0, 24.0, 36.0, 44.0, 50.0, 56.0, 62.0, 67.0, 72.0,
+    79.0, 88.0, 106.0, 134.0,
+];
+
+const INTENSITY_HYSTERESIS: &[f32; 21] = &[
+    1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 2.0, 2.0, 2.0, 2.0, 2.0, 2.0, 2.0, 3.0, 3.0, 4.0, 5.0, 6.0,
+    8.0, 8.0,
+];
+
+/// Table of 6*64/x, trained on real data to minimize the average error.
+const INV_TABLE: &[u8; 128] = &[
+    255, 255, 156, 110, 86, 70, 59, 51, 45, 40, 37, 33, 31, 28, 26, 25, 23, 22, 21, 20, 19, 18, 17,
+    16, 16, 15, 15, 14, 13, 13, 12, 12, 12, 12, 11, 11, 11, 10, 10, 10, 9, 9, 9, 9, 9, 9, 8, 8, 8,
+    8, 8, 7, 7, 7, 7, 7, 7, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 5, 5, 5, 5, 5, 5, 5, 5,
+    5, 5, 5, 5, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 3, 3, 3,
+    3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 2,
+];
+
+/// Result of the pitch pre-filter.
+struct Prefilter {
+    on: bool,
+    pitch_index: usize,
+    gain: f32,
+    qgain: u32,
+}
+
+/// The Celt encoder.
+#[derive(Clone, Debug)]
+pub(crate) struct CeltEncoder {
+    channels: usize,
+    stream_channels: usize,
+    upsample: usize,
+    // Startband
+    start: usize,
+    // Endband
+    end: usize,
+    force_intra: bool,
+    clip: bool,
+    disable_pf: bool,
+    complexity: i32,
+    /// `None` uses as many bits as are available.
+    bitrate: Option<i32>,
+    vbr: bool,
+    constrained_vbr: bool,
+    loss_rate: i32,
+    lsb_depth: i32,
+    disable_inv: bool,
+    /// Encodes a low frequency effects channel.
+    lfe: bool,
+    /// Masking of the bands by the other channels of a surround mix.
+    energy_mask: Option<[f32; 2 * NB_E_BANDS]>,
+
+    rng: u32,
+    spread_decision: u32,
+    delayed_intra: f32,
+    last_coded_bands: usize,
+    prefilter_period: usize,
+    prefilter_gain: f32,
+    prefilter_tapset: usize,
+    consec_transient: usize,
+    /// Signal type of the Silk layer in hybrid mode.
+    silk_signal_type: usize,
+    /// Quantization offset of the Silk layer in hybrid mode.
+    silk_offset: i32,
+    preemph_mem_e: [f32; 2],
+    vbr_reservoir: i32,
+    vbr_drift: i32,
+    vbr_offset: i32,
+    vbr_count: i32,
+    overlap_max: f32,
+    stereo_saving: f32,
+    intensity: usize,
+    spec_avg: f32,
+    tonal_average: i32,
+    hf_average: i32,
+    tapset_decision: usize,
+
+    /// Overlap of the last frame for each channel (OVERLAP per channel).
+    in_mem: Vec<f32>,
+    /// Input history of the pre-filter for each channel (COMBFILTER_MAXPERIOD per channel).
+    prefilter_mem: Vec<f32>,
+    old_band_e: [f32; 2 * NB_E_BANDS],
+    old_log_e: [f32; 2 * NB_E_BANDS],
+    old_log_e2: [f32; 2 * NB_E_BANDS],
+    energy_error: [f32; 2 * NB_E_BANDS],
+
+    mdct: Mdct,
+}
+
+impl CeltEncoder {
+    /// Creates a new Celt encoder.
+    pub(crate) fn new(sampling_rate: SamplingRate, channels: Channels) -> Result<Self, OpusError> {
+        let channels = channels as usize;
+        let mut enc = Self {
+            channels,
+            stream_channels: channels,
+            upsample: sampling_rate.resampling_factor() as usize,
+            start: 0,
+            end: EFF_E_BANDS,
+            force_intra: false,
+            clip: true,
+            disable_pf: false,
+            complexity: 5,
+            bitrate: None,
+            vbr: false,
+            constrained_vbr: true,
+            loss_rate: 0,
+            lsb_depth: 24,
+            disable_inv: false,
+            lfe: false,
+            energy_mask: None,
+            rng: 0,
+            spread_decision: SPREAD_NORMAL,
+            delayed_intra: 1.0,
+            last_coded_bands: 0,
+            prefilter_period: 0,
+            prefilter_gain: 0.0,
+            prefilter_tapset: 0,
+            consec_transient: 0,
+            silk_signal_type: 0,
+            silk_offset: 0,
+            preemph_mem_e: [0.0; 2],
+            vbr_reservoir: 0,
+            vbr_drift: 0,
+            vbr_offset: 0,
+            vbr_count: 0,
+            overlap_max: 0.0,
+            stereo_saving: 0.0,
+            intensity: 0,
+            spec_avg: 0.0,
+            tonal_average: 256,
+            hf_average: 0,
+            tapset_decision: 0,
+            in_mem: vec![0.0; channels * OVERLAP],
+            prefilter_mem: vec![0.0; channels * COMBFILTER_MAXPERIOD],
+            old_band_e: [0.0; 2 * NB_E_BANDS],
+            old_log_e: [0.0; 2 * NB_E_BANDS],
+            old_log_e2: [0.0; 2 * NB_E_BANDS],
+            energy_error: [0.0; 2 * NB_E_BANDS],
+            mdct: Mdct::default(),
+        };
+        enc.reset()?;
+
+        Ok(enc)
+    }
+
+    /// Resets the Celt encoder.
+    pub(crate) fn reset(&mut self) -> Result<(), OpusError> {
+        self.rng = 0;
+        self.spread_decision = SPREAD_NORMAL;
+        self.delayed_intra = 1.0;
+        self.last_coded_bands = 0;
+        self.prefilter_period = 0;
+        self.prefilter_gain = 0.0;
+        self.prefilter_tapset = 0;
+        self.consec_transient = 0;
+        self.silk_signal_type = 0;
+        self.silk_offset = 0;
+        self.preemph_mem_e = [0.0; 2];
+        self.vbr_reservoir = 0;
+        self.vbr_drift = 0;
+        self.vbr_offset = 0;
+        self.vbr_count = 0;
+        self.overlap_max = 0.0;
+        self.stereo_saving = 0.0;
+        self.intensity = 0;
+        self.spec_avg = 0.0;
+        self.tonal_average = 256;
+        self.hf_average = 0;
+        self.tapset_decision = 0;
+        self.in_mem.iter_mut().for_each(|x| *x = 0.0);
+        self.prefilter_mem.iter_mut().for_each(|x| *x = 0.0);
+        self.old_band_e = [0.0; 2 * NB_E_BANDS];
+        self.old_log_e = [-28.0; 2 * NB_E_BANDS];
+        self.old_log_e2 = [-28.0; 2 * NB_E_BANDS];
+        self.energy_error = [0.0; 2 * NB_E_BANDS];
+
+        Ok(())
+    }
+
+    /// Encodes a Celt frame into `compressed`.
+    ///
+    /// `pcm` contains `frame_size` interleaved samples per channel.
+    ///
+    /// Returns the number of bytes written.
+    pub(crate) fn encode(
+        &mut self,
+        pcm: &[f32],
+        frame_size: usize,
+        compressed: &mut [u8],
+    ) -> Result<usize, OpusError> {
+        let nb_compressed_bytes = usize::min(compressed.len(), 1275);
+        let mut enc = RangeEncoder::new(&mut compressed[..nb_compressed_bytes]);
+        self.encode_with_ec(pcm, frame_size, nb_compressed_bytes, &mut enc)
+    }
+
+    /// Encodes a Celt frame with the given range encoder, which may already
+    /// contain data of a previous coding layer.
+    ///
+    /// Returns the size of the frame in bytes.
+    pub(crate) fn encode_with_ec(
+        &mut self,
+        pcm: &[f32],
+        frame_size: usize,
+        nb_compressed_bytes: usize,
+        enc: &mut RangeEncoder,
+    ) -> Result<usize, OpusError> {
+        let cc = self.channels;
+        let c = self.stream_channels;
+        let start = self.start;
+        let end = self.end;
+        let hybrid = start != 0;
+
+        if nb_compressed_bytes < 2 {
+            return Err(OpusError::BadArguments(
+                "the output needs to be at least two bytes",
+            ));
+        }
+
+        let frame_size = frame_size * self.upsample;
+        let lm = (0..=MAX_LM)
+            .find(|lm| SHORT_MDCT_SIZE << lm == frame_size)
+            .ok_or(OpusError::BadArguments(
+                "frame_size is not a valid Celt frame size",
+            ))?;
+        let m = 1 << lm;
+        let n = m * SHORT_MDCT_SIZE;
+
+        let tell0_frac = enc.tell_frac() as i32;
+        let mut tell = enc.tell() as i32;
+        let nb_filled_bytes = ((tell + 4) >> 3) as usize;
+
+        // Can't produce more than 1275 output bytes.
+        let mut nb_compressed_bytes = usize::min(nb_compressed_bytes, 1275);
+        let mut nb_available_bytes = nb_compressed_bytes - nb_filled_bytes;
+
+        let vbr_rate;
+        let mut effective_bytes;
+        match self.bitrate {
+            Some(bitrate) if self.vbr => {
+                let den = (SAMPLING_RATE >> BITRES) as i32;
+                vbr_rate = (bitrate * frame_size as i32 + (den >> 1)) / den;
+                effective_bytes = (vbr_rate >> (3 + BITRES)) as usize;
+            }
+            bitrate => {
+                vbr_rate = 0;
+                if let Some(bitrate) = bitrate {
+                    let mut tmp = bitrate * frame_size as i32;
+                    if tell > 1 {
+                        tmp += tell;
+                    }
+                    let max_bytes = (tmp + 4 * SAMPLING_RATE as i32) / (8 * SAMPLING_RATE as i32);
+                    let max_bytes =
+                        usize::max(2, usize::min(nb_compressed_bytes, max_bytes as usize));
+                    if max_bytes < nb_compressed_bytes {
+                        nb_compressed_bytes = max_bytes;
+                        enc.shrink(nb_compressed_bytes);
+                    }
+                }
+                effective_bytes = nb_compressed_bytes - nb_filled_bytes;
+            }
+        }
+
+        let lm_bitrate_offset = (40 * c as i32 + 20) * ((400 >> lm) - 50);
+        let mut equiv_rate =
+            ((nb_compressed_bytes as i32 * 8 * 50) << (3 - lm)) - lm_bitrate_offset;
+        if let Some(bitrate) = self.bitrate {
+            equiv_rate = i32::min(equiv_rate, bitrate - lm_bitrate_offset);
+        }
+
+        if vbr_rate > 0 && self.constrained_vbr {
+            // Computes the max bit-rate allowed in VBR mode to avoid violating the
+            // target rate and buffering. We must do this up front so that bust-prevention
+            // logic triggers correctly if we don't have enough bits.
+            let vbr_bound = vbr_rate;
+            let max_allowed = i32::max(
+                if tell == 1 { 2 } else { 0 },
+                (vbr_rate + vbr_bound - self.vbr_reservoir) >> (BITRES + 3),
+            );
+            let max_allowed = usize::min(max_allowed as usize, nb_available_bytes);
+            if max_allowed < nb_available_bytes {
+                nb_compressed_bytes = nb_filled_bytes + max_allowed;
+                nb_available_bytes = max_allowed;
+                enc.shrink(nb_compressed_bytes);
+            }
+        }
+        let mut total_bits = nb_compressed_bytes as i32 * 8;
+
+        let eff_end = usize::min(end, EFF_E_BANDS);
+
+        let upsample = self.upsample;
+        let overlap_offset = c * (n - OVERLAP) / upsample;
+        let mut sample_max = f32::max(self.overlap_max, max_abs(&pcm[..overlap_offset]));
+        self.overlap_max = max_abs(&pcm[overlap_offset..overlap_offset + c * OVERLAP / upsample]);
+        sample_max = f32::max(sample_max, self.overlap_max);
+
+        let mut silence = sample_max <= 1.0 / (1 << self.lsb_depth) as f32;
+        if tell == 1 {
+            enc.encode_bit_logp(silence as u32, 15)?;
+        } else {
+            silence = false;
+        }
+        if silence {
+            // In VBR mode there is no need to send more than the minimum.
+            if vbr_rate > 0 {
+                nb_compressed_bytes = usize::min(nb_compressed_bytes, nb_filled_bytes + 2);
+                effective_bytes = nb_compressed_bytes;
+                total_bits = nb_compressed_bytes as i32 * 8;
+                nb_available_bytes = 2;
+                enc.shrink(nb_compressed_bytes);
+            }
+            // Pretend we've filled all the remaining bits with zeros
+            // (that's what the initialiser did anyway).
+            tell = nb_compressed_bytes as i32 * 8;
+            enc.skip_to(tell as u32);
+        }
+
+        let mut input = vec![0_f32; cc * (n + OVERLAP)];
+        let need_clip = self.clip && sample_max > 65536.0;
+        (0..cc).for_each(|ch| {
+            preemphasis(
+                &pcm[ch..],
+                &mut input[ch * (n + OVERLAP) + OVERLAP..],
+                n,
+                cc,
+                upsample,
+                &mut self.preemph_mem_e[ch],
+                need_clip,
+            );
+        });
+
+        // Find pitch period and gain.
+        let enabled = ((self.lfe && nb_available_bytes > 3) || nb_available_bytes > 12 * c)
+            && !hybrid
+            && !silence
+            && !self.disable_pf
+            && self.complexity >= 5;
+        let prefilter_tapset = self.tapset_decision;
+        let prefilter =
+            self.run_prefilter(&mut input, n, prefilter_tapset, enabled, nb_available_bytes);
+        let pitch_index = prefilter.pitch_index;
+        let gain1 = prefilter.gain;
+        if !prefilter.on {
+            if !hybrid && tell + 16 <= total_bits {
+                enc.encode_bit_logp(0, 1)?;
+            }
+        } else {
+            // This block is not gated by a total bits check only because
+            // of the nb_available_bytes check above.
+            enc.encode_bit_logp(1, 1)?;
+            let pitch = pitch_index as u32 + 1;
+            let octave = ilog(pitch) - 5;
+            enc.encode_uint(octave, 6)?;
+            enc.encode_bits(pitch - (16 << octave), 4 + octave)?;
+            enc.encode_bits(prefilter.qgain, 3)?;
+            enc.encode_icdf(prefilter_tapset, TAPSET_ICDF, 2)?;
+        }
+
+        let mut is_transient = false;
+        let mut short_blocks = 0;
+        let mut weak_transient = false;
+        let mut tf_estimate = 0.0;
+        let mut tf_chan = 0;
+        if self.complexity >= 1 && !self.lfe {
+            // Reduces the likelihood of energy instability on fricatives at low bitrate
+            // in hybrid mode. It seems like we still want to have real transients on vowels
+            // though (small Silk quantization offset value).
+            let allow_weak_transients =
+                hybrid && effective_bytes < 15 && self.silk_signal_type != 2;
+            let (transient, weak, estimate, chan) =
+                transient_analysis(&input, n + OVERLAP, cc, allow_weak_transients);
+            is_transient = transient;
+            weak_transient = weak;
+            tf_estimate = estimate;
+            tf_chan = chan;
+        }
+        let mut transient_got_disabled = false;
+        if lm > 0 && enc.tell() as i32 + 3 <= total_bits {
+            if is_transient {
+                short_blocks = m;
+            }
+        } else {
+            is_transient = false;
+            transient_got_disabled = true;
+        }
+
+        // Interleaved signal MDCTs.
+        let mut freq = vec![0_f32; cc * n];
+        let mut band_e = [0_f32; 2 * NB_E_BANDS];
+        let mut band_log_e = [0_f32; 2 * NB_E_BANDS];
+        let mut band_log_e2 = [0_f32; 2 * NB_E_BANDS];
+
+        let second_mdct = short_blocks != 0 && self.complexity >= 8;
+        if second_mdct {
+            self.compute_mdcts(0, &input, &mut freq, c, lm);
+            compute_band_energies(&freq, &mut band_e, eff_end, c, lm);
+            amp2_log2(eff_end, end, &band_e, &mut band_log_e2, c);
+            band_log_e2[..c * NB_E_BANDS]
+                .iter_mut()
+                .for_each(|x| *x += 0.5 * lm as f32);
+        }
+
+        self.compute_mdcts(short_blocks, &input, &mut freq, c, lm);
+        if cc == 2 && c == 1 {
+            tf_chan = 0;
+        }
+        compute_band_energies(&freq, &mut band_e, eff_end, c, lm);
+        if self.lfe {
+            (2..end).for_each(|i| {
+                band_e[i] = f32::max(f32::min(band_e[i], 1e-4 * band_e[0]), EPSILON);
+            });
+        }
+        amp2_log2(eff_end, end, &band_e, &mut band_log_e, c);
+
+        // This computes how much masking takes place between surround channels.
+        let mut surround_dynalloc = [0_f32; NB_E_BANDS];
+        let mut surround_masking = 0.0;
+        let mut surround_trim = 0.0;
+        let energy_mask = if hybrid || self.lfe {
+            None
+        } else {
+            self.energy_mask.as_ref()
+        };
+        if let Some(energy_mask) = energy_mask {
+            let (masking, trim) = surround_masking_analysis(
+                energy_mask,
+                c,
+                self.last_coded_bands,
+                &mut surround_dynalloc,
+            );
+            surround_masking = masking;
+            surround_trim = trim;
+        }
+
+        // Temporal VBR (but not for LFE).
+        let temporal_vbr = if self.lfe {
+            0.0
+        } else {
+            let offset = if short_blocks != 0 {
+                0.5 * lm as f32
+            } else {
+                0.0
+            };
+            let mut follow = -10.0_f32;
+            let mut frame_avg = 0.0;
+            (start..end).for_each(|i| {
+                follow = f32::max(follow - 1.0, band_log_e[i] - offset);
+                if c == 2 {
+                    follow = f32::max(follow, band_log_e[i + NB_E_BANDS] - offset);
+                }
+                frame_avg += follow;
+            });
+            frame_avg /= (end - start) as f32;
+            let temporal_vbr = (frame_avg - self.spec_avg).clamp(-1.5, 3.0);
+            self.spec_avg += 0.02 * temporal_vbr;
+            temporal_vbr
+        };
+
+        if !second_mdct {
+            band_log_e2[..c * NB_E_BANDS].copy_from_slice(&band_log_e[..c * NB_E_BANDS]);
+        }
+
+        // Last chance to catch any transient we might have missed in the
+        // time-domain analysis.
+        if lm > 0
+            && enc.tell() as i32 + 3 <= total_bits
+            && !is_transient
+            && self.complexity >= 5
+            && !self.lfe
+            && !hybrid
+            && patch_transient_decision(&band_log_e, &self.old_band_e, start, end, c)
+        {
+            is_transient = true;
+            short_blocks = m;
+            self.compute_mdcts(short_blocks, &input, &mut freq, c, lm);
+            compute_band_energies(&freq, &mut band_e, eff_end, c, lm);
+            amp2_log2(eff_end, end, &band_e, &mut band_log_e, c);
+            // Compensate for the scaling of short vs long mdcts.
+            band_log_e2[..c * NB_E_BANDS]
+                .iter_mut()
+                .for_each(|x| *x += 0.5 * lm as f32);
+            tf_estimate = 0.2;
+        }
+
+        if lm > 0 && enc.tell() as i32 + 3 <= total_bits {
+            enc.encode_bit_logp(is_transient as u32, 3)?;
+        }
+
+        // Interleaved normalised MDCTs.
+        let mut x = vec![0_f32; c * n];
+        normalise_bands(&freq, &mut x, &band_e, eff_end, c, m);
+
+        let enable_tf_analysis =
+            effective_bytes >= 15 * c && !hybrid && self.complexity >= 2 && !self.lfe;
+
+        let mut offsets = [0_i32; NB_E_BANDS];
+        let mut importance = [0_i32; NB_E_BANDS];
+        let mut spread_weight = [0_i32; NB_E_BANDS];
+        let (max_depth, tot_boost) = dynalloc_analysis(
+            &band_log_e,
+            &band_log_e2,
+            start,
+            end,
+            c,
+            &mut offsets,
+            self.lsb_depth,
+            is_transient,
+            self.vbr,
+            self.constrained_vbr,
+            lm,
+            effective_bytes as i32,
+            self.lfe,
+            &surround_dynalloc,
+            &mut importance,
+            &mut spread_weight,
+        );
+
+        let mut tf_res = [0_i32; NB_E_BANDS];
+        // Disable variable tf resolution for hybrid and at very low bitrate.
+        let tf_select = if enable_tf_analysis {
+            let lambda = i32::max(80, 20480 / effective_bytes as i32 + 2);
+            let tf_select = tf_analysis(
+                eff_end,
+                is_transient,
+                &mut tf_res,
+                lambda,
+                &x,
+                n,
+                lm,
+                tf_estimate,
+                tf_chan,
+                &importance,
+            );
+            (eff_end..end).for_each(|i| tf_res[i] = tf_res[eff_end - 1]);
+            tf_select
+        } else if hybrid && weak_transient {
+            // For weak transients, we rely on the fact that improving time resolution using
+            // TF on a long window is imperfect and will not result in an energy collapse at
+            // low bitrate.
+            tf_res[..end].iter_mut().for_each(|x| *x = 1);
+            0
+        } else if hybrid && effective_bytes < 15 && self.silk_signal_type != 2 {
+            // For low bitrate hybrid, we force temporal resolution to 5 ms rather than 2.5 ms.
+            tf_res[..end].iter_mut().for_each(|x| *x = 0);
+            is_transient as usize
+        } else {
+            tf_res[..end]
+                .iter_mut()
+                .for_each(|x| *x = is_transient as i32);
+            0
+        };
+
+        let mut error = [0_f32; 2 * NB_E_BANDS];
+        (0..c).for_each(|ch| {
+            (start..end).for_each(|i| {
+                // When the energy is stable, slightly bias energy quantization towards
+                // the previous error to make the gain more stable (a constant offset is
+                // better than fluctuations).
+                let idx = i + ch * NB_E_BANDS;
+                if (band_log_e[idx] - self.old_band_e[idx]).abs() < 2.0 {
+                    band_log_e[idx] -= self.energy_error[idx] * 0.25;
+                }
+            });
+        });
+        quant_coarse_energy(
+            start,
+            end,
+            eff_end,
+            &band_log_e,
+            &mut self.old_band_e,
+            total_bits as u32,
+            &mut error,
+            enc,
+            c,
+            lm,
+            nb_available_bytes,
+            self.force_intra,
+            &mut self.delayed_intra,
+            self.complexity >= 4,
+            self.loss_rate,
+            self.lfe,
+        )?;
+
+        tf_encode(start, end, is_transient, &mut tf_res, lm, tf_select, enc)?;
+
+        if enc.tell() as i32 + 4 <= total_bits {
+            if self.lfe {
+                self.tapset_decision = 0;
+                self.spread_decision = SPREAD_NORMAL;
+            } else if hybrid {
+                if self.complexity == 0 {
+                    self.spread_decision = SPREAD_NONE;
+                } else if is_transient {
+                    self.spread_decision = SPREAD_NORMAL;
+                } else {
+                    self.spread_decision = SPREAD_AGGRESSIVE;
+                }
+            } else if short_blocks != 0 || self.complexity < 3 || nb_available_bytes < 10 * c {
+                if self.complexity == 0 {
+                    self.spread_decision = SPREAD_NONE;
+                } else {
+                    self.spread_decision = SPREAD_NORMAL;
+                }
+            } else {
+                self.spread_decision = spreading_decision(
+                    &x,
+                    &mut self.tonal_average,
+                    self.spread_decision,
+                    &mut self.hf_average,
+                    &mut self.tapset_decision,
+                    prefilter.on && short_blocks == 0,
+                    eff_end,
+                    c,
+                    m,
+                    &spread_weight,
+                );
+            }
+            enc.encode_icdf(self.spread_decision as usize, SPREAD_ICDF, 5)?;
+        }
+
+        // For LFE, everything interesting is in the first band.
+        if self.lfe {
+            offsets[0] = i32::min(8, effective_bytes as i32 / 3);
+        }
+        let mut cap = [0_i32; NB_E_BANDS];
+        init_caps(&mut cap, lm as i32, c as i32);
+
+        let mut dynalloc_logp = 6;
+        total_bits <<= BITRES;
+        let mut total_boost = 0;
+        tell = enc.tell_frac() as i32;
+        (start..end).try_for_each(|i| {
+            let width = (c as i32 * i32::from(E_BANDS[i + 1] - E_BANDS[i])) << lm;
+            // quanta is 6 bits, but no more than 1 bit/sample
+            // and no less than 1/8 bit/sample.
+            let quanta = i32::min(width << BITRES, i32::max(6 << BITRES, width));
+            let mut dynalloc_loop_logp = dynalloc_logp;
+            let mut boost = 0;
+            let mut j = 0;
+            while tell + (dynalloc_loop_logp << BITRES) < total_bits - total_boost && boost < cap[i]
+            {
+                let flag = j < offsets[i];
+                enc.encode_bit_logp(flag as u32, dynalloc_loop_logp as u32)?;
+                tell = enc.tell_frac() as i32;
+                if !flag {
+                    break;
+                }
+                boost += quanta;
+                total_boost += quanta;
+                dynalloc_loop_logp = 1;
+                j += 1;
+            }
+            // Making dynalloc more likely.
+            if j > 0 {
+                dynalloc_logp = i32::max(2, dynalloc_logp - 1);
+            }
+            offsets[i] = boost;
+            Ok(())
+        })?;
+
+        let mut dual_stereo = false;
+        if c == 2 {
+            // Always use MS for 2.5 ms frames until we can do a better analysis.
+            if lm != 0 {
+                dual_stereo = stereo_analysis(&x, lm, n);
+            }
+
+            self.intensity = hysteresis_decision(
+                (equiv_rate / 1000) as f32,
+                INTENSITY_THRESHOLDS,
+                INTENSITY_HYSTERESIS,
+                self.intensity,
+            );
+            self.intensity = usize::min(end, usize::max(start, self.intensity));
+        }
+
+        let mut alloc_trim = 5;
+        if tell + (6 << BITRES) <= total_bits - total_boost {
+            if start > 0 || self.lfe {
+                self.stereo_saving = 0.0;
+                alloc_trim = 5;
+            } else {
+                alloc_trim = alloc_trim_analysis(
+                    &x,
+                    &band_log_e,
+                    end,
+                    lm,
+                    c,
+                    n,
+                    &mut self.stereo_saving,
+                    tf_estimate,
+                    self.intensity,
+                    surround_trim,
+                    equiv_rate,
+                );
+            }
+            enc.encode_icdf(alloc_trim as usize, TRIM_ICDF, 7)?;
+            tell = enc.tell_frac() as i32;
+        }
+
+        // Variable bitrate.
+        if vbr_rate > 0 {
+            let lm_diff = MAX_LM - lm;
+
+            // Don't attempt to use more than 510 kb/s, even for frames smaller than 20 ms.
+            // The Celt allocator will just not be able to use more than that anyway.
+            nb_compressed_bytes = usize::min(nb_compressed_bytes, 1275 >> (3 - lm));
+            let mut base_target = if !hybrid {
+                vbr_rate - ((40 * c as i32 + 20) << BITRES)
+            } else {
+                i32::max(0, vbr_rate - ((9 * c as i32 + 4) << BITRES))
+            };
+
+            if self.constrained_vbr {
+                base_target += self.vbr_offset >> lm_diff;
+            }
+
+            let mut target = if !hybrid {
+                compute_vbr(
+                    base_target,
+                    lm,
+                    equiv_rate,
+                    self.last_coded_bands,
+                    c,
+                    self.intensity,
+                    self.constrained_vbr,
+                    self.stereo_saving,
+                    tot_boost,
+                    tf_estimate,
+                    max_depth,
+                    self.lfe,
+                    self.energy_mask.is_some(),
+                    surround_masking,
+                    temporal_vbr,
+                )
+            } else {
+                let mut target = base_target;
+                // Tonal frames (offset < 100) need more bits than noisy (offset > 100) ones.
+                if self.silk_offset < 100 {
+                    target += 12 << BITRES >> (3 - lm);
+                }
+                if self.silk_offset > 100 {
+                    target -= 18 << BITRES >> (3 - lm);
+                }
+                // Boosting bitrate on transients and vowels with significant temporal spikes.
+                target += ((tf_estimate - 0.25) * (50 << BITRES) as f32) as i32;
+                // If we have a strong transient, let's make sure it has enough bits to code
+                // the first two bands, so that it can use folding rather than noise.
+                if tf_estimate > 0.7 {
+                    target = i32::max(target, 50 << BITRES);
+                }
+                target
+            };
+
+            // The current offset is removed from the target and the space used
+            // so far is added.
+            target += tell;
+            // In VBR mode the frame size must not be reduced so much that it would
+            // result in the encoder running out of bits.
+            // The margin of 2 bytes ensures that none of the bust-prevention logic
+            // in the decoder will have triggered so far.
+            let mut min_allowed =
+                ((tell + total_boost + (1 << (BITRES + 3)) - 1) >> (BITRES + 3)) + 2;
+            // Take into account the 37 bits we need to have left in the packet to
+            // signal a redundant frame in hybrid mode. Creating a shorter packet would
+            // create an entropy coder desync.
+            if hybrid {
+                min_allowed = i32::max(
+                    min_allowed,
+                    (tell0_frac + (37 << BITRES) + total_boost + (1 << (BITRES + 3)) - 1)
+                        >> (BITRES + 3),
+                );
+            }
+
+            let mut nb_available = (target + (1 << (BITRES + 2))) >> (BITRES + 3);
+            nb_available = i32::max(min_allowed, nb_available);
+            nb_available = i32::min(nb_compressed_bytes as i32, nb_available);
+
+            // By how much did we "miss" the target on that frame.
+            let mut delta = target - vbr_rate;
+
+            target = nb_available << (BITRES + 3);
+
+            // If the frame is silent we don't adjust our drift, otherwise
+            // the encoder will shoot to very high rates after hitting a
+            // span of silence, but we do allow the bitres to refill.
+            // This means that we'll undershoot our target in CVBR/VBR modes
+            // on files with lots of silence.
+            if silence {
+                nb_available = 2;
+                target = (2 * 8) << BITRES;
+                delta = 0;
+            }
+
+            let alpha = if self.vbr_count < 970 {
+                self.vbr_count += 1;
+                1.0 / (self.vbr_count + 20) as f32
+            } else {
+                0.001
+            };
+            // How many bits have we used in excess of what we're allowed.
+            if self.constrained_vbr {
+                self.vbr_reservoir += target - vbr_rate;
+            }
+
+            // Compute the offset we need to apply in order to reach the target.
+            if self.constrained_vbr {
+                self.vbr_drift += (alpha
+                    * ((delta * (1 << lm_diff)) - self.vbr_offset - self.vbr_drift) as f32)
+                    as i32;
+                self.vbr_offset = -self.vbr_drift;
+            }
+
+            if self.constrained_vbr && self.vbr_reservoir < 0 {
+                // We're under the min value -- increase rate unless we're just coding silence.
+                let adjust = (-self.vbr_reservoir) / (8 << BITRES);
+                if !silence {
+                    nb_available += adjust;
+                }
+                self.vbr_reservoir = 0;
+            }
+            nb_compressed_bytes = usize::min(nb_compressed_bytes, nb_available as usize);
+            // This moves the raw bits to take into account the new compressed size.
+            enc.shrink(nb_compressed_bytes);
+        }
+
+        // Bit allocation.
+        let mut bits = ((nb_compressed_bytes as i32 * 8) << BITRES) - enc.tell_frac() as i32 - 1;
+        let anti_collapse_rsv = if is_transient && lm >= 2 && bits >= (lm as i32 + 2) << BITRES {
+            1 << BITRES
+        } else {
+            0
+        };
+        bits -= anti_collapse_rsv;
+        let signal_bandwidth = if self.lfe { 1 } else { end - 1 };
+
+        let allocation = compute_allocation(
+            start,
+            end,
+            &offsets,
+            &cap,
+            alloc_trim,
+            self.intensity,
+            dual_stereo,
+            bits,
+            c as i32,
+            lm as i32,
+            &mut RangeCoder::Encoder(enc),
+            self.last_coded_bands,
+            signal_bandwidth,
+        )?;
+        self.intensity = allocation.intensity;
+        if self.last_coded_bands != 0 {
+            self.last_coded_bands = usize::min(
+                self.last_coded_bands + 1,
+                usize::max(self.last_coded_bands - 1, allocation.coded_bands),
+            );
+        } else {
+            self.last_coded_bands = allocation.coded_bands;
+        }
+
+        quant_fine_energy(
+            start,
+            end,
+            &mut self.old_band_e,
+            &mut error,
+            &allocation.fine_quant,
+            enc,
+            c,
+        )?;
+
+        // Residual quantisation.
+        let mut collapse_masks = [0_u8; 2 * NB_E_BANDS];
+        {
+            let (x, y) = x.split_at_mut(n);
+            let y = if c == 2 { Some(y) } else { None };
+            quant_all_bands(
+                start,
+                end,
+                x,
+                y,
+                &mut collapse_masks,
+                &band_e,
+                &allocation.pulses,
+                short_blocks != 0,
+                self.spread_decision,
+                allocation.dual_stereo,
+                self.intensity,
+                &tf_res,
+                nb_compressed_bytes as i32 * (8 << BITRES) - anti_collapse_rsv,
+                allocation.balance,
+                &mut RangeCoder::Encoder(enc),
+                lm,
+                allocation.coded_bands,
+                &mut self.rng,
+                self.complexity,
+                self.disable_inv,
+            )?;
+        }
+
+        if anti_collapse_rsv > 0 {
+            let anti_collapse_on = self.consec_transient < 2;
+            enc.encode_bits(anti_collapse_on as u32, 1)?;
+        }
+
+        quant_energy_finalise(
+            start,
+            end,
+            &mut self.old_band_e,
+            &mut error,
+            &allocation.fine_quant,
+            &allocation.fine_priority,
+            nb_compressed_bytes as i32 * 8 - enc.tell() as i32,
+            enc,
+            c,
+        )?;
+        self.energy_error = [0.0; 2 * NB_E_BANDS];
+        (0..c).for_each(|ch| {
+            (start..end).for_each(|i| {
+                let idx = i + ch * NB_E_BANDS;
+                self.energy_error[idx] = error[idx].clamp(-0.5, 0.5);
+            });
+        });
+
+        if silence {
+            self.old_band_e[..c * NB_E_BANDS]
+                .iter_mut()
+                .for_each(|x| *x = -28.0);
+        }
+
+        self.prefilter_period = pitch_index;
+        self.prefilter_gain = gain1;
+        self.prefilter_tapset = prefilter_tapset;
+
+        if cc == 2 && c == 1 {
+            self.old_band_e.copy_within(0..NB_E_BANDS, NB_E_BANDS);
+        }
+
+        if !is_transient {
+            self.old_log_e2 = self.old_log_e;
+            self.old_log_e = self.old_band_e;
+        } else {
+            self.old_log_e
+                .iter_mut()
+                .zip(self.old_band_e.iter())
+                .for_each(|(log_e, old)| *log_e = f32::min(*log_e, *old));
+        }
+
+        // In case start or end were to change.
+        (0..cc).for_each(|ch| {
+            (0..start).chain(end..NB_E_BANDS).for_each(|i| {
+                self.old_band_e[ch * NB_E_BANDS + i] = 0.0;
+                self.old_log_e[ch * NB_E_BANDS + i] = -28.0;
+                self.old_log_e2[ch * NB_E_BANDS + i] = -28.0;
+            });
+        });
+
+        if is_transient || transient_got_disabled {
+            self.consec_transient += 1;
+        } else {
+            self.consec_transient = 0;
+        }
+        self.rng = enc.range();
+
+        // If there's any room left (can only happen for very high rates),
+        // it's already filled with zeros.
+        enc.done()?;
+
+        Ok(nb_compressed_bytes)
+    }
+
+    /// Searches the pitch of the frame, applies the pre-filter (the inverse of
+    /// the decoder's post-filter) and updates the filter memories.
+    fn run_prefilter(
+        &mut self,
+        input: &mut [f32],
+        n: usize,
+        prefilter_tapset: usize,
+        enabled: bool,
+        nb_available_bytes: usize,
+    ) -> Prefilter {
+        let cc = self.channels;
+        let mut pre = vec![0_f32; cc * (n + COMBFILTER_MAXPERIOD)];
+        (0..cc).for_each(|c| {
+            let pre = &mut pre[c * (n + COMBFILTER_MAXPERIOD)..];
+            pre[..COMBFILTER_MAXPERIOD].copy_from_slice(
+                &self.prefilter_mem[c * COMBFILTER_MAXPERIOD..(c + 1) * COMBFILTER_MAXPERIOD],
+            );
+            pre[COMBFILTER_MAXPERIOD..COMBFILTER_MAXPERIOD + n].copy_from_slice(
+                &input[c * (n + OVERLAP) + OVERLAP..c * (n + OVERLAP) + OVERLAP + n],
+            );
+        });
+
+        let mut pitch_index;
+        let mut gain1;
+        if enabled {
+            let mut pitch_buf = vec![0_f32; (COMBFILTER_MAXPERIOD + n) >> 1];
+
+            pitch_downsample(&pre, &mut pitch_buf, COMBFILTER_MAXPERIOD + n, cc);
+            // Don't search the last 1.5 octaves of the range because
+            // there are too many false-positives due to short-term correlation.
+            pitch_index = pitch_search(
+                &pitch_buf[COMBFILTER_MAXPERIOD >> 1..],
+                &pitch_buf,
+                n,
+                COMBFILTER_MAXPERIOD - 3 * COMBFILTER_MINPERIOD,
+            );
+            pitch_index = COMBFILTER_MAXPERIOD - pitch_index;
+
+            gain1 = remove_doubling(
+                &pitch_buf,
+                COMBFILTER_MAXPERIOD,
+                COMBFILTER_MINPERIOD,
+                n,
+                &mut pitch_index,
+                self.prefilter_period,
+                self.prefilter_gain,
+            );
+            if pitch_index > COMBFILTER_MAXPERIOD - 2 {
+                pitch_index = COMBFILTER_MAXPERIOD - 2;
+            }
+            gain1 *= 0.7;
+            if self.loss_rate > 2 {
+                gain1 *= 0.5;
+            }
+            if self.loss_rate > 4 {
+                gain1 *= 0.5;
+            }
+            if self.loss_rate > 8 {
+                gain1 = 0.0;
+            }
+        } else {
+            gain1 = 0.0;
+            pitch_index = COMBFILTER_MINPERIOD;
+        }
+
+        // Gain threshold for enabling the prefilter/postfilter.
+        let mut pf_threshold = 0.2_f32;
+
+        // Adjusting the threshold based on rate and continuity.
+        if (pitch_index as i32 - self.prefilter_period as i32).abs() * 10 > pitch_index as i32 {
+            pf_threshold += 0.2;
+        }
+        if nb_available_bytes < 25 {
+            pf_threshold += 0.1;
+        }
+        if nb_available_bytes < 35 {
+            pf_threshold += 0.1;
+        }
+        if self.prefilter_gain > 0.4 {
+            pf_threshold -= 0.1;
+        }
+        if self.prefilter_gain > 0.55 {
+            pf_threshold -= 0.1;
+        }
+
+        // Hard threshold at 0.2.
+        pf_threshold = f32::max(pf_threshold, 0.2);
+        let on;
+        let qgain;
+        if gain1 < pf_threshold {
+            gain1 = 0.0;
+            on = false;
+            qgain = 0;
+        } else {
+            // This block is not gated by a total bits check only because
+            // of the nb_available_bytes check above.
+            if (gain1 - self.prefilter_gain).abs() < 0.1 {
+                gain1 = self.prefilter_gain;
+            }
+
+            let qg = (0.5 + gain1 * 32.0 / 3.0).floor() as i32 - 1;
+            qgain = qg.clamp(0, 7) as u32;
+            gain1 = 0.09375 * (qgain + 1) as f32;
+            on = true;
+        }
+
+        (0..cc).for_each(|c| {
+            let input = &mut input[c * (n + OVERLAP)..(c + 1) * (n + OVERLAP)];
+            let pre = &pre[c * (n + COMBFILTER_MAXPERIOD)..(c + 1) * (n + COMBFILTER_MAXPERIOD)];
+            let prefilter_mem =
+                &mut self.prefilter_mem[c * COMBFILTER_MAXPERIOD..(c + 1) * COMBFILTER_MAXPERIOD];
+
+            self.prefilter_period = usize::max(self.prefilter_period, COMBFILTER_MINPERIOD);
+            input[..OVERLAP].copy_from_slice(&self.in_mem[c * OVERLAP..(c + 1) * OVERLAP]);
+            comb_filter(
+                input,
+                OVERLAP,
+                pre,
+                COMBFILTER_MAXPERIOD,
+                self.prefilter_period,
+                pitch_index,
+                n,
+                -self.prefilter_gain,
+                -gain1,
+                self.prefilter_tapset,
+                prefilter_tapset,
+                OVERLAP,
+            );
+            self.in_mem[c * OVERLAP..(c + 1) * OVERLAP].copy_from_slice(&input[n..n + OVERLAP]);
+
+            if n > COMBFILTER_MAXPERIOD {
+                prefilter_mem.copy_from_slice(&pre[n..n + COMBFILTER_MAXPERIOD]);
+            } else {
+                prefilter_mem.copy_within(n.., 0);
+                prefilter_mem[COMBFILTER_MAXPERIOD - n..]
+                    .copy_from_slice(&pre[COMBFILTER_MAXPERIOD..COMBFILTER_MAXPERIOD + n]);
+            }
+        });
+
+        Prefilter {
+            on,
+            pitch_index,
+            gain: gain1,
+            qgain,
+        }
+    }
+
+    /// Transforms the pre-emphasized input into the MDCT domain.
+    ///
+    /// The sub-frames of short blocks are interleaved. A stereo input is
+    /// downmixed if only one channel is coded.
+    fn compute_mdcts(
+        &mut self,
+        short_blocks: usize,
+        input: &[f32],
+        out: &mut [f32],
+        c: usize,
+        lm: usize,
+    ) {
+        let cc = self.channels;
+        let (b, n, shift) = if short_blocks != 0 {
+            (short_blocks, SHORT_MDCT_SIZE, MAX_LM)
+        } else {
+            (1, SHORT_MDCT_SIZE << lm, MAX_LM - lm)
+        };
+
+        let mdct = &mut self.mdct;
+        (0..cc).for_each(|ch| {
+            (0..b).for_each(|b_i| {
+                // Interleaving the sub-frames while doing the MDCTs.
+                mdct.forward(
+                    &input[ch * (b * n + OVERLAP) + b_i * n..],
+                    &mut out[b_i + ch * n * b..],
+                    WINDOW,
+                    OVERLAP,
+                    shift,
+                    b,
+                );
+            });
+        });
+
+        if cc == 2 && c == 1 {
+            let (out, out2) = out.split_at_mut(b * n);
+            out.iter_mut()
+                .zip(out2.iter())
+                .for_each(|(x, y)| *x = (0.5 * *x) + (0.5 * *y));
+        }
+
+        if self.upsample != 1 {
+            let upsample = self.upsample;
+            (0..c).for_each(|ch| {
+                let bound = b * n / upsample;
+                let out = &mut out[ch * b * n..(ch + 1) * b * n];
+                out[..bound].iter_mut().for_each(|x| *x *= upsample as f32);
+                out[bound..].iter_mut().for_each(|x| *x = 0.0);
+            });
+        }
+    }
+
+    /// Get the final range.
+    pub(crate) fn final_range(&self) -> u32 {
+        self.rng
+    }
+
+    /// Sets the end band.
+    pub(crate) fn set_end_band(&mut self, end_band: usize) {
+        self.end = end_band;
+    }
+
+    /// Sets the start band.
+    pub(crate) fn set_start_band(&mut self, start_band: usize) {
+        self.start = start_band;
+    }
+
+    /// Sets the stream channels.
+    pub(crate) fn set_stream_channels(&mut self, channels: Channels) {
+        self.stream_channels = channels as usize;
+    }
+
+    /// Sets the target bitrate. `None` uses all available bytes.
+    pub(crate) fn set_bitrate(&mut self, bitrate: Option<i32>) {
+        self.bitrate = bitrate.map(|bitrate| i32::min(bitrate, 260000 * self.channels as i32));
+    }
+
+    /// Enables or disables the variable bitrate.
+    pub(crate) fn set_vbr(&mut self, vbr: bool) {
+        self.vbr = vbr;
+    }
+
+    /// Enables or disables the constraint of the variable bitrate.
+    pub(crate) fn set_vbr_constraint(&mut self, constrained_vbr: bool) {
+        self.constrained_vbr = constrained_vbr;
+    }
+
+    /// Sets the complexity (0-10).
+    pub(crate) fn set_complexity(&mut self, complexity: i32) {
+        self.complexity = complexity;
+    }
+
+    /// Sets the depth of the input signal in bits.
+    pub(crate) fn set_lsb_depth(&mut self, lsb_depth: i32) {
+        self.lsb_depth = lsb_depth;
+    }
+
+    /// Sets the expected packet loss in percent.
+    pub(crate) fn set_packet_loss(&mut self, loss_rate: i32) {
+        self.loss_rate = loss_rate;
+    }
+
+    /// Sets the signal type and quantization offset of the Silk layer in hybrid mode.
+    pub(crate) fn set_silk_info(&mut self, signal_type: usize, offset: i32) {
+        self.silk_signal_type = signal_type;
+        self.silk_offset = offset;
+    }
+
+    /// Enables or disables the coding of a low frequency effects channel.
+    pub(crate) fn set_lfe(&mut self, lfe: bool) {
+        self.lfe = lfe;
+    }
+
+    /// Sets the masking of the bands by the other channels of a surround mix.
+    ///
+    /// The mask contains `NB_E_BANDS` values per coded channel.
+    pub(crate) fn set_energy_mask(&mut self, energy_mask: Option<&[f32]>) {
+        self.energy_mask = energy_mask.map(|energy_mask| {
+            let mut mask = [0_f32; 2 * NB_E_BANDS];
+            let len = usize::min(energy_mask.len(), mask.len());
+            mask[..len].copy_from_slice(&energy_mask[..len]);
+            mask
+        });
+    }
+
+    /// Sets how much the encoder may rely on previous frames.
+    ///
+    /// 0 disables the pre-filter and the inter-frame prediction of the energy,
+    /// 1 disables only the pre-filter and 2 allows both.
+    pub(crate) fn set_prediction(&mut self, prediction: i32) {
+        self.disable_pf = prediction <= 1;
+        self.force_intra = prediction == 0;
+    }
+}
+
+/// Returns the largest absolute value of the samples.
+fn max_abs(x: &[f32]) -> f32 {
+    x.iter().fold(0.0, |max, x| f32::max(max, x.abs()))
+}
+
+/// Scales the interleaved input and applies the pre-emphasis filter.
+pub(crate) fn preemphasis(
+    pcm: &[f32],
+    input: &mut [f32],
+    n: usize,
+    cc: usize,
+    upsample: usize,
+    mem: &mut f32,
+    clip: bool,
+) {
+    let coef0 = PREEMPH[0];
+    let nu = n / upsample;
+
+    if upsample != 1 {
+        input[..n].iter_mut().for_each(|x| *x = 0.0);
+    }
+    (0..nu).for_each(|i| {
+        input[i * upsample] = pcm[cc * i] * 32768.0;
+    });
+
+    if clip {
+        // Clip input to avoid encoding non-portable files.
+        (0..nu).for_each(|i| {
+            input[i * upsample] = input[i * upsample].clamp(-65536.0, 65536.0);
+        });
+    }
+
+    let mut m = *mem;
+    input[..n].iter_mut().for_each(|x| {
+        let tmp = *x;
+        *x = tmp - m;
+        m = coef0 * tmp;
+    });
+    *mem = m;
+}
+
+/// Detects transients in the time domain with a simple masking model.
+///
+/// Returns whether the frame is transient, the transient estimate used to boost
+/// VBR and the channel with the strongest transient.
+fn transient_analysis(
+    input: &[f32],
+    len: usize,
+    channels: usize,
+    allow_weak_transients: bool,
+) -> (bool, bool, f32, usize) {
+    // Forward masking: 6.7 dB/ms.
+    //
+    // For lower bitrates, let's be more conservative and have a forward masking
+    // decay of 3.3 dB/ms. This avoids having to code transients at very low
+    // bitrate (mostly for hybrid), which can result in unstable energy and/or
+    // partial collapse.
+    let forward_decay = if allow_weak_transients {
+        0.03125
+    } else {
+        0.0625
+    };
+
+    let len2 = len / 2;
+    let mut tmp = vec![0_f32; len];
+    let mut mask_metric = 0;
+    let mut tf_chan = 0;
+    (0..channels).for_each(|c| {
+        // High-pass filter: (1 - 2*z^-1 + z^-2) / (1 - z^-1 + .5*z^-2).
+        let mut mem0 = 0.0;
+        let mut mem1 = 0.0;
+        tmp.iter_mut()
+            .zip(input[c * len..(c + 1) * len].iter())
+            .for_each(|(tmp, &x)| {
+                let y = mem0 + x;
+                mem0 = mem1 + y - 2.0 * x;
+                mem1 = x - 0.5 * y;
+                *tmp = y;
+            });
+        // First few samples are bad because we don't propagate the memory.
+        tmp[..12].iter_mut().for_each(|x| *x = 0.0);
+
+        // Grouping by two to reduce complexity.
+        // Forward pass to compute the post-echo threshold.
+        let mut mean = 0.0_f32;
+        let mut mem0 = 0.0;
+        (0..len2).for_each(|i| {
+            let x2 = (tmp[2 * i] * tmp[2 * i]) + (tmp[2 * i + 1] * tmp[2 * i + 1]);
+            mean += x2;
+            tmp[i] = mem0 + forward_decay * (x2 - mem0);
+            mem0 = tmp[i];
+        });
+
+        // Backward pass to compute the pre-echo threshold.
+        // Backward masking: 13.9 dB/ms.
+        let mut mem0 = 0.0;
+        let mut max_e = 0.0_f32;
+        (0..len2).rev().for_each(|i| {
+            tmp[i] = mem0 + 0.125 * (tmp[i] - mem0);
+            mem0 = tmp[i];
+            max_e = f32::max(max_e, mem0);
+        });
+
+        // Compute the ratio of the "frame energy" over the harmonic mean of the energy.
+        // This essentially corresponds to a bitrate-normalized temporal noise-to-mask
+        // ratio. As a compromise with the old transient detector, frame energy is the
+        // geometric mean of the energy and half the max.
+        let mean = (f64::from(mean * max_e) * 0.5 * len2 as f64).sqrt() as f32;
+        // Inverse of the mean energy.
+        let norm = len2 as f32 / (EPSILON + mean);
+
+        // Compute harmonic mean discarding the unreliable boundaries.
+        // The data is smooth, so we only take 1/4th of the samples.
+        let mut unmask = 0;
+        (12..len2 - 5).step_by(4).for_each(|i| {
+            // Do not round to nearest.
+            let id = (64.0 * norm * (tmp[i] + EPSILON)).floor().clamp(0.0, 127.0) as usize;
+            unmask += i32::from(INV_TABLE[id]);
+        });
+        // Normalize, compensate for the 1/4th of the sample and the factor of 6 in
+        // the inverse table.
+        let unmask = 64 * unmask * 4 / (6 * (len2 as i32 - 17));
+        if unmask > mask_metric {
+            tf_chan = c;
+            mask_metric = unmask;
+        }
+    });
+
+    let mut is_transient = mask_metric > 200;
+    let mut weak_transient = false;
+    // For low bitrates, define "weak transients" that need to be
+    // handled differently to avoid partial collapse.
+    if allow_weak_transients && is_transient && mask_metric < 600 {
+        is_transient = false;
+        weak_transient = true;
+    }
+
+    // Arbitrary metric for VBR boost.
+    let tf_max = f32::max(0.0, f64::from(27 * mask_metric).sqrt() as f32 - 42.0);
+    let tf_estimate =
+        f64::max(0.0, f64::from(0.0069_f32 * f32::min(163.0, tf_max)) - 0.139).sqrt() as f32;
+
+    (is_transient, weak_transient, tf_estimate, tf_chan)
+}
+
+/// Looks for sudden increases of energy to decide whether we need to patch
+/// the transient decision.
+fn patch_transient_decision(
+    new_e: &[f32],
+    old_e: &[f32],
+    start: usize,
+    end: usize,
+    channels: usize,
+) -> bool {
+    // Apply an aggressive (-6 dB/Bark) spreading function to the old frame to
+    // avoid false detection caused by irrelevant bands.
+    let mut spread_old = [0_f32; NB_E_BANDS];
+    let old = |i: usize| {
+        if channels == 1 {
+            old_e[i]
+        } else {
+            f32::max(old_e[i], old_e[i + NB_E_BANDS])
+        }
+    };
+    spread_old[start] = old(start);
+    (start + 1..end).for_each(|i| {
+        spread_old[i] = f32::max(spread_old[i - 1] - 1.0, old(i));
+    });
+    (start..end - 1).rev().for_each(|i| {
+        spread_old[i] = f32::max(spread_old[i], spread_old[i + 1] - 1.0);
+    });
+
+    // Compute mean increase.
+    let first = usize::max(2, start);
+    let mut mean_diff = 0.0;
+    (0..channels).for_each(|c| {
+        (first..end - 1).for_each(|i| {
+            let x1 = f32::max(0.0, new_e[i + c * NB_E_BANDS]);
+            let x2 = f32::max(0.0, spread_old[i]);
+            mean_diff += f32::max(0.0, x1 - x2);
+        });
+    });
+    mean_diff /= (channels * (end - 1 - first)) as f32;
+
+    mean_diff > 1.0
+}
+
+/// L1 norm of the band, biased towards a good frequency resolution.
+fn l1_metric(tmp: &[f32], n: usize, lm: usize, bias: f32) -> f32 {
+    let l1 = tmp[..n].iter().fold(0.0, |l1, x| l1 + x.abs());
+    // When in doubt, prefer good freq resolution.
+    l1 + (lm as f32 * bias) * l1
+}
+
+/// Decides the time-frequency resolution of each band.
+///
+/// Returns the tf_select decision and writes the per band changes into `tf_res`.
+#[allow(clippy::too_many_arguments)]
+fn tf_analysis(
+    len: usize,
+    is_transient: bool,
+    tf_res: &mut [i32; NB_E_BANDS],
+    lambda: i32,
+    x: &[f32],
+    n0: usize,
+    lm: usize,
+    tf_estimate: f32,
+    tf_chan: usize,
+    importance: &[i32; NB_E_BANDS],
+) -> usize {
+    let bias = 0.04 * f32::max(-0.25, 0.5 - tf_estimate);
+
+    let mut metric = [0_i32; NB_E_BANDS];
+    let mut tmp = vec![0_f32; usize::from(E_BANDS[len] - E_BANDS[len - 1]) << lm];
+    let mut tmp_1 = vec![0_f32; tmp.len()];
+    (0..len).for_each(|i| {
+        let n = usize::from(E_BANDS[i + 1] - E_BANDS[i]) << lm;
+        // Band is too narrow to be split down to LM=-1.
+        let narrow = (E_BANDS[i + 1] - E_BANDS[i]) == 1;
+        let offset = tf_chan * n0 + (usize::from(E_BANDS[i]) << lm);
+        tmp[..n].copy_from_slice(&x[offset..offset + n]);
+
+        let mut l1 = l1_metric(&tmp, n, if is_transient { lm } else { 0 }, bias);
+        let mut best_l1 = l1;
+        let mut best_level = 0;
+        // Check the -1 case for transients.
+        if is_transient && !narrow {
+            tmp_1[..n].copy_from_slice(&tmp[..n]);
+            haar1(&mut tmp_1, n >> lm, 1 << lm);
+            l1 = l1_metric(&tmp_1, n, lm + 1, bias);
+            if l1 < best_l1 {
+                best_l1 = l1;
+                best_level = -1;
+            }
+        }
+
+        let levels = lm + !(is_transient || narrow) as usize;
+        (0..levels).for_each(|k| {
+            let b = if is_transient { lm - k - 1 } else { k + 1 };
+            haar1(&mut tmp, n >> k, 1 << k);
+            l1 = l1_metric(&tmp, n, b, bias);
+            if l1 < best_l1 {
+                best_l1 = l1;
+                best_level = k as i32 + 1;
+            }
+        });
+
+        // Metric is in Q1 to be able to select the mid-point (-0.5) for narrower bands.
+        metric[i] = if is_transient {
+            2 * best_level
+        } else {
+            -2 * best_level
+        };
+        // For bands that can't be split to -1, set the metric to the half-way point to avoid
+        // biasing the decision.
+        if narrow && (metric[i] == 0 || metric[i] == -2 * lm as i32) {
+            metric[i] -= 1;
+        }
+    });
+
+    // Search for the optimal tf resolution, including tf_select.
+    let table = &TF_SELECT_TABLE[lm];
+    let offset = 4 * is_transient as usize;
+    let target = |sel: usize, tf: usize| 2 * i32::from(table[offset + 2 * sel + tf]);
+    let transient_lambda = if is_transient { 0 } else { lambda };
+
+    let mut selcost = [0_i32; 2];
+    (0..2).for_each(|sel| {
+        let mut cost0 = importance[0] * (metric[0] - target(sel, 0)).abs();
+        let mut cost1 = importance[0] * (metric[0] - target(sel, 1)).abs() + transient_lambda;
+        (1..len).for_each(|i| {
+            let curr0 = i32::min(cost0, cost1 + lambda);
+            let curr1 = i32::min(cost0 + lambda, cost1);
+            cost0 = curr0 + importance[i] * (metric[i] - target(sel, 0)).abs();
+            cost1 = curr1 + importance[i] * (metric[i] - target(sel, 1)).abs();
+        });
+        selcost[sel] = i32::min(cost0, cost1);
+    });
+
+    // For now, we're conservative and only allow tf_select=1 for transients.
+    // If tests confirm it's useful for non-transients, we could allow it.
+    let tf_select = (selcost[1] < selcost[0] && is_transient) as usize;
+
+    let mut path0 = [0_i32; NB_E_BANDS];
+    let mut path1 = [0_i32; NB_E_BANDS];
+    let mut cost0 = importance[0] * (metric[0] - target(tf_select, 0)).abs();
+    let mut cost1 = importance[0] * (metric[0] - target(tf_select, 1)).abs() + transient_lambda;
+    // Viterbi forward pass.
+    (1..len).for_each(|i| {
+        let from0 = cost0;
+        let from1 = cost1 + lambda;
+        let curr0 = if from0 < from1 {
+            path0[i] = 0;
+            from0
+        } else {
+            path0[i] = 1;
+            from1
+        };
+
+        let from0 = cost0 + lambda;
+        let from1 = cost1;
+        let curr1 = if from0 < from1 {
+            path1[i] = 0;
+            from0
+        } else {
+            path1[i] = 1;
+            from1
+        };
+        cost0 = curr0 + importance[i] * (metric[i] - target(tf_select, 0)).abs();
+        cost1 = curr1 + importance[i] * (metric[i] - target(tf_select, 1)).abs();
+    });
+    tf_res[len - 1] = if cost0 < cost1 { 0 } else { 1 };
+    // Viterbi backward pass to check the decisions.
+    (0..len - 1).rev().for_each(|i| {
+        tf_res[i] = if tf_res[i + 1] == 1 {
+            path1[i + 1]
+        } else {
+            path0[i + 1]
+        };
+    });
+
+    tf_select
+}
+
+/// Estimates the allocation trim from the spectral tilt and the inter-channel
+/// correlation.
+#[allow(clippy::too_many_arguments)]
+fn alloc_trim_analysis(
+    x: &[f32],
+    band_log_e: &[f32],
+    end: usize,
+    lm: usize,
+    channels: usize,
+    n0: usize,
+    stereo_saving: &mut f32,
+    tf_estimate: f32,
+    intensity: usize,
+    surround_trim: f32,
+    equiv_rate: i32,
+) -> i32 {
+    // At low bitrate, reducing the trim seems to help. At higher bitrates, it's less
+    // clear what's best, so we're keeping it as it was before, at least for now.
+    let mut trim = 5.0_f32;
+    if equiv_rate < 64000 {
+        trim = 4.0;
+    } else if equiv_rate < 80000 {
+        let frac = (equiv_rate - 64000) >> 10;
+        trim = 4.0 + (1.0 / 16.0) * frac as f32;
+    }
+
+    if channels == 2 {
+        let partial = |i: usize| {
+            let offset = usize::from(E_BANDS[i]) << lm;
+            let n = usize::from(E_BANDS[i + 1] - E_BANDS[i]) << lm;
+            inner_prod(&x[offset..], &x[n0 + offset..], n)
+        };
+
+        // Compute inter-channel correlation for low frequencies.
+        let mut sum = (0..8).fold(0.0, |sum, i| sum + partial(i));
+        sum *= 1.0 / 8.0;
+        sum = f32::min(1.0, sum.abs());
+        let mut min_xc = sum;
+        (8..intensity).for_each(|i| {
+            min_xc = f32::min(min_xc, partial(i).abs());
+        });
+        min_xc = f32::min(1.0, min_xc.abs());
+
+        // Mid-side savings estimations based on the LF average.
+        let log_xc = fast_log2(1.001 - sum * sum);
+        // Mid-side savings estimations based on min correlation.
+        let log_xc2 = f32::max(0.5 * log_xc, fast_log2(1.001 - min_xc * min_xc));
+
+        trim += f32::max(-4.0, 0.75 * log_xc);
+        *stereo_saving = f32::min(*stereo_saving + 0.25, -0.5 * log_xc2);
+    }
+
+    // Estimate spectral tilt.
+    let mut diff = 0.0;
+    (0..channels).for_each(|c| {
+        (0..end - 1).for_each(|i| {
+            diff += band_log_e[i + c * NB_E_BANDS] * (2 + 2 * i as i32 - end as i32) as f32;
+        });
+    });
+    diff /= (channels * (end - 1)) as f32;
+    trim -= ((diff + 1.0) / 6.0).clamp(-2.0, 2.0);
+    trim -= surround_trim;
+    trim -= 2.0 * tf_estimate;
+
+    let trim_index = (0.5 + trim).floor() as i32;
+    trim_index.clamp(0, 10)
+}
+
+/// Decides whether dual stereo (L/R) is cheaper to code than M/S stereo.
+#[allow(clippy::approx_constant)]
+fn stereo_analysis(x: &[f32], lm: usize, n0: usize) -> bool {
+    let mut sum_lr = EPSILON;
+    let mut sum_ms = EPSILON;
+
+    // Use the L1 norm to model the entropy of the L/R signal vs the M/S signal.
+    (0..13).for_each(|i| {
+        let band = usize::from(E_BANDS[i]) << lm..usize::from(E_BANDS[i + 1]) << lm;
+        band.for_each(|j| {
+            let l = x[j];
+            let r = x[n0 + j];
+            let m = l + r;
+            let s = l - r;
+            sum_lr += l.abs() + r.abs();
+            sum_ms += m.abs() + s.abs();
+        });
+    });
+    sum_ms *= 0.707107;
+
+    // We don't need thetas for lower bands with LM<=1.
+    let thetas = if lm <= 1 { 5 } else { 13 };
+    let bins = i32::from(E_BANDS[13]) << (lm + 1);
+    (bins + thetas) as f32 * sum_ms > bins as f32 * sum_lr
+}
+
+fn median_of_5(x: &[f32]) -> f32 {
+    let t2 = x[2];
+    let (mut t0, mut t1) = if x[0] > x[1] {
+        (x[1], x[0])
+    } else {
+        (x[0], x[1])
+    };
+    let (mut t3, mut t4) = if x[3] > x[4] {
+        (x[4], x[3])
+    } else {
+        (x[3], x[4])
+    };
+    if t0 > t3 {
+        std::mem::swap(&mut t0, &mut t3);
+        std::mem::swap(&mut t1, &mut t4);
+    }
+    if t2 > t1 {
+        if t1 < t3 {
+            f32::min(t2, t3)
+        } else {
+            f32::min(t4, t1)
+        }
+    } else if t2 < t3 {
+        f32::min(t1, t3)
+    } else {
+        f32::min(t2, t4)
+    }
+}
+
+fn median_of_3(x: &[f32]) -> f32 {
+    let (t0, t1) = if x[0] > x[1] {
+        (x[1], x[0])
+    } else {
+        (x[0], x[1])
+    };
+    let t2 = x[2];
+    if t1 < t2 {
+        t1
+    } else if t0 < t2 {
+        t2
+    } else {
+        t0
+    }
+}
+
+/// Computes the dynamic allocation boost of each band, the importance of the bands
+/// for the TF analysis and the spreading weights.
+///
+/// Returns the maximal depth of the signal over the noise floor and the total boost.
+#[allow(clippy::too_many_arguments)]
+fn dynalloc_analysis(
+    band_log_e: &[f32],
+    band_log_e2: &[f32],
+    start: usize,
+    end: usize,
+    channels: usize,
+    offsets: &mut [i32; NB_E_BANDS],
+    lsb_depth: i32,
+    is_transient: bool,
+    vbr: bool,
+    constrained_vbr: bool,
+    lm: usize,
+    effective_bytes: i32,
+    lfe: bool,
+    surround_dynalloc: &[f32; NB_E_BANDS],
+    importance: &mut [i32; NB_E_BANDS],
+    spread_weight: &mut [i32; NB_E_BANDS],
+) -> (f32, i32) {
+    let mut tot_boost = 0;
+    let mut follower = [0_f32; 2 * NB_E_BANDS];
+    let mut noise_floor = [0_f32; NB_E_BANDS];
+    offsets.iter_mut().for_each(|x| *x = 0);
+
+    // Dynamic allocation code.
+    (0..end).for_each(|i| noise_floor[i] = band_noise_floor(i, lsb_depth));
+    let mut max_depth = -31.9_f32;
+    (0..channels).for_each(|c| {
+        (0..end).for_each(|i| {
+            max_depth = f32::max(max_depth, band_log_e[c * NB_E_BANDS + i] - noise_floor[i]);
+        });
+    });
+
+    // Compute a really simple masking model to avoid taking into account completely masked
+    // bands when computing the spreading decision.
+    {
+        let mut mask = [0_f32; NB_E_BANDS];
+        (0..end).for_each(|i| {
+            mask[i] = band_log_e[i] - noise_floor[i];
+        });
+        if channels == 2 {
+            (0..end).for_each(|i| {
+                mask[i] = f32::max(mask[i], band_log_e[NB_E_BANDS + i] - noise_floor[i]);
+            });
+        }
+        let sig = mask;
+        (1..end).for_each(|i| {
+            mask[i] = f32::max(mask[i], mask[i - 1] - 2.0);
+        });
+        (0..end - 1).rev().for_each(|i| {
+            mask[i] = f32::max(mask[i], mask[i + 1] - 3.0);
+        });
+        (0..end).for_each(|i| {
+            // Compute SMR: Mask is never more than 72 dB below the peak and never below
+            // the noise floor.
+            let smr = sig[i] - f32::max(f32::max(0.0, max_depth - 12.0), mask[i]);
+            // Clamp SMR to make sure we're not shifting by something negative or too large.
+            let shift = (-((0.5 + smr).floor() as i32)).clamp(0, 5);
+            spread_weight[i] = 32 >> shift;
+        });
+    }
+
+    // Make sure that dynamic allocation can't make us bust the budget.
+    if effective_bytes > 50 && lm >= 1 && !lfe {
+        let mut last = 0;
+        (0..channels).for_each(|c| {
+            let e2 = &band_log_e2[c * NB_E_BANDS..(c + 1) * NB_E_BANDS];
+            let f = &mut follower[c * NB_E_BANDS..(c + 1) * NB_E_BANDS];
+            f[0] = e2[0];
+            (1..end).for_each(|i| {
+                // The last band to be at least 3 dB higher than the previous one
+                // is the last we'll consider. Otherwise, we run into problems on
+                // bandlimited signals.
+                if e2[i] > e2[i - 1] + 0.5 {
+                    last = i;
+                }
+                f[i] = f32::min(f[i - 1] + 1.5, e2[i]);
+            });
+            (0..last).rev().for_each(|i| {
+                f[i] = f32::min(f[i], f32::min(f[i + 1] + 2.0, e2[i]));
+            });
+
+            // Combine with a median filter to avoid dynalloc triggering unnecessarily.
+            // The "offset" value controls how conservative we are -- a higher offset
+            // reduces the impact of the median filter and makes dynalloc use more bits.
+            let offset = 1.0;
+            (2..end - 2).for_each(|i| {
+                f[i] = f32::max(f[i], median_of_5(&e2[i - 2..]) - offset);
+            });
+            let tmp = median_of_3(e2) - offset;
+            f[0] = f32::max(f[0], tmp);
+            f[1] = f32::max(f[1], tmp);
+            let tmp = median_of_3(&e2[end - 3..]) - offset;
+            f[end - 2] = f32::max(f[end - 2], tmp);
+            f[end - 1] = f32::max(f[end - 1], tmp);
+
+            (0..end).for_each(|i| {
+                f[i] = f32::max(f[i], noise_floor[i]);
+            });
+        });
+
+        if channels == 2 {
+            (start..end).for_each(|i| {
+                // Consider 24 dB "cross-talk".
+                follower[NB_E_BANDS + i] = f32::max(follower[NB_E_BANDS + i], follower[i] - 4.0);
+                follower[i] = f32::max(follower[i], follower[NB_E_BANDS + i] - 4.0);
+                follower[i] = 0.5
+                    * (f32::max(0.0, band_log_e[i] - follower[i])
+                        + f32::max(0.0, band_log_e[NB_E_BANDS + i] - follower[NB_E_BANDS + i]));
+            });
+        } else {
+            (start..end).for_each(|i| {
+                follower[i] = f32::max(0.0, band_log_e[i] - follower[i]);
+            });
+        }
+
+        (start..end).for_each(|i| {
+            follower[i] = f32::max(follower[i], surround_dynalloc[i]);
+        });
+        (start..end).for_each(|i| {
+            importance[i] = (0.5 + 13.0 * fast_exp2(f32::min(follower[i], 4.0))).floor() as i32;
+        });
+        // For non-transient CBR/CVBR frames, halve the dynalloc contribution.
+        if (!vbr || constrained_vbr) && !is_transient {
+            (start..end).for_each(|i| follower[i] *= 0.5);
+        }
+        (start..end).for_each(|i| {
+            if i < 8 {
+                follower[i] *= 2.0;
+            }
+            if i >= 12 {
+                follower[i] *= 0.5;
+            }
+        });
+
+        for i in start..end {
+            follower[i] = f32::min(follower[i], 4.0);
+
+            let width = (channels as i32 * i32::from(E_BANDS[i + 1] - E_BANDS[i])) << lm;
+            let (boost, boost_bits) = if width < 6 {
+                let boost = follower[i] as i32;
+                (boost, (boost * width) << BITRES)
+            } else if width > 48 {
+                let boost = (follower[i] * 8.0) as i32;
+                (boost, ((boost * width) << BITRES) / 8)
+            } else {
+                let boost = (follower[i] * width as f32 / 6.0) as i32;
+                (boost, (boost * 6) << BITRES)
+            };
+            // For CBR and non-transient CVBR frames, limit dynalloc to 2/3 of the bits.
+            if (!vbr || (constrained_vbr && !is_transient))
+                && (tot_boost + boost_bits) >> BITRES >> 3 > 2 * effective_bytes / 3
+            {
+                let cap = (2 * effective_bytes / 3) << BITRES << 3;
+                offsets[i] = cap - tot_boost;
+                tot_boost = cap;
+                break;
+            } else {
+                offsets[i] = boost;
+                tot_boost += boost_bits;
+            }
+        }
+    } else {
+        (start..end).for_each(|i| importance[i] = 13);
+    }
+
+    (max_depth, tot_boost)
+}
+
+/// Computes how much of the spectrum is masked by the other channels of a surround mix.
+///
+/// Fills the extra dynamic allocation of the bands that are less masked than the average
+/// and returns the average masking and the trim offset.
+fn surround_masking_analysis(
+    energy_mask: &[f32; 2 * NB_E_BANDS],
+    channels: usize,
+    last_coded_bands: usize,
+    surround_dynalloc: &mut [f32; NB_E_BANDS],
+) -> (f32, f32) {
+    let mask_end = usize::max(2, last_coded_bands);
+    let mut mask_avg = 0.0;
+    let mut diff = 0.0;
+    let mut count = 0;
+    (0..channels).for_each(|c| {
+        (0..mask_end).for_each(|i| {
+            let mut mask = energy_mask[NB_E_BANDS * c + i].clamp(-2.0, 0.25);
+            if mask > 0.0 {
+                mask *= 0.5;
+            }
+            let width = i32::from(E_BANDS[i + 1] - E_BANDS[i]);
+            mask_avg += mask * width as f32;
+            count += width;
+            diff += mask * (1 + 2 * i as i32 - mask_end as i32) as f32;
+        });
+    });
+    mask_avg /= count as f32;
+    mask_avg += 0.2;
+    diff = diff * 6.0 / (channels * (mask_end - 1) * (mask_end + 1) * mask_end) as f32;
+    // Again, being conservative.
+    diff *= 0.5;
+    diff = diff.clamp(-0.031, 0.031);
+
+    // Find the band that's in the middle of the coded spectrum.
+    let midband = (0..mask_end)
+        .find(|&i| E_BANDS[i + 1] >= E_BANDS[mask_end] / 2)
+        .unwrap_or(mask_end);
+    let mut count_dynalloc = 0;
+    (0..mask_end).for_each(|i| {
+        let lin = mask_avg + diff * (i as i32 - midband as i32) as f32;
+        let mut unmask = if channels == 2 {
+            f32::max(energy_mask[i], energy_mask[NB_E_BANDS + i])
+        } else {
+            energy_mask[i]
+        };
+        unmask = f32::min(unmask, 0.0);
+        unmask -= lin;
+        if unmask > 0.25 {
+            surround_dynalloc[i] = unmask - 0.25;
+            count_dynalloc += 1;
+        }
+    });
+    if count_dynalloc >= 3 {
+        // If we need dynalloc in many bands, it's probably because our
+        // initial masking rate was too low.
+        mask_avg += 0.25;
+        if mask_avg > 0.0 {
+            // Something went really wrong in the original calculations, disabling masking.
+            mask_avg = 0.0;
+            diff = 0.0;
+            surround_dynalloc[..mask_end]
+                .iter_mut()
+                .for_each(|x| *x = 0.0);
+        } else {
+            surround_dynalloc[..mask_end]
+                .iter_mut()
+                .for_each(|x| *x = f32::max(0.0, *x - 0.25));
+        }
+    }
+    mask_avg += 0.2;
+
+    // Convert to 1/64th units used for the trim.
+    (mask_avg, 64.0 * diff)
+}
+
+/// The noise floor of a band.
+///
+/// Takes into account the mean energy, the depth, the width of the bands and the
+/// pre-emphasis filter (approx. square of bark band ID).
+fn band_noise_floor(band: usize, lsb_depth: i32) -> f32 {
+    0.0625 * f32::from(LOG_N[band]) + 0.5 + (9 - lsb_depth) as f32 - E_MEANS[band]
+        + 0.0062 * ((band + 5) * (band + 5)) as f32
+}
+
+/// Encodes the time-frequency resolution changes of the bands.
+fn tf_encode(
+    start: usize,
+    end: usize,
+    is_transient: bool,
+    tf_res: &mut [i32; NB_E_BANDS],
+    lm: usize,
+    mut tf_select: usize,
+    enc: &mut RangeEncoder,
+) -> Result<(), OpusError> {
+    let mut budget = enc.storage() as u32 * 8;
+    let mut tell = enc.tell();
+    let mut logp = if is_transient { 2 } else { 4 };
+    // Reserve space to code the tf_select decision.
+    let tf_select_rsv = lm > 0 && tell + logp < budget;
+    budget -= tf_select_rsv as u32;
+
+    let mut curr = 0;
+    let mut tf_changed = 0;
+    (start..end).try_for_each(|i| {
+        if tell + logp <= budget {
+            enc.encode_bit_logp((tf_res[i] ^ curr) as u32, logp)?;
+            tell = enc.tell();
+            curr = tf_res[i];
+            tf_changed |= curr;
+        } else {
+            tf_res[i] = curr;
+        }
+        logp = if is_transient { 4 } else { 5 };
+        Ok(())
+    })?;
+
+    // Only code tf_select if it would actually make a difference.
+    let table = &TF_SELECT_TABLE[lm];
+    let offset = 4 * is_transient as usize;
+    if tf_select_rsv
+        && table[offset + tf_changed as usize] != table[offset + 2 + tf_changed as usize]
+    {
+        enc.encode_bit_logp(tf_select as u32, 1)?;
+    } else {
+        tf_select = 0;
+    }
+    (start..end).for_each(|i| {
+        tf_res[i] = i32::from(table[offset + 2 * tf_select + tf_res[i] as usize]);
+    });
+
+    Ok(())
+}
+
+/// Computes the target size of a VBR frame in 1/8 bits.
+#[allow(clippy::too_many_arguments)]
+fn compute_vbr(
+    base_target: i32,
+    lm: usize,
+    bitrate: i32,
+    last_coded_bands: usize,
+    channels: usize,
+    intensity: usize,
+    constrained_vbr: bool,
+    stereo_saving: f32,
+    tot_boost: i32,
+    tf_estimate: f32,
+    max_depth: f32,
+    lfe: bool,
+    has_surround_mask: bool,
+    surround_masking: f32,
+    temporal_vbr: f32,
+) -> i32 {
+    let coded_bands = if last_coded_bands != 0 {
+        last_coded_bands
+    } else {
+        NB_E_BANDS
+    };
+    let mut coded_bins = i32::from(E_BANDS[coded_bands]) << lm;
+    if channels == 2 {
+        coded_bins += i32::from(E_BANDS[usize::min(intensity, coded_bands)]) << lm;
+    }
+
+    let mut target = base_target;
+
+    // Stereo savings.
+    if channels == 2 {
+        let coded_stereo_bands = usize::min(intensity, coded_bands);
+        let coded_stereo_dof =
+            (i32::from(E_BANDS[coded_stereo_bands]) << lm) - coded_stereo_bands as i32;
+        // Maximum fraction of the bits we can save if the signal is mono.
+        let max_frac = 0.8 * coded_stereo_dof as f32 / coded_bins as f32;
+        let stereo_saving = f32::min(stereo_saving, 1.0);
+        target -= f32::min(
+            max_frac * target as f32,
+            (stereo_saving - 0.1) * (coded_stereo_dof << BITRES) as f32,
+        ) as i32;
+    }
+
+    // Boost the rate according to dynalloc (minus the dynalloc average for calibration).
+    target += tot_boost - (19 << lm);
+    // Apply transient boost, compensating for average boost.
+    target += ((tf_estimate - 0.044) * target as f32) as i32;
+
+    if has_surround_mask && !lfe {
+        let surround_target = target + (surround_masking * (coded_bins << BITRES) as f32) as i32;
+        target = i32::max(target / 4, surround_target);
+    }
+
+    let bins = i32::from(E_BANDS[NB_E_BANDS - 2]) << lm;
+    let floor_depth = ((((channels as i32 * bins) << BITRES) as f32) * max_depth) as i32;
+    let floor_depth = i32::max(floor_depth, target >> 2);
+    target = i32::min(target, floor_depth);
+
+    // Make VBR less aggressive for constrained VBR because we can't keep a higher bitrate
+    // for long. Needs tuning.
+    if (!has_surround_mask || lfe) && constrained_vbr {
+        target = base_target + (0.67 * (target - base_target) as f32) as i32;
+    }
+
+    if !has_surround_mask && tf_estimate < 0.2 {
+        let amount = 0.0000031 * i32::max(0, i32::min(32000, 96000 - bitrate)) as f32;
+        let tvbr_factor = temporal_vbr * amount;
+        target += (tvbr_factor * target as f32) as i32;
+    }
+
+    // Don't allow more than doubling the rate.
+    i32::min(2 * base_target, target)
+}
@@ -11,7 +11,6 @@ const MAX_FACTORS: usize = 8;
 /// This code is originally from Mark Borgerding's KISS-FFT but has been heavily modified
 /// to better suit Opus.
 pub(crate) struct KissFft {
-    pub(crate) nfft: usize,
     pub(crate) scale: f32,
     pub(crate) shift: usize,
     pub(crate) factors: [usize; 2 * MAX_FACTORS],
@@ -35,7 +34,7 @@ impl KissFft {
         }
         m = self.factors[2 * l - 1];
 
-        (0..l).into_iter().rev().for_each(|i| {
+        (0..l).rev().for_each(|i| {
             let m2 = if i != 0 { self.factors[2 * i - 1] } else { 1 };
 
             let stride = strides[i] << self.shift;
@@ -59,7 +58,7 @@ impl KissFft {
         let mut offset = 0;
         let mut offset2 = 0;
 
-        (0..n).into_iter().for_each(|_| {
+        (0..n).for_each(|_| {
             offset2 = offset + 4;
 
             let mut t = data[offset2];
@@ -87,18 +86,18 @@ impl KissFft {
 
     fn butterfly3(&self, data: &mut [Complex], stride: usize, m: usize, n: usize, mm: usize) {
         // m is guaranteed to be a multiple of 4.
-        debug_assert!(m % 4 == 0);
+        debug_assert!(m.is_multiple_of(4));
 
         let mut scratch = [Complex::default(); 5];
         let m2 = 2 * m;
         let epi3 = self.twiddles[stride * m];
 
-        (0..n).into_iter().for_each(|i| {
+        (0..n).for_each(|i| {
             let mut offset = i * mm;
             let mut tw1_offset = 0;
             let mut tw2_offset = 0;
 
-            (1..m + 1).into_iter().rev().for_each(|_| {
+            (1..m + 1).rev().for_each(|_| {
                 scratch[1] = data[offset + m] * self.twiddles[tw1_offset];
                 scratch[2] = data[offset + m2] * self.twiddles[tw2_offset];
 
@@ -129,7 +128,7 @@ impl KissFft {
             let mut offset = 0;
 
             // Degenerate case where all the twiddles are 1.
-            (0..n).into_iter().for_each(|_| {
+            (0..n).for_each(|_| {
                 let scratch0 = data[offset] - data[offset + 2];
                 let scratch1 = data[offset + 1] + data[offset + 3];
 
@@ -148,19 +147,19 @@ impl KissFft {
             });
         } else {
             // m is guaranteed to be a multiple of 4.
-            debug_assert!(m % 4 == 0);
+            debug_assert!(m.is_multiple_of(4));
 
             let m2 = 2 * m;
             let m3 = 3 * m;
             let mut scratch = [Complex::default(); 6];
 
-            (0..n).into_iter().for_each(|i| {
+            (0..n).for_each(|i| {
                 let mut offset = i * mm;
                 let mut tw1_offset = 0;
                 let mut tw2_offset = 0;
                 let mut tw3_offset = 0;
 
-                (0..m).into_iter().for_each(|_| {
+                (0..m).for_each(|_| {
                     scratch[0] = data[offset + m] * self.twiddles[tw1_offset];
                     scratch[1] = data[offset + m2] * self.twiddles[tw2_offset];
                     scratch[2] = data[offset + m3] * self.twiddles[tw3_offset];
@@ -188,20 +187,20 @@ impl KissFft {
 
     fn butterfly5(&self, data: &mut [Complex], stride: usize, m: usize, n: usize, mm: usize) {
         // m is guaranteed to be a multiple of 4.
-        debug_assert!(m % 4 == 0);
+        debug_assert!(m.is_multiple_of(4));
 
         let mut scratch = [Complex::default(); 13];
         let ya = self.twiddles[stride * m];
         let yb = self.twiddles[stride * 2 * m];
 
-        (0..n).into_iter().for_each(|i| {
+        (0..n).for_each(|i| {
             let mut offset0 = i * mm;
             let mut offset1 = offset0 + m;
             let mut offset2 = offset0 + 2 * m;
             let mut offset3 = offset0 + 3 * m;
             let mut offset4 = offset0 + 4 * m;
 
-            (0..m).into_iter().for_each(|u| {
+            (0..m).for_each(|u| {
                 scratch[0] = data[offset0];
                 scratch[1] = data[offset1] * self.twiddles[u * stride];
                 scratch[2] = data[offset2] * self.twiddles[2 * u * stride];
@@ -245,36 +244,32 @@ impl KissFft {
 #[allow(clippy::excessive_precision)]
 pub(crate) const FFT_CONFIGURATION: &[KissFft; 4] = &[
     KissFft {
-        nfft: 480,
         scale: 0.002083333,
         shift: 0,
         factors: [5, 96, 3, 32, 4, 8, 2, 4, 4, 1, 0, 0, 0, 0, 0, 0],
-        bitrev: &BITREV_480,
-        twiddles: &TWIDDLES_480000_960,
+        bitrev: BITREV_480,
+        twiddles: TWIDDLES_480000_960,
     },
     KissFft {
-        nfft: 240,
         scale: 0.004166667,
         shift: 1,
         factors: [5, 48, 3, 16, 4, 4, 4, 1, 0, 0, 0, 0, 0, 0, 0, 0],
-        bitrev: &BITREV_240,
-        twiddles: &TWIDDLES_480000_960,
+        bitrev: BITREV_240,
+        twiddles: TWIDDLES_480000_960,
     },
     KissFft {
-        nfft: 120,
         scale: 0.008333333,
         shift: 2,
         factors: [5, 24, 3, 8, 2, 4, 4, 1, 0, 0, 0, 0, 0, 0, 0, 0],
-        bitrev: &BITREV_120,
-        twiddles: &TWIDDLES_480000_960,
+        bitrev: BITREV_120,
+        twiddles: TWIDDLES_480000_960,
     },
     KissFft {
-        nfft: 60,
         scale: 0.016666667,
         shift: 3,
         factors: [5, 12, 3, 4, 4, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
-        bitrev: &BITREV_60,
-        twiddles: &TWIDDLES_480000_960,
+        bitrev: BITREV_60,
+        twiddles: TWIDDLES_480000_960,
     },
 ];
 
@@ -586,14 +581,14 @@ mod tests {
     #![allow(clippy::panic)]
     #![allow(clippy::unwrap_used)]
 
-    use nanorand::RNG;
+    use nanorand::Rng;
 
     use super::*;
 
     /// Applies the forward FFT on the given data in `input` and saved the result in `output`.
     fn forward(fft: &KissFft, input: &[Complex], output: &mut [Complex]) {
         // Bit-reverse and scale the input.
-        (0..fft.nfft).into_iter().for_each(|i| {
+        (0..fft.bitrev.len()).for_each(|i| {
             output[usize::from(fft.bitrev[i])] = input[i] * fft.scale;
         });
 
@@ -603,17 +598,17 @@ mod tests {
     /// Applies the inverse FFT on the given data in `input` and saved the result in `output`.
     fn inverse(fft: &KissFft, input: &[Complex], output: &mut [Complex]) {
         // Bit-reverse the input.
-        (0..fft.nfft).into_iter().for_each(|i| {
+        (0..fft.bitrev.len()).for_each(|i| {
             output[usize::from(fft.bitrev[i])] = input[i];
         });
 
-        (0..fft.nfft).into_iter().for_each(|i| {
+        (0..fft.bitrev.len()).for_each(|i| {
             output[i].i = -output[i].i;
         });
 
         fft.process(output);
 
-        (0..fft.nfft).into_iter().for_each(|i| {
+        (0..fft.bitrev.len()).for_each(|i| {
             output[i].i = -output[i].i;
         });
     }
@@ -663,11 +658,14 @@ mod tests {
         let mut input = vec![Complex::default(); nfft];
         let mut output = vec![Complex::default(); nfft];
 
-        let fft = FFT_CONFIGURATION.iter().find(|c| c.nfft == nfft).unwrap();
+        let fft = FFT_CONFIGURATION
+            .iter()
+            .find(|c| c.bitrev.len() == nfft)
+            .unwrap();
 
         input.iter_mut().for_each(|x| {
-            x.r = (rng.generate_range::<u32>(0, 32767) as i16 - 16384) as f32;
-            x.i = (rng.generate_range::<u32>(0, 32767) as i16 - 16384) as f32;
+            x.r = (rng.generate_range::<u32, _>(0..32767) as i16 - 16384) as f32;
+            x.i = (rng.generate_range::<u32, _>(0..32767) as i16 - 16384) as f32;
         });
 
         input.iter_mut().for_each(|x| {
@@ -682,9 +680,9 @@ mod tests {
         }
 
         if is_inverse {
-            inverse(&fft, &input, &mut output);
+            inverse(fft, &input, &mut output);
         } else {
-            forward(&fft, &input, &mut output);
+            forward(fft, &input, &mut output);
         }
 
         check(&input, &output, nfft, is_inverse);
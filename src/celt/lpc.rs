@@ -0,0 +1,166 @@
+//! Implements the linear prediction helpers.
+
+use crate::celt::pitch::pitch_xcorr;
+
+/// Computes the LPC coefficients of order `p` from the autocorrelation `ac`
+/// with the Levinson-Durbin recursion.
+pub(crate) fn lpc(lpc: &mut [f32], ac: &[f32], p: usize) {
+    let mut error = ac[0];
+
+    lpc[..p].iter_mut().for_each(|x| *x = 0.0);
+    if ac[0] == 0.0 {
+        return;
+    }
+
+    for i in 0..p {
+        // Sum up this iteration's reflection coefficient.
+        let mut rr = 0.0;
+        (0..i).for_each(|j| rr += lpc[j] * ac[i - j]);
+        rr += ac[i + 1];
+        let r = -rr / error;
+
+        // Update LPC coefficients and total error.
+        lpc[i] = r;
+        (0..(i + 1) >> 1).for_each(|j| {
+            let tmp1 = lpc[j];
+            let tmp2 = lpc[i - 1 - j];
+            lpc[j] = tmp1 + (r * tmp2);
+            lpc[i - 1 - j] = tmp2 + (r * tmp1);
+        });
+
+        error -= (r * r) * error;
+        // Bail out once we get 30 dB gain.
+        if error < 0.001 * ac[0] {
+            break;
+        }
+    }
+}
+
+/// Applies the FIR filter `num` of order `ord` to `x` and writes `n` samples into `y`.
+///
+/// `x` contains `ord` samples of history followed by the `n` samples to filter.
+pub(crate) fn fir(x: &[f32], num: &[f32], y: &mut [f32], n: usize, ord: usize) {
+    y[..n].iter_mut().enumerate().for_each(|(i, y)| {
+        let mut sum = x[ord + i];
+        (0..ord).for_each(|j| {
+            sum += num[ord - j - 1] * x[i + j];
+        });
+        *y = sum;
+    });
+}
+
+/// Applies the IIR filter `den` of order `ord` in place to the first `n` samples of `y`.
+///
+/// `mem` holds the last `ord` output samples, the most recent first.
+pub(crate) fn iir(y: &mut [f32], den: &[f32], n: usize, ord: usize, mem: &[f32]) {
+    debug_assert_eq!(ord & 3, 0);
+    debug_assert_eq!(n & 3, 0);
+
+    let mut rden = vec![0_f32; ord];
+    let mut yy = vec![0_f32; n + ord];
+    rden.iter_mut()
+        .enumerate()
+        .for_each(|(i, x)| *x = den[ord - i - 1]);
+    (0..ord).for_each(|i| yy[i] = -mem[ord - i - 1]);
+
+    (0..n).step_by(4).for_each(|i| {
+        // Unroll by 4 as if it were an FIR filter.
+        let mut sum = [y[i], y[i + 1], y[i + 2], y[i + 3]];
+        (0..ord).for_each(|j| {
+            sum[0] += rden[j] * yy[i + j];
+            sum[1] += rden[j] * yy[i + j + 1];
+            sum[2] += rden[j] * yy[i + j + 2];
+            sum[3] += rden[j] * yy[i + j + 3];
+        });
+
+        // Patch up the result to compensate for the fact that this is an IIR.
+        yy[i + ord] = -sum[0];
+        y[i] = sum[0];
+        sum[1] += yy[i + ord] * den[0];
+        yy[i + ord + 1] = -sum[1];
+        y[i + 1] = sum[1];
+        sum[2] += yy[i + ord + 1] * den[0];
+        sum[2] += yy[i + ord] * den[1];
+        yy[i + ord + 2] = -sum[2];
+        y[i + 2] = sum[2];
+        sum[3] += yy[i + ord + 2] * den[0];
+        sum[3] += yy[i + ord + 1] * den[1];
+        sum[3] += yy[i + ord] * den[2];
+        yy[i + ord + 3] = -sum[3];
+        y[i + 3] = sum[3];
+    });
+}
+
+/// Computes the autocorrelation of `x` for the lags `0..=lag`.
+///
+/// If a `window` is given, the first and last `overlap` samples are windowed.
+pub(crate) fn autocorr(
+    x: &[f32],
+    ac: &mut [f32],
+    window: Option<&[f32]>,
+    overlap: usize,
+    lag: usize,
+    n: usize,
+) {
+    let fast_n = n - lag;
+
+    let mut xx;
+    let x = match window {
+        Some(window) if overlap > 0 => {
+            xx = x[..n].to_vec();
+            (0..overlap).for_each(|i| {
+                xx[i] = x[i] * window[i];
+                xx[n - i - 1] = x[n - i - 1] * window[i];
+            });
+            &xx[..]
+        }
+        _ => &x[..n],
+    };
+
+    pitch_xcorr(x, x, ac, fast_n, lag + 1);
+    (0..=lag).for_each(|k| {
+        let d = (k + fast_n..n).fold(0.0, |d, i| d + (x[i] * x[i - k]));
+        ac[k] += d;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+
+    #[test]
+    fn test_lpc_predicts_sine() {
+        let x: Vec<f32> = (0..256).map(|i| (0.1 * i as f32).sin()).collect();
+        let mut ac = [0_f32; 3];
+        autocorr(&x, &mut ac, None, 0, 2, x.len());
+        assert!(ac[0] > ac[1] && ac[1] > ac[2]);
+
+        let mut coefs = [0_f32; 2];
+        lpc(&mut coefs, &ac, 2);
+
+        // The prediction error of a sine is small.
+        let residual = (2..x.len()).fold(0.0, |acc, i| {
+            let e = x[i] + coefs[0] * x[i - 1] + coefs[1] * x[i - 2];
+            acc + e * e
+        });
+        assert!(residual < 0.01 * ac[0]);
+    }
+
+    #[test]
+    fn test_iir_inverts_fir() {
+        let x: Vec<f32> = (0..132)
+            .map(|i| if i < 4 { 0.0 } else { (0.3 * i as f32).sin() })
+            .collect();
+        let num = [0.5_f32, -0.25, 0.125, -0.0625];
+
+        let mut filtered = [0_f32; 128];
+        fir(&x, &num, &mut filtered, 128, 4);
+        iir(&mut filtered, &num, 128, 4, &[0.0; 4]);
+
+        filtered
+            .iter()
+            .zip(x[4..].iter())
+            .for_each(|(y, x)| assert!((y - x).abs() < 1e-5));
+    }
+}
@@ -14,6 +14,7 @@ use crate::math::Complex;
 /// The algorithm is similar to (and inspired from) Fabrice Bellard's
 /// MDCT implementation in FFMPEG, but has differences in signs, ordering
 /// and scaling in many places.
+#[derive(Clone, Debug, Default)]
 pub(crate) struct Mdct {
     /// Float scratch pad.
     spf: Vec<f32>,
@@ -23,15 +24,6 @@ pub(crate) struct Mdct {
 
 const N: usize = 1920;
 
-impl Default for Mdct {
-    fn default() -> Self {
-        Self {
-            spf: vec![],
-            spc: vec![],
-        }
-    }
-}
-
 impl Mdct {
     /// Compute a forward MDCT and scale by 4/N, trashes the input array.
     pub(crate) fn forward(
@@ -45,7 +37,7 @@ impl Mdct {
     ) {
         let mut n = N;
         let mut trigp = 0;
-        (0..shift).into_iter().for_each(|_| {
+        (0..shift).for_each(|_| {
             n >>= 1;
             trigp += n;
         });
@@ -72,7 +64,7 @@ impl Mdct {
             let mut wp1 = wp0 - 1;
 
             // Real part arranged as -d-cR, Imag part arranged as -b+aR.
-            (0..overlap_offset).into_iter().for_each(|_| {
+            (0..overlap_offset).for_each(|_| {
                 self.spf[sp] = (window[wp1] * input[ip0 + n2]) + (window[wp0] * input[ip1]);
                 self.spf[sp + 1] = (window[wp0] * input[ip0]) - (window[wp1] * input[ip1 - n2]);
 
@@ -87,19 +79,17 @@ impl Mdct {
             wp1 = overlap - 1;
 
             // Real part arranged as a-bR, Imag part arranged as -c-dR.
-            (overlap_offset..n4 - overlap_offset)
-                .into_iter()
-                .for_each(|_| {
-                    self.spf[sp] = input[ip1];
-                    self.spf[sp + 1] = input[ip0];
-
-                    sp += 2;
-                    ip0 += 2;
-                    ip1 -= 2;
-                });
+            (overlap_offset..n4 - overlap_offset).for_each(|_| {
+                self.spf[sp] = input[ip1];
+                self.spf[sp + 1] = input[ip0];
+
+                sp += 2;
+                ip0 += 2;
+                ip1 -= 2;
+            });
 
             // Real part arranged as a-bR, Imag part arranged as -c-dR.
-            (n4 - overlap_offset..n4).into_iter().for_each(|_| {
+            (n4 - overlap_offset..n4).for_each(|_| {
                 self.spf[sp] = -(window[wp0] * input[ip0 - n2]) + (window[wp1] * input[ip1]);
                 self.spf[sp + 1] = (window[wp1] * input[ip0]) + (window[wp0] * input[ip1 + n2]);
 
@@ -118,7 +108,7 @@ impl Mdct {
             let mut sp = 0;
             let mut tmp = Complex::default();
 
-            (0..n4).into_iter().for_each(|i| {
+            (0..n4).for_each(|i| {
                 let t0 = TRIG[trigp + i];
                 let t1 = TRIG[trigp + n4 + i];
                 let re = self.spf[sp];
@@ -141,7 +131,7 @@ impl Mdct {
             let mut op0 = 0;
             let mut op1 = stride * (n2 - 1);
 
-            (0..n4).into_iter().for_each(|i| {
+            (0..n4).for_each(|i| {
                 output[op0] =
                     (self.spc[sp].i * TRIG[trigp + n4 + i]) - (self.spc[sp].r * TRIG[trigp + i]);
                 output[op1] =
@@ -167,7 +157,7 @@ impl Mdct {
     ) {
         let mut n = N;
         let mut trigp = 0;
-        (0..shift).into_iter().for_each(|_| {
+        (0..shift).for_each(|_| {
             n >>= 1;
             trigp += n;
         });
@@ -244,7 +234,7 @@ impl Mdct {
             let mut wp0 = 0;
             let mut wp1 = overlap - 1;
 
-            (0..overlap / 2).into_iter().for_each(|_| {
+            (0..overlap / 2).for_each(|_| {
                 let x0 = output[op1];
                 let x1 = output[op0];
                 output[op0] = (window[wp1] * x1) - (window[wp0] * x0);
@@ -632,7 +622,7 @@ mod tests {
 
     use std::f64::consts::PI;
 
-    use nanorand::RNG;
+    use nanorand::Rng;
 
     use super::*;
 
@@ -640,10 +630,10 @@ mod tests {
         let mut err_pow: f64 = 0.0;
         let mut sig_pow: f64 = 0.0;
 
-        (0..nfft / 2).into_iter().for_each(|i| {
+        (0..nfft / 2).for_each(|i| {
             let mut ansr: f64 = 0.0;
 
-            (0..nfft).into_iter().for_each(|k| {
+            (0..nfft).for_each(|k| {
                 let phase: f64 =
                     2.0 * PI * (k as f64 + 0.5 + 0.25 * nfft as f64) * (i as f64 + 0.5)
                         / nfft as f64;
@@ -673,10 +663,10 @@ mod tests {
         let mut err_pow: f64 = 0.0;
         let mut sig_pow: f64 = 0.0;
 
-        (0..nfft).into_iter().for_each(|i| {
+        (0..nfft).for_each(|i| {
             let mut ansr: f64 = 0.0;
 
-            (0..nfft / 2).into_iter().for_each(|k| {
+            (0..nfft / 2).for_each(|k| {
                 let phase: f64 =
                     2.0 * PI * (i as f64 + 0.50 + 0.25 * nfft as f64) * (k as f64 + 0.5)
                         / nfft as f64;
@@ -717,7 +707,7 @@ mod tests {
         let window = vec![1.0_f32; nfft / 2];
 
         input.iter_mut().for_each(|x| {
-            *x = (rng.generate_range::<u32>(0, 32768) as i16 - 16384) as f32;
+            *x = (rng.generate_range::<u32, _>(0..32768) as i16 - 16384) as f32;
             *x *= 32768.0;
         });
 
@@ -733,7 +723,7 @@ mod tests {
             mdct.backward(&input, &mut output, &window, nfft / 2, shift, 1);
 
             // Apply TDAC because backward() no longer does that.
-            (0..nfft / 4).into_iter().for_each(|i| {
+            (0..nfft / 4).for_each(|i| {
                 output[nfft - i - 1] = output[nfft / 2 + i];
             });
 
@@ -1,10 +1,43 @@
+pub(crate) use bands::compute_band_energies;
 pub(crate) use comb_filter::{comb_filter, comb_filter_inplace};
 pub(crate) use decoder::CeltDecoder;
+pub(crate) use encoder::{preemphasis, CeltEncoder};
 pub(crate) use kiss_fft::FFT_CONFIGURATION;
+pub(crate) use mdct::Mdct;
+pub(crate) use pitch::pitch_xcorr;
+pub(crate) use quant_bands::amp2_log2;
+pub(crate) use vq::{inner_prod, EPSILON};
 
+mod bands;
 mod comb_filter;
 mod decoder;
+mod encoder;
 mod kiss_fft;
+mod lpc;
 mod mdct;
 pub(crate) mod mode;
+mod pitch;
 mod pvc;
+mod quant_bands;
+mod rate;
+mod vq;
+
+/// Minimal period of the comb filter.
+pub(crate) const COMBFILTER_MINPERIOD: usize = 15;
+
+/// Maximal period of the comb filter.
+pub(crate) const COMBFILTER_MAXPERIOD: usize = 1024;
+
+/// Very small value to avoid denormals.
+pub(crate) const VERY_SMALL: f32 = 1e-30;
+
+pub(crate) const TF_SELECT_TABLE: &[[i8; 8]; 4] = &[
+    [0, -1, 0, -1, 0, -1, 0, -1], // 2.5 ms
+    [0, -1, 0, -2, 1, 0, 1, -1],  // 5 ms
+    [0, -2, 0, -3, 2, 0, 1, -1],  // 10 ms
+    [0, -2, 0, -3, 3, 0, 1, -1],  // 20 ms
+];
+
+pub(crate) const TRIM_ICDF: &[u8; 11] = &[126, 124, 119, 109, 87, 41, 19, 9, 4, 2, 0];
+pub(crate) const SPREAD_ICDF: &[u8; 4] = &[25, 23, 2, 0];
+pub(crate) const TAPSET_ICDF: &[u8; 3] = &[2, 1, 0];
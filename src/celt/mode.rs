@@ -4,7 +4,6 @@ pub(crate) const SAMPLING_RATE: u32 = 48000;
 pub(crate) const OVERLAP: usize = 120;
 pub(crate) const EFF_E_BANDS: usize = 21;
 pub(crate) const MAX_LM: usize = 3;
-pub(crate) const NB_SHORT_MDCTS: usize = 8;
 pub(crate) const SHORT_MDCT_SIZE: usize = 120;
 
 pub(crate) const PREEMPH: &[f32; 4] = &[0.8500061, 0.0, 1.0, 1.0];
@@ -0,0 +1,344 @@
+//! Implements the pitch analysis.
+
+use crate::celt::lpc::{autocorr, lpc};
+use crate::celt::rate::celt_udiv;
+use crate::celt::vq::inner_prod;
+
+const SECOND_CHECK: &[usize; 16] = &[0, 0, 3, 2, 3, 2, 5, 2, 3, 2, 3, 2, 5, 2, 3, 2];
+
+/// Computes the cross-correlation of `x` with `y` for the lags `0..max_pitch`.
+pub(crate) fn pitch_xcorr(x: &[f32], y: &[f32], xcorr: &mut [f32], len: usize, max_pitch: usize) {
+    xcorr[..max_pitch]
+        .iter_mut()
+        .enumerate()
+        .for_each(|(i, xcorr)| *xcorr = inner_prod(x, &y[i..], len));
+}
+
+/// Computes the inner products of `x` with `y0` and `y1`.
+#[inline(always)]
+fn dual_inner_prod(x: &[f32], y0: &[f32], y1: &[f32], n: usize) -> (f32, f32) {
+    (0..n).fold((0.0, 0.0), |(xy0, xy1), i| {
+        (xy0 + (x[i] * y0[i]), xy1 + (x[i] * y1[i]))
+    })
+}
+
+/// Finds the two best pitch candidates of the cross-correlation.
+fn find_best_pitch(xcorr: &[f32], y: &[f32], len: usize, max_pitch: usize) -> [usize; 2] {
+    let mut syy = 1.0;
+    let mut best_num = [-1.0_f32; 2];
+    let mut best_den = [0.0_f32; 2];
+    let mut best_pitch = [0, 1];
+
+    (0..len).for_each(|j| syy += y[j] * y[j]);
+    (0..max_pitch).for_each(|i| {
+        if xcorr[i] > 0.0 {
+            // Considering the range of xcorr16, this should avoid both underflows
+            // and overflows (inf) when squaring xcorr16.
+            let xcorr16 = xcorr[i] * 1e-12;
+            let num = xcorr16 * xcorr16;
+            if num * best_den[1] > best_num[1] * syy {
+                if num * best_den[0] > best_num[0] * syy {
+                    best_num[1] = best_num[0];
+                    best_den[1] = best_den[0];
+                    best_pitch[1] = best_pitch[0];
+                    best_num[0] = num;
+                    best_den[0] = syy;
+                    best_pitch[0] = i;
+                } else {
+                    best_num[1] = num;
+                    best_den[1] = syy;
+                    best_pitch[1] = i;
+                }
+            }
+        }
+        syy += (y[i + len] * y[i + len]) - (y[i] * y[i]);
+        syy = f32::max(1.0, syy);
+    });
+
+    best_pitch
+}
+
+/// Applies a 5-tap FIR filter in place.
+fn fir5(x: &mut [f32], num: &[f32; 5], n: usize) {
+    let mut mem = [0.0_f32; 5];
+    x[..n].iter_mut().for_each(|x| {
+        let mut sum = *x;
+        sum += num[0] * mem[0];
+        sum += num[1] * mem[1];
+        sum += num[2] * mem[2];
+        sum += num[3] * mem[3];
+        sum += num[4] * mem[4];
+        mem[4] = mem[3];
+        mem[3] = mem[2];
+        mem[2] = mem[1];
+        mem[1] = mem[0];
+        mem[0] = *x;
+        *x = sum;
+    });
+}
+
+/// Downsamples the signal by two and applies a whitening filter.
+///
+/// `x` contains `channels` signals of `len` samples each, which are downmixed
+/// into `x_lp` (`len / 2` samples).
+pub(crate) fn pitch_downsample(x: &[f32], x_lp: &mut [f32], len: usize, channels: usize) {
+    let half = len >> 1;
+
+    (0..channels).for_each(|c| {
+        let x = &x[c * len..(c + 1) * len];
+        let first = 0.5 * ((0.5 * x[1]) + x[0]);
+        if c == 0 {
+            x_lp[0] = first;
+        } else {
+            x_lp[0] += first;
+        }
+        (1..half).for_each(|i| {
+            let val = 0.5 * ((0.5 * (x[2 * i - 1] + x[2 * i + 1])) + x[2 * i]);
+            if c == 0 {
+                x_lp[i] = val;
+            } else {
+                x_lp[i] += val;
+            }
+        });
+    });
+
+    let mut ac = [0_f32; 5];
+    autocorr(x_lp, &mut ac, None, 0, 4, half);
+
+    // Noise floor -40 dB.
+    ac[0] *= 1.0001;
+    // Lag windowing.
+    (1..=4).for_each(|i| {
+        ac[i] -= ac[i] * (0.008 * i as f32) * (0.008 * i as f32);
+    });
+
+    let mut lpc_coef = [0_f32; 4];
+    lpc(&mut lpc_coef, &ac, 4);
+    let mut tmp = 1.0;
+    lpc_coef.iter_mut().for_each(|x| {
+        tmp *= 0.9;
+        *x *= tmp;
+    });
+
+    // Add a zero.
+    let c1 = 0.8;
+    let lpc2 = [
+        lpc_coef[0] + 0.8,
+        lpc_coef[1] + (c1 * lpc_coef[0]),
+        lpc_coef[2] + (c1 * lpc_coef[1]),
+        lpc_coef[3] + (c1 * lpc_coef[2]),
+        c1 * lpc_coef[3],
+    ];
+    fir5(x_lp, &lpc2, half);
+}
+
+/// Searches the pitch period of `x_lp` in `y` (both downsampled by two).
+///
+/// Returns the lag of the best match in `0..max_pitch`.
+pub(crate) fn pitch_search(x_lp: &[f32], y: &[f32], len: usize, max_pitch: usize) -> usize {
+    let lag = len + max_pitch;
+
+    // Downsample by 2 again.
+    let x_lp4: Vec<f32> = (0..len >> 2).map(|j| x_lp[2 * j]).collect();
+    let y_lp4: Vec<f32> = (0..lag >> 2).map(|j| y[2 * j]).collect();
+    let mut xcorr = vec![0_f32; max_pitch >> 1];
+
+    // Coarse search with 4x decimation.
+    pitch_xcorr(&x_lp4, &y_lp4, &mut xcorr, len >> 2, max_pitch >> 2);
+    let best_pitch = find_best_pitch(&xcorr, &y_lp4, len >> 2, max_pitch >> 2);
+
+    // Finer search with 2x decimation.
+    (0..max_pitch >> 1).for_each(|i| {
+        xcorr[i] = 0.0;
+        let i = i as i32;
+        if (i - 2 * best_pitch[0] as i32).abs() > 2 && (i - 2 * best_pitch[1] as i32).abs() > 2 {
+            return;
+        }
+        let i = i as usize;
+        let sum = inner_prod(x_lp, &y[i..], len >> 1);
+        xcorr[i] = f32::max(-1.0, sum);
+    });
+    let best_pitch = find_best_pitch(&xcorr, y, len >> 1, max_pitch >> 1);
+
+    // Refine by pseudo-interpolation.
+    let offset = if best_pitch[0] > 0 && best_pitch[0] < (max_pitch >> 1) - 1 {
+        let a = xcorr[best_pitch[0] - 1];
+        let b = xcorr[best_pitch[0]];
+        let c = xcorr[best_pitch[0] + 1];
+        if (c - a) > 0.7 * (b - a) {
+            1
+        } else if (a - c) > 0.7 * (b - c) {
+            -1
+        } else {
+            0
+        }
+    } else {
+        0
+    };
+
+    (2 * best_pitch[0] as i32 - offset) as usize
+}
+
+/// Computes the normalized correlation.
+#[inline(always)]
+fn compute_pitch_gain(xy: f32, xx: f32, yy: f32) -> f32 {
+    xy / f64::from(1.0 + xx * yy).sqrt() as f32
+}
+
+/// Checks whether a fraction of the pitch period `t0` (in samples at half the rate)
+/// is a better match, to avoid choosing a multiple of the real period.
+///
+/// `x` contains `maxperiod` samples of history followed by `n` samples of the
+/// current frame. Returns the pitch gain of the chosen period.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn remove_doubling(
+    x: &[f32],
+    maxperiod: usize,
+    minperiod: usize,
+    n: usize,
+    t0_: &mut usize,
+    prev_period: usize,
+    prev_gain: f32,
+) -> f32 {
+    let minperiod0 = minperiod;
+    let maxperiod = maxperiod / 2;
+    let minperiod = minperiod / 2;
+    *t0_ /= 2;
+    let prev_period = prev_period / 2;
+    let n = n / 2;
+    // Index of the current frame, the history is accessed with negative offsets.
+    let x0 = maxperiod;
+    if *t0_ >= maxperiod {
+        *t0_ = maxperiod - 1;
+    }
+
+    let t0 = *t0_;
+    let mut t = t0;
+    let mut yy_lookup = vec![0_f32; maxperiod + 1];
+    let (xx, xy) = dual_inner_prod(&x[x0..], &x[x0..], &x[x0 - t0..], n);
+    yy_lookup[0] = xx;
+    let mut yy = xx;
+    (1..=maxperiod).for_each(|i| {
+        yy = yy + (x[x0 - i] * x[x0 - i]) - (x[x0 + n - i] * x[x0 + n - i]);
+        yy_lookup[i] = f32::max(0.0, yy);
+    });
+    yy = yy_lookup[t0];
+    let mut best_xy = xy;
+    let mut best_yy = yy;
+    let g0 = compute_pitch_gain(xy, xx, yy);
+    let mut g = g0;
+
+    // Look for any pitch at T/k.
+    for (k, second_check) in SECOND_CHECK.iter().enumerate().skip(2) {
+        let t1 = celt_udiv((2 * t0 + k) as i32, (2 * k) as i32) as usize;
+        if t1 < minperiod {
+            break;
+        }
+        // Look for another strong correlation at t1b.
+        let t1b = if k == 2 {
+            if t1 + t0 > maxperiod {
+                t0
+            } else {
+                t0 + t1
+            }
+        } else {
+            celt_udiv((2 * second_check * t0 + k) as i32, (2 * k) as i32) as usize
+        };
+        let (xy, xy2) = dual_inner_prod(&x[x0..], &x[x0 - t1..], &x[x0 - t1b..], n);
+        let xy = 0.5 * (xy + xy2);
+        let yy = 0.5 * (yy_lookup[t1] + yy_lookup[t1b]);
+        let g1 = compute_pitch_gain(xy, xx, yy);
+
+        let period_diff = (t1 as i32 - prev_period as i32).abs();
+        let cont = if period_diff <= 1 {
+            prev_gain
+        } else if period_diff <= 2 && 5 * k * k < t0 {
+            0.5 * prev_gain
+        } else {
+            0.0
+        };
+        // Bias against very high pitch (very short period) to avoid false-positives
+        // due to short-term correlation.
+        let thresh = if t1 < 3 * minperiod {
+            f32::max(0.4, (0.85 * g0) - cont)
+        } else {
+            f32::max(0.3, (0.7 * g0) - cont)
+        };
+        if g1 > thresh {
+            best_xy = xy;
+            best_yy = yy;
+            t = t1;
+            g = g1;
+        }
+    }
+
+    best_xy = f32::max(0.0, best_xy);
+    let mut pg = if best_yy <= best_xy {
+        1.0
+    } else {
+        best_xy / (best_yy + 1.0)
+    };
+
+    let mut xcorr = [0_f32; 3];
+    (0..3).for_each(|k| {
+        xcorr[k] = inner_prod(&x[x0..], &x[x0 + 1 - t - k..], n);
+    });
+    let offset = if (xcorr[2] - xcorr[0]) > 0.7 * (xcorr[1] - xcorr[0]) {
+        1
+    } else if (xcorr[0] - xcorr[2]) > 0.7 * (xcorr[1] - xcorr[2]) {
+        -1
+    } else {
+        0
+    };
+
+    if pg > g {
+        pg = g;
+    }
+    *t0_ = usize::max((2 * t as i32 + offset) as usize, minperiod0);
+
+    pg
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+
+    use crate::celt::{COMBFILTER_MAXPERIOD, COMBFILTER_MINPERIOD};
+
+    #[test]
+    fn test_pitch_search_finds_period() {
+        let n = 960;
+        let period = 200;
+        let len = COMBFILTER_MAXPERIOD + n;
+        let x: Vec<f32> = (0..len)
+            .map(|i| {
+                let phase = (i % period) as f32 / period as f32;
+                1000.0 * (1.0 - 2.0 * phase)
+            })
+            .collect();
+
+        let mut pitch_buf = vec![0_f32; len >> 1];
+        pitch_downsample(&x, &mut pitch_buf, len, 1);
+
+        let pitch_index = pitch_search(
+            &pitch_buf[COMBFILTER_MAXPERIOD >> 1..],
+            &pitch_buf,
+            n,
+            COMBFILTER_MAXPERIOD - 3 * COMBFILTER_MINPERIOD,
+        );
+        let mut pitch_index = COMBFILTER_MAXPERIOD - pitch_index;
+        let gain = remove_doubling(
+            &pitch_buf,
+            COMBFILTER_MAXPERIOD,
+            COMBFILTER_MINPERIOD,
+            n,
+            &mut pitch_index,
+            0,
+            0.0,
+        );
+
+        assert!((pitch_index as i32 - period as i32).abs() <= 2);
+        assert!(gain > 0.9);
+    }
+}
@@ -133,7 +133,7 @@
 //! small N (and indeed decoding is also O(N) for N<3).
 //!
 //! * Fis86: "A Pyramid Vector Quantizer"
-//!          by Thomas R. Fischer (1986).
+//!   by Thomas R. Fischer (1986).
 //!
 
 use crate::range_coder::{RangeDecoder, RangeEncoder};
@@ -167,7 +167,7 @@ fn icwrs(y: &[i32], n: u32) -> u32 {
     let mut i = if val < 0 { 1 } else { 0 };
     let mut k = i32::abs(val) as u32;
 
-    (0..j).into_iter().rev().for_each(|j| {
+    (0..j).rev().for_each(|j| {
         i += pvq_u(n - j, k);
         k += i32::abs(y[j as usize]) as u32;
         if y[j as usize] < 0 {
@@ -230,7 +230,7 @@ fn cwrsi(y: &mut [i32], mut n: u32, mut k: u32, mut i: u32) -> f32 {
             let q = CELT_PVQ_U_DATA[CELT_PVQ_U_ROW[k as usize + 1] + n as usize];
 
             if p <= i && i < q {
-                i -= p as u32;
+                i -= p;
                 y[yp] = 0;
                 yp += 1;
             } else {
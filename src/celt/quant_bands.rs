@@ -0,0 +1,529 @@
+//! Implements the quantization of the band energies.
+
+use crate::celt::mode::NB_E_BANDS;
+use crate::celt::rate::MAX_FINE_BITS;
+use crate::math::fast_log2;
+use crate::range_coder::{RangeDecoder, RangeEncoder, Tell};
+use crate::OpusError;
+
+/// Mean energy in each band quantized in Q4 and converted back to float.
+pub(crate) const E_MEANS: &[f32; 25] = &[
+    6.437_5, 6.25, 5.75, 5.312_5, 5.062_5, 4.812_5, 4.5, 4.375, 4.875, 4.687_5, 4.562_5, 4.437_5,
+    4.875, 4.625, 4.312_5, 4.5, 4.375, 4.625, 4.75, 4.437_5, 3.75, 3.75, 3.75, 3.75, 3.75,
+];
+
+/// Prediction coefficients: 0.9, 0.8, 0.65, 0.5
+const PRED_COEF: &[f32; 4] = &[
+    29440.0 / 32768.0,
+    26112.0 / 32768.0,
+    21248.0 / 32768.0,
+    16384.0 / 32768.0,
+];
+const BETA_COEF: &[f32; 4] = &[
+    30147.0 / 32768.0,
+    22282.0 / 32768.0,
+    12124.0 / 32768.0,
+    6554.0 / 32768.0,
+];
+const BETA_INTRA: f32 = 4915.0 / 32768.0;
+
+/// Parameters of the Laplace-like probability models used for the coarse energy.
+/// There is one pair of parameters for each frame size, prediction type
+/// (inter/intra), and band number.
+/// The first number of each pair is the probability of 0, and the second is the
+/// decay rate, both in Q8 precision.
+#[rustfmt::skip]
+const E_PROB_MODEL: &[[[u8; 42]; 2]; 4] = &[
+    // 120 sample frames.
+    [
+        // Inter
+        [
+            72, 127, 65, 129, 66, 128, 65, 128, 64, 128, 62, 128, 64, 128,
+            64, 128, 92, 78, 92, 79, 92, 78, 90, 79, 116, 41, 115, 40,
+            114, 40, 132, 26, 132, 26, 145, 17, 161, 12, 176, 10, 177, 11,
+        ],
+        // Intra
+        [
+            24, 179, 48, 138, 54, 135, 54, 132, 53, 134, 56, 133, 55, 132,
+            55, 132, 61, 114, 70, 96, 74, 88, 75, 88, 87, 74, 89, 66,
+            91, 67, 100, 59, 108, 50, 120, 40, 122, 37, 97, 43, 78, 50,
+        ],
+    ],
+    // 240 sample frames.
+    [
+        // Inter
+        [
+            83, 78, 84, 81, 88, 75, 86, 74, 87, 71, 90, 73, 93, 74,
+            93, 74, 109, 40, 114, 36, 117, 34, 117, 34, 143, 17, 145, 18,
+            146, 19, 162, 12, 165, 10, 178, 7, 189, 6, 190, 8, 177, 9,
+        ],
+        // Intra
+        [
+            23, 178, 54, 115, 63, 102, 66, 98, 69, 99, 74, 89, 71, 91,
+            73, 91, 78, 89, 86, 80, 92, 66, 93, 64, 102, 59, 103, 60,
+            104, 60, 117, 52, 123, 44, 138, 35, 133, 31, 97, 38, 77, 45,
+        ],
+    ],
+    // 480 sample frames.
+    [
+        // Inter
+        [
+            61, 90, 93, 60, 105, 42, 107, 41, 110, 45, 116, 38, 113, 38,
+            112, 38, 124, 26, 132, 27, 136, 19, 140, 20, 155, 14, 159, 16,
+            158, 18, 170, 13, 177, 10, 187, 8, 192, 6, 175, 9, 159, 10,
+        ],
+        // Intra
+        [
+            21, 178, 59, 110, 71, 86, 75, 85, 84, 83, 91, 66, 88, 73,
+            87, 72, 92, 75, 98, 72, 105, 58, 107, 54, 115, 52, 114, 55,
+            112, 56, 129, 51, 132, 40, 150, 33, 140, 29, 98, 35, 77, 42,
+        ],
+    ],
+    // 960 sample frames.
+    [
+        // Inter
+        [
+            42, 121, 96, 66, 108, 43, 111, 40, 117, 44, 123, 32, 120, 36,
+            119, 33, 127, 33, 134, 34, 139, 21, 147, 23, 152, 20, 158, 25,
+            154, 26, 166, 21, 173, 16, 184, 13, 184, 10, 150, 13, 139, 15,
+        ],
+        // Intra
+        [
+            22, 178, 63, 114, 74, 82, 84, 83, 92, 82, 103, 62, 96, 72,
+            96, 67, 101, 73, 107, 72, 113, 55, 118, 52, 125, 52, 118, 52,
+            117, 55, 135, 49, 137, 39, 157, 32, 145, 29, 97, 33, 77, 40,
+        ],
+    ],
+];
+
+const SMALL_ENERGY_ICDF: &[u8; 3] = &[2, 1, 0];
+
+/// Converts the band amplitudes into the log2 domain and removes the mean energy.
+pub(crate) fn amp2_log2(
+    eff_end: usize,
+    end: usize,
+    band_e: &[f32],
+    band_log_e: &mut [f32],
+    channels: usize,
+) {
+    (0..channels).for_each(|c| {
+        (0..eff_end).for_each(|i| {
+            band_log_e[i + c * NB_E_BANDS] = fast_log2(band_e[i + c * NB_E_BANDS]) - E_MEANS[i];
+        });
+        (eff_end..end).for_each(|i| {
+            band_log_e[i + c * NB_E_BANDS] = -14.0;
+        });
+    });
+}
+
+/// Measures how much the energy changed since the last frame.
+fn loss_distortion(
+    e_bands: &[f32],
+    old_ebands: &[f32],
+    start: usize,
+    end: usize,
+    channels: usize,
+) -> f32 {
+    let mut dist = 0.0;
+    (0..channels).for_each(|c| {
+        (start..end).for_each(|i| {
+            let d = e_bands[i + c * NB_E_BANDS] - old_ebands[i + c * NB_E_BANDS];
+            dist += d * d;
+        });
+    });
+    f32::min(200.0, dist)
+}
+
+/// Encodes the coarse energy with the given prediction type.
+///
+/// Returns the "badness", i.e. how much the quantized values had to be changed
+/// to fit into the budget.
+#[allow(clippy::too_many_arguments)]
+fn quant_coarse_energy_impl(
+    start: usize,
+    end: usize,
+    e_bands: &[f32],
+    old_ebands: &mut [f32],
+    budget: i32,
+    error: &mut [f32],
+    enc: &mut RangeEncoder,
+    channels: usize,
+    lm: usize,
+    intra: bool,
+    max_decay: f32,
+    lfe: bool,
+) -> Result<i32, OpusError> {
+    let prob_model = &E_PROB_MODEL[lm][intra as usize];
+    let mut prev = [0_f32; 2];
+    let mut badness = 0;
+
+    if enc.tell() as i32 + 3 <= budget {
+        enc.encode_bit_logp(intra as u32, 3)?;
+    }
+
+    let (coef, beta) = if intra {
+        (0.0, BETA_INTRA)
+    } else {
+        (PRED_COEF[lm], BETA_COEF[lm])
+    };
+
+    // Encode at a fixed coarse resolution.
+    (start..end).try_for_each(|i| {
+        (0..channels).try_for_each(|c| {
+            let x = e_bands[i + c * NB_E_BANDS];
+            let old_e = f32::max(-9.0, old_ebands[i + c * NB_E_BANDS]);
+            let f = x - coef * old_e - prev[c];
+            // Rounding to nearest integer here is really important!
+            let mut qi = (0.5 + f).floor() as i32;
+            let decay_bound = f32::max(-28.0, old_ebands[i + c * NB_E_BANDS]) - max_decay;
+
+            // Prevent the energy from going down too quickly (e.g. for bands
+            // that have just one bin).
+            if qi < 0 && x < decay_bound {
+                qi += (decay_bound - x) as i32;
+                if qi > 0 {
+                    qi = 0;
+                }
+            }
+            let qi0 = qi;
+
+            // If we don't have enough bits to encode all the energy, just assume
+            // something safe.
+            let tell = enc.tell() as i32;
+            let bits_left = budget - tell - 3 * channels as i32 * (end - i) as i32;
+            if i != start && bits_left < 30 {
+                if bits_left < 24 {
+                    qi = i32::min(1, qi);
+                }
+                if bits_left < 16 {
+                    qi = i32::max(-1, qi);
+                }
+            }
+            if lfe && i >= 2 {
+                qi = i32::min(qi, 0);
+            }
+
+            if budget - tell >= 15 {
+                let pi = 2 * usize::min(i, 20);
+                enc.encode_laplace(
+                    &mut qi,
+                    u32::from(prob_model[pi]) << 7,
+                    u32::from(prob_model[pi + 1]) << 6,
+                )?;
+            } else if budget - tell >= 2 {
+                qi = i32::max(-1, i32::min(qi, 1));
+                enc.encode_icdf(
+                    ((2 * qi) ^ -((qi < 0) as i32)) as usize,
+                    SMALL_ENERGY_ICDF,
+                    2,
+                )?;
+            } else if budget - tell >= 1 {
+                qi = i32::min(0, qi);
+                enc.encode_bit_logp(-qi as u32, 1)?;
+            } else {
+                qi = -1;
+            }
+
+            error[i + c * NB_E_BANDS] = f - qi as f32;
+            badness += (qi0 - qi).abs();
+            let q = qi as f32;
+
+            let tmp = (coef * old_e) + prev[c] + q;
+            old_ebands[i + c * NB_E_BANDS] = tmp;
+            prev[c] = prev[c] + q - (beta * q);
+
+            Ok(())
+        })
+    })?;
+
+    Ok(if lfe { 0 } else { badness })
+}
+
+/// Encodes the coarse energy of the bands.
+///
+/// Decides between intra and inter prediction. With `two_pass` both are tried
+/// and the cheaper one is kept.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn quant_coarse_energy(
+    start: usize,
+    end: usize,
+    eff_end: usize,
+    e_bands: &[f32],
+    old_ebands: &mut [f32],
+    budget: u32,
+    error: &mut [f32],
+    enc: &mut RangeEncoder,
+    channels: usize,
+    lm: usize,
+    nb_available_bytes: usize,
+    force_intra: bool,
+    delayed_intra: &mut f32,
+    mut two_pass: bool,
+    loss_rate: i32,
+    lfe: bool,
+) -> Result<(), OpusError> {
+    let mut intra = force_intra
+        || (!two_pass
+            && *delayed_intra > (2 * channels * (end - start)) as f32
+            && nb_available_bytes > (end - start) * channels);
+    let intra_bias =
+        ((budget as f32 * *delayed_intra * loss_rate as f32) / (channels * 512) as f32) as i32;
+    let new_distortion = loss_distortion(e_bands, old_ebands, start, eff_end, channels);
+
+    let tell = enc.tell();
+    if tell + 3 > budget {
+        two_pass = false;
+        intra = false;
+    }
+
+    let mut max_decay = 16.0;
+    if end - start > 10 {
+        max_decay = f32::min(max_decay, 0.125 * nb_available_bytes as f32);
+    }
+    if lfe {
+        max_decay = 3.0;
+    }
+    let enc_start_state = enc.state();
+
+    let mut old_ebands_intra = [0_f32; 2 * NB_E_BANDS];
+    let mut error_intra = [0_f32; 2 * NB_E_BANDS];
+    let len = channels * NB_E_BANDS;
+    old_ebands_intra[..len].copy_from_slice(&old_ebands[..len]);
+
+    let mut badness1 = 0;
+    if two_pass || intra {
+        badness1 = quant_coarse_energy_impl(
+            start,
+            end,
+            e_bands,
+            &mut old_ebands_intra,
+            budget as i32,
+            &mut error_intra,
+            enc,
+            channels,
+            lm,
+            true,
+            max_decay,
+            lfe,
+        )?;
+    }
+
+    if !intra {
+        let tell_intra = enc.tell_frac() as i32;
+        let enc_intra_state = enc.state();
+        let intra_bits = enc.bytes_since(&enc_start_state).to_vec();
+
+        enc.set_state(&enc_start_state);
+
+        let badness2 = quant_coarse_energy_impl(
+            start,
+            end,
+            e_bands,
+            old_ebands,
+            budget as i32,
+            error,
+            enc,
+            channels,
+            lm,
+            false,
+            max_decay,
+            lfe,
+        )?;
+
+        if two_pass
+            && (badness1 < badness2
+                || (badness1 == badness2 && enc.tell_frac() as i32 + intra_bias > tell_intra))
+        {
+            enc.set_state(&enc_intra_state);
+            // Copy intra bits to bit-stream.
+            enc.restore_bytes(&enc_start_state, &intra_bits);
+            old_ebands[..len].copy_from_slice(&old_ebands_intra[..len]);
+            error[..len].copy_from_slice(&error_intra[..len]);
+            intra = true;
+        }
+    } else {
+        old_ebands[..len].copy_from_slice(&old_ebands_intra[..len]);
+        error[..len].copy_from_slice(&error_intra[..len]);
+    }
+
+    if intra {
+        *delayed_intra = new_distortion;
+    } else {
+        *delayed_intra = PRED_COEF[lm] * PRED_COEF[lm] * *delayed_intra + new_distortion;
+    }
+
+    Ok(())
+}
+
+/// Encodes the fine energy of the bands.
+pub(crate) fn quant_fine_energy(
+    start: usize,
+    end: usize,
+    old_ebands: &mut [f32],
+    error: &mut [f32],
+    fine_quant: &[i32; NB_E_BANDS],
+    enc: &mut RangeEncoder,
+    channels: usize,
+) -> Result<(), OpusError> {
+    // Encode finer resolution.
+    (start..end).try_for_each(|i| {
+        if fine_quant[i] <= 0 {
+            return Ok(());
+        }
+        let frac = 1 << fine_quant[i];
+        (0..channels).try_for_each(|c| {
+            let q2 = ((error[i + c * NB_E_BANDS] + 0.5) * frac as f32).floor() as i32;
+            let q2 = i32::max(0, i32::min(frac - 1, q2));
+            enc.encode_bits(q2 as u32, fine_quant[i] as u32)?;
+            let offset =
+                (q2 as f32 + 0.5) * (1 << (14 - fine_quant[i])) as f32 * (1.0 / 16384.0) - 0.5;
+            old_ebands[i + c * NB_E_BANDS] += offset;
+            error[i + c * NB_E_BANDS] -= offset;
+            Ok(())
+        })
+    })
+}
+
+/// Uses up the remaining bits to refine the energy of the bands.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn quant_energy_finalise(
+    start: usize,
+    end: usize,
+    old_ebands: &mut [f32],
+    error: &mut [f32],
+    fine_quant: &[i32; NB_E_BANDS],
+    fine_priority: &[i32; NB_E_BANDS],
+    mut bits_left: i32,
+    enc: &mut RangeEncoder,
+    channels: usize,
+) -> Result<(), OpusError> {
+    // Use up the remaining bits.
+    (0..2).try_for_each(|prio| {
+        let mut i = start;
+        while i < end && bits_left >= channels as i32 {
+            if fine_quant[i] < MAX_FINE_BITS && fine_priority[i] == prio {
+                (0..channels).try_for_each(|c| {
+                    let q2 = if error[i + c * NB_E_BANDS] < 0.0 {
+                        0
+                    } else {
+                        1
+                    };
+                    enc.encode_bits(q2, 1)?;
+                    let offset = (q2 as f32 - 0.5)
+                        * (1 << (14 - fine_quant[i] - 1)) as f32
+                        * (1.0 / 16384.0);
+                    old_ebands[i + c * NB_E_BANDS] += offset;
+                    error[i + c * NB_E_BANDS] -= offset;
+                    bits_left -= 1;
+                    Ok(())
+                })?;
+            }
+            i += 1;
+        }
+        Ok(())
+    })
+}
+
+/// Decodes the coarse energy of the bands.
+pub(crate) fn unquant_coarse_energy(
+    start: usize,
+    end: usize,
+    old_ebands: &mut [f32],
+    intra: bool,
+    dec: &mut RangeDecoder,
+    channels: usize,
+    lm: usize,
+) {
+    let prob_model = &E_PROB_MODEL[lm][intra as usize];
+    let mut prev = [0_f32; 2];
+
+    let (coef, beta) = if intra {
+        (0.0, BETA_INTRA)
+    } else {
+        (PRED_COEF[lm], BETA_COEF[lm])
+    };
+
+    let budget = dec.storage() as i32 * 8;
+
+    // Decode at a fixed coarse resolution.
+    (start..end).for_each(|i| {
+        (0..channels).for_each(|c| {
+            let tell = dec.tell() as i32;
+            let qi = if budget - tell >= 15 {
+                let pi = 2 * usize::min(i, 20);
+                dec.decode_laplace(
+                    u32::from(prob_model[pi]) << 7,
+                    u32::from(prob_model[pi + 1]) << 6,
+                )
+            } else if budget - tell >= 2 {
+                let qi = dec.decode_icdf(SMALL_ENERGY_ICDF, 2) as i32;
+                (qi >> 1) ^ -(qi & 1)
+            } else if budget - tell >= 1 {
+                -(dec.decode_bit_logp(1) as i32)
+            } else {
+                -1
+            };
+            let q = qi as f32;
+
+            let old = &mut old_ebands[i + c * NB_E_BANDS];
+            *old = f32::max(-9.0, *old);
+            let tmp = (coef * *old) + prev[c] + q;
+            *old = tmp;
+            prev[c] = prev[c] + q - (beta * q);
+        });
+    });
+}
+
+/// Decodes the fine energy of the bands.
+pub(crate) fn unquant_fine_energy(
+    start: usize,
+    end: usize,
+    old_ebands: &mut [f32],
+    fine_quant: &[i32; NB_E_BANDS],
+    dec: &mut RangeDecoder,
+    channels: usize,
+) {
+    // Decode finer resolution.
+    (start..end).for_each(|i| {
+        if fine_quant[i] <= 0 {
+            return;
+        }
+        (0..channels).for_each(|c| {
+            let q2 = dec.decode_bits(fine_quant[i] as u32);
+            let offset =
+                (q2 as f32 + 0.5) * (1 << (14 - fine_quant[i])) as f32 * (1.0 / 16384.0) - 0.5;
+            old_ebands[i + c * NB_E_BANDS] += offset;
+        });
+    });
+}
+
+/// Uses up the remaining bits to refine the energy of the bands.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn unquant_energy_finalise(
+    start: usize,
+    end: usize,
+    old_ebands: &mut [f32],
+    fine_quant: &[i32; NB_E_BANDS],
+    fine_priority: &[i32; NB_E_BANDS],
+    mut bits_left: i32,
+    dec: &mut RangeDecoder,
+    channels: usize,
+) {
+    // Use up the remaining bits.
+    (0..2).for_each(|prio| {
+        let mut i = start;
+        while i < end && bits_left >= channels as i32 {
+            if fine_quant[i] < MAX_FINE_BITS && fine_priority[i] == prio {
+                (0..channels).for_each(|c| {
+                    let q2 = dec.decode_bits(1);
+                    let offset = (q2 as f32 - 0.5)
+                        * (1 << (14 - fine_quant[i] - 1)) as f32
+                        * (1.0 / 16384.0);
+                    old_ebands[i + c * NB_E_BANDS] += offset;
+                    bits_left -= 1;
+                });
+            }
+            i += 1;
+        }
+    });
+}
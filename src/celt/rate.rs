@@ -0,0 +1,589 @@
+//! Implements the bit allocation.
+
+use crate::celt::mode::{
+    ALLOC_VECTORS, CACHE_BITS, CACHE_CAPS, CACHE_INDEX, E_BANDS, LOG_N, NB_ALLOC_VECTORS,
+    NB_E_BANDS,
+};
+use crate::range_coder::{RangeCoder, BITRES};
+use crate::OpusError;
+
+pub(crate) const MAX_FINE_BITS: i32 = 8;
+pub(crate) const FINE_OFFSET: i32 = 21;
+pub(crate) const QTHETA_OFFSET: i32 = 4;
+pub(crate) const QTHETA_OFFSET_TWOPHASE: i32 = 16;
+
+const LOG_MAX_PSEUDO: usize = 6;
+const ALLOC_STEPS: i32 = 6;
+const BITRES_I: i32 = BITRES as i32;
+
+const LOG2_FRAC_TABLE: &[u8; 24] = &[
+    0, 8, 13, 16, 19, 21, 23, 24, 26, 27, 28, 29, 30, 31, 32, 32, 33, 34, 34, 35, 36, 36, 37, 37,
+];
+
+/// The result of the bit allocation.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Allocation {
+    /// The number of bands that are coded.
+    pub(crate) coded_bands: usize,
+    /// The first band that uses intensity stereo.
+    pub(crate) intensity: usize,
+    /// Whether dual stereo is used.
+    pub(crate) dual_stereo: bool,
+    /// Remaining bits over the cap used for re-balancing.
+    pub(crate) balance: i32,
+    /// Bits allocated to PVQ for each band (in 1/8 bits).
+    pub(crate) pulses: [i32; NB_E_BANDS],
+    /// Fine energy bits for each band.
+    pub(crate) fine_quant: [i32; NB_E_BANDS],
+    /// Priority of each band for the final fine energy pass.
+    pub(crate) fine_priority: [i32; NB_E_BANDS],
+}
+
+/// Unsigned division of two signed integers.
+#[inline(always)]
+pub(crate) fn celt_udiv(n: i32, d: i32) -> i32 {
+    (n as u32 / d as u32) as i32
+}
+
+/// Returns the pulse cache for the given band and LM.
+#[inline(always)]
+fn cache(band: usize, lm: i32) -> &'static [u8] {
+    let index = CACHE_INDEX[(lm + 1) as usize * NB_E_BANDS + band];
+    &CACHE_BITS[index as usize..]
+}
+
+/// Returns the maximum number of bits that can be spend on the given band.
+#[inline(always)]
+pub(crate) fn bits_cache_max(band: usize, lm: i32) -> i32 {
+    let cache = cache(band, lm);
+    i32::from(cache[usize::from(cache[0])])
+}
+
+/// Converts the pseudo pulse value into the number of pulses.
+#[inline(always)]
+pub(crate) fn get_pulses(i: i32) -> i32 {
+    if i < 8 {
+        i
+    } else {
+        (8 + (i & 7)) << ((i >> 3) - 1)
+    }
+}
+
+/// Returns the number of pseudo pulses that are closest to the given bits.
+pub(crate) fn bits2pulses(band: usize, lm: i32, bits: i32) -> i32 {
+    let cache = cache(band, lm);
+
+    let mut lo = 0;
+    let mut hi = usize::from(cache[0]);
+    let bits = bits - 1;
+    (0..LOG_MAX_PSEUDO).for_each(|_| {
+        let mid = (lo + hi + 1) >> 1;
+        if i32::from(cache[mid]) >= bits {
+            hi = mid;
+        } else {
+            lo = mid;
+        }
+    });
+
+    let lo_bits = if lo == 0 { -1 } else { i32::from(cache[lo]) };
+    if bits - lo_bits <= i32::from(cache[hi]) - bits {
+        lo as i32
+    } else {
+        hi as i32
+    }
+}
+
+/// Returns the number of bits needed for the given number of pseudo pulses.
+pub(crate) fn pulses2bits(band: usize, lm: i32, pulses: i32) -> i32 {
+    if pulses == 0 {
+        0
+    } else {
+        i32::from(cache(band, lm)[pulses as usize]) + 1
+    }
+}
+
+/// Calculates the maximum number of bits that can be allocated to each band.
+pub(crate) fn init_caps(cap: &mut [i32; NB_E_BANDS], lm: i32, channels: i32) {
+    cap.iter_mut().enumerate().for_each(|(i, cap)| {
+        let n = i32::from(E_BANDS[i + 1] - E_BANDS[i]) << lm;
+        let caps = i32::from(CACHE_CAPS[NB_E_BANDS * (2 * lm + channels - 1) as usize + i]);
+        *cap = ((caps + 64) * channels * n) >> 2;
+    });
+}
+
+/// Computes the pulse allocation, i.e. how many pulses will go in each band.
+///
+/// When encoding, `intensity` and `dual_stereo` are the requested stereo
+/// parameters, `prev` is the number of bands coded in the last frame and
+/// `signal_bandwidth` the last band that contains signal. The decoder reads
+/// all of them from the stream and ignores the given values.
+///
+/// Returns the allocation, which includes the number of coded bands.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn compute_allocation(
+    start: usize,
+    end: usize,
+    offsets: &[i32; NB_E_BANDS],
+    cap: &[i32; NB_E_BANDS],
+    alloc_trim: i32,
+    intensity: usize,
+    dual_stereo: bool,
+    total: i32,
+    channels: i32,
+    lm: i32,
+    rc: &mut RangeCoder,
+    prev: usize,
+    signal_bandwidth: usize,
+) -> Result<Allocation, OpusError> {
+    let mut total = i32::max(total, 0);
+    let mut skip_start = start;
+
+    // Reserve a bit to signal the end of manually skipped bands.
+    let skip_rsv = if total >= 1 << BITRES { 1 << BITRES } else { 0 };
+    total -= skip_rsv;
+
+    // Reserve bits for the intensity and dual stereo parameters.
+    let mut intensity_rsv = 0;
+    let mut dual_stereo_rsv = 0;
+    if channels == 2 {
+        intensity_rsv = i32::from(LOG2_FRAC_TABLE[end - start]);
+        if intensity_rsv > total {
+            intensity_rsv = 0;
+        } else {
+            total -= intensity_rsv;
+            dual_stereo_rsv = if total >= 1 << BITRES { 1 << BITRES } else { 0 };
+            total -= dual_stereo_rsv;
+        }
+    }
+
+    let mut bits1 = [0_i32; NB_E_BANDS];
+    let mut bits2 = [0_i32; NB_E_BANDS];
+    let mut thresh = [0_i32; NB_E_BANDS];
+    let mut trim_offset = [0_i32; NB_E_BANDS];
+
+    (start..end).for_each(|j| {
+        let n = i32::from(E_BANDS[j + 1] - E_BANDS[j]);
+        // Below this threshold, we're sure not to allocate any PVQ bits.
+        thresh[j] = i32::max(channels << BITRES, ((3 * n) << lm << BITRES) >> 4);
+        // Tilt of the allocation curve.
+        trim_offset[j] =
+            (channels * n * (alloc_trim - 5 - lm) * (end - j - 1) as i32 * (1 << (lm + BITRES_I)))
+                >> 6;
+        // Giving less resolution to single-coefficient bands because they get
+        // more benefit from having one coarse value per coefficient.
+        if n << lm == 1 {
+            trim_offset[j] -= channels << BITRES;
+        }
+    });
+
+    let mut lo = 1;
+    let mut hi = NB_ALLOC_VECTORS as i32 - 1;
+    loop {
+        let mut done = false;
+        let mut psum = 0;
+        let mid = (lo + hi) >> 1;
+        (start..end).rev().for_each(|j| {
+            let n = i32::from(E_BANDS[j + 1] - E_BANDS[j]);
+            let mut bitsj =
+                (channels * n * i32::from(ALLOC_VECTORS[mid as usize * NB_E_BANDS + j])) << lm >> 2;
+            if bitsj > 0 {
+                bitsj = i32::max(0, bitsj + trim_offset[j]);
+            }
+            bitsj += offsets[j];
+            if bitsj >= thresh[j] || done {
+                done = true;
+                // Don't allocate more than we can actually use.
+                psum += i32::min(bitsj, cap[j]);
+            } else if bitsj >= channels << BITRES {
+                psum += channels << BITRES;
+            }
+        });
+        if psum > total {
+            hi = mid - 1;
+        } else {
+            lo = mid + 1;
+        }
+        if lo > hi {
+            break;
+        }
+    }
+
+    hi = lo;
+    lo -= 1;
+
+    (start..end).for_each(|j| {
+        let n = i32::from(E_BANDS[j + 1] - E_BANDS[j]);
+        let mut bits1j =
+            (channels * n * i32::from(ALLOC_VECTORS[lo as usize * NB_E_BANDS + j])) << lm >> 2;
+        let mut bits2j = if hi >= NB_ALLOC_VECTORS as i32 {
+            cap[j]
+        } else {
+            (channels * n * i32::from(ALLOC_VECTORS[hi as usize * NB_E_BANDS + j])) << lm >> 2
+        };
+        if bits1j > 0 {
+            bits1j = i32::max(0, bits1j + trim_offset[j]);
+        }
+        if bits2j > 0 {
+            bits2j = i32::max(0, bits2j + trim_offset[j]);
+        }
+        if lo > 0 {
+            bits1j += offsets[j];
+        }
+        bits2j += offsets[j];
+        if offsets[j] > 0 {
+            skip_start = j;
+        }
+        bits2j = i32::max(0, bits2j - bits1j);
+        bits1[j] = bits1j;
+        bits2[j] = bits2j;
+    });
+
+    interp_bits2pulses(
+        start,
+        end,
+        skip_start,
+        &bits1,
+        &bits2,
+        &thresh,
+        cap,
+        total,
+        skip_rsv,
+        intensity_rsv,
+        dual_stereo_rsv,
+        intensity,
+        dual_stereo,
+        channels,
+        lm,
+        rc,
+        prev,
+        signal_bandwidth,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn interp_bits2pulses(
+    start: usize,
+    end: usize,
+    skip_start: usize,
+    bits1: &[i32; NB_E_BANDS],
+    bits2: &[i32; NB_E_BANDS],
+    thresh: &[i32; NB_E_BANDS],
+    cap: &[i32; NB_E_BANDS],
+    mut total: i32,
+    skip_rsv: i32,
+    mut intensity_rsv: i32,
+    mut dual_stereo_rsv: i32,
+    intensity: usize,
+    dual_stereo: bool,
+    channels: i32,
+    lm: i32,
+    rc: &mut RangeCoder,
+    prev: usize,
+    signal_bandwidth: usize,
+) -> Result<Allocation, OpusError> {
+    let mut allocation = Allocation::default();
+    let bits = &mut allocation.pulses;
+    let ebits = &mut allocation.fine_quant;
+    let fine_priority = &mut allocation.fine_priority;
+
+    let alloc_floor = channels << BITRES;
+    let stereo = (channels > 1) as i32;
+
+    let log_m = lm << BITRES;
+    let mut lo = 0;
+    let mut hi = 1 << ALLOC_STEPS;
+    (0..ALLOC_STEPS).for_each(|_| {
+        let mid = (lo + hi) >> 1;
+        let mut psum = 0;
+        let mut done = false;
+        (start..end).rev().for_each(|j| {
+            let tmp = bits1[j] + ((mid * bits2[j]) >> ALLOC_STEPS);
+            if tmp >= thresh[j] || done {
+                done = true;
+                // Don't allocate more than we can actually use.
+                psum += i32::min(tmp, cap[j]);
+            } else if tmp >= alloc_floor {
+                psum += alloc_floor;
+            }
+        });
+        if psum > total {
+            hi = mid;
+        } else {
+            lo = mid;
+        }
+    });
+
+    let mut psum = 0;
+    let mut done = false;
+    (start..end).rev().for_each(|j| {
+        let mut tmp = bits1[j] + ((lo * bits2[j]) >> ALLOC_STEPS);
+        if tmp < thresh[j] && !done {
+            if tmp >= alloc_floor {
+                tmp = alloc_floor;
+            } else {
+                tmp = 0;
+            }
+        } else {
+            done = true;
+        }
+        // Don't allocate more than we can actually use.
+        tmp = i32::min(tmp, cap[j]);
+        bits[j] = tmp;
+        psum += tmp;
+    });
+
+    // Decide which bands to skip, working backwards from the end.
+    let mut coded_bands = end;
+    loop {
+        let j = coded_bands - 1;
+        // Never skip the first band, nor a band that has been boosted by
+        // dynalloc.
+        // In the first case, we'd be coding a bit to signal we're going to waste
+        // all the other bits.
+        // In the second case, we'd be coding a bit to redistribute all the bits
+        // we just signaled should be concentrated in this band.
+        if j <= skip_start {
+            // Give the bit we reserved to end skipping back.
+            total += skip_rsv;
+            break;
+        }
+
+        // Figure out how many left-over bits we would be adding to this band.
+        // This can include bits we've stolen back from higher, skipped bands.
+        let mut left = total - psum;
+        let percoeff = celt_udiv(left, i32::from(E_BANDS[coded_bands] - E_BANDS[start]));
+        left -= i32::from(E_BANDS[coded_bands] - E_BANDS[start]) * percoeff;
+        let rem = i32::max(left - i32::from(E_BANDS[j] - E_BANDS[start]), 0);
+        let band_width = i32::from(E_BANDS[coded_bands] - E_BANDS[j]);
+        let mut band_bits = bits[j] + percoeff * band_width + rem;
+
+        // Only code a skip decision if we're above the threshold for this band.
+        // Otherwise it is force-skipped.
+        // This ensures that we have enough bits to code the skip flag.
+        if band_bits >= i32::max(thresh[j], alloc_floor + (1 << BITRES)) {
+            match rc {
+                RangeCoder::Encoder(enc) => {
+                    // This block is the only part of the allocation function that
+                    // is not a mandatory part of the bitstream: any bands we choose to
+                    // skip here must be explicitly signaled.
+                    // We choose a threshold with some hysteresis to keep bands from
+                    // fluctuating in and out, but we try not to fold below a certain point.
+                    let depth_threshold = if coded_bands > 17 {
+                        if j < prev {
+                            7
+                        } else {
+                            9
+                        }
+                    } else {
+                        0
+                    };
+                    if coded_bands <= start + 2
+                        || (band_bits > ((depth_threshold * band_width) << lm << BITRES) >> 4
+                            && j <= signal_bandwidth)
+                    {
+                        enc.encode_bit_logp(1, 1)?;
+                        break;
+                    }
+                    enc.encode_bit_logp(0, 1)?;
+                }
+                RangeCoder::Decoder(dec) => {
+                    if dec.decode_bit_logp(1) {
+                        break;
+                    }
+                }
+            }
+            // We used a bit to skip this band.
+            psum += 1 << BITRES;
+            band_bits -= 1 << BITRES;
+        }
+
+        // Reclaim the bits originally allocated to this band.
+        psum -= bits[j] + intensity_rsv;
+        if intensity_rsv > 0 {
+            intensity_rsv = i32::from(LOG2_FRAC_TABLE[j - start]);
+        }
+        psum += intensity_rsv;
+        if band_bits >= alloc_floor {
+            // If we have enough for a fine energy bit per channel, use it.
+            psum += alloc_floor;
+            bits[j] = alloc_floor;
+        } else {
+            // Otherwise this band gets nothing at all.
+            bits[j] = 0;
+        }
+
+        coded_bands -= 1;
+    }
+
+    debug_assert!(coded_bands > start);
+
+    // Code the intensity and dual stereo parameters.
+    let intensity = if intensity_rsv > 0 {
+        match rc {
+            RangeCoder::Encoder(enc) => {
+                let intensity = usize::min(intensity, coded_bands);
+                enc.encode_uint((intensity - start) as u32, (coded_bands + 1 - start) as u32)?;
+                intensity
+            }
+            RangeCoder::Decoder(dec) => {
+                start + dec.decode_uint((coded_bands + 1 - start) as u32) as usize
+            }
+        }
+    } else {
+        0
+    };
+    if intensity <= start {
+        total += dual_stereo_rsv;
+        dual_stereo_rsv = 0;
+    }
+    let dual_stereo = if dual_stereo_rsv > 0 {
+        match rc {
+            RangeCoder::Encoder(enc) => {
+                enc.encode_bit_logp(dual_stereo as u32, 1)?;
+                dual_stereo
+            }
+            RangeCoder::Decoder(dec) => dec.decode_bit_logp(1),
+        }
+    } else {
+        false
+    };
+
+    // Allocate the remaining bits.
+    let mut left = total - psum;
+    let percoeff = celt_udiv(left, i32::from(E_BANDS[coded_bands] - E_BANDS[start]));
+    left -= i32::from(E_BANDS[coded_bands] - E_BANDS[start]) * percoeff;
+    (start..coded_bands).for_each(|j| {
+        bits[j] += percoeff * i32::from(E_BANDS[j + 1] - E_BANDS[j]);
+    });
+    (start..coded_bands).for_each(|j| {
+        let tmp = i32::min(left, i32::from(E_BANDS[j + 1] - E_BANDS[j]));
+        bits[j] += tmp;
+        left -= tmp;
+    });
+
+    let mut balance = 0;
+    (start..coded_bands).for_each(|j| {
+        debug_assert!(bits[j] >= 0);
+
+        let n0 = i32::from(E_BANDS[j + 1] - E_BANDS[j]);
+        let n = n0 << lm;
+        let bit = bits[j] + balance;
+        let mut excess;
+
+        if n > 1 {
+            excess = i32::max(bit - cap[j], 0);
+            bits[j] = bit - excess;
+
+            // Compensate for the extra DoF in stereo.
+            let den = channels * n
+                + if channels == 2 && n > 2 && !dual_stereo && j < intensity {
+                    1
+                } else {
+                    0
+                };
+
+            let nclogn = den * (i32::from(LOG_N[j]) + log_m);
+
+            // Offset for the number of fine bits by log2(N)/2 + FINE_OFFSET
+            // compared to their "fair share" of total/N.
+            let mut offset = (nclogn >> 1) - den * FINE_OFFSET;
+
+            // N=2 is the only point that doesn't match the curve.
+            if n == 2 {
+                offset += den << BITRES >> 2;
+            }
+
+            // Changing the offset for allocating the second and third
+            // fine energy bit.
+            if bits[j] + offset < (den * 2) << BITRES {
+                offset += nclogn >> 2;
+            } else if bits[j] + offset < (den * 3) << BITRES {
+                offset += nclogn >> 3;
+            }
+
+            // Divide with rounding.
+            ebits[j] = i32::max(0, bits[j] + offset + (den << (BITRES - 1)));
+            ebits[j] = (ebits[j] / den) >> BITRES;
+
+            // Make sure not to bust.
+            if channels * ebits[j] > (bits[j] >> BITRES) {
+                ebits[j] = bits[j] >> stereo >> BITRES;
+            }
+
+            // More than that is useless because that's about as far as PVQ can go.
+            ebits[j] = i32::min(ebits[j], MAX_FINE_BITS);
+
+            // If we rounded down or capped this band, make it a candidate for the
+            // final fine energy pass.
+            fine_priority[j] = (ebits[j] * (den << BITRES) >= bits[j] + offset) as i32;
+
+            // Remove the allocated fine bits; the rest are assigned to PVQ.
+            bits[j] -= (channels * ebits[j]) << BITRES;
+        } else {
+            // For N=1, all bits go to fine energy except for a single sign bit.
+            excess = i32::max(0, bit - (channels << BITRES));
+            bits[j] = bit - excess;
+            ebits[j] = 0;
+            fine_priority[j] = 1;
+        }
+
+        // Fine energy can't take advantage of the re-balancing in
+        // quant_all_bands(). Instead, do the re-balancing here.
+        if excess > 0 {
+            let extra_fine = i32::min(excess >> (stereo + BITRES_I), MAX_FINE_BITS - ebits[j]);
+            ebits[j] += extra_fine;
+            let extra_bits = (extra_fine * channels) << BITRES;
+            fine_priority[j] = (extra_bits >= excess - balance) as i32;
+            excess -= extra_bits;
+        }
+        balance = excess;
+
+        debug_assert!(bits[j] >= 0);
+        debug_assert!(ebits[j] >= 0);
+    });
+
+    // The skipped bands use all their bits for fine energy.
+    (coded_bands..end).for_each(|j| {
+        ebits[j] = bits[j] >> stereo >> BITRES;
+        debug_assert_eq!((channels * ebits[j]) << BITRES, bits[j]);
+        bits[j] = 0;
+        fine_priority[j] = (ebits[j] < 1) as i32;
+    });
+
+    allocation.coded_bands = coded_bands;
+    allocation.intensity = intensity;
+    allocation.dual_stereo = dual_stereo;
+    allocation.balance = balance;
+
+    Ok(allocation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_pulses() {
+        assert_eq!(get_pulses(0), 0);
+        assert_eq!(get_pulses(7), 7);
+        assert_eq!(get_pulses(8), 8);
+        assert_eq!(get_pulses(15), 15);
+        assert_eq!(get_pulses(16), 16);
+        assert_eq!(get_pulses(17), 18);
+        assert_eq!(get_pulses(39), 120);
+    }
+
+    #[test]
+    fn test_bits2pulses_roundtrip() {
+        (0..NB_E_BANDS).for_each(|band| {
+            (0..4).for_each(|lm| {
+                let max = i32::from(cache(band, lm)[0]);
+                (0..=max).for_each(|q| {
+                    // Different pulse counts can map to the same number of bits.
+                    let bits = pulses2bits(band, lm, q);
+                    assert_eq!(pulses2bits(band, lm, bits2pulses(band, lm, bits)), bits);
+                });
+            });
+        });
+    }
+}
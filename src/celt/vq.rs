@@ -0,0 +1,373 @@
+//! Implements the vector quantization.
+
+use std::f32::consts::PI;
+
+use crate::celt::pvc::{decode_pulses, encode_pulses};
+use crate::math::fast_atan2;
+use crate::range_coder::{RangeDecoder, RangeEncoder};
+use crate::OpusError;
+
+pub(crate) const SPREAD_NONE: u32 = 0;
+pub(crate) const SPREAD_LIGHT: u32 = 1;
+pub(crate) const SPREAD_NORMAL: u32 = 2;
+pub(crate) const SPREAD_AGGRESSIVE: u32 = 3;
+
+const SPREAD_FACTOR: [usize; 3] = [15, 10, 5];
+
+/// Small value to avoid divisions by zero.
+pub(crate) const EPSILON: f32 = 1e-15;
+
+/// Calculates the inner product of two vectors.
+#[inline(always)]
+pub(crate) fn inner_prod(x: &[f32], y: &[f32], n: usize) -> f32 {
+    x[..n]
+        .iter()
+        .zip(y[..n].iter())
+        .fold(0.0, |acc, (x, y)| acc + (x * y))
+}
+
+/// Cosine of the given angle, where 1.0 corresponds to 90°.
+#[inline(always)]
+pub(crate) fn cos_norm(x: f32) -> f32 {
+    f64::from((0.5 * PI) * x).cos() as f32
+}
+
+fn exp_rotation1(x: &mut [f32], len: usize, stride: usize, c: f32, s: f32) {
+    let ms = -s;
+    (0..len - stride).for_each(|i| {
+        let x1 = x[i];
+        let x2 = x[i + stride];
+        x[i + stride] = (c * x2) + (s * x1);
+        x[i] = (c * x1) + (ms * x2);
+    });
+
+    if len > 2 * stride {
+        (0..len - 2 * stride).rev().for_each(|i| {
+            let x1 = x[i];
+            let x2 = x[i + stride];
+            x[i + stride] = (c * x2) + (s * x1);
+            x[i] = (c * x1) + (ms * x2);
+        });
+    }
+}
+
+/// Applies a spreading rotation to the given vector.
+pub(crate) fn exp_rotation(
+    x: &mut [f32],
+    len: usize,
+    dir: i32,
+    stride: usize,
+    k: u32,
+    spread: u32,
+) {
+    if 2 * k as usize >= len || spread == SPREAD_NONE {
+        return;
+    }
+
+    let factor = SPREAD_FACTOR[spread as usize - 1];
+
+    let gain = len as f32 / (len + factor * k as usize) as f32;
+    let theta = 0.5 * (gain * gain);
+
+    let c = cos_norm(theta);
+    // sin(theta)
+    let s = cos_norm(1.0 - theta);
+
+    let mut stride2 = 0;
+    if len >= 8 * stride {
+        stride2 = 1;
+        // This is just a simple (equivalent) way of computing sqrt(len/stride) with rounding.
+        // It's basically incrementing long as (stride2+0.5)^2 < len/stride.
+        while (stride2 * stride2 + stride2) * stride + (stride >> 2) < len {
+            stride2 += 1;
+        }
+    }
+
+    let len = len / stride;
+    (0..stride).for_each(|i| {
+        let x = &mut x[i * len..];
+        if dir < 0 {
+            if stride2 != 0 {
+                exp_rotation1(x, len, stride2, s, c);
+            }
+            exp_rotation1(x, len, 1, c, s);
+        } else {
+            exp_rotation1(x, len, 1, c, -s);
+            if stride2 != 0 {
+                exp_rotation1(x, len, stride2, s, -c);
+            }
+        }
+    });
+}
+
+/// Takes the pitch vector and the decoded residual vector, computes the gain
+/// that will give ||p+g*y||=1 and mixes the residual with the pitch.
+fn normalise_residual(iy: &[i32], x: &mut [f32], n: usize, ryy: f32, gain: f32) {
+    let g = (1.0 / ryy.sqrt()) * gain;
+    x[..n]
+        .iter_mut()
+        .zip(iy.iter())
+        .for_each(|(x, iy)| *x = g * *iy as f32);
+}
+
+fn extract_collapse_mask(iy: &[i32], n: usize, b: usize) -> u32 {
+    if b <= 1 {
+        return 1;
+    }
+
+    let n0 = n / b;
+    (0..b).fold(0, |collapse_mask, i| {
+        let tmp = iy[i * n0..(i + 1) * n0].iter().fold(0, |tmp, x| tmp | x);
+        collapse_mask | (((tmp != 0) as u32) << i)
+    })
+}
+
+/// Searches for the pulse vector with `k` pulses that best matches the
+/// direction of `x`.
+///
+/// `x` is replaced by its absolute values. Returns the squared norm of the
+/// pulse vector.
+fn op_pvq_search(x: &mut [f32], iy: &mut [i32], k: u32, n: usize) -> f32 {
+    let mut y = [0_f32; 176];
+    let mut signx = [false; 176];
+
+    // Get rid of the sign.
+    (0..n).for_each(|j| {
+        signx[j] = x[j] < 0.0;
+        x[j] = x[j].abs();
+        iy[j] = 0;
+        y[j] = 0.0;
+    });
+
+    let mut xy = 0.0;
+    let mut yy = 0.0;
+    let mut pulses_left = k as i32;
+
+    // Do a pre-search by projecting on the pyramid.
+    if k as usize > n >> 1 {
+        let mut sum = x[..n].iter().fold(0.0, |acc, x| acc + *x);
+
+        // If X is too small, just replace it with a pulse at 0.
+        // Prevents infinities and NaNs from causing too many pulses
+        // to be allocated. 64 is an approximation of infinity here.
+        if !(sum > EPSILON && sum < 64.0) {
+            x[0] = 1.0;
+            x[1..n].iter_mut().for_each(|x| *x = 0.0);
+            sum = 1.0;
+        }
+
+        // Using K+e with e < 1 guarantees we cannot get more than K pulses.
+        let rcp = (k as f32 + 0.8) * (1.0 / sum);
+        (0..n).for_each(|j| {
+            iy[j] = (rcp * x[j]).floor() as i32;
+            y[j] = iy[j] as f32;
+            yy += y[j] * y[j];
+            xy += x[j] * y[j];
+            y[j] *= 2.0;
+            pulses_left -= iy[j];
+        });
+    }
+    debug_assert!(pulses_left >= 0);
+
+    // This should never happen, but just in case it does (e.g. on silence)
+    // we fill the first bin with pulses.
+    if pulses_left > n as i32 + 3 {
+        let tmp = pulses_left as f32;
+        yy += tmp * tmp;
+        yy += tmp * y[0];
+        iy[0] += pulses_left;
+        pulses_left = 0;
+    }
+
+    (0..pulses_left).for_each(|_| {
+        let mut best_id = 0;
+        // The squared magnitude term gets added anyway, so we might as well
+        // add it outside the loop.
+        yy += 1.0;
+
+        // Calculations for position 0 are out of the loop.
+        // Temporary sums of the new pulse(s).
+        let rxy = xy + x[0];
+        // We're multiplying y[j] by two so we don't have to do it here.
+        let ryy = yy + y[0];
+
+        // Approximate score: we maximise Rxy/sqrt(Ryy) (we're guaranteed that
+        // Rxy is positive because the sign is pre-computed).
+        let rxy = rxy * rxy;
+        let mut best_den = ryy;
+        let mut best_num = rxy;
+        (1..n).for_each(|j| {
+            let rxy = xy + x[j];
+            let ryy = yy + y[j];
+            let rxy = rxy * rxy;
+            // The idea is to check for num/den >= best_num/best_den, but that way
+            // we can do it without any division.
+            if best_den * rxy > ryy * best_num {
+                best_den = ryy;
+                best_num = rxy;
+                best_id = j;
+            }
+        });
+
+        // Updating the sums of the new pulse(s).
+        xy += x[best_id];
+        yy += y[best_id];
+
+        // Only now that we've made the final choice, update y/iy.
+        y[best_id] += 2.0;
+        iy[best_id] += 1;
+    });
+
+    // Put the original sign back.
+    iy[..n]
+        .iter_mut()
+        .zip(signx.iter())
+        .for_each(|(iy, signx)| {
+            if *signx {
+                *iy = -*iy;
+            }
+        });
+
+    yy
+}
+
+/// Searches and encodes the pulses of the given vector.
+///
+/// The vector is replaced by its quantized version.
+///
+/// Returns the collapse mask.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn alg_quant(
+    x: &mut [f32],
+    n: usize,
+    k: u32,
+    spread: u32,
+    b: usize,
+    enc: &mut RangeEncoder,
+    gain: f32,
+) -> Result<u32, OpusError> {
+    debug_assert!(k > 0, "alg_quant() needs at least one pulse");
+    debug_assert!(n > 1, "alg_quant() needs at least two dimensions");
+
+    let mut iy = [0_i32; 176];
+    exp_rotation(x, n, 1, b, k, spread);
+    let yy = op_pvq_search(x, &mut iy, k, n);
+    encode_pulses(enc, &iy[..n], n as u32, k)?;
+
+    normalise_residual(&iy, x, n, yy, gain);
+    exp_rotation(x, n, -1, b, k, spread);
+
+    Ok(extract_collapse_mask(&iy, n, b))
+}
+
+/// Decodes pulses and normalizes the resulting vector.
+///
+/// Returns the collapse mask.
+pub(crate) fn alg_unquant(
+    x: &mut [f32],
+    n: usize,
+    k: u32,
+    spread: u32,
+    b: usize,
+    dec: &mut RangeDecoder,
+    gain: f32,
+) -> u32 {
+    debug_assert!(k > 0, "alg_unquant() needs at least one pulse");
+    debug_assert!(n > 1, "alg_unquant() needs at least two dimensions");
+
+    let mut iy = [0_i32; 176];
+    let ryy = decode_pulses(dec, &mut iy[..n], n as u32, k);
+    normalise_residual(&iy, x, n, ryy, gain);
+    exp_rotation(x, n, -1, b, k, spread);
+    extract_collapse_mask(&iy, n, b)
+}
+
+/// Normalizes the given vector to the given gain.
+pub(crate) fn renormalise_vector(x: &mut [f32], n: usize, gain: f32) {
+    let e = EPSILON + inner_prod(x, x, n);
+    let g = (1.0 / e.sqrt()) * gain;
+    x[..n].iter_mut().for_each(|x| *x *= g);
+}
+
+/// Computes the angle between the two given vectors in Q14, where 16384
+/// corresponds to 90°.
+///
+/// In the stereo case the angle between the mid and side of the vectors is used.
+#[allow(clippy::approx_constant)]
+pub(crate) fn stereo_itheta(x: &[f32], y: &[f32], stereo: bool, n: usize) -> i32 {
+    let mut emid = EPSILON;
+    let mut eside = EPSILON;
+    if stereo {
+        x[..n].iter().zip(y[..n].iter()).for_each(|(x, y)| {
+            let m = *x + *y;
+            let s = *x - *y;
+            emid += m * m;
+            eside += s * s;
+        });
+    } else {
+        emid += inner_prod(x, x, n);
+        eside += inner_prod(y, y, n);
+    }
+    let mid = emid.sqrt();
+    let side = eside.sqrt();
+    // 0.63662 = 2/pi
+    (0.5 + 16384.0 * 0.63662 * fast_atan2(side, mid)).floor() as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_renormalise_vector() {
+        let mut x = [1.0, 2.0, 3.0, 4.0];
+        renormalise_vector(&mut x, 4, 1.0);
+        let e = inner_prod(&x, &x, 4);
+        assert!((e - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_exp_rotation_roundtrip() {
+        let input: Vec<f32> = (0..32).map(|i| (i as f32).sin()).collect();
+        let mut x = input.clone();
+        exp_rotation(&mut x, 32, 1, 2, 3, SPREAD_NORMAL);
+        assert!(x
+            .iter()
+            .zip(input.iter())
+            .any(|(a, b)| (a - b).abs() > 1e-3));
+        exp_rotation(&mut x, 32, -1, 2, 3, SPREAD_NORMAL);
+        x.iter().zip(input.iter()).for_each(|(a, b)| {
+            assert!((a - b).abs() < 1e-5);
+        });
+    }
+
+    #[test]
+    fn test_op_pvq_search() {
+        let mut x = [0.1, -0.7, 0.2, 0.0, -0.3, 0.6];
+        let mut iy = [0_i32; 6];
+        (1..12).for_each(|k| {
+            let mut x = x;
+            op_pvq_search(&mut x, &mut iy, k, 6);
+            assert_eq!(iy.iter().map(|x| x.unsigned_abs()).sum::<u32>(), k);
+        });
+        let yy = op_pvq_search(&mut x, &mut iy, 4, 6);
+        assert_eq!(iy, [0, -2, 0, 0, -1, 1]);
+        assert!((yy - 6.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_stereo_itheta() {
+        let x = [1.0, 0.0, 0.0, 0.0];
+        let y = [0.0, 1.0, 0.0, 0.0];
+        assert_eq!(stereo_itheta(&x, &x, false, 4), 8192);
+        assert_eq!(stereo_itheta(&x, &y, false, 4), 8192);
+        assert_eq!(stereo_itheta(&x, &x, true, 4), 0);
+    }
+
+    #[test]
+    fn test_extract_collapse_mask() {
+        let iy = [0, 0, 1, 0, 0, 0, -2, 0];
+        assert_eq!(extract_collapse_mask(&iy, 8, 1), 1);
+        assert_eq!(extract_collapse_mask(&iy, 8, 4), 0b1010);
+    }
+}
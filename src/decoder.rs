@@ -58,7 +58,11 @@ pub struct Decoder {
 
 impl Decoder {
     /// Creates a new `Decoder` with the given configuration.
-    pub(crate) fn new(configuration: &DecoderConfiguration) -> Result<Self, OpusError> {
+    ///
+    /// Use `SamplingRate::try_from()` and `Channels::try_from()` to build the
+    /// configuration from untrusted values. They reject unsupported sampling
+    /// rates and channel counts with `OpusError::BadArguments`.
+    pub fn new(configuration: &DecoderConfiguration) -> Result<Self, OpusError> {
         let inner = DecoderInner::new(configuration)?;
         Ok(Self {
             inner,
@@ -71,7 +75,7 @@ impl Decoder {
     /// This should be called when switching streams in order to prevent
     /// the back to back decoding from giving different results from
     /// one at a time decoding.
-    pub(crate) fn reset(&mut self) -> Result<(), OpusError> {
+    pub fn reset(&mut self) -> Result<(), OpusError> {
         self.buffer = vec![];
         self.inner.reset()
     }
@@ -113,6 +117,14 @@ impl Decoder {
         self.inner.last_packet_duration
     }
 
+    /// Returns true if the last decoded packet was a DTX packet.
+    ///
+    /// DTX packets don't contain any audio data, so the decoder generates comfort
+    /// noise until the next regular packet arrives. Lost packets don't change the state.
+    pub fn in_dtx(&self) -> bool {
+        self.inner.in_dtx
+    }
+
     /// Returns the final state of the codec's entropy coder.
     ///
     /// This is used for testing purposes, the encoder and decoder state
@@ -127,7 +139,7 @@ impl Decoder {
     /// Returns number of decoded samples for one channel.
     ///
     /// Caller needs to make sure that the samples buffer has enough space to fit
-    /// all samples inside the packet. Call `query_packet_sample_count()` to query
+    /// all samples inside the packet. Call `Packet::sample_count()` to query
     /// the number of samples inside a packet and resize the buffer if needed.
     ///
     /// The internal format is `f32`. Use `decode_float()` to access it directly.
@@ -135,15 +147,15 @@ impl Decoder {
     /// # Arguments
     /// * `packet`     - Input payload. Use a `None` to indicate packet loss.
     /// * `samples`    - Output signal encoded as PCM samples (interleaved if 2 channels).
-    ///                  Length must be at least `frame_size` * `channels`.
+    ///   Length must be at least `frame_size` * `channels`.
     /// * `frame_size` - Number of samples per channel of available space in a PCM.
-    ///                  `frame_size` must be a multiple of 2.5 ms (400 for 48kHz).
-    ///                  In the case of PLC (packet==`None`) or FEC (decode_fec=`true`), then
-    ///                  `frame_size` needs to be exactly the duration of audio that is missing,
-    ///                  otherwise the decoder will not be in the optimal state to decode
-    ///                  the next incoming packet.
+    ///   `frame_size` must be a multiple of 2.5 ms (400 for 48kHz).
+    ///   In the case of PLC (packet==`None`) or FEC (decode_fec=`true`), then
+    ///   `frame_size` needs to be exactly the duration of audio that is missing,
+    ///   otherwise the decoder will not be in the optimal state to decode
+    ///   the next incoming packet.
     /// * `decode_fec` - Request that any in-band forward error correction data be decoded.
-    ///                  If no such data is available, the frame is decoded as if it were lost.
+    ///   If no such data is available, the frame is decoded as if it were lost.
     ///
     pub fn decode<S: Sample>(
         &mut self,
@@ -152,43 +164,8 @@ impl Decoder {
         frame_size: NonZeroUsize,
         decode_fec: bool,
     ) -> Result<usize, OpusError> {
-        let mut frame_size = frame_size.get();
-        if !decode_fec {
-            if let Some(packet) = packet {
-                let sample_count = query_packet_sample_count(&packet, self.inner.sampling_rate)?;
-                if sample_count == 0 {
-                    return Err(OpusError::InvalidPacket);
-                }
-                frame_size = usize::min(frame_size, sample_count);
-            }
-        }
-
-        let size = frame_size * self.inner.channels as usize;
-        if self.buffer.len() < size {
-            self.buffer.resize(size, 0_f32);
-        }
-
-        let (sample_count, _) = self.inner.decode_native(
-            &packet,
-            &mut self.buffer,
-            frame_size,
-            decode_fec,
-            false,
-            true,
-        )?;
-
-        if sample_count != 0 {
-            if sample_count > samples.len() {
-                return Err(OpusError::BufferToSmall);
-            }
-
-            (0..sample_count * self.inner.channels as usize)
-                .into_iter()
-                .for_each(|i| {
-                    samples[i] = S::from_f32(self.buffer[i]);
-                });
-        }
-
+        let (sample_count, _) =
+            self.decode_generic(packet, samples, frame_size, decode_fec, false)?;
         Ok(sample_count)
     }
 
@@ -197,21 +174,21 @@ impl Decoder {
     /// Returns number of decoded samples for one channel.
     ///
     /// Caller needs to make sure that the samples buffer has enough space to fit
-    /// all samples inside the packet. Call `query_packet_sample_count()` to query
+    /// all samples inside the packet. Call `Packet::sample_count()` to query
     /// the number of samples inside a packet and resize the buffer if needed.
     ///
     /// # Arguments
     /// * `packet`     - Input payload. Use a `None` to indicate packet loss.
     /// * `samples`    - Output signal encoded as PCM samples (interleaved if 2 channels).
-    ///                  Length is frame_size * channels.
+    ///   Length is frame_size * channels.
     /// * `frame_size` - Number of samples per channel of available space in a PCM.
-    ///                  `frame_size` must be a multiple of 2.5 ms (400 for 48kHz).
-    ///                  In the case of PLC (packet==`None`) or FEC (decode_fec=`true`), then
-    ///                  `frame_size` needs to be exactly the duration of audio that is missing,
-    ///                  otherwise the decoder will not be in the optimal state to decode
-    ///                  the next incoming packet.
+    ///   `frame_size` must be a multiple of 2.5 ms (400 for 48kHz).
+    ///   In the case of PLC (packet==`None`) or FEC (decode_fec=`true`), then
+    ///   `frame_size` needs to be exactly the duration of audio that is missing,
+    ///   otherwise the decoder will not be in the optimal state to decode
+    ///   the next incoming packet.
     /// * `decode_fec` - Request that any in-band forward error correction data be decoded.
-    ///                  If no such data is available, the frame is decoded as if it were lost.
+    ///   If no such data is available, the frame is decoded as if it were lost.
     ///
     pub fn decode_float(
         &mut self,
@@ -230,35 +207,139 @@ impl Decoder {
         )?;
         Ok(sample_count)
     }
+
+    /// Decode a self-delimited Opus packet (RFC 6716 Appendix B) with a generic sample output.
+    ///
+    /// Returns number of decoded samples for one channel and the number of bytes
+    /// of `packet` that were consumed. The next packet starts after the consumed bytes.
+    ///
+    /// The internal format is `f32`. Use `decode_self_delimited_float()` to access it directly.
+    ///
+    /// # Arguments
+    /// * `packet`     - Input payload that starts with a self-delimited packet.
+    /// * `samples`    - Output signal encoded as PCM samples (interleaved if 2 channels).
+    ///   Length is frame_size * channels.
+    /// * `frame_size` - Number of samples per channel of available space in a PCM.
+    ///   `frame_size` must be a multiple of 2.5 ms (400 for 48kHz).
+    /// * `decode_fec` - Request that any in-band forward error correction data be decoded.
+    ///   If no such data is available, the frame is decoded as if it were lost.
+    ///
+    pub fn decode_self_delimited<S: Sample>(
+        &mut self,
+        packet: &[u8],
+        samples: &mut [S],
+        frame_size: NonZeroUsize,
+        decode_fec: bool,
+    ) -> Result<(usize, usize), OpusError> {
+        self.decode_generic(Some(packet), samples, frame_size, decode_fec, true)
+    }
+
+    /// Decode a self-delimited Opus packet (RFC 6716 Appendix B) with floating point output.
+    ///
+    /// Returns number of decoded samples for one channel and the number of bytes
+    /// of `packet` that were consumed. The next packet starts after the consumed bytes.
+    ///
+    /// # Arguments
+    /// * `packet`     - Input payload that starts with a self-delimited packet.
+    /// * `samples`    - Output signal encoded as PCM samples (interleaved if 2 channels).
+    ///   Length is frame_size * channels.
+    /// * `frame_size` - Number of samples per channel of available space in a PCM.
+    ///   `frame_size` must be a multiple of 2.5 ms (400 for 48kHz).
+    /// * `decode_fec` - Request that any in-band forward error correction data be decoded.
+    ///   If no such data is available, the frame is decoded as if it were lost.
+    ///
+    pub fn decode_self_delimited_float(
+        &mut self,
+        packet: &[u8],
+        samples: &mut [f32],
+        frame_size: NonZeroUsize,
+        decode_fec: bool,
+    ) -> Result<(usize, usize), OpusError> {
+        self.inner.decode_native(
+            &Some(packet),
+            samples,
+            frame_size.get(),
+            decode_fec,
+            true,
+            false,
+        )
+    }
+
+    fn decode_generic<S: Sample>(
+        &mut self,
+        packet: Option<&[u8]>,
+        samples: &mut [S],
+        frame_size: NonZeroUsize,
+        decode_fec: bool,
+        self_delimited: bool,
+    ) -> Result<(usize, usize), OpusError> {
+        let mut frame_size = frame_size.get();
+        if !decode_fec {
+            if let Some(packet) = packet {
+                let sample_count = query_packet_sample_count(packet, self.inner.sampling_rate)?;
+                if sample_count == 0 {
+                    return Err(OpusError::InvalidPacket);
+                }
+                frame_size = usize::min(frame_size, sample_count);
+            }
+        }
+
+        let size = frame_size * self.inner.channels as usize;
+        if self.buffer.len() < size {
+            self.buffer.resize(size, 0_f32);
+        }
+
+        let (sample_count, packet_offset) = self.inner.decode_native(
+            &packet,
+            &mut self.buffer,
+            frame_size,
+            decode_fec,
+            self_delimited,
+            true,
+        )?;
+
+        if sample_count != 0 {
+            if sample_count * self.inner.channels as usize > samples.len() {
+                return Err(OpusError::BufferToSmall);
+            }
+
+            (0..sample_count * self.inner.channels as usize).for_each(|i| {
+                samples[i] = S::from_f32(self.buffer[i]);
+            });
+        }
+
+        Ok((sample_count, packet_offset))
+    }
 }
 
 #[derive(Clone, Debug)]
-struct DecoderInner {
+pub(crate) struct DecoderInner {
     celt_dec: CeltDecoder,
     silk_dec: SilkDecoder,
     channels: Channels,
     sampling_rate: SamplingRate,
-    decode_gain: i16,
+    pub(crate) decode_gain: i16,
 
     stream_channels: Channels,
-    bandwidth: Option<Bandwidth>,
+    pub(crate) bandwidth: Option<Bandwidth>,
     mode: Option<CodecMode>,
     prev_mode: Option<CodecMode>,
     frame_size: usize,
     prev_redundancy: bool,
-    last_packet_duration: Option<usize>,
+    pub(crate) last_packet_duration: Option<usize>,
+    in_dtx: bool,
     // 48 x 2.5 ms = 120 ms
     frame_sizes: [usize; 48],
     softclip_mem: [f32; 2],
 
-    silk_buffer: Vec<f32>,
+    silk_buffer: Vec<i16>,
     redundant_audio: Vec<f32>,
 
-    final_range: u32,
+    pub(crate) final_range: u32,
 }
 
 impl DecoderInner {
-    fn new(configuration: &DecoderConfiguration) -> Result<Self, OpusError> {
+    pub(crate) fn new(configuration: &DecoderConfiguration) -> Result<Self, OpusError> {
         let celt_dec = CeltDecoder::new(configuration.sampling_rate, configuration.channels)?;
         let silk_dec = SilkDecoder::new(configuration.sampling_rate, configuration.channels)?;
 
@@ -275,6 +356,7 @@ impl DecoderInner {
             frame_size: configuration.sampling_rate as usize / 400,
             prev_redundancy: false,
             last_packet_duration: None,
+            in_dtx: false,
             frame_sizes: [0_usize; 48],
             softclip_mem: [0f32; 2],
             silk_buffer: vec![],
@@ -283,7 +365,7 @@ impl DecoderInner {
         })
     }
 
-    fn reset(&mut self) -> Result<(), OpusError> {
+    pub(crate) fn reset(&mut self) -> Result<(), OpusError> {
         self.silk_dec.reset()?;
         self.celt_dec.reset()?;
 
@@ -294,6 +376,7 @@ impl DecoderInner {
         self.frame_size = self.sampling_rate as usize / 400;
         self.prev_redundancy = false;
         self.last_packet_duration = None;
+        self.in_dtx = false;
         self.frame_sizes = [0_usize; 48];
         self.softclip_mem = [0f32; 2];
         self.silk_buffer = vec![];
@@ -303,7 +386,7 @@ impl DecoderInner {
     }
 
     /// Returns the samples decoded and the packet_offset (used for multiple streams).
-    fn decode_native(
+    pub(crate) fn decode_native(
         &mut self,
         packet: &Option<&[u8]>,
         samples: &mut [f32],
@@ -313,7 +396,7 @@ impl DecoderInner {
         soft_clip: bool,
     ) -> Result<(usize, usize), OpusError> {
         // The frame_size has to be to have a multiple of 2.5 ms.
-        if frame_size % (self.sampling_rate as usize / 400) != 0 {
+        if !frame_size.is_multiple_of(self.sampling_rate as usize / 400) {
             return Err(OpusError::BadArguments(
                 "frame_size must be a multiple of 2.5 ms of the sampling rate",
             ));
@@ -326,10 +409,10 @@ impl DecoderInner {
             let mut offset = 0;
             let mut packet_offset = 0;
 
-            let packet_mode = query_packet_codec_mode(packet);
-            let packet_bandwidth = query_packet_bandwidth(packet);
-            let packet_frame_size = query_packet_samples_per_frame(packet, self.sampling_rate);
-            let packet_stream_channels = query_packet_channel_count(packet);
+            let packet_mode = query_packet_codec_mode(packet)?;
+            let packet_bandwidth = query_packet_bandwidth(packet)?;
+            let packet_frame_size = query_packet_samples_per_frame(packet, self.sampling_rate)?;
+            let packet_stream_channels = query_packet_channel_count(packet)?;
 
             let count = parse_packet(
                 packet,
@@ -346,7 +429,9 @@ impl DecoderInner {
                     || packet_mode == CodecMode::CeltOnly
                     || self.mode == Some(CodecMode::CeltOnly)
                 {
-                    return self.decode_native(&None, samples, frame_size, false, false, soft_clip);
+                    let (sample_count, _) =
+                        self.decode_native(&None, samples, frame_size, false, false, soft_clip)?;
+                    return Ok((sample_count, packet_offset));
                 }
 
                 // Otherwise, run the PLC on everything except the size for which we might have FEC.
@@ -396,7 +481,7 @@ impl DecoderInner {
 
                 // Update the state as the last step to avoid updating it on an invalid packet.
                 let mut sample_count = 0;
-                (0..count).into_iter().try_for_each(|i| {
+                (0..count).try_for_each(|i| {
                     let count = self.decode_frame(
                         &Some(&packet[offset..offset + self.frame_sizes[i]]),
                         &mut samples[sample_count * self.channels as usize..],
@@ -411,6 +496,8 @@ impl DecoderInner {
                 })?;
 
                 self.last_packet_duration = Some(sample_count);
+                // Frames of 0 or 1 bytes don't carry audio data and trigger the PLC/DTX.
+                self.in_dtx = self.frame_sizes[..count].iter().all(|&size| size <= 1);
                 if soft_clip {
                     pcm_soft_clip(
                         &mut samples[..sample_count],
@@ -455,6 +542,9 @@ impl DecoderInner {
         let mut redundant_range: u32 = 0;
         let mut len = data.map_or(0, |x| x.len()) as u32;
 
+        // Payloads of 1 (2 including ToC) or 0 trigger the PLC/DTX.
+        let data = if len <= 1 { &None } else { data };
+
         let f20 = self.sampling_rate as usize / 50;
         let f10 = f20 >> 1;
         let f5 = f10 >> 1;
@@ -462,9 +552,10 @@ impl DecoderInner {
         if frame_size < f2_5 {
             return Err(OpusError::FrameSizeTooSmall);
         }
+        // Limit frame_size to avoid excessive allocations.
+        frame_size = usize::min(frame_size, self.sampling_rate as usize / 25 * 3);
 
-        // Payloads of 1 (2 including ToC) or 0 trigger the PLC/DTX.
-        let (mut dec, audiosize, mode, bandwidth) = if len <= 1 {
+        let (mut dec, audiosize, mode, bandwidth) = if data.is_none() {
             // In that case, don't conceal more than what the ToC says.
             frame_size = usize::min(frame_size, self.frame_size);
 
@@ -474,11 +565,9 @@ impl DecoderInner {
 
             if mode.is_none() {
                 // If we haven't got any packet yet, all we can do is return zeros.
-                (0..audiosize * self.channels as usize)
-                    .into_iter()
-                    .for_each(|i| {
-                        samples[i] = 0.0;
-                    });
+                (0..audiosize * self.channels as usize).for_each(|i| {
+                    samples[i] = 0.0;
+                });
 
                 return Ok(audiosize);
             }
@@ -520,7 +609,7 @@ impl DecoderInner {
             && self.prev_mode.is_some()
             && ((mode == Some(CodecMode::CeltOnly)
                 && self.prev_mode != Some(CodecMode::CeltOnly)
-                && self.prev_redundancy)
+                && !self.prev_redundancy)
                 || (mode != Some(CodecMode::CeltOnly)
                     && self.prev_mode == Some(CodecMode::CeltOnly)))
         {
@@ -552,7 +641,7 @@ impl DecoderInner {
         if mode != Some(CodecMode::CeltOnly) {
             let mut silk_frame_size = frame_size * self.channels as usize;
             if silk_frame_size > self.silk_buffer.len() {
-                self.silk_buffer.resize(silk_frame_size, 0_f32);
+                self.silk_buffer.resize(silk_frame_size, 0);
             }
 
             if self.prev_mode == Some(CodecMode::CeltOnly) {
@@ -602,7 +691,7 @@ impl DecoderInner {
                 let first_frame = decoded_samples == 0;
                 if let Err(err) = self.silk_dec.decode(
                     &mut dec,
-                    &self.silk_buffer[decoded_samples * self.channels as usize..],
+                    &mut self.silk_buffer[decoded_samples * self.channels as usize..],
                     &mut silk_frame_size,
                     lost_flag,
                     first_frame,
@@ -610,11 +699,9 @@ impl DecoderInner {
                     // PLC failure should not be fatal.
                     if lost_flag != LostFlag::NoLoss {
                         silk_frame_size = frame_size;
-                        (0..frame_size * self.channels as usize)
-                            .into_iter()
-                            .for_each(|i| {
-                                self.silk_buffer[i] = 0.0;
-                            });
+                        (0..frame_size * self.channels as usize).for_each(|i| {
+                            self.silk_buffer[i] = 0;
+                        });
                     } else {
                         return Err(err);
                     }
@@ -639,14 +726,15 @@ impl DecoderInner {
                         redundancy_bytes = if mode == Some(CodecMode::Hybrid) {
                             dec.decode_uint(256) + 2
                         } else {
-                            len as u32 - ((dec.tell() + 7) >> 3)
+                            len - ((dec.tell() + 7) >> 3)
                         };
-                        len -= redundancy_bytes;
                         // This is a sanity check. It should never happen for a valid packet, so the exact behaviour is not normative.
-                        if len * 8 < dec.tell() {
+                        if redundancy_bytes > len || (len - redundancy_bytes) * 8 < dec.tell() {
                             len = 0;
                             redundancy_bytes = 0;
                             redundancy = false;
+                        } else {
+                            len -= redundancy_bytes;
                         }
                         // Shrink decoder because of raw bits.
                         dec.shrink_storage(redundancy_bytes as usize);
@@ -659,9 +747,8 @@ impl DecoderInner {
             pcm_transition_silk_size = None;
         }
 
-        transition_buffer = pcm_transition_silk_size.map(|size| vec![0_f32; size]);
-
         if mode != Some(CodecMode::CeltOnly) {
+            transition_buffer = pcm_transition_silk_size.map(|size| vec![0_f32; size]);
             if let Some(buffer) = transition_buffer.as_mut() {
                 self.decode_frame(&None, buffer, usize::min(f5, audiosize), false)?;
             }
@@ -680,14 +767,14 @@ impl DecoderInner {
 
         if redundancy {
             let size = f5 * self.channels as usize;
-            if size < self.redundant_audio.len() {
+            if size > self.redundant_audio.len() {
                 self.redundant_audio.resize(size, 0_f32);
             }
         }
 
         // 5 ms redundant frame for CELT->SILK.
         if redundancy && celt_to_silk {
-            self.decode_redundancy(data, len, redundancy_bytes, &mut redundant_range, f5);
+            self.decode_redundancy(data, len, redundancy_bytes, &mut redundant_range, f5)?;
         }
 
         // MUST be after PLC.
@@ -708,30 +795,35 @@ impl DecoderInner {
 
             // Decode CELT.
             self.celt_dec
-                .decode(data, len as usize, samples, celt_frame_size, &mut dec);
-        } else if self.prev_mode == Some(CodecMode::Hybrid)
-            && !(redundancy && celt_to_silk && self.prev_redundancy)
-        {
+                .decode(data, len as usize, samples, celt_frame_size, &mut dec)?;
+        } else {
+            samples
+                .iter_mut()
+                .take(frame_size * self.channels as usize)
+                .for_each(|x| *x = 0.0);
+
             // For hybrid -> SILK transitions, we let the CELT MDCT do a fade-out by decoding a silence frame.
-            self.celt_dec.set_start_band(0);
-            let silence = [0xFF, 0xFF];
-            self.celt_dec
-                .decode(&Some(&silence), 2, samples, f2_5, &mut dec);
+            if self.prev_mode == Some(CodecMode::Hybrid)
+                && !(redundancy && celt_to_silk && self.prev_redundancy)
+            {
+                self.celt_dec.set_start_band(0);
+                let silence = [0xFF, 0xFF];
+                self.celt_dec
+                    .decode(&Some(&silence), 2, samples, f2_5, &mut None)?;
+            }
         }
 
         if mode != Some(CodecMode::CeltOnly) {
             // This merges the CELT and SILK outputs.
-            (0..frame_size * self.channels as usize)
-                .into_iter()
-                .for_each(|i| {
-                    samples[i] += (1.0 / 32768.0) * self.silk_buffer[i];
-                });
+            (0..frame_size * self.channels as usize).for_each(|i| {
+                samples[i] += (1.0 / 32768.0) * f32::from(self.silk_buffer[i]);
+            });
         }
 
         // 5 ms redundant frame for SILK->CELT.
         if redundancy && !celt_to_silk {
             self.celt_dec.reset()?;
-            self.decode_redundancy(data, len, redundancy_bytes, &mut redundant_range, f5);
+            self.decode_redundancy(data, len, redundancy_bytes, &mut redundant_range, f5)?;
             smooth_fade_into_in1(
                 &mut samples[self.channels as usize * (frame_size - f2_5)..],
                 &self.redundant_audio[self.channels as usize * f2_5..],
@@ -742,15 +834,15 @@ impl DecoderInner {
         }
 
         if redundancy && celt_to_silk {
-            (0..self.channels as usize).into_iter().for_each(|c| {
-                (0..f2_5).into_iter().for_each(|i| {
+            (0..self.channels as usize).for_each(|c| {
+                (0..f2_5).for_each(|i| {
                     samples[self.channels as usize * i + c] =
                         self.redundant_audio[self.channels as usize * i + c];
                 });
             });
-            smooth_fade_into_in1(
-                &mut self.redundant_audio[self.channels as usize * f2_5..],
-                &samples[self.channels as usize * f2_5..],
+            smooth_fade_into_in2(
+                &self.redundant_audio[self.channels as usize * f2_5..],
+                &mut samples[self.channels as usize * f2_5..],
                 f2_5,
                 self.channels as usize,
                 self.sampling_rate as usize,
@@ -759,11 +851,9 @@ impl DecoderInner {
 
         if let Some(buffer) = transition_buffer {
             if audiosize >= f5 {
-                (0..self.channels as usize * f2_5)
-                    .into_iter()
-                    .for_each(|i| {
-                        samples[i] = buffer[i];
-                    });
+                (0..self.channels as usize * f2_5).for_each(|i| {
+                    samples[i] = buffer[i];
+                });
                 smooth_fade_into_in2(
                     &buffer[self.channels as usize * f2_5..],
                     &mut samples[self.channels as usize * f2_5..],
@@ -789,11 +879,9 @@ impl DecoderInner {
 
         if self.decode_gain != 0 {
             let gain = fast_exp2(6.48814081e-4 * self.decode_gain as f32);
-            (0..frame_size * self.channels as usize)
-                .into_iter()
-                .for_each(|i| {
-                    samples[i] *= gain;
-                });
+            (0..frame_size * self.channels as usize).for_each(|i| {
+                samples[i] *= gain;
+            });
         }
 
         if let Some(dec) = dec.as_ref() {
@@ -815,18 +903,20 @@ impl DecoderInner {
         redundancy_bytes: u32,
         redundant_range: &mut u32,
         frame_size: usize,
-    ) {
+    ) -> Result<(), OpusError> {
         self.celt_dec.set_start_band(0);
         if let Some(data) = data {
             self.celt_dec.decode(
-                &Some(&data[len as usize..]),
+                &Some(&data[len as usize..len as usize + redundancy_bytes as usize]),
                 redundancy_bytes as usize,
                 &mut self.redundant_audio,
                 frame_size,
                 &mut None,
-            );
+            )?;
         }
         *redundant_range = self.celt_dec.final_range();
+
+        Ok(())
     }
 }
 
@@ -838,8 +928,8 @@ fn smooth_fade_into_in1(
     sampling_rate: usize,
 ) {
     let inc = 48000 / sampling_rate;
-    (0..channels).into_iter().for_each(|c| {
-        (0..overlap).into_iter().for_each(|i| {
+    (0..channels).for_each(|c| {
+        (0..overlap).for_each(|i| {
             let w = mode::WINDOW[i * inc] * mode::WINDOW[i * inc];
             in1[c + i * channels] =
                 (w * in2[i * channels + c]) + ((1.0 - w) * in1[i * channels + c]);
@@ -855,11 +945,456 @@ fn smooth_fade_into_in2(
     sampling_rate: usize,
 ) {
     let inc = 48000 / sampling_rate;
-    (0..channels).into_iter().for_each(|c| {
-        (0..overlap).into_iter().for_each(|i| {
+    (0..channels).for_each(|c| {
+        (0..overlap).for_each(|i| {
             let w = mode::WINDOW[i * inc] * mode::WINDOW[i * inc];
             in2[c + i * channels] =
                 (w * in2[i * channels + c]) + ((1.0 - w) * in1[i * channels + c]);
         });
     });
 }
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+    use crate::packet_to_self_delimited;
+    use nanorand::Rng;
+
+    // The final ranges were produced by decoding the packets with libopus 1.3.1.
+
+    // Hybrid superwideband, mono, 20 ms.
+    const TEST_PACKETS_HYBRID_SWB: &[&[u8]] = &[
+        &[
+            0x68, 0x84, 0x56, 0xBB, 0x86, 0x54, 0x83, 0x86, 0x30, 0x11, 0xCA, 0xD3, 0x81, 0xA5,
+            0x83, 0x79, 0xC9, 0x90, 0xEC, 0x3D, 0x7F, 0xC1, 0xF6, 0x4A, 0x70, 0x75, 0x78, 0x91,
+            0x9B, 0x3A, 0xA3, 0x2C, 0x14, 0x18, 0x2E, 0xB2, 0xB1, 0x55, 0x1E, 0xE5, 0x24, 0xB5,
+            0x74, 0x26, 0x15, 0x51, 0x04, 0x50, 0x5F, 0x79, 0x36, 0xC0, 0x90, 0x60, 0xC0,
+        ],
+        &[
+            0x68, 0xAC, 0x40, 0x54, 0x40, 0x30, 0x23, 0x94, 0xB6, 0x2F, 0xC7, 0x02, 0xA2, 0xB0,
+            0x54, 0x46, 0x1F, 0xE8, 0x14, 0x2C, 0x64, 0x6F, 0xF5, 0x9A, 0x43, 0x15, 0x12, 0x54,
+            0x23, 0x18, 0x0B, 0x0F, 0x05, 0xD4, 0xD0, 0x19, 0x9D, 0x95, 0x97, 0x45, 0x60, 0xFF,
+            0xC7, 0x1D, 0x01, 0xE5, 0xC9, 0xF7, 0xF1, 0xB6, 0xB7, 0xDE, 0x1C, 0x24, 0x7B, 0x8B,
+            0x8B, 0x6E, 0x90, 0x2E, 0xB5, 0x06, 0x38, 0x7C,
+        ],
+        &[
+            0x68, 0xBE, 0x88, 0x85, 0xD2, 0xA7, 0x88, 0x97, 0xD7, 0xD9, 0xB1, 0x5A, 0x71, 0xBE,
+            0x67, 0x21, 0x33, 0xBB, 0x68, 0x72, 0xCC, 0x09, 0x08, 0x79, 0x97, 0xC5, 0xA4, 0x56,
+            0x13, 0x4A, 0x38, 0x1E, 0xDA, 0x7F, 0x8B, 0x06, 0x7A, 0xEB, 0xCC, 0xFC, 0xC8, 0xE1,
+            0x0E, 0xB8, 0x11, 0xDC, 0x25, 0x3B, 0xB9, 0x4C, 0x1F, 0x42, 0x69, 0xC7, 0x2F, 0x57,
+            0x84, 0xEF, 0xDB, 0x54, 0x9F, 0xC4, 0x92, 0x36, 0xFE, 0x02, 0x8C,
+        ],
+    ];
+    const TEST_RANGES_HYBRID_SWB: &[u32] = &[0x564C_FA00, 0x1A29_2F00, 0x16FB_F500];
+
+    // Hybrid fullband, stereo, 10 ms.
+    const TEST_PACKETS_HYBRID_FB: &[&[u8]] = &[
+        &[
+            0x74, 0x8A, 0x31, 0x0C, 0xCC, 0x49, 0xF7, 0x54, 0x62, 0xE0, 0xE5, 0xEB, 0xDD, 0xF9,
+            0x21, 0x97, 0x1F, 0x36, 0x41, 0xCE, 0x21, 0x2F, 0x3A, 0xE9, 0x59, 0xC5, 0x10, 0x61,
+            0xDC, 0x1C, 0x83, 0x55, 0x8B, 0xF4, 0x2C, 0x9B, 0xF3, 0x16, 0xDA, 0x41,
+        ],
+        &[
+            0x74, 0x84, 0xEF, 0x69, 0x46, 0x32, 0x42, 0x8B, 0xF7, 0x67, 0x74, 0x25, 0xCB, 0x46,
+            0x12, 0xA4, 0x1E, 0xAB, 0x1A, 0x87, 0xD1, 0x3B, 0xB2, 0xE2, 0x3D, 0x5C, 0x38, 0x94,
+            0xA0, 0x97, 0xF0, 0x55,
+        ],
+        &[
+            0x74, 0x83, 0xAF, 0x94, 0x45, 0x44, 0x78, 0x6A, 0x1C, 0x7F, 0x34, 0xCD, 0x2F, 0xE0,
+            0x40, 0x4E, 0xDA, 0x79, 0x02, 0xA3, 0x6C, 0xA1, 0xAB, 0xE6, 0x8B, 0x82, 0xBA, 0xD9,
+            0x44, 0x5C, 0x3B, 0x68, 0x0B, 0xF3, 0x3D, 0xF3,
+        ],
+    ];
+    const TEST_RANGES_HYBRID_FB: &[u32] = &[0x0091_9EAA, 0x00C1_FB55, 0x0088_C372];
+
+    // Hybrid fullband, mono, 20 ms, switching to CELT and back with redundant CELT frames.
+    const TEST_PACKETS_HYBRID_CELT_TRANSITIONS: &[&[u8]] = &[
+        &[
+            0x78, 0x84, 0x7A, 0xDA, 0xE9, 0x67, 0xC2, 0x50, 0x00, 0x87, 0x03, 0x77, 0xD5, 0xDA,
+            0xBB, 0x54, 0xC7, 0x20, 0xC0, 0x23, 0x08, 0xA6, 0xFB, 0xDB, 0xF7, 0xAE, 0x28, 0xDA,
+            0x8C, 0x89, 0x6F, 0x85, 0x4B, 0x0A, 0xE8, 0xCF, 0xFD, 0x8C, 0xDC, 0x9C, 0x9A, 0x5E,
+            0x84, 0x4B, 0x0B, 0xF8, 0x90, 0xDD, 0xB1, 0xC4, 0xE5,
+        ],
+        &[
+            0x78, 0xBA, 0x6F, 0x24, 0xF3, 0x9F, 0xE2, 0x6C, 0x40, 0x24, 0xCF, 0x1F, 0x86, 0x86,
+            0xA1, 0xF6, 0x52, 0x53, 0x68, 0xA9, 0xD7, 0x13, 0x3D, 0x4E, 0x3D, 0x0F, 0xD1, 0x0E,
+            0xFB, 0x2A, 0xD0, 0x29, 0xC8, 0xD9, 0x6D, 0xA2, 0x7C, 0x7A, 0xB9, 0xC7, 0xE7, 0x08,
+            0x09, 0xB2, 0x4A, 0x44, 0x40, 0x70, 0x45, 0x6B, 0xEC, 0xEB, 0x4D, 0x4B, 0xD5, 0x7D,
+            0x2A,
+        ],
+        &[
+            0x78, 0xB9, 0xA5, 0x79, 0xC5, 0x83, 0xF9, 0x2C, 0x87, 0xF6, 0x5B, 0xAB, 0xEC, 0xD8,
+            0x3A, 0xEB, 0x89, 0x69, 0x88, 0x67, 0xC1, 0x5E, 0xE2, 0x2B, 0xE8, 0x2C, 0x49, 0xED,
+            0xD3, 0xBC, 0xD2, 0xBE, 0x2B, 0x43, 0xCF, 0x54, 0x4D, 0x07, 0x40, 0x09, 0xC1, 0x0D,
+            0x66, 0x01, 0x11, 0x45, 0x7B, 0x86, 0x90, 0x68, 0xC4, 0xFE, 0x41, 0x40, 0x54, 0x64,
+            0x9D, 0x45, 0x05, 0x9C, 0x29, 0xCB, 0xD8, 0x3D, 0xF1, 0xC5, 0x1C, 0x7F, 0x84, 0xC2,
+            0x7D, 0xA5, 0x9C, 0x30, 0xBE, 0x10, 0xB5, 0x92, 0x7C, 0x22, 0x2B, 0x20, 0x5F, 0xB9,
+            0x7D, 0x97, 0x0B, 0x77, 0x69, 0xFD, 0xEC, 0x17, 0xBF, 0x10,
+        ],
+        &[
+            0xF8, 0xF0, 0xF0, 0x6A, 0x9C, 0x7A, 0xBF, 0x4A, 0x05, 0x39, 0xA6, 0x28, 0xA5, 0xAE,
+            0xDC, 0xBC, 0x2E, 0x44, 0xE0, 0x7E, 0x9D, 0x4E, 0x72, 0x41, 0xDD, 0xC1, 0xBA, 0xA7,
+            0x45, 0x6C, 0xE8, 0xA9, 0x2A, 0xBC, 0x43, 0x68, 0x4C, 0xA9, 0x60, 0x6B, 0x0C, 0x74,
+            0xBE, 0x40, 0xE2, 0x33, 0x82, 0x67, 0x32, 0x66, 0xD6, 0x40, 0x1E, 0x76, 0x0E, 0xF3,
+            0xB9, 0x93, 0x28, 0x86, 0x17,
+        ],
+        &[
+            0x78, 0x95, 0xE2, 0x58, 0x02, 0x97, 0x2C, 0x2D, 0x39, 0x7E, 0xF8, 0x23, 0xE6, 0x50,
+            0xD0, 0x72, 0x5D, 0x16, 0x7B, 0xB4, 0x28, 0x14, 0x35, 0x7F, 0x6D, 0x53, 0xD1, 0x7A,
+            0x3F, 0xF4, 0x00, 0x60, 0x33, 0x53, 0x5B, 0x1C, 0xBC, 0x85, 0x40, 0x55, 0x1D, 0x85,
+            0x04, 0x2D, 0xAE, 0x7F, 0x90, 0xB4, 0xF2, 0x7C, 0xD7, 0xFC, 0x8D, 0x04, 0xA4, 0x98,
+            0x08, 0xE1, 0x3C, 0xFF, 0xC7, 0x02, 0xEF, 0xBD, 0xEE, 0xF4, 0xD6, 0xBE, 0x16, 0x71,
+            0x7F, 0x47, 0xF8, 0xB0, 0x46, 0xC1, 0x79, 0x4A, 0xA6, 0x8B, 0xE1, 0x06, 0xC6, 0x2A,
+            0xB2, 0x11, 0xD7, 0x6A, 0x8D, 0x26, 0x1A,
+        ],
+        &[
+            0x78, 0xBA, 0x38, 0x99, 0x81, 0xED, 0x2F, 0x92, 0x45, 0x13, 0x92, 0x9A, 0xEF, 0x37,
+            0x46, 0x03, 0x41, 0x39, 0xA1, 0x22, 0x69, 0x12, 0x77, 0xFA, 0x97, 0xE9, 0x22, 0x05,
+            0xB9, 0xA6, 0xA4, 0x87, 0x53, 0xD6, 0x6A, 0x0A, 0xA7, 0x20, 0x0C, 0xF8, 0x7B, 0xB8,
+            0xF7, 0x10, 0x04, 0x82, 0x7B, 0x6B, 0xCF, 0x29, 0x44, 0x95, 0x21,
+        ],
+        &[
+            0x78, 0xB9, 0xA5, 0x80, 0x52, 0xE0, 0xA8, 0xBD, 0x3B, 0xB1, 0x7E, 0xA7, 0x10, 0x16,
+            0xD9, 0x58, 0xEE, 0x99, 0x84, 0xB9, 0xAB, 0x83, 0x77, 0x9C, 0xD3, 0x6B, 0x8E, 0x4F,
+            0xBD, 0x8F, 0x86, 0x87, 0x61, 0x27, 0xB8, 0x63, 0xAF, 0x3F, 0xBF, 0x3B, 0xAB, 0xC3,
+            0xCD, 0x83, 0x43, 0x43, 0x85, 0xB8, 0x03, 0x6C, 0x78, 0x9E, 0x6C, 0xAB, 0xC2, 0xDD,
+            0x21, 0xD4, 0xB7, 0xE0, 0x0F, 0xFE, 0xFD, 0xEC, 0xA3, 0xDD,
+        ],
+    ];
+    const TEST_RANGES_HYBRID_CELT_TRANSITIONS: &[u32] = &[
+        0x05AE_6D00,
+        0x0833_1500,
+        0x01A8_E900,
+        0x1441_DC00,
+        0x03E1_27DC,
+        0x0527_EC00,
+        0x1980_A600,
+    ];
+
+    // SILK wideband, mono, 20 ms, switching to CELT and back with redundant CELT frames.
+    const TEST_PACKETS_SILK_CELT_TRANSITIONS: &[&[u8]] = &[
+        &[
+            0x48, 0x84, 0xC1, 0x99, 0xF1, 0xCF, 0x25, 0xB2, 0x40, 0x71, 0x64, 0xC3, 0x0E, 0x86,
+            0x38, 0x92, 0xF2, 0x79, 0x14, 0xB6, 0xB9, 0x9C, 0x5E, 0x42, 0x99, 0x51, 0x72, 0xC3,
+            0xB4, 0x66, 0x21, 0x11, 0x70, 0x9F, 0xAE, 0xC7, 0x9C, 0x4D, 0xBE, 0x34, 0x21,
+        ],
+        &[
+            0x48, 0xBC, 0x26, 0x13, 0x0C, 0x43, 0xF7, 0x56, 0x17, 0x1B, 0x0F, 0xEC, 0x95, 0x1C,
+            0x77, 0xF0, 0x3F, 0xB1, 0xF9, 0xAB, 0xEC, 0x1B, 0x86, 0x8D, 0xA2, 0x6A, 0x82, 0x6A,
+            0x51, 0xA7, 0xC2, 0x9E, 0x5D, 0x90, 0x8E, 0xFE, 0xB1, 0xE0, 0xA2, 0x5B, 0xE4, 0x85,
+            0x41, 0x2A, 0x9E, 0x82,
+        ],
+        &[
+            0x48, 0xBC, 0x27, 0x30, 0x2E, 0x16, 0x19, 0x85, 0x49, 0x2B, 0xDD, 0xA1, 0x48, 0x7F,
+            0x17, 0x55, 0x7B, 0xE7, 0x47, 0xB7, 0xF6, 0x9F, 0x98, 0xCC, 0x99, 0xAE, 0xF1, 0x50,
+            0xAA, 0xEF, 0x67, 0xC4, 0x3A, 0x95, 0x3C, 0xA6, 0x0E, 0x38, 0x6A, 0x19, 0x43, 0xAB,
+            0xC6, 0x1A, 0xC9, 0x7A, 0x37, 0x71, 0x59, 0x80, 0x7F, 0x7D, 0x53, 0xA0, 0x5F, 0x5C,
+            0x3B, 0x56, 0x74, 0xEF, 0x25, 0xD0, 0xD7, 0x36, 0x10, 0xC3, 0x71, 0x08, 0xD5, 0xD3,
+            0xEB, 0x9D, 0x17,
+        ],
+        &[
+            0xB8, 0xEE, 0x65, 0x9E, 0x78, 0xDD, 0xF2, 0x88, 0x7B, 0xC0, 0x8D, 0x51, 0x00, 0x11,
+            0x4C, 0x70, 0x9F, 0x59, 0x21, 0x04, 0x36, 0x50, 0xB2, 0xC6, 0x5F, 0x0C, 0x8D, 0x66,
+            0xF4, 0x88, 0x32, 0xB8, 0xE2, 0x96, 0x0C, 0x15, 0x45, 0xA3, 0xB9, 0x6D, 0x26, 0xD3,
+            0x4A, 0xAC, 0xE8, 0x1D,
+        ],
+        &[
+            0x48, 0x96, 0xF8, 0x12, 0xEC, 0x48, 0xEA, 0x45, 0x41, 0xCF, 0x57, 0xE1, 0xFB, 0x61,
+            0xFC, 0xA8, 0x5F, 0xB9, 0x23, 0x0A, 0xDA, 0xFA, 0x29, 0xE1, 0xAF, 0xC7, 0x4C, 0x0D,
+            0xEE, 0x27, 0x6F, 0x36, 0x51, 0xEA, 0xA7, 0x1E, 0x2C, 0x8D, 0x97, 0x71, 0x50, 0x66,
+            0xD5, 0x98, 0x00, 0xEC, 0x27, 0x3E, 0x4A, 0xA9, 0x2C, 0xC6, 0xED, 0x38, 0xD9, 0x88,
+            0xB7, 0x63, 0x84, 0x50, 0xDB, 0xBD, 0x0A, 0xAD, 0x3D, 0xAC, 0x58, 0x1D,
+        ],
+        &[
+            0x48, 0xBC, 0x27, 0x30, 0x2E, 0x15, 0xAA, 0x7D, 0x58, 0xF5, 0xA3, 0x02, 0x07, 0xC5,
+            0x35, 0x5F, 0xB0, 0x92, 0x68, 0xDE, 0x64, 0xC6, 0x9A, 0xA0, 0xE4, 0x11, 0xBB, 0xFC,
+            0x73, 0xAA, 0x49, 0x29, 0x43, 0xBE, 0x4C, 0x49, 0x7B, 0x07, 0x46, 0x10,
+        ],
+        &[
+            0x48, 0xBC, 0x1E, 0xD9, 0x57, 0xAC, 0xF6, 0xF5, 0xC6, 0xE9, 0x76, 0x24, 0x2B, 0x94,
+            0x69, 0x49, 0xFC, 0x5C, 0xB2, 0x71, 0x83, 0x4D, 0x3B, 0x02, 0x4B, 0x76, 0x3D, 0x26,
+            0x0B, 0x92, 0xC3, 0xB7, 0x50, 0x63, 0x2B, 0x45, 0x5F, 0xE1, 0xDF, 0x2A, 0xDF, 0xFD,
+            0x5E, 0x7B, 0x25, 0xFE, 0x24, 0x28,
+        ],
+    ];
+    const TEST_RANGES_SILK_CELT_TRANSITIONS: &[u32] = &[
+        0x00FF_94C2,
+        0x0151_F8A0,
+        0x3AFB_4880,
+        0x012E_0600,
+        0x02AD_CF67,
+        0x1610_D21F,
+        0x0705_A770,
+    ];
+
+    // SILK wideband, mono, 20 ms, switching to CELT and back without redundancy.
+    const TEST_PACKETS_SILK_CELT_SPLICED: &[&[u8]] = &[
+        &[
+            0x48, 0x85, 0x08, 0x19, 0xF1, 0xCF, 0x25, 0xAA, 0xE0, 0x43, 0x39, 0x64, 0x24, 0x1C,
+            0x67, 0xDE, 0x43, 0xCC, 0xB9, 0x0A, 0xE2, 0xC7, 0x55, 0x8A, 0x25, 0x58, 0xA2, 0xC3,
+            0x77, 0x80,
+        ],
+        &[
+            0x48, 0xBC, 0x79, 0x53, 0x0C, 0x43, 0xF7, 0x56, 0x19, 0xC3, 0xD6, 0xF5, 0xE5, 0xEB,
+            0x09, 0xF4, 0x8E, 0x40, 0x0B, 0xAE, 0x73, 0x21, 0x0A, 0xC3, 0xC1, 0x0F, 0xD8, 0x69,
+            0x33, 0x2E, 0xED, 0x10, 0x4B, 0xA0, 0x65, 0x20,
+        ],
+        &[
+            0xB8, 0xF1, 0x35, 0x39, 0x76, 0x97, 0xD0, 0xB1, 0xCB, 0xC3, 0x7E, 0x7A, 0xB2, 0xA7,
+            0x03, 0xC6, 0x87, 0x62, 0xBC, 0x2D, 0x43, 0x1F, 0x6A, 0x92, 0x3A, 0xF6, 0xB5, 0x2F,
+            0xCB, 0x9C, 0xE3, 0x54, 0x08, 0x1D,
+        ],
+        &[
+            0xB8, 0xEF, 0x0D, 0x59, 0x5D, 0x21, 0xD4, 0x52, 0x1E, 0xFD, 0x17, 0x41, 0x20, 0x36,
+            0x49, 0x9A, 0x90, 0x8F, 0xC7, 0xE7, 0x39, 0x3C, 0xA7, 0x8D, 0xCC, 0x73, 0xC0, 0x0A,
+            0x13, 0xA9, 0x26, 0xB8, 0x1D,
+        ],
+        &[
+            0x48, 0xBC, 0x50, 0xD0, 0x2E, 0x15, 0xDD, 0x93, 0x2C, 0xC9, 0x2D, 0x7C, 0x9E, 0xF4,
+            0x7E, 0x24, 0x88, 0x10, 0x03, 0x84, 0x86, 0x95, 0x66, 0x13, 0x12, 0x05, 0xC6, 0x30,
+            0x4C, 0x3F, 0xC6, 0xC2, 0xAD, 0xF4, 0x90, 0x7B, 0xDD, 0x28, 0x40,
+        ],
+        &[
+            0x48, 0xBC, 0x52, 0x23, 0x3D, 0xB1, 0x0F, 0x38, 0x8F, 0xB0, 0x6D, 0xC4, 0xDD, 0x59,
+            0x63, 0x66, 0xA4, 0x87, 0x17, 0x9A, 0x80, 0xFF, 0xF3, 0x61, 0x4D, 0x27, 0x7A, 0xAF,
+            0xD6, 0xFB, 0x7C, 0xB1, 0x61, 0x0D, 0x8C, 0x33, 0x7D, 0x1A, 0x23, 0x80,
+        ],
+    ];
+    const TEST_RANGES_SILK_CELT_SPLICED: &[u32] = &[
+        0x09DA_6A24,
+        0x1C78_9EC0,
+        0x6985_6400,
+        0x00B2_BEC8,
+        0x45E8_8800,
+        0x3EAE_8EC0,
+    ];
+
+    fn decode_and_check_range(
+        channels: Channels,
+        packets: &[&[u8]],
+        ranges: &[u32],
+        expected_samples: usize,
+    ) -> Vec<f32> {
+        let mut decoder = Decoder::new(&DecoderConfiguration {
+            sampling_rate: SamplingRate::Hz48000,
+            channels,
+            gain: 0,
+        })
+        .unwrap();
+        let mut samples = vec![0_f32; 5760 * channels as usize];
+        let mut output = vec![];
+
+        packets
+            .iter()
+            .zip(ranges.iter())
+            .for_each(|(packet, &range)| {
+                let count = decoder
+                    .decode_float(
+                        Some(packet),
+                        &mut samples,
+                        NonZeroUsize::new(5760).unwrap(),
+                        false,
+                    )
+                    .unwrap();
+                assert_eq!(count, expected_samples);
+                assert_eq!(decoder.final_range(), range);
+                assert_eq!(decoder.bandwidth(), query_packet_bandwidth(packet).ok());
+                output.extend_from_slice(&samples[..count * channels as usize]);
+            });
+
+        assert!(output.iter().any(|x| *x != 0.0));
+        output
+    }
+
+    #[test]
+    fn test_decoder_reset() {
+        let mut decoder = Decoder::new(&DecoderConfiguration::default()).unwrap();
+        let mut samples = vec![0_f32; 5760 * 2];
+
+        let mut decode_all = |decoder: &mut Decoder| -> Vec<u32> {
+            TEST_PACKETS_HYBRID_FB
+                .iter()
+                .map(|packet| {
+                    decoder
+                        .decode_float(
+                            Some(packet),
+                            &mut samples,
+                            NonZeroUsize::new(5760).unwrap(),
+                            false,
+                        )
+                        .unwrap();
+                    decoder.final_range()
+                })
+                .collect()
+        };
+
+        let first = decode_all(&mut decoder);
+        decoder.reset().unwrap();
+        assert_eq!(decoder.bandwidth(), None);
+        assert_eq!(decoder.pitch(), None);
+        let second = decode_all(&mut decoder);
+
+        assert_eq!(first, second);
+        assert_eq!(first, TEST_RANGES_HYBRID_FB);
+    }
+
+    #[test]
+    fn test_decode_self_delimited() {
+        let mut stream = vec![];
+        TEST_PACKETS_HYBRID_FB.iter().for_each(|packet| {
+            let mut data = [0_u8; 128];
+            let len = packet_to_self_delimited(packet, &mut data).unwrap();
+            stream.extend_from_slice(&data[..len]);
+        });
+
+        let mut decoder = Decoder::new(&DecoderConfiguration::default()).unwrap();
+        let mut samples = vec![0_i16; 5760 * 2];
+        let mut offset = 0;
+        let ranges: Vec<u32> = (0..TEST_PACKETS_HYBRID_FB.len())
+            .map(|_| {
+                let (count, used) = decoder
+                    .decode_self_delimited(
+                        &stream[offset..],
+                        &mut samples,
+                        NonZeroUsize::new(5760).unwrap(),
+                        false,
+                    )
+                    .unwrap();
+                assert_eq!(count, 480);
+                offset += used;
+                decoder.final_range()
+            })
+            .collect();
+        assert_eq!(offset, stream.len());
+        assert_eq!(ranges, TEST_RANGES_HYBRID_FB);
+
+        // The consumed bytes are also reported if the FEC falls back to the PLC.
+        let mut samples = vec![0_f32; 240 * 2];
+        let (count, used) = decoder
+            .decode_self_delimited_float(
+                &stream,
+                &mut samples,
+                NonZeroUsize::new(240).unwrap(),
+                true,
+            )
+            .unwrap();
+        assert_eq!(count, 240);
+        assert_eq!(used, TEST_PACKETS_HYBRID_FB[0].len() + 1);
+    }
+
+    #[test]
+    fn test_decode_hybrid_superwideband() {
+        decode_and_check_range(
+            Channels::Mono,
+            TEST_PACKETS_HYBRID_SWB,
+            TEST_RANGES_HYBRID_SWB,
+            960,
+        );
+    }
+
+    #[test]
+    fn test_decode_hybrid_fullband() {
+        decode_and_check_range(
+            Channels::Stereo,
+            TEST_PACKETS_HYBRID_FB,
+            TEST_RANGES_HYBRID_FB,
+            480,
+        );
+    }
+
+    #[test]
+    fn test_decode_hybrid_celt_transitions() {
+        decode_and_check_range(
+            Channels::Mono,
+            TEST_PACKETS_HYBRID_CELT_TRANSITIONS,
+            TEST_RANGES_HYBRID_CELT_TRANSITIONS,
+            960,
+        );
+    }
+
+    #[test]
+    fn test_decode_silk_celt_transitions() {
+        decode_and_check_range(
+            Channels::Mono,
+            TEST_PACKETS_SILK_CELT_TRANSITIONS,
+            TEST_RANGES_SILK_CELT_TRANSITIONS,
+            960,
+        );
+    }
+
+    #[test]
+    fn test_decode_silk_celt_spliced() {
+        let output = decode_and_check_range(
+            Channels::Mono,
+            TEST_PACKETS_SILK_CELT_SPLICED,
+            TEST_RANGES_SILK_CELT_SPLICED,
+            960,
+        );
+
+        // The first CELT frame fades in from the concealed SILK signal. libopus decodes
+        // an energy of 10.2702 for this frame.
+        let energy: f32 = output[1920..2880].iter().map(|x| x * x).sum();
+        assert!((energy - 10.2702).abs() < 0.01, "{}", energy);
+    }
+
+    #[test]
+    fn test_decode_invalid_redundancy() {
+        // Hybrid packet that signals more redundancy bytes than are left in the frame.
+        let packet = [
+            0x72, 0x26, 0x9C, 0x86, 0x0D, 0xE6, 0xC8, 0xA4, 0x91, 0xDE, 0x12, 0x2F, 0x74, 0x0A,
+            0x65, 0x9D, 0x32, 0x26, 0xC5, 0x21, 0xE5, 0xEA, 0x5A, 0xBB, 0xAE, 0xF9, 0x7C, 0x46,
+            0x93, 0x6E, 0x33, 0x22, 0xDA, 0xE8, 0x30, 0x87, 0xAD, 0xB2, 0x1C, 0xC8,
+        ];
+        let mut decoder = Decoder::new(&DecoderConfiguration::default()).unwrap();
+        let mut samples = vec![0_f32; 960 * 2];
+        let count = decoder
+            .decode_float(
+                Some(&packet),
+                &mut samples,
+                NonZeroUsize::new(960).unwrap(),
+                false,
+            )
+            .unwrap();
+        assert_eq!(count, 960);
+    }
+
+    #[test]
+    fn test_decode_random_packets() {
+        let mut rng = nanorand::WyRand::new_seed(42);
+        let mut data = [0_u8; 1500];
+
+        [
+            (SamplingRate::Hz48000, Channels::Stereo),
+            (SamplingRate::Hz16000, Channels::Mono),
+        ]
+        .iter()
+        .for_each(|&(sampling_rate, channels)| {
+            let mut decoder = Decoder::new(&DecoderConfiguration {
+                sampling_rate,
+                channels,
+                gain: 0,
+            })
+            .unwrap();
+            let mut samples = vec![0_f32; 5760 * 2];
+
+            (0..3000).for_each(|i| {
+                let len = rng.generate_range::<usize, _>(0..64 << (i % 5));
+                let data = &mut data[..len];
+                rng.fill(&mut *data);
+                let frame_size =
+                    sampling_rate as usize / 400 * rng.generate_range::<usize, _>(1..49);
+                let decode_fec = i % 8 == 0;
+
+                // Errors are fine, panics are not.
+                let _ = decoder.decode_float(
+                    Some(data),
+                    &mut samples,
+                    NonZeroUsize::new(frame_size).unwrap(),
+                    decode_fec,
+                );
+                assert!(samples.iter().all(|x| x.is_finite()));
+            });
+        });
+    }
+}
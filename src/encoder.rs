@@ -1,10 +1,2871 @@
-/* Ideas for the interface:
-fn max_encoded_size(&self, samples: usize) -> usize;
-fn encode<T: Sample>(&self, samples: &[T], out: &mut [u8]) -> usize;
+//! Implement the Opus encoder.
 
-pub struct EncodeResult {
-    pub samples_consumed: usize,
-    pub bytes_written: usize,
+use crate::celt::mode::{NB_E_BANDS, OVERLAP, WINDOW};
+use crate::celt::{CeltEncoder, EPSILON, VERY_SMALL};
+use crate::math::fast_exp2;
+use crate::range_coder::{RangeEncoder, Tell};
+use crate::repacketizer::{pad_packet, Repacketizer};
+use crate::silk::fixed::{lin2log, log2lin, smlawb, smulbb, smulwb, smulww};
+use crate::silk::{
+    EncoderControl, Prefill, SilkEncoder, MAX_CONSECUTIVE_DTX, NB_SPEECH_FRAMES_BEFORE_DTX,
 };
-fn encode<T: Sample>(&self, samples: &[T], out: &mut [u8]) -> EncodeResult;
- */
+use crate::{Bandwidth, Channels, CodecMode, InputSample, OpusError, SamplingRate};
+
+/// Transition thresholds for voice. The first value is the middle (memoriless)
+/// threshold. The second value is the hysteresis (difference with the middle).
+const VOICE_BANDWIDTH_THRESHOLDS: &[i32; 8] = &[
+    9000, 700, // NB<->MB
+    9000, 700, // MB<->WB
+    13500, 1000, // WB<->SWB
+    14000, 2000, // SWB<->FB
+];
+
+/// Transition thresholds for music. The first value is the middle (memoriless)
+/// threshold. The second value is the hysteresis (difference with the middle).
+const MUSIC_BANDWIDTH_THRESHOLDS: &[i32; 8] = &[
+    9000, 700, // NB<->MB
+    9000, 700, // MB<->WB
+    11000, 1000, // WB<->SWB
+    12000, 2000, // SWB<->FB
+];
+
+/// Threshold bit-rates for switching between Silk/hybrid and Celt-only
+/// as `[voice, music]` for mono and stereo.
+const MODE_THRESHOLDS: &[[i32; 2]; 2] = &[[64000, 10000], [44000, 10000]];
+
+/// Threshold bit-rates for using in-band FEC at each bandwidth. The first value
+/// is the middle (memoriless) threshold. The second value is the hysteresis.
+const FEC_THRESHOLDS: &[i32; 10] = &[
+    12000, 1000, // NB
+    14000, 1000, // MB
+    16000, 1000, // WB
+    20000, 1000, // SWB
+    22000, 1000, // FB
+];
+
+/// Threshold bit-rates for switching between mono and stereo.
+const STEREO_VOICE_THRESHOLD: i32 = 19000;
+const STEREO_MUSIC_THRESHOLD: i32 = 17000;
+
+/// Minimal cutoff frequency of the variable high pass filter in Hz.
+const VARIABLE_HP_MIN_CUTOFF_HZ: i32 = 60;
+/// Smoothing coefficient of the variable high pass filter cutoff in Q16.
+const VARIABLE_HP_SMTH_COEF2_Q16: i32 = 983;
+
+/// The intended application of the encoder.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Application {
+    /// Best for most VoIP/videoconference applications where listening quality
+    /// and intelligibility matter most.
+    Voip,
+    /// Best for broadcast/high-fidelity application where the decoded audio
+    /// should be as close as possible to the input.
+    Audio,
+    /// Only use when lowest-achievable latency is what matters most.
+    /// Voice-optimized modes cannot be used.
+    RestrictedLowDelay,
+}
+
+/// The bitrate of the encoder.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Bitrate {
+    /// The encoder chooses the bitrate based on the sampling rate and channels.
+    Auto,
+    /// Uses as many bits as the output buffer allows.
+    Max,
+    /// Explicit bitrate in bits per second. Values are clamped to
+    /// 500 to 750000 bits per second and channel.
+    BitsPerSecond(u32),
+}
+
+/// The duration of the frames the encoder produces.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FrameDuration {
+    /// 2.5 ms frames.
+    Ms2_5,
+    /// 5 ms frames.
+    Ms5,
+    /// 10 ms frames.
+    Ms10,
+    /// 20 ms frames.
+    Ms20,
+    /// 40 ms frames.
+    Ms40,
+    /// 60 ms frames.
+    Ms60,
+    /// 80 ms frames.
+    Ms80,
+    /// 100 ms frames.
+    Ms100,
+    /// 120 ms frames.
+    Ms120,
+}
+
+impl FrameDuration {
+    /// Returns the number of samples per channel of a frame at the given sampling rate.
+    pub fn sample_count(self, sampling_rate: SamplingRate) -> usize {
+        let fs = sampling_rate as usize;
+        match self {
+            FrameDuration::Ms2_5 => fs / 400,
+            FrameDuration::Ms5 => fs / 200,
+            FrameDuration::Ms10 => fs / 100,
+            FrameDuration::Ms20 => fs / 50,
+            FrameDuration::Ms40 => fs / 25,
+            FrameDuration::Ms60 => 3 * fs / 50,
+            FrameDuration::Ms80 => 4 * fs / 50,
+            FrameDuration::Ms100 => 5 * fs / 50,
+            FrameDuration::Ms120 => 6 * fs / 50,
+        }
+    }
+}
+
+/// Configures the encoder on creation.
+#[derive(Clone, Debug)]
+pub struct EncoderConfiguration {
+    /// Sampling rate of the input signal (Hz). Default: 48000 kHz.
+    pub sampling_rate: SamplingRate,
+    /// Number of channels of the input signal. Default: Stereo.
+    pub channels: Channels,
+    /// The intended application. Default: Audio.
+    pub application: Application,
+    /// The bitrate of the encoded stream. Default: Auto.
+    pub bitrate: Bitrate,
+    /// The computational complexity of the encoder (0-10). Default: 9.
+    pub complexity: u8,
+    /// The duration of the encoded frames. Default: 20 ms.
+    pub frame_duration: FrameDuration,
+    /// Use a variable bitrate. Default: true.
+    pub vbr: bool,
+    /// Constrain the variable bitrate, so that the bitrate doesn't
+    /// exceed the target bitrate over the duration of a few frames. Default: true.
+    pub vbr_constraint: bool,
+    /// Forces the encoder to use the given codec mode. Default: None.
+    pub force_mode: Option<CodecMode>,
+    /// Forces the encoder to use the given bandwidth. Default: None.
+    pub bandwidth: Option<Bandwidth>,
+    /// Embeds low bitrate redundancy (LBRR) data of the previous frame in Silk and
+    /// hybrid packets, so that decoders can recover a lost packet from the following
+    /// one. Default: false.
+    pub inband_fec: bool,
+    /// The expected packet loss in percent (0-100). Higher values make the encoder
+    /// more robust against loss at the cost of quality. Default: 0.
+    pub packet_loss_percentage: u8,
+    /// Use discontinuous transmission (DTX). During silence the encoder emits 1 byte
+    /// packets and updates the comfort noise of the decoder every 400 ms. Default: false.
+    pub dtx: bool,
+}
+
+impl Default for EncoderConfiguration {
+    fn default() -> Self {
+        Self {
+            sampling_rate: SamplingRate::Hz48000,
+            channels: Channels::Stereo,
+            application: Application::Audio,
+            bitrate: Bitrate::Auto,
+            complexity: 9,
+            frame_duration: FrameDuration::Ms20,
+            vbr: true,
+            vbr_constraint: true,
+            force_mode: None,
+            bandwidth: None,
+            inband_fec: false,
+            packet_loss_percentage: 0,
+            dtx: false,
+        }
+    }
+}
+
+/// Opus encoder.
+///
+/// Opus is a stateful codec with overlapping blocks and as a result Opus
+/// packets are not coded independently of each other. Frames must be
+/// passed into the encoder serially and in the correct order.
+#[derive(Clone, Debug)]
+pub struct Encoder {
+    inner: EncoderInner,
+    buffer: Vec<f32>,
+}
+
+impl Encoder {
+    /// Creates a new `Encoder` with the given configuration.
+    pub fn new(configuration: &EncoderConfiguration) -> Result<Self, OpusError> {
+        let inner = EncoderInner::new(configuration)?;
+        Ok(Self {
+            inner,
+            buffer: vec![],
+        })
+    }
+
+    /// Resets the Encoder to be equivalent to a freshly initialized encoder.
+    ///
+    /// This should be called when switching streams in order to prevent
+    /// the back to back encoding from giving different results from
+    /// one at a time encoding.
+    pub fn reset(&mut self) -> Result<(), OpusError> {
+        self.buffer = vec![];
+        self.inner.reset()
+    }
+
+    /// Returns the sampling rate the encoder was initialized with.
+    pub fn sampling_rate(&self) -> SamplingRate {
+        self.inner.sampling_rate
+    }
+
+    /// Returns the channels the encoder was initialized with.
+    pub fn channels(&self) -> Channels {
+        self.inner.channels
+    }
+
+    /// Returns the application the encoder was initialized with.
+    pub fn application(&self) -> Application {
+        self.inner.application
+    }
+
+    /// Returns the bitrate the encoder was initialized with.
+    pub fn bitrate(&self) -> Bitrate {
+        self.inner.user_bitrate
+    }
+
+    /// Returns the complexity the encoder was initialized with.
+    pub fn complexity(&self) -> u8 {
+        self.inner.complexity as u8
+    }
+
+    /// Returns true if the encoder was initialized with in-band forward error correction.
+    pub fn inband_fec(&self) -> bool {
+        self.inner.silk_mode.use_in_band_fec
+    }
+
+    /// Returns the expected packet loss percentage the encoder was initialized with.
+    pub fn packet_loss_percentage(&self) -> u8 {
+        self.inner.silk_mode.packet_loss_percentage as u8
+    }
+
+    /// Returns true if the encoder was initialized with discontinuous transmission.
+    pub fn dtx(&self) -> bool {
+        self.inner.use_dtx
+    }
+
+    /// Returns true if the encoder is currently in discontinuous transmission,
+    /// which means that the last frames were silent and only DTX packets were emitted.
+    pub fn in_dtx(&self) -> bool {
+        self.inner.in_dtx()
+    }
+
+    /// Returns the frame duration the encoder was initialized with.
+    pub fn frame_duration(&self) -> FrameDuration {
+        self.inner.frame_duration
+    }
+
+    /// Returns the number of samples per channel the encoder consumes per packet.
+    pub fn frame_size(&self) -> usize {
+        self.inner.frame_size
+    }
+
+    /// Returns the number of samples per channel the encoder delays the signal.
+    ///
+    /// Decoders need to discard these samples from the start of the decoded signal.
+    /// It's usually stored as the pre-skip in the header of the container.
+    pub fn lookahead(&self) -> usize {
+        self.inner.lookahead()
+    }
+
+    /// Returns the bandwidth of the last encoded packet.
+    pub fn bandwidth(&self) -> Bandwidth {
+        self.inner.bandwidth
+    }
+
+    /// Returns the final state of the codec's entropy coder.
+    ///
+    /// This is used for testing purposes, the encoder and decoder state
+    /// should be identical after coding a payload assuming no data
+    /// corruption or software bugs).
+    pub fn final_range(&self) -> u32 {
+        self.inner.final_range
+    }
+
+    /// Encodes an Opus packet from a generic sample input.
+    ///
+    /// Returns the length of the encoded packet in bytes.
+    ///
+    /// The internal format is `f32`. Use `encode_float()` to access it directly.
+    ///
+    /// # Arguments
+    /// * `samples` - Input signal encoded as PCM samples (interleaved if 2 channels).
+    ///   Length must be at least `frame_size()` * `channels`.
+    /// * `output`  - Output payload. A length of 4000 bytes is recommended. The
+    ///   length of the buffer limits the size of the encoded packet.
+    ///
+    pub fn encode<S: InputSample>(
+        &mut self,
+        samples: &[S],
+        output: &mut [u8],
+    ) -> Result<usize, OpusError> {
+        let size = self.inner.frame_size * self.inner.channels as usize;
+        if samples.len() < size {
+            return Err(OpusError::BadArguments(
+                "samples must contain at least frame_size * channels samples",
+            ));
+        }
+
+        if self.buffer.len() < size {
+            self.buffer.resize(size, 0_f32);
+        }
+        self.buffer
+            .iter_mut()
+            .zip(samples.iter())
+            .for_each(|(x, &s)| *x = s.to_f32());
+
+        let frame_size = self.inner.frame_size;
+        self.inner.encode_native(
+            &self.buffer[..size],
+            frame_size,
+            output,
+            S::LSB_DEPTH,
+            false,
+        )
+    }
+
+    /// Encodes an Opus packet from a floating point input.
+    ///
+    /// Returns the length of the encoded packet in bytes.
+    ///
+    /// Samples with a range beyond +/-1.0 are supported but will be clipped
+    /// by decoders using the integer API.
+    ///
+    /// # Arguments
+    /// * `samples` - Input signal encoded as PCM samples (interleaved if 2 channels).
+    ///   Length must be at least `frame_size()` * `channels`.
+    /// * `output`  - Output payload. A length of 4000 bytes is recommended. The
+    ///   length of the buffer limits the size of the encoded packet.
+    ///
+    pub fn encode_float(&mut self, samples: &[f32], output: &mut [u8]) -> Result<usize, OpusError> {
+        let size = self.inner.frame_size * self.inner.channels as usize;
+        if samples.len() < size {
+            return Err(OpusError::BadArguments(
+                "samples must contain at least frame_size * channels samples",
+            ));
+        }
+
+        let frame_size = self.inner.frame_size;
+        self.inner
+            .encode_native(&samples[..size], frame_size, output, 24, true)
+    }
+}
+
+/// State of the stereo width estimation.
+#[derive(Clone, Debug, Default)]
+struct StereoWidthState {
+    xx: f32,
+    xy: f32,
+    yy: f32,
+    smoothed_width: f32,
+    max_follower: f32,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct EncoderInner {
+    silk_enc: SilkEncoder,
+    silk_mode: EncoderControl,
+    celt_enc: CeltEncoder,
+    pub(crate) sampling_rate: SamplingRate,
+    pub(crate) channels: Channels,
+    application: Application,
+    user_bitrate: Bitrate,
+    complexity: i32,
+    frame_duration: FrameDuration,
+    pub(crate) frame_size: usize,
+    pub(crate) use_vbr: bool,
+    vbr_constraint: bool,
+    lsb_depth: i32,
+    use_dtx: bool,
+    encoder_buffer: usize,
+    delay_compensation: usize,
+
+    // Settings that are forced while encoding the frames of a multiframe packet.
+    force_mode: Option<CodecMode>,
+    user_bandwidth: Option<Bandwidth>,
+    force_channels: Option<Channels>,
+    nonfinal_frame: bool,
+
+    // Settings of the multistream encoder for surround sound.
+    lfe: bool,
+    energy_masking: Option<[f32; 2 * NB_E_BANDS]>,
+
+    stream_channels: Channels,
+    bitrate_bps: i32,
+    pub(crate) mode: CodecMode,
+    prev_mode: Option<CodecMode>,
+    prev_channels: Option<Channels>,
+    pub(crate) bandwidth: Bandwidth,
+    auto_bandwidth: Bandwidth,
+    first: bool,
+    variable_hp_smth2_q15: i32,
+    hp_mem: [f32; 4],
+    hybrid_stereo_width_q14: i32,
+    width_mem: StereoWidthState,
+    prev_hb_gain: f32,
+    silk_bw_switch: bool,
+    delay_buffer: Vec<f32>,
+    nb_no_activity_ms_q1: i32,
+
+    pub(crate) final_range: u32,
+}
+
+impl EncoderInner {
+    pub(crate) fn new(configuration: &EncoderConfiguration) -> Result<Self, OpusError> {
+        if configuration.complexity > 10 {
+            return Err(OpusError::BadArguments(
+                "complexity must be between 0 and 10",
+            ));
+        }
+        if configuration.bitrate == Bitrate::BitsPerSecond(0) {
+            return Err(OpusError::BadArguments("bitrate must not be zero"));
+        }
+        if configuration.packet_loss_percentage > 100 {
+            return Err(OpusError::BadArguments(
+                "packet loss percentage must be between 0 and 100",
+            ));
+        }
+
+        let sampling_rate = configuration.sampling_rate;
+        let channels = configuration.channels;
+        let fs = sampling_rate as usize;
+
+        let silk_mode = EncoderControl {
+            channels_api: channels as usize,
+            channels_internal: channels as usize,
+            api_sampling_rate: fs,
+            max_internal_sampling_rate: 16000,
+            min_internal_sampling_rate: 8000,
+            desired_internal_sampling_rate: 16000,
+            payload_size_ms: 20,
+            bitrate: 25000,
+            complexity: configuration.complexity as usize,
+            packet_loss_percentage: i32::from(configuration.packet_loss_percentage),
+            use_in_band_fec: configuration.inband_fec,
+            ..Default::default()
+        };
+
+        let mut celt_enc = CeltEncoder::new(sampling_rate, channels)?;
+        celt_enc.set_complexity(configuration.complexity as i32);
+        celt_enc.set_packet_loss(i32::from(configuration.packet_loss_percentage));
+
+        let mut encoder = Self {
+            silk_enc: SilkEncoder::new(),
+            silk_mode,
+            celt_enc,
+            sampling_rate,
+            channels,
+            application: configuration.application,
+            user_bitrate: Bitrate::Auto,
+            complexity: configuration.complexity as i32,
+            frame_duration: configuration.frame_duration,
+            frame_size: configuration.frame_duration.sample_count(sampling_rate),
+            use_vbr: configuration.vbr,
+            vbr_constraint: configuration.vbr_constraint,
+            lsb_depth: 24,
+            use_dtx: configuration.dtx,
+            encoder_buffer: fs / 100,
+            // Delay compensation of 4 ms (2.5 ms for SILK's extra look-ahead
+            // + 1.5 ms for SILK resamplers and stereo prediction).
+            delay_compensation: fs / 250,
+            force_mode: configuration.force_mode,
+            user_bandwidth: configuration.bandwidth,
+            force_channels: None,
+            nonfinal_frame: false,
+            lfe: false,
+            energy_masking: None,
+            stream_channels: channels,
+            bitrate_bps: 3000 + fs as i32 * channels as i32,
+            mode: CodecMode::Hybrid,
+            prev_mode: None,
+            prev_channels: None,
+            bandwidth: Bandwidth::Fullband,
+            auto_bandwidth: Bandwidth::Fullband,
+            first: true,
+            variable_hp_smth2_q15: lin2log(VARIABLE_HP_MIN_CUTOFF_HZ) << 8,
+            hp_mem: [0.0; 4],
+            hybrid_stereo_width_q14: 1 << 14,
+            width_mem: StereoWidthState::default(),
+            prev_hb_gain: 1.0,
+            silk_bw_switch: false,
+            delay_buffer: vec![0.0; fs / 100 * channels as usize],
+            nb_no_activity_ms_q1: 0,
+            final_range: 0,
+        };
+        encoder.set_bitrate(configuration.bitrate);
+
+        Ok(encoder)
+    }
+
+    /// Returns the encoder lookahead in samples at the encoder sampling rate.
+    fn lookahead(&self) -> usize {
+        let fs = self.sampling_rate as usize;
+        if self.application == Application::RestrictedLowDelay {
+            fs / 400
+        } else {
+            fs / 400 + self.delay_compensation
+        }
+    }
+
+    /// Returns true if the encoder is currently in DTX.
+    fn in_dtx(&self) -> bool {
+        if self.silk_mode.use_dtx
+            && matches!(
+                self.prev_mode,
+                Some(CodecMode::SilkOnly) | Some(CodecMode::Hybrid)
+            )
+        {
+            // DTX determined by Silk.
+            self.silk_enc.in_dtx()
+        } else if self.use_dtx {
+            // DTX determined by Opus.
+            self.nb_no_activity_ms_q1 >= NB_SPEECH_FRAMES_BEFORE_DTX * 20 * 2
+        } else {
+            false
+        }
+    }
+
+    pub(crate) fn reset(&mut self) -> Result<(), OpusError> {
+        self.silk_enc.reset();
+        self.celt_enc.reset()?;
+
+        self.stream_channels = self.channels;
+        self.mode = CodecMode::Hybrid;
+        self.prev_mode = None;
+        self.prev_channels = None;
+        self.bandwidth = Bandwidth::Fullband;
+        self.auto_bandwidth = Bandwidth::Fullband;
+        self.first = true;
+        self.variable_hp_smth2_q15 = lin2log(VARIABLE_HP_MIN_CUTOFF_HZ) << 8;
+        self.hp_mem = [0.0; 4];
+        self.hybrid_stereo_width_q14 = 1 << 14;
+        self.width_mem = StereoWidthState::default();
+        self.prev_hb_gain = 1.0;
+        self.silk_bw_switch = false;
+        self.delay_buffer.iter_mut().for_each(|x| *x = 0.0);
+        self.nb_no_activity_ms_q1 = 0;
+        self.final_range = 0;
+
+        Ok(())
+    }
+
+    /// Sets the bitrate of the encoder.
+    pub(crate) fn set_bitrate(&mut self, bitrate: Bitrate) {
+        self.user_bitrate = match bitrate {
+            Bitrate::BitsPerSecond(bitrate) => {
+                Bitrate::BitsPerSecond(bitrate.clamp(500, 750_000 * self.channels as u32))
+            }
+            bitrate => bitrate,
+        };
+    }
+
+    /// Forces the encoder to use the given bandwidth.
+    pub(crate) fn set_bandwidth(&mut self, bandwidth: Option<Bandwidth>) {
+        self.user_bandwidth = bandwidth;
+    }
+
+    /// Forces the encoder to use the given codec mode.
+    pub(crate) fn set_force_mode(&mut self, force_mode: Option<CodecMode>) {
+        self.force_mode = force_mode;
+    }
+
+    /// Forces the encoder to code the given number of channels.
+    pub(crate) fn set_force_channels(&mut self, force_channels: Option<Channels>) {
+        self.force_channels = force_channels;
+    }
+
+    /// Enables or disables the coding of a low frequency effects channel.
+    pub(crate) fn set_lfe(&mut self, lfe: bool) {
+        self.lfe = lfe;
+        self.celt_enc.set_lfe(lfe);
+    }
+
+    /// Sets the masking of the bands by the other channels of a surround mix.
+    ///
+    /// The mask contains 21 values per channel.
+    pub(crate) fn set_energy_mask(&mut self, energy_mask: Option<&[f32]>) {
+        self.energy_masking = energy_mask.map(|energy_mask| {
+            let mut mask = [0_f32; 2 * NB_E_BANDS];
+            let len = usize::min(energy_mask.len(), mask.len());
+            mask[..len].copy_from_slice(&energy_mask[..len]);
+            mask
+        });
+        self.celt_enc.set_energy_mask(energy_mask);
+    }
+
+    /// Encodes a frame of `frame_size` samples per channel into `data`.
+    ///
+    /// Returns the size of the packet.
+    pub(crate) fn encode_native(
+        &mut self,
+        pcm: &[f32],
+        frame_size: usize,
+        data: &mut [u8],
+        lsb_depth: i32,
+        float_api: bool,
+    ) -> Result<usize, OpusError> {
+        let fs = self.sampling_rate as usize;
+        let channels = self.channels as usize;
+        let out_data_bytes = data.len();
+        let mut max_data_bytes = usize::min(1276, out_data_bytes);
+        let mut redundancy = false;
+        let mut redundancy_bytes = 0;
+        let mut celt_to_silk = false;
+        let mut to_celt = false;
+        let mut prefill = Prefill::None;
+        let mut redundant_rng = 0;
+
+        self.final_range = 0;
+        if max_data_bytes == 0 {
+            return Err(OpusError::BufferToSmall);
+        }
+
+        // Cannot encode 100 ms in 1 byte.
+        if max_data_bytes == 1 && fs == frame_size * 10 {
+            return Err(OpusError::BufferToSmall);
+        }
+
+        let delay_compensation = if self.application == Application::RestrictedLowDelay {
+            0
+        } else {
+            self.delay_compensation
+        };
+
+        let lsb_depth = i32::min(lsb_depth, self.lsb_depth);
+
+        // The signal analysis is not implemented, so only digital silence
+        // is detected as inactivity.
+        let is_silence = self.complexity >= 7
+            && fs >= 16000
+            && is_digital_silence(&pcm[..frame_size * channels], lsb_depth);
+        let activity = if is_silence { Some(false) } else { None };
+
+        let stereo_width =
+            if self.channels == Channels::Stereo && self.force_channels != Some(Channels::Mono) {
+                compute_stereo_width(pcm, frame_size, fs, &mut self.width_mem)
+            } else {
+                0.0
+            };
+
+        let total_buffer = delay_compensation;
+        self.bitrate_bps = self.user_bitrate_to_bitrate(frame_size, max_data_bytes);
+
+        let frame_rate = (fs / frame_size) as i32;
+        if !self.use_vbr {
+            // Multiply by 12 to make sure the division is exact.
+            let frame_rate12 = 12 * fs as i32 / frame_size as i32;
+            // We need to make sure that "int" values always fit in 16 bits.
+            let cbr_bytes = i32::min(
+                (12 * self.bitrate_bps / 8 + frame_rate12 / 2) / frame_rate12,
+                max_data_bytes as i32,
+            );
+            self.bitrate_bps = cbr_bytes * frame_rate12 * 8 / 12;
+            // Make sure we provide at least one byte to avoid failing.
+            max_data_bytes = usize::max(1, cbr_bytes as usize);
+        }
+
+        if max_data_bytes < 3
+            || self.bitrate_bps < 3 * frame_rate * 8
+            || (frame_rate < 50
+                && (max_data_bytes as i32 * frame_rate < 300 || self.bitrate_bps < 2400))
+        {
+            return self.encode_plc_frame(data, out_data_bytes, max_data_bytes, frame_rate);
+        }
+        let max_rate = frame_rate * max_data_bytes as i32 * 8;
+
+        // Equivalent 20-ms rate for mode/channel/bandwidth decisions.
+        let mut equiv_rate = compute_equiv_rate(
+            self.bitrate_bps,
+            self.channels as i32,
+            frame_rate,
+            self.use_vbr,
+            None,
+            self.complexity,
+            self.silk_mode.packet_loss_percentage,
+        );
+
+        // The signal analysis is not implemented, so we can't estimate
+        // the probability of voice. Use the application instead.
+        let voice_est = if self.application == Application::Voip {
+            115
+        } else {
+            48
+        };
+
+        if let (Some(force_channels), Channels::Stereo) = (self.force_channels, self.channels) {
+            self.stream_channels = force_channels;
+        } else if self.channels == Channels::Stereo {
+            // Rate-dependent mono-stereo decision.
+            let mut stereo_threshold = STEREO_MUSIC_THRESHOLD
+                + ((voice_est * voice_est * (STEREO_VOICE_THRESHOLD - STEREO_MUSIC_THRESHOLD))
+                    >> 14);
+            if self.stream_channels == Channels::Stereo {
+                stereo_threshold -= 1000;
+            } else {
+                stereo_threshold += 1000;
+            }
+            self.stream_channels = if equiv_rate > stereo_threshold {
+                Channels::Stereo
+            } else {
+                Channels::Mono
+            };
+        } else {
+            self.stream_channels = self.channels;
+        }
+
+        // Update equivalent rate for channels decision.
+        equiv_rate = compute_equiv_rate(
+            self.bitrate_bps,
+            self.stream_channels as i32,
+            frame_rate,
+            self.use_vbr,
+            None,
+            self.complexity,
+            self.silk_mode.packet_loss_percentage,
+        );
+
+        // Allow Silk DTX if DTX is enabled but the generalized DTX can't be used.
+        self.silk_mode.use_dtx = self.use_dtx && !is_silence;
+
+        // Mode selection depending on application and signal type.
+        self.mode = if self.application == Application::RestrictedLowDelay {
+            CodecMode::CeltOnly
+        } else if let Some(force_mode) = self.force_mode {
+            force_mode
+        } else {
+            // Interpolate based on stereo width.
+            let mode_voice = ((1.0 - stereo_width) * MODE_THRESHOLDS[0][0] as f32
+                + stereo_width * MODE_THRESHOLDS[1][0] as f32) as i32;
+            let mode_music = ((1.0 - stereo_width) * MODE_THRESHOLDS[1][1] as f32
+                + stereo_width * MODE_THRESHOLDS[1][1] as f32) as i32;
+            // Interpolate based on speech/music probability.
+            let mut threshold =
+                mode_music + ((voice_est * voice_est * (mode_voice - mode_music)) >> 14);
+            // Bias towards Silk for VoIP because of some useful features.
+            if self.application == Application::Voip {
+                threshold += 8000;
+            }
+
+            // Hysteresis.
+            match self.prev_mode {
+                Some(CodecMode::CeltOnly) => threshold -= 4000,
+                Some(_) => threshold += 4000,
+                None => {}
+            }
+
+            let mut mode = if equiv_rate >= threshold {
+                CodecMode::CeltOnly
+            } else {
+                CodecMode::SilkOnly
+            };
+
+            // When FEC is enabled and there's enough packet loss, use Silk.
+            if self.silk_mode.use_in_band_fec
+                && self.silk_mode.packet_loss_percentage > (128 - voice_est) >> 4
+            {
+                mode = CodecMode::SilkOnly;
+            }
+            // When encoding voice and DTX is enabled, use Silk in order to make use of its DTX.
+            if self.silk_mode.use_dtx && voice_est > 100 {
+                mode = CodecMode::SilkOnly;
+            }
+
+            // If max_data_bytes represents less than 6 kb/s, switch to Celt-only mode.
+            let min_rate = if frame_rate > 50 { 9000 } else { 6000 };
+            if max_data_bytes < min_rate * frame_size / (fs * 8) {
+                mode = CodecMode::CeltOnly;
+            }
+
+            mode
+        };
+
+        // Override the chosen mode to make sure we meet the requested frame size.
+        if self.mode != CodecMode::CeltOnly && frame_size < fs / 100 {
+            self.mode = CodecMode::CeltOnly;
+        }
+        if self.lfe {
+            self.mode = CodecMode::CeltOnly;
+        }
+
+        if let Some(prev_mode) = self.prev_mode {
+            if (self.mode != CodecMode::CeltOnly && prev_mode == CodecMode::CeltOnly)
+                || (self.mode == CodecMode::CeltOnly && prev_mode != CodecMode::CeltOnly)
+            {
+                redundancy = true;
+                celt_to_silk = self.mode != CodecMode::CeltOnly;
+                if !celt_to_silk {
+                    // Switch to Silk/hybrid if frame size is 10 ms or more.
+                    if frame_size >= fs / 100 {
+                        self.mode = prev_mode;
+                        to_celt = true;
+                    } else {
+                        redundancy = false;
+                    }
+                }
+            }
+        }
+
+        // When encoding multiframes, we can ask for a switch to Celt only in the last frame.
+        // This switch is processed above as the requested mode shouldn't interrupt
+        // stereo->mono transition.
+        if self.stream_channels == Channels::Mono
+            && self.prev_channels == Some(Channels::Stereo)
+            && !self.silk_mode.to_mono
+            && self.mode != CodecMode::CeltOnly
+            && self.prev_mode != Some(CodecMode::CeltOnly)
+        {
+            // Delay stereo->mono transition by two frames so that Silk can do a smooth downmix.
+            self.silk_mode.to_mono = true;
+            self.stream_channels = Channels::Stereo;
+        } else {
+            self.silk_mode.to_mono = false;
+        }
+
+        // Update equivalent rate with mode decision.
+        equiv_rate = compute_equiv_rate(
+            self.bitrate_bps,
+            self.stream_channels as i32,
+            frame_rate,
+            self.use_vbr,
+            Some(self.mode),
+            self.complexity,
+            self.silk_mode.packet_loss_percentage,
+        );
+
+        if self.mode != CodecMode::CeltOnly && self.prev_mode == Some(CodecMode::CeltOnly) {
+            self.silk_enc.reset();
+            prefill = Prefill::Reset;
+        }
+
+        // Automatic (rate-dependent) bandwidth selection.
+        if self.mode == CodecMode::CeltOnly || self.first || self.silk_mode.allow_bandwidth_switch {
+            self.decide_bandwidth(voice_est, equiv_rate);
+
+            // Prevents any transition to SWB/FB until the Silk layer has fully
+            // switched to WB mode and turned the variable LP filter off.
+            if !self.first
+                && self.mode != CodecMode::CeltOnly
+                && !self.silk_mode.in_wb_mode_without_variable_lp
+                && self.bandwidth > Bandwidth::Wideband
+            {
+                self.bandwidth = Bandwidth::Wideband;
+            }
+        }
+
+        if let Some(user_bandwidth) = self.user_bandwidth {
+            self.bandwidth = user_bandwidth;
+        }
+
+        // This prevents us from using hybrid at unsafe CBR/max rates.
+        if self.mode != CodecMode::CeltOnly && max_rate < 15000 {
+            self.bandwidth = Bandwidth::min(self.bandwidth, Bandwidth::Wideband);
+        }
+
+        // Prevents Opus from wasting bits on frequencies that are above
+        // the Nyquist rate of the input signal.
+        let max_bandwidth = match self.sampling_rate {
+            SamplingRate::Hz48000 => Bandwidth::Fullband,
+            SamplingRate::Hz24000 => Bandwidth::Superwideband,
+            SamplingRate::Hz16000 => Bandwidth::Wideband,
+            SamplingRate::Hz12000 => Bandwidth::Mediumband,
+            SamplingRate::Hz8000 => Bandwidth::Narrowband,
+        };
+        self.bandwidth = Bandwidth::min(self.bandwidth, max_bandwidth);
+
+        self.silk_mode.lbrr_coded = decide_fec(
+            self.silk_mode.use_in_band_fec,
+            self.silk_mode.packet_loss_percentage,
+            self.silk_mode.lbrr_coded,
+            self.mode,
+            &mut self.bandwidth,
+            equiv_rate,
+        );
+        self.celt_enc.set_lsb_depth(lsb_depth);
+
+        // Celt mode doesn't support mediumband, use wideband instead.
+        if self.mode == CodecMode::CeltOnly && self.bandwidth == Bandwidth::Mediumband {
+            self.bandwidth = Bandwidth::Wideband;
+        }
+        if self.lfe {
+            self.bandwidth = Bandwidth::Narrowband;
+        }
+
+        let mut curr_bandwidth = self.bandwidth;
+
+        // Chooses the appropriate mode for speech.
+        // *NEVER* switch to/from Celt-only mode here as this will invalidate some assumptions.
+        if self.mode == CodecMode::SilkOnly && curr_bandwidth > Bandwidth::Wideband {
+            self.mode = CodecMode::Hybrid;
+        }
+        if self.mode == CodecMode::Hybrid && curr_bandwidth <= Bandwidth::Wideband {
+            self.mode = CodecMode::SilkOnly;
+        }
+
+        // Can't support higher than 60 ms frames, and 20 ms when in hybrid or Celt-only modes.
+        if (frame_size > fs / 50 && self.mode != CodecMode::SilkOnly) || frame_size > 3 * fs / 50 {
+            let enc_frame_size = if self.mode == CodecMode::SilkOnly {
+                if frame_size == 2 * fs / 25 {
+                    // 80 ms -> 2x 40 ms
+                    fs / 25
+                } else if frame_size == 3 * fs / 25 {
+                    // 120 ms -> 2x 60 ms
+                    3 * fs / 50
+                } else {
+                    // 100 ms -> 5x 20 ms
+                    fs / 50
+                }
+            } else {
+                fs / 50
+            };
+            let nb_frames = frame_size / enc_frame_size;
+            return self.encode_multiframe_packet(
+                pcm,
+                nb_frames,
+                enc_frame_size,
+                data,
+                to_celt,
+                lsb_depth,
+                float_api,
+            );
+        }
+
+        // For the first frame at a new Silk bandwidth.
+        if self.silk_bw_switch {
+            redundancy = true;
+            celt_to_silk = true;
+            self.silk_bw_switch = false;
+            // Do a prefill without resetting the sampling rate control.
+            prefill = Prefill::KeepLowpass;
+        }
+
+        // If we decided to go with Celt, make sure redundancy is off, no matter what
+        // we decided earlier.
+        if self.mode == CodecMode::CeltOnly {
+            redundancy = false;
+        }
+
+        if redundancy {
+            redundancy_bytes = compute_redundancy_bytes(
+                max_data_bytes,
+                self.bitrate_bps,
+                frame_rate,
+                self.stream_channels as i32,
+            );
+            if redundancy_bytes == 0 {
+                redundancy = false;
+            }
+        }
+
+        let bytes_target = i32::min(
+            (max_data_bytes - redundancy_bytes) as i32,
+            self.bitrate_bps * frame_size as i32 / (fs as i32 * 8),
+        ) - 1;
+
+        let mut pcm_buf = vec![0_f32; (total_buffer + frame_size) * channels];
+        let start = (self.encoder_buffer - total_buffer) * channels;
+        pcm_buf[..total_buffer * channels]
+            .copy_from_slice(&self.delay_buffer[start..start + total_buffer * channels]);
+
+        let hp_freq_smth1 = if self.mode == CodecMode::CeltOnly {
+            lin2log(VARIABLE_HP_MIN_CUTOFF_HZ) << 8
+        } else {
+            self.silk_enc.variable_hp_smth1_q15()
+        };
+        self.variable_hp_smth2_q15 = smlawb(
+            self.variable_hp_smth2_q15,
+            hp_freq_smth1 - self.variable_hp_smth2_q15,
+            VARIABLE_HP_SMTH_COEF2_Q16,
+        );
+
+        // Convert from log scale to Hertz.
+        let cutoff_hz = log2lin(self.variable_hp_smth2_q15 >> 8);
+
+        let filtered = &mut pcm_buf[total_buffer * channels..];
+        if self.application == Application::Voip {
+            hp_cutoff(
+                &pcm[..frame_size * channels],
+                cutoff_hz,
+                filtered,
+                &mut self.hp_mem,
+                channels,
+                fs as i32,
+            );
+        } else {
+            dc_reject(
+                &pcm[..frame_size * channels],
+                3,
+                filtered,
+                &mut self.hp_mem,
+                channels,
+                fs as i32,
+            );
+        }
+
+        if float_api {
+            let sum = filtered.iter().fold(0_f32, |sum, x| sum + x * x);
+            // This should filter out both NaNs and ridiculous signals that could
+            // cause NaNs further down.
+            if sum >= 1e9 || sum.is_nan() {
+                filtered.iter_mut().for_each(|x| *x = 0.0);
+                self.hp_mem = [0.0; 4];
+            }
+        }
+
+        let mut enc = RangeEncoder::new(&mut data[1..max_data_bytes]);
+
+        // Silk processing.
+        let mut hb_gain = 1.0;
+        if self.mode != CodecMode::CeltOnly {
+            // Distribute bits between Silk and Celt.
+            let total_bitrate = 8 * bytes_target * frame_rate;
+            if self.mode == CodecMode::Hybrid {
+                // Base rate for Silk.
+                self.silk_mode.bitrate = compute_silk_rate_for_hybrid(
+                    total_bitrate,
+                    curr_bandwidth,
+                    fs == 50 * frame_size,
+                    self.use_vbr,
+                    self.silk_mode.lbrr_coded,
+                    self.stream_channels as i32,
+                );
+
+                if self.energy_masking.is_none() {
+                    // Increasingly attenuate high band when it gets allocated fewer bits.
+                    let celt_rate = total_bitrate - self.silk_mode.bitrate;
+                    hb_gain = 1.0 - fast_exp2(-celt_rate as f32 * (1.0 / 1024.0));
+                }
+            } else {
+                // Silk gets all bits.
+                self.silk_mode.bitrate = total_bitrate;
+            }
+
+            // Surround masking for Silk.
+            if let (Some(energy_masking), true, false) =
+                (&self.energy_masking, self.use_vbr, self.lfe)
+            {
+                let (end, srate) = match self.bandwidth {
+                    Bandwidth::Narrowband => (13, 8000),
+                    Bandwidth::Mediumband => (15, 12000),
+                    _ => (17, 16000),
+                };
+                let mut mask_sum = 0.0;
+                (0..channels).for_each(|c| {
+                    (0..end).for_each(|i| {
+                        let mut mask = energy_masking[NB_E_BANDS * c + i].clamp(-2.0, 0.5);
+                        if mask > 0.0 {
+                            mask *= 0.5;
+                        }
+                        mask_sum += mask;
+                    });
+                });
+                // Conservative rate reduction, we cut the masking in half.
+                let mut masking_depth = mask_sum / end as f32 * channels as f32;
+                masking_depth += 0.2;
+                let mut rate_offset = (srate as f32 * masking_depth) as i32;
+                rate_offset = i32::max(rate_offset, -2 * self.silk_mode.bitrate / 3);
+                // Split the rate change between the Silk and Celt part for hybrid.
+                if self.bandwidth == Bandwidth::Superwideband
+                    || self.bandwidth == Bandwidth::Fullband
+                {
+                    self.silk_mode.bitrate += 3 * rate_offset / 5;
+                } else {
+                    self.silk_mode.bitrate += rate_offset;
+                }
+            }
+
+            self.silk_mode.payload_size_ms = 1000 * frame_size / fs;
+            self.silk_mode.channels_api = channels;
+            self.silk_mode.channels_internal = self.stream_channels as usize;
+            self.silk_mode.desired_internal_sampling_rate = match curr_bandwidth {
+                Bandwidth::Narrowband => 8000,
+                Bandwidth::Mediumband => 12000,
+                _ => 16000,
+            };
+            self.silk_mode.min_internal_sampling_rate = if self.mode == CodecMode::Hybrid {
+                // Don't allow bandwidth reduction at lowest bitrates in hybrid mode.
+                16000
+            } else {
+                8000
+            };
+            self.silk_mode.max_internal_sampling_rate = 16000;
+
+            if self.mode == CodecMode::SilkOnly {
+                let mut effective_max_rate = max_rate;
+                if frame_rate > 50 {
+                    effective_max_rate = effective_max_rate * 2 / 3;
+                }
+                if effective_max_rate < 8000 {
+                    self.silk_mode.max_internal_sampling_rate = 12000;
+                    self.silk_mode.desired_internal_sampling_rate =
+                        usize::min(12000, self.silk_mode.desired_internal_sampling_rate);
+                }
+                if effective_max_rate < 7000 {
+                    self.silk_mode.max_internal_sampling_rate = 8000;
+                    self.silk_mode.desired_internal_sampling_rate =
+                        usize::min(8000, self.silk_mode.desired_internal_sampling_rate);
+                }
+            }
+
+            self.silk_mode.use_cbr = !self.use_vbr;
+            self.silk_mode.complexity = self.complexity as usize;
+
+            // Max bits for Silk, counting ToC, redundancy bytes, and optionally redundancy.
+            self.silk_mode.max_bits = (max_data_bytes as i32 - 1) * 8;
+            if redundancy && redundancy_bytes >= 2 {
+                // Counting 1 bit for redundancy position and 20 bits for flag+size (only for hybrid).
+                self.silk_mode.max_bits -= redundancy_bytes as i32 * 8 + 1;
+                if self.mode == CodecMode::Hybrid {
+                    self.silk_mode.max_bits -= 20;
+                }
+            }
+            if self.silk_mode.use_cbr {
+                if self.mode == CodecMode::Hybrid {
+                    self.silk_mode.max_bits = i32::min(
+                        self.silk_mode.max_bits,
+                        self.silk_mode.bitrate * frame_size as i32 / fs as i32,
+                    );
+                }
+            } else if self.mode == CodecMode::Hybrid {
+                // Constrained VBR.
+                // Compute Silk bitrate corresponding to the max total bits available.
+                let max_bitrate = compute_silk_rate_for_hybrid(
+                    self.silk_mode.max_bits * fs as i32 / frame_size as i32,
+                    curr_bandwidth,
+                    fs == 50 * frame_size,
+                    self.use_vbr,
+                    self.silk_mode.lbrr_coded,
+                    self.stream_channels as i32,
+                );
+                self.silk_mode.max_bits = max_bitrate * frame_size as i32 / fs as i32;
+            }
+
+            if prefill != Prefill::None {
+                // Use a smooth onset for the Silk prefill to avoid the encoder trying to encode
+                // a discontinuity. The exact location is what we need to avoid leaving any "gap"
+                // in the audio when mixing with the redundant Celt frame. Here we can afford to
+                // overwrite the delay buffer because the only thing that uses it before it gets
+                // rewritten is the Celt prefill and even then only the part after the ramp really
+                // gets used (rather than sent to the encoder and discarded).
+                let prefill_offset =
+                    channels * (self.encoder_buffer - self.delay_compensation - fs / 400);
+                gain_fade(
+                    &mut self.delay_buffer[prefill_offset..],
+                    0.0,
+                    1.0,
+                    fs / 400,
+                    channels,
+                    fs,
+                );
+                self.delay_buffer[..prefill_offset]
+                    .iter_mut()
+                    .for_each(|x| *x = 0.0);
+
+                let pcm_silk: Vec<i16> =
+                    self.delay_buffer.iter().map(|&x| float2int16(x)).collect();
+
+                // The prefill doesn't produce a payload.
+                let mut dummy = [0_u8; 2];
+                let mut zero = 0;
+                self.silk_enc
+                    .encode(
+                        &mut self.silk_mode,
+                        &pcm_silk,
+                        &mut RangeEncoder::new(&mut dummy),
+                        &mut zero,
+                        prefill,
+                        activity,
+                    )
+                    .map_err(|_| OpusError::InternalError("can't prefill the silk encoder"))?;
+
+                // Prevent a second switch in the real encode call.
+                self.silk_mode.opus_can_switch = false;
+            }
+
+            let pcm_silk: Vec<i16> = pcm_buf[total_buffer * channels..]
+                .iter()
+                .map(|&x| float2int16(x))
+                .collect();
+
+            let mut n_bytes = 0;
+            self.silk_enc
+                .encode(
+                    &mut self.silk_mode,
+                    &pcm_silk,
+                    &mut enc,
+                    &mut n_bytes,
+                    Prefill::None,
+                    activity,
+                )
+                .map_err(|_| OpusError::InternalError("can't encode the silk frame"))?;
+
+            // Extract Silk internal bandwidth for signaling in first byte.
+            if self.mode == CodecMode::SilkOnly {
+                curr_bandwidth = match self.silk_mode.internal_sampling_rate {
+                    8000 => Bandwidth::Narrowband,
+                    12000 => Bandwidth::Mediumband,
+                    _ => Bandwidth::Wideband,
+                };
+            } else {
+                debug_assert_eq!(self.silk_mode.internal_sampling_rate, 16000);
+            }
+
+            self.silk_mode.opus_can_switch = self.silk_mode.switch_ready && !self.nonfinal_frame;
+
+            if n_bytes == 0 {
+                self.final_range = 0;
+                data[0] = gen_toc(self.mode, frame_rate, curr_bandwidth, self.stream_channels);
+                return Ok(1);
+            }
+
+            // FIXME: How do we allocate the redundancy for CBR?
+            if self.silk_mode.opus_can_switch {
+                redundancy_bytes = compute_redundancy_bytes(
+                    max_data_bytes,
+                    self.bitrate_bps,
+                    frame_rate,
+                    self.stream_channels as i32,
+                );
+                redundancy = redundancy_bytes != 0;
+                celt_to_silk = false;
+                self.silk_bw_switch = true;
+            }
+        }
+
+        let endband = match curr_bandwidth {
+            Bandwidth::Narrowband => 13,
+            Bandwidth::Mediumband | Bandwidth::Wideband => 17,
+            Bandwidth::Superwideband => 19,
+            Bandwidth::Fullband => 21,
+        };
+        self.celt_enc.set_end_band(endband);
+        self.celt_enc.set_stream_channels(self.stream_channels);
+        self.celt_enc.set_bitrate(None);
+        if self.mode != CodecMode::SilkOnly {
+            self.celt_enc.set_vbr(false);
+            // We may still decide to disable prediction later.
+            self.celt_enc.set_prediction(2);
+            if self.mode == CodecMode::Hybrid {
+                if self.use_vbr {
+                    self.celt_enc
+                        .set_bitrate(Some(self.bitrate_bps - self.silk_mode.bitrate));
+                    self.celt_enc.set_vbr_constraint(false);
+                }
+            } else if self.use_vbr {
+                self.celt_enc.set_vbr(true);
+                self.celt_enc.set_vbr_constraint(self.vbr_constraint);
+                self.celt_enc.set_bitrate(Some(self.bitrate_bps));
+            }
+        }
+
+        let mut tmp_prefill = vec![0_f32; channels * fs / 400];
+        if self.mode != CodecMode::SilkOnly
+            && self.prev_mode.is_some()
+            && self.prev_mode != Some(self.mode)
+        {
+            let start = (self.encoder_buffer - total_buffer - fs / 400) * channels;
+            tmp_prefill.copy_from_slice(&self.delay_buffer[start..start + channels * fs / 400]);
+        }
+
+        let used = frame_size + total_buffer;
+        if self.encoder_buffer > used {
+            self.delay_buffer.copy_within(
+                channels * frame_size..channels * (self.encoder_buffer - total_buffer),
+                0,
+            );
+            self.delay_buffer[channels * (self.encoder_buffer - used)..]
+                .copy_from_slice(&pcm_buf[..used * channels]);
+        } else {
+            self.delay_buffer
+                .copy_from_slice(&pcm_buf[(used - self.encoder_buffer) * channels..]);
+        }
+
+        // The gain fade and the stereo fade need to be after the buffer copying
+        // because we don't want any of this to affect the Silk part.
+        if self.prev_hb_gain < 1.0 || hb_gain < 1.0 {
+            gain_fade(
+                &mut pcm_buf,
+                self.prev_hb_gain,
+                hb_gain,
+                frame_size,
+                channels,
+                fs,
+            );
+        }
+        self.prev_hb_gain = hb_gain;
+
+        if self.mode != CodecMode::Hybrid || self.stream_channels == Channels::Mono {
+            self.silk_mode.stereo_width_q14 = if equiv_rate > 32000 {
+                16384
+            } else if equiv_rate < 16000 {
+                0
+            } else {
+                16384 - 2048 * (32000 - equiv_rate) / (equiv_rate - 14000)
+            };
+        }
+
+        if self.energy_masking.is_none() && self.channels == Channels::Stereo {
+            // Apply stereo width reduction (at low bitrates).
+            if self.hybrid_stereo_width_q14 < (1 << 14)
+                || self.silk_mode.stereo_width_q14 < (1 << 14)
+            {
+                let g1 = self.hybrid_stereo_width_q14 as f32 * (1.0 / 16384.0);
+                let g2 = self.silk_mode.stereo_width_q14 as f32 * (1.0 / 16384.0);
+                stereo_fade(&mut pcm_buf, g1, g2, frame_size, channels, fs);
+                self.hybrid_stereo_width_q14 = self.silk_mode.stereo_width_q14;
+            }
+        }
+
+        if self.mode != CodecMode::CeltOnly
+            && enc.tell() as usize + 17 + 20 * (self.mode == CodecMode::Hybrid) as usize
+                <= 8 * (max_data_bytes - 1)
+        {
+            // For Silk mode, the redundancy is inferred from the length.
+            if self.mode == CodecMode::Hybrid {
+                enc.encode_bit_logp(redundancy as u32, 12)?;
+            }
+            if redundancy {
+                enc.encode_bit_logp(celt_to_silk as u32, 1)?;
+                let max_redundancy = if self.mode == CodecMode::Hybrid {
+                    // Reserve the 8 bits needed for the redundancy length,
+                    // and at least a few bits for Celt if possible.
+                    (max_data_bytes as i32 - 1) - ((enc.tell() as i32 + 8 + 3 + 7) >> 3)
+                } else {
+                    (max_data_bytes as i32 - 1) - ((enc.tell() as i32 + 7) >> 3)
+                };
+                // Target the same bit-rate for redundancy as for the rest,
+                // up to a max of 257 bytes.
+                let bytes = i32::min(max_redundancy, redundancy_bytes as i32);
+                redundancy_bytes = i32::min(257, i32::max(2, bytes)) as usize;
+                if self.mode == CodecMode::Hybrid {
+                    enc.encode_uint(redundancy_bytes as u32 - 2, 256)?;
+                }
+            }
+        } else {
+            redundancy = false;
+        }
+
+        if !redundancy {
+            self.silk_bw_switch = false;
+            redundancy_bytes = 0;
+        }
+
+        let start_band = if self.mode != CodecMode::CeltOnly {
+            17
+        } else {
+            0
+        };
+
+        let mut ret = 0;
+        let mut nb_compr_bytes = if self.mode == CodecMode::SilkOnly {
+            ret = ((enc.tell() + 7) >> 3) as usize;
+            // A busted budget is caught below and signaled as a PLC frame.
+            let _ = enc.done();
+            ret
+        } else {
+            let nb_compr_bytes = (max_data_bytes - 1) - redundancy_bytes;
+            enc.shrink(nb_compr_bytes);
+            nb_compr_bytes
+        };
+
+        if self.mode == CodecMode::Hybrid {
+            self.celt_enc
+                .set_silk_info(self.silk_mode.signal_type, self.silk_mode.offset);
+        }
+
+        // The redundant frame is written behind the main frame once the range encoder is done.
+        let mut redundancy_data = vec![0_u8; redundancy_bytes];
+        let mut redundancy_offset = nb_compr_bytes;
+
+        // 5 ms redundant frame for Celt->Silk.
+        if redundancy && celt_to_silk {
+            self.celt_enc.set_start_band(0);
+            self.celt_enc.set_vbr(false);
+            self.celt_enc.set_bitrate(None);
+            self.celt_enc
+                .encode(&pcm_buf, fs / 200, &mut redundancy_data)
+                .map_err(|_| OpusError::InternalError("can't encode the redundant frame"))?;
+            redundant_rng = self.celt_enc.final_range();
+            self.celt_enc.reset()?;
+        }
+
+        self.celt_enc.set_start_band(start_band);
+
+        if self.mode != CodecMode::SilkOnly {
+            if self.prev_mode.is_some() && self.prev_mode != Some(self.mode) {
+                let mut dummy = [0_u8; 2];
+                self.celt_enc.reset()?;
+                // Prefilling.
+                self.celt_enc
+                    .encode(&tmp_prefill, fs / 400, &mut dummy)
+                    .map_err(|_| OpusError::InternalError("can't prefill the celt encoder"))?;
+                self.celt_enc.set_prediction(0);
+            }
+
+            // If false, we already busted the budget and we'll end up with a "PLC frame".
+            if enc.tell() as usize <= 8 * nb_compr_bytes {
+                let moves_redundancy =
+                    redundancy && celt_to_silk && self.mode == CodecMode::Hybrid && self.use_vbr;
+
+                // Set the bitrate again if it was overridden in the redundancy code above.
+                if moves_redundancy {
+                    self.celt_enc
+                        .set_bitrate(Some(self.bitrate_bps - self.silk_mode.bitrate));
+                }
+                self.celt_enc.set_vbr(self.use_vbr);
+                ret = self
+                    .celt_enc
+                    .encode_with_ec(&pcm_buf, frame_size, nb_compr_bytes, &mut enc)
+                    .map_err(|_| OpusError::InternalError("can't encode the celt frame"))?;
+
+                // Put Celt->Silk redundancy data in the right place.
+                if moves_redundancy {
+                    redundancy_offset = ret;
+                }
+            }
+        }
+
+        // 5 ms redundant frame for Silk->Celt.
+        if redundancy && !celt_to_silk {
+            let n2 = fs / 200;
+            let n4 = fs / 400;
+            self.celt_enc.reset()?;
+            self.celt_enc.set_start_band(0);
+            self.celt_enc.set_prediction(0);
+            self.celt_enc.set_vbr(false);
+            self.celt_enc.set_bitrate(None);
+
+            if self.mode == CodecMode::Hybrid {
+                // Shrink packet to what the encoder actually used.
+                nb_compr_bytes = ret;
+                enc.shrink(nb_compr_bytes);
+            }
+
+            let mut dummy = [0_u8; 2];
+            self.celt_enc
+                .encode(
+                    &pcm_buf[channels * (frame_size - n2 - n4)..],
+                    n4,
+                    &mut dummy,
+                )
+                .map_err(|_| OpusError::InternalError("can't prefill the celt encoder"))?;
+            self.celt_enc
+                .encode(
+                    &pcm_buf[channels * (frame_size - n2)..],
+                    n2,
+                    &mut redundancy_data,
+                )
+                .map_err(|_| OpusError::InternalError("can't encode the redundant frame"))?;
+            redundant_rng = self.celt_enc.final_range();
+            redundancy_offset = nb_compr_bytes;
+        }
+
+        let tell = enc.tell() as usize;
+        self.final_range = enc.range() ^ redundant_rng;
+
+        if redundancy {
+            data[1 + redundancy_offset..1 + redundancy_offset + redundancy_bytes]
+                .copy_from_slice(&redundancy_data);
+        }
+
+        // Signalling the mode in the first byte.
+        data[0] = gen_toc(self.mode, frame_rate, curr_bandwidth, self.stream_channels);
+
+        self.prev_mode = if to_celt {
+            Some(CodecMode::CeltOnly)
+        } else {
+            Some(self.mode)
+        };
+        self.prev_channels = Some(self.stream_channels);
+        self.first = false;
+
+        // DTX decision.
+        if self.use_dtx && is_silence {
+            if decide_dtx_mode(
+                activity,
+                &mut self.nb_no_activity_ms_q1,
+                (2 * 1000 * frame_size / fs) as i32,
+            ) {
+                self.final_range = 0;
+                data[0] = gen_toc(self.mode, frame_rate, curr_bandwidth, self.stream_channels);
+                return Ok(1);
+            }
+        } else {
+            self.nb_no_activity_ms_q1 = 0;
+        }
+
+        // In the unlikely case that the encoder busted its target, tell
+        // the decoder to call the PLC.
+        if tell > (max_data_bytes - 1) * 8 {
+            if max_data_bytes < 2 {
+                return Err(OpusError::BufferToSmall);
+            }
+            data[1] = 0;
+            ret = 1;
+            self.final_range = 0;
+        } else if self.mode == CodecMode::SilkOnly && !redundancy {
+            // When in LPC only mode it's perfectly reasonable to strip off trailing
+            // zero bytes as the required range decoder behavior is to fill these in.
+            // This can't be done when the MDCT modes are used because the decoder
+            // needs to know the actual length for allocation purposes.
+            while ret > 2 && data[ret] == 0 {
+                ret -= 1;
+            }
+        }
+
+        // Count ToC and redundancy.
+        ret += 1 + redundancy_bytes;
+        if !self.use_vbr {
+            pad_packet(&mut data[..max_data_bytes], ret)
+                .map_err(|_| OpusError::InternalError("can't pad the packet"))?;
+            ret = max_data_bytes;
+        }
+
+        Ok(ret)
+    }
+
+    /// Emits a packet that only signals the decoder to call the PLC,
+    /// since the space is too low to do something useful.
+    fn encode_plc_frame(
+        &mut self,
+        data: &mut [u8],
+        out_data_bytes: usize,
+        max_data_bytes: usize,
+        mut frame_rate: i32,
+    ) -> Result<usize, OpusError> {
+        let mut toc_mode = self.mode;
+        let mut bw = self.bandwidth;
+        let mut packet_code = 0;
+        let mut num_multiframes = 0;
+
+        if frame_rate > 100 {
+            toc_mode = CodecMode::CeltOnly;
+        }
+        // 40 ms -> 2 x 20 ms if in Celt-only or hybrid mode.
+        if frame_rate == 25 && toc_mode != CodecMode::SilkOnly {
+            frame_rate = 50;
+            packet_code = 1;
+        }
+
+        // >= 60 ms frames
+        if frame_rate <= 16 {
+            // 1 x 60 ms, 2 x 40 ms, 2 x 60 ms
+            if out_data_bytes == 1 || (toc_mode == CodecMode::SilkOnly && frame_rate != 10) {
+                toc_mode = CodecMode::SilkOnly;
+
+                packet_code = (frame_rate <= 12) as u8;
+                frame_rate = if frame_rate == 12 { 25 } else { 16 };
+            } else {
+                num_multiframes = 50 / frame_rate;
+                frame_rate = 50;
+                packet_code = 3;
+            }
+        }
+
+        if toc_mode == CodecMode::SilkOnly && bw > Bandwidth::Wideband {
+            bw = Bandwidth::Wideband;
+        } else if toc_mode == CodecMode::CeltOnly && bw == Bandwidth::Mediumband {
+            bw = Bandwidth::Narrowband;
+        } else if toc_mode == CodecMode::Hybrid && bw <= Bandwidth::Superwideband {
+            bw = Bandwidth::Superwideband;
+        }
+
+        data[0] = gen_toc(toc_mode, frame_rate, bw, self.stream_channels);
+        data[0] |= packet_code;
+
+        let ret = if packet_code <= 1 { 1 } else { 2 };
+        let max_data_bytes = usize::max(max_data_bytes, ret);
+
+        if packet_code == 3 {
+            data[1] = num_multiframes as u8;
+        }
+
+        if !self.use_vbr {
+            pad_packet(&mut data[..max_data_bytes], ret)
+                .map_err(|_| OpusError::InternalError("can't pad the packet"))?;
+            return Ok(max_data_bytes);
+        }
+
+        Ok(ret)
+    }
+
+    /// Encodes a packet that contains multiple frames of `frame_size` samples.
+    ///
+    /// Returns the size of the packet.
+    #[allow(clippy::too_many_arguments)]
+    fn encode_multiframe_packet(
+        &mut self,
+        pcm: &[f32],
+        nb_frames: usize,
+        frame_size: usize,
+        data: &mut [u8],
+        to_celt: bool,
+        lsb_depth: i32,
+        float_api: bool,
+    ) -> Result<usize, OpusError> {
+        let channels = self.channels as usize;
+
+        // Worst cases:
+        // 2 frames: Code 2 with different compressed sizes
+        // >2 frames: Code 3 VBR
+        let max_header_bytes = if nb_frames == 2 {
+            3
+        } else {
+            2 + (nb_frames - 1) * 2
+        };
+
+        let repacketize_len = if self.use_vbr || self.user_bitrate == Bitrate::Max {
+            data.len()
+        } else {
+            let cbr_bytes = 3 * self.bitrate_bps as usize
+                / (3 * 8 * self.sampling_rate as usize / (frame_size * nb_frames));
+            usize::min(cbr_bytes, data.len())
+        };
+        if repacketize_len < max_header_bytes {
+            return Err(OpusError::BufferToSmall);
+        }
+        let bytes_per_frame =
+            usize::min(1276, 1 + (repacketize_len - max_header_bytes) / nb_frames);
+
+        let bak_mode = self.force_mode;
+        let bak_bandwidth = self.user_bandwidth;
+        let bak_channels = self.force_channels;
+        let bak_to_mono = self.silk_mode.to_mono;
+
+        self.force_mode = Some(self.mode);
+        self.user_bandwidth = Some(self.bandwidth);
+        self.force_channels = Some(self.stream_channels);
+        if bak_to_mono {
+            self.force_channels = Some(Channels::Mono);
+        } else {
+            self.prev_channels = Some(self.stream_channels);
+        }
+
+        let mut tmp_data = vec![0_u8; nb_frames * bytes_per_frame];
+        let mut tmp_len = vec![0_usize; nb_frames];
+        let result = tmp_data
+            .chunks_exact_mut(bytes_per_frame)
+            .zip(tmp_len.iter_mut())
+            .enumerate()
+            .try_for_each(|(i, (frame, len))| {
+                let offset = i * channels * frame_size;
+                self.silk_mode.to_mono = false;
+                self.nonfinal_frame = i < nb_frames - 1;
+
+                // When switching from Silk/hybrid to Celt, only ask for a switch at the last frame.
+                if to_celt && i == nb_frames - 1 {
+                    self.force_mode = Some(CodecMode::CeltOnly);
+                }
+                *len = self.encode_native(
+                    &pcm[offset..offset + channels * frame_size],
+                    frame_size,
+                    frame,
+                    lsb_depth,
+                    float_api,
+                )?;
+                Ok(())
+            });
+
+        // Discard configs that were forced locally for the purpose of repacketization.
+        self.force_mode = bak_mode;
+        self.user_bandwidth = bak_bandwidth;
+        self.force_channels = bak_channels;
+        self.silk_mode.to_mono = bak_to_mono;
+        self.nonfinal_frame = false;
+
+        result.map_err(|_: OpusError| OpusError::InternalError("can't encode the frames"))?;
+
+        let mut rp = Repacketizer::new();
+        tmp_data
+            .chunks_exact(bytes_per_frame)
+            .zip(tmp_len.iter())
+            .try_for_each(|(frame, len)| rp.cat(&frame[..*len]))
+            .map_err(|_| OpusError::InternalError("can't repacketize the frames"))?;
+
+        rp.out_range_impl(
+            0,
+            nb_frames,
+            &mut data[..repacketize_len],
+            false,
+            !self.use_vbr,
+            &[],
+        )
+        .map_err(|_| OpusError::InternalError("can't repacketize the frames"))
+    }
+
+    /// Automatic (rate-dependent) bandwidth selection.
+    fn decide_bandwidth(&mut self, voice_est: i32, equiv_rate: i32) {
+        let mut bandwidth_thresholds = [0_i32; 8];
+
+        // Interpolate bandwidth thresholds depending on voice estimation.
+        bandwidth_thresholds
+            .iter_mut()
+            .zip(VOICE_BANDWIDTH_THRESHOLDS.iter())
+            .zip(MUSIC_BANDWIDTH_THRESHOLDS.iter())
+            .for_each(|((x, voice), music)| {
+                *x = music + ((voice_est * voice_est * (voice - music)) >> 14);
+            });
+
+        let bandwidth = [
+            Bandwidth::Fullband,
+            Bandwidth::Superwideband,
+            Bandwidth::Wideband,
+            Bandwidth::Mediumband,
+        ]
+        .iter()
+        .copied()
+        .find(|&bandwidth| {
+            let i = 2 * (bandwidth as usize - Bandwidth::Mediumband as usize);
+            let mut threshold = bandwidth_thresholds[i];
+            let hysteresis = bandwidth_thresholds[i + 1];
+            if !self.first {
+                if self.auto_bandwidth >= bandwidth {
+                    threshold -= hysteresis;
+                } else {
+                    threshold += hysteresis;
+                }
+            }
+            equiv_rate >= threshold
+        })
+        .unwrap_or(Bandwidth::Narrowband);
+
+        // We don't use mediumband anymore, except when explicitly requested or during
+        // mode transitions.
+        let bandwidth = if bandwidth == Bandwidth::Mediumband {
+            Bandwidth::Wideband
+        } else {
+            bandwidth
+        };
+
+        self.bandwidth = bandwidth;
+        self.auto_bandwidth = bandwidth;
+    }
+
+    fn user_bitrate_to_bitrate(&self, frame_size: usize, max_data_bytes: usize) -> i32 {
+        let fs = self.sampling_rate as i32;
+        let frame_size = frame_size as i32;
+        match self.user_bitrate {
+            Bitrate::Auto => 60 * fs / frame_size + fs * self.channels as i32,
+            Bitrate::Max => max_data_bytes as i32 * 8 * fs / frame_size,
+            Bitrate::BitsPerSecond(bitrate) => bitrate as i32,
+        }
+    }
+}
+
+/// Computes the equivalent 20 ms rate used for the mode, channel and bandwidth decisions.
+///
+/// `mode` is `None` if the mode is not known yet.
+fn compute_equiv_rate(
+    bitrate: i32,
+    channels: i32,
+    frame_rate: i32,
+    vbr: bool,
+    mode: Option<CodecMode>,
+    complexity: i32,
+    loss: i32,
+) -> i32 {
+    let mut equiv = bitrate;
+
+    // Take into account overhead from smaller frames.
+    if frame_rate > 50 {
+        equiv -= (40 * channels + 20) * (frame_rate - 50);
+    }
+
+    // CBR is about a 8% penalty for both SILK and CELT.
+    if !vbr {
+        equiv -= equiv / 12;
+    }
+
+    // Complexity makes about 10% difference (from 0 to 10) in general.
+    equiv = equiv * (90 + complexity) / 100;
+
+    match mode {
+        Some(CodecMode::SilkOnly) | Some(CodecMode::Hybrid) => {
+            // SILK complexity 0-1 uses the non-delayed-decision NSQ, which
+            // costs about 20%.
+            if complexity < 2 {
+                equiv = equiv * 4 / 5;
+            }
+            equiv -= equiv * loss / (6 * loss + 10);
+        }
+        Some(CodecMode::CeltOnly) => {
+            // CELT complexity 0-4 doesn't have the pitch filter, which costs
+            // about 10%.
+            if complexity < 5 {
+                equiv = equiv * 9 / 10;
+            }
+        }
+        None => {
+            // Half the SILK loss.
+            equiv -= equiv * loss / (12 * loss + 20);
+        }
+    }
+
+    equiv
+}
+
+/// Generates the TOC byte of a packet.
+fn gen_toc(mode: CodecMode, mut frame_rate: i32, bandwidth: Bandwidth, channels: Channels) -> u8 {
+    let mut period = 0;
+    while frame_rate < 400 {
+        frame_rate <<= 1;
+        period += 1;
+    }
+
+    let bandwidth = bandwidth as i32;
+    let mut toc = match mode {
+        CodecMode::SilkOnly => {
+            let mut toc = ((bandwidth - Bandwidth::Narrowband as i32) << 5) as u8;
+            toc |= ((period - 2) << 3) as u8;
+            toc
+        }
+        CodecMode::CeltOnly => {
+            let tmp = i32::max(0, bandwidth - Bandwidth::Mediumband as i32);
+            let mut toc = 0x80;
+            toc |= (tmp << 5) as u8;
+            toc |= (period << 3) as u8;
+            toc
+        }
+        CodecMode::Hybrid => {
+            let mut toc = 0x60;
+            toc |= ((bandwidth - Bandwidth::Superwideband as i32) << 4) as u8;
+            toc |= ((period - 2) << 3) as u8;
+            toc
+        }
+    };
+    toc |= ((channels == Channels::Stereo) as u8) << 2;
+
+    toc
+}
+
+/// Second order high pass filter with a variable cutoff frequency.
+fn hp_cutoff(
+    input: &[f32],
+    cutoff_hz: i32,
+    out: &mut [f32],
+    hp_mem: &mut [f32; 4],
+    channels: usize,
+    fs: i32,
+) {
+    let fc_q19 = smulbb(2471, cutoff_hz) / (fs / 1000);
+    let r_q28 = (1 << 28) - 471 * fc_q19;
+
+    // b = r * [ 1; -2; 1 ];
+    // a = [ 1; -2 * r * ( 1 - 0.5 * Fc^2 ); r^2 ];
+    let b_q28 = [r_q28, -r_q28 << 1, r_q28];
+
+    // -r * ( 2 - Fc * Fc );
+    let r_q22 = r_q28 >> 6;
+    let a_q28 = [
+        smulww(r_q22, smulww(fc_q19, fc_q19) - (2 << 22)),
+        smulww(r_q22, r_q22),
+    ];
+
+    (0..channels).for_each(|c| {
+        biquad(
+            &input[c..],
+            &b_q28,
+            &a_q28,
+            &mut hp_mem[2 * c..2 * c + 2],
+            &mut out[c..],
+            channels,
+        );
+    });
+}
+
+/// Direct form II transposed second order filter.
+fn biquad(
+    input: &[f32],
+    b_q28: &[i32; 3],
+    a_q28: &[i32; 2],
+    s: &mut [f32],
+    out: &mut [f32],
+    stride: usize,
+) {
+    let scale = 1.0 / (1 << 28) as f32;
+    let a = [a_q28[0] as f32 * scale, a_q28[1] as f32 * scale];
+    let b = [
+        b_q28[0] as f32 * scale,
+        b_q28[1] as f32 * scale,
+        b_q28[2] as f32 * scale,
+    ];
+
+    input
+        .iter()
+        .step_by(stride)
+        .zip(out.iter_mut().step_by(stride))
+        .for_each(|(&inval, out)| {
+            let vout = s[0] + b[0] * inval;
+            s[0] = s[1] - vout * a[0] + b[1] * inval;
+            s[1] = -vout * a[1] + b[2] * inval + VERY_SMALL;
+            *out = vout;
+        });
+}
+
+/// First order high pass filter that removes the DC offset.
+fn dc_reject(
+    input: &[f32],
+    cutoff_hz: i32,
+    out: &mut [f32],
+    hp_mem: &mut [f32; 4],
+    channels: usize,
+    fs: i32,
+) {
+    let coef = 6.3 * cutoff_hz as f32 / fs as f32;
+    let coef2 = 1.0 - coef;
+
+    (0..channels).for_each(|c| {
+        let mut m = hp_mem[2 * c];
+        input
+            .iter()
+            .skip(c)
+            .step_by(channels)
+            .zip(out.iter_mut().skip(c).step_by(channels))
+            .for_each(|(&x, out)| {
+                let y = x - m;
+                m = coef * x + VERY_SMALL + coef2 * m;
+                *out = y;
+            });
+        hp_mem[2 * c] = m;
+    });
+}
+
+/// Converts a float sample to a 16 bit integer, rounding to the nearest integer (ties to even).
+#[inline(always)]
+fn float2int16(x: f32) -> i16 {
+    (x * 32768.0).clamp(-32768.0, 32767.0).round_ties_even() as i16
+}
+
+/// Fades the stereo width from `g1` to `g2` over the overlap of the Celt window.
+fn stereo_fade(pcm: &mut [f32], g1: f32, g2: f32, frame_size: usize, channels: usize, fs: usize) {
+    let inc = 48000 / fs;
+    let overlap = OVERLAP / inc;
+    let g1 = 1.0 - g1;
+    let g2 = 1.0 - g2;
+
+    (0..frame_size).for_each(|i| {
+        let g = if i < overlap {
+            let w = WINDOW[i * inc] * WINDOW[i * inc];
+            w * g2 + (1.0 - w) * g1
+        } else {
+            g2
+        };
+        let diff = 0.5 * (pcm[i * channels] - pcm[i * channels + 1]);
+        let diff = g * diff;
+        pcm[i * channels] -= diff;
+        pcm[i * channels + 1] += diff;
+    });
+}
+
+/// Fades the gain from `g1` to `g2` over the overlap of the Celt window.
+fn gain_fade(pcm: &mut [f32], g1: f32, g2: f32, frame_size: usize, channels: usize, fs: usize) {
+    let inc = 48000 / fs;
+    let overlap = OVERLAP / inc;
+
+    (0..frame_size).for_each(|i| {
+        let g = if i < overlap {
+            let w = WINDOW[i * inc] * WINDOW[i * inc];
+            w * g2 + (1.0 - w) * g1
+        } else {
+            g2
+        };
+        pcm[i * channels..(i + 1) * channels]
+            .iter_mut()
+            .for_each(|x| *x *= g);
+    });
+}
+
+/// Estimates the width of a stereo signal. Returns a value between 0 (mono) and 1 (wide).
+fn compute_stereo_width(
+    pcm: &[f32],
+    frame_size: usize,
+    fs: usize,
+    mem: &mut StereoWidthState,
+) -> f32 {
+    let frame_rate = fs / frame_size;
+    let short_alpha = 1.0 - 25.0 / usize::max(50, frame_rate) as f32;
+
+    let mut xx = 0.0;
+    let mut xy = 0.0;
+    let mut yy = 0.0;
+
+    // The frame size is always a multiple of 4 *except* for 2.5 ms frames at 12 kHz.
+    // Since this setting is very rare, we just discard the last two samples.
+    pcm[..2 * (frame_size & !3)].chunks_exact(8).for_each(|x| {
+        let mut pxx = x[0] * x[0];
+        let mut pxy = x[0] * x[1];
+        let mut pyy = x[1] * x[1];
+        (1..4).for_each(|j| {
+            pxx += x[2 * j] * x[2 * j];
+            pxy += x[2 * j] * x[2 * j + 1];
+            pyy += x[2 * j + 1] * x[2 * j + 1];
+        });
+        xx += pxx;
+        xy += pxy;
+        yy += pyy;
+    });
+
+    if xx >= 1e9 || xx.is_nan() || yy >= 1e9 || yy.is_nan() {
+        xx = 0.0;
+        xy = 0.0;
+        yy = 0.0;
+    }
+
+    mem.xx += short_alpha * (xx - mem.xx);
+    mem.xy += short_alpha * (xy - mem.xy);
+    mem.yy += short_alpha * (yy - mem.yy);
+    mem.xx = f32::max(0.0, mem.xx);
+    mem.xy = f32::max(0.0, mem.xy);
+    mem.yy = f32::max(0.0, mem.yy);
+
+    if f32::max(mem.xx, mem.yy) > 8e-4 {
+        let sqrt_xx = mem.xx.sqrt();
+        let sqrt_yy = mem.yy.sqrt();
+        let qrrt_xx = sqrt_xx.sqrt();
+        let qrrt_yy = sqrt_yy.sqrt();
+
+        // Inter-channel correlation.
+        mem.xy = f32::min(mem.xy, sqrt_xx * sqrt_yy);
+        let corr = mem.xy / (EPSILON + sqrt_xx * sqrt_yy);
+        // Approximate loudness difference.
+        let ldiff = (qrrt_xx - qrrt_yy).abs() / (EPSILON + qrrt_xx + qrrt_yy);
+        let width = (1.0 - corr * corr).sqrt() * ldiff;
+        // Smoothing over one second.
+        mem.smoothed_width += (width - mem.smoothed_width) / frame_rate as f32;
+        // Peak follower.
+        mem.max_follower = f32::max(
+            mem.max_follower - 0.02 / frame_rate as f32,
+            mem.smoothed_width,
+        );
+    }
+
+    f32::min(1.0, 20.0 * mem.max_follower)
+}
+
+/// Returns true if the signal is digital silence for the given bit depth.
+fn is_digital_silence(pcm: &[f32], lsb_depth: i32) -> bool {
+    let sample_max = pcm.iter().fold(0.0_f32, |max, x| f32::max(max, x.abs()));
+    sample_max <= 1.0 / (1 << lsb_depth) as f32
+}
+
+/// Decides if DTX should be turned on (true) or off (false) and updates
+/// the number of consecutive milliseconds without activity in Q1.
+fn decide_dtx_mode(
+    activity: Option<bool>,
+    nb_no_activity_ms_q1: &mut i32,
+    frame_size_ms_q1: i32,
+) -> bool {
+    if activity == Some(false) {
+        // The number of consecutive DTX frames should be within the allowed bounds.
+        *nb_no_activity_ms_q1 += frame_size_ms_q1;
+        if *nb_no_activity_ms_q1 > NB_SPEECH_FRAMES_BEFORE_DTX * 20 * 2 {
+            if *nb_no_activity_ms_q1 <= (NB_SPEECH_FRAMES_BEFORE_DTX + MAX_CONSECUTIVE_DTX) * 20 * 2
+            {
+                // Valid frame for DTX.
+                return true;
+            }
+            *nb_no_activity_ms_q1 = NB_SPEECH_FRAMES_BEFORE_DTX * 20 * 2;
+        }
+    } else {
+        *nb_no_activity_ms_q1 = 0;
+    }
+
+    false
+}
+
+/// Decides if the Silk layer should embed LBRR data for in-band FEC.
+///
+/// If the loss is above 5 %, the bandwidth is lowered until the rate is high enough for FEC.
+fn decide_fec(
+    use_in_band_fec: bool,
+    packet_loss_perc: i32,
+    last_fec: bool,
+    mode: CodecMode,
+    bandwidth: &mut Bandwidth,
+    rate: i32,
+) -> bool {
+    const BANDWIDTHS: &[Bandwidth; 5] = &[
+        Bandwidth::Narrowband,
+        Bandwidth::Mediumband,
+        Bandwidth::Wideband,
+        Bandwidth::Superwideband,
+        Bandwidth::Fullband,
+    ];
+
+    if !use_in_band_fec || packet_loss_perc == 0 || mode == CodecMode::CeltOnly {
+        return false;
+    }
+
+    let orig_bandwidth = *bandwidth;
+    loop {
+        // Compute threshold for using FEC at the current bandwidth setting.
+        let i = 2 * *bandwidth as usize;
+        let mut lbrr_rate_thres_bps = FEC_THRESHOLDS[i];
+        let hysteresis = FEC_THRESHOLDS[i + 1];
+        if last_fec {
+            lbrr_rate_thres_bps -= hysteresis;
+        } else {
+            lbrr_rate_thres_bps += hysteresis;
+        }
+        lbrr_rate_thres_bps = smulwb(
+            lbrr_rate_thres_bps * (125 - i32::min(packet_loss_perc, 25)),
+            655,
+        );
+
+        // If loss <= 5%, we look at whether we have enough rate to enable FEC.
+        // If loss > 5%, we decrease the bandwidth until we can enable FEC.
+        if rate > lbrr_rate_thres_bps {
+            return true;
+        } else if packet_loss_perc <= 5 {
+            return false;
+        } else if *bandwidth > Bandwidth::Narrowband {
+            *bandwidth = BANDWIDTHS[*bandwidth as usize - 1];
+        } else {
+            break;
+        }
+    }
+
+    // Couldn't find any bandwidth to enable FEC, keep original bandwidth.
+    *bandwidth = orig_bandwidth;
+    false
+}
+
+/// Computes the part of the bitrate of a hybrid frame that is given to the Silk layer.
+fn compute_silk_rate_for_hybrid(
+    rate: i32,
+    bandwidth: Bandwidth,
+    frame_20ms: bool,
+    vbr: bool,
+    fec: bool,
+    channels: i32,
+) -> i32 {
+    // Columns: total rate, Silk rate for 10 ms and 20 ms without FEC
+    // and Silk rate for 10 ms and 20 ms with FEC.
+    const RATE_TABLE: &[[i32; 5]; 7] = &[
+        [0, 0, 0, 0, 0],
+        [12000, 10000, 10000, 11000, 11000],
+        [16000, 13500, 13500, 15000, 15000],
+        [20000, 16000, 16000, 18000, 18000],
+        [24000, 18000, 18000, 21000, 21000],
+        [32000, 22000, 22000, 28000, 28000],
+        [64000, 38000, 38000, 50000, 50000],
+    ];
+
+    // Do the allocation per-channel.
+    let rate = rate / channels;
+    let entry = 1 + frame_20ms as usize + 2 * fec as usize;
+
+    let mut silk_rate = match RATE_TABLE.iter().position(|row| row[0] > rate) {
+        Some(i) => {
+            let lo = RATE_TABLE[i - 1][entry];
+            let hi = RATE_TABLE[i][entry];
+            let x0 = RATE_TABLE[i - 1][0];
+            let x1 = RATE_TABLE[i][0];
+            (lo * (x1 - rate) + hi * (rate - x0)) / (x1 - x0)
+        }
+        None => {
+            let last = &RATE_TABLE[RATE_TABLE.len() - 1];
+            // For now, just give 50% of the extra bits to Silk.
+            last[entry] + (rate - last[0]) / 2
+        }
+    };
+
+    if !vbr {
+        // Tiny boost to Silk for CBR.
+        silk_rate += 100;
+    }
+    if bandwidth == Bandwidth::Superwideband {
+        silk_rate += 300;
+    }
+    silk_rate *= channels;
+
+    // Small adjustment for stereo (calibrated for 32 kb/s).
+    if channels == 2 && rate >= 12000 {
+        silk_rate -= 1000;
+    }
+
+    silk_rate
+}
+
+/// Computes the size of the redundant Celt frame used when switching modes.
+///
+/// Returns 0 if it's not worth to send a redundant frame.
+fn compute_redundancy_bytes(
+    max_data_bytes: usize,
+    bitrate_bps: i32,
+    frame_rate: i32,
+    channels: i32,
+) -> usize {
+    let base_bits = 40 * channels + 20;
+
+    // Equivalent rate for 5 ms frames.
+    let redundancy_rate = bitrate_bps + base_bits * (200 - frame_rate);
+    // For VBR, further increase the bitrate if we can afford it.
+    // It's pretty short and we'll avoid artefacts.
+    let redundancy_rate = 3 * redundancy_rate / 2;
+    let redundancy_bytes = redundancy_rate / 1600;
+
+    // Compute the max rate we can use given CBR or VBR with cap.
+    let available_bits = max_data_bytes as i32 * 8 - 2 * base_bits;
+    let redundancy_bytes_cap = (available_bits * 240 / (240 + 48000 / frame_rate) + base_bits) / 8;
+    let redundancy_bytes = i32::min(redundancy_bytes, redundancy_bytes_cap);
+
+    // If we can't get enough bits for redundancy to be worth it, rely on the decoder PLC.
+    if redundancy_bytes > 4 + 8 * channels {
+        i32::min(257, redundancy_bytes) as usize
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+    use std::convert::TryFrom;
+    use std::num::NonZeroUsize;
+
+    use super::*;
+    use crate::{parse_packet, query_packet_codec_mode, Decoder, DecoderConfiguration, Sample};
+
+    fn generate_signal(sampling_rate: SamplingRate, channels: Channels, length: usize) -> Vec<f32> {
+        let fs = sampling_rate as usize as f32;
+        let channels = channels as usize;
+        (0..length * channels)
+            .map(|i| {
+                let t = (i / channels) as f32 / fs;
+                let f = 440.0 * (1 + i % channels) as f32;
+                0.5 * f32::sin(2.0 * std::f32::consts::PI * f * t)
+            })
+            .collect()
+    }
+
+    fn roundtrip(configuration: &EncoderConfiguration, frames: usize, mode: CodecMode) {
+        let mut encoder = Encoder::new(configuration).unwrap();
+        let mut decoder = Decoder::new(&DecoderConfiguration {
+            sampling_rate: configuration.sampling_rate,
+            channels: configuration.channels,
+            gain: 0,
+        })
+        .unwrap();
+
+        let frame_size = encoder.frame_size();
+        let channels = configuration.channels as usize;
+        let signal = generate_signal(
+            configuration.sampling_rate,
+            configuration.channels,
+            frames * frame_size,
+        );
+        let mut packet = [0_u8; 1500];
+        let mut output = vec![0_f32; frame_size * channels];
+
+        signal
+            .chunks_exact(frame_size * channels)
+            .for_each(|frame| {
+                let len = encoder.encode_float(frame, &mut packet).unwrap();
+                let packet = &packet[..len];
+                assert_eq!(query_packet_codec_mode(packet).unwrap(), mode);
+
+                let mut sizes = [0_usize; 48];
+                parse_packet(packet, false, None, &mut sizes, None, None).unwrap();
+
+                let samples = decoder
+                    .decode_float(
+                        Some(packet),
+                        &mut output,
+                        NonZeroUsize::new(frame_size).unwrap(),
+                        false,
+                    )
+                    .unwrap();
+                assert_eq!(samples, frame_size);
+                assert_eq!(encoder.final_range(), decoder.final_range());
+            });
+    }
+
+    #[test]
+    fn test_roundtrip_frame_durations() {
+        [
+            FrameDuration::Ms2_5,
+            FrameDuration::Ms5,
+            FrameDuration::Ms10,
+            FrameDuration::Ms20,
+            FrameDuration::Ms40,
+            FrameDuration::Ms60,
+            FrameDuration::Ms120,
+        ]
+        .iter()
+        .for_each(|&frame_duration| {
+            roundtrip(
+                &EncoderConfiguration {
+                    frame_duration,
+                    ..Default::default()
+                },
+                10,
+                CodecMode::CeltOnly,
+            );
+        });
+    }
+
+    #[test]
+    fn test_roundtrip_configurations() {
+        [
+            SamplingRate::Hz8000,
+            SamplingRate::Hz16000,
+            SamplingRate::Hz48000,
+        ]
+        .iter()
+        .for_each(|&sampling_rate| {
+            [Channels::Mono, Channels::Stereo]
+                .iter()
+                .for_each(|&channels| {
+                    [0, 5, 10].iter().for_each(|&complexity| {
+                        roundtrip(
+                            &EncoderConfiguration {
+                                sampling_rate,
+                                channels,
+                                complexity,
+                                application: Application::Voip,
+                                bitrate: Bitrate::BitsPerSecond(24000),
+                                force_mode: Some(CodecMode::CeltOnly),
+                                ..Default::default()
+                            },
+                            10,
+                            CodecMode::CeltOnly,
+                        );
+                    })
+                })
+        });
+    }
+
+    #[test]
+    fn test_roundtrip_silk() {
+        [
+            Bandwidth::Narrowband,
+            Bandwidth::Mediumband,
+            Bandwidth::Wideband,
+        ]
+        .iter()
+        .for_each(|&bandwidth| {
+            [Channels::Mono, Channels::Stereo]
+                .iter()
+                .for_each(|&channels| {
+                    [
+                        FrameDuration::Ms10,
+                        FrameDuration::Ms20,
+                        FrameDuration::Ms40,
+                        FrameDuration::Ms60,
+                        FrameDuration::Ms120,
+                    ]
+                    .iter()
+                    .for_each(|&frame_duration| {
+                        roundtrip(
+                            &EncoderConfiguration {
+                                channels,
+                                frame_duration,
+                                application: Application::Voip,
+                                bitrate: Bitrate::BitsPerSecond(16000),
+                                force_mode: Some(CodecMode::SilkOnly),
+                                bandwidth: Some(bandwidth),
+                                ..Default::default()
+                            },
+                            10,
+                            CodecMode::SilkOnly,
+                        );
+                    })
+                })
+        });
+    }
+
+    #[test]
+    fn test_roundtrip_silk_complexity() {
+        [0, 2, 4, 6, 8, 10].iter().for_each(|&complexity| {
+            [false, true].iter().for_each(|&vbr| {
+                roundtrip(
+                    &EncoderConfiguration {
+                        sampling_rate: SamplingRate::Hz16000,
+                        channels: Channels::Mono,
+                        complexity,
+                        vbr,
+                        application: Application::Voip,
+                        bitrate: Bitrate::BitsPerSecond(24000),
+                        force_mode: Some(CodecMode::SilkOnly),
+                        ..Default::default()
+                    },
+                    10,
+                    CodecMode::SilkOnly,
+                );
+            })
+        });
+    }
+
+    #[test]
+    fn test_silk_full_scale_high_bitrate() {
+        // The noise shaping states of the delayed decision quantizer wrap around
+        // for full scale input at high bitrates.
+        [
+            (
+                700.0,
+                EncoderConfiguration {
+                    sampling_rate: SamplingRate::Hz48000,
+                    channels: Channels::Mono,
+                    application: Application::Voip,
+                    bitrate: Bitrate::BitsPerSecond(219724),
+                    complexity: 10,
+                    frame_duration: FrameDuration::Ms120,
+                    vbr: false,
+                    force_mode: Some(CodecMode::SilkOnly),
+                    bandwidth: Some(Bandwidth::Narrowband),
+                    ..Default::default()
+                },
+            ),
+            (
+                1300.0,
+                EncoderConfiguration {
+                    sampling_rate: SamplingRate::Hz8000,
+                    channels: Channels::Mono,
+                    application: Application::Audio,
+                    bitrate: Bitrate::BitsPerSecond(266255),
+                    vbr: false,
+                    force_mode: Some(CodecMode::SilkOnly),
+                    bandwidth: Some(Bandwidth::Mediumband),
+                    inband_fec: true,
+                    dtx: true,
+                    ..Default::default()
+                },
+            ),
+        ]
+        .iter()
+        .for_each(|(frequency, configuration)| {
+            let mut encoder = Encoder::new(configuration).unwrap();
+            let mut decoder = Decoder::new(&DecoderConfiguration {
+                sampling_rate: configuration.sampling_rate,
+                channels: configuration.channels,
+                gain: 0,
+            })
+            .unwrap();
+
+            let frame_size = encoder.frame_size();
+            let frame_size_nz = NonZeroUsize::new(frame_size).unwrap();
+            let fs = configuration.sampling_rate as usize;
+            let signal: Vec<f32> = (0..3 * fs)
+                .map(|i| f32::sin(2.0 * std::f32::consts::PI * frequency * i as f32 / fs as f32))
+                .collect();
+
+            let mut packet = [0_u8; 1500];
+            let mut output = vec![0_f32; frame_size];
+            signal.chunks_exact(frame_size).for_each(|frame| {
+                let len = encoder.encode_float(frame, &mut packet).unwrap();
+                let packet = &packet[..len];
+                assert_eq!(
+                    query_packet_codec_mode(packet).unwrap(),
+                    CodecMode::SilkOnly
+                );
+                decoder
+                    .decode_float(Some(packet), &mut output, frame_size_nz, false)
+                    .unwrap();
+                assert_eq!(encoder.final_range(), decoder.final_range());
+            });
+        });
+    }
+
+    #[test]
+    fn test_roundtrip_hybrid() {
+        [Bandwidth::Superwideband, Bandwidth::Fullband]
+            .iter()
+            .for_each(|&bandwidth| {
+                [Channels::Mono, Channels::Stereo]
+                    .iter()
+                    .for_each(|&channels| {
+                        [
+                            FrameDuration::Ms10,
+                            FrameDuration::Ms20,
+                            FrameDuration::Ms40,
+                        ]
+                        .iter()
+                        .for_each(|&frame_duration| {
+                            [false, true].iter().for_each(|&vbr| {
+                                roundtrip(
+                                    &EncoderConfiguration {
+                                        channels,
+                                        frame_duration,
+                                        vbr,
+                                        application: Application::Voip,
+                                        bitrate: Bitrate::BitsPerSecond(32000),
+                                        force_mode: Some(CodecMode::Hybrid),
+                                        bandwidth: Some(bandwidth),
+                                        ..Default::default()
+                                    },
+                                    10,
+                                    CodecMode::Hybrid,
+                                );
+                            })
+                        })
+                    })
+            });
+    }
+
+    #[test]
+    fn test_packet_loss_concealment() {
+        [
+            (CodecMode::CeltOnly, Bitrate::BitsPerSecond(64000)),
+            (CodecMode::SilkOnly, Bitrate::BitsPerSecond(16000)),
+            (CodecMode::Hybrid, Bitrate::BitsPerSecond(32000)),
+        ]
+        .iter()
+        .for_each(|&(mode, bitrate)| {
+            [Channels::Mono, Channels::Stereo]
+                .iter()
+                .for_each(|&channels| {
+                    let configuration = EncoderConfiguration {
+                        channels,
+                        application: Application::Voip,
+                        bitrate,
+                        force_mode: Some(mode),
+                        ..Default::default()
+                    };
+                    let mut encoder = Encoder::new(&configuration).unwrap();
+                    let mut decoder = Decoder::new(&DecoderConfiguration {
+                        sampling_rate: configuration.sampling_rate,
+                        channels,
+                        gain: 0,
+                    })
+                    .unwrap();
+
+                    let frame_size = encoder.frame_size();
+                    let channels = channels as usize;
+                    let signal = generate_signal(
+                        configuration.sampling_rate,
+                        configuration.channels,
+                        20 * frame_size,
+                    );
+                    let mut packet = [0_u8; 1500];
+                    let mut output = vec![0_f32; frame_size * channels];
+                    let mut energies = vec![];
+
+                    signal
+                        .chunks_exact(frame_size * channels)
+                        .enumerate()
+                        .for_each(|(i, frame)| {
+                            let len = encoder.encode_float(frame, &mut packet).unwrap();
+                            // Lose four packets in a row.
+                            let packet = if (10..14).contains(&i) {
+                                None
+                            } else {
+                                Some(&packet[..len])
+                            };
+
+                            let samples = decoder
+                                .decode_float(
+                                    packet,
+                                    &mut output,
+                                    NonZeroUsize::new(frame_size).unwrap(),
+                                    false,
+                                )
+                                .unwrap();
+                            assert_eq!(samples, frame_size);
+                            assert!(output.iter().all(|x| x.is_finite()));
+                            energies.push(output.iter().fold(0.0, |acc, x| acc + (x * x)));
+                        });
+
+                    // The concealment extrapolates the signal and fades it out.
+                    assert!(energies[10] > 0.1 * energies[9]);
+                    assert!(energies[13] < energies[10]);
+                    assert!(energies[19] > 0.1 * energies[9]);
+                })
+        });
+    }
+
+    #[test]
+    fn test_inband_fec() {
+        [
+            (CodecMode::SilkOnly, Bitrate::BitsPerSecond(24000)),
+            (CodecMode::Hybrid, Bitrate::BitsPerSecond(48000)),
+        ]
+        .iter()
+        .for_each(|&(mode, bitrate)| {
+            [Channels::Mono, Channels::Stereo]
+                .iter()
+                .for_each(|&channels| {
+                    let configuration = EncoderConfiguration {
+                        channels,
+                        application: Application::Voip,
+                        bitrate,
+                        force_mode: Some(mode),
+                        inband_fec: true,
+                        packet_loss_percentage: 10,
+                        ..Default::default()
+                    };
+                    let mut encoder = Encoder::new(&configuration).unwrap();
+                    assert!(encoder.inband_fec());
+                    assert_eq!(encoder.packet_loss_percentage(), 10);
+
+                    let decoder_configuration = DecoderConfiguration {
+                        sampling_rate: configuration.sampling_rate,
+                        channels,
+                        gain: 0,
+                    };
+                    let mut reference_decoder = Decoder::new(&decoder_configuration).unwrap();
+                    let mut fec_decoder = Decoder::new(&decoder_configuration).unwrap();
+                    let mut plc_decoder = Decoder::new(&decoder_configuration).unwrap();
+
+                    let frame_size = encoder.frame_size();
+                    let frame_size_nz = NonZeroUsize::new(frame_size).unwrap();
+                    let channels = channels as usize;
+                    let signal = generate_signal(
+                        configuration.sampling_rate,
+                        configuration.channels,
+                        20 * frame_size,
+                    );
+                    let packets: Vec<Vec<u8>> = signal
+                        .chunks_exact(frame_size * channels)
+                        .map(|frame| {
+                            let mut packet = [0_u8; 1500];
+                            let len = encoder.encode_float(frame, &mut packet).unwrap();
+                            packet[..len].to_vec()
+                        })
+                        .collect();
+
+                    let mut reference = vec![0_f32; frame_size * channels];
+                    let mut fec = vec![0_f32; frame_size * channels];
+                    let mut plc = vec![0_f32; frame_size * channels];
+                    let mut fec_error = 0.0;
+                    let mut plc_error = 0.0;
+                    packets.iter().enumerate().for_each(|(i, packet)| {
+                        reference_decoder
+                            .decode_float(Some(packet), &mut reference, frame_size_nz, false)
+                            .unwrap();
+
+                        // Recover the lost packet from the LBRR data of the next packet.
+                        let (fec_packet, plc_packet) = if i == 10 {
+                            (Some(packets[11].as_slice()), None)
+                        } else {
+                            (Some(packet.as_slice()), Some(packet.as_slice()))
+                        };
+                        let samples = fec_decoder
+                            .decode_float(fec_packet, &mut fec, frame_size_nz, i == 10)
+                            .unwrap();
+                        assert_eq!(samples, frame_size);
+                        plc_decoder
+                            .decode_float(plc_packet, &mut plc, frame_size_nz, false)
+                            .unwrap();
+
+                        if i == 10 {
+                            fec_error = reference
+                                .iter()
+                                .zip(fec.iter())
+                                .fold(0.0, |acc, (r, x)| acc + (r - x) * (r - x));
+                            plc_error = reference
+                                .iter()
+                                .zip(plc.iter())
+                                .fold(0.0, |acc, (r, x)| acc + (r - x) * (r - x));
+                        }
+                    });
+
+                    // The redundant data reconstructs the lost frame better than the concealment.
+                    assert!(fec_error < plc_error);
+                })
+        });
+    }
+
+    #[test]
+    fn test_dtx() {
+        // The generalized DTX is used for complexities of 7 and higher, Silk's DTX otherwise.
+        [3, 9].iter().for_each(|&complexity| {
+            let configuration = EncoderConfiguration {
+                channels: Channels::Mono,
+                application: Application::Voip,
+                bitrate: Bitrate::BitsPerSecond(24000),
+                complexity,
+                dtx: true,
+                ..Default::default()
+            };
+            let mut encoder = Encoder::new(&configuration).unwrap();
+            assert!(encoder.dtx());
+            let mut decoder = Decoder::new(&DecoderConfiguration {
+                sampling_rate: configuration.sampling_rate,
+                channels: configuration.channels,
+                gain: 0,
+            })
+            .unwrap();
+
+            let frame_size = encoder.frame_size();
+            let frame_size_nz = NonZeroUsize::new(frame_size).unwrap();
+            let mut signal = generate_signal(
+                configuration.sampling_rate,
+                configuration.channels,
+                10 * frame_size,
+            );
+            signal.resize(40 * frame_size, 0.0);
+
+            let mut output = vec![0_f32; frame_size];
+            let lengths: Vec<usize> = signal
+                .chunks_exact(frame_size)
+                .map(|frame| {
+                    let mut packet = [0_u8; 1500];
+                    let len = encoder.encode_float(frame, &mut packet).unwrap();
+                    decoder
+                        .decode_float(Some(&packet[..len]), &mut output, frame_size_nz, false)
+                        .unwrap();
+                    assert_eq!(decoder.in_dtx(), len == 1);
+                    assert!(output.iter().all(|x| x.is_finite()));
+                    len
+                })
+                .collect();
+
+            assert!(lengths[..10].iter().all(|&len| len > 1));
+            // DTX starts after 200 ms of silence.
+            assert!(lengths[25..].iter().all(|&len| len == 1));
+            assert!(encoder.in_dtx());
+        });
+    }
+
+    #[test]
+    fn test_automatic_mode_decision() {
+        let configuration = EncoderConfiguration {
+            channels: Channels::Mono,
+            application: Application::Voip,
+            bitrate: Bitrate::BitsPerSecond(12000),
+            ..Default::default()
+        };
+        let mut encoder = Encoder::new(&configuration).unwrap();
+        let mut decoder = Decoder::new(&DecoderConfiguration {
+            sampling_rate: SamplingRate::Hz48000,
+            channels: Channels::Mono,
+            gain: 0,
+        })
+        .unwrap();
+
+        let signal = generate_signal(SamplingRate::Hz48000, Channels::Mono, 960 * 30);
+        let mut packet = [0_u8; 1500];
+        let mut output = [0_f32; 960];
+        let mut modes = Vec::new();
+
+        signal.chunks_exact(960).enumerate().for_each(|(i, frame)| {
+            // Switch between a low and a high bitrate to force mode transitions.
+            let bitrate = if (i / 10) % 2 == 0 { 12000 } else { 96000 };
+            encoder.inner.user_bitrate = Bitrate::BitsPerSecond(bitrate);
+
+            let len = encoder.encode_float(frame, &mut packet).unwrap();
+            let packet = &packet[..len];
+            modes.push(query_packet_codec_mode(packet).unwrap());
+
+            decoder
+                .decode_float(
+                    Some(packet),
+                    &mut output,
+                    NonZeroUsize::new(960).unwrap(),
+                    false,
+                )
+                .unwrap();
+            assert_eq!(encoder.final_range(), decoder.final_range());
+        });
+
+        assert_eq!(modes[0], CodecMode::SilkOnly);
+        assert!(modes.contains(&CodecMode::CeltOnly));
+        assert_ne!(modes[29], CodecMode::CeltOnly);
+    }
+
+    #[test]
+    fn test_cbr_packet_size() {
+        let configuration = EncoderConfiguration {
+            bitrate: Bitrate::BitsPerSecond(64000),
+            vbr: false,
+            ..Default::default()
+        };
+        let mut encoder = Encoder::new(&configuration).unwrap();
+        let signal = generate_signal(SamplingRate::Hz48000, Channels::Stereo, 960 * 5);
+        let mut packet = [0_u8; 1500];
+
+        signal.chunks_exact(960 * 2).for_each(|frame| {
+            let len = encoder.encode_float(frame, &mut packet).unwrap();
+            // 64 kb/s at 20 ms frames.
+            assert_eq!(len, 160);
+        });
+    }
+
+    #[test]
+    fn test_encode_i16() {
+        let mut encoder = Encoder::new(&EncoderConfiguration {
+            channels: Channels::Mono,
+            ..Default::default()
+        })
+        .unwrap();
+        let signal: Vec<i16> = generate_signal(SamplingRate::Hz48000, Channels::Mono, 960)
+            .iter()
+            .map(|&x| i16::from_f32(x))
+            .collect();
+        let mut packet = [0_u8; 1500];
+
+        let len = encoder.encode(&signal, &mut packet).unwrap();
+        assert!(len > 1);
+        assert!(encoder.encode(&signal[..480], &mut packet).is_err());
+    }
+
+    #[test]
+    fn test_invalid_configuration() {
+        assert!(Encoder::new(&EncoderConfiguration {
+            complexity: 11,
+            ..Default::default()
+        })
+        .is_err());
+        assert!(Encoder::new(&EncoderConfiguration {
+            bitrate: Bitrate::BitsPerSecond(0),
+            ..Default::default()
+        })
+        .is_err());
+        assert!(Encoder::new(&EncoderConfiguration {
+            packet_loss_percentage: 101,
+            ..Default::default()
+        })
+        .is_err());
+        assert!(SamplingRate::try_from(44100).is_err());
+    }
+
+    #[test]
+    fn test_lookahead() {
+        let encoder = Encoder::new(&EncoderConfiguration::default()).unwrap();
+        assert_eq!(encoder.lookahead(), 312);
+        let encoder = Encoder::new(&EncoderConfiguration {
+            sampling_rate: SamplingRate::Hz16000,
+            application: Application::RestrictedLowDelay,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(encoder.lookahead(), 40);
+    }
+
+    #[test]
+    fn test_gen_toc() {
+        assert_eq!(
+            gen_toc(
+                CodecMode::CeltOnly,
+                50,
+                Bandwidth::Fullband,
+                Channels::Stereo
+            ),
+            0xFC
+        );
+        assert_eq!(
+            gen_toc(
+                CodecMode::CeltOnly,
+                400,
+                Bandwidth::Narrowband,
+                Channels::Mono
+            ),
+            0x80
+        );
+        assert_eq!(
+            gen_toc(CodecMode::SilkOnly, 50, Bandwidth::Wideband, Channels::Mono),
+            0x48
+        );
+        assert_eq!(
+            gen_toc(
+                CodecMode::Hybrid,
+                100,
+                Bandwidth::Superwideband,
+                Channels::Mono
+            ),
+            0x60
+        );
+    }
+}
@@ -13,6 +13,10 @@ pub enum OpusError {
     BufferToSmall,
     /// An internal error.
     InternalError(&'static str),
+    /// The container is malformed.
+    InvalidContainer(&'static str),
+    /// An I/O error of the underlying reader or writer.
+    Io(std::io::Error),
 }
 
 impl std::fmt::Display for OpusError {
@@ -33,12 +37,21 @@ impl std::fmt::Display for OpusError {
             OpusError::InvalidPacket => {
                 write!(f, "invalid packet")
             }
+            OpusError::InvalidContainer(message) => {
+                write!(f, "invalid container: {}", message)
+            }
+            OpusError::Io(err) => {
+                write!(f, "{}", err)
+            }
         }
     }
 }
 
 impl std::error::Error for OpusError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        None
+        match self {
+            OpusError::Io(err) => Some(err),
+            _ => None,
+        }
     }
 }
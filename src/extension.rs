@@ -0,0 +1,323 @@
+//! Implements the extensions that are stored inside the padding of Opus packets.
+//!
+//! Each extension starts with a byte that contains the ID in the upper 7 bits and
+//! a length flag `L` in the lowest bit. IDs 0 and 1 are reserved for padding and
+//! frame separators. Short extensions (IDs 2-31) carry `L` bytes of data, long
+//! extensions (IDs 32-127) carry a coded length if `L` is set, or extend to the
+//! end of the padding otherwise.
+
+use crate::OpusError;
+
+/// An extension inside the padding of an Opus packet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Extension<'a> {
+    /// ID of the extension (2-127).
+    pub id: u8,
+    /// Index of the frame inside the packet the extension belongs to.
+    pub frame: usize,
+    /// Payload of the extension. Extensions with an ID below 32 carry at most 1 byte.
+    pub data: &'a [u8],
+}
+
+/// Iterator over the extensions inside the padding of an Opus packet.
+///
+/// Returns an error and stops if the padding is malformed or if a frame separator
+/// points past the last frame of the packet.
+#[derive(Clone, Debug)]
+pub struct Extensions<'a> {
+    data: &'a [u8],
+    frame: usize,
+    frame_count: usize,
+}
+
+impl<'a> Extensions<'a> {
+    /// Creates an iterator over the extensions of the given padding of a packet
+    /// with `frame_count` frames.
+    pub(crate) fn new(data: &'a [u8], frame_count: usize) -> Self {
+        Self {
+            data,
+            frame: 0,
+            frame_count,
+        }
+    }
+
+    fn next_extension(&mut self) -> Result<Option<Extension<'a>>, OpusError> {
+        while !self.data.is_empty() {
+            let id = self.data[0] >> 1;
+            let l = usize::from(self.data[0] & 0x1);
+
+            if id == 0 {
+                // Padding. L=0 marks the rest of the data as padding.
+                self.data = if l == 1 { &self.data[1..] } else { &[] };
+            } else if id < 32 {
+                if self.data.len() < 1 + l {
+                    return Err(OpusError::InvalidPacket);
+                }
+                let data = &self.data[1..1 + l];
+                self.data = &self.data[1 + l..];
+
+                if id == 1 {
+                    // Frame separator. L=1 carries the frame increment.
+                    self.frame += if l == 1 { usize::from(data[0]) } else { 1 };
+                    if self.frame >= self.frame_count {
+                        return Err(OpusError::InvalidPacket);
+                    }
+                } else {
+                    return Ok(Some(Extension {
+                        id,
+                        frame: self.frame,
+                        data,
+                    }));
+                }
+            } else if l == 0 {
+                // The last long extension extends to the end of the padding.
+                let data = &self.data[1..];
+                self.data = &[];
+                return Ok(Some(Extension {
+                    id,
+                    frame: self.frame,
+                    data,
+                }));
+            } else {
+                let mut offset = 1;
+                let mut size = 0;
+                loop {
+                    let byte = *self.data.get(offset).ok_or(OpusError::InvalidPacket)?;
+                    offset += 1;
+                    size += usize::from(byte);
+                    if byte != 255 {
+                        break;
+                    }
+                }
+                if offset + size > self.data.len() {
+                    return Err(OpusError::InvalidPacket);
+                }
+                let data = &self.data[offset..offset + size];
+                self.data = &self.data[offset + size..];
+                return Ok(Some(Extension {
+                    id,
+                    frame: self.frame,
+                    data,
+                }));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl<'a> Iterator for Extensions<'a> {
+    type Item = Result<Extension<'a>, OpusError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_extension() {
+            Ok(extension) => extension.map(Ok),
+            Err(err) => {
+                self.data = &[];
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// Writes the extensions ordered by their frame.
+///
+/// Returns the number of bytes needed. If `data` is `None`, only the size is calculated.
+pub(crate) fn write_extensions(
+    mut data: Option<&mut [u8]>,
+    extensions: &[Extension],
+) -> Result<usize, OpusError> {
+    let max_len = data.as_ref().map_or(usize::MAX, |data| data.len());
+
+    if extensions.iter().any(|e| e.id < 2 || e.id > 127) {
+        return Err(OpusError::BadArguments(
+            "extension ID must be between 2 and 127",
+        ));
+    }
+    if extensions.iter().any(|e| e.id < 32 && e.data.len() > 1) {
+        return Err(OpusError::BadArguments(
+            "extensions with an ID below 32 can carry at most 1 byte",
+        ));
+    }
+    let max_frame = extensions.iter().map(|e| e.frame).max().unwrap_or(0);
+    if max_frame >= 48 {
+        return Err(OpusError::BadArguments("extension frame must be below 48"));
+    }
+
+    let mut pos = 0;
+    let mut put = |pos: &mut usize, bytes: &[u8]| -> Result<(), OpusError> {
+        if *pos + bytes.len() > max_len {
+            return Err(OpusError::BufferToSmall);
+        }
+        if let Some(data) = &mut data {
+            data[*pos..*pos + bytes.len()].copy_from_slice(bytes);
+        }
+        *pos += bytes.len();
+        Ok(())
+    };
+
+    let mut curr_frame = 0;
+    let mut written = 0;
+    for frame in 0..=max_frame {
+        for extension in extensions.iter().filter(|e| e.frame == frame) {
+            // Insert separator when needed.
+            if frame != curr_frame {
+                let diff = frame - curr_frame;
+                if diff == 1 {
+                    put(&mut pos, &[0x02])?;
+                } else {
+                    put(&mut pos, &[0x03, diff as u8])?;
+                }
+                curr_frame = frame;
+            }
+
+            let len = extension.data.len();
+            if extension.id < 32 {
+                put(&mut pos, &[(extension.id << 1) + len as u8])?;
+            } else {
+                let last = written == extensions.len() - 1;
+                put(&mut pos, &[(extension.id << 1) + !last as u8])?;
+                if !last {
+                    (0..len / 255).try_for_each(|_| put(&mut pos, &[255]))?;
+                    put(&mut pos, &[(len % 255) as u8])?;
+                }
+            }
+            put(&mut pos, extension.data)?;
+            written += 1;
+        }
+    }
+
+    Ok(pos)
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+
+    #[test]
+    fn test_parse_extensions() {
+        let data = [
+            0x01, // Padding byte
+            0x07, 0xAA, // ID 3 with 1 byte
+            0x02, // Next frame
+            0x08, // ID 4 without data
+            0x03, 0x02, // Skip 2 frames
+            0x41, 0x02, 0xBB, 0xCC, // ID 32 with coded length
+            0x50, 0xDD, 0xEE, // ID 40 until the end
+        ];
+        let extensions: Vec<Extension> = Extensions::new(&data, 4).map(|e| e.unwrap()).collect();
+        assert_eq!(
+            extensions,
+            vec![
+                Extension {
+                    id: 3,
+                    frame: 0,
+                    data: &[0xAA],
+                },
+                Extension {
+                    id: 4,
+                    frame: 1,
+                    data: &[],
+                },
+                Extension {
+                    id: 32,
+                    frame: 3,
+                    data: &[0xBB, 0xCC],
+                },
+                Extension {
+                    id: 40,
+                    frame: 3,
+                    data: &[0xDD, 0xEE],
+                },
+            ]
+        );
+
+        // Zero padding contains no extensions.
+        assert_eq!(Extensions::new(&[0, 0, 0], 1).count(), 0);
+        assert_eq!(Extensions::new(&[], 1).count(), 0);
+    }
+
+    #[test]
+    fn test_parse_invalid_extensions() {
+        // The data byte is missing.
+        let mut extensions = Extensions::new(&[0x07], 1);
+        assert!(extensions.next().unwrap().is_err());
+        assert!(extensions.next().is_none());
+
+        // The coded length exceeds the data.
+        assert!(Extensions::new(&[0x41, 0x03, 0xBB], 1)
+            .next()
+            .unwrap()
+            .is_err());
+
+        // The frame is out of range.
+        assert!(Extensions::new(&[0x03, 47, 0x02, 0x08], 48)
+            .next()
+            .unwrap()
+            .is_err());
+
+        // A separator moves past the last frame of the packet.
+        let mut extensions = Extensions::new(&[0x02, 0x08, 0x02, 0x08], 2);
+        assert_eq!(
+            extensions.next().unwrap().unwrap(),
+            Extension {
+                id: 4,
+                frame: 1,
+                data: &[],
+            }
+        );
+        assert!(extensions.next().unwrap().is_err());
+        assert!(extensions.next().is_none());
+        assert!(Extensions::new(&[0x03, 3, 0x08], 3)
+            .next()
+            .unwrap()
+            .is_err());
+    }
+
+    #[test]
+    fn test_write_extensions() {
+        let long = [0x55; 300];
+        let extensions = [
+            Extension {
+                id: 40,
+                frame: 2,
+                data: &[0xDD, 0xEE],
+            },
+            Extension {
+                id: 3,
+                frame: 0,
+                data: &[0xAA],
+            },
+            Extension {
+                id: 33,
+                frame: 0,
+                data: &long,
+            },
+        ];
+
+        let len = write_extensions(None, &extensions).unwrap();
+        let mut data = vec![0_u8; len];
+        assert_eq!(write_extensions(Some(&mut data), &extensions).unwrap(), len);
+        assert_eq!(&data[..2], &[0x07, 0xAA]);
+        assert_eq!(&data[2..5], &[0x43, 255, 45]);
+        assert_eq!(&data[305..], &[0x03, 2, 0x50, 0xDD, 0xEE]);
+
+        let parsed: Vec<Extension> = Extensions::new(&data, 4).map(|e| e.unwrap()).collect();
+        assert_eq!(parsed, vec![extensions[1], extensions[2], extensions[0]]);
+
+        assert!(write_extensions(Some(&mut data[..10]), &extensions).is_err());
+        let invalid = [Extension {
+            id: 1,
+            frame: 0,
+            data: &[],
+        }];
+        assert!(write_extensions(None, &invalid).is_err());
+        let invalid = [Extension {
+            id: 3,
+            frame: 0,
+            data: &[1, 2],
+        }];
+        assert!(write_extensions(None, &invalid).is_err());
+    }
+}
@@ -3,8 +3,6 @@
 #![deny(unsafe_code)]
 #![deny(clippy::panic)]
 #![deny(clippy::unwrap_used)]
-// FIXME only temporary until the main library calls are implemented.
-#![allow(unused)]
 
 //! Implements the free and open audio codec Opus in Rust.
 //!
@@ -30,8 +28,16 @@
 
 pub use decoder::*;
 pub use encoder::*;
-pub use encoder::*;
 pub use error::*;
+pub use extension::*;
+pub use multistream::*;
+#[cfg(feature = "ogg")]
+pub use ogg::*;
+pub use packet::*;
+pub use projection::*;
+pub use repacketizer::*;
+
+use std::convert::TryFrom;
 
 macro_rules! submodule {
   ($v:vis $name:ident) => {
@@ -44,10 +50,15 @@ pub(crate) mod celt;
 mod decoder;
 mod encoder;
 mod error;
+mod extension;
 pub(crate) mod math;
+mod multistream;
 #[cfg(feature = "ogg")]
 mod ogg;
+mod packet;
+mod projection;
 pub(crate) mod range_coder;
+mod repacketizer;
 pub(crate) mod silk;
 
 // Affects the following targets: avr and msp430
@@ -106,6 +117,76 @@ impl Sample for u32 {
     }
 }
 
+mod input {
+    /// The sample formats that the encoders accept.
+    ///
+    /// The trait can't be named outside of the crate, so it can't be implemented
+    /// for other types.
+    pub trait InputSample: Copy {
+        /// Number of significant bits of the sample format.
+        const LSB_DEPTH: i32;
+
+        /// Converts the sample into a float.
+        fn to_f32(self) -> f32;
+    }
+
+    impl InputSample for f32 {
+        const LSB_DEPTH: i32 = 24;
+
+        #[inline(always)]
+        fn to_f32(self) -> f32 {
+            self
+        }
+    }
+
+    impl InputSample for f64 {
+        const LSB_DEPTH: i32 = 24;
+
+        #[inline(always)]
+        fn to_f32(self) -> f32 {
+            self as f32
+        }
+    }
+
+    impl InputSample for i16 {
+        const LSB_DEPTH: i32 = 16;
+
+        #[inline(always)]
+        fn to_f32(self) -> f32 {
+            self as f32 / 32768.0
+        }
+    }
+
+    impl InputSample for i32 {
+        const LSB_DEPTH: i32 = 24;
+
+        #[inline(always)]
+        fn to_f32(self) -> f32 {
+            self as f32 / 2_147_483_648.0
+        }
+    }
+
+    impl InputSample for u16 {
+        const LSB_DEPTH: i32 = 16;
+
+        #[inline(always)]
+        fn to_f32(self) -> f32 {
+            (self as f32 - 32768.0) / 32768.0
+        }
+    }
+
+    impl InputSample for u32 {
+        const LSB_DEPTH: i32 = 24;
+
+        #[inline(always)]
+        fn to_f32(self) -> f32 {
+            (self ^ 0x8000_0000) as i32 as f32 / 2_147_483_648.0
+        }
+    }
+}
+
+pub(crate) use input::InputSample;
+
 /// Audio channels.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Channels {
@@ -115,6 +196,18 @@ pub enum Channels {
     Stereo = 2,
 }
 
+impl TryFrom<usize> for Channels {
+    type Error = OpusError;
+
+    fn try_from(channels: usize) -> Result<Self, Self::Error> {
+        match channels {
+            1 => Ok(Channels::Mono),
+            2 => Ok(Channels::Stereo),
+            _ => Err(OpusError::BadArguments("channels must be 1 or 2")),
+        }
+    }
+}
+
 /// Samples per second.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum SamplingRate {
@@ -130,8 +223,25 @@ pub enum SamplingRate {
     Hz48000 = 48000,
 }
 
+impl TryFrom<u32> for SamplingRate {
+    type Error = OpusError;
+
+    fn try_from(sampling_rate: u32) -> Result<Self, Self::Error> {
+        match sampling_rate {
+            8000 => Ok(SamplingRate::Hz8000),
+            12000 => Ok(SamplingRate::Hz12000),
+            16000 => Ok(SamplingRate::Hz16000),
+            24000 => Ok(SamplingRate::Hz24000),
+            48000 => Ok(SamplingRate::Hz48000),
+            _ => Err(OpusError::BadArguments(
+                "sampling rate must be 8000, 12000, 16000, 24000 or 48000 Hz",
+            )),
+        }
+    }
+}
+
 impl SamplingRate {
-    fn resampling_factor(self) -> u32 {
+    pub(crate) fn resampling_factor(self) -> u32 {
         match self {
             SamplingRate::Hz48000 => 1,
             SamplingRate::Hz24000 => 2,
@@ -143,7 +253,7 @@ impl SamplingRate {
 }
 
 /// Audio bandwidth.
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
 pub enum Bandwidth {
     /// 4 kHz passband.
     Narrowband,
@@ -209,308 +319,6 @@ pub enum CodecMode {
     CeltOnly,
 }
 
-/// Returns the bandwidth of an Opus packet.
-///
-/// Packet must have at least a size of 1.
-///
-/// # Arguments
-/// * `packet` - Input payload.
-///
-pub fn query_packet_bandwidth(packet: &[u8]) -> Bandwidth {
-    debug_assert!(!packet.is_empty());
-
-    let value = (packet[0] & 0xF8) >> 3;
-    value.into()
-}
-
-/// Returns the number of channels from an Opus packet.
-///
-/// Packet must have at least a size of 1.
-///
-/// # Arguments
-/// * `packet` - Input payload.
-///
-pub fn query_packet_channel_count(packet: &[u8]) -> Channels {
-    debug_assert!(!packet.is_empty());
-
-    if packet[0] & 0x4 != 0 {
-        Channels::Stereo
-    } else {
-        Channels::Mono
-    }
-}
-
-/// Returns the number of frames in an Opus packet.
-///
-/// Packet must have at least a size of 1.
-///
-/// # Arguments
-/// * `packet` - Input payload.
-///
-pub fn query_packet_frame_count(packet: &[u8]) -> Result<usize, OpusError> {
-    debug_assert!(!packet.is_empty());
-
-    let count = packet[0] & 0x3;
-    if count == 0 {
-        Ok(1)
-    } else if count != 3 {
-        Ok(2)
-    } else if packet.len() < 2 {
-        Err(OpusError::InvalidPacket)
-    } else {
-        Ok((packet[1] & 0x3F) as usize)
-    }
-}
-
-/// Returns the number of samples per frame from an Opus packet.
-///
-/// # Arguments
-/// * `packet`        - Input payload.
-/// * `sampling_rate` - Sampling rate.
-///
-pub fn query_packet_samples_per_frame(packet: &[u8], sampling_rate: SamplingRate) -> usize {
-    if packet[0] & 0x80 != 0 {
-        let audio_size = usize::from((packet[0] >> 3) & 0x3);
-        ((sampling_rate as usize) << audio_size) / 400
-    } else if (packet[0] & 0x60) == 0x60 {
-        if packet[0] & 0x08 != 0 {
-            sampling_rate as usize / 50
-        } else {
-            sampling_rate as usize / 100
-        }
-    } else {
-        let audio_size = usize::from((packet[0] >> 3) & 0x3);
-        if audio_size == 3 {
-            sampling_rate as usize * 60 / 1000
-        } else {
-            ((sampling_rate as usize) << audio_size) / 100
-        }
-    }
-}
-
-/// Returns the number of samples of an Opus packet.
-///
-/// Packet must have at least a size of 1.
-///
-/// # Arguments
-/// * `packet`        - Input payload.
-/// * `sampling_rate` - Sampling rate.
-///
-pub fn query_packet_sample_count(
-    packet: &[u8],
-    sampling_rate: SamplingRate,
-) -> Result<usize, OpusError> {
-    let count = query_packet_frame_count(packet)?;
-    let samples = count * query_packet_samples_per_frame(packet, sampling_rate);
-    if samples * 25 > sampling_rate as usize * 3 {
-        Err(OpusError::InvalidPacket)
-    } else {
-        Ok(samples)
-    }
-}
-
-/// Returns the codec mode of the Opus packet.
-///
-/// # Arguments
-/// * `packet`        - Input payload.
-///
-pub fn query_packet_codec_mode(packet: &[u8]) -> CodecMode {
-    if packet[0] & 0x80 == 0x80 {
-        CodecMode::CeltOnly
-    } else if packet[0] & 0x60 == 0x60 {
-        CodecMode::Hybrid
-    } else {
-        CodecMode::SilkOnly
-    }
-}
-
-/// Parse an Opus packet into one or more frames.
-///
-/// Returns the number of frames inside the packet.
-///
-/// Opus_decode will perform this operation internally so most applications do not need
-/// to use this function.
-///
-/// This function does not copy the frames, it returns the offsets to the frames inside the packet.
-///
-/// # Arguments
-/// * `packet`         - Opus packet to be parsed.
-/// * `self_delimited` - True if the packet has self delimited framing.
-/// * `frames`         - Returns the encapsulated frame offsets.
-/// * `sizes`          - Returns the sizes of the encapsulated frames.
-/// * `payload_offset` - Returns the position of the payload within the packet (in bytes).
-/// * `packet_offset`  - Returns the position of the next packet (in bytes) in
-///                      multi channel packets.
-///
-pub fn parse_packet(
-    packet: &[u8],
-    self_delimited: bool,
-    mut frames: Option<&mut [usize; 48]>,
-    sizes: &mut [usize; 48],
-    payload_offset: Option<&mut usize>,
-    packet_offset: Option<&mut usize>,
-) -> Result<usize, OpusError> {
-    let framesize = query_packet_samples_per_frame(packet, SamplingRate::Hz48000);
-    let mut offset = 1;
-    let mut len = packet.len() - offset;
-    let mut last_size = len;
-    let mut cbr = false;
-    let mut pad = 0;
-    let count: usize;
-
-    match packet[0] & 0x3 {
-        0 => {
-            // One frame.
-            count = 1;
-        }
-        1 => {
-            // Two CBR frames.
-            count = 2;
-            cbr = true;
-
-            if !self_delimited {
-                if len & 0x1 == 1 {
-                    return Err(OpusError::InvalidPacket);
-                }
-                last_size = len / 2;
-                // If last_size doesn't fit in size[0], we'll catch it later.
-                sizes[0] = last_size;
-            }
-        }
-        2 => {
-            // Two VBR frames.
-            count = 2;
-            let bytes = parse_size(&packet[offset..], &mut sizes[0])?;
-            len -= bytes;
-            if sizes[0] > len {
-                return Err(OpusError::InvalidPacket);
-            }
-            offset += bytes;
-            last_size = len - sizes[0];
-        }
-        3 => {
-            // Multiple CBR/VBR frames (from 0 to 120 ms).
-            if len < 1 {
-                return Err(OpusError::InvalidPacket);
-            }
-            // Number of frames encoded in bits 0 to 5.
-            let ch = usize::from(packet[offset]);
-            offset += 1;
-
-            count = ch & 0x3F;
-            if framesize * count > 5760 {
-                return Err(OpusError::InvalidPacket);
-            }
-            len -= 1;
-
-            // Padding flag is bit 6.
-            if ch & 0x40 != 0x0 {
-                let mut p = 255;
-                while p == 255 {
-                    p = usize::from(packet[offset]);
-                    offset += 1;
-                    len -= 1;
-
-                    let tmp = if p == 255 { 254 } else { p };
-                    len -= tmp;
-                    pad += tmp;
-                }
-            }
-
-            // VBR flag is bit 7.
-            cbr = ch & 0x80 == 0;
-            if !cbr {
-                // VBR case
-                last_size = len;
-                (0..count - 1).into_iter().try_for_each(|i| {
-                    let bytes = parse_size(&packet[offset..], &mut sizes[i])?;
-                    len -= bytes;
-                    if sizes[i] > len {
-                        return Err(OpusError::InvalidPacket);
-                    }
-                    offset += bytes;
-                    last_size -= bytes + sizes[i];
-
-                    Ok(())
-                })?;
-            } else if !self_delimited {
-                // CBR case.
-                last_size = len / count;
-                if last_size * count != len {
-                    return Err(OpusError::InvalidPacket);
-                }
-                (0..count - 1).into_iter().for_each(|i| {
-                    sizes[i] = last_size;
-                });
-            }
-        }
-        _ => {
-            unreachable!()
-        }
-    }
-
-    // Self-delimited framing has an extra size for the last frame.
-    if self_delimited {
-        let bytes = parse_size(&packet[offset..], &mut sizes[count - 1])?;
-        len -= bytes;
-        if sizes[count - 1] > len {
-            return Err(OpusError::InvalidPacket);
-        }
-        offset += bytes;
-        // For CBR packets, apply the size to all the frames.
-        if cbr {
-            if sizes[count - 1] * count > len {
-                return Err(OpusError::InvalidPacket);
-            }
-            (0..count - 1).into_iter().for_each(|i| {
-                sizes[i] = sizes[count - 1];
-            });
-        } else if bytes + sizes[count - 1] > last_size {
-            return Err(OpusError::InvalidPacket);
-        }
-    } else {
-        // Because it's not encoded explicitly, it's possible the size of the
-        // last packet (or all the packets, for the CBR case) is larger than
-        // 1275. Reject them here.
-        if last_size > 1275 {
-            return Err(OpusError::InvalidPacket);
-        }
-        sizes[count - 1] = last_size;
-    }
-
-    if let Some(payload_offset) = payload_offset {
-        *payload_offset = offset;
-    }
-
-    (0..count).into_iter().for_each(|i| {
-        if let Some(frames) = &mut frames {
-            frames[i] = offset;
-        }
-
-        offset += sizes[i];
-    });
-
-    if let Some(packet_offset) = packet_offset {
-        *packet_offset = pad + offset;
-    }
-
-    Ok(count)
-}
-
-fn parse_size(data: &[u8], size: &mut usize) -> Result<usize, OpusError> {
-    if data.is_empty() {
-        Err(OpusError::InvalidPacket)
-    } else if data[0] < 252 {
-        *size = data[0] as usize;
-        Ok(1)
-    } else if data.len() < 2 {
-        Err(OpusError::InvalidPacket)
-    } else {
-        *size = 4 * usize::from(data[1]) + usize::from(data[0]);
-        Ok(2)
-    }
-}
-
 /// Applies soft-clipping to bring a float signal within the [-1,1] range. If
 /// the signal is already in that range, nothing is done. If there are values
 /// outside of [-1,1], then the signal is clipped as smoothly as possible to
@@ -521,13 +329,12 @@ fn parse_size(data: &[u8], size: &mut usize) -> Result<usize, OpusError> {
 /// * `pcm`          - Input PCM and modified PCM.
 /// * `channels`     - Number of channels.
 /// * `softclip_mem` - State memory for the soft clipping process
-///                    (one float per channel, initialized to zero).
+///   (one float per channel, initialized to zero).
 ///
 pub fn pcm_soft_clip(pcm: &mut [f32], channels: usize, softclip_mem: &mut [f32]) {
     if pcm.is_empty() || channels == 0 || softclip_mem.len() < channels {
         return;
     }
-    let channels = channels;
     let frame_size = pcm.len() / channels;
 
     // First thing: saturate everything to +/- 2 which is the highest level our
@@ -536,7 +343,7 @@ pub fn pcm_soft_clip(pcm: &mut [f32], channels: usize, softclip_mem: &mut [f32])
     // discontinuity in the derivative.
     pcm.iter_mut().for_each(|x| *x = x.clamp(-2.0, 2.0));
 
-    (0..channels).into_iter().for_each(|c| {
+    (0..channels).for_each(|c| {
         let mut a = softclip_mem[c];
 
         // Continue applying the non-linearity from the previous frame to avoid
@@ -603,7 +410,7 @@ pub fn pcm_soft_clip(pcm: &mut [f32], channels: usize, softclip_mem: &mut [f32])
             }
 
             // Apply soft clipping.
-            (start..end).into_iter().for_each(|i| {
+            (start..end).for_each(|i| {
                 let off = c + i * channels;
                 pcm[off] += a * pcm[off] * pcm[off];
             });
@@ -614,7 +421,7 @@ pub fn pcm_soft_clip(pcm: &mut [f32], channels: usize, softclip_mem: &mut [f32])
                 let mut offset = x0 - pcm[c];
                 let delta = offset / peak_pos as f32;
 
-                (curr..peak_pos).into_iter().for_each(|i| {
+                (curr..peak_pos).for_each(|i| {
                     let off = c + i * channels;
                     offset -= delta;
                     pcm[off] += offset;
@@ -638,225 +445,42 @@ mod tests {
 
     use super::*;
 
-    const TEST_PACKET_SINGLE: &[u8] = &[
-        0x80, 0xDA, 0x84, 0xE8, 0x87, 0x77, 0x83, 0xD6, 0x48, 0xB3, 0x6B, 0x45,
-    ];
-    const TEST_PACKET_CBR: &[u8] = &[
-        0x81, 0xDA, 0x84, 0xE8, 0x87, 0x77, 0x83, 0xD6, 0x48, 0xB3, 0x6B,
-    ];
-    const TEST_PACKET_VBR: &[u8] = &[
-        0x82, 0x4, 0xDA, 0x84, 0xE8, 0x87, 0x77, 0x83, 0xD6, 0x48, 0xB3, 0x6B,
-    ];
-    const TEST_PACKET_INVALID: &[u8] = &[0x81, 0xDA];
-
     #[test]
-    fn test_query_packet_bandwidth() {
-        let bandwidths: Vec<Bandwidth> = (0..32)
-            .into_iter()
-            .map(|c| {
-                let arr = [c << 3];
-                query_packet_bandwidth(&arr)
-            })
-            .collect();
-
-        assert_eq!(bandwidths.len(), 32);
-        assert_eq!(bandwidths[0], Bandwidth::Narrowband);
-        assert_eq!(bandwidths[1], Bandwidth::Narrowband);
-        assert_eq!(bandwidths[2], Bandwidth::Narrowband);
-        assert_eq!(bandwidths[3], Bandwidth::Narrowband);
-        assert_eq!(bandwidths[4], Bandwidth::Mediumband);
-        assert_eq!(bandwidths[5], Bandwidth::Mediumband);
-        assert_eq!(bandwidths[6], Bandwidth::Mediumband);
-        assert_eq!(bandwidths[7], Bandwidth::Mediumband);
-        assert_eq!(bandwidths[8], Bandwidth::Wideband);
-        assert_eq!(bandwidths[9], Bandwidth::Wideband);
-        assert_eq!(bandwidths[10], Bandwidth::Wideband);
-        assert_eq!(bandwidths[11], Bandwidth::Wideband);
-        assert_eq!(bandwidths[12], Bandwidth::Superwideband);
-        assert_eq!(bandwidths[13], Bandwidth::Superwideband);
-        assert_eq!(bandwidths[14], Bandwidth::Fullband);
-        assert_eq!(bandwidths[15], Bandwidth::Fullband);
-        assert_eq!(bandwidths[16], Bandwidth::Narrowband);
-        assert_eq!(bandwidths[17], Bandwidth::Narrowband);
-        assert_eq!(bandwidths[18], Bandwidth::Narrowband);
-        assert_eq!(bandwidths[19], Bandwidth::Narrowband);
-        assert_eq!(bandwidths[20], Bandwidth::Wideband);
-        assert_eq!(bandwidths[21], Bandwidth::Wideband);
-        assert_eq!(bandwidths[22], Bandwidth::Wideband);
-        assert_eq!(bandwidths[23], Bandwidth::Wideband);
-        assert_eq!(bandwidths[24], Bandwidth::Superwideband);
-        assert_eq!(bandwidths[25], Bandwidth::Superwideband);
-        assert_eq!(bandwidths[26], Bandwidth::Superwideband);
-        assert_eq!(bandwidths[27], Bandwidth::Superwideband);
-        assert_eq!(bandwidths[28], Bandwidth::Fullband);
-        assert_eq!(bandwidths[29], Bandwidth::Fullband);
-        assert_eq!(bandwidths[30], Bandwidth::Fullband);
-        assert_eq!(bandwidths[31], Bandwidth::Fullband);
+    fn test_channels_try_from() {
+        assert_eq!(Channels::try_from(1).unwrap(), Channels::Mono);
+        assert_eq!(Channels::try_from(2).unwrap(), Channels::Stereo);
+        assert!(matches!(
+            Channels::try_from(0),
+            Err(OpusError::BadArguments(_))
+        ));
+        assert!(matches!(
+            Channels::try_from(3),
+            Err(OpusError::BadArguments(_))
+        ));
     }
 
     #[test]
-    fn test_query_packet_channel_count() {
-        assert_eq!(query_packet_channel_count(&[0]), Channels::Mono);
-        assert_eq!(query_packet_channel_count(&[0x4]), Channels::Stereo);
-    }
-
-    #[test]
-    fn test_query_packet_frame_count() {
-        assert_eq!(query_packet_frame_count(&[0]).unwrap(), 1);
-        assert_eq!(query_packet_frame_count(&[1]).unwrap(), 2);
-        assert_eq!(query_packet_frame_count(&[2]).unwrap(), 2);
-        assert!(query_packet_frame_count(&[3]).is_err());
-        assert_eq!(query_packet_frame_count(&[3, 5]).unwrap(), 5);
-    }
-
-    #[test]
-    fn test_query_packet_samples_per_frame() {
-        let frame_sizes: Vec<usize> = (0..32)
-            .into_iter()
-            .map(|c| {
-                let arr = [c << 3];
-                query_packet_samples_per_frame(&arr, SamplingRate::Hz48000)
-            })
-            .collect();
-
-        assert_eq!(frame_sizes.len(), 32);
-        assert_eq!(frame_sizes[0], 480);
-        assert_eq!(frame_sizes[1], 960);
-        assert_eq!(frame_sizes[2], 1920);
-        assert_eq!(frame_sizes[3], 2880);
-        assert_eq!(frame_sizes[4], 480);
-        assert_eq!(frame_sizes[5], 960);
-        assert_eq!(frame_sizes[6], 1920);
-        assert_eq!(frame_sizes[7], 2880);
-        assert_eq!(frame_sizes[8], 480);
-        assert_eq!(frame_sizes[9], 960);
-        assert_eq!(frame_sizes[10], 1920);
-        assert_eq!(frame_sizes[11], 2880);
-        assert_eq!(frame_sizes[12], 480);
-        assert_eq!(frame_sizes[13], 960);
-        assert_eq!(frame_sizes[14], 480);
-        assert_eq!(frame_sizes[15], 960);
-        assert_eq!(frame_sizes[16], 120);
-        assert_eq!(frame_sizes[17], 240);
-        assert_eq!(frame_sizes[18], 480);
-        assert_eq!(frame_sizes[19], 960);
-        assert_eq!(frame_sizes[20], 120);
-        assert_eq!(frame_sizes[21], 240);
-        assert_eq!(frame_sizes[22], 480);
-        assert_eq!(frame_sizes[23], 960);
-        assert_eq!(frame_sizes[24], 120);
-        assert_eq!(frame_sizes[25], 240);
-        assert_eq!(frame_sizes[26], 480);
-        assert_eq!(frame_sizes[27], 960);
-        assert_eq!(frame_sizes[28], 120);
-        assert_eq!(frame_sizes[29], 240);
-        assert_eq!(frame_sizes[30], 480);
-        assert_eq!(frame_sizes[31], 960);
-    }
-
-    #[test]
-    fn test_query_packet_sample_count() {
-        assert_eq!(
-            query_packet_sample_count(&[70], SamplingRate::Hz48000).unwrap(),
-            960
-        );
-        assert!(query_packet_sample_count(&[3], SamplingRate::Hz48000).is_err());
-        assert_eq!(
-            query_packet_sample_count(&[255, 5], SamplingRate::Hz48000).unwrap(),
-            4800
-        );
-    }
-
-    #[test]
-    fn test_parse_packet_with_single_frame() {
-        let mut frames = [0; 48];
-        let mut sizes = [0; 48];
-        let mut payload_offset = 0;
-        let mut packet_offset = 0;
-
-        let count = parse_packet(
-            TEST_PACKET_SINGLE,
-            false,
-            Some(&mut frames),
-            &mut sizes,
-            Some(&mut payload_offset),
-            Some(&mut packet_offset),
-        )
-        .unwrap();
-
-        assert_eq!(count, 1);
-        assert_eq!(frames[0], 1);
-        assert_eq!(sizes[0], 11);
-        assert_eq!(payload_offset, 1);
-        assert_eq!(packet_offset, 12);
-    }
-
-    #[test]
-    fn test_parse_packet_with_two_cbr_frames() {
-        let mut frames = [0; 48];
-        let mut sizes = [0; 48];
-        let mut payload_offset = 0;
-        let mut packet_offset = 0;
-
-        let count = parse_packet(
-            TEST_PACKET_CBR,
-            false,
-            Some(&mut frames),
-            &mut sizes,
-            Some(&mut payload_offset),
-            Some(&mut packet_offset),
-        )
-        .unwrap();
-
-        assert_eq!(count, 2);
-        assert_eq!(frames[0], 1);
-        assert_eq!(sizes[0], 5);
-        assert_eq!(frames[1], 6);
-        assert_eq!(sizes[1], 5);
-        assert_eq!(payload_offset, 1);
-        assert_eq!(packet_offset, 11);
-    }
-
-    #[test]
-    fn test_parse_packet_with_two_vbr_frames() {
-        let mut frames = [0; 48];
-        let mut sizes = [0; 48];
-        let mut payload_offset = 0;
-        let mut packet_offset = 0;
-
-        let count = parse_packet(
-            TEST_PACKET_VBR,
-            false,
-            Some(&mut frames),
-            &mut sizes,
-            Some(&mut payload_offset),
-            Some(&mut packet_offset),
-        )
-        .unwrap();
-
-        assert_eq!(count, 2);
-        assert_eq!(frames[0], 2);
-        assert_eq!(sizes[0], 4);
-        assert_eq!(frames[1], 6);
-        assert_eq!(sizes[1], 6);
-        assert_eq!(payload_offset, 2);
-        assert_eq!(packet_offset, 12);
+    fn test_sampling_rate_try_from() {
+        [8000, 12000, 16000, 24000, 48000].iter().for_each(|&rate| {
+            assert_eq!(SamplingRate::try_from(rate).unwrap() as u32, rate);
+        });
+        assert!(matches!(
+            SamplingRate::try_from(44100),
+            Err(OpusError::BadArguments(_))
+        ));
     }
 
     #[test]
-    fn test_parse_packet_invalid_frame() {
-        let mut frames = [0; 48];
-        let mut sizes = [0; 48];
-
-        assert!(parse_packet(
-            TEST_PACKET_INVALID,
-            false,
-            Some(&mut frames),
-            &mut sizes,
-            None,
-            None,
-        )
-        .is_err())
+    fn test_input_sample_to_f32() {
+        assert_eq!(0_i16.to_f32(), 0.0);
+        assert_eq!(i16::MIN.to_f32(), -1.0);
+        assert_eq!(32768_u16.to_f32(), 0.0);
+        assert_eq!(0_u16.to_f32(), -1.0);
+        assert_eq!(i32::MIN.to_f32(), -1.0);
+        assert_eq!(0x8000_0000_u32.to_f32(), 0.0);
+        assert_eq!(0_u32.to_f32(), -1.0);
+        assert_eq!(0x8000_0100_u32.to_f32(), 1.0 / 8_388_608.0);
+        assert_eq!(u32::MAX.to_f32(), i32::MAX.to_f32());
     }
 
     #[test]
@@ -864,25 +488,25 @@ mod tests {
         let mut x = [0_f32; 1024];
         let mut s = [0_f32; 8];
 
-        (0..1024).into_iter().for_each(|i| {
-            (0..1024).into_iter().for_each(|j| {
+        (0..1024).for_each(|i| {
+            (0..1024).for_each(|j| {
                 x[j] = (j & 255) as f32 * (1.0 / 32.0) - 4.0;
             });
 
             pcm_soft_clip(&mut x[i..], 1, &mut s);
 
-            (i..1024).into_iter().for_each(|j| {
+            (i..1024).for_each(|j| {
                 assert!(x[j] <= 1.0);
                 assert!(x[j] >= -1.0);
             });
         });
 
-        (1..9).into_iter().for_each(|i| {
-            (0..1024).into_iter().for_each(|j| {
+        (1..9).for_each(|i| {
+            (0..1024).for_each(|j| {
                 x[j] = (j & 255) as f32 * (1.0 / 32.0) - 4.0;
             });
             pcm_soft_clip(&mut x, i, &mut s);
-            (0..(1024 / i) * i).into_iter().for_each(|j| {
+            (0..(1024 / i) * i).for_each(|j| {
                 assert!(x[j] <= 1.0);
                 assert!(x[j] >= -1.0);
             });
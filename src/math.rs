@@ -1,4 +1,5 @@
-use std::f32::consts::{LN_2, LOG2_E, PI};
+use std::f32::consts::PI;
+use std::f64::consts::{LN_2, LOG2_E};
 
 /// The minimum number of bits required to store a positive integer in binary, or 0 for a non-positive integer.
 #[inline(always)]
@@ -7,15 +8,19 @@ pub(crate) fn ilog(x: u32) -> u32 {
 }
 
 /// Fast version for log2.
+///
+/// Calculated with double precision to stay consistent with the reference implementation.
 #[inline(always)]
 pub(crate) fn fast_log2(x: f32) -> f32 {
-    x.ln() * LOG2_E
+    (LOG2_E * f64::from(x).ln()) as f32
 }
 
 /// Fast version for exp2.
+///
+/// Calculated with double precision to stay consistent with the reference implementation.
 #[inline(always)]
 pub(crate) fn fast_exp2(x: f32) -> f32 {
-    (x * LN_2).exp()
+    (LN_2 * f64::from(x)).exp() as f32
 }
 
 /// Fast version for atan2.
@@ -68,8 +73,32 @@ pub(crate) fn bitexact_log2tan(isin: i32, icos: i32) -> i32 {
     (ls - lc) * (1 << 11) + a - b
 }
 
+/// Integer square root.
+pub(crate) fn isqrt32(mut val: u32) -> u32 {
+    // Uses the second method from http://www.azillionmonkeys.com/qed/sqroot.html
+    // The main idea is to search for the largest binary digit b such that
+    // (g+b)*(g+b) <= val, and add it to the solution g.
+    let mut g = 0;
+    let mut bshift = (ilog(val) as i32 - 1) >> 1;
+    let mut b = 1 << bshift;
+    loop {
+        let t = ((g << 1) + b) << bshift;
+        if t <= val {
+            g += b;
+            val -= t;
+        }
+        b >>= 1;
+        bshift -= 1;
+        if bshift < 0 {
+            break;
+        }
+    }
+    g
+}
+
+/// Multiplies two Q15 values.
 #[inline(always)]
-fn frac_mul16(rhs: i16, lhs: i16) -> i16 {
+pub(crate) fn frac_mul16(rhs: i16, lhs: i16) -> i16 {
     let x = i32::from(rhs) * i32::from(lhs);
     ((16384 + x) >> 15) as i16
 }
@@ -171,7 +200,7 @@ mod tests {
 
     #[test]
     fn test_div() {
-        (1..=327670).into_iter().for_each(|i| {
+        (1..=327670).for_each(|i| {
             let val = 1.0 / i as f32;
             let prod = val * i as f32;
 
@@ -240,7 +269,7 @@ mod tests {
         let mut last: i16 = 32767;
         let mut min_d: i16 = 32767;
 
-        (64..=16320).into_iter().for_each(|i| {
+        (64..=16320).for_each(|i| {
             let q = bitexact_cos(i);
             chk ^= i32::from(q) * i32::from(i);
             let d = last - q;
@@ -270,7 +299,7 @@ mod tests {
         let mut last: i32 = 15059;
         let mut min_d: i32 = 15059;
 
-        (64..8193).into_iter().for_each(|i| {
+        (64..8193).for_each(|i| {
             let mid = i32::from(bitexact_cos(i));
             let side = i32::from(bitexact_cos(16384 - i));
             let q = bitexact_log2tan(mid, side);
@@ -297,6 +326,20 @@ mod tests {
         assert_eq!(bitexact_log2tan(23171, 23171), 0);
     }
 
+    #[test]
+    fn test_isqrt32() {
+        (1..100_000).for_each(|i: u32| {
+            let r = isqrt32(i);
+            assert!(
+                r * r <= i && (r + 1) * (r + 1) > i,
+                "isqrt32({}) = {}",
+                i,
+                r
+            );
+        });
+        assert_eq!(isqrt32(u32::MAX), 65535);
+    }
+
     #[test]
     fn test_atan2() {
         assert!((fast_atan2(0.0, 0.0) - 0.0).abs() < 0.0001);
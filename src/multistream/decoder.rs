@@ -0,0 +1,650 @@
+//! Implements the multistream decoder.
+
+use std::num::NonZeroUsize;
+
+use crate::decoder::DecoderInner;
+use crate::multistream::ChannelLayout;
+use crate::projection::MappingMatrix;
+use crate::{
+    parse_packet, query_packet_sample_count, Bandwidth, Channels, DecoderConfiguration, OpusError,
+    Sample, SamplingRate,
+};
+
+/// Configures the multistream decoder on creation.
+///
+/// The stream count, the coupled stream count and the channel mapping table are
+/// usually read from the header of the container, for example the "OpusHead"
+/// packet of an Ogg Opus file. The channel mapping families 0 (mono / stereo),
+/// 1 (Vorbis channel order, up to 8 channels) and 255 (undefined) only differ in
+/// the way these values are chosen, so they are all supported by this configuration.
+#[derive(Clone, Debug)]
+pub struct MultistreamDecoderConfiguration {
+    /// Sample rate to decode at (Hz). Default: 48000 kHz.
+    pub sampling_rate: SamplingRate,
+    /// The total number of streams coded in the input (1-255). Default: 1.
+    pub streams: usize,
+    /// Number of streams to decode as coupled (2 channel) streams. Default: 1.
+    ///
+    /// The coupled streams are the first streams in the packet.
+    pub coupled_streams: usize,
+    /// Maps the output channels to the decoded channels. Default: [0, 1].
+    ///
+    /// The length of the mapping defines the number of output channels (1-255).
+    /// The decoded channels are numbered with the left and right channels of the
+    /// coupled streams first, followed by the channels of the uncoupled streams.
+    /// 255 marks a muted channel.
+    pub mapping: Vec<u8>,
+    /// Scales the decoded output by a factor specified in Q8 dB units. Default: 0.
+    pub gain: i16,
+}
+
+impl Default for MultistreamDecoderConfiguration {
+    fn default() -> Self {
+        Self {
+            sampling_rate: SamplingRate::Hz48000,
+            streams: 1,
+            coupled_streams: 1,
+            mapping: vec![0, 1],
+            gain: 0,
+        }
+    }
+}
+
+/// Opus multistream decoder.
+///
+/// Decodes packets that contain multiple Opus streams and maps the streams
+/// to up to 255 output channels. It can be used to decode surround sound.
+#[derive(Clone, Debug)]
+pub struct MultistreamDecoder {
+    inner: MultistreamDecoderInner,
+    buffer: Vec<f32>,
+}
+
+impl MultistreamDecoder {
+    /// Creates a new `MultistreamDecoder` with the given configuration.
+    pub fn new(configuration: &MultistreamDecoderConfiguration) -> Result<Self, OpusError> {
+        let inner = MultistreamDecoderInner::new(configuration, None)?;
+        Ok(Self {
+            inner,
+            buffer: vec![],
+        })
+    }
+
+    /// Resets the MultistreamDecoder to be equivalent to a freshly initialized decoder.
+    ///
+    /// This should be called when switching streams in order to prevent
+    /// the back to back decoding from giving different results from
+    /// one at a time decoding.
+    pub fn reset(&mut self) -> Result<(), OpusError> {
+        self.buffer = vec![];
+        self.inner.reset()
+    }
+
+    /// Returns the sampling rate the decoder was initialized with.
+    pub fn sampling_rate(&self) -> SamplingRate {
+        self.inner.sampling_rate
+    }
+
+    /// Returns the number of output channels.
+    pub fn channels(&self) -> usize {
+        self.inner.layout.channels()
+    }
+
+    /// Returns the number of streams.
+    pub fn streams(&self) -> usize {
+        self.inner.layout.streams
+    }
+
+    /// Returns the number of coupled streams.
+    pub fn coupled_streams(&self) -> usize {
+        self.inner.layout.coupled_streams
+    }
+
+    /// Returns the channel mapping table.
+    pub fn mapping(&self) -> &[u8] {
+        &self.inner.layout.mapping
+    }
+
+    /// Returns the amount to scale PCM signal by in Q8 dB units.
+    pub fn gain(&self) -> i16 {
+        self.inner.decoders[0].decode_gain
+    }
+
+    /// Returns the decoder's last bandpass.
+    pub fn bandwidth(&self) -> Option<Bandwidth> {
+        self.inner.decoders[0].bandwidth
+    }
+
+    /// Returns the duration (in samples) of the last packet successfully decoded or concealed.
+    pub fn last_packet_duration(&self) -> Option<usize> {
+        self.inner.decoders[0].last_packet_duration
+    }
+
+    /// Returns the final state of the codec's entropy coder.
+    ///
+    /// This is the combination of the final states of all streams.
+    pub fn final_range(&self) -> u32 {
+        self.inner
+            .decoders
+            .iter()
+            .fold(0, |acc, decoder| acc ^ decoder.final_range)
+    }
+
+    /// Decode a multistream Opus packet with a generic sample output.
+    ///
+    /// Returns number of decoded samples for one channel.
+    ///
+    /// The internal format is `f32`. Use `decode_float()` to access it directly.
+    ///
+    /// # Arguments
+    /// * `packet`     - Input payload. Use a `None` to indicate packet loss.
+    /// * `samples`    - Output signal encoded as PCM samples (interleaved).
+    ///   Length must be at least `frame_size` * `channels`.
+    /// * `frame_size` - Number of samples per channel of available space in a PCM.
+    ///   `frame_size` must be a multiple of 2.5 ms (400 for 48kHz).
+    ///   In the case of PLC (packet==`None`) or FEC (decode_fec=`true`), then
+    ///   `frame_size` needs to be exactly the duration of audio that is missing,
+    ///   otherwise the decoder will not be in the optimal state to decode
+    ///   the next incoming packet.
+    /// * `decode_fec` - Request that any in-band forward error correction data be decoded.
+    ///   If no such data is available, the frame is decoded as if it were lost.
+    ///
+    pub fn decode<S: Sample>(
+        &mut self,
+        packet: Option<&[u8]>,
+        samples: &mut [S],
+        frame_size: NonZeroUsize,
+        decode_fec: bool,
+    ) -> Result<usize, OpusError> {
+        let channels = self.inner.layout.channels();
+        let frame_size = usize::min(frame_size.get(), self.inner.sampling_rate as usize / 25 * 3);
+
+        let size = frame_size * channels;
+        if self.buffer.len() < size {
+            self.buffer.resize(size, 0_f32);
+        }
+
+        let sample_count = self.inner.decode_native(
+            packet,
+            &mut self.buffer[..size],
+            frame_size,
+            decode_fec,
+            true,
+        )?;
+
+        if sample_count * channels > samples.len() {
+            return Err(OpusError::BufferToSmall);
+        }
+        samples
+            .iter_mut()
+            .zip(self.buffer.iter())
+            .take(sample_count * channels)
+            .for_each(|(x, &s)| *x = S::from_f32(s));
+
+        Ok(sample_count)
+    }
+
+    /// Decode a multistream Opus packet with floating point output.
+    ///
+    /// Returns number of decoded samples for one channel.
+    ///
+    /// # Arguments
+    /// * `packet`     - Input payload. Use a `None` to indicate packet loss.
+    /// * `samples`    - Output signal encoded as PCM samples (interleaved).
+    ///   Length must be at least `frame_size` * `channels`.
+    /// * `frame_size` - Number of samples per channel of available space in a PCM.
+    ///   `frame_size` must be a multiple of 2.5 ms (400 for 48kHz).
+    ///   In the case of PLC (packet==`None`) or FEC (decode_fec=`true`), then
+    ///   `frame_size` needs to be exactly the duration of audio that is missing,
+    ///   otherwise the decoder will not be in the optimal state to decode
+    ///   the next incoming packet.
+    /// * `decode_fec` - Request that any in-band forward error correction data be decoded.
+    ///   If no such data is available, the frame is decoded as if it were lost.
+    ///
+    pub fn decode_float(
+        &mut self,
+        packet: Option<&[u8]>,
+        samples: &mut [f32],
+        frame_size: NonZeroUsize,
+        decode_fec: bool,
+    ) -> Result<usize, OpusError> {
+        self.inner
+            .decode_native(packet, samples, frame_size.get(), decode_fec, false)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct MultistreamDecoderInner {
+    pub(crate) layout: ChannelLayout,
+    pub(crate) sampling_rate: SamplingRate,
+    pub(crate) decoders: Vec<DecoderInner>,
+    /// Demixes the decoded channels into the output channels of the projection decoder.
+    demixing_matrix: Option<MappingMatrix>,
+    stream_buffer: Vec<f32>,
+}
+
+impl MultistreamDecoderInner {
+    pub(crate) fn new(
+        configuration: &MultistreamDecoderConfiguration,
+        demixing_matrix: Option<MappingMatrix>,
+    ) -> Result<Self, OpusError> {
+        let layout = ChannelLayout::new(
+            configuration.streams,
+            configuration.coupled_streams,
+            &configuration.mapping,
+        )?;
+
+        let decoders = (0..layout.streams)
+            .map(|s| {
+                let channels = if s < layout.coupled_streams {
+                    Channels::Stereo
+                } else {
+                    Channels::Mono
+                };
+                DecoderInner::new(&DecoderConfiguration {
+                    sampling_rate: configuration.sampling_rate,
+                    channels,
+                    gain: configuration.gain,
+                })
+            })
+            .collect::<Result<Vec<DecoderInner>, OpusError>>()?;
+
+        Ok(Self {
+            layout,
+            sampling_rate: configuration.sampling_rate,
+            decoders,
+            demixing_matrix,
+            stream_buffer: vec![],
+        })
+    }
+
+    pub(crate) fn reset(&mut self) -> Result<(), OpusError> {
+        self.decoders
+            .iter_mut()
+            .try_for_each(|decoder| decoder.reset())
+    }
+
+    /// Decodes all streams of the packet and maps them to the output channels.
+    ///
+    /// Returns the samples decoded per channel.
+    pub(crate) fn decode_native(
+        &mut self,
+        packet: Option<&[u8]>,
+        samples: &mut [f32],
+        frame_size: usize,
+        decode_fec: bool,
+        soft_clip: bool,
+    ) -> Result<usize, OpusError> {
+        let streams = self.layout.streams;
+        let channels = self.layout.channels();
+
+        // Limit frame_size to avoid excessive allocations.
+        let mut frame_size = usize::min(frame_size, self.sampling_rate as usize / 25 * 3);
+
+        let mut sample_count = frame_size;
+        if let Some(packet) = packet {
+            if packet.is_empty() {
+                return Err(OpusError::BadArguments("packet is empty"));
+            }
+            if packet.len() < 2 * streams - 1 {
+                return Err(OpusError::InvalidPacket);
+            }
+            let packet_sample_count = packet_validate(packet, streams, self.sampling_rate)?;
+            if packet_sample_count > frame_size {
+                return Err(OpusError::BufferToSmall);
+            }
+            if !decode_fec {
+                sample_count = packet_sample_count;
+            }
+        }
+        if samples.len() < sample_count * channels {
+            return Err(OpusError::BufferToSmall);
+        }
+
+        if self.stream_buffer.len() < 2 * frame_size {
+            self.stream_buffer.resize(2 * frame_size, 0.0);
+        }
+
+        let mut data = packet;
+        let layout = &self.layout;
+        let demixing_matrix = self.demixing_matrix.as_ref();
+        let buffer = &mut self.stream_buffer;
+        for (s, decoder) in self.decoders.iter_mut().enumerate() {
+            if matches!(data, Some(data) if data.is_empty()) {
+                return Err(OpusError::InternalError("the packet is too short"));
+            }
+
+            let (count, packet_offset) = decoder.decode_native(
+                &data,
+                buffer,
+                frame_size,
+                decode_fec,
+                s != streams - 1,
+                soft_clip,
+            )?;
+            data = data.map(|data| &data[packet_offset..]);
+            frame_size = count;
+
+            if s < layout.coupled_streams {
+                // Copy "left" audio to the channel(s) where it belongs.
+                layout.left_channels(s).for_each(|channel| {
+                    copy_channel_out(
+                        samples,
+                        channels,
+                        channel,
+                        Some(&buffer[..]),
+                        2,
+                        frame_size,
+                        demixing_matrix,
+                    )
+                });
+                // Copy "right" audio to the channel(s) where it belongs.
+                layout.right_channels(s).for_each(|channel| {
+                    copy_channel_out(
+                        samples,
+                        channels,
+                        channel,
+                        Some(&buffer[1..]),
+                        2,
+                        frame_size,
+                        demixing_matrix,
+                    )
+                });
+            } else {
+                // Copy audio to the channel(s) where it belongs.
+                layout.mono_channels(s).for_each(|channel| {
+                    copy_channel_out(
+                        samples,
+                        channels,
+                        channel,
+                        Some(&buffer[..]),
+                        1,
+                        frame_size,
+                        demixing_matrix,
+                    )
+                });
+            }
+        }
+
+        // Handle muted channels.
+        layout.muted_channels().for_each(|channel| {
+            copy_channel_out(
+                samples,
+                channels,
+                channel,
+                None,
+                0,
+                frame_size,
+                demixing_matrix,
+            )
+        });
+
+        Ok(frame_size)
+    }
+}
+
+/// Copies a decoded channel from `src` into the channel `dst_channel` of the interleaved `dst`.
+///
+/// A missing `src` mutes the channel. If a demixing matrix is given, the decoded channel
+/// is instead demixed into all channels of `dst`.
+fn copy_channel_out(
+    dst: &mut [f32],
+    dst_stride: usize,
+    dst_channel: usize,
+    src: Option<&[f32]>,
+    src_stride: usize,
+    frame_size: usize,
+    demixing_matrix: Option<&MappingMatrix>,
+) {
+    match (demixing_matrix, src) {
+        (Some(matrix), src) => {
+            if dst_channel == 0 {
+                dst[..frame_size * dst_stride]
+                    .iter_mut()
+                    .for_each(|x| *x = 0.0);
+            }
+            if let Some(src) = src {
+                matrix.multiply_channel_out(
+                    src,
+                    dst_channel,
+                    src_stride,
+                    dst,
+                    dst_stride,
+                    frame_size,
+                );
+            }
+        }
+        (None, Some(src)) => (0..frame_size).for_each(|i| {
+            dst[i * dst_stride + dst_channel] = src[i * src_stride];
+        }),
+        (None, None) => (0..frame_size).for_each(|i| {
+            dst[i * dst_stride + dst_channel] = 0.0;
+        }),
+    }
+}
+
+/// Validates the framing of all streams of a multistream packet.
+///
+/// Returns the number of samples per stream.
+fn packet_validate(
+    mut packet: &[u8],
+    streams: usize,
+    sampling_rate: SamplingRate,
+) -> Result<usize, OpusError> {
+    let mut sizes = [0_usize; 48];
+    let mut samples = 0;
+
+    for s in 0..streams {
+        if packet.is_empty() {
+            return Err(OpusError::InvalidPacket);
+        }
+
+        let mut packet_offset = 0;
+        parse_packet(
+            packet,
+            s != streams - 1,
+            None,
+            &mut sizes,
+            None,
+            Some(&mut packet_offset),
+        )?;
+        let stream_samples = query_packet_sample_count(&packet[..packet_offset], sampling_rate)?;
+        if s != 0 && samples != stream_samples {
+            return Err(OpusError::InvalidPacket);
+        }
+        samples = stream_samples;
+        packet = &packet[packet_offset..];
+    }
+
+    Ok(samples)
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+    use crate::{Application, Bitrate, Decoder, Encoder, EncoderConfiguration};
+
+    /// Converts a single frame packet into its self-delimited form.
+    fn self_delimited(packet: &[u8]) -> Vec<u8> {
+        assert_eq!(packet[0] & 0x3, 0);
+        let size = packet.len() - 1;
+        let mut out = vec![packet[0]];
+        if size < 252 {
+            out.push(size as u8);
+        } else {
+            let first = 252 + (size & 0x3);
+            out.push(first as u8);
+            out.push(((size - first) >> 2) as u8);
+        }
+        out.extend_from_slice(&packet[1..]);
+        out
+    }
+
+    fn generate_signal(channels: usize, length: usize) -> Vec<f32> {
+        (0..length * channels)
+            .map(|i| {
+                let t = (i / channels) as f32 / 48000.0;
+                let f = 220.0 * (1 + i % channels) as f32;
+                0.5 * f32::sin(2.0 * std::f32::consts::PI * f * t)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_multistream_decoder() {
+        // Two coupled streams and one mono stream with one muted
+        // and one duplicated channel.
+        let configuration = MultistreamDecoderConfiguration {
+            streams: 3,
+            coupled_streams: 2,
+            mapping: vec![0, 4, 1, 255, 2, 3, 4],
+            ..Default::default()
+        };
+        let mut decoder = MultistreamDecoder::new(&configuration).unwrap();
+        assert_eq!(decoder.channels(), 7);
+        assert_eq!(decoder.streams(), 3);
+        assert_eq!(decoder.coupled_streams(), 2);
+        assert_eq!(decoder.mapping(), &[0, 4, 1, 255, 2, 3, 4]);
+
+        let mut encoders: Vec<Encoder> = [Channels::Stereo, Channels::Stereo, Channels::Mono]
+            .iter()
+            .map(|&channels| {
+                Encoder::new(&EncoderConfiguration {
+                    channels,
+                    application: Application::Audio,
+                    bitrate: Bitrate::BitsPerSecond(64000),
+                    ..Default::default()
+                })
+                .unwrap()
+            })
+            .collect();
+        let mut reference_decoders: Vec<Decoder> = encoders
+            .iter()
+            .map(|encoder| {
+                Decoder::new(&DecoderConfiguration {
+                    sampling_rate: SamplingRate::Hz48000,
+                    channels: encoder.channels(),
+                    gain: 0,
+                })
+                .unwrap()
+            })
+            .collect();
+
+        let frame_size = 960;
+        let frame_size_nz = NonZeroUsize::new(frame_size).unwrap();
+        let signals: Vec<Vec<f32>> = encoders
+            .iter()
+            .map(|encoder| generate_signal(encoder.channels() as usize, 10 * frame_size))
+            .collect();
+
+        let mut output = vec![1_f32; frame_size * 7];
+        (0..10).for_each(|i| {
+            let mut packet = vec![];
+            let mut references = vec![];
+            let mut final_range = 0;
+            encoders
+                .iter_mut()
+                .zip(reference_decoders.iter_mut())
+                .zip(signals.iter())
+                .enumerate()
+                .for_each(|(s, ((encoder, reference_decoder), signal))| {
+                    let channels = encoder.channels() as usize;
+                    let mut data = [0_u8; 1500];
+                    let len = encoder
+                        .encode_float(
+                            &signal[i * frame_size * channels..(i + 1) * frame_size * channels],
+                            &mut data,
+                        )
+                        .unwrap();
+                    if s == 2 {
+                        packet.extend_from_slice(&data[..len]);
+                    } else {
+                        packet.extend_from_slice(&self_delimited(&data[..len]));
+                    }
+
+                    let mut reference = vec![0_f32; frame_size * channels];
+                    reference_decoder
+                        .decode_float(Some(&data[..len]), &mut reference, frame_size_nz, false)
+                        .unwrap();
+                    final_range ^= reference_decoder.final_range();
+                    references.push(reference);
+                });
+
+            let count = decoder
+                .decode_float(Some(&packet), &mut output, frame_size_nz, false)
+                .unwrap();
+            assert_eq!(count, frame_size);
+            assert_eq!(decoder.final_range(), final_range);
+
+            (0..frame_size).for_each(|j| {
+                let frame = &output[j * 7..(j + 1) * 7];
+                assert_eq!(frame[0], references[0][j * 2]);
+                assert_eq!(frame[1], references[2][j]);
+                assert_eq!(frame[2], references[0][j * 2 + 1]);
+                assert_eq!(frame[3], 0.0);
+                assert_eq!(frame[4], references[1][j * 2]);
+                assert_eq!(frame[5], references[1][j * 2 + 1]);
+                assert_eq!(frame[6], references[2][j]);
+            });
+        });
+
+        // Packet loss concealment.
+        let count = decoder
+            .decode_float(None, &mut output, frame_size_nz, false)
+            .unwrap();
+        assert_eq!(count, frame_size);
+        assert!(output.iter().all(|x| x.is_finite()));
+        assert_eq!(decoder.last_packet_duration(), Some(frame_size));
+
+        let mut output_i16 = vec![0_i16; frame_size * 7];
+        let count = decoder
+            .decode(None, &mut output_i16, frame_size_nz, false)
+            .unwrap();
+        assert_eq!(count, frame_size);
+    }
+
+    #[test]
+    fn test_multistream_decoder_invalid_packet() {
+        let configuration = MultistreamDecoderConfiguration {
+            streams: 2,
+            coupled_streams: 0,
+            mapping: vec![0, 1],
+            ..Default::default()
+        };
+        let mut decoder = MultistreamDecoder::new(&configuration).unwrap();
+        let frame_size = NonZeroUsize::new(960).unwrap();
+        let mut output = vec![0_f32; 960 * 2];
+
+        // Too short for two streams.
+        assert!(decoder
+            .decode_float(Some(&[0xF8]), &mut output, frame_size, false)
+            .is_err());
+        // The first stream is not self-delimited.
+        assert!(decoder
+            .decode_float(Some(&[0xF8, 0xFF, 0xFE]), &mut output, frame_size, false)
+            .is_err());
+        // Streams of different durations.
+        assert!(decoder
+            .decode_float(Some(&[0xF8, 0x00, 0xF0]), &mut output, frame_size, false)
+            .is_err());
+        // Two empty (DTX) frames are valid.
+        assert_eq!(
+            decoder
+                .decode_float(Some(&[0xF8, 0x00, 0xF8]), &mut output, frame_size, false)
+                .unwrap(),
+            960
+        );
+    }
+
+    #[test]
+    fn test_invalid_configuration() {
+        let configuration = MultistreamDecoderConfiguration {
+            streams: 2,
+            coupled_streams: 1,
+            mapping: vec![0, 1, 3],
+            ..Default::default()
+        };
+        assert!(MultistreamDecoder::new(&configuration).is_err());
+    }
+}
@@ -0,0 +1,1197 @@
+//! Implements the multistream and surround encoder.
+
+use crate::celt::mode::{MAX_LM, NB_E_BANDS, OVERLAP, SHORT_MDCT_SIZE, WINDOW};
+use crate::celt::{amp2_log2, compute_band_energies, inner_prod, preemphasis, Mdct};
+use crate::encoder::EncoderInner;
+use crate::math::{fast_log2, isqrt32};
+use crate::multistream::ChannelLayout;
+use crate::projection::MappingMatrix;
+use crate::repacketizer::Repacketizer;
+use crate::{
+    Application, Bandwidth, Bitrate, Channels, CodecMode, EncoderConfiguration, FrameDuration,
+    InputSample, OpusError, SamplingRate,
+};
+
+/// Max size in case the encoder decides to return six frames (6 x 20 ms = 120 ms).
+const MS_FRAME_TMP: usize = 6 * 1275 + 12;
+
+/// The stream count, coupled stream count and mapping of the Vorbis channel orders.
+///
+/// Index is the number of channels - 1.
+const VORBIS_MAPPINGS: &[(usize, usize, &[u8]); 8] = &[
+    (1, 0, &[0]),                      // 1: mono
+    (1, 1, &[0, 1]),                   // 2: stereo
+    (2, 1, &[0, 2, 1]),                // 3: 1-d surround
+    (2, 2, &[0, 1, 2, 3]),             // 4: quadraphonic surround
+    (3, 2, &[0, 4, 1, 2, 3]),          // 5: 5-channel surround
+    (4, 2, &[0, 4, 1, 2, 3, 5]),       // 6: 5.1 surround
+    (4, 3, &[0, 4, 1, 2, 3, 5, 6]),    // 7: 6.1 surround
+    (5, 3, &[0, 6, 1, 2, 3, 4, 5, 7]), // 8: 7.1 surround
+];
+
+/// Rough approximation of the differences of log2(2^a + 2^b) in steps of 0.5.
+#[allow(clippy::excessive_precision)]
+const LOG_SUM_DIFF_TABLE: &[f32; 17] = &[
+    0.5000000, 0.2924813, 0.1609640, 0.0849625, 0.0437314, 0.0221971, 0.0111839, 0.0056136,
+    0.0028123, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0,
+];
+
+/// Configures the multistream encoder on creation.
+///
+/// The stream count, the coupled stream count and the channel mapping table
+/// need to be stored in the header of the container, so that the decoder can
+/// reconstruct the channels. Use `SurroundEncoderConfiguration` to encode
+/// surround sound with the Vorbis channel order.
+#[derive(Clone, Debug)]
+pub struct MultistreamEncoderConfiguration {
+    /// Sampling rate of the input signal (Hz). Default: 48000 kHz.
+    pub sampling_rate: SamplingRate,
+    /// The total number of streams to encode (1-255). Default: 1.
+    pub streams: usize,
+    /// Number of streams to encode as coupled (2 channel) streams. Default: 1.
+    ///
+    /// The coupled streams are the first streams in the packet.
+    pub coupled_streams: usize,
+    /// Maps the input channels to the encoded channels. Default: [0, 1].
+    ///
+    /// The length of the mapping defines the number of input channels (1-255).
+    /// The encoded channels are numbered with the left and right channels of the
+    /// coupled streams first, followed by the channels of the uncoupled streams.
+    /// 255 marks a channel that is not encoded.
+    pub mapping: Vec<u8>,
+    /// The intended application. Default: Audio.
+    pub application: Application,
+    /// The total bitrate of all streams. Default: Auto.
+    pub bitrate: Bitrate,
+    /// The computational complexity of the encoder (0-10). Default: 9.
+    pub complexity: u8,
+    /// The duration of the encoded frames. Default: 20 ms.
+    pub frame_duration: FrameDuration,
+    /// Use a variable bitrate. Default: true.
+    pub vbr: bool,
+    /// Constrain the variable bitrate, so that the bitrate doesn't
+    /// exceed the target bitrate over the duration of a few frames. Default: true.
+    pub vbr_constraint: bool,
+    /// Forces the encoder to use the given codec mode. Default: None.
+    pub force_mode: Option<CodecMode>,
+    /// Forces the encoder to use the given bandwidth. Default: None.
+    pub bandwidth: Option<Bandwidth>,
+    /// Embeds low bitrate redundancy (LBRR) data of the previous frame in Silk and
+    /// hybrid packets. Default: false.
+    pub inband_fec: bool,
+    /// The expected packet loss in percent (0-100). Default: 0.
+    pub packet_loss_percentage: u8,
+    /// Use discontinuous transmission (DTX). Default: false.
+    pub dtx: bool,
+}
+
+impl Default for MultistreamEncoderConfiguration {
+    fn default() -> Self {
+        Self {
+            sampling_rate: SamplingRate::Hz48000,
+            streams: 1,
+            coupled_streams: 1,
+            mapping: vec![0, 1],
+            application: Application::Audio,
+            bitrate: Bitrate::Auto,
+            complexity: 9,
+            frame_duration: FrameDuration::Ms20,
+            vbr: true,
+            vbr_constraint: true,
+            force_mode: None,
+            bandwidth: None,
+            inband_fec: false,
+            packet_loss_percentage: 0,
+            dtx: false,
+        }
+    }
+}
+
+/// Configures the surround encoder on creation.
+///
+/// The streams and the channel mapping are chosen by the channel mapping family:
+///
+/// * 0 - Mono or stereo in a single stream.
+/// * 1 - 1 to 8 channels in the Vorbis channel order. The encoder allocates the bitrate
+///   between the channels based on the masking of the surround mix and codes the
+///   low frequency effects channel of 5.1, 6.1 and 7.1 with a reduced bitrate.
+/// * 2 - Ambisonics with (1 + n)^2 channels in the ACN order (n = 0..14), optionally
+///   followed by two non-diegetic (head-locked) stereo channels. Each ambisonic
+///   channel is coded as an independent mono stream, the stereo channels as one
+///   coupled stream.
+/// * 255 - Up to 255 independently coded mono streams.
+#[derive(Clone, Debug)]
+pub struct SurroundEncoderConfiguration {
+    /// Sampling rate of the input signal (Hz). Default: 48000 kHz.
+    pub sampling_rate: SamplingRate,
+    /// Number of channels of the input signal (1-255). Default: 6.
+    pub channels: usize,
+    /// The channel mapping family (0, 1, 2 or 255). Default: 1.
+    pub mapping_family: u8,
+    /// The intended application. Default: Audio.
+    pub application: Application,
+    /// The total bitrate of all streams. Default: Auto.
+    pub bitrate: Bitrate,
+    /// The computational complexity of the encoder (0-10). Default: 9.
+    pub complexity: u8,
+    /// The duration of the encoded frames. Default: 20 ms.
+    pub frame_duration: FrameDuration,
+    /// Use a variable bitrate. Default: true.
+    pub vbr: bool,
+    /// Constrain the variable bitrate, so that the bitrate doesn't
+    /// exceed the target bitrate over the duration of a few frames. Default: true.
+    pub vbr_constraint: bool,
+    /// Embeds low bitrate redundancy (LBRR) data of the previous frame in Silk and
+    /// hybrid packets. Default: false.
+    pub inband_fec: bool,
+    /// The expected packet loss in percent (0-100). Default: 0.
+    pub packet_loss_percentage: u8,
+    /// Use discontinuous transmission (DTX). Default: false.
+    pub dtx: bool,
+}
+
+impl Default for SurroundEncoderConfiguration {
+    fn default() -> Self {
+        Self {
+            sampling_rate: SamplingRate::Hz48000,
+            channels: 6,
+            mapping_family: 1,
+            application: Application::Audio,
+            bitrate: Bitrate::Auto,
+            complexity: 9,
+            frame_duration: FrameDuration::Ms20,
+            vbr: true,
+            vbr_constraint: true,
+            inband_fec: false,
+            packet_loss_percentage: 0,
+            dtx: false,
+        }
+    }
+}
+
+/// Opus multistream encoder.
+///
+/// Encodes up to 255 channels into packets that contain multiple Opus streams.
+/// All but the last stream of a packet use self-delimited framing.
+#[derive(Clone, Debug)]
+pub struct MultistreamEncoder {
+    inner: MultistreamEncoderInner,
+    buffer: Vec<f32>,
+}
+
+impl MultistreamEncoder {
+    /// Creates a new `MultistreamEncoder` with the given configuration.
+    pub fn new(configuration: &MultistreamEncoderConfiguration) -> Result<Self, OpusError> {
+        let layout = ChannelLayout::new(
+            configuration.streams,
+            configuration.coupled_streams,
+            &configuration.mapping,
+        )?;
+        let encoder_configuration = EncoderConfiguration {
+            sampling_rate: configuration.sampling_rate,
+            channels: Channels::Mono,
+            application: configuration.application,
+            bitrate: Bitrate::Auto,
+            complexity: configuration.complexity,
+            frame_duration: configuration.frame_duration,
+            vbr: configuration.vbr,
+            vbr_constraint: configuration.vbr_constraint,
+            force_mode: configuration.force_mode,
+            bandwidth: configuration.bandwidth,
+            inband_fec: configuration.inband_fec,
+            packet_loss_percentage: configuration.packet_loss_percentage,
+            dtx: configuration.dtx,
+        };
+        let inner = MultistreamEncoderInner::new(
+            &encoder_configuration,
+            layout,
+            MappingType::None,
+            None,
+            configuration.bitrate,
+            None,
+        )?;
+
+        Ok(Self {
+            inner,
+            buffer: vec![],
+        })
+    }
+
+    /// Creates a new `MultistreamEncoder` for the given channel mapping family.
+    ///
+    /// The chosen streams and mapping can be queried with `streams()`,
+    /// `coupled_streams()` and `mapping()`.
+    pub fn new_surround(configuration: &SurroundEncoderConfiguration) -> Result<Self, OpusError> {
+        let channels = configuration.channels;
+        if !(1..=255).contains(&channels) {
+            return Err(OpusError::BadArguments(
+                "number of channels must be between 1 and 255",
+            ));
+        }
+
+        let mut lfe_stream = None;
+        let layout = match (configuration.mapping_family, channels) {
+            (0, 1) => ChannelLayout::new(1, 0, &[0])?,
+            (0, 2) => ChannelLayout::new(1, 1, &[0, 1])?,
+            (1, 1..=8) => {
+                let (streams, coupled_streams, mapping) = VORBIS_MAPPINGS[channels - 1];
+                if channels >= 6 {
+                    lfe_stream = Some(streams - 1);
+                }
+                ChannelLayout::new(streams, coupled_streams, mapping)?
+            }
+            (2, _) => {
+                let order_plus_one = ambisonics_order_plus_one(channels)?;
+                let coupled_streams = usize::from(channels != order_plus_one * order_plus_one);
+                let streams = order_plus_one * order_plus_one + coupled_streams;
+                // The non-diegetic channels are the last channels, but the coupled stream is coded first.
+                let mapping: Vec<u8> = (0..streams - coupled_streams)
+                    .map(|i| (i + coupled_streams * 2) as u8)
+                    .chain((0..coupled_streams * 2).map(|i| i as u8))
+                    .collect();
+                ChannelLayout::new(streams, coupled_streams, &mapping)?
+            }
+            (255, _) => {
+                let mapping: Vec<u8> = (0..channels).map(|i| i as u8).collect();
+                ChannelLayout::new(channels, 0, &mapping)?
+            }
+            _ => {
+                return Err(OpusError::BadArguments(
+                    "unsupported channel mapping family or number of channels",
+                ))
+            }
+        };
+        let mapping_type = match configuration.mapping_family {
+            1 if channels > 2 => MappingType::Surround,
+            2 => MappingType::Ambisonics,
+            _ => MappingType::None,
+        };
+
+        let encoder_configuration = EncoderConfiguration {
+            sampling_rate: configuration.sampling_rate,
+            channels: Channels::Mono,
+            application: configuration.application,
+            bitrate: Bitrate::Auto,
+            complexity: configuration.complexity,
+            frame_duration: configuration.frame_duration,
+            vbr: configuration.vbr,
+            vbr_constraint: configuration.vbr_constraint,
+            force_mode: None,
+            bandwidth: None,
+            inband_fec: configuration.inband_fec,
+            packet_loss_percentage: configuration.packet_loss_percentage,
+            dtx: configuration.dtx,
+        };
+        let inner = MultistreamEncoderInner::new(
+            &encoder_configuration,
+            layout,
+            mapping_type,
+            lfe_stream,
+            configuration.bitrate,
+            None,
+        )?;
+
+        Ok(Self {
+            inner,
+            buffer: vec![],
+        })
+    }
+
+    /// Resets the MultistreamEncoder to be equivalent to a freshly initialized encoder.
+    ///
+    /// This should be called when switching streams in order to prevent
+    /// the back to back encoding from giving different results from
+    /// one at a time encoding.
+    pub fn reset(&mut self) -> Result<(), OpusError> {
+        self.buffer = vec![];
+        self.inner.reset()
+    }
+
+    /// Returns the sampling rate the encoder was initialized with.
+    pub fn sampling_rate(&self) -> SamplingRate {
+        self.inner.sampling_rate
+    }
+
+    /// Returns the number of input channels.
+    pub fn channels(&self) -> usize {
+        self.inner.layout.channels()
+    }
+
+    /// Returns the number of streams.
+    pub fn streams(&self) -> usize {
+        self.inner.layout.streams
+    }
+
+    /// Returns the number of coupled streams.
+    pub fn coupled_streams(&self) -> usize {
+        self.inner.layout.coupled_streams
+    }
+
+    /// Returns the channel mapping table.
+    pub fn mapping(&self) -> &[u8] {
+        &self.inner.layout.mapping
+    }
+
+    /// Returns the total bitrate the encoder was initialized with.
+    pub fn bitrate(&self) -> Bitrate {
+        self.inner.bitrate
+    }
+
+    /// Returns the number of samples per channel the encoder consumes per packet.
+    pub fn frame_size(&self) -> usize {
+        self.inner.frame_size
+    }
+
+    /// Returns the bandwidth of the first stream of the last encoded packet.
+    pub fn bandwidth(&self) -> Bandwidth {
+        self.inner.encoders[0].bandwidth
+    }
+
+    /// Returns the final state of the codec's entropy coder.
+    ///
+    /// This is the combination of the final states of all streams.
+    pub fn final_range(&self) -> u32 {
+        self.inner
+            .encoders
+            .iter()
+            .fold(0, |acc, encoder| acc ^ encoder.final_range)
+    }
+
+    /// Encodes a multistream Opus packet from a generic sample input.
+    ///
+    /// Returns the length of the encoded packet in bytes.
+    ///
+    /// The internal format is `f32`. Use `encode_float()` to access it directly.
+    ///
+    /// # Arguments
+    /// * `samples` - Input signal encoded as PCM samples (interleaved).
+    ///   Length must be at least `frame_size()` * `channels`.
+    /// * `output`  - Output payload. The length of the buffer limits the size
+    ///   of the encoded packet.
+    ///
+    pub fn encode<S: InputSample>(
+        &mut self,
+        samples: &[S],
+        output: &mut [u8],
+    ) -> Result<usize, OpusError> {
+        let size = self.inner.frame_size * self.inner.layout.channels();
+        if samples.len() < size {
+            return Err(OpusError::BadArguments(
+                "samples must contain at least frame_size * channels samples",
+            ));
+        }
+
+        if self.buffer.len() < size {
+            self.buffer.resize(size, 0_f32);
+        }
+        self.buffer
+            .iter_mut()
+            .zip(samples.iter())
+            .for_each(|(x, &s)| *x = s.to_f32());
+
+        self.inner
+            .encode_native(&self.buffer[..size], output, S::LSB_DEPTH, false)
+    }
+
+    /// Encodes a multistream Opus packet from a floating point input.
+    ///
+    /// Returns the length of the encoded packet in bytes.
+    ///
+    /// # Arguments
+    /// * `samples` - Input signal encoded as PCM samples (interleaved).
+    ///   Length must be at least `frame_size()` * `channels`.
+    /// * `output`  - Output payload. The length of the buffer limits the size
+    ///   of the encoded packet.
+    ///
+    pub fn encode_float(&mut self, samples: &[f32], output: &mut [u8]) -> Result<usize, OpusError> {
+        let size = self.inner.frame_size * self.inner.layout.channels();
+        if samples.len() < size {
+            return Err(OpusError::BadArguments(
+                "samples must contain at least frame_size * channels samples",
+            ));
+        }
+
+        self.inner.encode_native(&samples[..size], output, 24, true)
+    }
+}
+
+/// Defines how the streams of a multistream encoder are coded.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum MappingType {
+    /// All streams are coded independently.
+    None,
+    /// The streams are part of a surround mix with the Vorbis channel order.
+    Surround,
+    /// The streams are the channels of an ambisonic sound field.
+    Ambisonics,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct MultistreamEncoderInner {
+    pub(crate) layout: ChannelLayout,
+    mapping_type: MappingType,
+    lfe_stream: Option<usize>,
+    pub(crate) sampling_rate: SamplingRate,
+    pub(crate) bitrate: Bitrate,
+    pub(crate) frame_size: usize,
+    pub(crate) encoders: Vec<EncoderInner>,
+    /// Mixes the input channels into the coded channels of the projection encoder.
+    mixing_matrix: Option<MappingMatrix>,
+
+    /// Window memory of the surround analysis (OVERLAP per channel).
+    window_mem: Vec<f32>,
+    /// Pre-emphasis memory of the surround analysis.
+    preemph_mem: Vec<f32>,
+    mdct: Mdct,
+
+    stream_buffer: Vec<f32>,
+    packet_buffer: Vec<u8>,
+}
+
+impl MultistreamEncoderInner {
+    /// Creates the encoders of all streams from the template `configuration`.
+    pub(crate) fn new(
+        configuration: &EncoderConfiguration,
+        layout: ChannelLayout,
+        mapping_type: MappingType,
+        lfe_stream: Option<usize>,
+        bitrate: Bitrate,
+        mixing_matrix: Option<MappingMatrix>,
+    ) -> Result<Self, OpusError> {
+        if !layout.is_encodable() {
+            return Err(OpusError::BadArguments("invalid channel mapping"));
+        }
+
+        let channels = layout.channels();
+        let bitrate = match bitrate {
+            Bitrate::BitsPerSecond(0) => {
+                return Err(OpusError::BadArguments("bitrate must not be zero"));
+            }
+            Bitrate::BitsPerSecond(bitrate) => Bitrate::BitsPerSecond(
+                bitrate.clamp(500 * channels as u32, 300_000 * channels as u32),
+            ),
+            bitrate => bitrate,
+        };
+
+        let encoders = (0..layout.streams)
+            .map(|s| {
+                let channels = if s < layout.coupled_streams {
+                    Channels::Stereo
+                } else {
+                    Channels::Mono
+                };
+                let mut encoder = EncoderInner::new(&EncoderConfiguration {
+                    channels,
+                    ..configuration.clone()
+                })?;
+                if Some(s) == lfe_stream {
+                    encoder.set_lfe(true);
+                }
+                Ok(encoder)
+            })
+            .collect::<Result<Vec<EncoderInner>, OpusError>>()?;
+
+        let frame_size = configuration
+            .frame_duration
+            .sample_count(configuration.sampling_rate);
+
+        Ok(Self {
+            layout,
+            mapping_type,
+            lfe_stream,
+            sampling_rate: configuration.sampling_rate,
+            bitrate,
+            frame_size,
+            encoders,
+            mixing_matrix,
+            window_mem: vec![0.0; channels * OVERLAP],
+            preemph_mem: vec![0.0; channels],
+            mdct: Mdct::default(),
+            stream_buffer: vec![0.0; 2 * frame_size],
+            packet_buffer: vec![0; MS_FRAME_TMP],
+        })
+    }
+
+    pub(crate) fn reset(&mut self) -> Result<(), OpusError> {
+        self.window_mem.iter_mut().for_each(|x| *x = 0.0);
+        self.preemph_mem.iter_mut().for_each(|x| *x = 0.0);
+        self.encoders
+            .iter_mut()
+            .try_for_each(|encoder| encoder.reset())
+    }
+
+    /// Computes the bitrate of each stream.
+    ///
+    /// Returns the sum of all bitrates.
+    fn rate_allocation(&self, rates: &mut [i32]) -> i32 {
+        if self.mapping_type == MappingType::Ambisonics {
+            self.ambisonics_rate_allocation(rates);
+        } else {
+            self.surround_rate_allocation(rates);
+        }
+
+        rates.iter_mut().fold(0, |rate_sum, rate| {
+            *rate = i32::max(*rate, 500);
+            rate_sum + *rate
+        })
+    }
+
+    fn ambisonics_rate_allocation(&self, rates: &mut [i32]) {
+        let fs = self.sampling_rate as i32;
+        let frame_size = self.frame_size as i32;
+        let nb_streams = self.layout.streams as i32;
+        let nb_channels = nb_streams + self.layout.coupled_streams as i32;
+
+        let total_rate = match self.bitrate {
+            Bitrate::Auto => nb_channels * (fs + 60 * fs / frame_size) + nb_streams * 15000,
+            Bitrate::Max => nb_channels * 320000,
+            Bitrate::BitsPerSecond(bitrate) => bitrate as i32,
+        };
+
+        // Allocate equal number of bits to ambisonic (uncoupled) and non-diegetic
+        // (coupled) streams.
+        let per_stream_rate = total_rate / nb_streams;
+        rates.iter_mut().for_each(|rate| *rate = per_stream_rate);
+    }
+
+    fn surround_rate_allocation(&self, rates: &mut [i32]) {
+        let fs = self.sampling_rate as i32;
+        let frame_size = self.frame_size as i32;
+        let nb_lfe = self.lfe_stream.is_some() as i32;
+        let nb_coupled = self.layout.coupled_streams as i32;
+        let nb_uncoupled = self.layout.streams as i32 - nb_coupled - nb_lfe;
+        let nb_normal = 2 * nb_coupled + nb_uncoupled;
+
+        // Give each non-LFE channel enough bits per channel for coding band energy.
+        let channel_offset = 40 * i32::max(50, fs / frame_size);
+
+        let bitrate = match self.bitrate {
+            Bitrate::Auto => nb_normal * (channel_offset + fs + 10000) + 8000 * nb_lfe,
+            Bitrate::Max => nb_normal * 300000 + nb_lfe * 128000,
+            Bitrate::BitsPerSecond(bitrate) => bitrate as i32,
+        };
+
+        // Give LFE some basic stream_channel allocation but never exceed 1/20 of the
+        // total rate for the non-energy part to avoid problems at really low rate.
+        let lfe_offset = i32::min(bitrate / 20, 3000) + 15 * i32::max(50, fs / frame_size);
+
+        // We give each stream (coupled or uncoupled) a starting bitrate.
+        // This models the main saving of coupled channels over uncoupled.
+        let stream_offset =
+            (bitrate - channel_offset * nb_normal - lfe_offset * nb_lfe) / nb_normal / 2;
+        let stream_offset = stream_offset.clamp(0, 20000);
+
+        // Coupled streams get twice the mono rate after the offset is allocated.
+        let coupled_ratio = 512;
+        // Should depend on the bitrate, for now we assume LFE gets 1/8 the bits of mono.
+        let lfe_ratio = 32;
+
+        let total = (nb_uncoupled << 8) + coupled_ratio * nb_coupled + nb_lfe * lfe_ratio;
+        let channel_rate =
+            (256 * i64::from(
+                bitrate
+                    - lfe_offset * nb_lfe
+                    - stream_offset * (nb_coupled + nb_uncoupled)
+                    - channel_offset * nb_normal,
+            ) / i64::from(total)) as i32;
+
+        rates.iter_mut().enumerate().for_each(|(i, rate)| {
+            *rate = if i < self.layout.coupled_streams {
+                2 * channel_offset
+                    + i32::max(0, stream_offset + ((channel_rate * coupled_ratio) >> 8))
+            } else if Some(i) != self.lfe_stream {
+                channel_offset + i32::max(0, stream_offset + channel_rate)
+            } else {
+                i32::max(0, lfe_offset + ((channel_rate * lfe_ratio) >> 8))
+            };
+        });
+    }
+
+    /// Encodes all streams of a frame into `data`.
+    ///
+    /// Returns the size of the multistream packet.
+    pub(crate) fn encode_native(
+        &mut self,
+        pcm: &[f32],
+        data: &mut [u8],
+        lsb_depth: i32,
+        float_api: bool,
+    ) -> Result<usize, OpusError> {
+        let fs = self.sampling_rate as usize;
+        let frame_size = self.frame_size;
+        let streams = self.layout.streams;
+        let channels = self.layout.channels();
+        let vbr = self.encoders[0].use_vbr;
+
+        // Smallest packet the encoder can produce.
+        let mut smallest_packet = streams * 2 - 1;
+        // 100 ms needs an extra byte per stream for the ToC.
+        if fs / frame_size == 10 {
+            smallest_packet += streams;
+        }
+        let mut max_data_bytes = data.len();
+        if max_data_bytes < smallest_packet {
+            return Err(OpusError::BufferToSmall);
+        }
+
+        let mut band_smr = vec![0_f32; NB_E_BANDS * channels];
+        if self.mapping_type == MappingType::Surround {
+            surround_analysis(
+                &mut self.mdct,
+                pcm,
+                &mut band_smr,
+                &mut self.window_mem,
+                &mut self.preemph_mem,
+                frame_size,
+                channels,
+                self.sampling_rate,
+            );
+        }
+
+        // Compute bitrate allocation between streams (this could be a lot better).
+        let mut bitrates = vec![0_i32; streams];
+        let rate_sum = self.rate_allocation(&mut bitrates);
+
+        if !vbr {
+            let frame_rate = fs / frame_size;
+            match self.bitrate {
+                Bitrate::Auto => {
+                    max_data_bytes =
+                        usize::min(max_data_bytes, 3 * rate_sum as usize / (3 * 8 * frame_rate));
+                }
+                Bitrate::BitsPerSecond(bitrate) => {
+                    max_data_bytes = usize::min(
+                        max_data_bytes,
+                        usize::max(smallest_packet, 3 * bitrate as usize / (3 * 8 * frame_rate)),
+                    );
+                }
+                Bitrate::Max => {}
+            }
+        }
+
+        let mapping_type = self.mapping_type;
+        let coupled_streams = self.layout.coupled_streams;
+        // The equivalent rate is derived from the allocated rate when the bitrate is
+        // chosen automatically. libopus uses the value of OPUS_AUTO here, which
+        // limits all streams to narrowband.
+        let mut equiv_rate = match self.bitrate {
+            Bitrate::BitsPerSecond(bitrate) => bitrate as i32,
+            _ => rate_sum,
+        };
+        if frame_size * 50 < fs {
+            equiv_rate -= 60 * (fs / frame_size - 50) as i32 * channels as i32;
+        }
+        self.encoders
+            .iter_mut()
+            .zip(bitrates.iter())
+            .enumerate()
+            .for_each(|(s, (encoder, &bitrate))| {
+                encoder.set_bitrate(Bitrate::BitsPerSecond(bitrate as u32));
+                if mapping_type == MappingType::Surround {
+                    let channels = channels as i32;
+                    let bandwidth = if equiv_rate > 10000 * channels {
+                        Bandwidth::Fullband
+                    } else if equiv_rate > 7000 * channels {
+                        Bandwidth::Superwideband
+                    } else if equiv_rate > 5000 * channels {
+                        Bandwidth::Wideband
+                    } else {
+                        Bandwidth::Narrowband
+                    };
+                    encoder.set_bandwidth(Some(bandwidth));
+                    if s < coupled_streams {
+                        // To preserve the spatial image, force stereo Celt on coupled streams.
+                        encoder.set_force_mode(Some(CodecMode::CeltOnly));
+                        encoder.set_force_channels(Some(Channels::Stereo));
+                    }
+                } else if mapping_type == MappingType::Ambisonics {
+                    encoder.set_force_mode(Some(CodecMode::CeltOnly));
+                }
+            });
+
+        let mut band_log_e = [0_f32; 2 * NB_E_BANDS];
+        let mut tot_size = 0;
+        for s in 0..streams {
+            let stream_channels = if s < coupled_streams {
+                let left = self.layout.left_channels(s).next().unwrap_or_default();
+                let right = self.layout.right_channels(s).next().unwrap_or_default();
+                copy_channel_in(
+                    &mut self.stream_buffer,
+                    2,
+                    pcm,
+                    channels,
+                    left,
+                    frame_size,
+                    self.mixing_matrix.as_ref(),
+                );
+                copy_channel_in(
+                    &mut self.stream_buffer[1..],
+                    2,
+                    pcm,
+                    channels,
+                    right,
+                    frame_size,
+                    self.mixing_matrix.as_ref(),
+                );
+                band_log_e[..NB_E_BANDS]
+                    .copy_from_slice(&band_smr[NB_E_BANDS * left..NB_E_BANDS * (left + 1)]);
+                band_log_e[NB_E_BANDS..]
+                    .copy_from_slice(&band_smr[NB_E_BANDS * right..NB_E_BANDS * (right + 1)]);
+                2
+            } else {
+                let chan = self.layout.mono_channels(s).next().unwrap_or_default();
+                copy_channel_in(
+                    &mut self.stream_buffer,
+                    1,
+                    pcm,
+                    channels,
+                    chan,
+                    frame_size,
+                    self.mixing_matrix.as_ref(),
+                );
+                band_log_e[..NB_E_BANDS]
+                    .copy_from_slice(&band_smr[NB_E_BANDS * chan..NB_E_BANDS * (chan + 1)]);
+                1
+            };
+
+            let encoder = &mut self.encoders[s];
+            if mapping_type == MappingType::Surround {
+                encoder.set_energy_mask(Some(&band_log_e[..NB_E_BANDS * stream_channels]));
+            }
+
+            // Number of bytes left (+Toc).
+            let mut curr_max = max_data_bytes as i32 - tot_size as i32;
+            // Reserve one byte for the last stream and two for the others.
+            curr_max -= i32::max(0, 2 * (streams - s - 1) as i32 - 1);
+            // For 100 ms, reserve an extra byte per stream for the ToC.
+            if fs / frame_size == 10 {
+                curr_max -= (streams - s - 1) as i32;
+            }
+            curr_max = i32::min(curr_max, MS_FRAME_TMP as i32);
+            // Repacketizer will add one or two bytes for self-delimited frames.
+            if s != streams - 1 {
+                curr_max -= if curr_max > 253 { 2 } else { 1 };
+            }
+            if curr_max <= 0 {
+                return Err(OpusError::BufferToSmall);
+            }
+            if !vbr && s == streams - 1 {
+                encoder.set_bitrate(Bitrate::BitsPerSecond(
+                    curr_max as u32 * (8 * fs / frame_size) as u32,
+                ));
+            }
+
+            let len = encoder.encode_native(
+                &self.stream_buffer[..frame_size * stream_channels],
+                frame_size,
+                &mut self.packet_buffer[..curr_max as usize],
+                lsb_depth,
+                float_api,
+            )?;
+
+            // We need to use the repacketizer to add the self-delimiting lengths
+            // while taking into account the fact that the encoder can now return
+            // more than one frame at a time (e.g. 60 ms Celt-only).
+            let mut rp = Repacketizer::new();
+            rp.cat(&self.packet_buffer[..len])
+                .map_err(|_| OpusError::InternalError("can't repacketize the encoded stream"))?;
+            tot_size += rp.out_range_impl(
+                0,
+                rp.frame_count(),
+                &mut data[tot_size..max_data_bytes],
+                s != streams - 1,
+                !vbr && s == streams - 1,
+                &[],
+            )?;
+        }
+
+        Ok(tot_size)
+    }
+}
+
+/// Returns the ambisonics order + 1 of the given number of channels.
+///
+/// Allowed numbers of channels are (1 + n)^2 + 2j, for n = 0...14 and j = 0 or 1.
+pub(crate) fn ambisonics_order_plus_one(channels: usize) -> Result<usize, OpusError> {
+    if !(1..=227).contains(&channels) {
+        return Err(OpusError::BadArguments(
+            "number of ambisonic channels must be between 1 and 227",
+        ));
+    }
+
+    let order_plus_one = isqrt32(channels as u32) as usize;
+    let nondiegetic_channels = channels - order_plus_one * order_plus_one;
+    if nondiegetic_channels != 0 && nondiegetic_channels != 2 {
+        return Err(OpusError::BadArguments(
+            "invalid number of ambisonic channels",
+        ));
+    }
+
+    Ok(order_plus_one)
+}
+
+/// Copies the channel `src_channel` of the interleaved `src` into `dst`.
+///
+/// If a mixing matrix is given, the channel is mixed from all channels of `src`.
+fn copy_channel_in(
+    dst: &mut [f32],
+    dst_stride: usize,
+    src: &[f32],
+    src_stride: usize,
+    src_channel: usize,
+    frame_size: usize,
+    mixing_matrix: Option<&MappingMatrix>,
+) {
+    if let Some(matrix) = mixing_matrix {
+        matrix.multiply_channel_in(src, src_stride, dst, src_channel, dst_stride, frame_size);
+    } else {
+        (0..frame_size).for_each(|i| {
+            dst[i * dst_stride] = src[i * src_stride + src_channel];
+        });
+    }
+}
+
+/// Returns the position of the channels in the mix: 0 don't mix, 1: left, 2: center, 3: right.
+fn channel_pos(channels: usize) -> [usize; 8] {
+    match channels {
+        4 => [1, 3, 1, 3, 0, 0, 0, 0],
+        3 | 5 | 6 => [1, 2, 3, 1, 3, 0, 0, 0],
+        7 => [1, 2, 3, 1, 3, 2, 0, 0],
+        8 => [1, 2, 3, 1, 3, 1, 3, 0],
+        _ => [0; 8],
+    }
+}
+
+/// Computes a rough approximation of log2(2^a + 2^b).
+fn log_sum(a: f32, b: f32) -> f32 {
+    let (max, diff) = if a > b { (a, a - b) } else { (b, b - a) };
+    // Inverted to catch NaNs.
+    #[allow(clippy::neg_cmp_op_on_partial_ord)]
+    if !(diff < 8.0) {
+        return max;
+    }
+    let low = (2.0 * diff).floor() as usize;
+    let frac = 2.0 * diff - low as f32;
+    max + LOG_SUM_DIFF_TABLE[low] + frac * (LOG_SUM_DIFF_TABLE[low + 1] - LOG_SUM_DIFF_TABLE[low])
+}
+
+/// Computes the signal to mask ratio of each band of each channel of a surround mix.
+///
+/// The masking of a channel is estimated from the energy of the other channels
+/// on the same side of the mix.
+#[allow(clippy::too_many_arguments)]
+fn surround_analysis(
+    mdct: &mut Mdct,
+    pcm: &[f32],
+    band_log_e: &mut [f32],
+    mem: &mut [f32],
+    preemph_mem: &mut [f32],
+    len: usize,
+    channels: usize,
+    sampling_rate: SamplingRate,
+) {
+    let upsample = sampling_rate.resampling_factor() as usize;
+    let frame_size = len * upsample;
+    let freq_size = usize::min(960, frame_size);
+    let nb_frames = frame_size / freq_size;
+
+    // LM = log2(frame_size / 120)
+    let lm = (0..MAX_LM)
+        .find(|&lm| SHORT_MDCT_SIZE << lm == frame_size)
+        .unwrap_or(MAX_LM);
+
+    let mut input = vec![0_f32; frame_size + OVERLAP];
+    let mut freq = vec![0_f32; freq_size];
+    let pos = channel_pos(channels);
+    let mut mask_log_e = [[-28.0_f32; NB_E_BANDS]; 3];
+
+    (0..channels).for_each(|c| {
+        input[..OVERLAP].copy_from_slice(&mem[c * OVERLAP..(c + 1) * OVERLAP]);
+        preemphasis(
+            &pcm[c..],
+            &mut input[OVERLAP..],
+            frame_size,
+            channels,
+            upsample,
+            &mut preemph_mem[c],
+            false,
+        );
+
+        // This should filter out both NaNs and ridiculous signals that could
+        // cause NaNs further down.
+        let sum = inner_prod(&input, &input, frame_size + OVERLAP);
+        #[allow(clippy::neg_cmp_op_on_partial_ord)]
+        if !(sum < 1e18) || sum.is_nan() {
+            input.iter_mut().for_each(|x| *x = 0.0);
+            preemph_mem[c] = 0.0;
+        }
+
+        let mut band_e = [0_f32; NB_E_BANDS];
+        (0..nb_frames).for_each(|frame| {
+            let mut tmp_e = [0_f32; NB_E_BANDS];
+            mdct.forward(
+                &input[960 * frame..],
+                &mut freq,
+                WINDOW,
+                OVERLAP,
+                MAX_LM - lm,
+                1,
+            );
+            if upsample != 1 {
+                let bound = freq_size / upsample;
+                freq[..bound].iter_mut().for_each(|x| *x *= upsample as f32);
+                freq[bound..].iter_mut().for_each(|x| *x = 0.0);
+            }
+            compute_band_energies(&freq, &mut tmp_e, NB_E_BANDS, 1, lm);
+            // If we have multiple frames, take the max energy.
+            band_e
+                .iter_mut()
+                .zip(tmp_e.iter())
+                .for_each(|(e, &tmp)| *e = f32::max(*e, tmp));
+        });
+
+        let band_log_e = &mut band_log_e[NB_E_BANDS * c..NB_E_BANDS * (c + 1)];
+        amp2_log2(NB_E_BANDS, NB_E_BANDS, &band_e, band_log_e, 1);
+        // Apply spreading function with -6 dB/band going up and -12 dB/band going down.
+        (1..NB_E_BANDS).for_each(|i| {
+            band_log_e[i] = f32::max(band_log_e[i], band_log_e[i - 1] - 1.0);
+        });
+        (0..NB_E_BANDS - 1).rev().for_each(|i| {
+            band_log_e[i] = f32::max(band_log_e[i], band_log_e[i + 1] - 2.0);
+        });
+
+        match pos[c] {
+            1 => (0..NB_E_BANDS).for_each(|i| {
+                mask_log_e[0][i] = log_sum(mask_log_e[0][i], band_log_e[i]);
+            }),
+            3 => (0..NB_E_BANDS).for_each(|i| {
+                mask_log_e[2][i] = log_sum(mask_log_e[2][i], band_log_e[i]);
+            }),
+            2 => (0..NB_E_BANDS).for_each(|i| {
+                mask_log_e[0][i] = log_sum(mask_log_e[0][i], band_log_e[i] - 0.5);
+                mask_log_e[2][i] = log_sum(mask_log_e[2][i], band_log_e[i] - 0.5);
+            }),
+            _ => {}
+        }
+
+        mem[c * OVERLAP..(c + 1) * OVERLAP]
+            .copy_from_slice(&input[frame_size..frame_size + OVERLAP]);
+    });
+
+    (0..NB_E_BANDS).for_each(|i| {
+        mask_log_e[1][i] = f32::min(mask_log_e[0][i], mask_log_e[2][i]);
+    });
+    let channel_offset = 0.5 * fast_log2(2.0 / (channels - 1) as f32);
+    mask_log_e
+        .iter_mut()
+        .flat_map(|mask| mask.iter_mut())
+        .for_each(|x| *x += channel_offset);
+
+    (0..channels).for_each(|c| {
+        let band_log_e = &mut band_log_e[NB_E_BANDS * c..NB_E_BANDS * (c + 1)];
+        if pos[c] != 0 {
+            let mask = &mask_log_e[pos[c] - 1];
+            band_log_e
+                .iter_mut()
+                .zip(mask.iter())
+                .for_each(|(x, &mask)| *x -= mask);
+        } else {
+            band_log_e.iter_mut().for_each(|x| *x = 0.0);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+    use crate::{MultistreamDecoder, MultistreamDecoderConfiguration};
+    use std::num::NonZeroUsize;
+
+    fn generate_signal(channels: usize, length: usize) -> Vec<f32> {
+        (0..length * channels)
+            .map(|i| {
+                let t = (i / channels) as f32 / 48000.0;
+                let f = 110.0 * (1 + i % channels) as f32;
+                0.25 * f32::sin(2.0 * std::f32::consts::PI * f * t)
+            })
+            .collect()
+    }
+
+    fn roundtrip(encoder: &mut MultistreamEncoder, frames: usize) -> Vec<usize> {
+        let channels = encoder.channels();
+        let frame_size = encoder.frame_size();
+        let mut decoder = MultistreamDecoder::new(&MultistreamDecoderConfiguration {
+            sampling_rate: encoder.sampling_rate(),
+            streams: encoder.streams(),
+            coupled_streams: encoder.coupled_streams(),
+            mapping: encoder.mapping().to_vec(),
+            gain: 0,
+        })
+        .unwrap();
+
+        let signal = generate_signal(channels, frames * frame_size);
+        let mut output = vec![0_f32; frame_size * channels];
+        (0..frames)
+            .map(|i| {
+                let mut packet = [0_u8; 4000];
+                let len = encoder
+                    .encode_float(
+                        &signal[i * frame_size * channels..(i + 1) * frame_size * channels],
+                        &mut packet,
+                    )
+                    .unwrap();
+                let count = decoder
+                    .decode_float(
+                        Some(&packet[..len]),
+                        &mut output,
+                        NonZeroUsize::new(frame_size).unwrap(),
+                        false,
+                    )
+                    .unwrap();
+                assert_eq!(count, frame_size);
+                assert_eq!(decoder.final_range(), encoder.final_range());
+                assert!(output.iter().all(|x| x.is_finite()));
+                len
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_multistream_encoder() {
+        let configuration = MultistreamEncoderConfiguration {
+            streams: 3,
+            coupled_streams: 1,
+            mapping: vec![0, 2, 1, 3],
+            bitrate: Bitrate::BitsPerSecond(128000),
+            ..Default::default()
+        };
+        let mut encoder = MultistreamEncoder::new(&configuration).unwrap();
+        assert_eq!(encoder.channels(), 4);
+        assert_eq!(encoder.frame_size(), 960);
+        roundtrip(&mut encoder, 10);
+
+        let mut packet = [0_u8; 4000];
+        let samples = vec![0_i16; 960 * 4];
+        assert!(encoder.encode(&samples, &mut packet).unwrap() > 0);
+        assert!(encoder.encode(&samples[..960], &mut packet).is_err());
+        assert!(encoder.encode(&samples, &mut packet[..4]).is_err());
+        encoder.reset().unwrap();
+    }
+
+    #[test]
+    fn test_surround_encoder() {
+        let configuration = SurroundEncoderConfiguration::default();
+        let mut encoder = MultistreamEncoder::new_surround(&configuration).unwrap();
+        assert_eq!(encoder.streams(), 4);
+        assert_eq!(encoder.coupled_streams(), 2);
+        assert_eq!(encoder.mapping(), &[0, 4, 1, 2, 3, 5]);
+        roundtrip(&mut encoder, 10);
+
+        // The coupled streams are coded with Celt and the LFE is narrowband.
+        assert_eq!(encoder.inner.encoders[0].mode, CodecMode::CeltOnly);
+        assert_eq!(encoder.inner.encoders[1].mode, CodecMode::CeltOnly);
+        assert_eq!(encoder.inner.encoders[3].bandwidth, Bandwidth::Narrowband);
+        assert_eq!(encoder.bandwidth(), Bandwidth::Fullband);
+
+        let configuration = SurroundEncoderConfiguration {
+            channels: 8,
+            frame_duration: FrameDuration::Ms10,
+            bitrate: Bitrate::BitsPerSecond(256000),
+            ..Default::default()
+        };
+        let mut encoder = MultistreamEncoder::new_surround(&configuration).unwrap();
+        assert_eq!(encoder.streams(), 5);
+        assert_eq!(encoder.coupled_streams(), 3);
+        roundtrip(&mut encoder, 10);
+
+        let configuration = SurroundEncoderConfiguration {
+            channels: 3,
+            mapping_family: 255,
+            ..Default::default()
+        };
+        let mut encoder = MultistreamEncoder::new_surround(&configuration).unwrap();
+        assert_eq!(encoder.streams(), 3);
+        assert_eq!(encoder.coupled_streams(), 0);
+        assert_eq!(encoder.mapping(), &[0, 1, 2]);
+        roundtrip(&mut encoder, 5);
+    }
+
+    #[test]
+    fn test_ambisonics_encoder() {
+        // Second-order ambisonics with two non-diegetic channels.
+        let configuration = SurroundEncoderConfiguration {
+            channels: 11,
+            mapping_family: 2,
+            ..Default::default()
+        };
+        let mut encoder = MultistreamEncoder::new_surround(&configuration).unwrap();
+        assert_eq!(encoder.streams(), 10);
+        assert_eq!(encoder.coupled_streams(), 1);
+        assert_eq!(encoder.mapping(), &[2, 3, 4, 5, 6, 7, 8, 9, 10, 0, 1]);
+        roundtrip(&mut encoder, 5);
+        assert!(encoder
+            .inner
+            .encoders
+            .iter()
+            .all(|encoder| encoder.mode == CodecMode::CeltOnly));
+
+        assert_eq!(ambisonics_order_plus_one(1).unwrap(), 1);
+        assert_eq!(ambisonics_order_plus_one(6).unwrap(), 2);
+        assert_eq!(ambisonics_order_plus_one(227).unwrap(), 15);
+        assert!(ambisonics_order_plus_one(0).is_err());
+        assert!(ambisonics_order_plus_one(5).is_err());
+        assert!(ambisonics_order_plus_one(228).is_err());
+    }
+
+    #[test]
+    fn test_surround_encoder_cbr() {
+        let configuration = SurroundEncoderConfiguration {
+            bitrate: Bitrate::BitsPerSecond(192000),
+            vbr: false,
+            ..Default::default()
+        };
+        let mut encoder = MultistreamEncoder::new_surround(&configuration).unwrap();
+        let sizes = roundtrip(&mut encoder, 10);
+        assert!(sizes.iter().all(|&len| len == 192000 / 8 / 50));
+    }
+
+    #[test]
+    fn test_invalid_configuration() {
+        // The second stream has no input channel.
+        let configuration = MultistreamEncoderConfiguration {
+            streams: 2,
+            coupled_streams: 0,
+            mapping: vec![0, 255],
+            ..Default::default()
+        };
+        assert!(MultistreamEncoder::new(&configuration).is_err());
+
+        let configuration = MultistreamEncoderConfiguration {
+            bitrate: Bitrate::BitsPerSecond(0),
+            ..Default::default()
+        };
+        assert!(MultistreamEncoder::new(&configuration).is_err());
+
+        let configuration = SurroundEncoderConfiguration {
+            channels: 3,
+            mapping_family: 0,
+            ..Default::default()
+        };
+        assert!(MultistreamEncoder::new_surround(&configuration).is_err());
+
+        let configuration = SurroundEncoderConfiguration {
+            channels: 9,
+            mapping_family: 1,
+            ..Default::default()
+        };
+        assert!(MultistreamEncoder::new_surround(&configuration).is_err());
+
+        let configuration = SurroundEncoderConfiguration {
+            channels: 7,
+            mapping_family: 2,
+            ..Default::default()
+        };
+        assert!(MultistreamEncoder::new_surround(&configuration).is_err());
+    }
+}
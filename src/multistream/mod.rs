@@ -0,0 +1,140 @@
+//! Implements the multistream decoder and encoder.
+//!
+//! A multistream packet contains multiple Opus streams, which are decoded independently
+//! and then mapped to the output channels. All but the last stream use self-delimited
+//! framing.
+
+pub use decoder::*;
+pub use encoder::*;
+
+use crate::OpusError;
+
+mod decoder;
+mod encoder;
+
+/// The layout of the channels of a multistream packet.
+#[derive(Clone, Debug)]
+pub(crate) struct ChannelLayout {
+    /// Number of streams.
+    pub(crate) streams: usize,
+    /// Number of streams that are coupled stereo streams.
+    pub(crate) coupled_streams: usize,
+    /// Maps each channel to a decoded channel. 255 marks a muted channel.
+    pub(crate) mapping: Vec<u8>,
+}
+
+impl ChannelLayout {
+    /// Creates a new layout and validates it.
+    pub(crate) fn new(
+        streams: usize,
+        coupled_streams: usize,
+        mapping: &[u8],
+    ) -> Result<Self, OpusError> {
+        if mapping.is_empty() || mapping.len() > 255 {
+            return Err(OpusError::BadArguments(
+                "number of channels must be between 1 and 255",
+            ));
+        }
+        if streams < 1 || coupled_streams > streams || streams + coupled_streams > 255 {
+            return Err(OpusError::BadArguments("invalid number of streams"));
+        }
+
+        let max_channel = streams + coupled_streams;
+        if mapping
+            .iter()
+            .any(|&x| x != 255 && usize::from(x) >= max_channel)
+        {
+            return Err(OpusError::BadArguments("invalid channel mapping"));
+        }
+
+        Ok(Self {
+            streams,
+            coupled_streams,
+            mapping: mapping.to_vec(),
+        })
+    }
+
+    /// Returns the number of channels.
+    pub(crate) fn channels(&self) -> usize {
+        self.mapping.len()
+    }
+
+    /// Returns the channels that are mapped to the left channel of the coupled stream `stream_id`.
+    pub(crate) fn left_channels(&self, stream_id: usize) -> impl Iterator<Item = usize> + '_ {
+        self.channels_of(stream_id * 2)
+    }
+
+    /// Returns the channels that are mapped to the right channel of the coupled stream `stream_id`.
+    pub(crate) fn right_channels(&self, stream_id: usize) -> impl Iterator<Item = usize> + '_ {
+        self.channels_of(stream_id * 2 + 1)
+    }
+
+    /// Returns the channels that are mapped to the uncoupled stream `stream_id`.
+    pub(crate) fn mono_channels(&self, stream_id: usize) -> impl Iterator<Item = usize> + '_ {
+        self.channels_of(stream_id + self.coupled_streams)
+    }
+
+    /// Returns the channels that are muted.
+    pub(crate) fn muted_channels(&self) -> impl Iterator<Item = usize> + '_ {
+        self.channels_of(255)
+    }
+
+    /// Returns true if every stream has at least one channel it can be encoded from.
+    pub(crate) fn is_encodable(&self) -> bool {
+        (0..self.streams).all(|s| {
+            if s < self.coupled_streams {
+                self.left_channels(s).next().is_some() && self.right_channels(s).next().is_some()
+            } else {
+                self.mono_channels(s).next().is_some()
+            }
+        })
+    }
+
+    fn channels_of(&self, decoded_channel: usize) -> impl Iterator<Item = usize> + '_ {
+        self.mapping
+            .iter()
+            .enumerate()
+            .filter(move |(_, &x)| usize::from(x) == decoded_channel)
+            .map(|(i, _)| i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+
+    #[test]
+    fn test_channel_layout() {
+        // 5.1 surround: 2 coupled streams and 2 mono streams.
+        let layout = ChannelLayout::new(4, 2, &[0, 4, 1, 2, 3, 5]).unwrap();
+        assert_eq!(layout.channels(), 6);
+        assert_eq!(layout.left_channels(0).collect::<Vec<_>>(), vec![0]);
+        assert_eq!(layout.right_channels(0).collect::<Vec<_>>(), vec![2]);
+        assert_eq!(layout.left_channels(1).collect::<Vec<_>>(), vec![3]);
+        assert_eq!(layout.right_channels(1).collect::<Vec<_>>(), vec![4]);
+        assert_eq!(layout.mono_channels(2).collect::<Vec<_>>(), vec![1]);
+        assert_eq!(layout.mono_channels(3).collect::<Vec<_>>(), vec![5]);
+        assert_eq!(layout.muted_channels().count(), 0);
+        assert!(layout.is_encodable());
+
+        // A decoded channel can be mapped to multiple channels.
+        let layout = ChannelLayout::new(1, 0, &[0, 255, 0]).unwrap();
+        assert_eq!(layout.mono_channels(0).collect::<Vec<_>>(), vec![0, 2]);
+        assert_eq!(layout.muted_channels().collect::<Vec<_>>(), vec![1]);
+        assert!(layout.is_encodable());
+
+        // The second stream has no channel to be encoded from.
+        let layout = ChannelLayout::new(2, 0, &[0, 255]).unwrap();
+        assert!(!layout.is_encodable());
+    }
+
+    #[test]
+    fn test_invalid_channel_layout() {
+        assert!(ChannelLayout::new(1, 0, &[]).is_err());
+        assert!(ChannelLayout::new(0, 0, &[0]).is_err());
+        assert!(ChannelLayout::new(1, 2, &[0]).is_err());
+        assert!(ChannelLayout::new(200, 100, &[0]).is_err());
+        assert!(ChannelLayout::new(1, 1, &[0, 2]).is_err());
+    }
+}
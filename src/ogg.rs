@@ -1 +0,0 @@
-//! Implement the reading and writing of Opus inside Ogg containers.
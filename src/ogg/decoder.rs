@@ -0,0 +1,535 @@
+//! Implements the decoder of Ogg Opus streams.
+
+use std::io::{Read, Seek};
+use std::num::NonZeroUsize;
+
+use crate::{
+    Decoder, MultistreamDecoder, OggOpusReader, OpusError, OpusHead, OpusTags, ProjectionDecoder,
+    Sample, SamplingRate,
+};
+
+/// Number of samples that are decoded before the target of a seek, so that the
+/// decoder state converges (80 ms, as recommended by RFC 7845).
+const PRE_ROLL: u64 = 3840;
+
+/// Maximum number of samples per channel of a packet (120 ms).
+const MAX_FRAME_SIZE: usize = 5760;
+
+/// The decoder that matches the channel mapping family of the stream.
+#[derive(Clone, Debug)]
+enum StreamDecoder {
+    Single(Box<Decoder>),
+    Multistream(MultistreamDecoder),
+    Projection(ProjectionDecoder),
+}
+
+impl StreamDecoder {
+    fn new(head: &OpusHead) -> Result<Self, OpusError> {
+        let sampling_rate = SamplingRate::Hz48000;
+        match head.mapping_family {
+            0 => Ok(StreamDecoder::Single(Box::new(Decoder::new(
+                &head.decoder_configuration(sampling_rate)?,
+            )?))),
+            3 => Ok(StreamDecoder::Projection(ProjectionDecoder::new(
+                &head.projection_decoder_configuration(sampling_rate)?,
+            )?)),
+            _ => Ok(StreamDecoder::Multistream(MultistreamDecoder::new(
+                &head.multistream_decoder_configuration(sampling_rate)?,
+            )?)),
+        }
+    }
+
+    fn reset(&mut self) -> Result<(), OpusError> {
+        match self {
+            StreamDecoder::Single(decoder) => decoder.reset(),
+            StreamDecoder::Multistream(decoder) => decoder.reset(),
+            StreamDecoder::Projection(decoder) => decoder.reset(),
+        }
+    }
+
+    fn decode_float(&mut self, packet: &[u8], samples: &mut [f32]) -> Result<usize, OpusError> {
+        let frame_size = NonZeroUsize::new(MAX_FRAME_SIZE)
+            .ok_or(OpusError::InternalError("frame size is zero"))?;
+        match self {
+            StreamDecoder::Single(decoder) => {
+                decoder.decode_float(Some(packet), samples, frame_size, false)
+            }
+            StreamDecoder::Multistream(decoder) => {
+                decoder.decode_float(Some(packet), samples, frame_size, false)
+            }
+            StreamDecoder::Projection(decoder) => {
+                decoder.decode_float(Some(packet), samples, frame_size, false)
+            }
+        }
+    }
+}
+
+/// Decodes Ogg Opus streams.
+///
+/// The output is always sampled at 48 kHz. The pre-skip and the end trimming of the
+/// stream are applied, so that the output matches the original input sample by sample.
+/// Positions are counted in samples per channel from the start of the output of the
+/// current stream.
+///
+/// The streams of chained containers are decoded one after another. A single call of
+/// `decode()` never returns samples of two streams. The channel count can change at
+/// the start of a stream, so it needs to be checked with `channels()` after decoding.
+#[derive(Debug)]
+pub struct OggOpusDecoder<R: Read> {
+    reader: OggOpusReader<R>,
+    decoder: StreamDecoder,
+    /// The header the decoder was created for.
+    decoder_head: OpusHead,
+    channels: usize,
+    buffer: Vec<f32>,
+    buffer_start: usize,
+    buffer_end: usize,
+    /// Granule position of the next output sample.
+    granule_position: u64,
+    /// Granule position at the end of the last decoded packet.
+    packet_end: Option<u64>,
+}
+
+impl<R: Read> OggOpusDecoder<R> {
+    /// Creates a new `OggOpusDecoder` and reads the headers of the stream.
+    pub fn new(reader: R) -> Result<Self, OpusError> {
+        let reader = OggOpusReader::new(reader)?;
+        let decoder = StreamDecoder::new(reader.head())?;
+        let channels = reader.head().channels;
+        let granule_position = u64::from(reader.head().pre_skip);
+
+        Ok(Self {
+            decoder_head: reader.head().clone(),
+            reader,
+            decoder,
+            channels,
+            buffer: vec![0.0; MAX_FRAME_SIZE * channels],
+            buffer_start: 0,
+            buffer_end: 0,
+            granule_position,
+            packet_end: None,
+        })
+    }
+
+    /// Returns the identification header of the stream.
+    pub fn head(&self) -> &OpusHead {
+        self.reader.head()
+    }
+
+    /// Returns the comment header of the stream.
+    pub fn tags(&self) -> &OpusTags {
+        self.reader.tags()
+    }
+
+    /// Returns the number of output channels.
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// Returns the index of the current stream of a chained container, starting at 0.
+    pub fn link(&self) -> usize {
+        self.reader.link()
+    }
+
+    /// Returns the position of the next output sample in the current stream.
+    pub fn position(&self) -> u64 {
+        self.granule_position
+            .saturating_sub(u64::from(self.reader.head().pre_skip))
+    }
+
+    /// Decodes the next samples from a generic sample output.
+    ///
+    /// Returns the number of decoded samples per channel. Returns 0 at the end of the stream.
+    ///
+    /// The internal format is `f32`. Use `decode_float()` to access it directly.
+    ///
+    /// # Arguments
+    /// * `samples` - Output signal encoded as PCM samples (interleaved if more than 1 channel).
+    ///   Length must be at least `channels()`.
+    ///
+    pub fn decode<S: Sample>(&mut self, samples: &mut [S]) -> Result<usize, OpusError> {
+        if samples.len() < self.channels {
+            return Err(OpusError::BadArguments(
+                "samples must contain at least one sample per channel",
+            ));
+        }
+
+        while self.buffer_start == self.buffer_end {
+            if !self.decode_packet()? {
+                return Ok(0);
+            }
+        }
+
+        // The channel count might have changed at the start of a new stream.
+        let frame_size = samples.len() / self.channels;
+        if frame_size == 0 {
+            return Err(OpusError::BadArguments(
+                "samples must contain at least one sample per channel",
+            ));
+        }
+
+        let count = usize::min(frame_size, self.buffer_end - self.buffer_start);
+        let buffer = &self.buffer
+            [self.buffer_start * self.channels..(self.buffer_start + count) * self.channels];
+        samples
+            .iter_mut()
+            .zip(buffer)
+            .for_each(|(sample, &x)| *sample = S::from_f32(x));
+        self.buffer_start += count;
+        self.granule_position += count as u64;
+
+        Ok(count)
+    }
+
+    /// Decodes the next samples.
+    ///
+    /// Returns the number of decoded samples per channel. Returns 0 at the end of the stream.
+    ///
+    /// # Arguments
+    /// * `samples` - Output signal encoded as PCM samples (interleaved if more than 1 channel).
+    ///   Length must be at least `channels()`.
+    ///
+    pub fn decode_float(&mut self, samples: &mut [f32]) -> Result<usize, OpusError> {
+        self.decode(samples)
+    }
+
+    /// Decodes the next packet into the buffer. Continues with the next stream of a
+    /// chained container. Returns false at the end of the container.
+    fn decode_packet(&mut self) -> Result<bool, OpusError> {
+        let packet = loop {
+            match self.reader.read_packet()? {
+                Some(packet) => break packet,
+                None if self.reader.next_stream()? => self.start_stream()?,
+                None => return Ok(false),
+            }
+        };
+        let count = self.decoder.decode_float(&packet.data, &mut self.buffer)? as u64;
+
+        // The end of the stream can be trimmed, so the packet doesn't start before the end
+        // of the previous packet.
+        let start = packet.granule_position.saturating_sub(count);
+        let start = self
+            .packet_end
+            .map_or(start, |previous| u64::max(start, previous));
+        let end = u64::min(packet.granule_position, start + count).max(start);
+        self.packet_end = Some(packet.granule_position);
+
+        // Discard the samples before the current position, which is the end of the
+        // pre-skip or the target of a seek.
+        self.granule_position = u64::max(self.granule_position, start);
+        self.buffer_start = u64::min(self.granule_position, end).saturating_sub(start) as usize;
+        self.buffer_end = (end - start) as usize;
+
+        Ok(true)
+    }
+
+    /// Prepares the decoding of a new stream. The decoder is only recreated if the
+    /// channel configuration changed, otherwise it is reset.
+    fn start_stream(&mut self) -> Result<(), OpusError> {
+        let head = self.reader.head();
+        let changed = head.channels != self.decoder_head.channels
+            || head.output_gain != self.decoder_head.output_gain
+            || head.mapping_family != self.decoder_head.mapping_family
+            || head.streams != self.decoder_head.streams
+            || head.coupled_streams != self.decoder_head.coupled_streams
+            || head.mapping != self.decoder_head.mapping
+            || head.demixing_matrix != self.decoder_head.demixing_matrix;
+        if changed {
+            self.decoder = StreamDecoder::new(head)?;
+            self.channels = head.channels;
+            self.buffer = vec![0.0; MAX_FRAME_SIZE * self.channels];
+            self.decoder_head = head.clone();
+        } else {
+            self.decoder.reset()?;
+        }
+
+        self.buffer_start = 0;
+        self.buffer_end = 0;
+        self.granule_position = u64::from(head.pre_skip);
+        self.packet_end = None;
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> OggOpusDecoder<R> {
+    /// Seeks to the given sample position in the current stream.
+    ///
+    /// Decoding starts at least 80 ms before the position and the samples before the
+    /// position are discarded, so the output continues exactly at the position.
+    ///
+    /// # Arguments
+    /// * `sample_position` - Position of the next output sample (48 kHz samples per channel).
+    ///
+    pub fn seek(&mut self, sample_position: u64) -> Result<(), OpusError> {
+        let target = u64::from(self.reader.head().pre_skip) + sample_position;
+        self.reader.seek(target.saturating_sub(PRE_ROLL))?;
+        self.decoder.reset()?;
+
+        self.buffer_start = 0;
+        self.buffer_end = 0;
+        self.granule_position = target;
+        self.packet_end = None;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::panic)]
+    #![allow(clippy::unwrap_used)]
+
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        Channels, DecoderConfiguration, Encoder, EncoderConfiguration, OggOpusWriter,
+        OggOpusWriterConfiguration,
+    };
+
+    const FRAMES: usize = 150;
+    const END_TRIM: u64 = 700;
+
+    fn signal(i: usize) -> f32 {
+        // A chirp, so that a misaligned output doesn't correlate.
+        let t = i as f32 / 48000.0;
+        (t * (200.0 + 400.0 * t) * std::f32::consts::TAU).sin() * 0.5
+    }
+
+    fn encode_stream() -> Vec<u8> {
+        encode_link(vec![], Channels::Stereo, 0)
+    }
+
+    /// Appends a stream to a chained container.
+    fn encode_link(data: Vec<u8>, channels: Channels, serial: u32) -> Vec<u8> {
+        let mut encoder = Encoder::new(&EncoderConfiguration {
+            channels,
+            ..EncoderConfiguration::default()
+        })
+        .unwrap();
+        let channels = channels as usize;
+        let head = OpusHead {
+            channels,
+            pre_skip: encoder.lookahead() as u16,
+            coupled_streams: channels - 1,
+            mapping: (0..channels as u8).collect(),
+            ..OpusHead::default()
+        };
+        let mut writer = OggOpusWriter::new(
+            data,
+            &head,
+            &OpusTags::default(),
+            &OggOpusWriterConfiguration {
+                serial,
+                page_duration: Duration::from_millis(100),
+            },
+        )
+        .unwrap();
+
+        let mut output = [0_u8; 1500];
+        (0..FRAMES).for_each(|i| {
+            let samples: Vec<f32> = (0..960 * channels)
+                .map(|j| signal(i * 960 + j / channels))
+                .collect();
+            let len = encoder.encode_float(&samples, &mut output).unwrap();
+            writer.write_packet(&output[..len]).unwrap();
+        });
+        writer.finish(END_TRIM).unwrap()
+    }
+
+    fn decode_all<R: Read>(decoder: &mut OggOpusDecoder<R>) -> Vec<f32> {
+        let mut output = vec![];
+        let mut samples = [0_f32; 1000];
+        loop {
+            let count = decoder.decode_float(&mut samples).unwrap();
+            if count == 0 {
+                break;
+            }
+            output.extend_from_slice(&samples[..count * 2]);
+        }
+        output
+    }
+
+    #[test]
+    fn test_decode_stream() {
+        let data = encode_stream();
+        let mut decoder = OggOpusDecoder::new(data.as_slice()).unwrap();
+        assert_eq!(decoder.channels(), 2);
+        assert_eq!(decoder.position(), 0);
+
+        let output = decode_all(&mut decoder);
+        let expected = FRAMES * 960 - 312 - END_TRIM as usize;
+        assert_eq!(output.len(), expected * 2);
+        assert_eq!(decoder.position(), expected as u64);
+
+        // The pre-skip aligns the output with the input.
+        let error: f32 = (48000..48960)
+            .map(|i| (output[i * 2] - signal(i)).abs())
+            .sum::<f32>()
+            / 960.0;
+        assert!(error < 0.05, "error: {}", error);
+    }
+
+    #[test]
+    fn test_trimmed_single_page() {
+        let mut encoder = Encoder::new(&EncoderConfiguration::default()).unwrap();
+        let mut decoder = Decoder::new(&DecoderConfiguration::default()).unwrap();
+        let pre_skip = encoder.lookahead();
+        let mut output = [0_u8; 1500];
+        let mut reference = vec![];
+        let packets: Vec<Vec<u8>> = (0..3)
+            .map(|i| {
+                let samples: Vec<f32> = (0..960 * 2).map(|j| signal(i * 960 + j / 2)).collect();
+                let len = encoder.encode_float(&samples, &mut output).unwrap();
+                let mut decoded = vec![0_f32; 960 * 2];
+                decoder
+                    .decode_float(
+                        Some(&output[..len]),
+                        &mut decoded,
+                        NonZeroUsize::new(960).unwrap(),
+                        false,
+                    )
+                    .unwrap();
+                reference.extend_from_slice(&decoded);
+                output[..len].to_vec()
+            })
+            .collect();
+
+        // The trim is shorter and longer than the last packet.
+        [500_u64, 1500].iter().for_each(|&end_trim| {
+            let head = OpusHead {
+                pre_skip: pre_skip as u16,
+                ..OpusHead::default()
+            };
+            let mut writer = OggOpusWriter::new(
+                vec![],
+                &head,
+                &OpusTags::default(),
+                &OggOpusWriterConfiguration::default(),
+            )
+            .unwrap();
+            packets
+                .iter()
+                .for_each(|packet| writer.write_packet(packet).unwrap());
+            let data = writer.finish(end_trim).unwrap();
+
+            let output = decode_all(&mut OggOpusDecoder::new(data.as_slice()).unwrap());
+            let expected = 3 * 960 - pre_skip - end_trim as usize;
+            assert_eq!(output.len(), expected * 2);
+            assert_eq!(
+                output,
+                reference[pre_skip * 2..(pre_skip + expected) * 2].to_vec()
+            );
+        });
+    }
+
+    #[test]
+    fn test_chained_streams() {
+        let data = encode_stream();
+        let reference = decode_all(&mut OggOpusDecoder::new(data.as_slice()).unwrap());
+        let data = encode_link(data, Channels::Stereo, 1);
+        let data = encode_link(data, Channels::Mono, 2);
+
+        let mut decoder = OggOpusDecoder::new(data.as_slice()).unwrap();
+        let mut samples = [0_f32; 1000];
+        let mut outputs = vec![(2, vec![])];
+        loop {
+            let count = decoder.decode_float(&mut samples).unwrap();
+            if count == 0 {
+                break;
+            }
+            if decoder.link() == outputs.len() {
+                outputs.push((decoder.channels(), vec![]));
+            }
+            let (channels, output) = outputs.last_mut().unwrap();
+            assert_eq!(*channels, decoder.channels());
+            output.extend_from_slice(&samples[..count * decoder.channels()]);
+        }
+
+        let expected = FRAMES * 960 - 312 - END_TRIM as usize;
+        assert_eq!(outputs.len(), 3);
+        assert_eq!(outputs[0], (2, reference.clone()));
+        // The decoder is reset for each stream.
+        assert_eq!(outputs[1], (2, reference));
+        // The decoder is recreated for a different channel count.
+        assert_eq!(outputs[2].0, 1);
+        assert_eq!(outputs[2].1.len(), expected);
+    }
+
+    #[test]
+    fn test_seek() {
+        let data = encode_stream();
+        let reference = decode_all(&mut OggOpusDecoder::new(data.as_slice()).unwrap());
+        let mut decoder = OggOpusDecoder::new(Cursor::new(data)).unwrap();
+
+        // Seeking to the start is identical to a fresh decoder.
+        decoder.seek(0).unwrap();
+        assert_eq!(decode_all(&mut decoder), reference);
+
+        // 47_688 is exactly on a page boundary (granule position 48_000 with 312 samples
+        // of pre-skip), the other positions are inside of pages.
+        [100_000_usize, 47_688, 5, 3000, 96_017, 130_000, 142_000]
+            .iter()
+            .for_each(|&position| {
+                decoder.seek(position as u64).unwrap();
+                assert_eq!(decoder.position(), position as u64);
+
+                let mut output = vec![];
+                let mut samples = [0_f32; 500 * 2];
+                while output.len() < 10_000 * 2 {
+                    let count = decoder.decode_float(&mut samples).unwrap();
+                    if count == 0 {
+                        break;
+                    }
+                    output.extend_from_slice(&samples[..count * 2]);
+                }
+                let count = output.len() / 2;
+                assert!(count > 0);
+                assert_eq!(decoder.position(), (position + count) as u64);
+
+                let expected = |shift: usize| &reference[(position + shift - 1) * 2..];
+                if position + 312 < PRE_ROLL as usize {
+                    // The pre-roll starts at the beginning of the stream.
+                    assert_eq!(output.as_slice(), &expected(1)[..count * 2]);
+                    return;
+                }
+
+                // The decoder state converges during the first frames after the pre-roll.
+                let rms = |output: &[f32], expected: &[f32]| {
+                    let sum: f32 = output
+                        .iter()
+                        .zip(expected)
+                        .map(|(a, b)| (a - b) * (a - b))
+                        .sum();
+                    (sum / output.len() as f32).sqrt()
+                };
+                let start = &output[..usize::min(count, 2000) * 2];
+                let error = rms(start, expected(1));
+                assert!(error < 0.05, "position: {}, error: {}", position, error);
+                assert!(error < rms(start, expected(0)), "position: {}", position);
+                assert!(error < rms(start, expected(2)), "position: {}", position);
+                let max = start
+                    .iter()
+                    .zip(expected(1))
+                    .map(|(a, b)| (a - b).abs())
+                    .fold(0.0, f32::max);
+                assert!(max < 0.15, "position: {}, max: {}", position, max);
+
+                // Afterwards, the output matches the linear decode.
+                if count == 10_000 {
+                    let max = output[8000 * 2..]
+                        .iter()
+                        .zip(&expected(1)[8000 * 2..])
+                        .map(|(a, b)| (a - b).abs())
+                        .fold(0.0, f32::max);
+                    assert!(max < 1e-3, "position: {}, max: {}", position, max);
+                }
+            });
+
+        // Seeking past the end yields no samples.
+        decoder.seek(1_000_000).unwrap();
+        let mut samples = [0_f32; 960 * 2];
+        assert_eq!(decoder.decode_float(&mut samples).unwrap(), 0);
+    }
+}
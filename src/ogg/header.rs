@@ -0,0 +1,358 @@
+//! Implements the identification header "OpusHead".
+
+use std::convert::TryFrom;
+
+use crate::ogg::page::{read_u16, read_u32};
+use crate::{
+    Channels, DecoderConfiguration, MultistreamDecoderConfiguration, OpusError,
+    ProjectionDecoderConfiguration, SamplingRate,
+};
+
+/// The identification header of an Ogg Opus stream.
+///
+/// Describes how the packets of the stream need to be decoded.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OpusHead {
+    /// Version of the header. Default: 1.
+    ///
+    /// The upper four bits contain the major version, which needs to be 0.
+    pub version: u8,
+    /// Number of output channels (1-255). Default: 2.
+    pub channels: usize,
+    /// Number of samples at 48 kHz that need to be discarded from the start of the
+    /// decoder output. Default: 0.
+    pub pre_skip: u16,
+    /// Sampling rate of the original input (Hz). This is only informational. Default: 48000.
+    pub input_sampling_rate: u32,
+    /// Gain to apply to the decoder output in Q8 dB units. Default: 0.
+    pub output_gain: i16,
+    /// Channel mapping family. Default: 0.
+    ///
+    /// * 0   - Mono or stereo with a single stream.
+    /// * 1   - Up to 8 channels in the Vorbis channel order.
+    /// * 2   - Ambisonics with a channel mapping table.
+    /// * 3   - Ambisonics with a demixing matrix.
+    /// * 255 - Undefined channel order.
+    pub mapping_family: u8,
+    /// The total number of streams in each packet (1-255). Default: 1.
+    pub streams: usize,
+    /// Number of streams that are coupled (2 channel) streams. Default: 1.
+    pub coupled_streams: usize,
+    /// Maps the output channels to the decoded channels. Default: [0, 1].
+    ///
+    /// Empty for the channel mapping family 3.
+    pub mapping: Vec<u8>,
+    /// The demixing matrix of the channel mapping family 3. Default: empty.
+    ///
+    /// See [`ProjectionDecoderConfiguration::demixing_matrix`] for the layout.
+    pub demixing_matrix: Vec<u8>,
+}
+
+impl Default for OpusHead {
+    fn default() -> Self {
+        Self {
+            version: 1,
+            channels: 2,
+            pre_skip: 0,
+            input_sampling_rate: 48000,
+            output_gain: 0,
+            mapping_family: 0,
+            streams: 1,
+            coupled_streams: 1,
+            mapping: vec![0, 1],
+            demixing_matrix: vec![],
+        }
+    }
+}
+
+impl OpusHead {
+    /// Parses and validates an "OpusHead" packet.
+    pub(crate) fn parse(data: &[u8]) -> Result<Self, OpusError> {
+        if data.len() < 19 || &data[..8] != b"OpusHead" {
+            return Err(OpusError::InvalidContainer("missing OpusHead"));
+        }
+        let version = data[8];
+        if version & 0xF0 != 0 {
+            return Err(OpusError::InvalidContainer("unsupported OpusHead version"));
+        }
+
+        let channels = usize::from(data[9]);
+        let mapping_family = data[18];
+        let mut head = Self {
+            version,
+            channels,
+            pre_skip: read_u16(&data[10..12]),
+            input_sampling_rate: read_u32(&data[12..16]),
+            output_gain: read_u16(&data[16..18]) as i16,
+            mapping_family,
+            streams: 1,
+            coupled_streams: channels.saturating_sub(1),
+            mapping: (0..channels).map(|x| x as u8).collect(),
+            demixing_matrix: vec![],
+        };
+
+        if mapping_family != 0 {
+            if data.len() < 21 {
+                return Err(OpusError::InvalidContainer("truncated OpusHead"));
+            }
+            head.streams = usize::from(data[19]);
+            head.coupled_streams = usize::from(data[20]);
+
+            let table = &data[21..];
+            if mapping_family == 3 {
+                let size = 2 * channels * (head.streams + head.coupled_streams);
+                if table.len() < size {
+                    return Err(OpusError::InvalidContainer("truncated OpusHead"));
+                }
+                head.mapping = vec![];
+                head.demixing_matrix = table[..size].to_vec();
+            } else {
+                if table.len() < channels {
+                    return Err(OpusError::InvalidContainer("truncated OpusHead"));
+                }
+                head.mapping = table[..channels].to_vec();
+            }
+        }
+
+        head.validate()?;
+        Ok(head)
+    }
+
+    /// Validates the channel configuration.
+    pub(crate) fn validate(&self) -> Result<(), OpusError> {
+        if self.version & 0xF0 != 0 {
+            return Err(OpusError::InvalidContainer("unsupported OpusHead version"));
+        }
+        if self.channels < 1 || self.channels > 255 {
+            return Err(OpusError::InvalidContainer("invalid channel count"));
+        }
+        if self.streams < 1
+            || self.coupled_streams > self.streams
+            || self.streams + self.coupled_streams > 255
+        {
+            return Err(OpusError::InvalidContainer("invalid stream count"));
+        }
+
+        match self.mapping_family {
+            0 if self.channels > 2 || self.streams != 1 => {
+                return Err(OpusError::InvalidContainer("invalid channel count"));
+            }
+            1 if self.channels > 8 => {
+                return Err(OpusError::InvalidContainer("invalid channel count"));
+            }
+            2 | 3 => {
+                // Full order ambisonics with an optional pair of non-diegetic channels.
+                let order = (self.channels as f64).sqrt() as usize;
+                let remainder = self.channels - order * order;
+                if self.channels > 227 || (remainder != 0 && remainder != 2) {
+                    return Err(OpusError::InvalidContainer("invalid channel count"));
+                }
+            }
+            _ => {}
+        }
+
+        if self.mapping_family == 3 {
+            if self.demixing_matrix.len()
+                != 2 * self.channels * (self.streams + self.coupled_streams)
+            {
+                return Err(OpusError::InvalidContainer("invalid demixing matrix"));
+            }
+        } else {
+            let max_channel = self.streams + self.coupled_streams;
+            if self.mapping.len() != self.channels
+                || self
+                    .mapping
+                    .iter()
+                    .any(|&x| x != 255 && usize::from(x) >= max_channel)
+            {
+                return Err(OpusError::InvalidContainer("invalid channel mapping"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Serializes the header into an "OpusHead" packet.
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(21 + self.mapping.len() + self.demixing_matrix.len());
+        data.extend_from_slice(b"OpusHead");
+        data.push(self.version);
+        data.push(self.channels as u8);
+        data.extend_from_slice(&self.pre_skip.to_le_bytes());
+        data.extend_from_slice(&self.input_sampling_rate.to_le_bytes());
+        data.extend_from_slice(&self.output_gain.to_le_bytes());
+        data.push(self.mapping_family);
+        if self.mapping_family != 0 {
+            data.push(self.streams as u8);
+            data.push(self.coupled_streams as u8);
+            if self.mapping_family == 3 {
+                data.extend_from_slice(&self.demixing_matrix);
+            } else {
+                data.extend_from_slice(&self.mapping);
+            }
+        }
+        data
+    }
+
+    /// Returns the configuration of a `Decoder` for the stream.
+    ///
+    /// Only streams with the channel mapping family 0 can be decoded with a `Decoder`.
+    ///
+    /// # Arguments
+    /// * `sampling_rate` - Sample rate to decode at.
+    ///
+    pub fn decoder_configuration(
+        &self,
+        sampling_rate: SamplingRate,
+    ) -> Result<DecoderConfiguration, OpusError> {
+        if self.mapping_family != 0 {
+            return Err(OpusError::BadArguments(
+                "only the channel mapping family 0 can be decoded with a decoder",
+            ));
+        }
+        Ok(DecoderConfiguration {
+            sampling_rate,
+            channels: Channels::try_from(self.channels)?,
+            gain: self.output_gain,
+        })
+    }
+
+    /// Returns the configuration of a `MultistreamDecoder` for the stream.
+    ///
+    /// Streams with the channel mapping family 3 need to be decoded with a `ProjectionDecoder`.
+    ///
+    /// # Arguments
+    /// * `sampling_rate` - Sample rate to decode at.
+    ///
+    pub fn multistream_decoder_configuration(
+        &self,
+        sampling_rate: SamplingRate,
+    ) -> Result<MultistreamDecoderConfiguration, OpusError> {
+        if self.mapping_family == 3 {
+            return Err(OpusError::BadArguments(
+                "the channel mapping family 3 needs to be decoded with a projection decoder",
+            ));
+        }
+        Ok(MultistreamDecoderConfiguration {
+            sampling_rate,
+            streams: self.streams,
+            coupled_streams: self.coupled_streams,
+            mapping: self.mapping.clone(),
+            gain: self.output_gain,
+        })
+    }
+
+    /// Returns the configuration of a `ProjectionDecoder` for the stream.
+    ///
+    /// Only streams with the channel mapping family 3 can be decoded with a `ProjectionDecoder`.
+    ///
+    /// # Arguments
+    /// * `sampling_rate` - Sample rate to decode at.
+    ///
+    pub fn projection_decoder_configuration(
+        &self,
+        sampling_rate: SamplingRate,
+    ) -> Result<ProjectionDecoderConfiguration, OpusError> {
+        if self.mapping_family != 3 {
+            return Err(OpusError::BadArguments(
+                "only the channel mapping family 3 can be decoded with a projection decoder",
+            ));
+        }
+        Ok(ProjectionDecoderConfiguration {
+            sampling_rate,
+            channels: self.channels,
+            streams: self.streams,
+            coupled_streams: self.coupled_streams,
+            demixing_matrix: self.demixing_matrix.clone(),
+            gain: self.output_gain,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::panic)]
+    #![allow(clippy::unwrap_used)]
+
+    use super::*;
+
+    #[test]
+    fn test_opus_head() {
+        let data = [
+            b'O', b'p', b'u', b's', b'H', b'e', b'a', b'd', 1, 2, 0x38, 0x01, 0x44, 0xAC, 0, 0,
+            0x00, 0xFF, 0,
+        ];
+        let head = OpusHead::parse(&data).unwrap();
+        assert_eq!(head.channels, 2);
+        assert_eq!(head.pre_skip, 312);
+        assert_eq!(head.input_sampling_rate, 44100);
+        assert_eq!(head.output_gain, -256);
+        assert_eq!(head.streams, 1);
+        assert_eq!(head.coupled_streams, 1);
+        assert_eq!(head.mapping, vec![0, 1]);
+        assert_eq!(head.to_bytes(), data);
+
+        let config = head.decoder_configuration(SamplingRate::Hz24000).unwrap();
+        assert_eq!(config.channels, Channels::Stereo);
+        assert_eq!(config.gain, -256);
+        assert!(head
+            .projection_decoder_configuration(SamplingRate::Hz48000)
+            .is_err());
+
+        // Unsupported major version.
+        let mut invalid = data;
+        invalid[8] = 0x10;
+        assert!(OpusHead::parse(&invalid).is_err());
+        // Family 0 only supports mono and stereo.
+        invalid = data;
+        invalid[9] = 3;
+        assert!(OpusHead::parse(&invalid).is_err());
+        assert!(OpusHead::parse(&data[..18]).is_err());
+    }
+
+    #[test]
+    fn test_opus_head_surround() {
+        let head = OpusHead {
+            channels: 6,
+            mapping_family: 1,
+            streams: 4,
+            coupled_streams: 2,
+            mapping: vec![0, 4, 1, 2, 3, 5],
+            ..OpusHead::default()
+        };
+        let data = head.to_bytes();
+        assert_eq!(OpusHead::parse(&data).unwrap(), head);
+        assert!(head.decoder_configuration(SamplingRate::Hz48000).is_err());
+        let config = head
+            .multistream_decoder_configuration(SamplingRate::Hz48000)
+            .unwrap();
+        assert_eq!(config.mapping, head.mapping);
+
+        // Mapping points to a channel that doesn't exist.
+        let mut invalid = data.clone();
+        *invalid.last_mut().unwrap() = 6;
+        assert!(OpusHead::parse(&invalid).is_err());
+        // Truncated mapping table.
+        assert!(OpusHead::parse(&data[..data.len() - 1]).is_err());
+
+        let head = OpusHead {
+            channels: 4,
+            mapping_family: 3,
+            streams: 2,
+            coupled_streams: 2,
+            mapping: vec![],
+            demixing_matrix: vec![0; 32],
+            ..OpusHead::default()
+        };
+        assert_eq!(OpusHead::parse(&head.to_bytes()).unwrap(), head);
+        assert!(head
+            .projection_decoder_configuration(SamplingRate::Hz48000)
+            .is_ok());
+        assert!(OpusHead {
+            channels: 5,
+            ..head
+        }
+        .validate()
+        .is_err());
+    }
+}
@@ -0,0 +1,24 @@
+//! Implements the reading and writing of Opus inside Ogg containers.
+//!
+//! The Ogg encapsulation of Opus is specified in RFC 7845. A logical Ogg Opus stream
+//! starts with an "OpusHead" packet on its own page, followed by an "OpusTags" packet
+//! that finishes its page. All following packets are Opus audio packets. The granule
+//! position of a page counts the 48 kHz samples at the end of its last finished packet,
+//! including the pre-skip.
+//!
+//! A container can multiplex several logical streams, whose first pages precede all
+//! other pages. Chained containers concatenate such links, each link starts with new
+//! logical streams.
+
+pub use decoder::*;
+pub use header::*;
+pub use reader::*;
+pub use tags::*;
+pub use writer::*;
+
+mod decoder;
+mod header;
+mod page;
+mod reader;
+mod tags;
+mod writer;
@@ -0,0 +1,441 @@
+//! Implements the Ogg page framing.
+//!
+//! A page consists of a 27 byte header, a lacing table and the payload. The lacing
+//! table splits the payload into segments of up to 255 bytes. A packet ends with the
+//! first segment that is shorter than 255 bytes, so packets can span multiple pages.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::OpusError;
+
+/// The page continues a packet of the previous page.
+pub(crate) const HEADER_CONTINUED: u8 = 0x01;
+/// First page of a logical stream.
+pub(crate) const HEADER_FIRST: u8 = 0x02;
+/// Last page of a logical stream.
+pub(crate) const HEADER_LAST: u8 = 0x04;
+
+/// Granule position of pages on which no packet finishes.
+pub(crate) const NO_GRANULE_POSITION: u64 = u64::MAX;
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0_u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Updates the Ogg CRC (polynomial 0x04C11DB7, no reflection, no final XOR).
+pub(crate) fn crc32(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, &byte| {
+        (crc << 8) ^ CRC_TABLE[usize::from((crc >> 24) as u8 ^ byte)]
+    })
+}
+
+/// A single Ogg page.
+#[derive(Clone, Debug)]
+pub(crate) struct Page {
+    /// Combination of the `HEADER_*` flags.
+    pub(crate) header_type: u8,
+    /// Codec specific position of the last packet that finishes on this page.
+    pub(crate) granule_position: u64,
+    /// Serial number of the logical stream.
+    pub(crate) serial: u32,
+    /// Sequence number of the page inside its logical stream.
+    pub(crate) sequence: u32,
+    /// The lacing values of the segments.
+    pub(crate) lacing: Vec<u8>,
+    /// The payload of all segments.
+    pub(crate) data: Vec<u8>,
+}
+
+impl Page {
+    /// Reads the next page and verifies its checksum. Returns `None` if the reader is at its end.
+    pub(crate) fn read<R: Read>(reader: &mut R) -> Result<Option<Self>, OpusError> {
+        let mut header = [0_u8; 27];
+        let len = read_full(reader, &mut header)?;
+        if len == 0 {
+            return Ok(None);
+        }
+        if len < header.len() {
+            return Err(OpusError::InvalidContainer("truncated page"));
+        }
+        if &header[..4] != b"OggS" {
+            return Err(OpusError::InvalidContainer("missing capture pattern"));
+        }
+        if header[4] != 0 {
+            return Err(OpusError::InvalidContainer(
+                "unsupported stream structure version",
+            ));
+        }
+
+        let mut lacing = vec![0_u8; usize::from(header[26])];
+        if read_full(reader, &mut lacing)? < lacing.len() {
+            return Err(OpusError::InvalidContainer("truncated page"));
+        }
+        let mut data = vec![0_u8; lacing.iter().map(|&x| usize::from(x)).sum()];
+        if read_full(reader, &mut data)? < data.len() {
+            return Err(OpusError::InvalidContainer("truncated page"));
+        }
+
+        let checksum = read_u32(&header[22..26]);
+        header[22..26].copy_from_slice(&[0; 4]);
+        let crc = crc32(crc32(crc32(0, &header), &lacing), &data);
+        if crc != checksum {
+            return Err(OpusError::InvalidContainer("page checksum mismatch"));
+        }
+
+        Ok(Some(Self {
+            header_type: header[5],
+            granule_position: read_u64(&header[6..14]),
+            serial: read_u32(&header[14..18]),
+            sequence: read_u32(&header[18..22]),
+            lacing,
+            data,
+        }))
+    }
+
+    /// Finds the first valid page that starts in the byte range `start..end`.
+    ///
+    /// Returns the page together with its offset. Candidates with a wrong checksum are skipped.
+    pub(crate) fn find<R: Read + Seek>(
+        reader: &mut R,
+        start: u64,
+        end: u64,
+    ) -> Result<Option<(u64, Self)>, OpusError> {
+        let mut buffer = vec![0_u8; 65536];
+        let mut position = start;
+        while position < end {
+            reader
+                .seek(SeekFrom::Start(position))
+                .map_err(OpusError::Io)?;
+            let len = read_full(reader, &mut buffer)?;
+            let candidates: Vec<u64> = buffer[..len]
+                .windows(4)
+                .enumerate()
+                .filter(|(_, window)| window == b"OggS")
+                .map(|(i, _)| position + i as u64)
+                .filter(|&offset| offset < end)
+                .collect();
+
+            for offset in candidates {
+                reader
+                    .seek(SeekFrom::Start(offset))
+                    .map_err(OpusError::Io)?;
+                match Self::read(reader) {
+                    Ok(Some(page)) => return Ok(Some((offset, page))),
+                    Ok(None) => return Ok(None),
+                    Err(OpusError::Io(err)) => return Err(OpusError::Io(err)),
+                    // Not a page, only a random match of the capture pattern.
+                    Err(_) => {}
+                }
+            }
+
+            if len < buffer.len() {
+                break;
+            }
+            // The capture pattern could span the chunks.
+            position += (len - 3) as u64;
+        }
+        Ok(None)
+    }
+
+    /// Splits a single packet into pages. The packet finishes on the last page.
+    pub(crate) fn paginate(
+        packet: &[u8],
+        header_type: u8,
+        serial: u32,
+        sequence: u32,
+        granule_position: u64,
+    ) -> Vec<Self> {
+        let lacing = lace(packet.len());
+        let count = lacing.len().div_ceil(255);
+        let mut offset = 0;
+        lacing
+            .chunks(255)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let size: usize = chunk.iter().map(|&x| usize::from(x)).sum();
+                let data = packet[offset..offset + size].to_vec();
+                offset += size;
+                Self {
+                    header_type: if i == 0 {
+                        header_type
+                    } else {
+                        HEADER_CONTINUED
+                    },
+                    granule_position: if i + 1 == count {
+                        granule_position
+                    } else {
+                        NO_GRANULE_POSITION
+                    },
+                    serial,
+                    sequence: sequence.wrapping_add(i as u32),
+                    lacing: chunk.to_vec(),
+                    data,
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the size of the page in bytes.
+    pub(crate) fn size(&self) -> u64 {
+        (27 + self.lacing.len() + self.data.len()) as u64
+    }
+
+    /// Writes the page and its checksum.
+    pub(crate) fn write<W: Write>(&self, writer: &mut W) -> Result<(), OpusError> {
+        debug_assert!(self.lacing.len() <= 255);
+
+        let mut header = [0_u8; 27];
+        header[..4].copy_from_slice(b"OggS");
+        header[5] = self.header_type;
+        header[6..14].copy_from_slice(&self.granule_position.to_le_bytes());
+        header[14..18].copy_from_slice(&self.serial.to_le_bytes());
+        header[18..22].copy_from_slice(&self.sequence.to_le_bytes());
+        header[26] = self.lacing.len() as u8;
+        let crc = crc32(crc32(crc32(0, &header), &self.lacing), &self.data);
+        header[22..26].copy_from_slice(&crc.to_le_bytes());
+
+        writer
+            .write_all(&header)
+            .and_then(|_| writer.write_all(&self.lacing))
+            .and_then(|_| writer.write_all(&self.data))
+            .map_err(OpusError::Io)
+    }
+
+    /// Returns true if the page continues a packet of the previous page.
+    pub(crate) fn is_continued(&self) -> bool {
+        self.header_type & HEADER_CONTINUED != 0
+    }
+
+    /// Returns true if the page is the first page of its logical stream.
+    pub(crate) fn is_first(&self) -> bool {
+        self.header_type & HEADER_FIRST != 0
+    }
+
+    /// Returns true if the page is the last page of its logical stream.
+    pub(crate) fn is_last(&self) -> bool {
+        self.header_type & HEADER_LAST != 0
+    }
+
+    /// Splits the payload into packet fragments. The flag is true if the packet
+    /// finishes on this page.
+    pub(crate) fn fragments(&self) -> Vec<(&[u8], bool)> {
+        let mut fragments = vec![];
+        let mut start = 0;
+        let mut end = 0;
+        self.lacing.iter().for_each(|&value| {
+            end += usize::from(value);
+            if value < 255 {
+                fragments.push((&self.data[start..end], true));
+                start = end;
+            }
+        });
+        if self.lacing.last() == Some(&255) {
+            fragments.push((&self.data[start..end], false));
+        }
+        fragments
+    }
+}
+
+/// Reassembles the packets of a logical stream from its pages.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PacketAssembler {
+    partial: Option<Vec<u8>>,
+    sequence: Option<u32>,
+}
+
+impl PacketAssembler {
+    /// Returns the packets that finish on the given page.
+    ///
+    /// Packets that are missing a part because of a gap in the page sequence are dropped.
+    pub(crate) fn push(&mut self, page: &Page) -> Vec<Vec<u8>> {
+        let in_sequence = self.sequence.is_none() || self.sequence == Some(page.sequence);
+        self.sequence = Some(page.sequence.wrapping_add(1));
+        if !in_sequence || !page.is_continued() {
+            self.partial = None;
+        }
+
+        let mut skip = page.is_continued() && self.partial.is_none();
+        let mut packets = vec![];
+        page.fragments()
+            .into_iter()
+            .for_each(|(fragment, finished)| {
+                if skip {
+                    // We don't have the start of the continued packet.
+                    skip = false;
+                    return;
+                }
+                let mut packet = self.partial.take().unwrap_or_default();
+                packet.extend_from_slice(fragment);
+                if finished {
+                    packets.push(packet);
+                } else {
+                    self.partial = Some(packet);
+                }
+            });
+        packets
+    }
+
+    /// Drops the unfinished packet and forgets the page sequence.
+    pub(crate) fn reset(&mut self) {
+        self.partial = None;
+        self.sequence = None;
+    }
+}
+
+/// Returns the lacing values of a packet with the given size.
+pub(crate) fn lace(len: usize) -> Vec<u8> {
+    let mut lacing = vec![255; len / 255];
+    lacing.push((len % 255) as u8);
+    lacing
+}
+
+/// Reads until the buffer is full or the reader is at its end. Returns the number of bytes read.
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<usize, OpusError> {
+    let mut len = 0;
+    while len < buffer.len() {
+        match reader.read(&mut buffer[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(OpusError::Io(err)),
+        }
+    }
+    Ok(len)
+}
+
+pub(crate) fn read_u16(data: &[u8]) -> u16 {
+    u16::from_le_bytes([data[0], data[1]])
+}
+
+pub(crate) fn read_u32(data: &[u8]) -> u32 {
+    u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+}
+
+pub(crate) fn read_u64(data: &[u8]) -> u64 {
+    u64::from(read_u32(data)) | u64::from(read_u32(&data[4..])) << 32
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::panic)]
+    #![allow(clippy::unwrap_used)]
+
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(0, b"123456789"), 0x89A1_897F);
+    }
+
+    #[test]
+    fn test_page_roundtrip() {
+        let page = Page {
+            header_type: HEADER_FIRST | HEADER_LAST,
+            granule_position: 0x0102_0304_0506_0708,
+            serial: 0xDEAD_BEEF,
+            sequence: 7,
+            lacing: lace(300),
+            data: vec![0x55; 300],
+        };
+        let mut data = vec![];
+        page.write(&mut data).unwrap();
+        assert_eq!(data.len(), 27 + 2 + 300);
+
+        let read = Page::read(&mut data.as_slice()).unwrap().unwrap();
+        assert_eq!(read.header_type, page.header_type);
+        assert_eq!(read.granule_position, page.granule_position);
+        assert_eq!(read.serial, page.serial);
+        assert_eq!(read.sequence, page.sequence);
+        assert_eq!(read.lacing, vec![255, 45]);
+        assert_eq!(read.data, page.data);
+    }
+
+    #[test]
+    fn test_paginate() {
+        let packet = vec![0x11; 255 * 255 + 10];
+        let pages = Page::paginate(&packet, HEADER_FIRST, 3, 5, 0);
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].header_type, HEADER_FIRST);
+        assert_eq!(pages[0].granule_position, NO_GRANULE_POSITION);
+        assert_eq!(pages[0].data.len(), 255 * 255);
+        assert_eq!(pages[1].header_type, HEADER_CONTINUED);
+        assert_eq!(pages[1].granule_position, 0);
+        assert_eq!(pages[1].sequence, 6);
+        assert_eq!(pages[1].lacing, vec![10]);
+
+        let mut assembler = PacketAssembler::default();
+        assert!(assembler.push(&pages[0]).is_empty());
+        assert_eq!(assembler.push(&pages[1]), vec![packet]);
+    }
+
+    #[test]
+    fn test_find_page() {
+        let page = Page {
+            header_type: 0,
+            granule_position: 960,
+            serial: 1,
+            sequence: 2,
+            lacing: lace(3),
+            data: b"OggS"[..3].to_vec(),
+        };
+        let mut data = b"xxOggSxx".to_vec();
+        page.write(&mut data).unwrap();
+        let mut reader = std::io::Cursor::new(data);
+
+        let (offset, found) = Page::find(&mut reader, 0, 100).unwrap().unwrap();
+        assert_eq!(offset, 8);
+        assert_eq!(found.granule_position, 960);
+        assert_eq!(found.size(), 31);
+        assert!(Page::find(&mut reader, 0, 8).unwrap().is_none());
+        assert!(Page::find(&mut reader, 9, 100).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_packet_assembler() {
+        let page = |sequence, header_type, lacing: &[u8]| Page {
+            header_type,
+            granule_position: 0,
+            serial: 1,
+            sequence,
+            lacing: lacing.to_vec(),
+            data: (0..lacing.iter().map(|&x| usize::from(x)).sum())
+                .map(|x| x as u8)
+                .collect(),
+        };
+
+        let mut assembler = PacketAssembler::default();
+        let packets = assembler.push(&page(0, 0, &[3, 255]));
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].len(), 3);
+
+        let packets = assembler.push(&page(1, HEADER_CONTINUED, &[255, 10, 0]));
+        assert_eq!(
+            packets.iter().map(|p| p.len()).collect::<Vec<_>>(),
+            vec![520, 0]
+        );
+
+        // The continued packet of a page after a gap is dropped.
+        assembler.push(&page(2, 0, &[255]));
+        let packets = assembler.push(&page(4, HEADER_CONTINUED, &[1, 2]));
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].len(), 2);
+    }
+}
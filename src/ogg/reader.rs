@@ -0,0 +1,883 @@
+//! Implements the Ogg Opus demuxer.
+
+use std::collections::VecDeque;
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::ogg::page::{PacketAssembler, Page, NO_GRANULE_POSITION};
+use crate::{query_packet_sample_count, OpusError, OpusHead, OpusTags, SamplingRate};
+
+/// Byte range below which the bisection switches to a linear scan.
+const BISECTION_THRESHOLD: u64 = 65536;
+
+/// An Opus packet read from an Ogg container.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OggPacket {
+    /// The Opus packet.
+    pub data: Vec<u8>,
+    /// Number of 48 kHz samples at the end of the packet, including the pre-skip.
+    ///
+    /// The granule position of the last packet of the stream can be smaller than the
+    /// duration of all packets. The decoder output needs to be trimmed accordingly.
+    pub granule_position: u64,
+    /// True for the last packet of the stream.
+    pub end_of_stream: bool,
+}
+
+/// A logical stream of the container that isn't the Opus stream that is read.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OggStream {
+    /// Serial number of the logical stream.
+    pub serial: u32,
+    /// The data of the first page of the stream, which usually starts with the
+    /// identification header of its codec.
+    pub header: Vec<u8>,
+}
+
+/// Reads Opus packets from an Ogg container.
+///
+/// The reader reads the first Opus stream of the container. Pages of other
+/// logical streams are skipped, they are listed by `other_streams()`.
+///
+/// Chained containers consist of multiple links, which follow each other and contain
+/// their own logical streams. `read_packet()` only returns the packets of the current
+/// link. `next_stream()` continues with the Opus stream of the next link.
+#[derive(Debug)]
+pub struct OggOpusReader<R: Read> {
+    reader: R,
+    head: OpusHead,
+    tags: OpusTags,
+    serial: u32,
+    other_streams: Vec<OggStream>,
+    link: usize,
+    offset: u64,
+    data_start: u64,
+    assembler: PacketAssembler,
+    /// The first page of the next link, if it was already read.
+    pending: Option<Page>,
+    packets: VecDeque<OggPacket>,
+    granule_position: Option<u64>,
+    end_of_stream: bool,
+}
+
+/// The headers of the Opus stream of a link.
+struct Link {
+    head: OpusHead,
+    tags: OpusTags,
+    serial: u32,
+    other_streams: Vec<OggStream>,
+    assembler: PacketAssembler,
+}
+
+impl<R: Read> OggOpusReader<R> {
+    /// Creates a new `OggOpusReader` and reads the headers of the Opus stream.
+    ///
+    /// Leading links of a chained container without an Opus stream are skipped.
+    pub fn new(mut reader: R) -> Result<Self, OpusError> {
+        let mut offset = 0;
+        let link = Self::read_link(&mut reader, &mut offset, None)?
+            .ok_or(OpusError::InvalidContainer("missing OpusHead"))?;
+
+        Ok(Self {
+            reader,
+            head: link.head,
+            tags: link.tags,
+            serial: link.serial,
+            other_streams: link.other_streams,
+            link: 0,
+            offset,
+            data_start: offset,
+            assembler: link.assembler,
+            pending: None,
+            packets: VecDeque::new(),
+            granule_position: None,
+            end_of_stream: false,
+        })
+    }
+
+    /// Returns the identification header of the stream.
+    pub fn head(&self) -> &OpusHead {
+        &self.head
+    }
+
+    /// Returns the comment header of the stream.
+    pub fn tags(&self) -> &OpusTags {
+        &self.tags
+    }
+
+    /// Returns the serial number of the logical stream.
+    pub fn serial(&self) -> u32 {
+        self.serial
+    }
+
+    /// Returns the other logical streams of the current link, which are skipped.
+    pub fn other_streams(&self) -> &[OggStream] {
+        &self.other_streams
+    }
+
+    /// Returns the index of the current link of a chained container, starting at 0.
+    ///
+    /// Links without an Opus stream are not counted.
+    pub fn link(&self) -> usize {
+        self.link
+    }
+
+    /// Returns the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Reads the next Opus packet. Returns `None` at the end of the stream.
+    ///
+    /// Packets that are incomplete because of missing pages are skipped. The stream
+    /// also ends at the start of the next link, if its last page is missing.
+    pub fn read_packet(&mut self) -> Result<Option<OggPacket>, OpusError> {
+        while self.packets.is_empty() && !self.end_of_stream {
+            match self.read_stream_page()? {
+                Some(page) => self.push_page(&page),
+                None => self.end_of_stream = true,
+            }
+        }
+        Ok(self.packets.pop_front())
+    }
+
+    /// Continues with the Opus stream of the next link of a chained container.
+    ///
+    /// The remaining packets of the current stream are skipped and the headers of the
+    /// next stream are read. Links without an Opus stream are skipped. Returns false
+    /// at the end of the container.
+    pub fn next_stream(&mut self) -> Result<bool, OpusError> {
+        self.packets.clear();
+        self.end_of_stream = true;
+        let link = match Self::read_link(&mut self.reader, &mut self.offset, self.pending.take())? {
+            Some(link) => link,
+            None => return Ok(false),
+        };
+
+        self.head = link.head;
+        self.tags = link.tags;
+        self.serial = link.serial;
+        self.other_streams = link.other_streams;
+        self.link += 1;
+        self.data_start = self.offset;
+        self.assembler = link.assembler;
+        self.granule_position = None;
+        self.end_of_stream = false;
+        Ok(true)
+    }
+
+    /// Reads the headers of the next link that contains an Opus stream and counts the
+    /// bytes read. Returns `None` at the end of the container.
+    fn read_link(
+        reader: &mut R,
+        offset: &mut u64,
+        mut pending: Option<Page>,
+    ) -> Result<Option<Link>, OpusError> {
+        let mut assembler = PacketAssembler::default();
+        let mut other_streams = vec![];
+        let mut opus: Option<(u32, OpusHead)> = None;
+        // The first pages of all logical streams of a link precede all other pages.
+        let mut first_pages = false;
+
+        loop {
+            let page = match pending.take() {
+                Some(page) => page,
+                None => match Page::read(reader)? {
+                    Some(page) => {
+                        *offset += page.size();
+                        page
+                    }
+                    None if opus.is_some() => {
+                        return Err(OpusError::InvalidContainer("missing OpusTags"))
+                    }
+                    None => return Ok(None),
+                },
+            };
+
+            if page.is_first() {
+                if !first_pages {
+                    if opus.is_some() {
+                        return Err(OpusError::InvalidContainer("missing OpusTags"));
+                    }
+                    // A new link starts.
+                    first_pages = true;
+                    other_streams.clear();
+                }
+                if opus.is_none() && page.data.starts_with(b"OpusHead") {
+                    let packets = assembler.push(&page);
+                    if packets.len() != 1 || page.fragments().len() != 1 {
+                        return Err(OpusError::InvalidContainer(
+                            "OpusHead must be alone on its page",
+                        ));
+                    }
+                    if page.granule_position != 0 {
+                        return Err(OpusError::InvalidContainer(
+                            "OpusHead page must have a granule position of 0",
+                        ));
+                    }
+                    opus = Some((page.serial, OpusHead::parse(&packets[0])?));
+                } else {
+                    other_streams.push(OggStream {
+                        serial: page.serial,
+                        header: page.data,
+                    });
+                }
+                continue;
+            }
+            first_pages = false;
+
+            // Links without an Opus stream are skipped.
+            match &opus {
+                Some((serial, _)) if *serial == page.serial => {}
+                _ => continue,
+            }
+            let packets = assembler.push(&page);
+            if let Some(packet) = packets.first() {
+                if packets.len() != 1 || page.fragments().len() != 1 {
+                    return Err(OpusError::InvalidContainer("OpusTags must finish its page"));
+                }
+                let tags = OpusTags::parse(packet)?;
+                if let Some((serial, head)) = opus {
+                    return Ok(Some(Link {
+                        head,
+                        tags,
+                        serial,
+                        other_streams,
+                        assembler,
+                    }));
+                }
+            }
+        }
+    }
+
+    /// Reads the next page of the logical stream and counts the bytes read.
+    ///
+    /// Returns `None` at the start of the next link and keeps its first page.
+    fn read_stream_page(&mut self) -> Result<Option<Page>, OpusError> {
+        while let Some(page) = Page::read(&mut self.reader)? {
+            self.offset += page.size();
+            if page.is_first() {
+                self.pending = Some(page);
+                return Ok(None);
+            }
+            if page.serial == self.serial {
+                return Ok(Some(page));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Queues the packets that finish on the page together with their granule positions.
+    fn push_page(&mut self, page: &Page) {
+        let packets = self.assembler.push(page);
+        if page.is_last() {
+            self.end_of_stream = true;
+        }
+        if packets.is_empty() {
+            return;
+        }
+
+        let durations: Vec<u64> = packets
+            .iter()
+            .map(|packet| {
+                query_packet_sample_count(packet, SamplingRate::Hz48000).map_or(0, |x| x as u64)
+            })
+            .collect();
+
+        // The granule position of the page belongs to its last packet. The positions of
+        // the other packets are derived from it, except on the last page, where the end
+        // of the stream might be trimmed. There, they are counted from the previous page.
+        // If the last page is also the first audio page, the trimming must not cut the
+        // start of the stream, so they are counted from its start.
+        let mut granules = Vec::with_capacity(durations.len());
+        if page.granule_position == NO_GRANULE_POSITION || page.is_last() {
+            let mut granule = match self.granule_position {
+                Some(granule) => granule,
+                None if page.granule_position != NO_GRANULE_POSITION => {
+                    page.granule_position.saturating_sub(durations.iter().sum())
+                }
+                None => 0,
+            };
+            durations.iter().for_each(|&duration| {
+                granule = granule.saturating_add(duration);
+                granules.push(granule);
+            });
+            if page.granule_position != NO_GRANULE_POSITION {
+                granules
+                    .iter_mut()
+                    .for_each(|granule| *granule = u64::min(*granule, page.granule_position));
+                if let Some(last) = granules.last_mut() {
+                    *last = page.granule_position;
+                }
+            }
+        } else {
+            let mut granule = page.granule_position;
+            durations.iter().rev().for_each(|&duration| {
+                granules.push(granule);
+                granule = granule.saturating_sub(duration);
+            });
+            granules.reverse();
+        }
+        self.granule_position = granules.last().copied();
+
+        let last = packets.len() - 1;
+        packets
+            .into_iter()
+            .zip(granules)
+            .enumerate()
+            .for_each(|(i, (data, granule_position))| {
+                self.packets.push_back(OggPacket {
+                    data,
+                    granule_position,
+                    end_of_stream: page.is_last() && i == last,
+                });
+            });
+    }
+}
+
+impl<R: Read + Seek> OggOpusReader<R> {
+    /// Seeks to the packet that contains the given granule position.
+    ///
+    /// Seeking is limited to the stream of the current link.
+    ///
+    /// The next packet returned by `read_packet()` is the first packet that ends after
+    /// the granule position. The page is found by a bisection over the granule
+    /// positions of the pages, so only a few pages need to be read.
+    ///
+    /// # Arguments
+    /// * `granule_position` - Granule position to seek to (48 kHz samples including the pre-skip).
+    ///
+    pub fn seek(&mut self, granule_position: u64) -> Result<(), OpusError> {
+        let position = self.reader.stream_position().map_err(OpusError::Io)?;
+        let origin = position
+            .checked_sub(self.offset)
+            .ok_or(OpusError::InternalError("reader position is out of sync"))?;
+        let start = origin + self.data_start;
+        let end = self.reader.seek(SeekFrom::End(0)).map_err(OpusError::Io)?;
+
+        let page = self.bisect(start, end, granule_position)?;
+
+        self.assembler.reset();
+        self.pending = None;
+        self.packets.clear();
+        self.granule_position = None;
+        self.end_of_stream = false;
+        match page {
+            Some((offset, page)) => {
+                // Continue after the page, but keep the packet that finishes on the next page.
+                let next = offset + page.size();
+                self.reader
+                    .seek(SeekFrom::Start(next))
+                    .map_err(OpusError::Io)?;
+                self.offset = next - origin;
+                self.assembler.push(&page);
+                self.granule_position = Some(page.granule_position);
+                self.end_of_stream = page.is_last();
+            }
+            None => {
+                self.reader
+                    .seek(SeekFrom::Start(start))
+                    .map_err(OpusError::Io)?;
+                self.offset = self.data_start;
+            }
+        }
+
+        while let Some(packet) = self.read_packet()? {
+            if packet.granule_position > granule_position {
+                self.packets.push_front(packet);
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Finds the last page in the byte range `start..end` with a granule position that
+    /// doesn't exceed the given granule position.
+    fn bisect(
+        &mut self,
+        start: u64,
+        end: u64,
+        granule_position: u64,
+    ) -> Result<Option<(u64, Page)>, OpusError> {
+        let mut best = None;
+        let mut low = start;
+        let mut high = end;
+
+        // Pages that start at or after `high` have a larger granule position.
+        while low < high && high - low > BISECTION_THRESHOLD {
+            let middle = low + (high - low) / 2;
+            match self.find_granule_page(middle, high)? {
+                Some((offset, page)) if page.granule_position <= granule_position => {
+                    low = offset + page.size();
+                    best = Some((offset, page));
+                }
+                _ => high = middle,
+            }
+        }
+
+        let mut offset = low;
+        while let Some((page_offset, page)) = self.find_granule_page(offset, high)? {
+            if page.granule_position > granule_position {
+                break;
+            }
+            offset = page_offset + page.size();
+            best = Some((page_offset, page));
+        }
+
+        Ok(best)
+    }
+
+    /// Finds the first page of the logical stream with a granule position that starts
+    /// in the byte range `start..end`.
+    fn find_granule_page(
+        &mut self,
+        start: u64,
+        end: u64,
+    ) -> Result<Option<(u64, Page)>, OpusError> {
+        let mut offset = start;
+        while let Some((page_offset, page)) = Page::find(&mut self.reader, offset, end)? {
+            if page.serial == self.serial && page.granule_position != NO_GRANULE_POSITION {
+                return Ok(Some((page_offset, page)));
+            }
+            offset = page_offset + page.size();
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::panic)]
+    #![allow(clippy::unwrap_used)]
+
+    use std::num::NonZeroUsize;
+
+    use super::*;
+    use crate::ogg::page::{crc32, lace, HEADER_CONTINUED, HEADER_FIRST, HEADER_LAST};
+    use crate::{
+        Decoder, Encoder, EncoderConfiguration, OggOpusWriter, OggOpusWriterConfiguration,
+    };
+    use nanorand::Rng;
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    fn page(
+        header_type: u8,
+        granule_position: u64,
+        serial: u32,
+        sequence: u32,
+        lacing: &[u8],
+        data: &[u8],
+    ) -> Vec<u8> {
+        let mut page = b"OggS\0".to_vec();
+        page.push(header_type);
+        page.extend_from_slice(&granule_position.to_le_bytes());
+        page.extend_from_slice(&serial.to_le_bytes());
+        page.extend_from_slice(&sequence.to_le_bytes());
+        page.extend_from_slice(&[0; 4]);
+        page.push(lacing.len() as u8);
+        page.extend_from_slice(lacing);
+        page.extend_from_slice(data);
+        let crc = crc32(0, &page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        page
+    }
+
+    fn packets_page(
+        header_type: u8,
+        granule_position: u64,
+        serial: u32,
+        sequence: u32,
+        packets: &[Vec<u8>],
+    ) -> Vec<u8> {
+        let lacing: Vec<u8> = packets.iter().flat_map(|p| lace(p.len())).collect();
+        let data: Vec<u8> = packets.concat();
+        page(
+            header_type,
+            granule_position,
+            serial,
+            sequence,
+            &lacing,
+            &data,
+        )
+    }
+
+    fn headers(serial: u32, head: &OpusHead, tags: &OpusTags) -> Vec<u8> {
+        let mut data = packets_page(HEADER_FIRST, 0, serial, 0, &[head.to_bytes()]);
+        data.extend(packets_page(0, 0, serial, 1, &[tags.to_bytes()]));
+        data
+    }
+
+    fn encode(frames: usize) -> Vec<Vec<u8>> {
+        let mut encoder = Encoder::new(&EncoderConfiguration::default()).unwrap();
+        let mut output = [0_u8; 1500];
+        (0..frames)
+            .map(|i| {
+                let samples: Vec<f32> = (0..960 * 2)
+                    .map(|j| ((i * 960 + j / 2) as f32 * 0.05).sin() * 0.5)
+                    .collect();
+                let len = encoder.encode_float(&samples, &mut output).unwrap();
+                output[..len].to_vec()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_read_stream() {
+        let head = OpusHead {
+            pre_skip: 312,
+            ..OpusHead::default()
+        };
+        let tags = OpusTags {
+            vendor: "opus-native".to_string(),
+            comments: vec!["TITLE=Test".to_string()],
+            ..OpusTags::default()
+        };
+        let packets = encode(25);
+
+        // Multiplexed with a second logical stream, which is skipped.
+        let mut data = packets_page(HEADER_FIRST, 0, 7, 0, &[b"other".to_vec()]);
+        data.extend(headers(1, &head, &tags));
+        data.extend(packets_page(0, 312 + 960 * 10, 1, 2, &packets[..10]));
+        data.extend(packets_page(0, 0, 7, 1, &[b"data".to_vec()]));
+        data.extend(packets_page(0, 312 + 960 * 20, 1, 3, &packets[10..20]));
+        // The end of the stream is trimmed.
+        let end = 312 + 960 * 24 + 100;
+        data.extend(packets_page(HEADER_LAST, end, 1, 4, &packets[20..]));
+
+        let mut reader = OggOpusReader::new(data.as_slice()).unwrap();
+        assert_eq!(reader.head(), &head);
+        assert_eq!(reader.tags(), &tags);
+        assert_eq!(reader.serial(), 1);
+
+        let mut decoder = Decoder::new(
+            &reader
+                .head()
+                .decoder_configuration(SamplingRate::Hz48000)
+                .unwrap(),
+        )
+        .unwrap();
+        let mut samples = vec![0_f32; 960 * 2];
+
+        let mut count = 0;
+        while let Some(packet) = reader.read_packet().unwrap() {
+            assert_eq!(packet.data, packets[count]);
+            let expected = if count == 24 {
+                end
+            } else {
+                312 + 960 * (count as u64 + 1)
+            };
+            assert_eq!(packet.granule_position, expected);
+            assert_eq!(packet.end_of_stream, count == 24);
+
+            let len = decoder
+                .decode_float(
+                    Some(&packet.data),
+                    &mut samples,
+                    NonZeroUsize::new(960).unwrap(),
+                    false,
+                )
+                .unwrap();
+            assert_eq!(len, 960);
+            count += 1;
+        }
+        assert_eq!(count, 25);
+        assert!(reader.read_packet().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_trimmed_single_page() {
+        let packets = encode(3);
+        let mut data = headers(1, &OpusHead::default(), &OpusTags::default());
+        // The only audio page is also the last page, its end is trimmed.
+        data.extend(packets_page(HEADER_LAST, 3 * 960 - 500, 1, 2, &packets));
+
+        let mut reader = OggOpusReader::new(data.as_slice()).unwrap();
+        let granules: Vec<u64> = std::iter::from_fn(|| reader.read_packet().unwrap())
+            .map(|packet| packet.granule_position)
+            .collect();
+        assert_eq!(granules, vec![960, 1920, 2380]);
+
+        // The trim spans multiple packets.
+        let mut data = headers(1, &OpusHead::default(), &OpusTags::default());
+        data.extend(packets_page(HEADER_LAST, 1000, 1, 2, &packets));
+        let mut reader = OggOpusReader::new(data.as_slice()).unwrap();
+        let granules: Vec<u64> = std::iter::from_fn(|| reader.read_packet().unwrap())
+            .map(|packet| packet.granule_position)
+            .collect();
+        assert_eq!(granules, vec![960, 1000, 1000]);
+
+        // A stream that starts at a later position isn't trimmed.
+        let mut data = headers(1, &OpusHead::default(), &OpusTags::default());
+        data.extend(packets_page(HEADER_LAST, 10_000, 1, 2, &packets));
+        let mut reader = OggOpusReader::new(data.as_slice()).unwrap();
+        let granules: Vec<u64> = std::iter::from_fn(|| reader.read_packet().unwrap())
+            .map(|packet| packet.granule_position)
+            .collect();
+        assert_eq!(granules, vec![10_000 - 1920, 10_000 - 960, 10_000]);
+    }
+
+    #[test]
+    fn test_packet_across_pages() {
+        let packet: Vec<u8> = (0..700).map(|x| x as u8).collect();
+        let mut toc_packet = vec![0xFC];
+        toc_packet.extend_from_slice(&packet[1..]);
+
+        let mut data = headers(3, &OpusHead::default(), &OpusTags::default());
+        data.extend(page(
+            0,
+            NO_GRANULE_POSITION,
+            3,
+            2,
+            &[255, 255],
+            &toc_packet[..510],
+        ));
+        let mut lacing = vec![190];
+        lacing.extend(lace(1));
+        let mut rest = toc_packet[510..].to_vec();
+        rest.push(0xFC);
+        data.extend(page(
+            HEADER_CONTINUED | HEADER_LAST,
+            1920,
+            3,
+            3,
+            &lacing,
+            &rest,
+        ));
+
+        let mut reader = OggOpusReader::new(data.as_slice()).unwrap();
+        let first = reader.read_packet().unwrap().unwrap();
+        assert_eq!(first.data, toc_packet);
+        assert_eq!(first.granule_position, 960);
+        assert!(!first.end_of_stream);
+        let second = reader.read_packet().unwrap().unwrap();
+        assert_eq!(second.data, vec![0xFC]);
+        assert_eq!(second.granule_position, 1920);
+        assert!(second.end_of_stream);
+        assert!(reader.read_packet().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let mut data = headers(1, &OpusHead::default(), &OpusTags::default());
+        data.extend(packets_page(HEADER_LAST, 960, 1, 2, &[vec![0xFC, 1, 2]]));
+        assert!(OggOpusReader::new(data.as_slice()).is_ok());
+
+        let len = data.len();
+        data[len - 1] ^= 0x1;
+        let mut reader = OggOpusReader::new(data.as_slice()).unwrap();
+        assert!(matches!(
+            reader.read_packet(),
+            Err(OpusError::InvalidContainer(_))
+        ));
+
+        data[30] ^= 0x1;
+        assert!(OggOpusReader::new(data.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_invalid_headers() {
+        let head = OpusHead::default().to_bytes();
+        let tags = OpusTags::default().to_bytes();
+
+        assert!(OggOpusReader::new([].as_ref()).is_err());
+
+        // OpusHead on a page that isn't the first page of the stream.
+        let mut data = packets_page(0, 0, 1, 0, std::slice::from_ref(&head));
+        data.extend(packets_page(0, 0, 1, 1, std::slice::from_ref(&tags)));
+        assert!(OggOpusReader::new(data.as_slice()).is_err());
+
+        // OpusHead shares its page with OpusTags.
+        let data = packets_page(HEADER_FIRST, 0, 1, 0, &[head.clone(), tags.clone()]);
+        assert!(OggOpusReader::new(data.as_slice()).is_err());
+
+        // OpusTags doesn't finish its page.
+        let mut data = packets_page(HEADER_FIRST, 0, 1, 0, std::slice::from_ref(&head));
+        data.extend(packets_page(0, 0, 1, 1, &[tags.clone(), vec![0xFC]]));
+        assert!(OggOpusReader::new(data.as_slice()).is_err());
+
+        // Missing OpusTags.
+        let mut data = packets_page(HEADER_FIRST, 0, 1, 0, std::slice::from_ref(&head));
+        data.extend(packets_page(0, 960, 1, 1, &[vec![0xFC]]));
+        assert!(OggOpusReader::new(data.as_slice()).is_err());
+
+        // Invalid OpusHead.
+        let mut invalid = head;
+        invalid[9] = 0;
+        let mut data = packets_page(HEADER_FIRST, 0, 1, 0, &[invalid]);
+        data.extend(packets_page(0, 0, 1, 1, &[tags]));
+        assert!(OggOpusReader::new(data.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_seek() {
+        let mut writer = OggOpusWriter::new(
+            vec![],
+            &OpusHead::default(),
+            &OpusTags::default(),
+            &OggOpusWriterConfiguration {
+                page_duration: Duration::from_millis(100),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // About 1 MB of 10 ms and 20 ms packets, some contain the capture pattern.
+        let packets: Vec<Vec<u8>> = (0..3000)
+            .map(|i| {
+                let mut packet = vec![(i % 251) as u8; 200 + i % 300];
+                packet[0] = if i % 3 == 0 { 0xF4 } else { 0xFC };
+                if i % 7 == 0 {
+                    packet[10..14].copy_from_slice(b"OggS");
+                }
+                packet
+            })
+            .collect();
+        let mut granules = vec![];
+        packets.iter().for_each(|packet| {
+            writer.write_packet(packet).unwrap();
+            granules.push(writer.granule_position());
+        });
+        let end = writer.granule_position();
+        let data = writer.finish(0).unwrap();
+        assert!(data.len() > 1_000_000);
+
+        let mut reader = OggOpusReader::new(Cursor::new(data)).unwrap();
+        [0, 1, 479, 480, 1_000_000, 700_000, 5000, end - 1]
+            .iter()
+            .for_each(|&target| {
+                reader.seek(target).unwrap();
+                let index = granules.iter().position(|&x| x > target).unwrap();
+                let packet = reader.read_packet().unwrap().unwrap();
+                assert_eq!(packet.granule_position, granules[index]);
+                assert_eq!(packet.data, packets[index]);
+
+                let next = reader.read_packet().unwrap();
+                assert_eq!(next.map(|x| x.data), packets.get(index + 1).cloned());
+            });
+
+        reader.seek(end).unwrap();
+        assert!(reader.read_packet().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_chained_streams() {
+        let stereo = OpusHead {
+            pre_skip: 312,
+            ..OpusHead::default()
+        };
+        let mono = OpusHead {
+            channels: 1,
+            coupled_streams: 0,
+            mapping: vec![0],
+            ..OpusHead::default()
+        };
+        let tags = OpusTags::default();
+        let packets = encode(6);
+
+        // A link with a single Opus stream.
+        let mut data = headers(1, &stereo, &tags);
+        data.extend(packets_page(HEADER_LAST, 312 + 1920, 1, 2, &packets[..2]));
+        // A link without an Opus stream, which is skipped.
+        data.extend(packets_page(
+            HEADER_FIRST,
+            0,
+            2,
+            0,
+            &[b"\x01vorbis".to_vec()],
+        ));
+        data.extend(packets_page(HEADER_LAST, 960, 2, 1, &[b"data".to_vec()]));
+        // A link with a multiplexed stream and a missing last page.
+        data.extend(packets_page(HEADER_FIRST, 0, 3, 0, &[mono.to_bytes()]));
+        data.extend(packets_page(HEADER_FIRST, 0, 4, 0, &[b"other".to_vec()]));
+        data.extend(packets_page(0, 0, 3, 1, &[tags.to_bytes()]));
+        data.extend(packets_page(0, 0, 4, 1, &[b"data".to_vec()]));
+        data.extend(packets_page(0, 1920, 3, 2, &packets[2..4]));
+        // A link that reuses the header of the first link.
+        data.extend(headers(5, &stereo, &tags));
+        data.extend(packets_page(HEADER_LAST, 312 + 1920, 5, 2, &packets[4..]));
+
+        let mut reader = OggOpusReader::new(Cursor::new(data)).unwrap();
+        let read_all = |reader: &mut OggOpusReader<_>| {
+            std::iter::from_fn(|| reader.read_packet().unwrap())
+                .map(|packet| packet.data)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(reader.link(), 0);
+        assert_eq!(reader.head(), &stereo);
+        assert!(reader.other_streams().is_empty());
+        assert_eq!(read_all(&mut reader), packets[..2].to_vec());
+
+        assert!(reader.next_stream().unwrap());
+        assert_eq!(reader.link(), 1);
+        assert_eq!(reader.serial(), 3);
+        assert_eq!(reader.head(), &mono);
+        assert_eq!(
+            reader.other_streams(),
+            &[OggStream {
+                serial: 4,
+                header: b"other".to_vec(),
+            }]
+        );
+        assert_eq!(read_all(&mut reader), packets[2..4].to_vec());
+
+        // Seeking stays in the current link.
+        reader.seek(0).unwrap();
+        assert_eq!(read_all(&mut reader), packets[2..4].to_vec());
+        reader.seek(960).unwrap();
+        assert_eq!(read_all(&mut reader), packets[3..4].to_vec());
+
+        assert!(reader.next_stream().unwrap());
+        assert_eq!(reader.link(), 2);
+        assert_eq!(reader.serial(), 5);
+        assert!(reader.other_streams().is_empty());
+        assert_eq!(read_all(&mut reader), packets[4..].to_vec());
+
+        assert!(!reader.next_stream().unwrap());
+        assert!(reader.read_packet().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_skip_streams() {
+        let mut data = packets_page(HEADER_FIRST, 0, 2, 0, &[b"\x01vorbis".to_vec()]);
+        data.extend(packets_page(HEADER_LAST, 960, 2, 1, &[b"data".to_vec()]));
+        assert!(OggOpusReader::new(data.as_slice()).is_err());
+
+        // Leading links without an Opus stream are skipped.
+        data.extend(headers(1, &OpusHead::default(), &OpusTags::default()));
+        data.extend(packets_page(HEADER_LAST, 960, 1, 2, &[vec![0xFC]]));
+        let mut reader = OggOpusReader::new(data.as_slice()).unwrap();
+        assert_eq!(reader.serial(), 1);
+        assert_eq!(reader.link(), 0);
+        assert!(!reader.next_stream().unwrap());
+        assert!(reader.read_packet().unwrap().is_none());
+
+        // A new link before the OpusTags of the current link.
+        let mut data = packets_page(HEADER_FIRST, 0, 1, 0, &[OpusHead::default().to_bytes()]);
+        data.extend(headers(2, &OpusHead::default(), &OpusTags::default()));
+        assert!(OggOpusReader::new(data.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_random_corruption() {
+        let mut rng = nanorand::WyRand::new_seed(42);
+        let packets = encode(10);
+        let mut stream = headers(1, &OpusHead::default(), &OpusTags::default());
+        stream.extend(packets_page(0, 960 * 5, 1, 2, &packets[..5]));
+        stream.extend(packets_page(HEADER_LAST, 960 * 10, 1, 3, &packets[5..]));
+
+        (0..2000).for_each(|i| {
+            let mut data = stream.clone();
+            (0..1 + i % 4).for_each(|_| {
+                let position = rng.generate_range::<usize, _>(0..data.len());
+                data[position] = rng.generate::<u8>();
+            });
+            data.truncate(rng.generate_range::<usize, _>(1..data.len() + 1));
+
+            // Errors are fine, panics are not.
+            if let Ok(mut reader) = OggOpusReader::new(data.as_slice()) {
+                while let Ok(Some(_)) = reader.read_packet() {}
+            }
+        });
+    }
+}
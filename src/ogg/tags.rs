@@ -0,0 +1,722 @@
+//! Implements the comment header "OpusTags" and the rewriting of the comments of a stream.
+//!
+//! The comment header uses the Vorbis comment format. Pictures are stored as base64
+//! encoded FLAC picture blocks in the `METADATA_BLOCK_PICTURE` comment. The gains
+//! of RFC 7845 are stored in the `R128_TRACK_GAIN` and `R128_ALBUM_GAIN` comments.
+
+use std::io::{Read, Write};
+
+use crate::ogg::page::{read_u32, PacketAssembler, Page};
+use crate::OpusError;
+
+const PICTURE_KEY: &str = "METADATA_BLOCK_PICTURE";
+const TRACK_GAIN_KEY: &str = "R128_TRACK_GAIN";
+const ALBUM_GAIN_KEY: &str = "R128_ALBUM_GAIN";
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// The comment header of an Ogg Opus stream.
+///
+/// Comments are stored as `KEY=value` pairs. Keys are case-insensitive.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct OpusTags {
+    /// Identifies the encoder that created the stream.
+    pub vendor: String,
+    /// The user comments in the `KEY=value` form.
+    pub comments: Vec<String>,
+    /// Binary data after the comments. Default: empty.
+    ///
+    /// Only data with the least significant bit of the first byte set is kept,
+    /// everything else is padding.
+    pub binary_data: Vec<u8>,
+}
+
+impl OpusTags {
+    /// Parses an "OpusTags" packet.
+    ///
+    /// Returns an error if a string isn't valid UTF-8, so that rewriting the comments
+    /// can't alter them.
+    pub(crate) fn parse(data: &[u8]) -> Result<Self, OpusError> {
+        if data.len() < 8 || &data[..8] != b"OpusTags" {
+            return Err(OpusError::InvalidContainer("missing OpusTags"));
+        }
+        let mut data = &data[8..];
+        let vendor = read_string(&mut data)?;
+        let count = read_length(&mut data)?;
+
+        // Every comment needs at least 4 bytes, which bounds the allocation.
+        if count > data.len() / 4 {
+            return Err(OpusError::InvalidContainer("truncated OpusTags"));
+        }
+        let mut comments = Vec::with_capacity(count);
+        (0..count).try_for_each(|_| -> Result<(), OpusError> {
+            comments.push(read_string(&mut data)?);
+            Ok(())
+        })?;
+
+        let binary_data = if matches!(data.first(), Some(&x) if x & 0x1 != 0) {
+            data.to_vec()
+        } else {
+            vec![]
+        };
+
+        Ok(Self {
+            vendor,
+            comments,
+            binary_data,
+        })
+    }
+
+    /// Serializes the comments into an "OpusTags" packet.
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut data = b"OpusTags".to_vec();
+        data.extend_from_slice(&(self.vendor.len() as u32).to_le_bytes());
+        data.extend_from_slice(self.vendor.as_bytes());
+        data.extend_from_slice(&(self.comments.len() as u32).to_le_bytes());
+        self.comments.iter().for_each(|comment| {
+            data.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            data.extend_from_slice(comment.as_bytes());
+        });
+        data.extend_from_slice(&self.binary_data);
+        data
+    }
+
+    /// Returns the values of all comments with the given key.
+    ///
+    /// # Arguments
+    /// * `key` - Case-insensitive key of the comments.
+    ///
+    pub fn get<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.comments
+            .iter()
+            .filter_map(move |comment| split_comment(comment, key))
+    }
+
+    /// Adds a comment. Existing comments with the same key are kept.
+    ///
+    /// # Arguments
+    /// * `key`   - Key of the comment. Only printable ASCII characters without `=` are allowed.
+    /// * `value` - Value of the comment.
+    ///
+    pub fn add(&mut self, key: &str, value: &str) -> Result<(), OpusError> {
+        validate_key(key)?;
+        self.comments.push(format!("{}={}", key, value));
+        Ok(())
+    }
+
+    /// Replaces all comments with the given key by a single comment.
+    ///
+    /// # Arguments
+    /// * `key`   - Key of the comment. Only printable ASCII characters without `=` are allowed.
+    /// * `value` - Value of the comment.
+    ///
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), OpusError> {
+        validate_key(key)?;
+        // The new comment takes the place of the first replaced comment.
+        let position = self
+            .comments
+            .iter()
+            .position(|comment| split_comment(comment, key).is_some());
+        self.remove(key);
+        let position = position.unwrap_or(self.comments.len());
+        self.comments.insert(position, format!("{}={}", key, value));
+        Ok(())
+    }
+
+    /// Removes all comments with the given key. Returns the number of removed comments.
+    ///
+    /// # Arguments
+    /// * `key` - Case-insensitive key of the comments.
+    ///
+    pub fn remove(&mut self, key: &str) -> usize {
+        let count = self.comments.len();
+        self.comments
+            .retain(|comment| split_comment(comment, key).is_none());
+        count - self.comments.len()
+    }
+
+    /// Returns the pictures of the stream, for example the cover art.
+    ///
+    /// Returns an error for each picture that is malformed.
+    pub fn pictures(&self) -> impl Iterator<Item = Result<Picture, OpusError>> + '_ {
+        self.get(PICTURE_KEY)
+            .map(|value| base64_decode(value).and_then(|data| Picture::parse(&data)))
+    }
+
+    /// Adds a picture.
+    ///
+    /// # Arguments
+    /// * `picture` - The picture to add.
+    ///
+    pub fn add_picture(&mut self, picture: &Picture) -> Result<(), OpusError> {
+        self.add(PICTURE_KEY, &base64_encode(&picture.to_bytes()))
+    }
+
+    /// Removes all pictures. Returns the number of removed pictures.
+    pub fn remove_pictures(&mut self) -> usize {
+        self.remove(PICTURE_KEY)
+    }
+
+    /// Returns the gain that normalizes the track to a loudness of -23 LUFS in Q8 dB units.
+    ///
+    /// The gain applies in addition to the output gain of the "OpusHead".
+    pub fn track_gain(&self) -> Option<i16> {
+        self.get(TRACK_GAIN_KEY)
+            .find_map(|value| value.parse().ok())
+    }
+
+    /// Sets or removes the track gain.
+    ///
+    /// # Arguments
+    /// * `gain` - Gain in Q8 dB units.
+    ///
+    pub fn set_track_gain(&mut self, gain: Option<i16>) {
+        self.set_gain(TRACK_GAIN_KEY, gain);
+    }
+
+    /// Returns the gain that normalizes the album to a loudness of -23 LUFS in Q8 dB units.
+    ///
+    /// The gain applies in addition to the output gain of the "OpusHead".
+    pub fn album_gain(&self) -> Option<i16> {
+        self.get(ALBUM_GAIN_KEY)
+            .find_map(|value| value.parse().ok())
+    }
+
+    /// Sets or removes the album gain.
+    ///
+    /// # Arguments
+    /// * `gain` - Gain in Q8 dB units.
+    ///
+    pub fn set_album_gain(&mut self, gain: Option<i16>) {
+        self.set_gain(ALBUM_GAIN_KEY, gain);
+    }
+
+    fn set_gain(&mut self, key: &str, gain: Option<i16>) {
+        match gain {
+            Some(gain) => {
+                // The key is valid, so setting can't fail.
+                let _ = self.set(key, &gain.to_string());
+            }
+            None => {
+                self.remove(key);
+            }
+        }
+    }
+}
+
+/// A picture of the stream, stored as a FLAC picture block.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Picture {
+    /// Type of the picture, as defined by the ID3v2 "APIC" frame. Default: 0.
+    ///
+    /// For example 3 is the front cover and 4 the back cover.
+    pub picture_type: u32,
+    /// MIME type of the picture data, for example "image/png".
+    ///
+    /// The MIME type "-->" marks that the data is an URL of the picture.
+    pub mime_type: String,
+    /// Description of the picture.
+    pub description: String,
+    /// Width of the picture in pixels.
+    pub width: u32,
+    /// Height of the picture in pixels.
+    pub height: u32,
+    /// Color depth of the picture in bits per pixel.
+    pub depth: u32,
+    /// Number of colors of indexed pictures, 0 for pictures that are not indexed.
+    pub colors: u32,
+    /// The encoded picture data.
+    pub data: Vec<u8>,
+}
+
+impl Picture {
+    /// Parses a FLAC picture block.
+    pub(crate) fn parse(data: &[u8]) -> Result<Self, OpusError> {
+        let mut data = data;
+        let picture_type = read_u32_be(&mut data)?;
+        let len = read_u32_be(&mut data)? as usize;
+        let mime_type = read_picture_string(&mut data, len)?;
+        let len = read_u32_be(&mut data)? as usize;
+        let description = read_picture_string(&mut data, len)?;
+        let width = read_u32_be(&mut data)?;
+        let height = read_u32_be(&mut data)?;
+        let depth = read_u32_be(&mut data)?;
+        let colors = read_u32_be(&mut data)?;
+        let len = read_u32_be(&mut data)? as usize;
+        let data = read_bytes(&mut data, len)?.to_vec();
+
+        Ok(Self {
+            picture_type,
+            mime_type,
+            description,
+            width,
+            height,
+            depth,
+            colors,
+            data,
+        })
+    }
+
+    /// Serializes the picture into a FLAC picture block.
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(32 + self.mime_type.len() + self.data.len());
+        data.extend_from_slice(&self.picture_type.to_be_bytes());
+        data.extend_from_slice(&(self.mime_type.len() as u32).to_be_bytes());
+        data.extend_from_slice(self.mime_type.as_bytes());
+        data.extend_from_slice(&(self.description.len() as u32).to_be_bytes());
+        data.extend_from_slice(self.description.as_bytes());
+        data.extend_from_slice(&self.width.to_be_bytes());
+        data.extend_from_slice(&self.height.to_be_bytes());
+        data.extend_from_slice(&self.depth.to_be_bytes());
+        data.extend_from_slice(&self.colors.to_be_bytes());
+        data.extend_from_slice(&(self.data.len() as u32).to_be_bytes());
+        data.extend_from_slice(&self.data);
+        data
+    }
+}
+
+/// Rewrites the comment header of an Ogg Opus stream without re-encoding the audio.
+///
+/// Copies the container and replaces the "OpusTags" packet of the first Opus stream.
+/// If the new comment header needs a different number of pages, the following pages
+/// of the stream are renumbered. Pages of other logical streams are copied unchanged.
+/// Returns the writer.
+///
+/// # Arguments
+/// * `reader` - Input container.
+/// * `writer` - Output container.
+/// * `tags`   - The new comment header.
+///
+pub fn rewrite_tags<R: Read, W: Write>(
+    mut reader: R,
+    mut writer: W,
+    tags: &OpusTags,
+) -> Result<W, OpusError> {
+    let packet = tags.to_bytes();
+    let mut serial = None;
+    let mut assembler = PacketAssembler::default();
+    // Sequence number of the first page and number of pages of the old comment header.
+    let mut tags_pages: Option<(u32, u32)> = None;
+    let mut shift = None;
+
+    while let Some(mut page) = Page::read(&mut reader)? {
+        match serial {
+            None => {
+                if !page.is_first() {
+                    return Err(OpusError::InvalidContainer("missing OpusHead"));
+                }
+                if page.data.starts_with(b"OpusHead") {
+                    serial = Some(page.serial);
+                }
+            }
+            Some(serial) if page.serial == serial && shift.is_none() => {
+                let (first, count) = tags_pages.get_or_insert((page.sequence, 0));
+                *count += 1;
+                let (first, count) = (*first, *count);
+
+                let packets = assembler.push(&page);
+                if packets.is_empty() {
+                    continue;
+                }
+                if packets.len() != 1 || page.fragments().len() != 1 {
+                    return Err(OpusError::InvalidContainer("OpusTags must finish its page"));
+                }
+                OpusTags::parse(&packets[0])?;
+
+                let pages = Page::paginate(&packet, 0, serial, first, 0);
+                pages.iter().try_for_each(|page| page.write(&mut writer))?;
+                shift = Some((pages.len() as u32).wrapping_sub(count));
+                continue;
+            }
+            Some(serial) if page.serial == serial => {
+                if let Some(shift) = shift {
+                    page.sequence = page.sequence.wrapping_add(shift);
+                }
+            }
+            _ => {}
+        }
+        page.write(&mut writer)?;
+    }
+
+    if shift.is_none() {
+        return Err(OpusError::InvalidContainer("missing OpusTags"));
+    }
+    writer.flush().map_err(OpusError::Io)?;
+    Ok(writer)
+}
+
+/// Validates that the key only contains printable ASCII characters without `=`.
+fn validate_key(key: &str) -> Result<(), OpusError> {
+    if key.is_empty() || !key.bytes().all(|x| (0x20..=0x7D).contains(&x) && x != b'=') {
+        return Err(OpusError::BadArguments("invalid comment key"));
+    }
+    Ok(())
+}
+
+/// Returns the value of the comment if it has the given key.
+fn split_comment<'a>(comment: &'a str, key: &str) -> Option<&'a str> {
+    let (comment_key, value) = comment.split_at(comment.find('=')?);
+    if comment_key.eq_ignore_ascii_case(key) {
+        Some(&value[1..])
+    } else {
+        None
+    }
+}
+
+fn read_length(data: &mut &[u8]) -> Result<usize, OpusError> {
+    if data.len() < 4 {
+        return Err(OpusError::InvalidContainer("truncated OpusTags"));
+    }
+    let len = read_u32(data) as usize;
+    *data = &data[4..];
+    Ok(len)
+}
+
+fn read_string(data: &mut &[u8]) -> Result<String, OpusError> {
+    let len = read_length(data)?;
+    if data.len() < len {
+        return Err(OpusError::InvalidContainer("truncated OpusTags"));
+    }
+    let string = String::from_utf8(data[..len].to_vec())
+        .map_err(|_| OpusError::InvalidContainer("OpusTags contains invalid UTF-8"))?;
+    *data = &data[len..];
+    Ok(string)
+}
+
+fn read_bytes<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8], OpusError> {
+    if data.len() < len {
+        return Err(OpusError::InvalidContainer("truncated picture"));
+    }
+    let (value, rest) = data.split_at(len);
+    *data = rest;
+    Ok(value)
+}
+
+fn read_u32_be(data: &mut &[u8]) -> Result<u32, OpusError> {
+    let value = read_bytes(data, 4)?;
+    Ok(u32::from_be_bytes([value[0], value[1], value[2], value[3]]))
+}
+
+fn read_picture_string(data: &mut &[u8], len: usize) -> Result<String, OpusError> {
+    String::from_utf8(read_bytes(data, len)?.to_vec())
+        .map_err(|_| OpusError::InvalidContainer("picture contains invalid UTF-8"))
+}
+
+/// Encodes the data as base64 with padding.
+fn base64_encode(data: &[u8]) -> String {
+    let mut output = String::with_capacity(data.len().div_ceil(3) * 4);
+    data.chunks(3).for_each(|chunk| {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let indices = [
+            b[0] >> 2,
+            ((b[0] & 0x3) << 4) | (b[1] >> 4),
+            ((b[1] & 0xF) << 2) | (b[2] >> 6),
+            b[2] & 0x3F,
+        ];
+        indices.iter().enumerate().for_each(|(i, &index)| {
+            if i <= chunk.len() {
+                output.push(char::from(BASE64_ALPHABET[usize::from(index)]));
+            } else {
+                output.push('=');
+            }
+        });
+    });
+    output
+}
+
+/// Decodes base64 data with padding.
+fn base64_decode(data: &str) -> Result<Vec<u8>, OpusError> {
+    let data = data.as_bytes();
+    if !data.len().is_multiple_of(4) {
+        return Err(OpusError::InvalidContainer("invalid base64 data"));
+    }
+    let mut output = Vec::with_capacity(data.len() / 4 * 3);
+    let count = data.len() / 4;
+    data.chunks(4).enumerate().try_for_each(|(i, chunk)| {
+        let padding = chunk.iter().rev().take_while(|&&x| x == b'=').count();
+        if padding > 2 || (padding > 0 && i + 1 != count) {
+            return Err(OpusError::InvalidContainer("invalid base64 data"));
+        }
+        let mut value = 0_u32;
+        chunk[..4 - padding].iter().try_for_each(|&x| {
+            let index = BASE64_ALPHABET
+                .iter()
+                .position(|&y| y == x)
+                .ok_or(OpusError::InvalidContainer("invalid base64 data"))?;
+            value = (value << 6) | index as u32;
+            Ok(())
+        })?;
+        value <<= 6 * padding as u32;
+        output.extend_from_slice(&value.to_be_bytes()[1..4 - padding]);
+        Ok(())
+    })?;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::panic)]
+    #![allow(clippy::unwrap_used)]
+
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{OggOpusReader, OggOpusWriter, OggOpusWriterConfiguration, OpusHead};
+
+    fn write_stream(tags: &OpusTags) -> Vec<u8> {
+        let mut writer = OggOpusWriter::new(
+            vec![],
+            &OpusHead::default(),
+            tags,
+            &OggOpusWriterConfiguration {
+                serial: 5,
+                page_duration: Duration::from_millis(100),
+            },
+        )
+        .unwrap();
+        (0..50).for_each(|i| {
+            let mut packet = vec![i as u8; 100 + i * 10];
+            packet[0] = 0xFC;
+            writer.write_packet(&packet).unwrap();
+        });
+        writer.finish(100).unwrap()
+    }
+
+    fn read_stream(data: &[u8]) -> (OpusTags, Vec<(Vec<u8>, u64)>) {
+        let mut reader = OggOpusReader::new(data).unwrap();
+        let mut packets = vec![];
+        while let Some(packet) = reader.read_packet().unwrap() {
+            packets.push((packet.data, packet.granule_position));
+        }
+        (reader.tags().clone(), packets)
+    }
+
+    #[test]
+    fn test_opus_tags() {
+        let tags = OpusTags {
+            vendor: "opus-native".to_string(),
+            comments: vec![
+                "TITLE=Test".to_string(),
+                "artist=A".to_string(),
+                "ARTIST=B".to_string(),
+            ],
+            binary_data: vec![],
+        };
+        let data = tags.to_bytes();
+        assert_eq!(OpusTags::parse(&data).unwrap(), tags);
+        assert_eq!(tags.get("Artist").collect::<Vec<_>>(), vec!["A", "B"]);
+        assert_eq!(tags.get("TITLE").collect::<Vec<_>>(), vec!["Test"]);
+        assert_eq!(tags.get("ALBUM").count(), 0);
+
+        assert!(OpusTags::parse(&data[..data.len() - 1]).is_err());
+        assert!(OpusTags::parse(b"OpusHead").is_err());
+        // Huge comment count.
+        let mut invalid = data[..23].to_vec();
+        invalid.extend_from_slice(&[0xFF; 4]);
+        assert!(OpusTags::parse(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_invalid_utf8() {
+        let tags = OpusTags {
+            vendor: "opus-native".to_string(),
+            comments: vec!["TITLE=Grüße".to_string(), "ARTIST=Test".to_string()],
+            binary_data: vec![],
+        };
+        let data = tags.to_bytes();
+        assert_eq!(OpusTags::parse(&data).unwrap().to_bytes(), data);
+
+        // Comments that aren't valid UTF-8 are rejected instead of being altered.
+        let position = data.windows(4).position(|x| x == b"Test").unwrap();
+        let mut invalid = data.clone();
+        invalid[position + 1] = 0xFF;
+        assert!(OpusTags::parse(&invalid).is_err());
+        // The vendor string starts with a truncated multi-byte sequence.
+        let mut invalid = data;
+        invalid[12] = 0xC3;
+        assert!(OpusTags::parse(&invalid).is_err());
+
+        // Neither reading nor rewriting accepts them.
+        let original = write_stream(&tags);
+        let mut data = original.as_slice();
+        let mut stream = vec![];
+        while let Some(mut page) = Page::read(&mut data).unwrap() {
+            if let Some(position) = page.data.windows(4).position(|x| x == b"Test") {
+                page.data[position + 1] = 0xFF;
+            }
+            page.write(&mut stream).unwrap();
+        }
+        assert!(OggOpusReader::new(stream.as_slice()).is_err());
+        assert!(rewrite_tags(stream.as_slice(), vec![], &tags).is_err());
+
+        let picture = Picture {
+            description: "Cover".to_string(),
+            ..Picture::default()
+        };
+        let mut data = picture.to_bytes();
+        data[12] = 0xFF;
+        assert!(Picture::parse(&data).is_err());
+    }
+
+    #[test]
+    fn test_binary_data() {
+        let mut data = OpusTags::default().to_bytes();
+        data.extend_from_slice(&[0x0, 0x0]);
+        assert!(OpusTags::parse(&data).unwrap().binary_data.is_empty());
+
+        let len = data.len();
+        data[len - 2] = 0x1;
+        let tags = OpusTags::parse(&data).unwrap();
+        assert_eq!(tags.binary_data, vec![0x1, 0x0]);
+        assert_eq!(tags.to_bytes(), data);
+    }
+
+    #[test]
+    fn test_edit_comments() {
+        let mut tags = OpusTags::default();
+        tags.add("TITLE", "First").unwrap();
+        tags.add("ARTIST", "A").unwrap();
+        tags.add("artist", "B").unwrap();
+        tags.add("ALBUM", "Album").unwrap();
+        assert!(tags.add("TI=TLE", "x").is_err());
+        assert!(tags.add("", "x").is_err());
+        assert!(tags.add("TITLE\n", "x").is_err());
+
+        tags.set("Artist", "C=D").unwrap();
+        assert_eq!(
+            tags.comments,
+            vec!["TITLE=First", "Artist=C=D", "ALBUM=Album"]
+        );
+        assert_eq!(tags.get("ARTIST").collect::<Vec<_>>(), vec!["C=D"]);
+
+        tags.set("GENRE", "Jazz").unwrap();
+        assert_eq!(tags.comments.last().unwrap(), "GENRE=Jazz");
+        assert_eq!(tags.remove("title"), 1);
+        assert_eq!(tags.remove("title"), 0);
+        assert_eq!(tags.remove("genre"), 1);
+        assert_eq!(tags.comments, vec!["Artist=C=D", "ALBUM=Album"]);
+    }
+
+    #[test]
+    fn test_gains() {
+        let mut tags = OpusTags::default();
+        assert_eq!(tags.track_gain(), None);
+        tags.add("R128_TRACK_GAIN", "invalid").unwrap();
+        assert_eq!(tags.track_gain(), None);
+
+        tags.set_track_gain(Some(-573));
+        tags.set_album_gain(Some(256));
+        assert_eq!(tags.track_gain(), Some(-573));
+        assert_eq!(tags.album_gain(), Some(256));
+        assert_eq!(
+            tags.comments,
+            vec!["R128_TRACK_GAIN=-573", "R128_ALBUM_GAIN=256"]
+        );
+
+        tags.set_track_gain(None);
+        assert_eq!(tags.track_gain(), None);
+        assert_eq!(tags.comments, vec!["R128_ALBUM_GAIN=256"]);
+    }
+
+    #[test]
+    fn test_base64() {
+        [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ]
+        .iter()
+        .for_each(|(plain, encoded)| {
+            assert_eq!(&base64_encode(plain.as_bytes()), encoded);
+            assert_eq!(base64_decode(encoded).unwrap(), plain.as_bytes());
+        });
+
+        let data: Vec<u8> = (0..=255).collect();
+        assert_eq!(base64_decode(&base64_encode(&data)).unwrap(), data);
+
+        assert!(base64_decode("Zm9").is_err());
+        assert!(base64_decode("Zm=v").is_err());
+        assert!(base64_decode("Zg==Zg==").is_err());
+        assert!(base64_decode("Z===").is_err());
+        assert!(base64_decode("Zm9!").is_err());
+    }
+
+    #[test]
+    fn test_pictures() {
+        let picture = Picture {
+            picture_type: 3,
+            mime_type: "image/png".to_string(),
+            description: "Cover".to_string(),
+            width: 640,
+            height: 480,
+            depth: 24,
+            colors: 0,
+            data: (0..1000).map(|x| x as u8).collect(),
+        };
+        let mut tags = OpusTags::default();
+        tags.add_picture(&picture).unwrap();
+        tags.add("METADATA_BLOCK_PICTURE", "invalid").unwrap();
+
+        let pictures: Vec<_> = tags.pictures().collect();
+        assert_eq!(pictures.len(), 2);
+        assert_eq!(pictures[0].as_ref().unwrap(), &picture);
+        assert!(pictures[1].is_err());
+
+        let data = picture.to_bytes();
+        assert!(Picture::parse(&data[..data.len() - 1]).is_err());
+
+        assert_eq!(tags.remove_pictures(), 2);
+        assert_eq!(tags.pictures().count(), 0);
+    }
+
+    #[test]
+    fn test_rewrite_tags() {
+        let tags = OpusTags {
+            vendor: "opus-native".to_string(),
+            comments: vec!["TITLE=Test".to_string()],
+            binary_data: vec![],
+        };
+        let original = write_stream(&tags);
+        let (_, packets) = read_stream(&original);
+
+        // A large picture needs multiple pages.
+        let mut new_tags = tags.clone();
+        new_tags.set("TITLE", "New").unwrap();
+        new_tags.set_track_gain(Some(-100));
+        new_tags
+            .add_picture(&Picture {
+                picture_type: 3,
+                mime_type: "image/jpeg".to_string(),
+                data: vec![0xAB; 200_000],
+                ..Picture::default()
+            })
+            .unwrap();
+        let rewritten = rewrite_tags(original.as_slice(), vec![], &new_tags).unwrap();
+        let (read_tags, read_packets) = read_stream(&rewritten);
+        assert_eq!(read_tags, new_tags);
+        assert_eq!(read_packets, packets);
+
+        // The pages are numbered without gaps.
+        let mut data = rewritten.as_slice();
+        let mut sequence = 0;
+        while let Some(page) = Page::read(&mut data).unwrap() {
+            assert_eq!(page.sequence, sequence);
+            sequence += 1;
+        }
+
+        // Rewriting the original tags restores the original file.
+        let restored = rewrite_tags(rewritten.as_slice(), vec![], &tags).unwrap();
+        assert_eq!(restored, original);
+
+        assert!(rewrite_tags(&original[..original.len() / 100], vec![], &tags).is_err());
+    }
+}
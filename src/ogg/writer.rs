@@ -0,0 +1,347 @@
+//! Implements the Ogg Opus muxer.
+
+use std::io::Write;
+use std::time::Duration;
+
+use crate::ogg::page::{
+    lace, Page, HEADER_CONTINUED, HEADER_FIRST, HEADER_LAST, NO_GRANULE_POSITION,
+};
+use crate::{query_packet_sample_count, OpusError, OpusHead, OpusTags, SamplingRate};
+
+/// Configures the Ogg Opus writer on creation.
+#[derive(Clone, Debug)]
+pub struct OggOpusWriterConfiguration {
+    /// Serial number of the logical stream. Default: 0.
+    ///
+    /// Chained or multiplexed streams need a unique serial number for each stream.
+    pub serial: u32,
+    /// Maximum duration of the audio on a single page. Default: 1 s.
+    ///
+    /// Shorter pages reduce the latency of live streams and speed up seeking at the
+    /// cost of a higher container overhead.
+    pub page_duration: Duration,
+}
+
+impl Default for OggOpusWriterConfiguration {
+    fn default() -> Self {
+        Self {
+            serial: 0,
+            page_duration: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Writes Opus packets into an Ogg container.
+///
+/// The headers are written on creation. The stream needs to be finished with
+/// `finish()`, which writes the last page.
+#[derive(Debug)]
+pub struct OggOpusWriter<W: Write> {
+    writer: W,
+    serial: u32,
+    sequence: u32,
+    page_duration: u64,
+    granule_position: u64,
+    page_samples: u64,
+    lacing: Vec<u8>,
+    data: Vec<u8>,
+    continued: bool,
+}
+
+impl<W: Write> OggOpusWriter<W> {
+    /// Creates a new `OggOpusWriter` and writes the headers of the stream.
+    ///
+    /// The pre-skip of the header should be set to the lookahead of the encoder,
+    /// converted to 48 kHz samples.
+    ///
+    /// # Arguments
+    /// * `writer`        - Output of the container.
+    /// * `head`          - The identification header of the stream.
+    /// * `tags`          - The comment header of the stream.
+    /// * `configuration` - Configuration of the writer.
+    ///
+    pub fn new(
+        writer: W,
+        head: &OpusHead,
+        tags: &OpusTags,
+        configuration: &OggOpusWriterConfiguration,
+    ) -> Result<Self, OpusError> {
+        if head.validate().is_err() {
+            return Err(OpusError::BadArguments("invalid OpusHead"));
+        }
+        let page_duration = configuration.page_duration.as_micros() as u64 * 48 / 1000;
+        if page_duration == 0 {
+            return Err(OpusError::BadArguments("page duration must not be zero"));
+        }
+
+        let mut ogg_writer = Self {
+            writer,
+            serial: configuration.serial,
+            sequence: 0,
+            page_duration,
+            granule_position: 0,
+            page_samples: 0,
+            lacing: vec![],
+            data: vec![],
+            continued: false,
+        };
+
+        // Both headers need to finish their pages.
+        [(head.to_bytes(), HEADER_FIRST), (tags.to_bytes(), 0)]
+            .iter()
+            .try_for_each(|(packet, header_type)| {
+                Page::paginate(
+                    packet,
+                    *header_type,
+                    ogg_writer.serial,
+                    ogg_writer.sequence,
+                    0,
+                )
+                .iter()
+                .try_for_each(|page| {
+                    page.write(&mut ogg_writer.writer)?;
+                    ogg_writer.sequence = ogg_writer.sequence.wrapping_add(1);
+                    Ok(())
+                })
+            })?;
+
+        Ok(ogg_writer)
+    }
+
+    /// Returns the granule position after the last written packet.
+    pub fn granule_position(&self) -> u64 {
+        self.granule_position
+    }
+
+    /// Writes an Opus packet.
+    ///
+    /// Packets are collected until the page duration is reached. The page that
+    /// contains the last packet is only written by `finish()`.
+    ///
+    /// # Arguments
+    /// * `packet` - The Opus packet.
+    ///
+    pub fn write_packet(&mut self, packet: &[u8]) -> Result<(), OpusError> {
+        let samples = query_packet_sample_count(packet, SamplingRate::Hz48000)? as u64;
+
+        let segments = packet.len() / 255 + 1;
+        if !self.lacing.is_empty()
+            && (self.lacing.len() + segments > 255
+                || self.page_samples + samples > self.page_duration)
+        {
+            self.write_page(0, self.granule_position)?;
+        }
+
+        self.append(packet)?;
+        self.granule_position += samples;
+        self.page_samples += samples;
+
+        Ok(())
+    }
+
+    /// Writes the last page of the stream and returns the underlying writer.
+    ///
+    /// # Arguments
+    /// * `end_trim` - Number of 48 kHz samples to discard from the end of the decoded
+    ///   signal. This is the padding of the last frame that the encoder
+    ///   added to the input. It can't exceed the duration of the last page.
+    ///
+    pub fn finish(mut self, end_trim: u64) -> Result<W, OpusError> {
+        if end_trim > self.page_samples {
+            return Err(OpusError::BadArguments(
+                "end trim must not exceed the duration of the last page",
+            ));
+        }
+        let granule_position = self.granule_position - end_trim;
+        self.write_page(HEADER_LAST, granule_position)?;
+        self.writer.flush().map_err(OpusError::Io)?;
+        Ok(self.writer)
+    }
+
+    /// Appends a packet to the current page. Writes full pages of packets that need
+    /// more than 255 segments.
+    fn append(&mut self, packet: &[u8]) -> Result<(), OpusError> {
+        self.lacing.extend(lace(packet.len()));
+        self.data.extend_from_slice(packet);
+
+        while self.lacing.len() > 255 {
+            // The packet doesn't finish on these pages.
+            let size = 255 * 255;
+            let lacing = self.lacing.split_off(255);
+            let data = self.data.split_off(size);
+            self.write_page(0, NO_GRANULE_POSITION)?;
+            self.lacing = lacing;
+            self.data = data;
+            self.continued = true;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the current page.
+    fn write_page(&mut self, header_type: u8, granule_position: u64) -> Result<(), OpusError> {
+        let header_type = if self.continued {
+            header_type | HEADER_CONTINUED
+        } else {
+            header_type
+        };
+        let page = Page {
+            header_type,
+            granule_position,
+            serial: self.serial,
+            sequence: self.sequence,
+            lacing: std::mem::take(&mut self.lacing),
+            data: std::mem::take(&mut self.data),
+        };
+        page.write(&mut self.writer)?;
+
+        self.sequence = self.sequence.wrapping_add(1);
+        self.page_samples = 0;
+        self.continued = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::panic)]
+    #![allow(clippy::unwrap_used)]
+
+    use super::*;
+    use crate::{Encoder, EncoderConfiguration, OggOpusReader};
+
+    fn page_count(data: &[u8]) -> usize {
+        data.windows(4).filter(|x| x == b"OggS").count()
+    }
+
+    #[test]
+    fn test_write_stream() {
+        let mut encoder = Encoder::new(&EncoderConfiguration::default()).unwrap();
+        let head = OpusHead {
+            pre_skip: encoder.lookahead() as u16,
+            ..OpusHead::default()
+        };
+        let tags = OpusTags {
+            vendor: "opus-native".to_string(),
+            comments: vec!["TITLE=Test".to_string()],
+            ..OpusTags::default()
+        };
+        let configuration = OggOpusWriterConfiguration {
+            serial: 42,
+            page_duration: Duration::from_millis(200),
+        };
+        let mut writer = OggOpusWriter::new(vec![], &head, &tags, &configuration).unwrap();
+
+        let mut output = [0_u8; 1500];
+        let packets: Vec<Vec<u8>> = (0..60)
+            .map(|i| {
+                let samples: Vec<f32> = (0..960 * 2)
+                    .map(|j| ((i * 960 + j / 2) as f32 * 0.03).sin() * 0.5)
+                    .collect();
+                let len = encoder.encode_float(&samples, &mut output).unwrap();
+                output[..len].to_vec()
+            })
+            .collect();
+        packets
+            .iter()
+            .for_each(|packet| writer.write_packet(packet).unwrap());
+        assert_eq!(writer.granule_position(), 60 * 960);
+
+        // The input was 500 samples shorter than the encoded frames.
+        let data = writer.finish(500).unwrap();
+        // Two header pages and 6 pages with 10 packets each.
+        assert_eq!(page_count(&data), 8);
+
+        let mut reader = OggOpusReader::new(data.as_slice()).unwrap();
+        assert_eq!(reader.head(), &head);
+        assert_eq!(reader.head().pre_skip, 312);
+        assert_eq!(reader.tags(), &tags);
+        assert_eq!(reader.serial(), 42);
+
+        let mut count = 0;
+        while let Some(packet) = reader.read_packet().unwrap() {
+            assert_eq!(packet.data, packets[count]);
+            let expected = if count == 59 {
+                60 * 960 - 500
+            } else {
+                (count as u64 + 1) * 960
+            };
+            assert_eq!(packet.granule_position, expected);
+            assert_eq!(packet.end_of_stream, count == 59);
+            count += 1;
+        }
+        assert_eq!(count, 60);
+    }
+
+    #[test]
+    fn test_write_large_packets() {
+        // Large comments span multiple pages.
+        let tags = OpusTags {
+            vendor: "opus-native".to_string(),
+            comments: vec![format!("COMMENT={}", "x".repeat(100_000))],
+            ..OpusTags::default()
+        };
+        let mut writer = OggOpusWriter::new(
+            vec![],
+            &OpusHead::default(),
+            &tags,
+            &OggOpusWriterConfiguration::default(),
+        )
+        .unwrap();
+
+        let mut large = vec![0x7_u8; 70_000];
+        large[0] = 0xFC;
+        writer.write_packet(&[0xFC, 1, 2]).unwrap();
+        writer.write_packet(&large).unwrap();
+        writer.write_packet(&[0xFC, 3]).unwrap();
+        let data = writer.finish(0).unwrap();
+        assert_eq!(page_count(&data), 6);
+
+        let mut reader = OggOpusReader::new(data.as_slice()).unwrap();
+        assert_eq!(reader.tags(), &tags);
+        let packets: Vec<_> = std::iter::from_fn(|| reader.read_packet().unwrap()).collect();
+        assert_eq!(packets.len(), 3);
+        assert_eq!(packets[0].data, vec![0xFC, 1, 2]);
+        assert_eq!(packets[0].granule_position, 960);
+        assert_eq!(packets[1].data, large);
+        assert_eq!(packets[1].granule_position, 1920);
+        assert_eq!(packets[2].data, vec![0xFC, 3]);
+        assert_eq!(packets[2].granule_position, 2880);
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        let tags = OpusTags::default();
+        assert!(OggOpusWriter::new(
+            vec![],
+            &OpusHead {
+                channels: 3,
+                ..OpusHead::default()
+            },
+            &tags,
+            &OggOpusWriterConfiguration::default(),
+        )
+        .is_err());
+        assert!(OggOpusWriter::new(
+            vec![],
+            &OpusHead::default(),
+            &tags,
+            &OggOpusWriterConfiguration {
+                page_duration: Duration::from_micros(1),
+                ..Default::default()
+            },
+        )
+        .is_err());
+
+        let mut writer = OggOpusWriter::new(
+            vec![],
+            &OpusHead::default(),
+            &tags,
+            &OggOpusWriterConfiguration::default(),
+        )
+        .unwrap();
+        assert!(writer.write_packet(&[]).is_err());
+        writer.write_packet(&[0xFC, 1]).unwrap();
+        assert!(writer.finish(961).is_err());
+    }
+}
@@ -0,0 +1,871 @@
+//! Implements the parsing of Opus packets.
+
+use crate::{Bandwidth, Channels, CodecMode, Extensions, FrameDuration, OpusError, SamplingRate};
+
+/// A parsed Opus packet.
+///
+/// The packet borrows the payload and doesn't copy the frames. The payload is fully
+/// validated on creation, so inspecting the packet afterwards can't fail.
+#[derive(Clone, Copy, Debug)]
+pub struct Packet<'a> {
+    data: &'a [u8],
+    frame_count: usize,
+    sizes: [usize; 48],
+    payload_offset: usize,
+    padding: usize,
+}
+
+impl<'a> Packet<'a> {
+    /// Parses an Opus packet.
+    ///
+    /// # Arguments
+    /// * `data` - Input payload.
+    ///
+    pub fn new(data: &'a [u8]) -> Result<Self, OpusError> {
+        Self::parse(data, false)
+    }
+
+    /// Parses an Opus packet with self-delimited framing (RFC 6716 Appendix B).
+    ///
+    /// Only the first packet inside `data` is parsed. Use `as_bytes()` to get the
+    /// bytes that belong to the packet and to find the start of the next packet.
+    ///
+    /// # Arguments
+    /// * `data` - Input payload.
+    ///
+    pub fn new_self_delimited(data: &'a [u8]) -> Result<Self, OpusError> {
+        Self::parse(data, true)
+    }
+
+    fn parse(data: &'a [u8], self_delimited: bool) -> Result<Self, OpusError> {
+        if data.is_empty() {
+            return Err(OpusError::InvalidPacket);
+        }
+
+        let mut sizes = [0_usize; 48];
+        let mut payload_offset = 0;
+        let mut packet_offset = 0;
+        let frame_count = parse_packet(
+            data,
+            self_delimited,
+            None,
+            &mut sizes,
+            Some(&mut payload_offset),
+            Some(&mut packet_offset),
+        )?;
+        let frames_size: usize = sizes[..frame_count].iter().sum();
+        let padding = packet_offset - payload_offset - frames_size;
+
+        Ok(Self {
+            data: &data[..packet_offset],
+            frame_count,
+            sizes,
+            payload_offset,
+            padding,
+        })
+    }
+
+    /// Returns the payload of the packet.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Returns the TOC byte of the packet.
+    pub fn toc(&self) -> u8 {
+        self.data[0]
+    }
+
+    /// Returns the codec mode of the packet.
+    pub fn codec_mode(&self) -> CodecMode {
+        toc_codec_mode(self.toc())
+    }
+
+    /// Returns the bandwidth of the packet.
+    pub fn bandwidth(&self) -> Bandwidth {
+        toc_bandwidth(self.toc())
+    }
+
+    /// Returns the number of channels of the packet.
+    pub fn channels(&self) -> Channels {
+        toc_channel_count(self.toc())
+    }
+
+    /// Returns the duration of a single frame of the packet.
+    pub fn frame_duration(&self) -> FrameDuration {
+        match toc_samples_per_frame(self.toc(), SamplingRate::Hz48000) {
+            120 => FrameDuration::Ms2_5,
+            240 => FrameDuration::Ms5,
+            480 => FrameDuration::Ms10,
+            960 => FrameDuration::Ms20,
+            1920 => FrameDuration::Ms40,
+            _ => FrameDuration::Ms60,
+        }
+    }
+
+    /// Returns the number of samples per frame.
+    ///
+    /// # Arguments
+    /// * `sampling_rate` - Sampling rate.
+    ///
+    pub fn samples_per_frame(&self, sampling_rate: SamplingRate) -> usize {
+        toc_samples_per_frame(self.toc(), sampling_rate)
+    }
+
+    /// Returns the number of frames in the packet.
+    pub fn frame_count(&self) -> usize {
+        self.frame_count
+    }
+
+    /// Returns the number of samples of the packet.
+    ///
+    /// # Arguments
+    /// * `sampling_rate` - Sampling rate.
+    ///
+    pub fn sample_count(&self, sampling_rate: SamplingRate) -> usize {
+        self.frame_count * self.samples_per_frame(sampling_rate)
+    }
+
+    /// Returns the number of padding bytes of the packet.
+    pub fn padding(&self) -> usize {
+        self.padding
+    }
+
+    /// Returns an iterator over the extensions inside the padding of the packet.
+    pub fn extensions(&self) -> Extensions<'a> {
+        Extensions::new(
+            &self.data[self.data.len() - self.padding..],
+            self.frame_count,
+        )
+    }
+
+    /// Returns an iterator over the frames of the packet.
+    pub fn frames(&self) -> Frames<'a> {
+        Frames {
+            packet: *self,
+            index: 0,
+            offset: self.payload_offset,
+        }
+    }
+}
+
+/// Iterator over the frames of an Opus packet.
+#[derive(Clone, Debug)]
+pub struct Frames<'a> {
+    packet: Packet<'a>,
+    index: usize,
+    offset: usize,
+}
+
+impl<'a> Iterator for Frames<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.packet.frame_count {
+            return None;
+        }
+
+        let size = self.packet.sizes[self.index];
+        let frame = &self.packet.data[self.offset..self.offset + size];
+        self.index += 1;
+        self.offset += size;
+
+        Some(frame)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.packet.frame_count - self.index;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<'a> ExactSizeIterator for Frames<'a> {}
+
+/// Returns the bandwidth of an Opus packet.
+///
+/// # Arguments
+/// * `packet` - Input payload.
+///
+pub(crate) fn query_packet_bandwidth(packet: &[u8]) -> Result<Bandwidth, OpusError> {
+    Ok(toc_bandwidth(first_byte(packet)?))
+}
+
+/// Returns the number of channels from an Opus packet.
+///
+/// # Arguments
+/// * `packet` - Input payload.
+///
+pub(crate) fn query_packet_channel_count(packet: &[u8]) -> Result<Channels, OpusError> {
+    Ok(toc_channel_count(first_byte(packet)?))
+}
+
+/// Returns the number of frames in an Opus packet.
+///
+/// # Arguments
+/// * `packet` - Input payload.
+///
+pub(crate) fn query_packet_frame_count(packet: &[u8]) -> Result<usize, OpusError> {
+    let count = first_byte(packet)? & 0x3;
+    if count == 0 {
+        Ok(1)
+    } else if count != 3 {
+        Ok(2)
+    } else if packet.len() < 2 {
+        Err(OpusError::InvalidPacket)
+    } else {
+        Ok((packet[1] & 0x3F) as usize)
+    }
+}
+
+/// Returns the number of samples per frame from an Opus packet.
+///
+/// # Arguments
+/// * `packet`        - Input payload.
+/// * `sampling_rate` - Sampling rate.
+///
+pub(crate) fn query_packet_samples_per_frame(
+    packet: &[u8],
+    sampling_rate: SamplingRate,
+) -> Result<usize, OpusError> {
+    Ok(toc_samples_per_frame(first_byte(packet)?, sampling_rate))
+}
+
+/// Returns the number of samples of an Opus packet.
+///
+/// # Arguments
+/// * `packet`        - Input payload.
+/// * `sampling_rate` - Sampling rate.
+///
+pub(crate) fn query_packet_sample_count(
+    packet: &[u8],
+    sampling_rate: SamplingRate,
+) -> Result<usize, OpusError> {
+    let count = query_packet_frame_count(packet)?;
+    let samples = count * query_packet_samples_per_frame(packet, sampling_rate)?;
+    if samples * 25 > sampling_rate as usize * 3 {
+        Err(OpusError::InvalidPacket)
+    } else {
+        Ok(samples)
+    }
+}
+
+/// Returns the codec mode of the Opus packet.
+///
+/// # Arguments
+/// * `packet`        - Input payload.
+///
+pub(crate) fn query_packet_codec_mode(packet: &[u8]) -> Result<CodecMode, OpusError> {
+    Ok(toc_codec_mode(first_byte(packet)?))
+}
+
+fn first_byte(packet: &[u8]) -> Result<u8, OpusError> {
+    packet.first().copied().ok_or(OpusError::InvalidPacket)
+}
+
+fn toc_bandwidth(toc: u8) -> Bandwidth {
+    ((toc & 0xF8) >> 3).into()
+}
+
+fn toc_channel_count(toc: u8) -> Channels {
+    if toc & 0x4 != 0 {
+        Channels::Stereo
+    } else {
+        Channels::Mono
+    }
+}
+
+fn toc_samples_per_frame(toc: u8, sampling_rate: SamplingRate) -> usize {
+    if toc & 0x80 != 0 {
+        let audio_size = usize::from((toc >> 3) & 0x3);
+        ((sampling_rate as usize) << audio_size) / 400
+    } else if (toc & 0x60) == 0x60 {
+        if toc & 0x08 != 0 {
+            sampling_rate as usize / 50
+        } else {
+            sampling_rate as usize / 100
+        }
+    } else {
+        let audio_size = usize::from((toc >> 3) & 0x3);
+        if audio_size == 3 {
+            sampling_rate as usize * 60 / 1000
+        } else {
+            ((sampling_rate as usize) << audio_size) / 100
+        }
+    }
+}
+
+fn toc_codec_mode(toc: u8) -> CodecMode {
+    if toc & 0x80 == 0x80 {
+        CodecMode::CeltOnly
+    } else if toc & 0x60 == 0x60 {
+        CodecMode::Hybrid
+    } else {
+        CodecMode::SilkOnly
+    }
+}
+
+/// Parse an Opus packet into one or more frames.
+///
+/// Returns the number of frames inside the packet.
+///
+/// Opus_decode will perform this operation internally so most applications do not need
+/// to use this function.
+///
+/// This function does not copy the frames, it returns the offsets to the frames inside the packet.
+///
+/// # Arguments
+/// * `packet`         - Opus packet to be parsed.
+/// * `self_delimited` - True if the packet has self delimited framing.
+/// * `frames`         - Returns the encapsulated frame offsets.
+/// * `sizes`          - Returns the sizes of the encapsulated frames.
+/// * `payload_offset` - Returns the position of the payload within the packet (in bytes).
+/// * `packet_offset`  - Returns the position of the next packet (in bytes) in
+///   multi channel packets.
+///
+pub(crate) fn parse_packet(
+    packet: &[u8],
+    self_delimited: bool,
+    mut frames: Option<&mut [usize; 48]>,
+    sizes: &mut [usize; 48],
+    payload_offset: Option<&mut usize>,
+    packet_offset: Option<&mut usize>,
+) -> Result<usize, OpusError> {
+    if packet.is_empty() {
+        return Err(OpusError::InvalidPacket);
+    }
+    let framesize = query_packet_samples_per_frame(packet, SamplingRate::Hz48000)?;
+    let mut offset = 1;
+    let mut len = packet.len() - offset;
+    let mut last_size = len;
+    let mut cbr = false;
+    let mut pad = 0;
+    let count: usize;
+
+    match packet[0] & 0x3 {
+        0 => {
+            // One frame.
+            count = 1;
+        }
+        1 => {
+            // Two CBR frames.
+            count = 2;
+            cbr = true;
+
+            if !self_delimited {
+                if len & 0x1 == 1 {
+                    return Err(OpusError::InvalidPacket);
+                }
+                last_size = len / 2;
+                // If last_size doesn't fit in size[0], we'll catch it later.
+                sizes[0] = last_size;
+            }
+        }
+        2 => {
+            // Two VBR frames.
+            count = 2;
+            let bytes = parse_size(&packet[offset..offset + len], &mut sizes[0])?;
+            len -= bytes;
+            if sizes[0] > len {
+                return Err(OpusError::InvalidPacket);
+            }
+            offset += bytes;
+            last_size = len - sizes[0];
+        }
+        3 => {
+            // Multiple CBR/VBR frames (from 0 to 120 ms).
+            if len < 1 {
+                return Err(OpusError::InvalidPacket);
+            }
+            // Number of frames encoded in bits 0 to 5.
+            let ch = usize::from(packet[offset]);
+            offset += 1;
+
+            count = ch & 0x3F;
+            if count == 0 || framesize * count > 5760 {
+                return Err(OpusError::InvalidPacket);
+            }
+            len -= 1;
+
+            // Padding flag is bit 6.
+            if ch & 0x40 != 0x0 {
+                let mut p = 255;
+                while p == 255 {
+                    if len == 0 {
+                        return Err(OpusError::InvalidPacket);
+                    }
+                    p = usize::from(packet[offset]);
+                    offset += 1;
+                    len -= 1;
+
+                    let tmp = if p == 255 { 254 } else { p };
+                    if tmp > len {
+                        return Err(OpusError::InvalidPacket);
+                    }
+                    len -= tmp;
+                    pad += tmp;
+                }
+            }
+
+            // VBR flag is bit 7.
+            cbr = ch & 0x80 == 0;
+            if !cbr {
+                // VBR case
+                last_size = len;
+                (0..count - 1).try_for_each(|i| {
+                    let bytes = parse_size(&packet[offset..offset + len], &mut sizes[i])?;
+                    len -= bytes;
+                    if sizes[i] > len || bytes + sizes[i] > last_size {
+                        return Err(OpusError::InvalidPacket);
+                    }
+                    offset += bytes;
+                    last_size -= bytes + sizes[i];
+
+                    Ok(())
+                })?;
+            } else if !self_delimited {
+                // CBR case.
+                last_size = len / count;
+                if last_size * count != len {
+                    return Err(OpusError::InvalidPacket);
+                }
+                (0..count - 1).for_each(|i| {
+                    sizes[i] = last_size;
+                });
+            }
+        }
+        _ => {
+            unreachable!()
+        }
+    }
+
+    // Self-delimited framing has an extra size for the last frame.
+    if self_delimited {
+        let bytes = parse_size(&packet[offset..offset + len], &mut sizes[count - 1])?;
+        len -= bytes;
+        if sizes[count - 1] > len {
+            return Err(OpusError::InvalidPacket);
+        }
+        offset += bytes;
+        // For CBR packets, apply the size to all the frames.
+        if cbr {
+            if sizes[count - 1] * count > len {
+                return Err(OpusError::InvalidPacket);
+            }
+            (0..count - 1).for_each(|i| {
+                sizes[i] = sizes[count - 1];
+            });
+        } else if bytes + sizes[count - 1] > last_size {
+            return Err(OpusError::InvalidPacket);
+        }
+    } else {
+        // Because it's not encoded explicitly, it's possible the size of the
+        // last packet (or all the packets, for the CBR case) is larger than
+        // 1275. Reject them here.
+        if last_size > 1275 {
+            return Err(OpusError::InvalidPacket);
+        }
+        sizes[count - 1] = last_size;
+    }
+
+    if let Some(payload_offset) = payload_offset {
+        *payload_offset = offset;
+    }
+
+    (0..count).for_each(|i| {
+        if let Some(frames) = &mut frames {
+            frames[i] = offset;
+        }
+
+        offset += sizes[i];
+    });
+
+    if let Some(packet_offset) = packet_offset {
+        *packet_offset = pad + offset;
+    }
+
+    Ok(count)
+}
+
+fn parse_size(data: &[u8], size: &mut usize) -> Result<usize, OpusError> {
+    if data.is_empty() {
+        Err(OpusError::InvalidPacket)
+    } else if data[0] < 252 {
+        *size = data[0] as usize;
+        Ok(1)
+    } else if data.len() < 2 {
+        Err(OpusError::InvalidPacket)
+    } else {
+        *size = 4 * usize::from(data[1]) + usize::from(data[0]);
+        Ok(2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+
+    use super::*;
+    use crate::Extension;
+    use nanorand::Rng;
+
+    const TEST_PACKET_SINGLE: &[u8] = &[
+        0x80, 0xDA, 0x84, 0xE8, 0x87, 0x77, 0x83, 0xD6, 0x48, 0xB3, 0x6B, 0x45,
+    ];
+    const TEST_PACKET_CBR: &[u8] = &[
+        0x81, 0xDA, 0x84, 0xE8, 0x87, 0x77, 0x83, 0xD6, 0x48, 0xB3, 0x6B,
+    ];
+    const TEST_PACKET_VBR: &[u8] = &[
+        0x82, 0x4, 0xDA, 0x84, 0xE8, 0x87, 0x77, 0x83, 0xD6, 0x48, 0xB3, 0x6B,
+    ];
+    const TEST_PACKET_INVALID: &[u8] = &[0x81, 0xDA];
+
+    #[test]
+    fn test_query_packet_bandwidth() {
+        let bandwidths: Vec<Bandwidth> = (0..32)
+            .map(|c| {
+                let arr = [c << 3];
+                query_packet_bandwidth(&arr).unwrap()
+            })
+            .collect();
+
+        assert_eq!(bandwidths.len(), 32);
+        assert_eq!(bandwidths[0], Bandwidth::Narrowband);
+        assert_eq!(bandwidths[1], Bandwidth::Narrowband);
+        assert_eq!(bandwidths[2], Bandwidth::Narrowband);
+        assert_eq!(bandwidths[3], Bandwidth::Narrowband);
+        assert_eq!(bandwidths[4], Bandwidth::Mediumband);
+        assert_eq!(bandwidths[5], Bandwidth::Mediumband);
+        assert_eq!(bandwidths[6], Bandwidth::Mediumband);
+        assert_eq!(bandwidths[7], Bandwidth::Mediumband);
+        assert_eq!(bandwidths[8], Bandwidth::Wideband);
+        assert_eq!(bandwidths[9], Bandwidth::Wideband);
+        assert_eq!(bandwidths[10], Bandwidth::Wideband);
+        assert_eq!(bandwidths[11], Bandwidth::Wideband);
+        assert_eq!(bandwidths[12], Bandwidth::Superwideband);
+        assert_eq!(bandwidths[13], Bandwidth::Superwideband);
+        assert_eq!(bandwidths[14], Bandwidth::Fullband);
+        assert_eq!(bandwidths[15], Bandwidth::Fullband);
+        assert_eq!(bandwidths[16], Bandwidth::Narrowband);
+        assert_eq!(bandwidths[17], Bandwidth::Narrowband);
+        assert_eq!(bandwidths[18], Bandwidth::Narrowband);
+        assert_eq!(bandwidths[19], Bandwidth::Narrowband);
+        assert_eq!(bandwidths[20], Bandwidth::Wideband);
+        assert_eq!(bandwidths[21], Bandwidth::Wideband);
+        assert_eq!(bandwidths[22], Bandwidth::Wideband);
+        assert_eq!(bandwidths[23], Bandwidth::Wideband);
+        assert_eq!(bandwidths[24], Bandwidth::Superwideband);
+        assert_eq!(bandwidths[25], Bandwidth::Superwideband);
+        assert_eq!(bandwidths[26], Bandwidth::Superwideband);
+        assert_eq!(bandwidths[27], Bandwidth::Superwideband);
+        assert_eq!(bandwidths[28], Bandwidth::Fullband);
+        assert_eq!(bandwidths[29], Bandwidth::Fullband);
+        assert_eq!(bandwidths[30], Bandwidth::Fullband);
+        assert_eq!(bandwidths[31], Bandwidth::Fullband);
+    }
+
+    #[test]
+    fn test_query_packet_channel_count() {
+        assert_eq!(query_packet_channel_count(&[0]).unwrap(), Channels::Mono);
+        assert_eq!(
+            query_packet_channel_count(&[0x4]).unwrap(),
+            Channels::Stereo
+        );
+    }
+
+    #[test]
+    fn test_query_packet_frame_count() {
+        assert_eq!(query_packet_frame_count(&[0]).unwrap(), 1);
+        assert_eq!(query_packet_frame_count(&[1]).unwrap(), 2);
+        assert_eq!(query_packet_frame_count(&[2]).unwrap(), 2);
+        assert!(query_packet_frame_count(&[3]).is_err());
+        assert_eq!(query_packet_frame_count(&[3, 5]).unwrap(), 5);
+    }
+
+    #[test]
+    fn test_query_packet_samples_per_frame() {
+        let frame_sizes: Vec<usize> = (0..32)
+            .map(|c| {
+                let arr = [c << 3];
+                query_packet_samples_per_frame(&arr, SamplingRate::Hz48000).unwrap()
+            })
+            .collect();
+
+        assert_eq!(frame_sizes.len(), 32);
+        assert_eq!(frame_sizes[0], 480);
+        assert_eq!(frame_sizes[1], 960);
+        assert_eq!(frame_sizes[2], 1920);
+        assert_eq!(frame_sizes[3], 2880);
+        assert_eq!(frame_sizes[4], 480);
+        assert_eq!(frame_sizes[5], 960);
+        assert_eq!(frame_sizes[6], 1920);
+        assert_eq!(frame_sizes[7], 2880);
+        assert_eq!(frame_sizes[8], 480);
+        assert_eq!(frame_sizes[9], 960);
+        assert_eq!(frame_sizes[10], 1920);
+        assert_eq!(frame_sizes[11], 2880);
+        assert_eq!(frame_sizes[12], 480);
+        assert_eq!(frame_sizes[13], 960);
+        assert_eq!(frame_sizes[14], 480);
+        assert_eq!(frame_sizes[15], 960);
+        assert_eq!(frame_sizes[16], 120);
+        assert_eq!(frame_sizes[17], 240);
+        assert_eq!(frame_sizes[18], 480);
+        assert_eq!(frame_sizes[19], 960);
+        assert_eq!(frame_sizes[20], 120);
+        assert_eq!(frame_sizes[21], 240);
+        assert_eq!(frame_sizes[22], 480);
+        assert_eq!(frame_sizes[23], 960);
+        assert_eq!(frame_sizes[24], 120);
+        assert_eq!(frame_sizes[25], 240);
+        assert_eq!(frame_sizes[26], 480);
+        assert_eq!(frame_sizes[27], 960);
+        assert_eq!(frame_sizes[28], 120);
+        assert_eq!(frame_sizes[29], 240);
+        assert_eq!(frame_sizes[30], 480);
+        assert_eq!(frame_sizes[31], 960);
+    }
+
+    #[test]
+    fn test_query_packet_sample_count() {
+        assert_eq!(
+            query_packet_sample_count(&[70], SamplingRate::Hz48000).unwrap(),
+            960
+        );
+        assert!(query_packet_sample_count(&[3], SamplingRate::Hz48000).is_err());
+        assert_eq!(
+            query_packet_sample_count(&[255, 5], SamplingRate::Hz48000).unwrap(),
+            4800
+        );
+    }
+
+    #[test]
+    fn test_parse_packet_with_single_frame() {
+        let mut frames = [0; 48];
+        let mut sizes = [0; 48];
+        let mut payload_offset = 0;
+        let mut packet_offset = 0;
+
+        let count = parse_packet(
+            TEST_PACKET_SINGLE,
+            false,
+            Some(&mut frames),
+            &mut sizes,
+            Some(&mut payload_offset),
+            Some(&mut packet_offset),
+        )
+        .unwrap();
+
+        assert_eq!(count, 1);
+        assert_eq!(frames[0], 1);
+        assert_eq!(sizes[0], 11);
+        assert_eq!(payload_offset, 1);
+        assert_eq!(packet_offset, 12);
+    }
+
+    #[test]
+    fn test_parse_packet_with_two_cbr_frames() {
+        let mut frames = [0; 48];
+        let mut sizes = [0; 48];
+        let mut payload_offset = 0;
+        let mut packet_offset = 0;
+
+        let count = parse_packet(
+            TEST_PACKET_CBR,
+            false,
+            Some(&mut frames),
+            &mut sizes,
+            Some(&mut payload_offset),
+            Some(&mut packet_offset),
+        )
+        .unwrap();
+
+        assert_eq!(count, 2);
+        assert_eq!(frames[0], 1);
+        assert_eq!(sizes[0], 5);
+        assert_eq!(frames[1], 6);
+        assert_eq!(sizes[1], 5);
+        assert_eq!(payload_offset, 1);
+        assert_eq!(packet_offset, 11);
+    }
+
+    #[test]
+    fn test_parse_packet_with_two_vbr_frames() {
+        let mut frames = [0; 48];
+        let mut sizes = [0; 48];
+        let mut payload_offset = 0;
+        let mut packet_offset = 0;
+
+        let count = parse_packet(
+            TEST_PACKET_VBR,
+            false,
+            Some(&mut frames),
+            &mut sizes,
+            Some(&mut payload_offset),
+            Some(&mut packet_offset),
+        )
+        .unwrap();
+
+        assert_eq!(count, 2);
+        assert_eq!(frames[0], 2);
+        assert_eq!(sizes[0], 4);
+        assert_eq!(frames[1], 6);
+        assert_eq!(sizes[1], 6);
+        assert_eq!(payload_offset, 2);
+        assert_eq!(packet_offset, 12);
+    }
+
+    #[test]
+    fn test_parse_packet_invalid_frame() {
+        let mut frames = [0; 48];
+        let mut sizes = [0; 48];
+
+        assert!(parse_packet(
+            TEST_PACKET_INVALID,
+            false,
+            Some(&mut frames),
+            &mut sizes,
+            None,
+            None,
+        )
+        .is_err())
+    }
+
+    #[test]
+    fn test_packet() {
+        let packet = Packet::new(TEST_PACKET_VBR).unwrap();
+        assert_eq!(packet.toc(), 0x82);
+        assert_eq!(packet.codec_mode(), CodecMode::CeltOnly);
+        assert_eq!(packet.bandwidth(), Bandwidth::Narrowband);
+        assert_eq!(packet.channels(), Channels::Mono);
+        assert_eq!(packet.frame_duration(), FrameDuration::Ms2_5);
+        assert_eq!(packet.samples_per_frame(SamplingRate::Hz48000), 120);
+        assert_eq!(packet.frame_count(), 2);
+        assert_eq!(packet.sample_count(SamplingRate::Hz16000), 80);
+        assert_eq!(packet.padding(), 0);
+
+        let frames: Vec<&[u8]> = packet.frames().collect();
+        assert_eq!(frames, vec![&TEST_PACKET_VBR[2..6], &TEST_PACKET_VBR[6..]]);
+        assert_eq!(packet.frames().len(), 2);
+    }
+
+    #[test]
+    fn test_packet_with_padding() {
+        // Code 3 VBR packet with two frames and 255 bytes of padding.
+        let mut data = vec![0x1B, 0xC2, 255, 1, 1, 7, 8, 8];
+        data.resize(data.len() + 255, 0);
+        let packet = Packet::new(&data).unwrap();
+        assert_eq!(packet.frame_duration(), FrameDuration::Ms60);
+        assert_eq!(packet.frame_count(), 2);
+        assert_eq!(packet.sample_count(SamplingRate::Hz48000), 5760);
+        assert_eq!(packet.padding(), 255);
+
+        let frames: Vec<&[u8]> = packet.frames().collect();
+        assert_eq!(frames, vec![&[7][..], &[8, 8][..]]);
+    }
+
+    #[test]
+    fn test_self_delimited_packet() {
+        // Two self-delimited packets followed by each other.
+        let data = [0x80, 3, 1, 2, 3, 0x81, 2, 4, 4, 5, 5];
+        let packet = Packet::new_self_delimited(&data).unwrap();
+        assert_eq!(packet.as_bytes(), &data[..5]);
+        assert_eq!(packet.frames().collect::<Vec<_>>(), vec![&[1, 2, 3][..]]);
+
+        let packet = Packet::new_self_delimited(&data[5..]).unwrap();
+        assert_eq!(packet.as_bytes(), &data[5..]);
+        assert_eq!(
+            packet.frames().collect::<Vec<_>>(),
+            vec![&[4, 4][..], &[5, 5][..]]
+        );
+
+        // The size of the last frame is missing.
+        assert!(Packet::new_self_delimited(&[0x80]).is_err());
+    }
+
+    #[test]
+    fn test_packet_extensions() {
+        // Code 3 CBR packet with two frames and an extension for the second frame.
+        let data = [0xFB, 0x42, 4, 1, 1, 0x01, 0x02, 0x07, 0xAA];
+        let packet = Packet::new(&data).unwrap();
+        assert_eq!(packet.padding(), 4);
+
+        let extensions: Vec<Extension> = packet.extensions().map(|e| e.unwrap()).collect();
+        assert_eq!(
+            extensions,
+            vec![Extension {
+                id: 3,
+                frame: 1,
+                data: &[0xAA],
+            }]
+        );
+        assert_eq!(
+            Packet::new(TEST_PACKET_VBR).unwrap().extensions().count(),
+            0
+        );
+
+        // The second separator points past the last frame.
+        let data = [0xFB, 0x42, 5, 1, 1, 0x01, 0x02, 0x02, 0x07, 0xAA];
+        let packet = Packet::new(&data).unwrap();
+        let mut extensions = packet.extensions();
+        assert!(extensions.next().unwrap().is_err());
+        assert!(extensions.next().is_none());
+    }
+
+    #[test]
+    fn test_invalid_packet() {
+        assert!(Packet::new(&[]).is_err());
+        assert!(Packet::new(TEST_PACKET_INVALID).is_err());
+    }
+
+    #[test]
+    fn test_random_packets() {
+        let mut rng = nanorand::WyRand::new_seed(42);
+        let mut data = [0_u8; 1500];
+
+        (0..100000).for_each(|i| {
+            let len = rng.generate_range::<usize, _>(0..64 << (i % 5));
+            let data = &mut data[..len];
+            rng.fill(&mut *data);
+            if i % 2 == 0 && len > 0 {
+                // Code 3 packets have the most complex framing.
+                data[0] |= 0x3;
+            }
+
+            assert_eq!(query_packet_bandwidth(data).is_ok(), len > 0);
+            assert_eq!(query_packet_channel_count(data).is_ok(), len > 0);
+            assert_eq!(query_packet_codec_mode(data).is_ok(), len > 0);
+            assert_eq!(
+                query_packet_samples_per_frame(data, SamplingRate::Hz48000).is_ok(),
+                len > 0
+            );
+            let _ = query_packet_sample_count(data, SamplingRate::Hz48000);
+
+            [false, true].iter().for_each(|&self_delimited| {
+                let packet = if self_delimited {
+                    Packet::new_self_delimited(data)
+                } else {
+                    Packet::new(data)
+                };
+                if let Ok(packet) = packet {
+                    let frames: Vec<&[u8]> = packet.frames().collect();
+                    let frames_size: usize = frames.iter().map(|frame| frame.len()).sum();
+                    assert!(!frames.is_empty() && frames.len() <= 48);
+                    assert!(frames.iter().all(|frame| frame.len() <= 1275));
+                    assert!(frames_size + packet.padding() < packet.as_bytes().len());
+                    assert!(packet.sample_count(SamplingRate::Hz48000) <= 5760);
+                    assert_eq!(
+                        packet.sample_count(SamplingRate::Hz48000),
+                        query_packet_sample_count(data, SamplingRate::Hz48000).unwrap()
+                    );
+                    packet.extensions().for_each(|extension| {
+                        if let Ok(extension) = extension {
+                            assert!(extension.id > 1 && extension.frame < 48);
+                        }
+                    });
+                    if !self_delimited {
+                        assert_eq!(packet.as_bytes().len(), len);
+                    }
+                }
+            });
+        });
+    }
+}
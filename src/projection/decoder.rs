@@ -0,0 +1,276 @@
+//! Implements the projection decoder.
+
+use std::num::NonZeroUsize;
+
+use crate::multistream::MultistreamDecoderInner;
+use crate::projection::MappingMatrix;
+use crate::{Bandwidth, MultistreamDecoderConfiguration, OpusError, Sample, SamplingRate};
+
+/// Configures the projection decoder on creation.
+///
+/// The stream count, the coupled stream count and the demixing matrix are usually
+/// read from the header of the container, for example the "OpusHead" packet of an
+/// Ogg Opus file with the channel mapping family 3.
+#[derive(Clone, Debug)]
+pub struct ProjectionDecoderConfiguration {
+    /// Sample rate to decode at (Hz). Default: 48000 kHz.
+    pub sampling_rate: SamplingRate,
+    /// Number of output channels (1-255). Default: 4.
+    pub channels: usize,
+    /// The total number of streams coded in the input (1-255). Default: 2.
+    pub streams: usize,
+    /// Number of streams to decode as coupled (2 channel) streams. Default: 2.
+    pub coupled_streams: usize,
+    /// The demixing matrix. Default: empty.
+    ///
+    /// The matrix has `channels` rows and `streams + coupled_streams` columns. The cells
+    /// are ordered column-wise and are encoded as 16 bit little endian integers.
+    pub demixing_matrix: Vec<u8>,
+    /// Scales the decoded output by a factor specified in Q8 dB units. Default: 0.
+    ///
+    /// This is usually the gain of the demixing matrix.
+    pub gain: i16,
+}
+
+impl Default for ProjectionDecoderConfiguration {
+    fn default() -> Self {
+        Self {
+            sampling_rate: SamplingRate::Hz48000,
+            channels: 4,
+            streams: 2,
+            coupled_streams: 2,
+            demixing_matrix: vec![],
+            gain: 0,
+        }
+    }
+}
+
+/// Opus projection decoder.
+///
+/// Decodes packets that were encoded with the channel mapping family 3 and restores
+/// the ambisonic channels with the demixing matrix.
+#[derive(Clone, Debug)]
+pub struct ProjectionDecoder {
+    inner: MultistreamDecoderInner,
+    buffer: Vec<f32>,
+}
+
+impl ProjectionDecoder {
+    /// Creates a new `ProjectionDecoder` with the given configuration.
+    pub fn new(configuration: &ProjectionDecoderConfiguration) -> Result<Self, OpusError> {
+        let channels = configuration.channels;
+        let demixing_matrix = MappingMatrix::from_bytes(
+            channels,
+            configuration.streams + configuration.coupled_streams,
+            0,
+            &configuration.demixing_matrix,
+        )?;
+
+        // Set trivial mapping so each output channel pairs with a matrix column.
+        let mapping: Vec<u8> = (0..channels).map(|i| i as u8).collect();
+        let multistream_configuration = MultistreamDecoderConfiguration {
+            sampling_rate: configuration.sampling_rate,
+            streams: configuration.streams,
+            coupled_streams: configuration.coupled_streams,
+            mapping,
+            gain: configuration.gain,
+        };
+        let inner =
+            MultistreamDecoderInner::new(&multistream_configuration, Some(demixing_matrix))?;
+
+        Ok(Self {
+            inner,
+            buffer: vec![],
+        })
+    }
+
+    /// Resets the ProjectionDecoder to be equivalent to a freshly initialized decoder.
+    ///
+    /// This should be called when switching streams in order to prevent
+    /// the back to back decoding from giving different results from
+    /// one at a time decoding.
+    pub fn reset(&mut self) -> Result<(), OpusError> {
+        self.buffer = vec![];
+        self.inner.reset()
+    }
+
+    /// Returns the sampling rate the decoder was initialized with.
+    pub fn sampling_rate(&self) -> SamplingRate {
+        self.inner.sampling_rate
+    }
+
+    /// Returns the number of output channels.
+    pub fn channels(&self) -> usize {
+        self.inner.layout.channels()
+    }
+
+    /// Returns the number of streams.
+    pub fn streams(&self) -> usize {
+        self.inner.layout.streams
+    }
+
+    /// Returns the number of coupled streams.
+    pub fn coupled_streams(&self) -> usize {
+        self.inner.layout.coupled_streams
+    }
+
+    /// Returns the amount to scale PCM signal by in Q8 dB units.
+    pub fn gain(&self) -> i16 {
+        self.inner.decoders[0].decode_gain
+    }
+
+    /// Returns the decoder's last bandpass.
+    pub fn bandwidth(&self) -> Option<Bandwidth> {
+        self.inner.decoders[0].bandwidth
+    }
+
+    /// Returns the duration (in samples) of the last packet successfully decoded or concealed.
+    pub fn last_packet_duration(&self) -> Option<usize> {
+        self.inner.decoders[0].last_packet_duration
+    }
+
+    /// Returns the final state of the codec's entropy coder.
+    ///
+    /// This is the combination of the final states of all streams.
+    pub fn final_range(&self) -> u32 {
+        self.inner
+            .decoders
+            .iter()
+            .fold(0, |acc, decoder| acc ^ decoder.final_range)
+    }
+
+    /// Decode a projection Opus packet with a generic sample output.
+    ///
+    /// Returns number of decoded samples for one channel.
+    ///
+    /// The internal format is `f32`. Use `decode_float()` to access it directly.
+    ///
+    /// # Arguments
+    /// * `packet`     - Input payload. Use a `None` to indicate packet loss.
+    /// * `samples`    - Output signal encoded as PCM samples (interleaved).
+    ///   Length must be at least `frame_size` * `channels`.
+    /// * `frame_size` - Number of samples per channel of available space in a PCM.
+    ///   `frame_size` must be a multiple of 2.5 ms (400 for 48kHz).
+    ///   In the case of PLC (packet==`None`) or FEC (decode_fec=`true`), then
+    ///   `frame_size` needs to be exactly the duration of audio that is missing,
+    ///   otherwise the decoder will not be in the optimal state to decode
+    ///   the next incoming packet.
+    /// * `decode_fec` - Request that any in-band forward error correction data be decoded.
+    ///   If no such data is available, the frame is decoded as if it were lost.
+    ///
+    pub fn decode<S: Sample>(
+        &mut self,
+        packet: Option<&[u8]>,
+        samples: &mut [S],
+        frame_size: NonZeroUsize,
+        decode_fec: bool,
+    ) -> Result<usize, OpusError> {
+        let channels = self.inner.layout.channels();
+        let frame_size = usize::min(frame_size.get(), self.inner.sampling_rate as usize / 25 * 3);
+
+        let size = frame_size * channels;
+        if self.buffer.len() < size {
+            self.buffer.resize(size, 0_f32);
+        }
+
+        let sample_count = self.inner.decode_native(
+            packet,
+            &mut self.buffer[..size],
+            frame_size,
+            decode_fec,
+            true,
+        )?;
+
+        if sample_count * channels > samples.len() {
+            return Err(OpusError::BufferToSmall);
+        }
+        samples
+            .iter_mut()
+            .zip(self.buffer.iter())
+            .take(sample_count * channels)
+            .for_each(|(x, &s)| *x = S::from_f32(s));
+
+        Ok(sample_count)
+    }
+
+    /// Decode a projection Opus packet with floating point output.
+    ///
+    /// Returns number of decoded samples for one channel.
+    ///
+    /// # Arguments
+    /// * `packet`     - Input payload. Use a `None` to indicate packet loss.
+    /// * `samples`    - Output signal encoded as PCM samples (interleaved).
+    ///   Length must be at least `frame_size` * `channels`.
+    /// * `frame_size` - Number of samples per channel of available space in a PCM.
+    ///   `frame_size` must be a multiple of 2.5 ms (400 for 48kHz).
+    ///   In the case of PLC (packet==`None`) or FEC (decode_fec=`true`), then
+    ///   `frame_size` needs to be exactly the duration of audio that is missing,
+    ///   otherwise the decoder will not be in the optimal state to decode
+    ///   the next incoming packet.
+    /// * `decode_fec` - Request that any in-band forward error correction data be decoded.
+    ///   If no such data is available, the frame is decoded as if it were lost.
+    ///
+    pub fn decode_float(
+        &mut self,
+        packet: Option<&[u8]>,
+        samples: &mut [f32],
+        frame_size: NonZeroUsize,
+        decode_fec: bool,
+    ) -> Result<usize, OpusError> {
+        self.inner
+            .decode_native(packet, samples, frame_size.get(), decode_fec, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+
+    #[test]
+    fn test_projection_decoder() {
+        // Identity matrix.
+        let demixing_matrix: Vec<u8> = (0..16)
+            .flat_map(|i| if i % 5 == 0 { [255, 127] } else { [0, 0] })
+            .collect();
+        let configuration = ProjectionDecoderConfiguration {
+            demixing_matrix,
+            ..Default::default()
+        };
+        let mut decoder = ProjectionDecoder::new(&configuration).unwrap();
+        assert_eq!(decoder.channels(), 4);
+
+        // Two empty (DTX) frames.
+        let frame_size = NonZeroUsize::new(960).unwrap();
+        let mut output = vec![1_f32; 960 * 4];
+        assert_eq!(
+            decoder
+                .decode_float(Some(&[0xF8, 0x00, 0xFC]), &mut output, frame_size, false)
+                .unwrap(),
+            960
+        );
+        assert!(output.iter().all(|x| x.is_finite()));
+
+        let mut output = vec![0_i16; 960 * 4];
+        assert_eq!(
+            decoder
+                .decode(None, &mut output, frame_size, false)
+                .unwrap(),
+            960
+        );
+    }
+
+    #[test]
+    fn test_invalid_configuration() {
+        // The demixing matrix is missing.
+        let configuration = ProjectionDecoderConfiguration::default();
+        assert!(ProjectionDecoder::new(&configuration).is_err());
+
+        // The demixing matrix has the wrong size.
+        let configuration = ProjectionDecoderConfiguration {
+            demixing_matrix: vec![0; 30],
+            ..Default::default()
+        };
+        assert!(ProjectionDecoder::new(&configuration).is_err());
+    }
+}
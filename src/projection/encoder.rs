@@ -0,0 +1,354 @@
+//! Implements the projection encoder.
+
+use crate::multistream::{
+    ambisonics_order_plus_one, ChannelLayout, MappingType, MultistreamEncoderInner,
+};
+use crate::projection::{ambisonics_matrices, MappingMatrix};
+use crate::{
+    Application, Bandwidth, Bitrate, Channels, EncoderConfiguration, FrameDuration, InputSample,
+    OpusError, SamplingRate,
+};
+
+/// Configures the projection encoder on creation.
+///
+/// The number of channels defines the order of the ambisonics: (1 + n)^2 channels
+/// in the ACN order for n = 1..3, optionally followed by two non-diegetic (head-locked)
+/// stereo channels.
+#[derive(Clone, Debug)]
+pub struct ProjectionEncoderConfiguration {
+    /// Sampling rate of the input signal (Hz). Default: 48000 kHz.
+    pub sampling_rate: SamplingRate,
+    /// Number of channels of the input signal (4, 6, 9, 11, 16 or 18). Default: 4.
+    pub channels: usize,
+    /// The intended application. Default: Audio.
+    pub application: Application,
+    /// The total bitrate of all streams. Default: Auto.
+    pub bitrate: Bitrate,
+    /// The computational complexity of the encoder (0-10). Default: 9.
+    pub complexity: u8,
+    /// The duration of the encoded frames. Default: 20 ms.
+    pub frame_duration: FrameDuration,
+    /// Use a variable bitrate. Default: true.
+    pub vbr: bool,
+    /// Constrain the variable bitrate, so that the bitrate doesn't
+    /// exceed the target bitrate over the duration of a few frames. Default: true.
+    pub vbr_constraint: bool,
+    /// Embeds low bitrate redundancy (LBRR) data of the previous frame in Silk and
+    /// hybrid packets. Default: false.
+    pub inband_fec: bool,
+    /// The expected packet loss in percent (0-100). Default: 0.
+    pub packet_loss_percentage: u8,
+    /// Use discontinuous transmission (DTX). Default: false.
+    pub dtx: bool,
+}
+
+impl Default for ProjectionEncoderConfiguration {
+    fn default() -> Self {
+        Self {
+            sampling_rate: SamplingRate::Hz48000,
+            channels: 4,
+            application: Application::Audio,
+            bitrate: Bitrate::Auto,
+            complexity: 9,
+            frame_duration: FrameDuration::Ms20,
+            vbr: true,
+            vbr_constraint: true,
+            inband_fec: false,
+            packet_loss_percentage: 0,
+            dtx: false,
+        }
+    }
+}
+
+/// Opus projection encoder.
+///
+/// Encodes first to third-order ambisonics with the channel mapping family 3.
+/// The ambisonic channels are mixed into coupled streams with a pre-computed
+/// mixing matrix. The demixing matrix needs to be stored in the header of the
+/// container, so that the decoder can restore the ambisonic channels.
+#[derive(Clone, Debug)]
+pub struct ProjectionEncoder {
+    inner: MultistreamEncoderInner,
+    demixing_matrix: MappingMatrix,
+    buffer: Vec<f32>,
+}
+
+impl ProjectionEncoder {
+    /// Creates a new `ProjectionEncoder` with the given configuration.
+    pub fn new(configuration: &ProjectionEncoderConfiguration) -> Result<Self, OpusError> {
+        let channels = configuration.channels;
+        let order_plus_one = ambisonics_order_plus_one(channels)?;
+        let (mixing_matrix, demixing_matrix) = ambisonics_matrices(order_plus_one)?;
+
+        let streams = channels.div_ceil(2);
+        let coupled_streams = channels / 2;
+
+        // Ensure matrices are large enough for desired coding scheme.
+        if streams + coupled_streams > mixing_matrix.rows
+            || channels > mixing_matrix.cols
+            || channels > demixing_matrix.rows
+            || streams + coupled_streams > demixing_matrix.cols
+        {
+            return Err(OpusError::BadArguments(
+                "mapping matrix is too small for the number of channels",
+            ));
+        }
+
+        // Set trivial mapping so each input channel pairs with a matrix column.
+        let mapping: Vec<u8> = (0..channels).map(|i| i as u8).collect();
+        let layout = ChannelLayout::new(streams, coupled_streams, &mapping)?;
+
+        let encoder_configuration = EncoderConfiguration {
+            sampling_rate: configuration.sampling_rate,
+            channels: Channels::Mono,
+            application: configuration.application,
+            bitrate: Bitrate::Auto,
+            complexity: configuration.complexity,
+            frame_duration: configuration.frame_duration,
+            vbr: configuration.vbr,
+            vbr_constraint: configuration.vbr_constraint,
+            force_mode: None,
+            bandwidth: None,
+            inband_fec: configuration.inband_fec,
+            packet_loss_percentage: configuration.packet_loss_percentage,
+            dtx: configuration.dtx,
+        };
+        let inner = MultistreamEncoderInner::new(
+            &encoder_configuration,
+            layout,
+            MappingType::None,
+            None,
+            configuration.bitrate,
+            Some(mixing_matrix),
+        )?;
+
+        Ok(Self {
+            inner,
+            demixing_matrix,
+            buffer: vec![],
+        })
+    }
+
+    /// Resets the ProjectionEncoder to be equivalent to a freshly initialized encoder.
+    ///
+    /// This should be called when switching streams in order to prevent
+    /// the back to back encoding from giving different results from
+    /// one at a time encoding.
+    pub fn reset(&mut self) -> Result<(), OpusError> {
+        self.buffer = vec![];
+        self.inner.reset()
+    }
+
+    /// Returns the sampling rate the encoder was initialized with.
+    pub fn sampling_rate(&self) -> SamplingRate {
+        self.inner.sampling_rate
+    }
+
+    /// Returns the number of input channels.
+    pub fn channels(&self) -> usize {
+        self.inner.layout.channels()
+    }
+
+    /// Returns the number of streams.
+    pub fn streams(&self) -> usize {
+        self.inner.layout.streams
+    }
+
+    /// Returns the number of coupled streams.
+    pub fn coupled_streams(&self) -> usize {
+        self.inner.layout.coupled_streams
+    }
+
+    /// Returns the total bitrate the encoder was initialized with.
+    pub fn bitrate(&self) -> Bitrate {
+        self.inner.bitrate
+    }
+
+    /// Returns the number of samples per channel the encoder consumes per packet.
+    pub fn frame_size(&self) -> usize {
+        self.inner.frame_size
+    }
+
+    /// Returns the bandwidth of the first stream of the last encoded packet.
+    pub fn bandwidth(&self) -> Bandwidth {
+        self.inner.encoders[0].bandwidth
+    }
+
+    /// Returns the final state of the codec's entropy coder.
+    ///
+    /// This is the combination of the final states of all streams.
+    pub fn final_range(&self) -> u32 {
+        self.inner
+            .encoders
+            .iter()
+            .fold(0, |acc, encoder| acc ^ encoder.final_range)
+    }
+
+    /// Returns the demixing matrix the decoder needs to restore the ambisonic channels.
+    ///
+    /// The matrix has `channels` rows and `streams + coupled_streams` columns. The cells
+    /// are ordered column-wise and are encoded as 16 bit little endian integers, which
+    /// is the format of the demixing matrix in the "OpusHead" packet of an Ogg Opus file.
+    pub fn demixing_matrix(&self) -> Vec<u8> {
+        let layout = &self.inner.layout;
+        self.demixing_matrix
+            .to_bytes(layout.channels(), layout.streams + layout.coupled_streams)
+    }
+
+    /// Returns the gain of the demixing matrix in Q8 dB units.
+    ///
+    /// The gain needs to be applied as output gain by the decoder.
+    pub fn demixing_matrix_gain(&self) -> i16 {
+        self.demixing_matrix.gain
+    }
+
+    /// Encodes a projection Opus packet from a generic sample input.
+    ///
+    /// Returns the length of the encoded packet in bytes.
+    ///
+    /// The internal format is `f32`. Use `encode_float()` to access it directly.
+    ///
+    /// # Arguments
+    /// * `samples` - Input signal encoded as PCM samples (interleaved).
+    ///   Length must be at least `frame_size()` * `channels`.
+    /// * `output`  - Output payload. The length of the buffer limits the size
+    ///   of the encoded packet.
+    ///
+    pub fn encode<S: InputSample>(
+        &mut self,
+        samples: &[S],
+        output: &mut [u8],
+    ) -> Result<usize, OpusError> {
+        let size = self.inner.frame_size * self.inner.layout.channels();
+        if samples.len() < size {
+            return Err(OpusError::BadArguments(
+                "samples must contain at least frame_size * channels samples",
+            ));
+        }
+
+        if self.buffer.len() < size {
+            self.buffer.resize(size, 0_f32);
+        }
+        self.buffer
+            .iter_mut()
+            .zip(samples.iter())
+            .for_each(|(x, &s)| *x = s.to_f32());
+
+        self.inner
+            .encode_native(&self.buffer[..size], output, S::LSB_DEPTH, false)
+    }
+
+    /// Encodes a projection Opus packet from a floating point input.
+    ///
+    /// Returns the length of the encoded packet in bytes.
+    ///
+    /// # Arguments
+    /// * `samples` - Input signal encoded as PCM samples (interleaved).
+    ///   Length must be at least `frame_size()` * `channels`.
+    /// * `output`  - Output payload. The length of the buffer limits the size
+    ///   of the encoded packet.
+    ///
+    pub fn encode_float(&mut self, samples: &[f32], output: &mut [u8]) -> Result<usize, OpusError> {
+        let size = self.inner.frame_size * self.inner.layout.channels();
+        if samples.len() < size {
+            return Err(OpusError::BadArguments(
+                "samples must contain at least frame_size * channels samples",
+            ));
+        }
+
+        self.inner.encode_native(&samples[..size], output, 24, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+    use crate::{ProjectionDecoder, ProjectionDecoderConfiguration};
+    use std::num::NonZeroUsize;
+
+    #[test]
+    fn test_projection_encoder() {
+        // Third-order ambisonics with two non-diegetic channels.
+        let configuration = ProjectionEncoderConfiguration {
+            channels: 18,
+            bitrate: Bitrate::BitsPerSecond(512000),
+            ..Default::default()
+        };
+        let mut encoder = ProjectionEncoder::new(&configuration).unwrap();
+        assert_eq!(encoder.streams(), 9);
+        assert_eq!(encoder.coupled_streams(), 9);
+        assert_eq!(encoder.demixing_matrix().len(), 18 * 18 * 2);
+        assert_eq!(encoder.demixing_matrix_gain(), 0);
+
+        let mut decoder = ProjectionDecoder::new(&ProjectionDecoderConfiguration {
+            channels: encoder.channels(),
+            streams: encoder.streams(),
+            coupled_streams: encoder.coupled_streams(),
+            demixing_matrix: encoder.demixing_matrix(),
+            gain: encoder.demixing_matrix_gain(),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let channels = encoder.channels();
+        let frame_size = encoder.frame_size();
+        let mut output = vec![0_f32; frame_size * channels];
+        (0..5).for_each(|i| {
+            let samples: Vec<f32> = (0..frame_size * channels)
+                .map(|j| {
+                    let t = (i * frame_size + j / channels) as f32 / 48000.0;
+                    let f = 110.0 * (1 + j % channels) as f32;
+                    0.25 * f32::sin(2.0 * std::f32::consts::PI * f * t)
+                })
+                .collect();
+            let mut packet = [0_u8; 8000];
+            let len = encoder.encode_float(&samples, &mut packet).unwrap();
+            let count = decoder
+                .decode_float(
+                    Some(&packet[..len]),
+                    &mut output,
+                    NonZeroUsize::new(frame_size).unwrap(),
+                    false,
+                )
+                .unwrap();
+            assert_eq!(count, frame_size);
+            assert_eq!(decoder.final_range(), encoder.final_range());
+            assert!(output.iter().all(|x| x.is_finite()));
+        });
+        assert!(output.iter().any(|&x| x != 0.0));
+
+        let samples = vec![0_i16; frame_size * channels];
+        let mut packet = [0_u8; 8000];
+        assert!(encoder.encode(&samples, &mut packet).unwrap() > 0);
+        assert!(encoder.encode(&samples[..frame_size], &mut packet).is_err());
+    }
+
+    #[test]
+    fn test_projection_encoder_demixing_matrix() {
+        // Second-order ambisonics without non-diegetic channels
+        // only use a subset of the demixing matrix.
+        let configuration = ProjectionEncoderConfiguration {
+            channels: 9,
+            ..Default::default()
+        };
+        let encoder = ProjectionEncoder::new(&configuration).unwrap();
+        assert_eq!(encoder.streams(), 5);
+        assert_eq!(encoder.coupled_streams(), 4);
+        assert_eq!(encoder.demixing_matrix_gain(), 3050);
+
+        let (_, demixing) = ambisonics_matrices(3).unwrap();
+        assert_eq!(encoder.demixing_matrix(), demixing.to_bytes(9, 9));
+    }
+
+    #[test]
+    fn test_invalid_configuration() {
+        [0, 1, 2, 5, 25, 228].iter().for_each(|&channels| {
+            let configuration = ProjectionEncoderConfiguration {
+                channels,
+                ..Default::default()
+            };
+            assert!(ProjectionEncoder::new(&configuration).is_err());
+        });
+    }
+}
@@ -0,0 +1,285 @@
+//! Implements the mapping matrices of the projection encoder and decoder.
+
+use crate::OpusError;
+
+/// A mixing or demixing matrix that maps between the ambisonic channels and the coded streams.
+#[derive(Clone, Debug)]
+pub(crate) struct MappingMatrix {
+    /// Number of channels outputted from the matrix.
+    pub(crate) rows: usize,
+    /// Number of channels inputted to the matrix.
+    pub(crate) cols: usize,
+    /// Gain in dB. S7.8-format.
+    pub(crate) gain: i16,
+    /// Matrix cell data in column-wise ordering.
+    data: Vec<i16>,
+}
+
+impl MappingMatrix {
+    /// Creates a new matrix from the given column-wise ordered cells.
+    pub(crate) fn new(
+        rows: usize,
+        cols: usize,
+        gain: i16,
+        data: &[i16],
+    ) -> Result<Self, OpusError> {
+        // The matrix must only support up to 255 channels in or out. Additionally,
+        // the cells must fit into 65004 bytes in order to be stored in an Ogg header.
+        if rows > 255 || cols > 255 || rows * cols * 2 > 65004 {
+            return Err(OpusError::BadArguments("mapping matrix is too large"));
+        }
+        if data.len() != rows * cols {
+            return Err(OpusError::BadArguments(
+                "mapping matrix doesn't contain rows * cols cells",
+            ));
+        }
+
+        Ok(Self {
+            rows,
+            cols,
+            gain,
+            data: data.to_vec(),
+        })
+    }
+
+    /// Creates a new matrix from the little endian cells stored in an Ogg header.
+    pub(crate) fn from_bytes(
+        rows: usize,
+        cols: usize,
+        gain: i16,
+        bytes: &[u8],
+    ) -> Result<Self, OpusError> {
+        if bytes.len() != rows * cols * 2 {
+            return Err(OpusError::BadArguments(
+                "mapping matrix doesn't contain rows * cols cells",
+            ));
+        }
+        let data: Vec<i16> = bytes
+            .chunks_exact(2)
+            .map(|cell| i16::from_le_bytes([cell[0], cell[1]]))
+            .collect();
+
+        Self::new(rows, cols, gain, &data)
+    }
+
+    /// Returns the first `rows` rows of the first `cols` columns as little endian cells.
+    pub(crate) fn to_bytes(&self, rows: usize, cols: usize) -> Vec<u8> {
+        (0..cols)
+            .flat_map(|col| (0..rows).map(move |row| (row, col)))
+            .flat_map(|(row, col)| self.data[self.index(row, col)].to_le_bytes())
+            .collect()
+    }
+
+    #[inline(always)]
+    fn index(&self, row: usize, col: usize) -> usize {
+        self.rows * col + row
+    }
+
+    /// Computes the row `output_row` of the matrix product with the interleaved `input`
+    /// and writes it into `output` with a stride of `output_rows`.
+    pub(crate) fn multiply_channel_in(
+        &self,
+        input: &[f32],
+        input_rows: usize,
+        output: &mut [f32],
+        output_row: usize,
+        output_rows: usize,
+        frame_size: usize,
+    ) {
+        debug_assert!(input_rows <= self.cols && output_rows <= self.rows);
+
+        (0..frame_size).for_each(|i| {
+            let tmp = (0..input_rows).fold(0.0, |tmp, col| {
+                tmp + f32::from(self.data[self.index(output_row, col)])
+                    * input[input_rows * i + col]
+            });
+            output[output_rows * i] = (1.0 / 32768.0) * tmp;
+        });
+    }
+
+    /// Adds the contribution of the input channel `input_row` of `input` (with a stride
+    /// of `input_rows`) to all channels of the interleaved `output`.
+    pub(crate) fn multiply_channel_out(
+        &self,
+        input: &[f32],
+        input_row: usize,
+        input_rows: usize,
+        output: &mut [f32],
+        output_rows: usize,
+        frame_size: usize,
+    ) {
+        debug_assert!(input_rows <= self.cols && output_rows <= self.rows);
+
+        (0..frame_size).for_each(|i| {
+            let input_sample = input[input_rows * i];
+            (0..output_rows).for_each(|row| {
+                let tmp = (1.0 / 32768.0)
+                    * f32::from(self.data[self.index(row, input_row)])
+                    * input_sample;
+                output[output_rows * i + row] += tmp;
+            });
+        });
+    }
+}
+
+/// Returns the pre-computed mixing and demixing matrices for the given ambisonics order + 1.
+///
+/// Matrices exist for the first to third-order ambisonics.
+pub(crate) fn ambisonics_matrices(
+    order_plus_one: usize,
+) -> Result<(MappingMatrix, MappingMatrix), OpusError> {
+    match order_plus_one {
+        2 => Ok((
+            MappingMatrix::new(6, 6, 0, FOA_MIXING_DATA)?,
+            MappingMatrix::new(6, 6, 0, FOA_DEMIXING_DATA)?,
+        )),
+        3 => Ok((
+            MappingMatrix::new(11, 11, 0, SOA_MIXING_DATA)?,
+            MappingMatrix::new(11, 11, 3050, SOA_DEMIXING_DATA)?,
+        )),
+        4 => Ok((
+            MappingMatrix::new(18, 18, 0, TOA_MIXING_DATA)?,
+            MappingMatrix::new(18, 18, 0, TOA_DEMIXING_DATA)?,
+        )),
+        _ => Err(OpusError::BadArguments(
+            "only first to third-order ambisonics are supported",
+        )),
+    }
+}
+
+/// Mixing matrix of first-order ambisonics (column-wise).
+const FOA_MIXING_DATA: &[i16; 36] = &[
+    16384, 0, -16384, 23170, 0, 0, 16384, 23170, 16384, 0, 0, 0, 16384, 0, -16384, -23170, 0, 0,
+    16384, -23170, 16384, 0, 0, 0, 0, 0, 0, 0, 32767, 0, 0, 0, 0, 0, 0, 32767,
+];
+
+/// Mixing matrix of second-order ambisonics (column-wise).
+const SOA_MIXING_DATA: &[i16; 121] = &[
+    10923, 7723, 13377, -13377, 11585, 9459, 7723, -16384, -6689, 0, 0, 10923, 7723, 13377, 13377,
+    -11585, 9459, 7723, 16384, -6689, 0, 0, 10923, -15447, 13377, 0, 0, -18919, 7723, 0, 13377, 0,
+    0, 10923, 7723, -13377, -13377, 11585, -9459, 7723, 16384, -6689, 0, 0, 10923, -7723, 0, 13377,
+    -16384, 0, -15447, 0, 9459, 0, 0, 10923, -7723, 0, -13377, 16384, 0, -15447, 0, 9459, 0, 0,
+    10923, 15447, 0, 0, 0, 0, -15447, 0, -18919, 0, 0, 10923, 7723, -13377, 13377, -11585, -9459,
+    7723, -16384, -6689, 0, 0, 10923, -15447, -13377, 0, 0, 18919, 7723, 0, 13377, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 32767, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 32767,
+];
+
+/// Mixing matrix of third-order ambisonics (column-wise).
+const TOA_MIXING_DATA: &[i16; 324] = &[
+    8208, 0, -881, 14369, 0, 0, -8192, -4163, 13218, 0, 0, 0, 11095, -8836, -6218, 14833, 0, 0,
+    8208, -10161, 881, 10161, -13218, -2944, -8192, 2944, 0, -10488, -6218, 6248, -11095, -6248, 0,
+    -10488, 0, 0, 8208, 10161, 881, -10161, -13218, 2944, -8192, -2944, 0, 10488, -6218, -6248,
+    -11095, 6248, 0, 10488, 0, 0, 8176, 5566, -11552, 5566, 9681, -11205, 8192, -11205, 0, 4920,
+    -15158, 9756, -3334, 9756, 0, -4920, 0, 0, 8176, 7871, 11552, 0, 0, 15846, 8192, 0, -9681,
+    -6958, 0, 13797, 3334, 0, -15158, 0, 0, 0, 8176, 0, 11552, 7871, 0, 0, 8192, 15846, 9681, 0, 0,
+    0, 3334, 13797, 15158, 6958, 0, 0, 8176, 5566, -11552, -5566, -9681, -11205, 8192, 11205, 0,
+    4920, 15158, 9756, -3334, -9756, 0, 4920, 0, 0, 8208, 14369, -881, 0, 0, -4163, -8192, 0,
+    -13218, -14833, 0, -8836, 11095, 0, 6218, 0, 0, 0, 8208, 10161, 881, 10161, 13218, 2944, -8192,
+    2944, 0, 10488, 6218, -6248, -11095, -6248, 0, -10488, 0, 0, 8208, -14369, -881, 0, 0, 4163,
+    -8192, 0, -13218, 14833, 0, 8836, 11095, 0, 6218, 0, 0, 0, 8208, 0, -881, -14369, 0, 0, -8192,
+    4163, 13218, 0, 0, 0, 11095, 8836, -6218, -14833, 0, 0, 8176, -5566, -11552, 5566, -9681,
+    11205, 8192, -11205, 0, -4920, 15158, -9756, -3334, 9756, 0, -4920, 0, 0, 8176, 0, 11552,
+    -7871, 0, 0, 8192, -15846, 9681, 0, 0, 0, 3334, -13797, 15158, -6958, 0, 0, 8176, -7871, 11552,
+    0, 0, -15846, 8192, 0, -9681, 6958, 0, -13797, 3334, 0, -15158, 0, 0, 0, 8176, -5566, -11552,
+    -5566, 9681, 11205, 8192, 11205, 0, -4920, -15158, -9756, -3334, -9756, 0, 4920, 0, 0, 8208,
+    -10161, 881, -10161, 13218, -2944, -8192, -2944, 0, -10488, 6218, 6248, -11095, 6248, 0, 10488,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 32767, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 32767,
+];
+
+/// Demixing matrix of first-order ambisonics (column-wise).
+const FOA_DEMIXING_DATA: &[i16; 36] = &[
+    16384, 16384, 16384, 16384, 0, 0, 0, 23170, 0, -23170, 0, 0, -16384, 16384, -16384, 16384, 0,
+    0, 23170, 0, -23170, 0, 0, 0, 0, 0, 0, 0, 32767, 0, 0, 0, 0, 0, 0, 32767,
+];
+
+/// Demixing matrix of second-order ambisonics (column-wise).
+const SOA_DEMIXING_DATA: &[i16; 121] = &[
+    2771, 2771, 2771, 2771, 2771, 2771, 2771, 2771, 2771, 0, 0, 10033, 10033, -20066, 10033, 14189,
+    14189, -28378, 10033, -20066, 0, 0, 3393, 3393, 3393, -3393, 0, 0, 0, -3393, -3393, 0, 0,
+    -17378, 17378, 0, -17378, -24576, 24576, 0, 17378, 0, 0, 0, -14189, 14189, 0, -14189, -28378,
+    28378, 0, 14189, 0, 0, 0, 2399, 2399, -4799, -2399, 0, 0, 0, -2399, 4799, 0, 0, 1959, 1959,
+    1959, 1959, -3918, -3918, -3918, 1959, 1959, 0, 0, -4156, 4156, 0, 4156, 0, 0, 0, -4156, 0, 0,
+    0, 8192, 8192, -16384, 8192, 16384, 16384, -32768, 8192, -16384, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 8312, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 8312,
+];
+
+/// Demixing matrix of third-order ambisonics (column-wise).
+const TOA_DEMIXING_DATA: &[i16; 324] = &[
+    8192, 8192, 8192, 8192, 8192, 8192, 8192, 8192, 8192, 8192, 8192, 8192, 8192, 8192, 8192, 8192,
+    0, 0, 0, -9779, 9779, 6263, 8857, 0, 6263, 13829, 9779, -13829, 0, -6263, 0, -8857, -6263,
+    -9779, 0, 0, -3413, 3413, 3413, -11359, 11359, 11359, -11359, -3413, 3413, -3413, -3413,
+    -11359, 11359, 11359, -11359, 3413, 0, 0, 13829, 9779, -9779, 6263, 0, 8857, -6263, 0, 9779, 0,
+    -13829, 6263, -8857, 0, -6263, -9779, 0, 0, 0, -15617, -15617, 6406, 0, 0, -6406, 0, 15617, 0,
+    0, -6406, 0, 0, 6406, 15617, 0, 0, 0, -5003, 5003, -10664, 15081, 0, -10664, -7075, 5003, 7075,
+    0, 10664, 0, -15081, 10664, -5003, 0, 0, -8176, -8176, -8176, 8208, 8208, 8208, 8208, -8176,
+    -8176, -8176, -8176, 8208, 8208, 8208, 8208, -8176, 0, 0, -7075, 5003, -5003, -10664, 0, 15081,
+    10664, 0, 5003, 0, 7075, -10664, -15081, 0, 10664, -5003, 0, 0, 15617, 0, 0, 0, -6406, 6406, 0,
+    -15617, 0, -15617, 15617, 0, 6406, -6406, 0, 0, 0, 0, 0, -11393, 11393, 2993, -4233, 0, 2993,
+    -16112, 11393, 16112, 0, -2993, 0, 4233, -2993, -11393, 0, 0, 0, -9974, -9974, -13617, 0, 0,
+    13617, 0, 9974, 0, 0, 13617, 0, 0, -13617, 9974, 0, 0, 0, 5579, -5579, 10185, 14403, 0, 10185,
+    -7890, -5579, 7890, 0, -10185, 0, -14403, -10185, 5579, 0, 0, 11826, -11826, -11826, -901, 901,
+    901, -901, 11826, -11826, 11826, 11826, -901, 901, 901, -901, -11826, 0, 0, -7890, -5579, 5579,
+    10185, 0, 14403, -10185, 0, -5579, 0, 7890, 10185, -14403, 0, -10185, 5579, 0, 0, -9974, 0, 0,
+    0, -13617, 13617, 0, 9974, 0, 9974, -9974, 0, 13617, -13617, 0, 0, 0, 0, 16112, -11393, 11393,
+    -2993, 0, 4233, 2993, 0, -11393, 0, -16112, -2993, -4233, 0, 2993, 11393, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 32767, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    32767,
+];
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+
+    #[test]
+    fn test_mapping_matrix_bytes() {
+        let matrix = MappingMatrix::new(3, 2, 0, &[1, -2, 3, 256, -32768, 32767]).unwrap();
+        assert_eq!(
+            matrix.to_bytes(3, 2),
+            vec![1, 0, 254, 255, 3, 0, 0, 1, 0, 128, 255, 127]
+        );
+        // The top left 2x1 sub-matrix.
+        assert_eq!(matrix.to_bytes(2, 1), vec![1, 0, 254, 255]);
+
+        let bytes = matrix.to_bytes(3, 2);
+        let parsed = MappingMatrix::from_bytes(3, 2, 0, &bytes).unwrap();
+        assert_eq!(parsed.data, matrix.data);
+
+        assert!(MappingMatrix::from_bytes(3, 2, 0, &bytes[..10]).is_err());
+        assert!(MappingMatrix::new(2, 2, 0, &[0; 3]).is_err());
+        assert!(MappingMatrix::new(256, 1, 0, &[0; 256]).is_err());
+        assert!(MappingMatrix::new(255, 255, 0, &[0; 255 * 255]).is_err());
+    }
+
+    #[test]
+    fn test_mapping_matrix_multiply() {
+        // Swaps the two channels and halves the first output channel.
+        let matrix = MappingMatrix::new(2, 2, 0, &[0, 32767, 16384, 0]).unwrap();
+        let input = [0.5, 1.0, -0.5, -1.0];
+
+        let mut output = [0.0; 4];
+        matrix.multiply_channel_in(&input, 2, &mut output, 0, 2, 2);
+        matrix.multiply_channel_in(&input, 2, &mut output[1..], 1, 2, 2);
+        assert_eq!(output, [0.5, 0.49998474, -0.5, -0.49998474]);
+
+        let mut output = [0.0; 4];
+        matrix.multiply_channel_out(&input, 0, 2, &mut output, 2, 2);
+        matrix.multiply_channel_out(&input[1..], 1, 2, &mut output, 2, 2);
+        assert_eq!(output, [0.5, 0.49998474, -0.5, -0.49998474]);
+    }
+
+    #[test]
+    fn test_ambisonics_matrices() {
+        (2..=4).for_each(|order_plus_one| {
+            let (mixing, demixing) = ambisonics_matrices(order_plus_one).unwrap();
+            let channels = order_plus_one * order_plus_one + 2;
+            assert_eq!(mixing.rows, channels);
+            assert_eq!(mixing.cols, channels);
+            assert_eq!(demixing.rows, channels);
+            assert_eq!(demixing.cols, channels);
+        });
+        assert!(ambisonics_matrices(1).is_err());
+        assert!(ambisonics_matrices(5).is_err());
+    }
+}
@@ -0,0 +1,14 @@
+//! Implements the projection decoder and encoder for ambisonics.
+//!
+//! The projection encoder mixes the ambisonic channels with a mixing matrix into
+//! the coded streams (channel mapping family 3). The decoder uses the matching
+//! demixing matrix, which is stored in the header of the container, to restore
+//! the ambisonic channels.
+
+pub use decoder::*;
+pub use encoder::*;
+pub(crate) use mapping_matrix::*;
+
+mod decoder;
+mod encoder;
+mod mapping_matrix;
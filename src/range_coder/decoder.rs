@@ -82,6 +82,19 @@ impl<'d> RangeDecoder<'d> {
         self.storage -= shrink_by;
     }
 
+    /// Returns the length of the usable buffer.
+    pub(crate) fn storage(&self) -> usize {
+        self.storage
+    }
+
+    /// Pretends that all bits up to `total_bits` have been read.
+    ///
+    /// `tell()` will report `total_bits` afterwards.
+    pub(crate) fn skip_to(&mut self, total_bits: u32) {
+        let tell = self.tell();
+        self.bits_total = self.bits_total.wrapping_add(total_bits.wrapping_sub(tell));
+    }
+
     /// Reads the next byte from the start of the buffer.
     fn read_byte(&mut self) -> u8 {
         if self.offs < self.storage {
@@ -125,7 +138,7 @@ impl<'d> RangeDecoder<'d> {
     ///
     /// # Arguments
     /// * `ft` - The total frequency of the symbols in the alphabet the
-    ///          next symbol was encoded with.
+    ///   next symbol was encoded with.
     ///
     /// Returns the cumulative frequency representing the encoded symbol.
     ///
@@ -161,13 +174,13 @@ impl<'d> RangeDecoder<'d> {
     ///
     /// # Arguments
     /// * `fl` - The cumulative frequency of all symbols that come before the symbol
-    ///          decoded.
+    ///   decoded.
     /// * `fh` - The cumulative frequency of all symbols up to and including the symbol
-    ///          decoded. Together with fl, this defines the range [fl,fh) in which the
-    ///          value returned above must fall.
+    ///   decoded. Together with fl, this defines the range [fl,fh) in which the
+    ///   value returned above must fall.
     /// * `ft` - The total frequency of the symbols in the alphabet the symbol decoded
-    ///          was encoded in. This must be the same as passed to the preceding call
-    ///          to decode().
+    ///   was encoded in. This must be the same as passed to the preceding call
+    ///   to decode().
     ///
     pub(crate) fn update(&mut self, fl: u32, fh: u32, ft: u32) {
         let s = self.ext * (ft - fh);
@@ -201,9 +214,9 @@ impl<'d> RangeDecoder<'d> {
     ///
     /// # Arguments
     /// * `icdf` - The "inverse" CDF, such that symbol `s` falls in the range
-    ///            `[s>0?ft-icdf[s-1]:0..ft-icdf[s]]`, where `ft = 1 << ftb`.
-    ///            The values must be monotonically non-increasing, and the last
-    ///            value must be 0.
+    ///   `[s>0?ft-icdf[s-1]:0..ft-icdf[s]]`, where `ft = 1 << ftb`.
+    ///   The values must be monotonically non-increasing, and the last
+    ///   value must be 0.
     /// * `ftb`  - The number of bits of precision in the cumulative distribution.
     ///
     /// Returns the decoded symbol `s`.
@@ -239,7 +252,7 @@ impl<'d> RangeDecoder<'d> {
     ///
     /// # Arguments
     /// * `ft` - The number of integers that can be decoded (one more than the max).
-    ///          This must be at least 2, and no more than 2**32-1.
+    ///   This must be at least 2, and no more than 2**32-1.
     ///
     /// Returns the decoded bits.
     pub(crate) fn decode_uint(&mut self, mut ft: u32) -> u32 {
@@ -273,7 +286,7 @@ impl<'d> RangeDecoder<'d> {
     ///
     /// # Arguments
     /// * `bits`   - The number of bits to extract. This must be
-    ///              between 0 and 25, inclusive.
+    ///   between 0 and 25, inclusive.
     ///
     /// Returns the decoded bits.
     pub(crate) fn decode_bits(&mut self, bits: u32) -> u32 {
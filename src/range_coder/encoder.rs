@@ -31,6 +31,27 @@ pub(crate) struct RangeEncoder<'e> {
     ext: u32,
     /// A buffered output symbol, awaiting carry propagation.
     rem: Option<u32>,
+    /// Set if the buffer was too small to hold the written bytes.
+    error: bool,
+}
+
+/// A copy of the internal state of a range encoder.
+///
+/// Used to try out different encodings of the same data and roll back
+/// to the one that was chosen.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RangeEncoderState {
+    storage: usize,
+    end_offs: usize,
+    end_window: u32,
+    end_bits: u32,
+    bits_total: u32,
+    offs: usize,
+    rng: u32,
+    val: u32,
+    ext: u32,
+    rem: Option<u32>,
+    error: bool,
 }
 
 impl<'e> Tell for RangeEncoder<'e> {
@@ -65,47 +86,93 @@ impl<'e> RangeEncoder<'e> {
             val: 0,
             ext: 0,
             rem: None,
+            error: false,
         }
     }
 
-    /// Resets the state of the encoder.
-    pub(crate) fn reset(&mut self) {
-        self.storage = self.buffer.len();
-        self.end_offs = 0;
-        self.end_window = 0;
-        self.end_bits = 0;
-        self.bits_total = CODE_BITS + 1;
-        self.offs = 0;
-        self.rng = CODE_TOP;
-        self.val = 0;
-        self.ext = 0;
-        self.rem = None;
-    }
-
     /// Returns the range of the compressed bytes. Valid after calling `done()`.
-    pub fn range_bytes(&self) -> usize {
+    #[cfg(test)]
+    pub(crate) fn range_bytes(&self) -> usize {
         self.offs
     }
 
+    /// Returns the size of the currently used region of the buffer.
+    pub(crate) fn storage(&self) -> usize {
+        self.storage
+    }
+
+    /// Returns the current state of the encoder.
+    pub(crate) fn state(&self) -> RangeEncoderState {
+        RangeEncoderState {
+            storage: self.storage,
+            end_offs: self.end_offs,
+            end_window: self.end_window,
+            end_bits: self.end_bits,
+            bits_total: self.bits_total,
+            offs: self.offs,
+            rng: self.rng,
+            val: self.val,
+            ext: self.ext,
+            rem: self.rem,
+            error: self.error,
+        }
+    }
+
+    /// Restores a state previously returned by `state()`.
+    ///
+    /// The bytes written after the state was taken are not restored.
+    /// Use `bytes_since()` and `restore_bytes()` for that.
+    pub(crate) fn set_state(&mut self, state: &RangeEncoderState) {
+        self.storage = state.storage;
+        self.end_offs = state.end_offs;
+        self.end_window = state.end_window;
+        self.end_bits = state.end_bits;
+        self.bits_total = state.bits_total;
+        self.offs = state.offs;
+        self.rng = state.rng;
+        self.val = state.val;
+        self.ext = state.ext;
+        self.rem = state.rem;
+        self.error = state.error;
+    }
+
+    /// Returns the part of the buffer that can have been written to since
+    /// the given state was taken.
+    pub(crate) fn bytes_since(&self, state: &RangeEncoderState) -> &[u8] {
+        &self.buffer[state.offs..state.storage]
+    }
+
+    /// Writes back bytes previously returned by `bytes_since()`.
+    pub(crate) fn restore_bytes(&mut self, state: &RangeEncoderState, bytes: &[u8]) {
+        self.buffer[state.offs..state.storage].copy_from_slice(bytes);
+    }
+
+    /// Pretends that all bits up to `total_bits` have been written.
+    ///
+    /// `tell()` will report `total_bits` afterwards.
+    pub(crate) fn skip_to(&mut self, total_bits: u32) {
+        let tell = self.tell();
+        self.bits_total = self.bits_total.wrapping_add(total_bits.wrapping_sub(tell));
+    }
+
     /// Writes a byte from front to back.
-    fn write_byte(&mut self, value: u8) -> Result<(), OpusError> {
+    fn write_byte(&mut self, value: u8) {
         if self.offs + self.end_offs >= self.storage {
-            return Err(OpusError::BufferToSmall);
+            self.error = true;
+            return;
         }
         self.buffer[self.offs] = value;
         self.offs += 1;
-
-        Ok(())
     }
 
     /// Writes a byte from back to front.
-    fn write_byte_at_end(&mut self, value: u8) -> Result<(), OpusError> {
+    fn write_byte_at_end(&mut self, value: u8) {
         if self.offs + self.end_offs >= self.storage {
-            return Err(OpusError::BufferToSmall);
+            self.error = true;
+            return;
         }
         self.end_offs += 1;
         self.buffer[self.storage - self.end_offs] = value;
-        Ok(())
     }
 
     /// Outputs a symbol, with a carry bit.
@@ -121,7 +188,7 @@ impl<'e> RangeEncoder<'e> {
     ///
     /// The alternative is to truncate the range in order to force a carry, but
     /// requires similar carry tracking in the decoder, needlessly slowing it down.
-    fn carry_out(&mut self, c: u32) -> Result<(), OpusError> {
+    fn carry_out(&mut self, c: u32) {
         if c != SYM_MAX {
             // No further carry propagation possible, flush buffer.
             let carry = c >> SYM_BITS;
@@ -130,13 +197,13 @@ impl<'e> RangeEncoder<'e> {
             // This compare should be taken care of by branch-prediction thereafter.
             if let Some(rem) = self.rem {
                 let b = (rem + carry) as u8;
-                self.write_byte(b)?
+                self.write_byte(b);
             }
 
             if self.ext > 0 {
                 let sym = ((SYM_MAX + carry) & SYM_MAX) as u8;
                 loop {
-                    self.write_byte(sym)?;
+                    self.write_byte(sym);
 
                     self.ext -= 1;
                     if self.ext == 0 {
@@ -148,23 +215,19 @@ impl<'e> RangeEncoder<'e> {
         } else {
             self.ext += 1;
         }
-
-        Ok(())
     }
 
     /// Normalizes the contents of val and range so that range lies entirely
     /// in the high-order symbol.
-    fn normalize(&mut self) -> Result<(), OpusError> {
+    fn normalize(&mut self) {
         // If the range is too small, output some bits and rescale it.
         while self.rng <= CODE_BOT {
-            self.carry_out(self.val >> CODE_SHIFT)?;
+            self.carry_out(self.val >> CODE_SHIFT);
             // Move the next-to-high-order symbol into the high-order position.
             self.val = (self.val << SYM_BITS) & (CODE_TOP - 1);
             self.rng <<= SYM_BITS;
             self.bits_total += SYM_BITS;
         }
-
-        Ok(())
     }
 
     /// Encodes a symbol given its frequency information.
@@ -178,10 +241,10 @@ impl<'e> RangeEncoder<'e> {
     ///
     /// # Argument  
     /// * `fl` - The cumulative frequency of all symbols that come before the one to be
-    ///          encoded.
+    ///   encoded.
     /// * `fh` - The cumulative frequency of all symbols up to and including the one to
-    ///          be encoded. Together with _fl, this defines the range [_fl,_fh) in
-    ///          which the decoded value will fall.
+    ///   be encoded. Together with _fl, this defines the range [_fl,_fh) in
+    ///   which the decoded value will fall.
     /// * `ft` - The sum of the frequencies of all the symbols.
     ///
     pub(crate) fn encode(&mut self, fl: u32, fh: u32, ft: u32) -> Result<(), OpusError> {
@@ -192,7 +255,7 @@ impl<'e> RangeEncoder<'e> {
         } else {
             self.rng -= r * (ft - fh);
         };
-        self.normalize()?;
+        self.normalize();
 
         Ok(())
     }
@@ -206,7 +269,7 @@ impl<'e> RangeEncoder<'e> {
         } else {
             self.rng -= r * ((1 << bits) - fh);
         }
-        self.normalize()?;
+        self.normalize();
 
         Ok(())
     }
@@ -221,7 +284,7 @@ impl<'e> RangeEncoder<'e> {
             self.val = l + r
         };
         self.rng = if val != 0 { s } else { r };
-        self.normalize()?;
+        self.normalize();
 
         Ok(())
     }
@@ -231,9 +294,9 @@ impl<'e> RangeEncoder<'e> {
     /// # Arguments
     /// * `s`    - The index of the symbol to encode.
     /// * `icdf` - The "inverse" CDF, such that symbol _s falls in the range
-    ///            `[s>0?ft-icdf[s-1]:0..ft-icdf[s]]`, where `ft = 1 << ftb`.
-    ///            The values must be monotonically non-increasing, and the last value
-    ///            must be 0.
+    ///   `[s>0?ft-icdf[s-1]:0..ft-icdf[s]]`, where `ft = 1 << ftb`.
+    ///   The values must be monotonically non-increasing, and the last value
+    ///   must be 0.
     /// * `ftb`  - The number of bits of precision in the cumulative distribution.
     ///
     pub(crate) fn encode_icdf(&mut self, s: usize, icdf: &[u8], ftb: u32) -> Result<(), OpusError> {
@@ -244,7 +307,7 @@ impl<'e> RangeEncoder<'e> {
         } else {
             self.rng -= r * u32::from(icdf[s])
         };
-        self.normalize()?;
+        self.normalize();
 
         Ok(())
     }
@@ -254,7 +317,7 @@ impl<'e> RangeEncoder<'e> {
     /// # Arguments
     /// * `fl` - The integer to encode.
     /// * `ft` - The number of integers that can be encoded (one more than the max).
-    ///          This must be at least 2, and no more than 2**32-1.
+    ///   This must be at least 2, and no more than 2**32-1.
     pub(crate) fn encode_uint(&mut self, fl: u32, mut ft: u32) -> Result<(), OpusError> {
         // In order to optimize log(), it is undefined for the value 0.
         debug_assert!(ft > 1);
@@ -278,7 +341,7 @@ impl<'e> RangeEncoder<'e> {
     /// # Arguments
     /// * `fl`   - The bits to encode.
     /// * `bits` - The number of bits to encode.
-    ///            This must be between 1 and 25, inclusive.
+    ///   This must be between 1 and 25, inclusive.
     pub(crate) fn encode_bits(&mut self, fl: u32, bits: u32) -> Result<(), OpusError> {
         debug_assert!(bits > 0);
         let mut window = self.end_window;
@@ -286,7 +349,7 @@ impl<'e> RangeEncoder<'e> {
 
         if used + bits > WINDOW_SIZE {
             loop {
-                self.write_byte_at_end((window & SYM_MAX) as u8)?;
+                self.write_byte_at_end((window & SYM_MAX) as u8);
                 window >>= SYM_BITS;
                 used -= SYM_BITS;
 
@@ -320,9 +383,9 @@ impl<'e> RangeEncoder<'e> {
     ///
     /// # Arguments
     /// * `val`   - The bits to encode (in the least _nbits significant bits).
-    ///            They will be decoded in order from most-significant to least.
+    ///   They will be decoded in order from most-significant to least.
     /// * `nbits` - The number of bits to overwrite.
-    ///            This must be no more than 8.
+    ///   This must be no more than 8.
     ///
     pub(crate) fn patch_initial_bits(&mut self, val: u32, nbits: u32) -> Result<(), OpusError> {
         debug_assert!(nbits <= SYM_BITS);
@@ -356,8 +419,8 @@ impl<'e> RangeEncoder<'e> {
     ///
     /// # Arguments
     /// * `len` - The number of bytes in the new buffer.
-    ///           This must be large enough to contain the bits already written, and
-    ///           must be no larger than the existing size.
+    ///   This must be large enough to contain the bits already written, and
+    ///   must be no larger than the existing size.
     pub(crate) fn shrink(&mut self, len: usize) {
         debug_assert!(self.offs + self.end_offs <= len);
         let start = self.storage - self.end_offs;
@@ -385,19 +448,19 @@ impl<'e> RangeEncoder<'e> {
             end = (self.val + mask) & !mask;
         }
         while l > 0 {
-            self.carry_out(end >> CODE_SHIFT)?;
+            self.carry_out(end >> CODE_SHIFT);
             end = (end << SYM_BITS) & (CODE_TOP - 1);
             l -= SYM_BITS as i32;
         }
         // If we have a buffered byte flush it into the output buffer.
         if self.rem.is_some() || self.ext > 0 {
-            self.carry_out(0)?
+            self.carry_out(0);
         };
         // If we have buffered extra bits, flush them as well.
         let mut window = self.end_window;
         let mut used = self.end_bits;
         while used >= SYM_BITS {
-            self.write_byte_at_end((window & SYM_MAX) as u8)?;
+            self.write_byte_at_end((window & SYM_MAX) as u8);
             window >>= SYM_BITS;
             used -= SYM_BITS;
         }
@@ -421,6 +484,10 @@ impl<'e> RangeEncoder<'e> {
             }
         }
 
+        if self.error {
+            return Err(OpusError::BufferToSmall);
+        }
+
         Ok(())
     }
 
@@ -467,8 +534,8 @@ impl<'e> RangeEncoder<'e> {
                 ndi_max = (ndi_max - s) >> 1;
                 let di = i32::min(val - i, ndi_max - 1);
                 fl += (2 * di + 1 + s) as u32;
-                fs = u32::min(1, (32768 - fl) as u32);
-                *value = (i + di + s) as i32 ^ s;
+                fs = u32::min(1, 32768 - fl);
+                *value = (i + di + s) ^ s;
             } else {
                 fs += 1;
                 fl += (fs as i32 & !s) as u32;
@@ -478,6 +545,6 @@ impl<'e> RangeEncoder<'e> {
             debug_assert!(fs > 0);
             debug_assert!(fl > 0);
         }
-        self.encode_bin(fl, (fl + fs) as u32, 15)
+        self.encode_bin(fl, fl + fs, 15)
     }
 }
@@ -30,15 +30,15 @@
 //! the input buffer.
 //!
 //! * Pas76: "Source coding algorithms for fast data compression"
-//!          by Richard Clark Pasco (1976).
+//!   by Richard Clark Pasco (1976).
 //!
 //! * Mar79: "Range encoding: an algorithm for removing redundancy from a digitised message"
-//!          by Martin, G.N.N. (1979)
+//!   by Martin, G.N.N. (1979)
 //!
 //! * MNW98: "Arithmetic Coding Revisited"
-//!          by Alistair Moffat and Radford Neal and Ian H. Witten (1998).
+//!   by Alistair Moffat and Radford Neal and Ian H. Witten (1998).
 pub(crate) use decoder::RangeDecoder;
-pub(crate) use encoder::RangeEncoder;
+pub(crate) use encoder::{RangeEncoder, RangeEncoderState};
 
 use crate::math::ilog;
 
@@ -48,7 +48,7 @@ mod encoder;
 /// The number of bits to use for the range-coded part of unsigned integers.
 const UINT_BITS: u32 = 8;
 /// The resolution of fractional-precision bit usage measurements, i.e., 3 => 1/8th bits.
-const BITRES: u32 = 3;
+pub(crate) const BITRES: u32 = 3;
 /// Must be at least 32 bits, but if you have fast arithmetic on a larger type,
 /// you can speed up the decoder by using it here.
 const WINDOW_SIZE: u32 = 32;
@@ -111,6 +111,34 @@ pub(crate) trait Tell {
     }
 }
 
+/// Either a range encoder or a range decoder.
+///
+/// Used by the code that is shared between the encoder and the decoder.
+pub(crate) enum RangeCoder<'a, 'b> {
+    /// The encoder.
+    Encoder(&'a mut RangeEncoder<'b>),
+    /// The decoder.
+    Decoder(&'a mut RangeDecoder<'b>),
+}
+
+impl<'a, 'b> Tell for RangeCoder<'a, 'b> {
+    #[inline(always)]
+    fn bits_total(&self) -> u32 {
+        match self {
+            RangeCoder::Encoder(enc) => enc.bits_total(),
+            RangeCoder::Decoder(dec) => dec.bits_total(),
+        }
+    }
+
+    #[inline(always)]
+    fn range(&self) -> u32 {
+        match self {
+            RangeCoder::Encoder(enc) => enc.range(),
+            RangeCoder::Decoder(dec) => dec.range(),
+        }
+    }
+}
+
 fn get_lapace_freq(fs0: u32, decay: u32) -> u32 {
     let ft = 32768 - 32 - fs0;
     (ft * (16384 - decay)) >> 15
@@ -119,11 +147,12 @@ fn get_lapace_freq(fs0: u32, decay: u32) -> u32 {
 #[cfg(test)]
 mod tests {
     #![allow(clippy::panic)]
+    #![allow(clippy::drop_non_drop)]
     #![allow(clippy::unwrap_used)]
 
     use std::f64::consts::LOG2_E;
 
-    use nanorand::RNG;
+    use nanorand::Rng;
 
     use super::*;
 
@@ -304,27 +333,28 @@ mod tests {
         let mut buffer = vec![0_u8; DATA_SIZE];
 
         for _ in 0..1024 {
-            let ft = rnd.generate_range::<u32>(2, 1024);
-            let sz = rnd.generate_range::<usize>(128, 512);
+            let ft = rnd.generate_range::<u32, _>(2..1024);
+            let sz = rnd.generate_range::<usize, _>(128..512);
 
             let mut data = vec![0_u32; sz];
             let mut tell = vec![0_u32; sz + 1];
 
             let mut enc = RangeEncoder::new(&mut buffer);
-            let zeros = rnd.generate_range::<u32>(0, 14) == 0;
+            let zeros = rnd.generate_range::<u32, _>(0..14) == 0;
             tell[0] = enc.tell_frac();
             for j in 0..sz {
                 if zeros {
                     data[j] = 0;
                 } else {
-                    data[j] = rnd.generate_range(0, ft);
+                    data[j] = rnd.generate_range(0..ft);
                 }
                 enc.encode_uint(data[j], ft).unwrap();
                 tell[j + 1] = enc.tell_frac();
             }
-            if rnd.generate_range::<u32>(0, 2) == 0 {
-                while enc.tell() % 8 != 0 {
-                    enc.encode_uint(rnd.generate_range::<u32>(0, 2), 2).unwrap();
+            if rnd.generate_range::<u32, _>(0..2) == 0 {
+                while !enc.tell().is_multiple_of(8) {
+                    enc.encode_uint(rnd.generate_range::<u32, _>(0..2), 2)
+                        .unwrap();
                 }
             }
             let tell_bits = enc.tell();
@@ -339,10 +369,10 @@ mod tests {
             );
 
             assert!(
-                (tell_bits + 7) / 8 >= enc.range_bytes() as u32,
+                tell_bits.div_ceil(8) >= enc.range_bytes() as u32,
                 "tell() lied, there's {} bytes instead of {}",
                 enc.range_bytes(),
-                (tell_bits + 7) / 8,
+                tell_bits.div_ceil(8),
             );
 
             drop(enc);
@@ -384,7 +414,7 @@ mod tests {
         let mut buffer = vec![0_u8; DATA_SIZE];
 
         for _ in 0..1024 {
-            let sz = rnd.generate_range::<usize>(128, 512);
+            let sz = rnd.generate_range::<usize, _>(128..512);
             let mut logp1 = vec![0_u32; sz];
             let mut data = vec![0_u32; sz];
             let mut tell = vec![0_u32; sz + 1];
@@ -393,9 +423,9 @@ mod tests {
             let mut enc = RangeEncoder::new(&mut buffer);
             tell[0] = enc.tell_frac();
             for j in 0..sz {
-                data[j] = rnd.generate_range::<u32>(0, 2);
-                logp1[j] = rnd.generate_range::<u32>(1, 17);
-                enc_method[j] = rnd.generate_range::<u32>(0, 4);
+                data[j] = rnd.generate_range::<u32, _>(0..2);
+                logp1[j] = rnd.generate_range::<u32, _>(1..17);
+                enc_method[j] = rnd.generate_range::<u32, _>(0..4);
                 match enc_method[j] {
                     0 => {
                         let x = if data[j] != 0 { (1 << logp1[j]) - 1 } else { 0 };
@@ -421,10 +451,10 @@ mod tests {
             enc.done().unwrap();
 
             assert!(
-                (enc.tell() + 7) / 8 >= enc.range_bytes() as u32,
+                enc.tell().div_ceil(8) >= enc.range_bytes() as u32,
                 "tell() lied, there's {} bytes instead of {}",
                 enc.range_bytes(),
-                (enc.tell() + 7) / 8,
+                enc.tell().div_ceil(8),
             );
 
             drop(enc);
@@ -440,7 +470,7 @@ mod tests {
             );
 
             for j in 0..sz {
-                let dec_method = rnd.generate_range::<u32>(0, 4);
+                let dec_method = rnd.generate_range::<u32, _>(0..4);
                 let sym: u32;
                 match dec_method {
                     0 => {
@@ -548,12 +578,12 @@ mod tests {
 
         let mut enc = RangeEncoder::new(&mut buffer);
 
-        (3..10000).into_iter().for_each(|i| {
-            val[i] = rng.generate_range::<u32>(0, 16) as i32 - 7;
-            decay[i] = rng.generate_range::<u32>(5000, 16000);
+        (3..10000).for_each(|i| {
+            val[i] = rng.generate_range::<u32, _>(0..16) as i32 - 7;
+            decay[i] = rng.generate_range::<u32, _>(5000..16000);
         });
 
-        (0..10000).into_iter().for_each(|i| {
+        (0..10000).for_each(|i| {
             enc.encode_laplace(&mut val[i], get_start_freq(decay[i]), decay[i])
                 .unwrap();
         });
@@ -563,7 +593,7 @@ mod tests {
 
         let mut dec = RangeDecoder::new(&buffer);
 
-        (0..10000).into_iter().for_each(|i| {
+        (0..10000).for_each(|i| {
             let d = dec.decode_laplace(get_start_freq(decay[i]), decay[i]);
             assert_eq!(d, val[i], "Got {} instead of {}", d, val[i]);
         });
@@ -0,0 +1,946 @@
+//! Implements the repacketizer.
+
+use crate::extension::write_extensions;
+use crate::{parse_packet, query_packet_frame_count, query_packet_samples_per_frame, Packet};
+use crate::{Extension, Extensions, OpusError, SamplingRate};
+
+/// Merges the frames of multiple Opus packets into a single packet or splits
+/// the frames of a packet into multiple packets.
+///
+/// All packets need to have the same configuration (TOC without the frame count code)
+/// and can only contain up to 120 ms of audio in total. The repacketizer doesn't copy
+/// the frames, so the added packets need to outlive it.
+///
+/// Repacketizing doesn't re-encode the audio, so it can be used to merge multiple
+/// 20 ms packets into a 60 ms packet for storage or to split them up again for
+/// real-time delivery.
+///
+/// Extensions inside the padding of the added packets are kept and moved to
+/// the output packet that contains their frame.
+#[derive(Clone, Debug, Default)]
+pub struct Repacketizer<'a> {
+    toc: u8,
+    frames: Vec<&'a [u8]>,
+    paddings: Vec<Padding<'a>>,
+    frame_size: usize,
+}
+
+/// The padding of an added packet.
+#[derive(Clone, Debug)]
+struct Padding<'a> {
+    /// Index of the first frame of the packet.
+    frame: usize,
+    /// Number of frames of the packet.
+    frame_count: usize,
+    data: &'a [u8],
+}
+
+impl<'a> Repacketizer<'a> {
+    /// Creates a new, empty repacketizer.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Removes all frames from the repacketizer.
+    ///
+    /// Needs to be called before adding packets with a different configuration.
+    pub fn reset(&mut self) {
+        self.frames.clear();
+        self.paddings.clear();
+    }
+
+    /// Adds the frames of a packet to the repacketizer.
+    ///
+    /// Returns an error if the packet is invalid, has a different configuration than
+    /// the previously added packets or if the total duration would exceed 120 ms.
+    /// The state of the repacketizer is unchanged in that case.
+    ///
+    /// # Arguments
+    /// * `packet` - Input payload.
+    ///
+    pub fn cat(&mut self, packet: &'a [u8]) -> Result<(), OpusError> {
+        self.cat_impl(packet, false)
+    }
+
+    /// Adds the frames of a packet with normal or self-delimited framing to the repacketizer.
+    pub(crate) fn cat_impl(
+        &mut self,
+        packet: &'a [u8],
+        self_delimited: bool,
+    ) -> Result<(), OpusError> {
+        if packet.is_empty() {
+            return Err(OpusError::InvalidPacket);
+        }
+        if self.frames.is_empty() {
+            self.toc = packet[0];
+            self.frame_size = query_packet_samples_per_frame(packet, SamplingRate::Hz8000)?;
+        } else if (self.toc & 0xFC) != (packet[0] & 0xFC) {
+            return Err(OpusError::InvalidPacket);
+        }
+
+        let count = query_packet_frame_count(packet)?;
+        if count < 1 {
+            return Err(OpusError::InvalidPacket);
+        }
+        // Check the 120 ms maximum packet size.
+        if (count + self.frames.len()) * self.frame_size > 960 {
+            return Err(OpusError::InvalidPacket);
+        }
+
+        let mut offsets = [0_usize; 48];
+        let mut sizes = [0_usize; 48];
+        let mut packet_offset = 0;
+        let count = parse_packet(
+            packet,
+            self_delimited,
+            Some(&mut offsets),
+            &mut sizes,
+            None,
+            Some(&mut packet_offset),
+        )?;
+
+        let padding_offset = offsets[count - 1] + sizes[count - 1];
+        if padding_offset < packet_offset {
+            self.paddings.push(Padding {
+                frame: self.frames.len(),
+                frame_count: count,
+                data: &packet[padding_offset..packet_offset],
+            });
+        }
+        (0..count).for_each(|i| {
+            self.frames.push(&packet[offsets[i]..offsets[i] + sizes[i]]);
+        });
+
+        Ok(())
+    }
+
+    /// Returns the number of frames in the repacketizer.
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Writes a packet with the frames `begin..end` into `data`.
+    ///
+    /// Returns the size of the packet.
+    ///
+    /// # Arguments
+    /// * `begin` - The index of the first frame of the packet.
+    /// * `end`   - The index after the last frame of the packet.
+    /// * `data`  - Output payload. A length of 1277 bytes per frame is always sufficient.
+    ///
+    pub fn out_range(&self, begin: usize, end: usize, data: &mut [u8]) -> Result<usize, OpusError> {
+        self.out_range_impl(begin, end, data, false, false, &[])
+    }
+
+    /// Writes a packet with the frames `begin..end` and additional extensions into `data`.
+    ///
+    /// The extensions are written into the padding of the packet. Their frame index
+    /// is relative to `begin`.
+    ///
+    /// Returns the size of the packet.
+    ///
+    /// # Arguments
+    /// * `begin`      - The index of the first frame of the packet.
+    /// * `end`        - The index after the last frame of the packet.
+    /// * `data`       - Output payload.
+    /// * `extensions` - The extensions to add to the packet.
+    ///
+    pub fn out_range_with_extensions(
+        &self,
+        begin: usize,
+        end: usize,
+        data: &mut [u8],
+        extensions: &[Extension],
+    ) -> Result<usize, OpusError> {
+        if extensions
+            .iter()
+            .any(|e| e.frame >= end.saturating_sub(begin))
+        {
+            return Err(OpusError::BadArguments(
+                "extension frame is outside of the frame range",
+            ));
+        }
+        self.out_range_impl(begin, end, data, false, false, extensions)
+    }
+
+    /// Writes a packet with all frames of the repacketizer into `data`.
+    ///
+    /// Returns the size of the packet.
+    ///
+    /// # Arguments
+    /// * `data`  - Output payload. A length of 1277 bytes per frame is always sufficient.
+    ///
+    pub fn out(&self, data: &mut [u8]) -> Result<usize, OpusError> {
+        self.out_range_impl(0, self.frames.len(), data, false, false, &[])
+    }
+
+    /// Writes a packet with the frames `begin..end` into `data`.
+    ///
+    /// If `self_delimited` is set, the size of the last frame is written too,
+    /// so that the packet can be followed by other packets. If `pad` is set,
+    /// the packet is padded to the size of `data`. The `extensions` are written
+    /// together with the extensions of the added packets into the padding.
+    ///
+    /// Returns the size of the packet.
+    pub(crate) fn out_range_impl(
+        &self,
+        begin: usize,
+        end: usize,
+        data: &mut [u8],
+        self_delimited: bool,
+        pad: bool,
+        extensions: &[Extension],
+    ) -> Result<usize, OpusError> {
+        if begin >= end || end > self.frames.len() {
+            return Err(OpusError::BadArguments("invalid frame range"));
+        }
+
+        // Collect the extensions of the added packets that belong to the frame range.
+        let mut all_extensions = extensions.to_vec();
+        for padding in self.paddings.iter() {
+            for extension in Extensions::new(padding.data, padding.frame_count) {
+                let mut extension = extension?;
+                let frame = padding.frame + extension.frame;
+                if (begin..end).contains(&frame) {
+                    extension.frame = frame - begin;
+                    all_extensions.push(extension);
+                }
+            }
+        }
+
+        let frames = &self.frames[begin..end];
+        let count = frames.len();
+        let maxlen = data.len();
+        let toc = self.toc & 0xFC;
+        let self_delimited_size = if self_delimited {
+            1 + (frames[count - 1].len() >= 252) as usize
+        } else {
+            0
+        };
+
+        let mut tot_size = self_delimited_size;
+        let mut ptr = 0;
+        if count == 1 {
+            // Code 0
+            tot_size += frames[0].len() + 1;
+            if tot_size > maxlen {
+                return Err(OpusError::BufferToSmall);
+            }
+            data[ptr] = toc;
+            ptr += 1;
+        } else if count == 2 {
+            if frames[1].len() == frames[0].len() {
+                // Code 1
+                tot_size += 2 * frames[0].len() + 1;
+                if tot_size > maxlen {
+                    return Err(OpusError::BufferToSmall);
+                }
+                data[ptr] = toc | 0x1;
+                ptr += 1;
+            } else {
+                // Code 2
+                tot_size +=
+                    frames[0].len() + frames[1].len() + 2 + (frames[0].len() >= 252) as usize;
+                if tot_size > maxlen {
+                    return Err(OpusError::BufferToSmall);
+                }
+                data[ptr] = toc | 0x2;
+                ptr += 1;
+                ptr += encode_size(frames[0].len(), &mut data[ptr..]);
+            }
+        }
+
+        let mut ext_begin = 0;
+        let mut ext_len = 0;
+        let mut ones_begin = 0;
+        let mut ones_end = 0;
+        if count > 2 || (pad && tot_size < maxlen) || !all_extensions.is_empty() {
+            // Code 3
+            // Restart the process for the padding case.
+            ptr = 0;
+            tot_size = self_delimited_size;
+            let vbr = frames.iter().any(|frame| frame.len() != frames[0].len());
+            if vbr {
+                tot_size += 2;
+                frames[..count - 1].iter().for_each(|frame| {
+                    tot_size += 1 + (frame.len() >= 252) as usize + frame.len();
+                });
+                tot_size += frames[count - 1].len();
+
+                if tot_size > maxlen {
+                    return Err(OpusError::BufferToSmall);
+                }
+                data[0] = toc | 0x3;
+                data[1] = count as u8 | 0x80;
+            } else {
+                tot_size += count * frames[0].len() + 2;
+                if tot_size > maxlen {
+                    return Err(OpusError::BufferToSmall);
+                }
+                data[0] = toc | 0x3;
+                data[1] = count as u8;
+            }
+            ptr += 2;
+
+            let mut pad_amount = if pad { maxlen - tot_size } else { 0 };
+            if !all_extensions.is_empty() {
+                ext_len = write_extensions(None, &all_extensions)?;
+                if !pad {
+                    pad_amount = ext_len + ext_len / 254 + 1;
+                } else if pad_amount == 0 {
+                    return Err(OpusError::BufferToSmall);
+                }
+            }
+            if pad_amount != 0 {
+                data[1] |= 0x40;
+                let nb_255s = (pad_amount - 1) / 255;
+                if tot_size + ext_len + nb_255s + 1 > maxlen {
+                    return Err(OpusError::BufferToSmall);
+                }
+                // Extensions are placed at the end, the rest of the padding is filled with 0x01.
+                ext_begin = tot_size + pad_amount - ext_len;
+                ones_begin = tot_size + nb_255s + 1;
+                ones_end = ext_begin;
+                data[ptr..ptr + nb_255s].iter_mut().for_each(|x| *x = 255);
+                ptr += nb_255s;
+                data[ptr] = (pad_amount - 255 * nb_255s - 1) as u8;
+                ptr += 1;
+                tot_size += pad_amount;
+            }
+            if vbr {
+                frames[..count - 1].iter().for_each(|frame| {
+                    ptr += encode_size(frame.len(), &mut data[ptr..]);
+                });
+            }
+        }
+
+        if self_delimited {
+            ptr += encode_size(frames[count - 1].len(), &mut data[ptr..]);
+        }
+
+        // Copy the actual data.
+        frames.iter().for_each(|frame| {
+            data[ptr..ptr + frame.len()].copy_from_slice(frame);
+            ptr += frame.len();
+        });
+
+        if ext_len > 0 {
+            write_extensions(
+                Some(&mut data[ext_begin..ext_begin + ext_len]),
+                &all_extensions,
+            )?;
+        }
+        data[ones_begin..ones_end]
+            .iter_mut()
+            .for_each(|x| *x = 0x01);
+        if pad && all_extensions.is_empty() {
+            // Fill padding with zeros.
+            data[ptr..].iter_mut().for_each(|x| *x = 0);
+        }
+
+        Ok(tot_size)
+    }
+}
+
+/// Pads a given Opus packet to a larger size (possibly changing the TOC sequence).
+///
+/// The padding is added with code 3 framing, so the result is still a valid packet
+/// that decodes to the same audio. This can be used to create packets of a constant
+/// size, which don't reveal the content of the packet by their size.
+///
+/// # Arguments
+/// * `data` - The buffer containing the packet to pad. The packet is padded
+///   in place to the length of the buffer.
+/// * `len`  - The size of the packet.
+///
+pub fn pad_packet(data: &mut [u8], len: usize) -> Result<(), OpusError> {
+    if len < 1 || len > data.len() {
+        return Err(OpusError::BadArguments("invalid packet length"));
+    }
+    if len == data.len() {
+        return Ok(());
+    }
+
+    let packet = data[..len].to_vec();
+    let mut rp = Repacketizer::new();
+    rp.cat(&packet)?;
+    rp.out_range_impl(0, rp.frame_count(), data, false, true, &[])?;
+
+    Ok(())
+}
+
+/// Removes all padding from a given Opus packet and rewrites the TOC sequence to
+/// minimize space usage.
+///
+/// Extensions inside the padding are kept.
+///
+/// Returns the new size of the packet, which is written to the start of `data`.
+///
+/// # Arguments
+/// * `data` - The packet to strip.
+///
+pub fn unpad_packet(data: &mut [u8]) -> Result<usize, OpusError> {
+    if data.is_empty() {
+        return Err(OpusError::BadArguments("packet is empty"));
+    }
+
+    let packet = data.to_vec();
+    let mut rp = Repacketizer::new();
+    rp.cat(&packet)?;
+    rp.out_range_impl(0, rp.frame_count(), data, false, false, &[])
+}
+
+/// Converts an Opus packet into its self-delimited form (RFC 6716 Appendix B).
+///
+/// Self-delimited packets store the size of their last frame, so they can be
+/// concatenated without an extra length field. Padding without extensions is removed.
+///
+/// Returns the size of the self-delimited packet.
+///
+/// # Arguments
+/// * `packet` - The packet to convert.
+/// * `data`   - Output payload. A length of `packet.len()` + 2 bytes is always sufficient.
+///
+pub fn packet_to_self_delimited(packet: &[u8], data: &mut [u8]) -> Result<usize, OpusError> {
+    let mut rp = Repacketizer::new();
+    rp.cat(packet)?;
+    rp.out_range_impl(0, rp.frame_count(), data, true, false, &[])
+}
+
+/// Converts a self-delimited Opus packet back into a normal packet.
+///
+/// Only the first packet inside `packet` is converted. Padding without extensions
+/// is removed.
+///
+/// Returns the size of the normal packet.
+///
+/// # Arguments
+/// * `packet` - The self-delimited packet to convert.
+/// * `data`   - Output payload. A length of `packet.len()` bytes is always sufficient.
+///
+pub fn self_delimited_to_packet(packet: &[u8], data: &mut [u8]) -> Result<usize, OpusError> {
+    let packet = Packet::new_self_delimited(packet)?;
+    let mut rp = Repacketizer::new();
+    rp.cat_impl(packet.as_bytes(), true)?;
+    rp.out_range_impl(0, rp.frame_count(), data, false, false, &[])
+}
+
+/// Pads a given multistream Opus packet to a larger size (possibly changing
+/// the TOC sequence).
+///
+/// The padding is added to the last stream of the packet.
+///
+/// # Arguments
+/// * `data`    - The buffer containing the packet to pad. The packet is padded
+///   in place to the length of the buffer.
+/// * `len`     - The size of the packet.
+/// * `streams` - The number of streams in the packet (1-255).
+///
+pub fn pad_multistream_packet(
+    data: &mut [u8],
+    len: usize,
+    streams: usize,
+) -> Result<(), OpusError> {
+    if len < 1 || len > data.len() {
+        return Err(OpusError::BadArguments("invalid packet length"));
+    }
+    if streams < 1 {
+        return Err(OpusError::BadArguments("invalid number of streams"));
+    }
+    if len == data.len() {
+        return Ok(());
+    }
+
+    // Seek to the last stream.
+    let mut sizes = [0_usize; 48];
+    let mut offset = 0;
+    for _ in 0..streams - 1 {
+        if offset >= len {
+            return Err(OpusError::InvalidPacket);
+        }
+        let mut packet_offset = 0;
+        parse_packet(
+            &data[offset..len],
+            true,
+            None,
+            &mut sizes,
+            None,
+            Some(&mut packet_offset),
+        )?;
+        offset += packet_offset;
+    }
+    if offset >= len {
+        return Err(OpusError::InvalidPacket);
+    }
+
+    pad_packet(&mut data[offset..], len - offset)
+}
+
+/// Removes all padding from a given multistream Opus packet and rewrites the TOC
+/// sequence to minimize space usage.
+///
+/// Extensions inside the padding are kept.
+///
+/// Returns the new size of the packet, which is written to the start of `data`.
+///
+/// # Arguments
+/// * `data`    - The packet to strip.
+/// * `streams` - The number of streams in the packet (1-255).
+///
+pub fn unpad_multistream_packet(data: &mut [u8], streams: usize) -> Result<usize, OpusError> {
+    if data.is_empty() {
+        return Err(OpusError::BadArguments("packet is empty"));
+    }
+    if streams < 1 {
+        return Err(OpusError::BadArguments("invalid number of streams"));
+    }
+
+    let packet = data.to_vec();
+    let mut sizes = [0_usize; 48];
+    let mut offset = 0;
+    let mut dst_len = 0;
+    for s in 0..streams {
+        let self_delimited = s != streams - 1;
+        if offset >= packet.len() {
+            return Err(OpusError::InvalidPacket);
+        }
+
+        let mut packet_offset = 0;
+        parse_packet(
+            &packet[offset..],
+            self_delimited,
+            None,
+            &mut sizes,
+            None,
+            Some(&mut packet_offset),
+        )?;
+
+        let mut rp = Repacketizer::new();
+        rp.cat_impl(&packet[offset..offset + packet_offset], self_delimited)?;
+        dst_len += rp.out_range_impl(
+            0,
+            rp.frame_count(),
+            &mut data[dst_len..],
+            self_delimited,
+            false,
+            &[],
+        )?;
+        offset += packet_offset;
+    }
+
+    Ok(dst_len)
+}
+
+/// Writes the size of a frame in its one or two byte form.
+///
+/// Returns the number of bytes written.
+fn encode_size(size: usize, data: &mut [u8]) -> usize {
+    if size < 252 {
+        data[0] = size as u8;
+        1
+    } else {
+        data[0] = (252 + (size & 0x3)) as u8;
+        data[1] = ((size - data[0] as usize) >> 2) as u8;
+        2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+    use crate::{
+        query_packet_sample_count, Bitrate, Encoder, EncoderConfiguration, MultistreamDecoder,
+        MultistreamDecoderConfiguration, MultistreamEncoder, MultistreamEncoderConfiguration,
+    };
+    use nanorand::Rng;
+    use std::num::NonZeroUsize;
+
+    fn frame_sizes(packet: &[u8]) -> Vec<usize> {
+        let mut sizes = [0_usize; 48];
+        let count = parse_packet(packet, false, None, &mut sizes, None, None).unwrap();
+        sizes[..count].to_vec()
+    }
+
+    #[test]
+    fn test_encode_size() {
+        let mut data = [0_u8; 2];
+        assert_eq!(encode_size(251, &mut data), 1);
+        assert_eq!(data[0], 251);
+        assert_eq!(encode_size(1275, &mut data), 2);
+        assert_eq!(data[0] as usize + 4 * data[1] as usize, 1275);
+    }
+
+    #[test]
+    fn test_cat_and_out_range() {
+        let first = [0xFC, 1, 2, 3];
+        let second = [0xFC, 4, 5];
+        let third = [0xFC, 6, 7, 8];
+
+        let mut rp = Repacketizer::new();
+        rp.cat(&first).unwrap();
+        rp.cat(&second).unwrap();
+        assert_eq!(rp.frame_count(), 2);
+
+        // Code 2: two frames with different sizes.
+        let mut data = [0_u8; 16];
+        let len = rp.out_range(0, 2, &mut data).unwrap();
+        assert_eq!(&data[..len], &[0xFE, 3, 1, 2, 3, 4, 5]);
+
+        // Code 3 VBR.
+        rp.cat(&third).unwrap();
+        let len = rp.out_range(0, 3, &mut data).unwrap();
+        assert_eq!(frame_sizes(&data[..len]), vec![3, 2, 3]);
+
+        // Code 0: a single frame.
+        let len = rp.out_range(0, 1, &mut data).unwrap();
+        assert_eq!(&data[..len], &[0xFC, 1, 2, 3]);
+
+        assert!(rp.out_range(0, 3, &mut data[..4]).is_err());
+        assert!(rp.out_range(1, 1, &mut data).is_err());
+
+        // Code 1: two frames with the same size.
+        let mut rp = Repacketizer::new();
+        rp.cat(&first).unwrap();
+        rp.cat(&third).unwrap();
+        let len = rp.out_range(0, 2, &mut data).unwrap();
+        assert_eq!(data[0] & 0x3, 1);
+        assert_eq!(&data[..len], &[0xFD, 1, 2, 3, 6, 7, 8]);
+    }
+
+    #[test]
+    fn test_self_delimited_out_range() {
+        let mut rp = Repacketizer::new();
+        rp.cat(&[0xFC, 1, 2, 3]).unwrap();
+
+        let mut data = [0_u8; 16];
+        let len = rp
+            .out_range_impl(0, 1, &mut data, true, false, &[])
+            .unwrap();
+        assert_eq!(&data[..len], &[0xFC, 3, 1, 2, 3]);
+
+        let mut sizes = [0_usize; 48];
+        let mut packet_offset = 0;
+        let count = parse_packet(
+            &data[..len],
+            true,
+            None,
+            &mut sizes,
+            None,
+            Some(&mut packet_offset),
+        )
+        .unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(sizes[0], 3);
+        assert_eq!(packet_offset, len);
+
+        // Padded self-delimited packets use code 3.
+        let len = rp.out_range_impl(0, 1, &mut data, true, true, &[]).unwrap();
+        assert_eq!(len, data.len());
+        let count = parse_packet(
+            &data,
+            true,
+            None,
+            &mut sizes,
+            None,
+            Some(&mut packet_offset),
+        )
+        .unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(sizes[0], 3);
+        assert_eq!(packet_offset, data.len());
+    }
+
+    #[test]
+    fn test_merge_and_split() {
+        let mut encoder = Encoder::new(&EncoderConfiguration {
+            bitrate: Bitrate::BitsPerSecond(64000),
+            ..Default::default()
+        })
+        .unwrap();
+        let samples: Vec<f32> = (0..3 * 960 * 2)
+            .map(|i| 0.5 * f32::sin(i as f32 * 0.01))
+            .collect();
+        let packets: Vec<Vec<u8>> = samples
+            .chunks_exact(960 * 2)
+            .map(|frame| {
+                let mut packet = vec![0_u8; 1500];
+                let len = encoder.encode_float(frame, &mut packet).unwrap();
+                packet.truncate(len);
+                packet
+            })
+            .collect();
+
+        // Merge three 20 ms packets into a 60 ms packet.
+        let mut rp = Repacketizer::new();
+        packets.iter().for_each(|packet| rp.cat(packet).unwrap());
+        assert_eq!(rp.frame_count(), 3);
+        let mut merged = vec![0_u8; 3 * 1277];
+        let len = rp.out(&mut merged).unwrap();
+        merged.truncate(len);
+        assert_eq!(query_packet_frame_count(&merged).unwrap(), 3);
+        assert_eq!(
+            query_packet_sample_count(&merged, SamplingRate::Hz48000).unwrap(),
+            2880
+        );
+
+        // Split the 60 ms packet into the original packets.
+        let mut rp = Repacketizer::new();
+        rp.cat(&merged).unwrap();
+        packets.iter().enumerate().for_each(|(i, packet)| {
+            let mut data = vec![0_u8; 1277];
+            let len = rp.out_range(i, i + 1, &mut data).unwrap();
+            assert_eq!(&data[..len], packet.as_slice());
+        });
+
+        rp.reset();
+        assert_eq!(rp.frame_count(), 0);
+        assert!(rp.out(&mut [0_u8; 1277]).is_err());
+    }
+
+    #[test]
+    fn test_cat_mismatch() {
+        let mut rp = Repacketizer::new();
+        rp.cat(&[0xFC, 1]).unwrap();
+        assert!(rp.cat(&[0x7C, 1]).is_err());
+        assert!(rp.cat(&[]).is_err());
+
+        // 6 x 20 ms is the maximum.
+        (0..5).for_each(|_| rp.cat(&[0xFC, 1]).unwrap());
+        assert!(rp.cat(&[0xFC, 1]).is_err());
+    }
+
+    #[test]
+    fn test_unpad_packet() {
+        let mut data = [0_u8; 300];
+        data[..4].copy_from_slice(&[0xFC, 1, 2, 3]);
+        pad_packet(&mut data, 4).unwrap();
+        assert_eq!(unpad_packet(&mut data).unwrap(), 4);
+        assert_eq!(&data[..4], &[0xFC, 1, 2, 3]);
+
+        // Code 3 CBR packet with two frames and padding.
+        let mut data = [0xFF, 0x42, 2, 1, 2, 3, 4, 0, 0];
+        assert_eq!(unpad_packet(&mut data).unwrap(), 5);
+        assert_eq!(&data[..5], &[0xFD, 1, 2, 3, 4]);
+
+        assert!(unpad_packet(&mut []).is_err());
+    }
+
+    #[test]
+    fn test_out_range_with_extensions() {
+        let mut rp = Repacketizer::new();
+        rp.cat(&[0xFC, 1, 2, 3]).unwrap();
+        rp.cat(&[0xFC, 4, 5]).unwrap();
+
+        let extensions = [
+            Extension {
+                id: 3,
+                frame: 1,
+                data: &[0xAA],
+            },
+            Extension {
+                id: 33,
+                frame: 0,
+                data: &[0xBB, 0xCC],
+            },
+        ];
+        let mut data = [0_u8; 32];
+        let len = rp
+            .out_range_with_extensions(0, 2, &mut data, &extensions)
+            .unwrap();
+        let packet = Packet::new(&data[..len]).unwrap();
+        assert_eq!(
+            packet.frames().collect::<Vec<_>>(),
+            vec![&[1, 2, 3][..], &[4, 5][..]]
+        );
+        let parsed: Vec<Extension> = packet.extensions().map(|e| e.unwrap()).collect();
+        assert_eq!(parsed, vec![extensions[1], extensions[0]]);
+
+        // Extensions of the added packets are moved with their frames.
+        let merged = data;
+        let mut rp = Repacketizer::new();
+        rp.cat(&merged[..len]).unwrap();
+        let mut data = [0_u8; 32];
+        let len = rp.out_range(1, 2, &mut data).unwrap();
+        let packet = Packet::new(&data[..len]).unwrap();
+        assert_eq!(packet.frames().collect::<Vec<_>>(), vec![&[4, 5][..]]);
+        let parsed: Vec<Extension> = packet.extensions().map(|e| e.unwrap()).collect();
+        assert_eq!(
+            parsed,
+            vec![Extension {
+                id: 3,
+                frame: 0,
+                data: &[0xAA],
+            }]
+        );
+
+        // Padding and unpadding keep the extensions.
+        let mut padded = [0_u8; 64];
+        padded[..len].copy_from_slice(&data[..len]);
+        pad_packet(&mut padded, len).unwrap();
+        let packet = Packet::new(&padded).unwrap();
+        assert_eq!(packet.extensions().count(), 1);
+        assert_eq!(unpad_packet(&mut padded).unwrap(), len);
+        assert_eq!(&padded[..len], &data[..len]);
+
+        assert!(rp
+            .out_range_with_extensions(0, 1, &mut data, &extensions)
+            .is_err());
+        let mut rp = Repacketizer::new();
+        rp.cat(&[0xFF, 0x41, 1, 1, 2, 3, 0x07]).unwrap();
+        assert!(rp.out(&mut data).is_err());
+    }
+
+    #[test]
+    fn test_self_delimited_conversion() {
+        let packets: [&[u8]; 4] = [
+            &[0xFC, 1, 2, 3],
+            &[0xFD, 1, 2, 3, 4],
+            &[0xFE, 1, 7, 8, 8],
+            &[0xFF, 0x83, 1, 2, 7, 8, 8, 9, 9, 9],
+        ];
+
+        let mut stream = vec![];
+        packets.iter().for_each(|packet| {
+            let mut data = [0_u8; 16];
+            let len = packet_to_self_delimited(packet, &mut data).unwrap();
+            assert_eq!(len, packet.len() + 1);
+            stream.extend_from_slice(&data[..len]);
+        });
+
+        let mut offset = 0;
+        packets.iter().for_each(|packet| {
+            let mut data = [0_u8; 16];
+            let len = self_delimited_to_packet(&stream[offset..], &mut data).unwrap();
+            assert_eq!(&data[..len], *packet);
+            offset += Packet::new_self_delimited(&stream[offset..])
+                .unwrap()
+                .as_bytes()
+                .len();
+        });
+        assert_eq!(offset, stream.len());
+
+        // Padding is removed.
+        let mut data = [0_u8; 16];
+        let len = packet_to_self_delimited(&[0xFF, 0x41, 2, 1, 2, 3, 0, 0], &mut data).unwrap();
+        assert_eq!(&data[..len], &[0xFC, 3, 1, 2, 3]);
+
+        assert!(packet_to_self_delimited(&[], &mut data).is_err());
+        assert!(self_delimited_to_packet(&[0xFC, 4, 1, 2, 3], &mut data).is_err());
+    }
+
+    #[test]
+    fn test_pad_and_unpad_multistream_packet() {
+        let mut encoder = MultistreamEncoder::new(&MultistreamEncoderConfiguration {
+            streams: 2,
+            coupled_streams: 0,
+            mapping: vec![0, 1],
+            bitrate: Bitrate::BitsPerSecond(64000),
+            ..Default::default()
+        })
+        .unwrap();
+        let decoder_configuration = MultistreamDecoderConfiguration {
+            streams: 2,
+            coupled_streams: 0,
+            mapping: vec![0, 1],
+            ..Default::default()
+        };
+        let mut padded_decoder = MultistreamDecoder::new(&decoder_configuration).unwrap();
+        let mut unpadded_decoder = MultistreamDecoder::new(&decoder_configuration).unwrap();
+        let frame_size = NonZeroUsize::new(960).unwrap();
+
+        let samples: Vec<f32> = (0..960 * 2)
+            .map(|i| 0.5 * f32::sin(i as f32 * 0.01))
+            .collect();
+        let mut packet = [0_u8; 1500];
+        let len = encoder.encode_float(&samples, &mut packet).unwrap();
+
+        let mut padded = [0_u8; 1000];
+        padded[..len].copy_from_slice(&packet[..len]);
+        pad_multistream_packet(&mut padded, len, 2).unwrap();
+
+        let mut unpadded = padded;
+        let unpadded_len = unpad_multistream_packet(&mut unpadded, 2).unwrap();
+        assert_eq!(&unpadded[..unpadded_len], &packet[..len]);
+
+        let mut padded_output = [0_f32; 960 * 2];
+        let mut unpadded_output = [0_f32; 960 * 2];
+        padded_decoder
+            .decode_float(Some(&padded), &mut padded_output, frame_size, false)
+            .unwrap();
+        unpadded_decoder
+            .decode_float(
+                Some(&unpadded[..unpadded_len]),
+                &mut unpadded_output,
+                frame_size,
+                false,
+            )
+            .unwrap();
+        assert_eq!(padded_output, unpadded_output);
+
+        assert!(pad_multistream_packet(&mut padded, len, 0).is_err());
+        assert!(pad_multistream_packet(&mut padded, len, 3).is_err());
+        assert!(pad_multistream_packet(&mut padded, 0, 2).is_err());
+        assert!(unpad_multistream_packet(&mut padded[..2], 2).is_err());
+    }
+
+    #[test]
+    fn test_pad_packet() {
+        let mut data = [0_u8; 300];
+        data[..4].copy_from_slice(&[0xFC, 1, 2, 3]);
+        pad_packet(&mut data, 4).unwrap();
+
+        let mut sizes = [0_usize; 48];
+        let mut offsets = [0_usize; 48];
+        let count = parse_packet(&data, false, Some(&mut offsets), &mut sizes, None, None).unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(sizes[0], 3);
+        assert_eq!(&data[offsets[0]..offsets[0] + 3], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn test_random_packets() {
+        let mut rng = nanorand::WyRand::new_seed(42);
+        let mut data = [0_u8; 1024];
+
+        (0..20000).for_each(|i| {
+            let len = rng.generate_range::<usize, _>(1..64 << (i % 5));
+            let data = &mut data[..len];
+            rng.fill(&mut *data);
+            if i % 2 == 0 {
+                data[0] |= 0x3;
+            }
+            let frames: Vec<Vec<u8>> = match Packet::new(data) {
+                Ok(packet) => packet.frames().map(|frame| frame.to_vec()).collect(),
+                Err(_) => {
+                    assert!(unpad_packet(&mut data.to_vec()).is_err());
+                    return;
+                }
+            };
+
+            let mut unpadded = data.to_vec();
+            let unpadded_len = match unpad_packet(&mut unpadded) {
+                Ok(len) => len,
+                // The padding contains malformed extensions.
+                Err(_) => return,
+            };
+            let packet = Packet::new(&unpadded[..unpadded_len]).unwrap();
+            assert_eq!(packet.frames().collect::<Vec<_>>(), frames);
+
+            let mut padded = unpadded[..unpadded_len].to_vec();
+            padded.resize(unpadded_len + 300, 0);
+            pad_packet(&mut padded, unpadded_len).unwrap();
+            let packet = Packet::new(&padded).unwrap();
+            assert_eq!(packet.frames().collect::<Vec<_>>(), frames);
+
+            let mut self_delimited = [0_u8; 1500];
+            let self_delimited_len =
+                packet_to_self_delimited(&unpadded[..unpadded_len], &mut self_delimited).unwrap();
+            let mut restored = [0_u8; 1500];
+            let restored_len =
+                self_delimited_to_packet(&self_delimited[..self_delimited_len], &mut restored)
+                    .unwrap();
+            assert_eq!(&restored[..restored_len], &unpadded[..unpadded_len]);
+        });
+    }
+}
@@ -1,8 +1,67 @@
 //! Implements the Silk decoder.
 
 use crate::range_coder::RangeDecoder;
+use crate::silk::fixed::{
+    add_sat16, add_sat32, clz32, div32_varq, inverse32_varq, lshift_sat32, rand, rshift_round,
+    sat16, smlawb, smulbb, smultt, smulwb, smulww, sqrt_approx, sum_sqr_shift,
+};
+use crate::silk::gain::gains_dequant;
+use crate::silk::lpc::{bwexpander, lpc_analysis_filter, lpc_inverse_pred_gain};
+use crate::silk::nlsf::{nlsf2a, nlsf_decode, nlsf_unpack};
+use crate::silk::pitch::decode_pitch;
+use crate::silk::pulses::decode_pulses;
+use crate::silk::resampler::Resampler;
+use crate::silk::stereo::{
+    stereo_decode_mid_only, stereo_decode_pred, stereo_ms_to_lr, StereoDecoderState,
+};
+use crate::silk::tables::{
+    NlsfCodebook, DELTA_GAIN_ICDF, GAIN_ICDF, LBRR_FLAGS_2_ICDF, LBRR_FLAGS_3_ICDF,
+    LTP_GAIN_ICDF_0, LTP_GAIN_ICDF_1, LTP_GAIN_ICDF_2, LTP_GAIN_VQ_0, LTP_GAIN_VQ_1, LTP_GAIN_VQ_2,
+    LTP_PER_INDEX_ICDF, LTP_SCALES_TABLE_Q14, LTP_SCALE_ICDF, NLSF_CB_NB_MB, NLSF_CB_WB,
+    NLSF_EXT_ICDF, NLSF_INTERPOLATION_FACTOR_ICDF, PITCH_CONTOUR_10_MS_ICDF,
+    PITCH_CONTOUR_10_MS_NB_ICDF, PITCH_CONTOUR_ICDF, PITCH_CONTOUR_NB_ICDF, PITCH_DELTA_ICDF,
+    PITCH_LAG_ICDF, QUANTIZATION_OFFSETS_Q10, TYPE_OFFSET_NO_VAD_ICDF, TYPE_OFFSET_VAD_ICDF,
+    UNIFORM4_ICDF, UNIFORM6_ICDF, UNIFORM8_ICDF,
+};
+use crate::silk::{
+    CondCoding, SideInfoIndices, BWE_AFTER_LOSS_Q16, LTP_MEM_LENGTH_MS, LTP_ORDER, MAX_API_FS_KHZ,
+    MAX_FRAMES_PER_PACKET, MAX_FRAME_LENGTH, MAX_LPC_ORDER, MAX_NB_SUBFR, MAX_SUB_FRAME_LENGTH,
+    MIN_LPC_ORDER, NLSF_QUANT_MAX_AMPLITUDE, QUANT_LEVEL_ADJUST_Q10, SHELL_CODEC_FRAME_LENGTH,
+    SUB_FRAME_LENGTH_MS, TYPE_NO_VOICE_ACTIVITY, TYPE_VOICED,
+};
 use crate::{Channels, OpusError, SamplingRate};
 
+/// Bandwidth expansion of the LPC coefficients during concealment (0.99 in Q16).
+const PLC_BWE_COEF_Q16: i32 = 64881;
+/// Minimal LTP gain at the start of a voiced concealment (0.7 in Q14).
+const V_PITCH_GAIN_START_MIN_Q14: i32 = 11469;
+/// Maximal LTP gain at the start of a voiced concealment (0.95 in Q14).
+const V_PITCH_GAIN_START_MAX_Q14: i32 = 15565;
+const MAX_PITCH_LAG_MS: i32 = 18;
+const RAND_BUF_SIZE: usize = 128;
+const RAND_BUF_MASK: usize = RAND_BUF_SIZE - 1;
+/// 2^3 = 8 dB LPC gain.
+const LOG2_INV_LPC_GAIN_HIGH_THRES: i32 = 3;
+/// 2^8 = 24 dB LPC gain.
+const LOG2_INV_LPC_GAIN_LOW_THRES: i32 = 8;
+/// Pitch lag drift per subframe (0.01 in Q16).
+const PITCH_DRIFT_FAC_Q16: i32 = 655;
+
+/// Attenuation of the harmonic part per lost frame (0.99, 0.95 in Q15).
+const HARM_ATT_Q15: &[i32; 2] = &[32440, 31130];
+/// Attenuation of the random part of voiced frames per lost frame (0.95, 0.8 in Q15).
+const PLC_RAND_ATTENUATE_V_Q15: &[i32; 2] = &[31130, 26214];
+/// Attenuation of the random part of unvoiced frames per lost frame (0.99, 0.9 in Q15).
+const PLC_RAND_ATTENUATE_UV_Q15: &[i32; 2] = &[32440, 29491];
+/// Mask of the index into the comfort noise excitation buffer.
+const CNG_BUF_MASK_MAX: usize = 255;
+/// Smoothing coefficient of the comfort noise gain (0.25^(1/4) in Q16).
+const CNG_GAIN_SMTH_Q16: i32 = 4634;
+/// Threshold to adapt the comfort noise gain faster (-3 dB in Q16).
+const CNG_GAIN_SMTH_THRESHOLD_Q16: i32 = 46396;
+/// Smoothing coefficient of the comfort noise NLSFs (0.25 in Q16).
+const CNG_NLSF_SMTH_Q16: i32 = 16348;
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub(crate) enum LostFlag {
     /// No packet loss.
@@ -20,36 +79,54 @@ pub(crate) struct SilkDecoder {
     channels: Channels,
     internal_sampling_rate: SamplingRate,
     internal_channels: Channels,
-    // TODO silk_decoder_state
     payload_size_ms: usize,
+
+    channel_state: [ChannelDecoder; 2],
+    stereo_state: StereoDecoderState,
+    prev_channels_api: Channels,
+    prev_channels_internal: Channels,
+    prev_decode_only_middle: bool,
 }
 
 impl SilkDecoder {
     /// Creates a new Silk decoder. Configures the output sampling rate and output channels.
     pub(crate) fn new(sampling_rate: SamplingRate, channels: Channels) -> Result<Self, OpusError> {
-        // TODO
         Ok(Self {
             sampling_rate,
             channels,
             internal_sampling_rate: SamplingRate::Hz48000,
             internal_channels: Channels::Stereo,
             payload_size_ms: 0,
+            channel_state: [ChannelDecoder::default(), ChannelDecoder::default()],
+            stereo_state: StereoDecoderState::default(),
+            prev_channels_api: Channels::Mono,
+            prev_channels_internal: Channels::Mono,
+            prev_decode_only_middle: false,
         })
     }
 
     /// Resets the Silk decoder.
     pub(crate) fn reset(&mut self) -> Result<(), OpusError> {
-        unimplemented!()
-    }
+        self.channel_state
+            .iter_mut()
+            .for_each(|state| *state = ChannelDecoder::default());
+        self.stereo_state = StereoDecoderState::default();
 
-    /// Gets the pitch of the last decoded frame.
-    pub(crate) fn pitch(&self) -> u32 {
-        unimplemented!()
+        // Not strictly needed, but it's cleaner that way.
+        self.prev_decode_only_middle = false;
+
+        Ok(())
     }
 
-    /// Sets the internal channels.
-    pub(crate) fn internal_channels(&mut self, internal_channels: Channels) {
-        self.internal_channels = internal_channels;
+    /// Gets the pitch of the last decoded frame, measured at 48 kHz.
+    pub(crate) fn pitch(&self) -> u32 {
+        let state = &self.channel_state[0];
+        if state.prev_signal_type == TYPE_VOICED {
+            const MULT_TAB: [i32; 3] = [6, 4, 3];
+            (state.lag_prev * MULT_TAB[(state.fs_khz - 8) >> 2]) as u32
+        } else {
+            0
+        }
     }
 
     /// Sets the internal channels.
@@ -67,15 +144,1819 @@ impl SilkDecoder {
         self.payload_size_ms = payload_size_ms;
     }
 
-    /// TODO implement me
+    /// Decodes a frame.
+    ///
+    /// Writes the interleaved output samples into `samples` and the number of decoded
+    /// samples per channel into `frame_size`. `first_frame` indicates the first decoder
+    /// call for the current packet.
     pub(crate) fn decode(
-        &self,
+        &mut self,
         dec: &mut Option<RangeDecoder>,
-        samples: &[f32],
+        samples: &mut [i16],
         frame_size: &mut usize,
         lost_flag: LostFlag,
         first_frame: bool,
     ) -> Result<(), OpusError> {
-        unimplemented!()
+        let channels_api = self.channels as usize;
+        let channels_internal = self.internal_channels as usize;
+        let api_sampling_rate = self.sampling_rate as usize;
+        let internal_sampling_rate = self.internal_sampling_rate as usize;
+
+        let mut decode_only_middle = false;
+        let mut ms_pred_q13 = [0_i32; 2];
+
+        // Test if first frame in payload.
+        if first_frame {
+            self.channel_state
+                .iter_mut()
+                .take(channels_internal)
+                .for_each(|state| state.n_frames_decoded = 0);
+        }
+
+        // If mono -> stereo transition in bitstream: init state of second channel.
+        if channels_internal > self.prev_channels_internal as usize {
+            self.channel_state[1] = ChannelDecoder::default();
+        }
+
+        let stereo_to_mono = channels_internal == 1
+            && self.prev_channels_internal == Channels::Stereo
+            && internal_sampling_rate == 1000 * self.channel_state[0].fs_khz;
+
+        if self.channel_state[0].n_frames_decoded == 0 {
+            let (n_frames_per_packet, nb_subfr) = match self.payload_size_ms {
+                // Assuming packet loss, use 10 ms.
+                0 | 10 => (1, 2),
+                20 => (1, 4),
+                40 => (2, 4),
+                60 => (3, 4),
+                _ => {
+                    return Err(OpusError::InternalError(
+                        "invalid frame size for the silk decoder",
+                    ))
+                }
+            };
+
+            let fs_khz_dec = (internal_sampling_rate >> 10) + 1;
+            if fs_khz_dec != 8 && fs_khz_dec != 12 && fs_khz_dec != 16 {
+                return Err(OpusError::InternalError(
+                    "invalid internal sampling rate for the silk decoder",
+                ));
+            }
+
+            for state in self.channel_state.iter_mut().take(channels_internal) {
+                state.n_frames_per_packet = n_frames_per_packet;
+                state.nb_subfr = nb_subfr;
+                state.set_fs(fs_khz_dec, api_sampling_rate)?;
+            }
+        }
+
+        if channels_api == 2
+            && channels_internal == 2
+            && (self.prev_channels_api == Channels::Mono
+                || self.prev_channels_internal == Channels::Mono)
+        {
+            self.stereo_state.pred_prev_q13 = [0; 2];
+            self.stereo_state.s_side = [0; 2];
+            self.channel_state[1].resampler = self.channel_state[0].resampler.clone();
+        }
+        self.prev_channels_api = self.channels;
+        self.prev_channels_internal = self.internal_channels;
+
+        if !(8000..=MAX_API_FS_KHZ * 1000).contains(&api_sampling_rate) {
+            return Err(OpusError::InternalError(
+                "invalid API sampling rate for the silk decoder",
+            ));
+        }
+
+        if lost_flag != LostFlag::Loss && self.channel_state[0].n_frames_decoded == 0 {
+            let dec = dec.as_mut().ok_or(OpusError::InternalError(
+                "silk decoder needs a range decoder",
+            ))?;
+            self.decode_packet_flags(dec, channels_internal);
+
+            if lost_flag == LostFlag::NoLoss {
+                self.skip_lbrr_data(dec, channels_internal);
+            }
+        }
+
+        // Get MS predictor index.
+        if channels_internal == 2 {
+            let frame_index = self.channel_state[0].n_frames_decoded;
+            if lost_flag == LostFlag::NoLoss
+                || (lost_flag == LostFlag::DecodeFec
+                    && self.channel_state[0].lbrr_flags[frame_index])
+            {
+                let dec = dec.as_mut().ok_or(OpusError::InternalError(
+                    "silk decoder needs a range decoder",
+                ))?;
+                stereo_decode_pred(dec, &mut ms_pred_q13);
+
+                // For LBRR data, decode mid-only flag only if side-channel's LBRR flag is false.
+                if (lost_flag == LostFlag::NoLoss && !self.channel_state[1].vad_flags[frame_index])
+                    || (lost_flag == LostFlag::DecodeFec
+                        && !self.channel_state[1].lbrr_flags[frame_index])
+                {
+                    decode_only_middle = stereo_decode_mid_only(dec);
+                } else {
+                    decode_only_middle = false;
+                }
+            } else {
+                ms_pred_q13
+                    .iter_mut()
+                    .zip(self.stereo_state.pred_prev_q13.iter())
+                    .for_each(|(pred, prev)| *pred = i32::from(*prev));
+            }
+        }
+
+        // Reset side channel decoder prediction memory for first frame with side coding.
+        if channels_internal == 2 && !decode_only_middle && self.prev_decode_only_middle {
+            let state = &mut self.channel_state[1];
+            state.out_buf = [0; MAX_FRAME_LENGTH + 2 * MAX_SUB_FRAME_LENGTH];
+            state.s_lpc_q14_buf = [0; MAX_LPC_ORDER];
+            state.lag_prev = 100;
+            state.last_gain_index = 10;
+            state.prev_signal_type = TYPE_NO_VOICE_ACTIVITY;
+            state.first_frame_after_reset = true;
+        }
+
+        let has_side = if lost_flag == LostFlag::NoLoss {
+            !decode_only_middle
+        } else {
+            !self.prev_decode_only_middle
+                || (channels_internal == 2
+                    && lost_flag == LostFlag::DecodeFec
+                    && self.channel_state[1].lbrr_flags[self.channel_state[1].n_frames_decoded])
+        };
+
+        // Both channels start with two samples of history used by the stereo processing.
+        let mut samples_out1 = [[0_i16; MAX_FRAME_LENGTH + 2]; 2];
+        let mut n_samples_out_dec = 0;
+
+        // Call decoder for one frame.
+        for (n, samples_out1) in samples_out1.iter_mut().enumerate().take(channels_internal) {
+            if n == 0 || has_side {
+                let frame_index = self.channel_state[0].n_frames_decoded as isize - n as isize;
+
+                // Use independent coding if no previous frame available.
+                let cond_coding = if frame_index <= 0 {
+                    CondCoding::Independently
+                } else if lost_flag == LostFlag::DecodeFec {
+                    if self.channel_state[n].lbrr_flags[frame_index as usize - 1] {
+                        CondCoding::Conditionally
+                    } else {
+                        CondCoding::Independently
+                    }
+                } else if n > 0 && self.prev_decode_only_middle {
+                    // If we skipped a side frame in this packet, we don't
+                    // need LTP scaling; the LTP state is well-defined.
+                    CondCoding::IndependentlyNoLtpScaling
+                } else {
+                    CondCoding::Conditionally
+                };
+
+                n_samples_out_dec = self.channel_state[n].decode_frame(
+                    dec,
+                    &mut samples_out1[2..],
+                    lost_flag,
+                    cond_coding,
+                )?;
+            } else {
+                samples_out1[2..2 + n_samples_out_dec]
+                    .iter_mut()
+                    .for_each(|x| *x = 0);
+            }
+            self.channel_state[n].n_frames_decoded += 1;
+        }
+
+        let [samples_out1_0, samples_out1_1] = &mut samples_out1;
+        if channels_api == 2 && channels_internal == 2 {
+            // Convert mid / side to left / right.
+            stereo_ms_to_lr(
+                &mut self.stereo_state,
+                samples_out1_0,
+                samples_out1_1,
+                &ms_pred_q13,
+                self.channel_state[0].fs_khz,
+                n_samples_out_dec,
+            );
+        } else {
+            // Buffering.
+            samples_out1_0[..2].copy_from_slice(&self.stereo_state.s_mid);
+            self.stereo_state
+                .s_mid
+                .copy_from_slice(&samples_out1_0[n_samples_out_dec..n_samples_out_dec + 2]);
+        }
+
+        // Number of output samples.
+        let n_samples_out =
+            n_samples_out_dec * api_sampling_rate / (self.channel_state[0].fs_khz * 1000);
+        *frame_size = n_samples_out;
+
+        let mut resample_out = [0_i16; MAX_API_FS_KHZ * 20];
+        for (n, samples_out1) in samples_out1
+            .iter()
+            .enumerate()
+            .take(usize::min(channels_api, channels_internal))
+        {
+            // Resample decoded signal to API sampling rate.
+            self.channel_state[n].resampler.resample(
+                &mut resample_out[..n_samples_out],
+                &samples_out1[1..1 + n_samples_out_dec],
+            );
+
+            // Interleave if stereo output and stereo stream.
+            samples
+                .iter_mut()
+                .skip(n)
+                .step_by(channels_api)
+                .zip(resample_out.iter().take(n_samples_out))
+                .for_each(|(out, x)| *out = *x);
+        }
+
+        // Create two channel output from mono stream.
+        if channels_api == 2 && channels_internal == 1 {
+            if stereo_to_mono {
+                // Resample right channel for newly collapsed stereo just in case
+                // we weren't doing collapsing when switching to mono.
+                self.channel_state[1].resampler.resample(
+                    &mut resample_out[..n_samples_out],
+                    &samples_out1[0][1..1 + n_samples_out_dec],
+                );
+                samples
+                    .iter_mut()
+                    .skip(1)
+                    .step_by(2)
+                    .zip(resample_out.iter().take(n_samples_out))
+                    .for_each(|(out, x)| *out = *x);
+            } else {
+                samples
+                    .chunks_exact_mut(2)
+                    .take(n_samples_out)
+                    .for_each(|x| x[1] = x[0]);
+            }
+        }
+
+        if lost_flag == LostFlag::Loss {
+            // On packet loss, remove the gain clamping to prevent having the energy "bounce back"
+            // if we lose packets when the energy is going down.
+            self.channel_state
+                .iter_mut()
+                .take(channels_internal)
+                .for_each(|state| state.last_gain_index = 10);
+        } else {
+            self.prev_decode_only_middle = decode_only_middle;
+        }
+
+        Ok(())
+    }
+
+    /// Decodes the VAD and LBRR flags at the start of a packet.
+    fn decode_packet_flags(&mut self, dec: &mut RangeDecoder, channels_internal: usize) {
+        self.channel_state
+            .iter_mut()
+            .take(channels_internal)
+            .for_each(|state| {
+                (0..state.n_frames_per_packet).for_each(|i| {
+                    state.vad_flags[i] = dec.decode_bit_logp(1);
+                });
+                state.lbrr_flag = dec.decode_bit_logp(1);
+            });
+
+        self.channel_state
+            .iter_mut()
+            .take(channels_internal)
+            .for_each(|state| {
+                state.lbrr_flags = [false; MAX_FRAMES_PER_PACKET];
+                if state.lbrr_flag {
+                    if state.n_frames_per_packet == 1 {
+                        state.lbrr_flags[0] = true;
+                    } else {
+                        let icdf: &[u8] = if state.n_frames_per_packet == 2 {
+                            LBRR_FLAGS_2_ICDF
+                        } else {
+                            LBRR_FLAGS_3_ICDF
+                        };
+                        let lbrr_symbol = dec.decode_icdf(icdf, 8) + 1;
+                        (0..state.n_frames_per_packet).for_each(|i| {
+                            state.lbrr_flags[i] = (lbrr_symbol >> i) & 1 != 0;
+                        });
+                    }
+                }
+            });
+    }
+
+    /// Regular decoding skips all LBRR data.
+    fn skip_lbrr_data(&mut self, dec: &mut RangeDecoder, channels_internal: usize) {
+        let mut pulses = [0_i16; MAX_FRAME_LENGTH];
+        let mut ms_pred_q13 = [0_i32; 2];
+
+        (0..self.channel_state[0].n_frames_per_packet).for_each(|i| {
+            (0..channels_internal).for_each(|n| {
+                if self.channel_state[n].lbrr_flags[i] {
+                    if channels_internal == 2 && n == 0 {
+                        stereo_decode_pred(dec, &mut ms_pred_q13);
+                        if !self.channel_state[1].lbrr_flags[i] {
+                            stereo_decode_mid_only(dec);
+                        }
+                    }
+
+                    // Use conditional coding if previous frame available.
+                    let state = &mut self.channel_state[n];
+                    let cond_coding = if i > 0 && state.lbrr_flags[i - 1] {
+                        CondCoding::Conditionally
+                    } else {
+                        CondCoding::Independently
+                    };
+                    state.decode_indices(dec, i, true, cond_coding);
+                    decode_pulses(
+                        dec,
+                        &mut pulses,
+                        state.indices.signal_type,
+                        state.indices.quant_offset_type,
+                        state.frame_length,
+                    );
+                }
+            });
+        });
+    }
+}
+
+/// Decoder control of a frame.
+#[derive(Clone, Debug, Default)]
+struct DecoderControl {
+    pitch_l: [i32; MAX_NB_SUBFR],
+    gains_q16: [i32; MAX_NB_SUBFR],
+    pred_coef_q12: [[i16; MAX_LPC_ORDER]; 2],
+    ltp_coef_q14: [i16; LTP_ORDER * MAX_NB_SUBFR],
+    ltp_scale_q14: i32,
+}
+
+/// State of the packet loss concealment.
+#[derive(Clone, Debug)]
+struct PlcState {
+    /// Pitch lag to use for voiced concealment.
+    pitch_l_q8: i32,
+    /// LTP coefficients to use for voiced concealment.
+    ltp_coef_q14: [i16; LTP_ORDER],
+    prev_lpc_q12: [i16; MAX_LPC_ORDER],
+    /// Was the previous frame lost.
+    last_frame_lost: bool,
+    /// Seed for unvoiced signal generation.
+    rand_seed: i32,
+    /// Scaling of the unvoiced random signal.
+    rand_scale_q14: i16,
+    conc_energy: i32,
+    conc_energy_shift: i32,
+    prev_ltp_scale_q14: i32,
+    prev_gain_q16: [i32; 2],
+    fs_khz: usize,
+    nb_subfr: usize,
+    subfr_length: usize,
+}
+
+impl Default for PlcState {
+    fn default() -> Self {
+        Self {
+            pitch_l_q8: 0,
+            ltp_coef_q14: [0; LTP_ORDER],
+            prev_lpc_q12: [0; MAX_LPC_ORDER],
+            last_frame_lost: false,
+            rand_seed: 0,
+            rand_scale_q14: 0,
+            conc_energy: 0,
+            conc_energy_shift: 0,
+            prev_ltp_scale_q14: 0,
+            prev_gain_q16: [1 << 16; 2],
+            fs_khz: 0,
+            nb_subfr: 2,
+            subfr_length: 20,
+        }
+    }
+}
+
+/// State of the comfort noise generation.
+#[derive(Clone, Debug)]
+struct CngState {
+    /// Excitation of the frames without voice activity.
+    exc_buf_q14: [i32; MAX_FRAME_LENGTH],
+    /// Smoothed NLSFs of the frames without voice activity.
+    smth_nlsf_q15: [i16; MAX_LPC_ORDER],
+    synth_state: [i32; MAX_LPC_ORDER],
+    /// Smoothed gain of the frames without voice activity.
+    smth_gain_q16: i32,
+    rand_seed: i32,
+    fs_khz: usize,
+}
+
+impl Default for CngState {
+    fn default() -> Self {
+        Self {
+            exc_buf_q14: [0; MAX_FRAME_LENGTH],
+            smth_nlsf_q15: [0; MAX_LPC_ORDER],
+            synth_state: [0; MAX_LPC_ORDER],
+            smth_gain_q16: 0,
+            rand_seed: 3_176_576,
+            fs_khz: 0,
+        }
+    }
+}
+
+/// Decoder state of a single channel.
+#[derive(Clone, Debug)]
+struct ChannelDecoder {
+    prev_gain_q16: i32,
+    exc_q14: [i32; MAX_FRAME_LENGTH],
+    s_lpc_q14_buf: [i32; MAX_LPC_ORDER],
+    /// Buffer for output signal.
+    out_buf: [i16; MAX_FRAME_LENGTH + 2 * MAX_SUB_FRAME_LENGTH],
+    /// Previous lag.
+    lag_prev: i32,
+    /// Previous gain index.
+    last_gain_index: i8,
+    /// Sampling frequency in kHz.
+    fs_khz: usize,
+    /// API sample frequency in Hz.
+    fs_api_hz: usize,
+    /// Number of 5 ms subframes in a frame.
+    nb_subfr: usize,
+    /// Frame length in samples.
+    frame_length: usize,
+    /// Subframe length in samples.
+    subfr_length: usize,
+    /// Length of LTP memory.
+    ltp_mem_length: usize,
+    /// LPC order.
+    lpc_order: usize,
+    /// Used to interpolate LSFs.
+    prev_nlsf_q15: [i16; MAX_LPC_ORDER],
+    /// Flag for deactivating NLSF interpolation.
+    first_frame_after_reset: bool,
+    /// Pointer to iCDF table for low bits of pitch lag index.
+    pitch_lag_low_bits_icdf: &'static [u8],
+    /// Pointer to iCDF table for pitch contour index.
+    pitch_contour_icdf: &'static [u8],
+
+    // For buffering payload in case of more frames per packet.
+    n_frames_decoded: usize,
+    n_frames_per_packet: usize,
+
+    // Specifically for entropy coding.
+    ec_prev_signal_type: usize,
+    ec_prev_lag_index: i16,
+
+    vad_flags: [bool; MAX_FRAMES_PER_PACKET],
+    lbrr_flag: bool,
+    lbrr_flags: [bool; MAX_FRAMES_PER_PACKET],
+
+    resampler: Resampler,
+
+    /// Pointer to NLSF codebook.
+    nlsf_cb: &'static NlsfCodebook,
+
+    /// Quantization indices.
+    indices: SideInfoIndices,
+
+    loss_cnt: usize,
+    prev_signal_type: usize,
+
+    plc: PlcState,
+    cng: CngState,
+}
+
+impl Default for ChannelDecoder {
+    fn default() -> Self {
+        Self {
+            // Used to deactivate LSF interpolation.
+            first_frame_after_reset: true,
+            prev_gain_q16: 65536,
+            exc_q14: [0; MAX_FRAME_LENGTH],
+            s_lpc_q14_buf: [0; MAX_LPC_ORDER],
+            out_buf: [0; MAX_FRAME_LENGTH + 2 * MAX_SUB_FRAME_LENGTH],
+            lag_prev: 0,
+            last_gain_index: 0,
+            fs_khz: 0,
+            fs_api_hz: 0,
+            nb_subfr: 0,
+            frame_length: 0,
+            subfr_length: 0,
+            ltp_mem_length: 0,
+            lpc_order: 0,
+            prev_nlsf_q15: [0; MAX_LPC_ORDER],
+            pitch_lag_low_bits_icdf: &[],
+            pitch_contour_icdf: &[],
+            n_frames_decoded: 0,
+            n_frames_per_packet: 0,
+            ec_prev_signal_type: 0,
+            ec_prev_lag_index: 0,
+            vad_flags: [false; MAX_FRAMES_PER_PACKET],
+            lbrr_flag: false,
+            lbrr_flags: [false; MAX_FRAMES_PER_PACKET],
+            resampler: Resampler::default(),
+            nlsf_cb: NLSF_CB_NB_MB,
+            indices: SideInfoIndices::default(),
+            loss_cnt: 0,
+            prev_signal_type: 0,
+            plc: PlcState::default(),
+            cng: CngState::default(),
+        }
+    }
+}
+
+impl ChannelDecoder {
+    /// Sets the internal and the API sampling rate.
+    fn set_fs(&mut self, fs_khz: usize, fs_api_hz: usize) -> Result<(), OpusError> {
+        debug_assert!(fs_khz == 8 || fs_khz == 12 || fs_khz == 16);
+        debug_assert!(self.nb_subfr == MAX_NB_SUBFR || self.nb_subfr == MAX_NB_SUBFR / 2);
+
+        // New (sub)frame length.
+        self.subfr_length = SUB_FRAME_LENGTH_MS * fs_khz;
+        let frame_length = self.nb_subfr * self.subfr_length;
+
+        // Initialize resampler when switching internal or external sampling frequency.
+        if self.fs_khz != fs_khz || self.fs_api_hz != fs_api_hz {
+            // Initialize the resampler for preparing resampling from fs_khz to fs_api_hz.
+            self.resampler.init(fs_khz * 1000, fs_api_hz, false)?;
+            self.fs_api_hz = fs_api_hz;
+        }
+
+        if self.fs_khz != fs_khz || frame_length != self.frame_length {
+            if fs_khz == 8 {
+                if self.nb_subfr == MAX_NB_SUBFR {
+                    self.pitch_contour_icdf = PITCH_CONTOUR_NB_ICDF;
+                } else {
+                    self.pitch_contour_icdf = PITCH_CONTOUR_10_MS_NB_ICDF;
+                }
+            } else if self.nb_subfr == MAX_NB_SUBFR {
+                self.pitch_contour_icdf = PITCH_CONTOUR_ICDF;
+            } else {
+                self.pitch_contour_icdf = PITCH_CONTOUR_10_MS_ICDF;
+            }
+
+            if self.fs_khz != fs_khz {
+                self.ltp_mem_length = LTP_MEM_LENGTH_MS * fs_khz;
+                if fs_khz == 8 || fs_khz == 12 {
+                    self.lpc_order = MIN_LPC_ORDER;
+                    self.nlsf_cb = NLSF_CB_NB_MB;
+                } else {
+                    self.lpc_order = MAX_LPC_ORDER;
+                    self.nlsf_cb = NLSF_CB_WB;
+                }
+
+                self.pitch_lag_low_bits_icdf = match fs_khz {
+                    16 => UNIFORM8_ICDF,
+                    12 => UNIFORM6_ICDF,
+                    _ => UNIFORM4_ICDF,
+                };
+
+                self.first_frame_after_reset = true;
+                self.lag_prev = 100;
+                self.last_gain_index = 10;
+                self.prev_signal_type = TYPE_NO_VOICE_ACTIVITY;
+                self.out_buf = [0; MAX_FRAME_LENGTH + 2 * MAX_SUB_FRAME_LENGTH];
+                self.s_lpc_q14_buf = [0; MAX_LPC_ORDER];
+            }
+
+            self.fs_khz = fs_khz;
+            self.frame_length = frame_length;
+        }
+
+        debug_assert!(self.frame_length > 0 && self.frame_length <= MAX_FRAME_LENGTH);
+
+        Ok(())
+    }
+
+    /// Decodes a frame. Returns the number of decoded samples.
+    fn decode_frame(
+        &mut self,
+        dec: &mut Option<RangeDecoder>,
+        out: &mut [i16],
+        lost_flag: LostFlag,
+        cond_coding: CondCoding,
+    ) -> Result<usize, OpusError> {
+        let l = self.frame_length;
+        let mut ctrl = DecoderControl::default();
+
+        debug_assert!(l > 0 && l <= MAX_FRAME_LENGTH);
+
+        if lost_flag == LostFlag::NoLoss
+            || (lost_flag == LostFlag::DecodeFec && self.lbrr_flags[self.n_frames_decoded])
+        {
+            let dec = dec.as_mut().ok_or(OpusError::InternalError(
+                "silk decoder needs a range decoder",
+            ))?;
+
+            // The pulse buffer needs to be a multiple of the shell codec frame length.
+            let mut pulses = [0_i16; MAX_FRAME_LENGTH + SHELL_CODEC_FRAME_LENGTH];
+            let pulses_len = (l + SHELL_CODEC_FRAME_LENGTH - 1) & !(SHELL_CODEC_FRAME_LENGTH - 1);
+
+            // Decode quantization indices of side info.
+            let decode_lbrr = lost_flag == LostFlag::DecodeFec;
+            self.decode_indices(dec, self.n_frames_decoded, decode_lbrr, cond_coding);
+
+            // Decode quantization indices of excitation.
+            decode_pulses(
+                dec,
+                &mut pulses[..pulses_len],
+                self.indices.signal_type,
+                self.indices.quant_offset_type,
+                l,
+            );
+
+            // Decode parameters and pulse signal.
+            self.decode_parameters(&mut ctrl, cond_coding);
+
+            // Run inverse NSQ.
+            self.decode_core(&mut ctrl, &mut out[..l], &pulses[..l]);
+
+            // Update PLC state.
+            self.plc(&mut ctrl, &mut out[..l], false);
+
+            self.loss_cnt = 0;
+            self.prev_signal_type = self.indices.signal_type;
+
+            // A frame has been decoded without errors.
+            self.first_frame_after_reset = false;
+        } else {
+            // Handle packet loss by extrapolation.
+            self.plc(&mut ctrl, &mut out[..l], true);
+        }
+
+        // Update output buffer.
+        debug_assert!(self.ltp_mem_length >= self.frame_length);
+        let mv_len = self.ltp_mem_length - self.frame_length;
+        self.out_buf
+            .copy_within(self.frame_length..self.frame_length + mv_len, 0);
+        self.out_buf[mv_len..mv_len + self.frame_length].copy_from_slice(&out[..l]);
+
+        // Comfort noise generation / estimation.
+        self.cng(&ctrl, &mut out[..l]);
+
+        // Ensure smooth connection of extrapolated and good frames.
+        self.plc_glue_frames(&mut out[..l]);
+
+        // Update some decoder state variables.
+        self.lag_prev = ctrl.pitch_l[self.nb_subfr - 1];
+
+        Ok(l)
+    }
+
+    /// Decodes the side information parameters.
+    fn decode_indices(
+        &mut self,
+        dec: &mut RangeDecoder,
+        frame_index: usize,
+        decode_lbrr: bool,
+        cond_coding: CondCoding,
+    ) {
+        let mut ec_ix = [0_i16; MAX_LPC_ORDER];
+        let mut pred_q8 = [0_u8; MAX_LPC_ORDER];
+
+        // Decode signal type and quantizer offset.
+        let ix = if decode_lbrr || self.vad_flags[frame_index] {
+            dec.decode_icdf(TYPE_OFFSET_VAD_ICDF, 8) as usize + 2
+        } else {
+            dec.decode_icdf(TYPE_OFFSET_NO_VAD_ICDF, 8) as usize
+        };
+        self.indices.signal_type = ix >> 1;
+        self.indices.quant_offset_type = ix & 1;
+
+        // Decode gains.
+        // First subframe.
+        if cond_coding == CondCoding::Conditionally {
+            // Conditional coding.
+            self.indices.gains_indices[0] = dec.decode_icdf(DELTA_GAIN_ICDF, 8) as i8;
+        } else {
+            // Independent coding, in two stages: MSB bits followed by 3 LSBs.
+            self.indices.gains_indices[0] =
+                (dec.decode_icdf(&GAIN_ICDF[self.indices.signal_type], 8) << 3) as i8;
+            self.indices.gains_indices[0] += dec.decode_icdf(UNIFORM8_ICDF, 8) as i8;
+        }
+
+        // Remaining subframes.
+        (1..self.nb_subfr).for_each(|i| {
+            self.indices.gains_indices[i] = dec.decode_icdf(DELTA_GAIN_ICDF, 8) as i8;
+        });
+
+        // Decode LSF indices.
+        let cb = self.nlsf_cb;
+        self.indices.nlsf_indices[0] = dec.decode_icdf(
+            &cb.cb1_icdf[(self.indices.signal_type >> 1) * cb.vectors..],
+            8,
+        ) as i8;
+        nlsf_unpack(
+            &mut ec_ix,
+            &mut pred_q8,
+            cb,
+            self.indices.nlsf_indices[0] as usize,
+        );
+        debug_assert_eq!(cb.order, self.lpc_order);
+        (0..cb.order).for_each(|i| {
+            let mut ix = dec.decode_icdf(&cb.ec_icdf[ec_ix[i] as usize..], 8) as i32;
+            if ix == 0 {
+                ix -= dec.decode_icdf(NLSF_EXT_ICDF, 8) as i32;
+            } else if ix == 2 * NLSF_QUANT_MAX_AMPLITUDE as i32 {
+                ix += dec.decode_icdf(NLSF_EXT_ICDF, 8) as i32;
+            }
+            self.indices.nlsf_indices[i + 1] = (ix - NLSF_QUANT_MAX_AMPLITUDE as i32) as i8;
+        });
+
+        // Decode LSF interpolation factor.
+        if self.nb_subfr == MAX_NB_SUBFR {
+            self.indices.nlsf_interp_coef_q2 =
+                dec.decode_icdf(NLSF_INTERPOLATION_FACTOR_ICDF, 8) as i8;
+        } else {
+            self.indices.nlsf_interp_coef_q2 = 4;
+        }
+
+        if self.indices.signal_type == TYPE_VOICED {
+            // Decode pitch lags.
+            // Get lag index.
+            let mut decode_absolute_lag_index = true;
+            if cond_coding == CondCoding::Conditionally && self.ec_prev_signal_type == TYPE_VOICED {
+                // Decode delta index.
+                let delta_lag_index = dec.decode_icdf(PITCH_DELTA_ICDF, 8) as i16;
+                if delta_lag_index > 0 {
+                    self.indices.lag_index = self.ec_prev_lag_index + delta_lag_index - 9;
+                    decode_absolute_lag_index = false;
+                }
+            }
+            if decode_absolute_lag_index {
+                // Absolute decoding.
+                self.indices.lag_index =
+                    (dec.decode_icdf(PITCH_LAG_ICDF, 8) as usize * (self.fs_khz >> 1)) as i16;
+                self.indices.lag_index += dec.decode_icdf(self.pitch_lag_low_bits_icdf, 8) as i16;
+            }
+            self.ec_prev_lag_index = self.indices.lag_index;
+
+            // Get contour index.
+            self.indices.contour_index = dec.decode_icdf(self.pitch_contour_icdf, 8) as i8;
+
+            // Decode LTP gains.
+            // Decode PERIndex value.
+            self.indices.per_index = dec.decode_icdf(LTP_PER_INDEX_ICDF, 8) as i8;
+
+            let ltp_gain_icdf: &[u8] = match self.indices.per_index {
+                0 => LTP_GAIN_ICDF_0,
+                1 => LTP_GAIN_ICDF_1,
+                _ => LTP_GAIN_ICDF_2,
+            };
+            (0..self.nb_subfr).for_each(|k| {
+                self.indices.ltp_index[k] = dec.decode_icdf(ltp_gain_icdf, 8) as i8;
+            });
+
+            // Decode LTP scaling.
+            if cond_coding == CondCoding::Independently {
+                self.indices.ltp_scale_index = dec.decode_icdf(LTP_SCALE_ICDF, 8) as i8;
+            } else {
+                self.indices.ltp_scale_index = 0;
+            }
+        }
+        self.ec_prev_signal_type = self.indices.signal_type;
+
+        // Decode seed.
+        self.indices.seed = dec.decode_icdf(UNIFORM4_ICDF, 8) as i8;
+    }
+
+    /// Decodes the parameters from the indices.
+    fn decode_parameters(&mut self, ctrl: &mut DecoderControl, cond_coding: CondCoding) {
+        let mut nlsf_q15 = [0_i16; MAX_LPC_ORDER];
+        let mut nlsf0_q15 = [0_i16; MAX_LPC_ORDER];
+        let order = self.lpc_order;
+
+        // Dequant gains.
+        gains_dequant(
+            &mut ctrl.gains_q16,
+            &self.indices.gains_indices,
+            &mut self.last_gain_index,
+            cond_coding == CondCoding::Conditionally,
+            self.nb_subfr,
+        );
+
+        // Decode NLSFs.
+        nlsf_decode(&mut nlsf_q15, &self.indices.nlsf_indices, self.nlsf_cb);
+
+        // Convert NLSF parameters to AR prediction filter coefficients.
+        nlsf2a(&mut ctrl.pred_coef_q12[1], &nlsf_q15[..order]);
+
+        // If just reset, e.g., because internal Fs changed, do not allow interpolation.
+        // Improves the case of packet loss in the first frame after a switch.
+        if self.first_frame_after_reset {
+            self.indices.nlsf_interp_coef_q2 = 4;
+        }
+
+        if self.indices.nlsf_interp_coef_q2 < 4 {
+            // Calculation of the interpolated NLSF0 vector from the interpolation factor,
+            // the previous NLSF1, and the current NLSF1.
+            (0..order).for_each(|i| {
+                nlsf0_q15[i] = (i32::from(self.prev_nlsf_q15[i])
+                    + ((i32::from(self.indices.nlsf_interp_coef_q2)
+                        * (i32::from(nlsf_q15[i]) - i32::from(self.prev_nlsf_q15[i])))
+                        >> 2)) as i16;
+            });
+
+            // Convert NLSF parameters to AR prediction filter coefficients.
+            nlsf2a(&mut ctrl.pred_coef_q12[0], &nlsf0_q15[..order]);
+        } else {
+            // Copy LPC coefficients for first half from second half.
+            ctrl.pred_coef_q12[0] = ctrl.pred_coef_q12[1];
+        }
+
+        self.prev_nlsf_q15[..order].copy_from_slice(&nlsf_q15[..order]);
+
+        // After a packet loss do BWE of LPC coefs.
+        if self.loss_cnt > 0 {
+            bwexpander(&mut ctrl.pred_coef_q12[0][..order], BWE_AFTER_LOSS_Q16);
+            bwexpander(&mut ctrl.pred_coef_q12[1][..order], BWE_AFTER_LOSS_Q16);
+        }
+
+        if self.indices.signal_type == TYPE_VOICED {
+            // Decode pitch values.
+            decode_pitch(
+                self.indices.lag_index,
+                self.indices.contour_index as usize,
+                &mut ctrl.pitch_l,
+                self.fs_khz,
+                self.nb_subfr,
+            );
+
+            // Decode codebook index.
+            (0..self.nb_subfr).for_each(|k| {
+                let ix = self.indices.ltp_index[k] as usize;
+                let cbk_q7 = match self.indices.per_index {
+                    0 => &LTP_GAIN_VQ_0[ix],
+                    1 => &LTP_GAIN_VQ_1[ix],
+                    _ => &LTP_GAIN_VQ_2[ix],
+                };
+                (0..LTP_ORDER).for_each(|i| {
+                    ctrl.ltp_coef_q14[k * LTP_ORDER + i] = i16::from(cbk_q7[i]) << 7;
+                });
+            });
+
+            // Decode LTP scaling.
+            let ix = self.indices.ltp_scale_index as usize;
+            ctrl.ltp_scale_q14 = i32::from(LTP_SCALES_TABLE_Q14[ix]);
+        } else {
+            ctrl.pitch_l = [0; MAX_NB_SUBFR];
+            ctrl.ltp_coef_q14 = [0; LTP_ORDER * MAX_NB_SUBFR];
+            self.indices.per_index = 0;
+            ctrl.ltp_scale_q14 = 0;
+        }
+    }
+
+    /// Core decoder. Performs inverse NSQ operation LTP + LPC.
+    fn decode_core(&mut self, ctrl: &mut DecoderControl, xq: &mut [i16], pulses: &[i16]) {
+        let mut lag = 0;
+        let mut s_ltp = [0_i16; 2 * MAX_FRAME_LENGTH];
+        let mut s_ltp_q15 = [0_i32; 2 * MAX_FRAME_LENGTH + MAX_FRAME_LENGTH];
+        let mut res_q14 = [0_i32; MAX_SUB_FRAME_LENGTH];
+        let mut s_lpc_q14 = [0_i32; MAX_SUB_FRAME_LENGTH + MAX_LPC_ORDER];
+
+        debug_assert!(self.prev_gain_q16 != 0);
+
+        let offset_q10 = i32::from(
+            QUANTIZATION_OFFSETS_Q10[self.indices.signal_type >> 1][self.indices.quant_offset_type],
+        );
+
+        let nlsf_interpolation_flag = self.indices.nlsf_interp_coef_q2 < 1 << 2;
+
+        // Decode excitation.
+        let mut rand_seed = i32::from(self.indices.seed);
+        self.exc_q14
+            .iter_mut()
+            .zip(pulses.iter())
+            .take(self.frame_length)
+            .for_each(|(exc, &pulse)| {
+                rand_seed = rand(rand_seed);
+                *exc = i32::from(pulse) << 14;
+                if *exc > 0 {
+                    *exc -= QUANT_LEVEL_ADJUST_Q10 << 4;
+                } else if *exc < 0 {
+                    *exc += QUANT_LEVEL_ADJUST_Q10 << 4;
+                }
+                *exc += offset_q10 << 4;
+                if rand_seed < 0 {
+                    *exc = -*exc;
+                }
+                rand_seed = rand_seed.wrapping_add(i32::from(pulse));
+            });
+
+        // Copy LPC state.
+        s_lpc_q14[..MAX_LPC_ORDER].copy_from_slice(&self.s_lpc_q14_buf);
+
+        let subfr_length = self.subfr_length;
+        let order = self.lpc_order;
+        let mut s_ltp_buf_idx = self.ltp_mem_length;
+
+        // Loop over subframes.
+        (0..self.nb_subfr).for_each(|k| {
+            let exc_q14 = &self.exc_q14[k * subfr_length..(k + 1) * subfr_length];
+            let a_q12 = ctrl.pred_coef_q12[k >> 1];
+            let b_q14 = &mut ctrl.ltp_coef_q14[k * LTP_ORDER..(k + 1) * LTP_ORDER];
+            let mut signal_type = self.indices.signal_type;
+
+            let gain_q10 = ctrl.gains_q16[k] >> 6;
+            let mut inv_gain_q31 = inverse32_varq(ctrl.gains_q16[k], 47);
+
+            // Calculate gain adjustment factor.
+            let gain_adj_q16 = if ctrl.gains_q16[k] != self.prev_gain_q16 {
+                let gain_adj_q16 = div32_varq(self.prev_gain_q16, ctrl.gains_q16[k], 16);
+
+                // Scale short term state.
+                s_lpc_q14
+                    .iter_mut()
+                    .take(MAX_LPC_ORDER)
+                    .for_each(|x| *x = smulww(gain_adj_q16, *x));
+
+                gain_adj_q16
+            } else {
+                1 << 16
+            };
+
+            // Save inv_gain.
+            debug_assert!(inv_gain_q31 != 0);
+            self.prev_gain_q16 = ctrl.gains_q16[k];
+
+            // Avoid abrupt transition from voiced PLC to unvoiced normal decoding.
+            if self.loss_cnt > 0
+                && self.prev_signal_type == TYPE_VOICED
+                && self.indices.signal_type != TYPE_VOICED
+                && k < MAX_NB_SUBFR / 2
+            {
+                b_q14.iter_mut().for_each(|x| *x = 0);
+                // 0.25 in Q14
+                b_q14[LTP_ORDER / 2] = 4096;
+
+                signal_type = TYPE_VOICED;
+                ctrl.pitch_l[k] = self.lag_prev;
+            }
+
+            if signal_type == TYPE_VOICED {
+                // Voiced.
+                lag = ctrl.pitch_l[k] as usize;
+
+                // Re-whitening.
+                if k == 0 || (k == 2 && nlsf_interpolation_flag) {
+                    // Rewhiten with new A coefs.
+                    let start_idx = self.ltp_mem_length - lag - order - LTP_ORDER / 2;
+                    debug_assert!(start_idx > 0);
+
+                    if k == 2 {
+                        self.out_buf[self.ltp_mem_length..self.ltp_mem_length + 2 * subfr_length]
+                            .copy_from_slice(&xq[..2 * subfr_length]);
+                    }
+
+                    lpc_analysis_filter(
+                        &mut s_ltp[start_idx..],
+                        &self.out_buf[start_idx + k * subfr_length..],
+                        &a_q12,
+                        self.ltp_mem_length - start_idx,
+                        order,
+                    );
+
+                    // After rewhitening the LTP state is unscaled.
+                    if k == 0 {
+                        // Do LTP downscaling to reduce inter-packet dependency.
+                        inv_gain_q31 = smulwb(inv_gain_q31, ctrl.ltp_scale_q14) << 2;
+                    }
+                    (0..lag + LTP_ORDER / 2).for_each(|i| {
+                        s_ltp_q15[s_ltp_buf_idx - i - 1] =
+                            smulwb(inv_gain_q31, i32::from(s_ltp[self.ltp_mem_length - i - 1]));
+                    });
+                } else {
+                    // Update LTP state when gain changes.
+                    if gain_adj_q16 != 1 << 16 {
+                        (0..lag + LTP_ORDER / 2).for_each(|i| {
+                            let x = &mut s_ltp_q15[s_ltp_buf_idx - i - 1];
+                            *x = smulww(gain_adj_q16, *x);
+                        });
+                    }
+                }
+            }
+
+            // Long-term prediction.
+            let res_q14: &[i32] = if signal_type == TYPE_VOICED {
+                let b_q14 = &ctrl.ltp_coef_q14[k * LTP_ORDER..(k + 1) * LTP_ORDER];
+                (0..subfr_length).for_each(|i| {
+                    let pred_lag_idx = s_ltp_buf_idx - lag + LTP_ORDER / 2;
+
+                    // Avoids introducing a bias because smlawb() always rounds to -inf.
+                    let mut ltp_pred_q13 = 2;
+                    (0..LTP_ORDER).for_each(|j| {
+                        ltp_pred_q13 = smlawb(
+                            ltp_pred_q13,
+                            s_ltp_q15[pred_lag_idx - j],
+                            i32::from(b_q14[j]),
+                        );
+                    });
+
+                    // Generate LPC excitation.
+                    res_q14[i] = exc_q14[i].wrapping_add(ltp_pred_q13 << 1);
+
+                    // Update states.
+                    s_ltp_q15[s_ltp_buf_idx] = res_q14[i] << 1;
+                    s_ltp_buf_idx += 1;
+                });
+                &res_q14
+            } else {
+                exc_q14
+            };
+
+            (0..subfr_length).for_each(|i| {
+                // Short-term prediction.
+                debug_assert!(order == 10 || order == 16);
+
+                // Avoids introducing a bias because smlawb() always rounds to -inf.
+                let mut lpc_pred_q10 = (order >> 1) as i32;
+                (0..order).for_each(|j| {
+                    lpc_pred_q10 = smlawb(
+                        lpc_pred_q10,
+                        s_lpc_q14[MAX_LPC_ORDER + i - j - 1],
+                        i32::from(a_q12[j]),
+                    );
+                });
+
+                // Add prediction to LPC excitation.
+                s_lpc_q14[MAX_LPC_ORDER + i] = add_sat32(res_q14[i], lshift_sat32(lpc_pred_q10, 4));
+
+                // Scale with gain.
+                xq[k * subfr_length + i] = sat16(rshift_round(
+                    smulww(s_lpc_q14[MAX_LPC_ORDER + i], gain_q10),
+                    8,
+                ));
+            });
+
+            // Update LPC filter state.
+            s_lpc_q14.copy_within(subfr_length..subfr_length + MAX_LPC_ORDER, 0);
+        });
+
+        // Save LPC state.
+        self.s_lpc_q14_buf
+            .copy_from_slice(&s_lpc_q14[..MAX_LPC_ORDER]);
+    }
+
+    /// Resets the packet loss concealment state.
+    fn plc_reset(&mut self) {
+        self.plc.pitch_l_q8 = (self.frame_length << (8 - 1)) as i32;
+        self.plc.prev_gain_q16 = [1 << 16; 2];
+        self.plc.subfr_length = 20;
+        self.plc.nb_subfr = 2;
+    }
+
+    /// Packet loss concealment. Either conceals a lost frame or updates the
+    /// concealment state with a correctly decoded frame.
+    fn plc(&mut self, ctrl: &mut DecoderControl, frame: &mut [i16], lost: bool) {
+        if self.fs_khz != self.plc.fs_khz {
+            self.plc_reset();
+            self.plc.fs_khz = self.fs_khz;
+        }
+
+        if lost {
+            // Generate signal.
+            self.plc_conceal(ctrl, frame);
+            self.loss_cnt += 1;
+        } else {
+            // Update state.
+            self.plc_update(ctrl);
+        }
+    }
+
+    /// Updates the state of the PLC with the parameters of a decoded frame.
+    fn plc_update(&mut self, ctrl: &DecoderControl) {
+        let nb_subfr = self.nb_subfr;
+        let plc = &mut self.plc;
+
+        // Update parameters used in case of packet loss.
+        self.prev_signal_type = self.indices.signal_type;
+        let mut ltp_gain_q14 = 0;
+        if self.indices.signal_type == TYPE_VOICED {
+            // Find the parameters for the last subframe which contains a pitch pulse.
+            let mut j = 0;
+            while j * self.subfr_length < ctrl.pitch_l[nb_subfr - 1] as usize {
+                if j == nb_subfr {
+                    break;
+                }
+                let offset = (nb_subfr - 1 - j) * LTP_ORDER;
+                let temp_ltp_gain_q14 = ctrl.ltp_coef_q14[offset..offset + LTP_ORDER]
+                    .iter()
+                    .fold(0, |acc, &x| acc + i32::from(x));
+                if temp_ltp_gain_q14 > ltp_gain_q14 {
+                    ltp_gain_q14 = temp_ltp_gain_q14;
+                    plc.ltp_coef_q14
+                        .copy_from_slice(&ctrl.ltp_coef_q14[offset..offset + LTP_ORDER]);
+                    plc.pitch_l_q8 = ctrl.pitch_l[nb_subfr - 1 - j] << 8;
+                }
+                j += 1;
+            }
+
+            plc.ltp_coef_q14 = [0; LTP_ORDER];
+            plc.ltp_coef_q14[LTP_ORDER / 2] = ltp_gain_q14 as i16;
+
+            // Limit LT coefs.
+            if ltp_gain_q14 < V_PITCH_GAIN_START_MIN_Q14 {
+                let tmp = V_PITCH_GAIN_START_MIN_Q14 << 10;
+                let scale_q10 = tmp / i32::max(ltp_gain_q14, 1);
+                plc.ltp_coef_q14.iter_mut().for_each(|x| {
+                    *x = (smulbb(i32::from(*x), scale_q10) >> 10) as i16;
+                });
+            } else if ltp_gain_q14 > V_PITCH_GAIN_START_MAX_Q14 {
+                let tmp = V_PITCH_GAIN_START_MAX_Q14 << 14;
+                let scale_q14 = tmp / i32::max(ltp_gain_q14, 1);
+                plc.ltp_coef_q14.iter_mut().for_each(|x| {
+                    *x = (smulbb(i32::from(*x), scale_q14) >> 14) as i16;
+                });
+            }
+        } else {
+            plc.pitch_l_q8 = smulbb(self.fs_khz as i32, 18) << 8;
+            plc.ltp_coef_q14 = [0; LTP_ORDER];
+        }
+
+        // Save LPC coefficients.
+        let order = self.lpc_order;
+        plc.prev_lpc_q12[..order].copy_from_slice(&ctrl.pred_coef_q12[1][..order]);
+        plc.prev_ltp_scale_q14 = ctrl.ltp_scale_q14;
+
+        // Save last two gains.
+        plc.prev_gain_q16
+            .copy_from_slice(&ctrl.gains_q16[nb_subfr - 2..nb_subfr]);
+
+        plc.subfr_length = self.subfr_length;
+        plc.nb_subfr = nb_subfr;
+    }
+
+    /// Conceals a lost frame by extrapolating the LTP and LPC state of the last frame.
+    fn plc_conceal(&mut self, ctrl: &mut DecoderControl, frame: &mut [i16]) {
+        let ltp_mem_length = self.ltp_mem_length;
+        let frame_length = self.frame_length;
+        let order = self.lpc_order;
+
+        let mut s_ltp_q14 = [0_i32; 2 * MAX_FRAME_LENGTH];
+        let mut s_ltp = [0_i16; MAX_FRAME_LENGTH];
+
+        let prev_gain_q10 = [
+            self.plc.prev_gain_q16[0] >> 6,
+            self.plc.prev_gain_q16[1] >> 6,
+        ];
+
+        if self.first_frame_after_reset {
+            self.plc.prev_lpc_q12 = [0; MAX_LPC_ORDER];
+        }
+
+        let (energy1, shift1, energy2, shift2) = plc_energy(
+            &self.exc_q14,
+            &prev_gain_q10,
+            self.subfr_length,
+            self.nb_subfr,
+        );
+
+        let rand_offset = if (energy1 >> shift2) < (energy2 >> shift1) {
+            // First sub-frame has lowest energy.
+            ((self.plc.nb_subfr - 1) * self.plc.subfr_length).saturating_sub(RAND_BUF_SIZE)
+        } else {
+            // Second sub-frame has lowest energy.
+            (self.plc.nb_subfr * self.plc.subfr_length).saturating_sub(RAND_BUF_SIZE)
+        };
+
+        // Set up gain to random noise component.
+        let mut rand_scale_q14 = self.plc.rand_scale_q14;
+
+        // Set up attenuation gains.
+        let att_idx = usize::min(HARM_ATT_Q15.len() - 1, self.loss_cnt);
+        let harm_gain_q15 = HARM_ATT_Q15[att_idx];
+        let mut rand_gain_q15 = if self.prev_signal_type == TYPE_VOICED {
+            PLC_RAND_ATTENUATE_V_Q15[att_idx]
+        } else {
+            PLC_RAND_ATTENUATE_UV_Q15[att_idx]
+        };
+
+        // LPC concealment. Apply BWE to previous LPC.
+        bwexpander(&mut self.plc.prev_lpc_q12[..order], PLC_BWE_COEF_Q16);
+
+        let mut a_q12 = [0_i16; MAX_LPC_ORDER];
+        a_q12[..order].copy_from_slice(&self.plc.prev_lpc_q12[..order]);
+
+        // First lost frame.
+        if self.loss_cnt == 0 {
+            rand_scale_q14 = 1 << 14;
+
+            if self.prev_signal_type == TYPE_VOICED {
+                // Reduce random noise gain for voiced frames.
+                self.plc
+                    .ltp_coef_q14
+                    .iter()
+                    .for_each(|&b| rand_scale_q14 = rand_scale_q14.wrapping_sub(b));
+                rand_scale_q14 = i16::max(3277, rand_scale_q14);
+                rand_scale_q14 =
+                    (smulbb(i32::from(rand_scale_q14), self.plc.prev_ltp_scale_q14) >> 14) as i16;
+            } else {
+                // Reduce random noise for unvoiced frames with high LPC gain.
+                let inv_gain_q30 = lpc_inverse_pred_gain(&self.plc.prev_lpc_q12[..order]);
+
+                let mut down_scale_q30 =
+                    i32::min((1 << 30) >> LOG2_INV_LPC_GAIN_HIGH_THRES, inv_gain_q30);
+                down_scale_q30 = i32::max((1 << 30) >> LOG2_INV_LPC_GAIN_LOW_THRES, down_scale_q30);
+                down_scale_q30 <<= LOG2_INV_LPC_GAIN_HIGH_THRES;
+
+                rand_gain_q15 = smulwb(down_scale_q30, rand_gain_q15) >> 14;
+            }
+        }
+
+        let mut rand_seed = self.plc.rand_seed;
+        let mut lag = rshift_round(self.plc.pitch_l_q8, 8) as usize;
+        let mut s_ltp_buf_idx = ltp_mem_length;
+
+        // Rewhiten LTP state.
+        let idx = ltp_mem_length - lag - order - LTP_ORDER / 2;
+        debug_assert!(idx > 0);
+        lpc_analysis_filter(
+            &mut s_ltp[idx..],
+            &self.out_buf[idx..],
+            &a_q12[..order],
+            ltp_mem_length - idx,
+            order,
+        );
+
+        // Scale LTP state.
+        let inv_gain_q30 = i32::min(inverse32_varq(self.plc.prev_gain_q16[1], 46), i32::MAX >> 1);
+        (idx + order..ltp_mem_length).for_each(|i| {
+            s_ltp_q14[i] = smulwb(inv_gain_q30, i32::from(s_ltp[i]));
+        });
+
+        // LTP synthesis filtering.
+        let rand_buf = &self.exc_q14[rand_offset..];
+        let plc = &mut self.plc;
+        let max_pitch_l_q8 = smulbb(MAX_PITCH_LAG_MS, self.fs_khz as i32) << 8;
+        let subfr_length = self.subfr_length;
+        (0..self.nb_subfr).for_each(|_| {
+            let b_q14 = &mut plc.ltp_coef_q14;
+
+            (0..subfr_length).for_each(|_| {
+                let pred_lag_idx = s_ltp_buf_idx - lag + LTP_ORDER / 2;
+
+                // Avoids introducing a bias because smlawb() always rounds to -inf.
+                let mut ltp_pred_q12 = 2;
+                (0..LTP_ORDER).for_each(|j| {
+                    ltp_pred_q12 = smlawb(
+                        ltp_pred_q12,
+                        s_ltp_q14[pred_lag_idx - j],
+                        i32::from(b_q14[j]),
+                    );
+                });
+
+                // Generate LPC excitation.
+                rand_seed = rand(rand_seed);
+                let idx = (rand_seed >> 25) as usize & RAND_BUF_MASK;
+                s_ltp_q14[s_ltp_buf_idx] =
+                    smlawb(ltp_pred_q12, rand_buf[idx], i32::from(rand_scale_q14)) << 2;
+                s_ltp_buf_idx += 1;
+            });
+
+            // Gradually reduce LTP gain.
+            b_q14
+                .iter_mut()
+                .for_each(|b| *b = (smulbb(harm_gain_q15, i32::from(*b)) >> 15) as i16);
+
+            // Gradually reduce excitation gain.
+            rand_scale_q14 = (smulbb(i32::from(rand_scale_q14), rand_gain_q15) >> 15) as i16;
+
+            // Slowly increase pitch lag.
+            plc.pitch_l_q8 = smlawb(plc.pitch_l_q8, plc.pitch_l_q8, PITCH_DRIFT_FAC_Q16);
+            plc.pitch_l_q8 = i32::min(plc.pitch_l_q8, max_pitch_l_q8);
+            lag = rshift_round(plc.pitch_l_q8, 8) as usize;
+        });
+
+        // LPC synthesis filtering.
+        let s_lpc_q14 = &mut s_ltp_q14[ltp_mem_length - MAX_LPC_ORDER..];
+
+        // Copy LPC state.
+        s_lpc_q14[..MAX_LPC_ORDER].copy_from_slice(&self.s_lpc_q14_buf);
+
+        debug_assert!(order >= 10);
+        (0..frame_length).for_each(|i| {
+            // Avoids introducing a bias because smlawb() always rounds to -inf.
+            let mut lpc_pred_q10 = (order >> 1) as i32;
+            (0..order).for_each(|j| {
+                lpc_pred_q10 = smlawb(
+                    lpc_pred_q10,
+                    s_lpc_q14[MAX_LPC_ORDER + i - j - 1],
+                    i32::from(a_q12[j]),
+                );
+            });
+
+            // Add prediction to LPC excitation.
+            s_lpc_q14[MAX_LPC_ORDER + i] =
+                add_sat32(s_lpc_q14[MAX_LPC_ORDER + i], lshift_sat32(lpc_pred_q10, 4));
+
+            // Scale with gain.
+            frame[i] = sat16(rshift_round(
+                smulww(s_lpc_q14[MAX_LPC_ORDER + i], prev_gain_q10[1]),
+                8,
+            ));
+        });
+
+        // Save LPC state.
+        self.s_lpc_q14_buf
+            .copy_from_slice(&s_lpc_q14[frame_length..frame_length + MAX_LPC_ORDER]);
+
+        // Update states.
+        self.plc.rand_seed = rand_seed;
+        self.plc.rand_scale_q14 = rand_scale_q14;
+        ctrl.pitch_l = [lag as i32; MAX_NB_SUBFR];
+    }
+
+    /// Glues concealed frames with new good received frames.
+    fn plc_glue_frames(&mut self, frame: &mut [i16]) {
+        let plc = &mut self.plc;
+
+        if self.loss_cnt > 0 {
+            // Calculate energy in concealed residual.
+            let (energy, shift) = sum_sqr_shift(frame);
+            plc.conc_energy = energy;
+            plc.conc_energy_shift = shift;
+            plc.last_frame_lost = true;
+        } else {
+            if plc.last_frame_lost {
+                // Calculate residual in decoded signal if last frame was lost.
+                let (mut energy, energy_shift) = sum_sqr_shift(frame);
+
+                // Normalize energies.
+                if energy_shift > plc.conc_energy_shift {
+                    plc.conc_energy >>= energy_shift - plc.conc_energy_shift;
+                } else if energy_shift < plc.conc_energy_shift {
+                    energy >>= plc.conc_energy_shift - energy_shift;
+                }
+
+                // Fade in the energy difference.
+                if energy > plc.conc_energy {
+                    let lz = clz32(plc.conc_energy) - 1;
+                    plc.conc_energy <<= lz;
+                    energy >>= i32::max(24 - lz, 0);
+
+                    let frac_q24 = plc.conc_energy / i32::max(energy, 1);
+
+                    let mut gain_q16 = sqrt_approx(frac_q24) << 4;
+                    let mut slope_q16 = ((1 << 16) - gain_q16) / frame.len() as i32;
+                    // Make slope 4x steeper to avoid missing onsets after DTX.
+                    slope_q16 <<= 2;
+
+                    for x in frame.iter_mut() {
+                        *x = smulwb(gain_q16, i32::from(*x)) as i16;
+                        gain_q16 += slope_q16;
+                        if gain_q16 > 1 << 16 {
+                            break;
+                        }
+                    }
+                }
+            }
+            plc.last_frame_lost = false;
+        }
+    }
+
+    /// Resets the comfort noise generation state.
+    fn cng_reset(&mut self) {
+        let nlsf_step_q15 = i16::MAX / (self.lpc_order + 1) as i16;
+        let mut nlsf_acc_q15 = 0;
+        self.cng
+            .smth_nlsf_q15
+            .iter_mut()
+            .take(self.lpc_order)
+            .for_each(|x| {
+                nlsf_acc_q15 += nlsf_step_q15;
+                *x = nlsf_acc_q15;
+            });
+        self.cng.smth_gain_q16 = 0;
+        self.cng.rand_seed = 3_176_576;
+    }
+
+    /// Updates the comfort noise estimate and adds comfort noise to the
+    /// `frame` when the packet was lost or during DTX.
+    fn cng(&mut self, ctrl: &DecoderControl, frame: &mut [i16]) {
+        if self.fs_khz != self.cng.fs_khz {
+            self.cng_reset();
+            self.cng.fs_khz = self.fs_khz;
+        }
+
+        let lpc_order = self.lpc_order;
+        let cng = &mut self.cng;
+
+        if self.loss_cnt == 0 && self.prev_signal_type == TYPE_NO_VOICE_ACTIVITY {
+            // Update the parameters. Smoothing of the LSFs.
+            cng.smth_nlsf_q15
+                .iter_mut()
+                .zip(self.prev_nlsf_q15.iter())
+                .take(lpc_order)
+                .for_each(|(x, &prev)| {
+                    *x += smulwb(i32::from(prev) - i32::from(*x), CNG_NLSF_SMTH_Q16) as i16;
+                });
+
+            // Find the subframe with the highest gain.
+            let mut max_gain_q16 = 0;
+            let mut subfr = 0;
+            (0..self.nb_subfr).for_each(|i| {
+                if ctrl.gains_q16[i] > max_gain_q16 {
+                    max_gain_q16 = ctrl.gains_q16[i];
+                    subfr = i;
+                }
+            });
+
+            // Update the excitation buffer with the excitation of this subframe.
+            let subfr_length = self.subfr_length;
+            cng.exc_buf_q14
+                .copy_within(..(self.nb_subfr - 1) * subfr_length, subfr_length);
+            cng.exc_buf_q14[..subfr_length]
+                .copy_from_slice(&self.exc_q14[subfr * subfr_length..(subfr + 1) * subfr_length]);
+
+            // Smooth the gains.
+            ctrl.gains_q16
+                .iter()
+                .take(self.nb_subfr)
+                .for_each(|&gain_q16| {
+                    cng.smth_gain_q16 += smulwb(gain_q16 - cng.smth_gain_q16, CNG_GAIN_SMTH_Q16);
+                    // If the smoothed gain is 3 dB greater than this subframe's gain,
+                    // use this subframe's gain to adapt faster.
+                    if smulww(cng.smth_gain_q16, CNG_GAIN_SMTH_THRESHOLD_Q16) > gain_q16 {
+                        cng.smth_gain_q16 = gain_q16;
+                    }
+                });
+        }
+
+        // Add the comfort noise when the packet is lost or during DTX.
+        if self.loss_cnt > 0 {
+            let length = frame.len();
+            let mut cng_sig_q14 = [0_i32; MAX_FRAME_LENGTH + MAX_LPC_ORDER];
+
+            // Generate the excitation.
+            let mut gain_q16 = smulww(
+                i32::from(self.plc.rand_scale_q14),
+                self.plc.prev_gain_q16[1],
+            );
+            if gain_q16 >= 1 << 21 || cng.smth_gain_q16 > 1 << 23 {
+                gain_q16 = smultt(gain_q16, gain_q16);
+                gain_q16 = smultt(cng.smth_gain_q16, cng.smth_gain_q16).wrapping_sub(gain_q16 << 5);
+                gain_q16 = sqrt_approx(gain_q16) << 16;
+            } else {
+                gain_q16 = smulww(gain_q16, gain_q16);
+                gain_q16 = smulww(cng.smth_gain_q16, cng.smth_gain_q16).wrapping_sub(gain_q16 << 5);
+                gain_q16 = sqrt_approx(gain_q16) << 8;
+            }
+            let gain_q10 = gain_q16 >> 6;
+
+            cng_exc(
+                &mut cng_sig_q14[MAX_LPC_ORDER..MAX_LPC_ORDER + length],
+                &cng.exc_buf_q14,
+                &mut cng.rand_seed,
+            );
+
+            // Convert the NLSFs to the filter representation.
+            let mut a_q12 = [0_i16; MAX_LPC_ORDER];
+            nlsf2a(&mut a_q12[..lpc_order], &cng.smth_nlsf_q15[..lpc_order]);
+
+            // Generate the signal by synthesis filtering.
+            cng_sig_q14[..MAX_LPC_ORDER].copy_from_slice(&cng.synth_state);
+            debug_assert!(lpc_order == 10 || lpc_order == 16);
+            frame.iter_mut().enumerate().for_each(|(i, x)| {
+                // Avoids introducing a bias because smlawb() always rounds to -inf.
+                let lpc_pred_q10 = a_q12.iter().take(lpc_order).enumerate().fold(
+                    (lpc_order >> 1) as i32,
+                    |acc, (j, &a)| {
+                        smlawb(acc, cng_sig_q14[MAX_LPC_ORDER + i - j - 1], i32::from(a))
+                    },
+                );
+
+                // Update the states.
+                cng_sig_q14[MAX_LPC_ORDER + i] = add_sat32(
+                    cng_sig_q14[MAX_LPC_ORDER + i],
+                    lshift_sat32(lpc_pred_q10, 4),
+                );
+
+                // Scale with the gain and add to the input signal.
+                *x = add_sat16(
+                    *x,
+                    sat16(rshift_round(
+                        smulww(cng_sig_q14[MAX_LPC_ORDER + i], gain_q10),
+                        8,
+                    )),
+                );
+            });
+            cng.synth_state
+                .copy_from_slice(&cng_sig_q14[length..length + MAX_LPC_ORDER]);
+        } else {
+            cng.synth_state[..lpc_order].iter_mut().for_each(|x| *x = 0);
+        }
+    }
+}
+
+/// Generates the excitation of the comfort noise by randomly
+/// picking samples from the excitation buffer `exc_buf_q14`.
+fn cng_exc(exc_q14: &mut [i32], exc_buf_q14: &[i32], rand_seed: &mut i32) {
+    let mut exc_mask = CNG_BUF_MASK_MAX;
+    while exc_mask > exc_q14.len() {
+        exc_mask >>= 1;
+    }
+
+    let mut seed = *rand_seed;
+    exc_q14.iter_mut().for_each(|x| {
+        seed = rand(seed);
+        let idx = ((seed >> 24) & exc_mask as i32) as usize;
+        *x = exc_buf_q14[idx];
+    });
+    *rand_seed = seed;
+}
+
+/// Computes the energies of the last two subframes of the excitation, scaled with
+/// their gains. Returns `(energy1, shift1, energy2, shift2)`.
+fn plc_energy(
+    exc_q14: &[i32],
+    prev_gain_q10: &[i32; 2],
+    subfr_length: usize,
+    nb_subfr: usize,
+) -> (i32, i32, i32, i32) {
+    let mut exc_buf = [0_i16; 2 * MAX_SUB_FRAME_LENGTH];
+
+    // Find random noise component.
+    // Scale previous excitation signal.
+    (0..2).for_each(|k| {
+        (0..subfr_length).for_each(|i| {
+            exc_buf[k * subfr_length + i] = sat16(
+                smulww(
+                    exc_q14[i + (k + nb_subfr - 2) * subfr_length],
+                    prev_gain_q10[k],
+                ) >> 8,
+            );
+        });
+    });
+
+    // Find the subframe with lowest energy of the last two and use that as random noise generator.
+    let (energy1, shift1) = sum_sqr_shift(&exc_buf[..subfr_length]);
+    let (energy2, shift2) = sum_sqr_shift(&exc_buf[subfr_length..2 * subfr_length]);
+
+    (energy1, shift1, energy2, shift2)
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+    use crate::{Bandwidth, Channels, Decoder, DecoderConfiguration, SamplingRate};
+    use std::num::NonZeroUsize;
+
+    // SILK-only packets and their final ranges, as produced and decoded by libopus 1.3.1.
+
+    // Narrowband, mono, 10, 20, 40 and 60 ms.
+    const TEST_PACKETS_NB_MONO: &[&[u8]] = &[
+        &[
+            0x00, 0x85, 0x71, 0xFF, 0xBE, 0x3B, 0xA3, 0x46, 0x05, 0x43, 0xD8,
+        ],
+        &[
+            0x08, 0x97, 0x2E, 0x86, 0x78, 0xC3, 0x8E, 0x47, 0xA4, 0x97, 0x2C, 0xC2, 0x94, 0x6D,
+            0x7C, 0xA6, 0x3B, 0x88, 0x4E, 0x85, 0x74, 0xC4,
+        ],
+        &[
+            0x10, 0xDB, 0xB9, 0x04, 0x72, 0x17, 0x68, 0xDD, 0xD0, 0x50, 0x2C, 0xFA, 0x6F, 0x28,
+            0x0F, 0xB6, 0xE8, 0xA5, 0xB2, 0xF3, 0x0F, 0xB0, 0x99, 0x58, 0x41, 0x2D, 0xD8, 0xB2,
+            0x5E, 0x2A, 0x98, 0xD0, 0xA7, 0x50, 0xEA, 0xBC, 0xFA, 0x94, 0x23, 0x6D, 0x73, 0x85,
+            0x3C, 0x66, 0x5C, 0x6E, 0x2F, 0xAE, 0x3D, 0xBC, 0x73, 0x5C, 0xAC,
+        ],
+        &[
+            0x18, 0xED, 0xE9, 0x06, 0x9A, 0xB6, 0x37, 0xDB, 0xC6, 0x3A, 0x3F, 0xCD, 0x71, 0x9C,
+            0xA7, 0x1C, 0x34, 0xCB, 0x5D, 0x8F, 0x72, 0x74, 0x84, 0xBD, 0x7E, 0xF2, 0x2E, 0x7F,
+            0xDB, 0x7A, 0x20, 0xBA, 0xEC, 0x3D, 0x91, 0xBE, 0x35, 0xF0, 0x57, 0x10, 0x9C, 0x55,
+            0xB8, 0x33, 0xFF, 0xFA, 0x0F, 0x0F, 0x88, 0xD2, 0xA9, 0x65, 0x2C, 0x2E, 0xBD, 0x4B,
+            0xC7, 0x30, 0xF8, 0x1C, 0x53, 0x6C, 0xDF, 0x22, 0xFB, 0x7B, 0xF3, 0x63, 0x1E, 0x48,
+            0xC0,
+        ],
+    ];
+    const TEST_RANGES_NB_MONO: &[u32] = &[0x0997_FA94, 0x00B0_F455, 0x03B0_33A0, 0x5AC0_5400];
+
+    // Narrowband, stereo, 10, 20, 40 and 60 ms.
+    const TEST_PACKETS_NB_STEREO: &[&[u8]] = &[
+        &[
+            0x04, 0x8C, 0x63, 0x96, 0x5A, 0xCE, 0x7A, 0xA8, 0xBE, 0xE4, 0xC2, 0x47, 0x6A, 0x25,
+            0x46, 0x33, 0x8E,
+        ],
+        &[
+            0x0C, 0x8C, 0x67, 0x3A, 0x38, 0xAC, 0xBD, 0xBE, 0x00, 0x19, 0xC1, 0x04, 0xF3, 0xDA,
+            0x14, 0x53, 0x77, 0x51, 0xEC, 0xD8, 0x37, 0x64, 0xDF, 0x85, 0x88, 0x8D, 0xD1, 0x68,
+            0x74, 0xE4, 0x1A, 0x61, 0xA7, 0x59, 0x80,
+        ],
+        &[
+            0x14, 0xC3, 0x19, 0xCE, 0x11, 0xD4, 0xCB, 0x6C, 0x62, 0xF2, 0xC3, 0x6B, 0x70, 0xAD,
+            0x9B, 0x1F, 0xF4, 0xA4, 0x54, 0x93, 0x63, 0x24, 0x3D, 0x0F, 0xE2, 0xA5, 0x19, 0x73,
+            0x41, 0x54, 0xE5, 0x37, 0x83, 0x47, 0x0C, 0xD6, 0x38, 0x83, 0xC5, 0x84, 0x63, 0x5C,
+            0x8B, 0x14, 0x10, 0x4B, 0xF2, 0x55, 0xE3, 0xB9, 0x4B, 0xEE, 0xA1, 0x56, 0x13, 0xEF,
+            0xEB, 0xBF, 0xF8, 0xD8, 0xAC, 0x47, 0xB4, 0xBA, 0xD1, 0xE7, 0x76, 0xE0, 0xE0, 0xCF,
+            0x43, 0x9F, 0x6E, 0x6E, 0xC0,
+        ],
+        &[
+            0x1C, 0xE0, 0xC6, 0x3C, 0x73, 0x7F, 0xCE, 0x7E, 0xFF, 0x1C, 0x3F, 0x78, 0x8F, 0x42,
+            0x8F, 0x17, 0xCD, 0xE0, 0x8E, 0xC4, 0x45, 0x62, 0x77, 0xF4, 0xA3, 0x6C, 0xCA, 0x71,
+            0x0F, 0x17, 0xBC, 0xB3, 0x50, 0x51, 0x43, 0x4E, 0x13, 0xDA, 0x07, 0xB3, 0x0F, 0x4F,
+            0xA3, 0xA5, 0x39, 0x9F, 0x21, 0x13, 0xF8, 0x7F, 0xD9, 0xA9, 0x54, 0x6D, 0xFE, 0x89,
+            0x47, 0x2D, 0xA0, 0xD1, 0xB9, 0xCA, 0x27, 0x2F, 0xAC, 0xE5, 0x8B, 0x28, 0x7F, 0xF3,
+            0x4A, 0x88, 0x49, 0xE0, 0xC6, 0xC9, 0xC2, 0x61, 0x1A, 0x41, 0xA3, 0x5E, 0xAA, 0x54,
+            0xC4, 0xD7, 0x0B, 0x91, 0x15, 0xBB, 0x3F, 0xBA, 0xE9, 0x21, 0xC3, 0x22, 0xA3, 0xEF,
+            0x70, 0x01, 0x2F, 0x77, 0xEE, 0x3E, 0x50, 0xE4, 0xE0,
+        ],
+    ];
+    const TEST_RANGES_NB_STEREO: &[u32] = &[0x0329_0C6B, 0x2E23_AE40, 0x2E37_30A6, 0x2470_A12C];
+
+    // Mediumband, mono, 10, 20, 40 and 60 ms.
+    const TEST_PACKETS_MB_MONO: &[&[u8]] = &[
+        &[
+            0x20, 0x85, 0x82, 0x7D, 0x33, 0x12, 0x26, 0x2C, 0xDC, 0x71, 0xD8,
+        ],
+        &[
+            0x28, 0x97, 0x2E, 0x8A, 0x79, 0x03, 0xBC, 0x7A, 0x57, 0x66, 0xDE, 0x19, 0x6D, 0x28,
+            0x0D, 0x16, 0xC1, 0x64, 0x8C, 0x91, 0x44, 0x0E, 0x44, 0x48, 0x26, 0x2F, 0xE4,
+        ],
+        &[
+            0x30, 0xDB, 0xDF, 0x9B, 0xB1, 0x3A, 0x62, 0xB4, 0xB9, 0xD4, 0xEB, 0x7A, 0x49, 0x76,
+            0x90, 0xA4, 0x53, 0x7A, 0xCA, 0xEB, 0x47, 0xCC, 0x81, 0xC2, 0x44, 0xE6, 0xDA, 0x75,
+            0xC8, 0x45, 0xA4, 0x38, 0x0C, 0x7D, 0xBC, 0xC5, 0x7E, 0x29, 0xAE, 0x03, 0x57, 0x6C,
+            0xE2, 0x22, 0xFE, 0x69, 0xCC, 0xC0, 0xE0,
+        ],
+        &[
+            0x38, 0xEE, 0x27, 0x6E, 0x3E, 0x2D, 0xD6, 0xF4, 0xE7, 0x2F, 0x7E, 0xC8, 0xE5, 0x3D,
+            0x3E, 0x9A, 0x20, 0xE2, 0x1A, 0xC6, 0x78, 0x88, 0x31, 0x06, 0x16, 0x42, 0x6B, 0xE3,
+            0x72, 0x18, 0x55, 0xA9, 0xC4, 0x57, 0x28, 0x5A, 0xF0, 0x90, 0x72, 0x7B, 0x28, 0x5F,
+            0x9A, 0x36, 0x5D, 0x7A, 0x8B, 0x2E, 0x9A, 0x33, 0x68, 0x3A, 0x87, 0x5A, 0xA2, 0xBF,
+            0xBA, 0xDB, 0x87, 0x05, 0x79, 0x70, 0x20, 0x33,
+        ],
+    ];
+    const TEST_RANGES_MB_MONO: &[u32] = &[0x0894_FC14, 0x02E8_CF11, 0x2308_C7EF, 0x651C_4800];
+
+    // Mediumband, stereo, 10, 20, 40 and 60 ms.
+    const TEST_PACKETS_MB_STEREO: &[&[u8]] = &[
+        &[
+            0x24, 0x84, 0x59, 0xEA, 0xA0, 0xB7, 0xC3, 0x6E, 0x91, 0x7D, 0x2E, 0x39, 0x70, 0x86,
+            0xF1, 0x60, 0x11, 0x43, 0x70, 0xE4,
+        ],
+        &[
+            0x2C, 0x84, 0x74, 0x84, 0x75, 0xCC, 0x18, 0xCD, 0x36, 0xF2, 0x84, 0x6B, 0x27, 0x85,
+            0x77, 0x27, 0xB0, 0xE7, 0xFB, 0x88, 0x62, 0xC6, 0xD1, 0x5D, 0x4B, 0x08, 0x20, 0x9F,
+            0x2B, 0x20, 0x11, 0xE7, 0x1F, 0x77, 0x8B, 0x8B, 0x1D, 0xAA, 0x92, 0x7F, 0xBE, 0x28,
+        ],
+        &[
+            0x34, 0xC1, 0x1D, 0x1F, 0x01, 0x93, 0x40, 0xBC, 0xED, 0xEF, 0x8D, 0xF2, 0x2F, 0xC5,
+            0x56, 0xE9, 0x19, 0xB1, 0xF7, 0x7D, 0xE7, 0x3A, 0xB5, 0xB3, 0xB8, 0x08, 0x6B, 0xBE,
+            0xAD, 0x94, 0xE1, 0xCA, 0x74, 0x65, 0x5D, 0x70, 0x38, 0xFF, 0x36, 0xBA, 0xC5, 0xE9,
+            0x2A, 0x96, 0xD3, 0xA2, 0x07, 0xE9, 0xBB, 0x68, 0xF5, 0x26, 0x2D, 0x43, 0xB2, 0x74,
+            0x59, 0x58, 0xE4, 0xC4, 0xA6, 0xCB, 0x12, 0x20, 0xCE, 0x3A, 0xAB, 0x33, 0x5E, 0x2E,
+            0x19, 0xAC, 0xAF, 0x51, 0x37, 0x7B, 0xC2,
+        ],
+        &[
+            0x3C, 0xE0, 0x45, 0xB5, 0xFD, 0xFA, 0x6F, 0x74, 0xD2, 0x40, 0xEB, 0x9F, 0x1C, 0x60,
+            0x24, 0xCA, 0x01, 0x92, 0xCF, 0x42, 0x42, 0x6A, 0x96, 0x81, 0x7D, 0xA0, 0xE6, 0xAC,
+            0x77, 0x4C, 0xC6, 0x6F, 0xF0, 0x7E, 0xF2, 0x7D, 0xED, 0x27, 0xCE, 0x6A, 0xE6, 0x5B,
+            0x79, 0xCD, 0xE3, 0x85, 0xA3, 0xFB, 0x7D, 0x63, 0x5C, 0x3E, 0xA4, 0xC7, 0x81, 0x6D,
+            0x17, 0x52, 0x26, 0x37, 0xC1, 0xA2, 0x59, 0xAF, 0x0E, 0xA9, 0x69, 0x36, 0xAA, 0x64,
+            0xFE, 0xAF, 0x93, 0x95, 0x11, 0x90, 0x56, 0x32, 0x64, 0x51, 0xE8, 0x20, 0x12, 0x2F,
+            0x5D, 0xDA, 0x45, 0xF4, 0xCE, 0x4C, 0x05, 0x58, 0x01, 0x3C, 0x50, 0xAF, 0x59, 0x0B,
+            0xEB, 0x89, 0x1D, 0xEC, 0xA5, 0x63, 0x2F, 0x53, 0xDB, 0x89, 0x20, 0x20,
+        ],
+    ];
+    const TEST_RANGES_MB_STEREO: &[u32] = &[0x0288_6EB3, 0x0E45_9770, 0x7F4F_6D00, 0x11C6_4308];
+
+    // Wideband, mono, 10, 20, 40 and 60 ms.
+    const TEST_PACKETS_WB_MONO: &[&[u8]] = &[
+        &[
+            0x40, 0x85, 0x9E, 0xF2, 0x25, 0x28, 0xEA, 0xB5, 0xDD, 0x02, 0x1C, 0x24,
+        ],
+        &[
+            0x48, 0x97, 0x86, 0x5A, 0x31, 0xA6, 0xDF, 0x85, 0x88, 0xF4, 0x28, 0x64, 0xD3, 0x6B,
+            0x2A, 0x04, 0x5E, 0x41, 0x71, 0x9B, 0x29, 0x1F, 0xBC, 0x29, 0x70, 0xB1, 0x74,
+        ],
+        &[
+            0x50, 0xDC, 0xC4, 0xC4, 0x1C, 0x0F, 0x39, 0x3F, 0xC3, 0x1A, 0xA7, 0x37, 0xEA, 0x6D,
+            0xBC, 0x5C, 0xBC, 0x15, 0xFD, 0xFB, 0x0B, 0x13, 0x8C, 0xA2, 0x41, 0x7F, 0xB9, 0xD4,
+            0x50, 0x75, 0xD5, 0xDB, 0xF7, 0x8B, 0x35, 0x2A, 0x20, 0xC3, 0x37, 0xB2, 0x49, 0x44,
+            0xE9, 0x1B, 0x35, 0x77, 0xC6, 0x80,
+        ],
+        &[
+            0x58, 0xEE, 0x78, 0x61, 0x1F, 0xC7, 0x9D, 0x38, 0xFA, 0xF2, 0x67, 0x2B, 0xE6, 0x74,
+            0x09, 0x19, 0x16, 0x2D, 0x63, 0x61, 0x04, 0xC4, 0x41, 0x67, 0x81, 0x16, 0x71, 0xD3,
+            0xFC, 0x44, 0x65, 0x0E, 0xB0, 0x65, 0xB4, 0xA3, 0x38, 0xD9, 0x18, 0xC8, 0xE0, 0x36,
+            0x54, 0x2F, 0x8A, 0xA6, 0x20, 0x57, 0x06, 0x27, 0xF3, 0xC8, 0xCE, 0xD3, 0x83, 0xB8,
+            0x76, 0xC1, 0x5E, 0xB1, 0xE4, 0xC3, 0x35, 0xDF, 0x83, 0x9D, 0x3F, 0x63, 0x60, 0xD6,
+            0x96, 0xB0, 0xE7, 0x46,
+        ],
+    ];
+    const TEST_RANGES_WB_MONO: &[u32] = &[0x01E9_3AAD, 0x05B4_9318, 0x649C_7A00, 0x01C6_85F5];
+
+    // Wideband, stereo, 10, 20, 40 and 60 ms.
+    const TEST_PACKETS_WB_STEREO: &[&[u8]] = &[
+        &[
+            0x44, 0x84, 0x6D, 0xFB, 0x74, 0x62, 0x89, 0x66, 0x2C, 0x66, 0x32, 0x0B, 0x0A, 0x90,
+            0xB7, 0x22, 0xFC, 0xDC, 0xCF, 0xC0,
+        ],
+        &[
+            0x4C, 0x84, 0x6F, 0x45, 0x2E, 0x4E, 0xEA, 0xAD, 0x84, 0x1F, 0xA8, 0x30, 0xB2, 0x6C,
+            0xE7, 0x3A, 0x6E, 0x4C, 0xD3, 0xC3, 0xDF, 0x09, 0x64, 0xBF, 0xA3, 0xFA, 0x04, 0x76,
+            0x03, 0x24, 0xD7, 0x2F, 0x49, 0x74, 0x6C, 0x95, 0x59, 0x79, 0xD4, 0x16, 0x19, 0x42,
+            0x23, 0x4F, 0x13,
+        ],
+        &[
+            0x54, 0xC1, 0x22, 0x0D, 0x20, 0x15, 0x85, 0x48, 0xCA, 0x90, 0xB7, 0xC4, 0x9A, 0xB8,
+            0x62, 0xEF, 0x72, 0xE8, 0xB4, 0x8E, 0x0F, 0x59, 0x1A, 0x70, 0x0B, 0x91, 0x12, 0x7B,
+            0x92, 0x7B, 0xB3, 0x18, 0x63, 0x8F, 0x6A, 0xB0, 0x92, 0x60, 0x79, 0xDE, 0x5D, 0x62,
+            0x2A, 0x47, 0x35, 0x9F, 0x5B, 0x34, 0x66, 0x46, 0x75, 0x52, 0xD5, 0x31, 0xD9, 0x39,
+            0xBA, 0x60, 0x71, 0x4D, 0x56, 0x22, 0xD9, 0xEE, 0xB7, 0xC5, 0x0C, 0x15, 0x85, 0x36,
+            0x13, 0xA6, 0x38,
+        ],
+        &[
+            0x5C, 0xE0, 0x47, 0x12, 0xAE, 0xF6, 0xD5, 0x7E, 0x43, 0xF7, 0xAC, 0x0B, 0x36, 0x0D,
+            0x5D, 0xAD, 0x0B, 0x9B, 0x99, 0xCB, 0x0F, 0xF7, 0x9C, 0x49, 0x44, 0x78, 0x2E, 0xC6,
+            0x73, 0x6F, 0xE7, 0x2B, 0xB1, 0x90, 0x56, 0xB6, 0xA5, 0xA4, 0x11, 0x8D, 0xDF, 0x06,
+            0x48, 0x31, 0x7C, 0x62, 0xD4, 0x52, 0x84, 0x5C, 0xC5, 0xB3, 0x7A, 0x41, 0x71, 0x9D,
+            0xF8, 0x0A, 0xE6, 0xF9, 0xA2, 0xBD, 0x6D, 0x34, 0xB3, 0xCC, 0x76, 0xAB, 0xE4, 0x23,
+            0x18, 0xC7, 0xA1, 0xF5, 0xA6, 0x7B, 0xE2, 0x3C, 0xFF, 0x34, 0xD0, 0x1D, 0x3A, 0x6D,
+            0xB4, 0x53, 0x96, 0x6E, 0x88, 0x03, 0xDB, 0xC2, 0xF5, 0x60, 0x49, 0xF8, 0xF2, 0x6D,
+            0x75, 0x86, 0x93, 0x66, 0x91, 0x1C, 0xEB, 0x93, 0x80, 0xF6, 0x56, 0xF7, 0x49, 0x80,
+        ],
+    ];
+    const TEST_RANGES_WB_STEREO: &[u32] = &[0x292C_FE13, 0x1DF8_3458, 0x08BC_9AE4, 0x00CB_13CD];
+
+    const TEST_FRAME_SIZES: &[usize] = &[480, 960, 1920, 2880];
+
+    fn decode_and_check_range(
+        channels: Channels,
+        packets: &[&[u8]],
+        ranges: &[u32],
+        bandwidth: Bandwidth,
+    ) {
+        let mut decoder = Decoder::new(&DecoderConfiguration {
+            sampling_rate: SamplingRate::Hz48000,
+            channels,
+            gain: 0,
+        })
+        .unwrap();
+        let mut samples = vec![0_f32; 5760 * channels as usize];
+
+        packets
+            .iter()
+            .zip(ranges.iter())
+            .zip(TEST_FRAME_SIZES.iter())
+            .for_each(|((packet, &range), &frame_size)| {
+                let count = decoder
+                    .decode_float(
+                        Some(packet),
+                        &mut samples,
+                        NonZeroUsize::new(5760).unwrap(),
+                        false,
+                    )
+                    .unwrap();
+                assert_eq!(count, frame_size);
+                assert_eq!(decoder.final_range(), range);
+                assert_eq!(decoder.bandwidth(), Some(bandwidth));
+                assert!(samples[..count * channels as usize]
+                    .iter()
+                    .any(|x| *x != 0.0));
+            });
+    }
+
+    #[test]
+    fn test_decode_narrowband() {
+        decode_and_check_range(
+            Channels::Mono,
+            TEST_PACKETS_NB_MONO,
+            TEST_RANGES_NB_MONO,
+            Bandwidth::Narrowband,
+        );
+        decode_and_check_range(
+            Channels::Stereo,
+            TEST_PACKETS_NB_STEREO,
+            TEST_RANGES_NB_STEREO,
+            Bandwidth::Narrowband,
+        );
+    }
+
+    #[test]
+    fn test_decode_mediumband() {
+        decode_and_check_range(
+            Channels::Mono,
+            TEST_PACKETS_MB_MONO,
+            TEST_RANGES_MB_MONO,
+            Bandwidth::Mediumband,
+        );
+        decode_and_check_range(
+            Channels::Stereo,
+            TEST_PACKETS_MB_STEREO,
+            TEST_RANGES_MB_STEREO,
+            Bandwidth::Mediumband,
+        );
+    }
+
+    #[test]
+    fn test_decode_wideband() {
+        decode_and_check_range(
+            Channels::Mono,
+            TEST_PACKETS_WB_MONO,
+            TEST_RANGES_WB_MONO,
+            Bandwidth::Wideband,
+        );
+        decode_and_check_range(
+            Channels::Stereo,
+            TEST_PACKETS_WB_STEREO,
+            TEST_RANGES_WB_STEREO,
+            Bandwidth::Wideband,
+        );
     }
 }
@@ -0,0 +1,3171 @@
+//! Implements the Silk encoder.
+
+use crate::range_coder::{RangeEncoder, RangeEncoderState, Tell};
+use crate::silk::fixed::{
+    limit, lin2log, log2lin, lshift_sat32, rshift_round, sat16, smlawb, smulbb, smulwb,
+};
+use crate::silk::float::{
+    apply_sine_window, autocorrelation, burg_modified, bwexpander, energy, float2int, k2a, log2,
+    lpc_analysis_filter, residual_energy, scale_copy_vector, schur, sigmoid,
+    warped_autocorrelation, SineWindow, MAX_SHAPE_LPC_ORDER,
+};
+use crate::silk::gain::{self, gains_dequant, gains_quant};
+use crate::silk::ltp::{find_ltp, ltp_analysis_filter, quant_ltp_gains_flp};
+use crate::silk::nlsf::{
+    a2nlsf, interpolate, nlsf2a, nlsf_encode, nlsf_unpack, nlsf_vq_weights_laroia,
+};
+use crate::silk::nsq::{NsqParameters, NsqState, MAX_DEL_DEC_STATES};
+use crate::silk::pitch::{pitch_analysis_core, PE_MAX_COMPLEX, PE_MID_COMPLEX, PE_MIN_COMPLEX};
+use crate::silk::pulses::encode_pulses;
+use crate::silk::resampler::Resampler;
+use crate::silk::stereo::{
+    stereo_encode_mid_only, stereo_encode_pred, stereo_lr_to_ms, StereoEncoderState,
+};
+use crate::silk::tables::{
+    NlsfCodebook, DELTA_GAIN_ICDF, GAIN_ICDF, LBRR_FLAGS_2_ICDF, LBRR_FLAGS_3_ICDF,
+    LTP_GAIN_ICDF_0, LTP_GAIN_ICDF_1, LTP_GAIN_ICDF_2, LTP_PER_INDEX_ICDF, LTP_SCALES_TABLE_Q14,
+    LTP_SCALE_ICDF, NLSF_CB_NB_MB, NLSF_CB_WB, NLSF_EXT_ICDF, NLSF_INTERPOLATION_FACTOR_ICDF,
+    PITCH_CONTOUR_10_MS_ICDF, PITCH_CONTOUR_10_MS_NB_ICDF, PITCH_CONTOUR_ICDF,
+    PITCH_CONTOUR_NB_ICDF, PITCH_DELTA_ICDF, PITCH_LAG_ICDF, QUANTIZATION_OFFSETS_Q10,
+    TARGET_RATE_MB_21, TARGET_RATE_NB_21, TARGET_RATE_WB_21, TRANSITION_LP_A_Q28,
+    TRANSITION_LP_B_Q28, TYPE_OFFSET_NO_VAD_ICDF, TYPE_OFFSET_VAD_ICDF, UNIFORM4_ICDF,
+    UNIFORM6_ICDF, UNIFORM8_ICDF,
+};
+use crate::silk::vad::{VadState, VAD_N_BANDS};
+use crate::silk::{
+    CondCoding, SideInfoIndices, LA_SHAPE_MS, LTP_MEM_LENGTH_MS, LTP_ORDER, MAX_API_FS_KHZ,
+    MAX_CONSECUTIVE_DTX, MAX_FRAMES_PER_PACKET, MAX_FRAME_LENGTH, MAX_FS_KHZ, MAX_LPC_ORDER,
+    MAX_NB_SUBFR, MAX_PREDICTION_POWER_GAIN, MIN_LPC_ORDER, MIN_QGAIN_DB,
+    NB_SPEECH_FRAMES_BEFORE_DTX, NLSF_QUANT_MAX_AMPLITUDE, N_LEVELS_QGAIN, SUB_FRAME_LENGTH_MS,
+    TYPE_NO_VOICE_ACTIVITY, TYPE_UNVOICED, TYPE_VOICED,
+};
+use crate::OpusError;
+
+/// Maximum frame length of a single Silk frame in ms.
+const MAX_FRAME_LENGTH_MS: usize = SUB_FRAME_LENGTH_MS * MAX_NB_SUBFR;
+/// Look-ahead of the pitch analysis in ms.
+const LA_PITCH_MS: usize = 2;
+const LA_PITCH_MAX: usize = LA_PITCH_MS * MAX_FS_KHZ;
+/// Length of the LPC window used in the pitch analysis in ms.
+const FIND_PITCH_LPC_WIN_MS: usize = 20 + (LA_PITCH_MS << 1);
+const FIND_PITCH_LPC_WIN_MS_2_SF: usize = 10 + (LA_PITCH_MS << 1);
+const FIND_PITCH_LPC_WIN_MAX: usize = FIND_PITCH_LPC_WIN_MS * MAX_FS_KHZ;
+/// Order of the LPC analysis in the pitch analysis.
+const MAX_FIND_PITCH_LPC_ORDER: usize = 16;
+const LA_SHAPE_MAX: usize = LA_SHAPE_MS * MAX_FS_KHZ;
+/// Maximal length of the noise shaping window.
+const SHAPE_LPC_WIN_MAX: usize = 15 * MAX_FS_KHZ;
+
+/// Number of frames of the bandwidth transition.
+const TRANSITION_FRAMES: i32 = 256;
+const TRANSITION_NB: usize = 3;
+const TRANSITION_NA: usize = 2;
+const TRANSITION_INT_NUM: usize = 5;
+
+/// Decay time of the bit reservoir.
+const BITRESERVOIR_DECAY_TIME_MS: i32 = 500;
+/// Speech activity threshold in Q8 below which a frame is treated as silent.
+const SPEECH_ACTIVITY_DTX_THRES_Q8: i32 = 13;
+/// Slope of the speech activity threshold used for bandwidth switching in Q24.
+const SPEECH_ACTIVITY_SWITCH_SLOPE_Q24: i32 = 3188;
+/// Speech activity threshold in Q8 above which a frame is LBRR coded.
+const LBRR_SPEECH_ACTIVITY_THRES_Q8: i32 = 77;
+
+// High pass filtering.
+const VARIABLE_HP_MIN_CUTOFF_HZ: i32 = 60;
+const VARIABLE_HP_MAX_CUTOFF_HZ: i32 = 100;
+const VARIABLE_HP_MAX_DELTA_FREQ_Q7: i32 = 51;
+const VARIABLE_HP_SMTH_COEF1_Q16: i32 = 6554;
+
+// Pitch estimator thresholds in Q16.
+const PITCH_EST_THRESHOLD_MIN_Q16: i32 = 52429;
+const PITCH_EST_THRESHOLD_LOW_Q16: i32 = 49807;
+const PITCH_EST_THRESHOLD_MID_Q16: i32 = 48497;
+const PITCH_EST_THRESHOLD_HIGH_Q16: i32 = 47186;
+const PITCH_EST_THRESHOLD_MAX_Q16: i32 = 45875;
+/// Warping multiplier of the noise shaping filter in Q16.
+const WARPING_MULTIPLIER_Q16: i32 = 983;
+
+// Tuning parameters.
+const FIND_PITCH_WHITE_NOISE_FRACTION: f32 = 1e-3;
+const FIND_PITCH_BANDWIDTH_EXPANSION: f32 = 0.99;
+const MAX_PREDICTION_POWER_GAIN_AFTER_RESET: f32 = 1e2;
+const BG_SNR_DECR_DB: f32 = 2.0;
+const HARM_SNR_INCR_DB: f32 = 2.0;
+const ENERGY_VARIATION_THRESHOLD_QNT_OFFSET: f32 = 0.6;
+const SHAPE_WHITE_NOISE_FRACTION: f32 = 3e-5;
+const BANDWIDTH_EXPANSION: f32 = 0.94;
+const HARMONIC_SHAPING: f32 = 0.3;
+const HIGH_RATE_OR_LOW_QUALITY_HARMONIC_SHAPING: f32 = 0.2;
+const HP_NOISE_COEF: f32 = 0.25;
+const HARM_HP_NOISE_COEF: f32 = 0.35;
+const LOW_FREQ_SHAPING: f32 = 4.0;
+const LOW_QUALITY_LOW_FREQ_SHAPING_DECR: f32 = 0.5;
+const SUBFR_SMTH_COEF: f32 = 0.4;
+const LAMBDA_OFFSET: f32 = 1.2;
+const LAMBDA_SPEECH_ACT: f32 = -0.2;
+const LAMBDA_DELAYED_DECISIONS: f32 = -0.05;
+const LAMBDA_INPUT_QUALITY: f32 = -0.1;
+const LAMBDA_CODING_QUALITY: f32 = -0.2;
+const LAMBDA_QUANT_OFFSET: f32 = 0.8;
+
+/// Control parameters of the Silk encoder.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct EncoderControl {
+    /// Number of channels of the input signal.
+    pub(crate) channels_api: usize,
+    /// Number of channels to encode.
+    pub(crate) channels_internal: usize,
+    /// Sampling rate of the input signal in Hz.
+    pub(crate) api_sampling_rate: usize,
+    /// Maximum internal sampling rate in Hz.
+    pub(crate) max_internal_sampling_rate: usize,
+    /// Minimum internal sampling rate in Hz.
+    pub(crate) min_internal_sampling_rate: usize,
+    /// Desired internal sampling rate in Hz.
+    pub(crate) desired_internal_sampling_rate: usize,
+    /// Number of samples per packet in ms.
+    pub(crate) payload_size_ms: usize,
+    /// Bitrate during active speech in bits/second.
+    pub(crate) bitrate: i32,
+    /// Complexity mode. 0 is lowest, 10 is highest complexity.
+    pub(crate) complexity: usize,
+    /// Use constant bitrate.
+    pub(crate) use_cbr: bool,
+    /// Maximum number of bits allowed for the frame.
+    pub(crate) max_bits: i32,
+    /// Uplink packet loss in percent (0-100).
+    pub(crate) packet_loss_percentage: i32,
+    /// Enable in-band forward error correction.
+    pub(crate) use_in_band_fec: bool,
+    /// Encode LBRR data for the next packet.
+    pub(crate) lbrr_coded: bool,
+    /// Enable discontinuous transmission (DTX).
+    pub(crate) use_dtx: bool,
+    /// Causes a smooth downmix to mono.
+    pub(crate) to_mono: bool,
+    /// Opus encoder is allowing us to switch the bandwidth.
+    pub(crate) opus_can_switch: bool,
+    /// Output: the internal sampling rate used in Hz.
+    pub(crate) internal_sampling_rate: usize,
+    /// Output: signals that the encoder is ready to switch the bandwidth.
+    pub(crate) switch_ready: bool,
+    /// Output: signals that the encoder allows a bandwidth switch.
+    pub(crate) allow_bandwidth_switch: bool,
+    /// Output: signals that the encoder is in wideband mode without the variable low pass filter.
+    pub(crate) in_wb_mode_without_variable_lp: bool,
+    /// Output: the smoothed stereo width in Q14.
+    pub(crate) stereo_width_q14: i32,
+    /// Output: the signal type of the last encoded frame.
+    pub(crate) signal_type: usize,
+    /// Output: the quantization offset of the last encoded frame in Q10.
+    pub(crate) offset: i32,
+}
+
+/// Prefill mode of the Silk encoder.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Prefill {
+    /// Regular encoding.
+    None,
+    /// Resets the encoder and fills its buffers without producing a payload.
+    Reset,
+    /// Like `Reset`, but keeps the state of the variable low pass filter.
+    KeepLowpass,
+}
+
+impl EncoderControl {
+    /// Checks the control parameters.
+    fn check(&self) -> Result<(), OpusError> {
+        if ![8000, 12000, 16000, 24000, 32000, 44100, 48000].contains(&self.api_sampling_rate) {
+            return Err(OpusError::BadArguments("invalid API sampling rate"));
+        }
+
+        let internal_rates = [8000, 12000, 16000];
+        if !internal_rates.contains(&self.desired_internal_sampling_rate)
+            || !internal_rates.contains(&self.max_internal_sampling_rate)
+            || !internal_rates.contains(&self.min_internal_sampling_rate)
+            || self.min_internal_sampling_rate > self.desired_internal_sampling_rate
+            || self.max_internal_sampling_rate < self.desired_internal_sampling_rate
+            || self.min_internal_sampling_rate > self.max_internal_sampling_rate
+        {
+            return Err(OpusError::BadArguments("invalid internal sampling rate"));
+        }
+
+        if ![10, 20, 40, 60].contains(&self.payload_size_ms) {
+            return Err(OpusError::BadArguments("invalid payload size"));
+        }
+
+        if !(1..=2).contains(&self.channels_api)
+            || !(1..=2).contains(&self.channels_internal)
+            || self.channels_internal > self.channels_api
+        {
+            return Err(OpusError::BadArguments("invalid number of channels"));
+        }
+
+        if self.complexity > 10 {
+            return Err(OpusError::BadArguments("invalid complexity"));
+        }
+
+        if !(0..=100).contains(&self.packet_loss_percentage) {
+            return Err(OpusError::BadArguments("invalid packet loss percentage"));
+        }
+
+        Ok(())
+    }
+}
+
+/// The Silk encoder.
+#[derive(Clone, Debug)]
+pub(crate) struct SilkEncoder {
+    channel_state: [ChannelEncoder; 2],
+    stereo_state: StereoEncoderState,
+    n_bits_used_lbrr: i32,
+    n_bits_exceeded: i32,
+    channels_api: usize,
+    channels_internal: usize,
+    prev_channels_internal: usize,
+    time_since_switch_allowed_ms: i32,
+    allow_bandwidth_switch: bool,
+    prev_decode_only_middle: bool,
+}
+
+impl SilkEncoder {
+    /// Creates a new Silk encoder.
+    pub(crate) fn new() -> Self {
+        Self {
+            channel_state: [ChannelEncoder::default(), ChannelEncoder::default()],
+            stereo_state: StereoEncoderState::default(),
+            n_bits_used_lbrr: 0,
+            n_bits_exceeded: 0,
+            channels_api: 1,
+            channels_internal: 1,
+            prev_channels_internal: 0,
+            time_since_switch_allowed_ms: 0,
+            allow_bandwidth_switch: false,
+            prev_decode_only_middle: false,
+        }
+    }
+
+    /// Resets the Silk encoder.
+    pub(crate) fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Returns true if the Silk encoder has been in DTX for the last frames.
+    pub(crate) fn in_dtx(&self) -> bool {
+        let state0 = &self.channel_state[0];
+        let state1 = &self.channel_state[1];
+        // Stereo: check the second channel unless only the middle channel was encoded.
+        state0.no_speech_counter >= NB_SPEECH_FRAMES_BEFORE_DTX
+            && (self.channels_internal == 1
+                || self.prev_decode_only_middle
+                || state1.no_speech_counter >= NB_SPEECH_FRAMES_BEFORE_DTX)
+    }
+
+    /// Returns the smoothed log2 of the cutoff frequency of the variable high pass filter in Q15.
+    pub(crate) fn variable_hp_smth1_q15(&self) -> i32 {
+        self.channel_state[0].variable_hp_smth1_q15
+    }
+
+    /// Encodes a frame of `samples`, which holds the interleaved input of 10 to 60 ms.
+    ///
+    /// `n_bytes_out` holds the number of bytes written for the packet. It is zero
+    /// until the last frame of the packet has been encoded.
+    ///
+    /// When prefilling, `samples` needs to hold exactly 10 ms and nothing is written to `enc`.
+    ///
+    /// `activity` is the voice activity decision of the Opus encoder, `None` if it made no decision.
+    /// `n_bytes_out` is zero after the last frame if all channels are in DTX.
+    pub(crate) fn encode(
+        &mut self,
+        control: &mut EncoderControl,
+        mut samples: &[i16],
+        enc: &mut RangeEncoder,
+        n_bytes_out: &mut usize,
+        prefill: Prefill,
+        activity: Option<bool>,
+    ) -> Result<(), OpusError> {
+        let prefill_flag = prefill != Prefill::None;
+
+        self.channel_state
+            .iter_mut()
+            .for_each(|state| state.n_frames_encoded = 0);
+
+        // Check values in the encoder control structure.
+        control.check()?;
+
+        control.switch_ready = false;
+
+        if control.channels_internal > self.channels_internal {
+            // Mono -> Stereo transition: init state of second channel and stereo state.
+            self.channel_state[1] = ChannelEncoder::default();
+
+            self.stereo_state.pred_prev_q13 = [0; 2];
+            self.stereo_state.s_side = [0; 2];
+            self.stereo_state.mid_side_amp_q0 = [0, 1, 0, 1];
+            self.stereo_state.width_prev_q14 = 0;
+            self.stereo_state.smth_width_q14 = 1 << 14;
+            if self.channels_api == 2 {
+                self.channel_state[1].resampler = self.channel_state[0].resampler.clone();
+            }
+        }
+
+        let transition = control.payload_size_ms != self.channel_state[0].packet_size_ms
+            || self.channels_internal != control.channels_internal;
+        self.channels_api = control.channels_api;
+        self.channels_internal = control.channels_internal;
+        let mut tmp_payload_size_ms = 0;
+        let mut tmp_complexity = 0;
+
+        let channels_api = self.channels_api;
+        let channels_internal = self.channels_internal;
+
+        let n_blocks_of_10ms = (100 * samples.len() / channels_api) / control.api_sampling_rate;
+        let tot_blocks = if n_blocks_of_10ms > 1 {
+            n_blocks_of_10ms >> 1
+        } else {
+            1
+        };
+        let mut curr_block = 0;
+
+        let mut n_samples_in = samples.len() / channels_api;
+        if prefill_flag {
+            // Only accept input length of 10 ms.
+            if n_blocks_of_10ms != 1 {
+                return Err(OpusError::BadArguments("prefill input length is not 10 ms"));
+            }
+
+            let mut save_lp = self.channel_state[0].lp.clone();
+            // Save the sampling rate so the bandwidth switching code can keep handling transitions.
+            save_lp.saved_fs_khz = self.channel_state[0].fs_khz;
+
+            // Reset encoder.
+            self.channel_state
+                .iter_mut()
+                .take(channels_internal)
+                .for_each(|state| {
+                    *state = ChannelEncoder::default();
+                    // Restore the variable LP state.
+                    if prefill == Prefill::KeepLowpass {
+                        state.lp = save_lp.clone();
+                    }
+                });
+
+            tmp_payload_size_ms = control.payload_size_ms;
+            control.payload_size_ms = 10;
+            tmp_complexity = control.complexity;
+            control.complexity = 0;
+            self.channel_state
+                .iter_mut()
+                .take(channels_internal)
+                .for_each(|state| {
+                    state.controlled_since_last_payload = false;
+                    state.prefill_flag = true;
+                });
+        } else {
+            // Only accept input lengths that are a multiple of 10 ms.
+            if n_blocks_of_10ms * control.api_sampling_rate != 100 * n_samples_in {
+                return Err(OpusError::BadArguments(
+                    "input length is not a multiple of 10 ms",
+                ));
+            }
+
+            // Make sure no more than one packet can be produced.
+            if 1000 * n_samples_in > control.payload_size_ms * control.api_sampling_rate {
+                return Err(OpusError::BadArguments(
+                    "input length is longer than the payload size",
+                ));
+            }
+        }
+
+        let allow_bandwidth_switch = self.allow_bandwidth_switch;
+        for n in 0..channels_internal {
+            // Force the side channel to the same rate as the mid.
+            let force_fs_khz = if n == 1 {
+                self.channel_state[0].fs_khz
+            } else {
+                0
+            };
+            let state = &mut self.channel_state[n];
+            state.control_encoder(control, force_fs_khz, allow_bandwidth_switch)?;
+            if state.first_frame_after_reset || transition {
+                state.lbrr_flags = [false; MAX_FRAMES_PER_PACKET];
+            }
+            state.in_dtx = state.use_dtx;
+        }
+        debug_assert!(
+            channels_internal == 1 || self.channel_state[0].fs_khz == self.channel_state[1].fs_khz
+        );
+
+        // Input buffering / resampling and encoding.
+        let n_samples_to_buffer_max = 10 * n_blocks_of_10ms * self.channel_state[0].fs_khz;
+        let n_frames_per_packet = self.channel_state[0].n_frames_per_packet;
+        let mut buf = [0_i16; MAX_FRAME_LENGTH_MS * MAX_API_FS_KHZ];
+        loop {
+            let state = &self.channel_state[0];
+            let mut n_samples_to_buffer = usize::min(
+                state.frame_length - state.input_buf_ix,
+                n_samples_to_buffer_max,
+            );
+            let n_samples_from_input =
+                n_samples_to_buffer * state.api_fs_hz / (state.fs_khz * 1000);
+
+            if channels_api == 2 && channels_internal == 2 {
+                let id = self.channel_state[0].n_frames_encoded;
+                buf.iter_mut()
+                    .zip(samples.chunks_exact(2))
+                    .take(n_samples_from_input)
+                    .for_each(|(x, s)| *x = s[0]);
+
+                // Making sure to start both resamplers from the same state when switching from mono to stereo.
+                if self.prev_channels_internal == 1 && id == 0 {
+                    self.channel_state[1].resampler = self.channel_state[0].resampler.clone();
+                }
+
+                let state = &mut self.channel_state[0];
+                state.buffer_input(&buf[..n_samples_from_input], n_samples_to_buffer);
+                state.input_buf_ix += n_samples_to_buffer;
+
+                let state = &mut self.channel_state[1];
+                n_samples_to_buffer = usize::min(
+                    state.frame_length - state.input_buf_ix,
+                    10 * n_blocks_of_10ms * state.fs_khz,
+                );
+                buf.iter_mut()
+                    .zip(samples.chunks_exact(2))
+                    .take(n_samples_from_input)
+                    .for_each(|(x, s)| *x = s[1]);
+
+                state.buffer_input(&buf[..n_samples_from_input], n_samples_to_buffer);
+                state.input_buf_ix += n_samples_to_buffer;
+            } else if channels_api == 2 && channels_internal == 1 {
+                // Combine left and right channels before resampling.
+                buf.iter_mut()
+                    .zip(samples.chunks_exact(2))
+                    .take(n_samples_from_input)
+                    .for_each(|(x, s)| {
+                        *x = rshift_round(i32::from(s[0]) + i32::from(s[1]), 1) as i16
+                    });
+
+                self.channel_state[0]
+                    .buffer_input(&buf[..n_samples_from_input], n_samples_to_buffer);
+
+                // On the first mono frame, average the results for the two resampler states.
+                if self.prev_channels_internal == 2 && self.channel_state[0].n_frames_encoded == 0 {
+                    self.channel_state[1]
+                        .buffer_input(&buf[..n_samples_from_input], n_samples_to_buffer);
+
+                    let (state0, state1) = self.channel_state.split_at_mut(1);
+                    let (state0, state1) = (&mut state0[0], &state1[0]);
+                    let offset0 = state0.input_buf_ix + 2;
+                    let offset1 = state1.input_buf_ix + 2;
+                    (0..state0.frame_length).for_each(|n| {
+                        state0.input_buf[offset0 + n] = ((i32::from(state0.input_buf[offset0 + n])
+                            + i32::from(state1.input_buf[offset1 + n]))
+                            >> 1) as i16;
+                    });
+                }
+
+                self.channel_state[0].input_buf_ix += n_samples_to_buffer;
+            } else {
+                debug_assert!(channels_api == 1 && channels_internal == 1);
+                buf[..n_samples_from_input].copy_from_slice(&samples[..n_samples_from_input]);
+
+                let state = &mut self.channel_state[0];
+                state.buffer_input(&buf[..n_samples_from_input], n_samples_to_buffer);
+                state.input_buf_ix += n_samples_to_buffer;
+            }
+
+            samples = &samples[n_samples_from_input * channels_api..];
+            n_samples_in -= n_samples_from_input;
+
+            // Default.
+            self.allow_bandwidth_switch = false;
+
+            // Silk encoder.
+            if self.channel_state[0].input_buf_ix < self.channel_state[0].frame_length {
+                break;
+            }
+
+            // Enough data in input buffer, so encode.
+            debug_assert_eq!(
+                self.channel_state[0].input_buf_ix,
+                self.channel_state[0].frame_length
+            );
+            debug_assert!(
+                channels_internal == 1
+                    || self.channel_state[0].input_buf_ix == self.channel_state[1].input_buf_ix
+            );
+
+            if self.channel_state[0].n_frames_encoded == 0 && !prefill_flag {
+                // Create space at start of payload for VAD and FEC flags.
+                let icdf = [
+                    (256 - (256 >> ((n_frames_per_packet + 1) * channels_internal))) as u8,
+                    0,
+                ];
+                enc.encode_icdf(0, &icdf, 8)?;
+
+                // Encode any LBRR data from previous packet.
+                self.encode_lbrr_data(enc)?;
+
+                self.n_bits_used_lbrr = enc.tell() as i32;
+            }
+
+            self.channel_state[0].hp_variable_cutoff();
+
+            // Total target bits for packet.
+            let mut n_bits = control.bitrate * control.payload_size_ms as i32 / 1000;
+            // Subtract bits used for LBRR.
+            if !prefill_flag {
+                n_bits -= self.n_bits_used_lbrr;
+            }
+            // Divide by number of uncoded frames left in packet.
+            n_bits /= n_frames_per_packet as i32;
+            // Convert to bits/second.
+            let mut target_rate_bps = if control.payload_size_ms == 10 {
+                smulbb(n_bits, 100)
+            } else {
+                smulbb(n_bits, 50)
+            };
+            // Subtract fraction of bits in excess of target in previous frames and packets.
+            target_rate_bps -= self.n_bits_exceeded * 1000 / BITRESERVOIR_DECAY_TIME_MS;
+            if !prefill_flag && self.channel_state[0].n_frames_encoded > 0 {
+                // Compare actual vs target bits so far in this packet.
+                let bits_balance = enc.tell() as i32
+                    - self.n_bits_used_lbrr
+                    - n_bits * self.channel_state[0].n_frames_encoded as i32;
+                target_rate_bps -= bits_balance * 1000 / BITRESERVOIR_DECAY_TIME_MS;
+            }
+            // Never exceed input bitrate.
+            target_rate_bps = limit(target_rate_bps, control.bitrate, 5000);
+
+            // Convert Left/Right to Mid/Side.
+            let mut ms_target_rates_bps = [0_i32; 2];
+            let n_frames_encoded = self.channel_state[0].n_frames_encoded;
+            if channels_internal == 2 {
+                let (state0, state1) = self.channel_state.split_at_mut(1);
+                let (state0, state1) = (&mut state0[0], &mut state1[0]);
+
+                let mut pred_ix = self.stereo_state.pred_ix[n_frames_encoded];
+                let mut mid_only_flag = self.stereo_state.mid_only_flags[n_frames_encoded];
+                stereo_lr_to_ms(
+                    &mut self.stereo_state,
+                    &mut state0.input_buf,
+                    &mut state1.input_buf,
+                    &mut pred_ix,
+                    &mut mid_only_flag,
+                    &mut ms_target_rates_bps,
+                    target_rate_bps,
+                    state0.speech_activity_q8,
+                    control.to_mono,
+                    state0.fs_khz,
+                    state0.frame_length,
+                );
+                self.stereo_state.pred_ix[n_frames_encoded] = pred_ix;
+                self.stereo_state.mid_only_flags[n_frames_encoded] = mid_only_flag;
+
+                if !mid_only_flag {
+                    // Reset side channel encoder memory for first frame with side coding.
+                    if self.prev_decode_only_middle {
+                        state1.reset_side_channel();
+                    }
+                    state1.encode_do_vad(activity);
+                } else {
+                    state1.vad_flags[n_frames_encoded] = false;
+                }
+
+                // Encode stereo parameters.
+                if !prefill_flag {
+                    stereo_encode_pred(enc, &pred_ix)?;
+                    if !state1.vad_flags[n_frames_encoded] {
+                        stereo_encode_mid_only(enc, mid_only_flag)?;
+                    }
+                }
+            } else {
+                // Buffering.
+                let state = &mut self.channel_state[0];
+                let frame_length = state.frame_length;
+                state.input_buf[..2].copy_from_slice(&self.stereo_state.s_mid);
+                self.stereo_state
+                    .s_mid
+                    .copy_from_slice(&state.input_buf[frame_length..frame_length + 2]);
+            }
+
+            self.channel_state[0].encode_do_vad(activity);
+
+            // Encode.
+            for n in 0..channels_internal {
+                let mut max_bits = control.max_bits;
+
+                // Handle limitation of total number of bits for frames in a packet.
+                if tot_blocks == 2 && curr_block == 0 {
+                    max_bits = max_bits * 3 / 5;
+                } else if tot_blocks == 3 {
+                    if curr_block == 0 {
+                        max_bits = max_bits * 2 / 5;
+                    } else if curr_block == 1 {
+                        max_bits = max_bits * 3 / 4;
+                    }
+                }
+                let mut use_cbr = control.use_cbr && curr_block == tot_blocks - 1;
+
+                let channel_rate_bps = if channels_internal == 1 {
+                    target_rate_bps
+                } else {
+                    if n == 0 && ms_target_rates_bps[1] > 0 {
+                        use_cbr = false;
+                        // Give mid up to 1/2 of the max bits for that frame.
+                        max_bits -= control.max_bits / (tot_blocks as i32 * 2);
+                    }
+                    ms_target_rates_bps[n]
+                };
+
+                if channel_rate_bps > 0 {
+                    // Use independent coding if no previous frame available.
+                    let cond_coding = if self.channel_state[0].n_frames_encoded <= n {
+                        CondCoding::Independently
+                    } else if n > 0 && self.prev_decode_only_middle {
+                        // If we skipped a side frame in this packet, we don't
+                        // need LTP scaling; the LTP state is well-defined.
+                        CondCoding::IndependentlyNoLtpScaling
+                    } else {
+                        CondCoding::Conditionally
+                    };
+
+                    let state = &mut self.channel_state[n];
+                    state.control_snr(channel_rate_bps);
+                    state.encode_frame(n_bytes_out, enc, cond_coding, max_bits, use_cbr)?;
+                }
+
+                let state = &mut self.channel_state[n];
+                state.controlled_since_last_payload = false;
+                state.input_buf_ix = 0;
+                state.n_frames_encoded += 1;
+            }
+
+            self.prev_decode_only_middle =
+                self.stereo_state.mid_only_flags[self.channel_state[0].n_frames_encoded - 1];
+
+            // Insert VAD and FEC flags at beginning of bitstream.
+            if *n_bytes_out > 0 && self.channel_state[0].n_frames_encoded == n_frames_per_packet {
+                let mut flags = 0;
+                self.channel_state
+                    .iter()
+                    .take(channels_internal)
+                    .for_each(|state| {
+                        state
+                            .vad_flags
+                            .iter()
+                            .take(state.n_frames_per_packet)
+                            .for_each(|&vad_flag| {
+                                flags <<= 1;
+                                flags |= u32::from(vad_flag);
+                            });
+                        flags <<= 1;
+                        flags |= u32::from(state.lbrr_flag);
+                    });
+                if !prefill_flag {
+                    enc.patch_initial_bits(
+                        flags,
+                        ((n_frames_per_packet + 1) * channels_internal) as u32,
+                    )?;
+                }
+
+                // Return zero bytes if all channels are in DTX.
+                if self.channel_state[0].in_dtx
+                    && (channels_internal == 1 || self.channel_state[1].in_dtx)
+                {
+                    *n_bytes_out = 0;
+                }
+
+                // Update the bit reservoir.
+                self.n_bits_exceeded += *n_bytes_out as i32 * 8;
+                self.n_bits_exceeded -= control.bitrate * control.payload_size_ms as i32 / 1000;
+                self.n_bits_exceeded = limit(self.n_bits_exceeded, 0, 10000);
+
+                // Update the flag indicating if bandwidth switching is allowed.
+                let speech_act_thr_for_switch_q8 = smlawb(
+                    SPEECH_ACTIVITY_DTX_THRES_Q8,
+                    SPEECH_ACTIVITY_SWITCH_SLOPE_Q24,
+                    self.time_since_switch_allowed_ms,
+                );
+                if self.channel_state[0].speech_activity_q8 < speech_act_thr_for_switch_q8 {
+                    self.allow_bandwidth_switch = true;
+                    self.time_since_switch_allowed_ms = 0;
+                } else {
+                    self.allow_bandwidth_switch = false;
+                    self.time_since_switch_allowed_ms += control.payload_size_ms as i32;
+                }
+            }
+
+            if n_samples_in == 0 {
+                break;
+            }
+
+            curr_block += 1;
+        }
+
+        self.prev_channels_internal = channels_internal;
+
+        control.allow_bandwidth_switch = self.allow_bandwidth_switch;
+        control.in_wb_mode_without_variable_lp =
+            self.channel_state[0].fs_khz == 16 && self.channel_state[0].lp.mode == 0;
+        control.internal_sampling_rate = self.channel_state[0].fs_khz * 1000;
+        control.stereo_width_q14 = if control.to_mono {
+            0
+        } else {
+            i32::from(self.stereo_state.smth_width_q14)
+        };
+        if prefill_flag {
+            control.payload_size_ms = tmp_payload_size_ms;
+            control.complexity = tmp_complexity;
+            self.channel_state
+                .iter_mut()
+                .take(channels_internal)
+                .for_each(|state| {
+                    state.controlled_since_last_payload = false;
+                    state.prefill_flag = false;
+                });
+        }
+        let indices = &self.channel_state[0].indices;
+        control.signal_type = indices.signal_type;
+        control.offset = i32::from(
+            QUANTIZATION_OFFSETS_Q10[indices.signal_type >> 1][indices.quant_offset_type],
+        );
+
+        Ok(())
+    }
+
+    /// Encodes the LBRR flags, indices and excitation signals of the previous packet.
+    fn encode_lbrr_data(&mut self, enc: &mut RangeEncoder) -> Result<(), OpusError> {
+        let channels_internal = self.channels_internal;
+        let n_frames_per_packet = self.channel_state[0].n_frames_per_packet;
+
+        // Encode LBRR flags.
+        self.channel_state
+            .iter_mut()
+            .take(channels_internal)
+            .try_for_each(|state| {
+                let lbrr_symbol = state
+                    .lbrr_flags
+                    .iter()
+                    .take(state.n_frames_per_packet)
+                    .enumerate()
+                    .fold(0, |symbol, (i, &flag)| symbol | (usize::from(flag) << i));
+                state.lbrr_flag = lbrr_symbol > 0;
+                if lbrr_symbol > 0 && state.n_frames_per_packet > 1 {
+                    let icdf: &[u8] = if state.n_frames_per_packet == 2 {
+                        LBRR_FLAGS_2_ICDF
+                    } else {
+                        LBRR_FLAGS_3_ICDF
+                    };
+                    enc.encode_icdf(lbrr_symbol - 1, icdf, 8)?;
+                }
+                Ok::<(), OpusError>(())
+            })?;
+
+        // Code LBRR indices and excitation signals.
+        for i in 0..n_frames_per_packet {
+            for n in 0..channels_internal {
+                if !self.channel_state[n].lbrr_flags[i] {
+                    continue;
+                }
+
+                if channels_internal == 2 && n == 0 {
+                    stereo_encode_pred(enc, &self.stereo_state.pred_ix[i])?;
+                    // For LBRR data there's no need to code the mid-only flag if the side-channel LBRR flag is set.
+                    if !self.channel_state[1].lbrr_flags[i] {
+                        stereo_encode_mid_only(enc, self.stereo_state.mid_only_flags[i])?;
+                    }
+                }
+
+                let state = &mut self.channel_state[n];
+                // Use conditional coding if previous frame available.
+                let cond_coding = if i > 0 && state.lbrr_flags[i - 1] {
+                    CondCoding::Conditionally
+                } else {
+                    CondCoding::Independently
+                };
+                state.encode_indices(enc, i, true, cond_coding)?;
+                let indices = &state.indices_lbrr[i];
+                encode_pulses(
+                    enc,
+                    indices.signal_type,
+                    indices.quant_offset_type,
+                    &mut state.pulses_lbrr[i],
+                    state.frame_length,
+                )?;
+            }
+        }
+
+        // Reset LBRR flags.
+        self.channel_state
+            .iter_mut()
+            .take(channels_internal)
+            .for_each(|state| state.lbrr_flags = [false; MAX_FRAMES_PER_PACKET]);
+
+        Ok(())
+    }
+}
+
+/// State of the variable low pass filter used for smooth bandwidth transitions.
+#[derive(Clone, Debug, Default)]
+struct LpState {
+    /// Low pass filter state.
+    in_lp_state: [i32; 2],
+    /// Counter which is mapped to a cut-off frequency.
+    transition_frame_no: i32,
+    /// Operating mode, <0: switch down, >0: switch up; 0: do nothing.
+    mode: i32,
+    /// If non-zero, holds the last sampling rate before a bandwidth switching reset.
+    saved_fs_khz: usize,
+}
+
+/// State of the noise shaping analysis.
+#[derive(Clone, Debug, Default)]
+struct ShapeState {
+    last_gain_index: i8,
+    harm_shape_gain_smth: f32,
+    tilt_smth: f32,
+}
+
+/// Encoder control of a frame.
+#[derive(Clone, Debug)]
+struct FrameControl {
+    // Prediction and coding parameters.
+    gains: [f32; MAX_NB_SUBFR],
+    pred_coef: [[f32; MAX_LPC_ORDER]; 2],
+    ltp_coef: [f32; LTP_ORDER * MAX_NB_SUBFR],
+    pitch_l: [i32; MAX_NB_SUBFR],
+
+    // Noise shaping parameters.
+    ar: [f32; MAX_NB_SUBFR * MAX_SHAPE_LPC_ORDER],
+    lf_ma_shp: [f32; MAX_NB_SUBFR],
+    lf_ar_shp: [f32; MAX_NB_SUBFR],
+    tilt: [f32; MAX_NB_SUBFR],
+    harm_shape_gain: [f32; MAX_NB_SUBFR],
+    lambda: f32,
+    input_quality: f32,
+    coding_quality: f32,
+
+    // Measures.
+    pred_gain: f32,
+    lt_pred_cod_gain: f32,
+    /// Residual energy per subframe.
+    res_nrg: [f32; MAX_NB_SUBFR],
+
+    // Parameters for CBR mode.
+    gains_unq_q16: [i32; MAX_NB_SUBFR],
+    last_gain_index_prev: i8,
+}
+
+impl Default for FrameControl {
+    fn default() -> Self {
+        Self {
+            gains: [0.0; MAX_NB_SUBFR],
+            pred_coef: [[0.0; MAX_LPC_ORDER]; 2],
+            ltp_coef: [0.0; LTP_ORDER * MAX_NB_SUBFR],
+            pitch_l: [0; MAX_NB_SUBFR],
+            ar: [0.0; MAX_NB_SUBFR * MAX_SHAPE_LPC_ORDER],
+            lf_ma_shp: [0.0; MAX_NB_SUBFR],
+            lf_ar_shp: [0.0; MAX_NB_SUBFR],
+            tilt: [0.0; MAX_NB_SUBFR],
+            harm_shape_gain: [0.0; MAX_NB_SUBFR],
+            lambda: 0.0,
+            input_quality: 0.0,
+            coding_quality: 0.0,
+            pred_gain: 0.0,
+            lt_pred_cod_gain: 0.0,
+            res_nrg: [0.0; MAX_NB_SUBFR],
+            gains_unq_q16: [0; MAX_NB_SUBFR],
+            last_gain_index_prev: 0,
+        }
+    }
+}
+
+/// Encoder state of a single channel.
+#[derive(Clone, Debug)]
+struct ChannelEncoder {
+    /// State for the variable low pass filter.
+    lp: LpState,
+    /// Noise shaping state.
+    shape: ShapeState,
+    /// Noise shaping quantizer state.
+    nsq: NsqState,
+    /// Voice activity detector state.
+    vad: VadState,
+    /// Resampler from the API to the internal sampling rate.
+    resampler: Resampler,
+    /// Buffer for the input signal including two samples of history.
+    input_buf: [i16; MAX_FRAME_LENGTH + 2],
+    /// Buffer length / index of the input buffer.
+    input_buf_ix: usize,
+    /// Buffer for the analysis signal, holds the LTP memory, the frame and the look-ahead.
+    x_buf: [f32; 2 * MAX_FRAME_LENGTH + LA_SHAPE_MAX],
+    /// Quantized pulses of the current frame.
+    pulses: [i8; MAX_FRAME_LENGTH],
+    /// Quantized pulses of the LBRR frames.
+    pulses_lbrr: [[i8; MAX_FRAME_LENGTH]; MAX_FRAMES_PER_PACKET],
+
+    /// Smoothed log2 of the high pass cutoff frequency in Q15.
+    variable_hp_smth1_q15: i32,
+    /// API sampling frequency in Hz.
+    api_fs_hz: usize,
+    /// Previous API sampling frequency in Hz.
+    prev_api_fs_hz: usize,
+    /// Maximum internal sampling frequency in Hz.
+    max_internal_fs_hz: usize,
+    /// Minimum internal sampling frequency in Hz.
+    min_internal_fs_hz: usize,
+    /// Desired internal sampling frequency in Hz.
+    desired_internal_fs_hz: usize,
+    /// Internal sampling frequency in kHz.
+    fs_khz: usize,
+    /// Number of 5 ms subframes in a frame.
+    nb_subfr: usize,
+    /// Frame length in samples.
+    frame_length: usize,
+    /// Subframe length in samples.
+    subfr_length: usize,
+    /// Length of the LTP memory.
+    ltp_mem_length: usize,
+    /// Look-ahead of the pitch analysis.
+    la_pitch: usize,
+    /// Look-ahead of the noise shaping analysis.
+    la_shape: usize,
+    /// Window length of the noise shaping analysis.
+    shape_win_length: usize,
+    /// Window length of the LPC analysis in the pitch analysis.
+    pitch_lpc_win_length: usize,
+    /// Order of the LPC analysis in the pitch analysis.
+    pitch_estimation_lpc_order: usize,
+    /// Complexity of the pitch analysis.
+    pitch_estimation_complexity: usize,
+    /// Threshold of the pitch analysis in Q16.
+    pitch_estimation_threshold_q16: i32,
+    /// Order of the prediction filter.
+    predict_lpc_order: usize,
+    /// Order of the noise shaping filter.
+    shaping_lpc_order: usize,
+    /// Number of states in the delayed decision quantizer.
+    n_states_delayed_decision: usize,
+    /// Flag for using NLSF interpolation.
+    use_interpolated_nlsfs: bool,
+    /// Number of survivors in the NLSF MSVQ.
+    nlsf_msvq_survivors: usize,
+    /// Warping parameter of the noise shaping filter in Q16.
+    warping_q16: i32,
+    /// Complexity mode.
+    complexity: usize,
+    /// Packet size in ms.
+    packet_size_ms: usize,
+    /// Quality setting.
+    snr_db_q7: i32,
+    /// Packet loss rate measured by the far end in percent.
+    packet_loss_perc: i32,
+    /// Use constant bitrate.
+    use_cbr: bool,
+    /// Allows switching of the internal bandwidth.
+    allow_bandwidth_switch: bool,
+    /// Flag that is set when the encoder was configured since the last payload.
+    controlled_since_last_payload: bool,
+    /// Flag for deactivating NLSF interpolation and pitch prediction.
+    first_frame_after_reset: bool,
+    /// Flag that is set when only buffers are filled, but no payload is produced.
+    prefill_flag: bool,
+    /// Counter of the encoded frames, used for the seed.
+    frame_counter: i32,
+
+    // Analysis results.
+    speech_activity_q8: i32,
+    input_tilt_q15: i32,
+    input_quality_bands_q15: [i32; VAD_N_BANDS],
+    ltp_corr: f32,
+    sum_log_gain_q7: i32,
+    prev_lag: i32,
+    prev_signal_type: usize,
+    /// Used to interpolate LSFs.
+    prev_nlsf_q15: [i16; MAX_LPC_ORDER],
+
+    // For buffering payload in case of more frames per packet.
+    n_frames_per_packet: usize,
+    n_frames_encoded: usize,
+
+    // Specifically for entropy coding.
+    ec_prev_signal_type: usize,
+    ec_prev_lag_index: i16,
+
+    vad_flags: [bool; MAX_FRAMES_PER_PACKET],
+
+    // Discontinuous transmission.
+    use_dtx: bool,
+    in_dtx: bool,
+    no_speech_counter: i32,
+
+    // Low bitrate redundancy.
+    lbrr_enabled: bool,
+    lbrr_gain_increases: i8,
+    lbrr_prev_last_gain_index: i8,
+    lbrr_flag: bool,
+    lbrr_flags: [bool; MAX_FRAMES_PER_PACKET],
+    indices_lbrr: [SideInfoIndices; MAX_FRAMES_PER_PACKET],
+
+    /// Pointer to iCDF table for low bits of pitch lag index.
+    pitch_lag_low_bits_icdf: &'static [u8],
+    /// Pointer to iCDF table for pitch contour index.
+    pitch_contour_icdf: &'static [u8],
+    /// Pointer to NLSF codebook.
+    nlsf_cb: &'static NlsfCodebook,
+
+    /// Quantization indices.
+    indices: SideInfoIndices,
+}
+
+impl Default for ChannelEncoder {
+    fn default() -> Self {
+        let variable_hp_smth1_q15 = (lin2log(VARIABLE_HP_MIN_CUTOFF_HZ << 16) - (16 << 7)) << 8;
+
+        Self {
+            lp: LpState::default(),
+            shape: ShapeState::default(),
+            nsq: NsqState::default(),
+            vad: VadState::default(),
+            resampler: Resampler::default(),
+            input_buf: [0; MAX_FRAME_LENGTH + 2],
+            input_buf_ix: 0,
+            x_buf: [0.0; 2 * MAX_FRAME_LENGTH + LA_SHAPE_MAX],
+            pulses: [0; MAX_FRAME_LENGTH],
+            pulses_lbrr: [[0; MAX_FRAME_LENGTH]; MAX_FRAMES_PER_PACKET],
+            variable_hp_smth1_q15,
+            api_fs_hz: 0,
+            prev_api_fs_hz: 0,
+            max_internal_fs_hz: 0,
+            min_internal_fs_hz: 0,
+            desired_internal_fs_hz: 0,
+            fs_khz: 0,
+            nb_subfr: 0,
+            frame_length: 0,
+            subfr_length: 0,
+            ltp_mem_length: 0,
+            la_pitch: 0,
+            la_shape: 0,
+            shape_win_length: 0,
+            pitch_lpc_win_length: 0,
+            pitch_estimation_lpc_order: 0,
+            pitch_estimation_complexity: 0,
+            pitch_estimation_threshold_q16: 0,
+            predict_lpc_order: 0,
+            shaping_lpc_order: 0,
+            n_states_delayed_decision: 0,
+            use_interpolated_nlsfs: false,
+            nlsf_msvq_survivors: 0,
+            warping_q16: 0,
+            complexity: 0,
+            packet_size_ms: 0,
+            snr_db_q7: 0,
+            packet_loss_perc: 0,
+            use_cbr: false,
+            allow_bandwidth_switch: false,
+            controlled_since_last_payload: false,
+            // Used to deactivate LSF interpolation and pitch prediction.
+            first_frame_after_reset: true,
+            prefill_flag: false,
+            frame_counter: 0,
+            speech_activity_q8: 0,
+            input_tilt_q15: 0,
+            input_quality_bands_q15: [0; VAD_N_BANDS],
+            ltp_corr: 0.0,
+            sum_log_gain_q7: 0,
+            prev_lag: 0,
+            prev_signal_type: 0,
+            prev_nlsf_q15: [0; MAX_LPC_ORDER],
+            n_frames_per_packet: 0,
+            n_frames_encoded: 0,
+            ec_prev_signal_type: 0,
+            ec_prev_lag_index: 0,
+            vad_flags: [false; MAX_FRAMES_PER_PACKET],
+            use_dtx: false,
+            in_dtx: false,
+            no_speech_counter: 0,
+            lbrr_enabled: false,
+            lbrr_gain_increases: 0,
+            lbrr_prev_last_gain_index: 0,
+            lbrr_flag: false,
+            lbrr_flags: [false; MAX_FRAMES_PER_PACKET],
+            indices_lbrr: Default::default(),
+            pitch_lag_low_bits_icdf: &[],
+            pitch_contour_icdf: &[],
+            nlsf_cb: NLSF_CB_NB_MB,
+            indices: SideInfoIndices::default(),
+        }
+    }
+}
+
+impl ChannelEncoder {
+    /// Resamples the input and appends it to the input buffer.
+    fn buffer_input(&mut self, input: &[i16], n_samples: usize) {
+        let start = self.input_buf_ix + 2;
+        self.resampler
+            .resample(&mut self.input_buf[start..start + n_samples], input);
+    }
+
+    /// Resets the side channel encoder memory for the first frame with side coding.
+    fn reset_side_channel(&mut self) {
+        self.shape = ShapeState::default();
+        self.nsq.reset();
+        self.prev_nlsf_q15 = [0; MAX_LPC_ORDER];
+        self.lp.in_lp_state = [0; 2];
+
+        self.prev_lag = 100;
+        self.shape.last_gain_index = 10;
+        self.prev_signal_type = TYPE_NO_VOICE_ACTIVITY;
+        self.first_frame_after_reset = true;
+    }
+
+    /// Configures the encoder.
+    fn control_encoder(
+        &mut self,
+        control: &mut EncoderControl,
+        force_fs_khz: usize,
+        allow_bandwidth_switch: bool,
+    ) -> Result<(), OpusError> {
+        // Set encoder parameters from control structure.
+        self.use_cbr = control.use_cbr;
+        self.use_dtx = control.use_dtx;
+        self.api_fs_hz = control.api_sampling_rate;
+        self.max_internal_fs_hz = control.max_internal_sampling_rate;
+        self.min_internal_fs_hz = control.min_internal_sampling_rate;
+        self.desired_internal_fs_hz = control.desired_internal_sampling_rate;
+        self.allow_bandwidth_switch = allow_bandwidth_switch;
+
+        if self.controlled_since_last_payload && !self.prefill_flag {
+            if self.api_fs_hz != self.prev_api_fs_hz && self.fs_khz > 0 {
+                // Change in API sampling rate in the middle of encoding a packet.
+                self.setup_resamplers(self.fs_khz)?;
+            }
+            return Ok(());
+        }
+
+        // Beyond this point we know that there are no previously coded frames in the payload buffer.
+
+        // Determine internal sampling rate.
+        let mut fs_khz = self.control_audio_bandwidth(control);
+        if force_fs_khz != 0 {
+            fs_khz = force_fs_khz;
+        }
+
+        // Prepare resampler and buffered data.
+        self.setup_resamplers(fs_khz)?;
+
+        // Set internal sampling frequency.
+        self.setup_fs(fs_khz, control.payload_size_ms);
+
+        // Set encoding complexity.
+        self.setup_complexity(control.complexity);
+
+        // Set packet loss rate measured by the far end.
+        self.packet_loss_perc = control.packet_loss_percentage;
+
+        // Set LBRR usage.
+        self.setup_lbrr(control.lbrr_coded);
+
+        self.controlled_since_last_payload = true;
+
+        Ok(())
+    }
+
+    /// Enables or disables the LBRR coding and sets the gain increase of the LBRR excitation.
+    fn setup_lbrr(&mut self, lbrr_coded: bool) {
+        let lbrr_in_previous_packet = self.lbrr_enabled;
+        self.lbrr_enabled = lbrr_coded;
+        if self.lbrr_enabled {
+            // Set gain increase for coding LBRR excitation.
+            self.lbrr_gain_increases = if !lbrr_in_previous_packet {
+                // Previous packet did not have LBRR, and was therefore coded at a higher bitrate.
+                7
+            } else {
+                i32::max(7 - smulwb(self.packet_loss_perc, 26214), 2) as i8
+            };
+        }
+    }
+
+    /// Controls the internal sampling rate. Returns the internal sampling rate in kHz.
+    fn control_audio_bandwidth(&mut self, control: &mut EncoderControl) -> usize {
+        let mut orig_khz = self.fs_khz;
+        // Handle a bandwidth-switching reset where we need to be aware what the last sampling rate was.
+        if orig_khz == 0 {
+            orig_khz = self.lp.saved_fs_khz;
+        }
+        let mut fs_khz = orig_khz;
+        let mut fs_hz = fs_khz * 1000;
+
+        if fs_hz == 0 {
+            // Encoder has just been initialized.
+            fs_hz = usize::min(self.desired_internal_fs_hz, self.api_fs_hz);
+            fs_khz = fs_hz / 1000;
+        } else if fs_hz > self.api_fs_hz
+            || fs_hz > self.max_internal_fs_hz
+            || fs_hz < self.min_internal_fs_hz
+        {
+            // Make sure internal rate is not higher than external rate or maximum allowed, or lower than minimum allowed.
+            fs_hz = self.api_fs_hz;
+            fs_hz = usize::min(fs_hz, self.max_internal_fs_hz);
+            fs_hz = usize::max(fs_hz, self.min_internal_fs_hz);
+            fs_khz = fs_hz / 1000;
+        } else {
+            // State machine for the internal sampling rate switching.
+            if self.lp.transition_frame_no >= TRANSITION_FRAMES {
+                // Stop transition phase.
+                self.lp.mode = 0;
+            }
+
+            if self.allow_bandwidth_switch || control.opus_can_switch {
+                if orig_khz * 1000 > self.desired_internal_fs_hz {
+                    // Switch down.
+                    if self.lp.mode == 0 {
+                        // New transition.
+                        self.lp.transition_frame_no = TRANSITION_FRAMES;
+
+                        // Reset transition filter state.
+                        self.lp.in_lp_state = [0; 2];
+                    }
+
+                    if control.opus_can_switch {
+                        // Stop transition phase.
+                        self.lp.mode = 0;
+
+                        // Switch to a lower sample frequency.
+                        fs_khz = if orig_khz == 16 { 12 } else { 8 };
+                    } else if self.lp.transition_frame_no <= 0 {
+                        control.switch_ready = true;
+                        // Make room for redundancy.
+                        control.max_bits -=
+                            control.max_bits * 5 / (control.payload_size_ms as i32 + 5);
+                    } else {
+                        // Direction: down (at double speed).
+                        self.lp.mode = -2;
+                    }
+                } else if orig_khz * 1000 < self.desired_internal_fs_hz {
+                    // Switch up.
+                    if control.opus_can_switch {
+                        // Switch to a higher sample frequency.
+                        fs_khz = if orig_khz == 8 { 12 } else { 16 };
+
+                        // New transition.
+                        self.lp.transition_frame_no = 0;
+
+                        // Reset transition filter state.
+                        self.lp.in_lp_state = [0; 2];
+
+                        // Direction: up.
+                        self.lp.mode = 1;
+                    } else if self.lp.mode == 0 {
+                        control.switch_ready = true;
+                        // Make room for redundancy.
+                        control.max_bits -=
+                            control.max_bits * 5 / (control.payload_size_ms as i32 + 5);
+                    } else {
+                        // Direction: up.
+                        self.lp.mode = 1;
+                    }
+                } else if self.lp.mode < 0 {
+                    self.lp.mode = 1;
+                }
+            }
+        }
+
+        fs_khz
+    }
+
+    /// Sets up the resampler and resamples the buffered data when the sampling rates change.
+    fn setup_resamplers(&mut self, fs_khz: usize) -> Result<(), OpusError> {
+        if self.fs_khz != fs_khz || self.prev_api_fs_hz != self.api_fs_hz {
+            if self.fs_khz == 0 {
+                // Initialize the resampler for enc_API.c preparing resampling from api_fs_hz to fs_khz.
+                self.resampler.init(self.api_fs_hz, fs_khz * 1000, true)?;
+            } else {
+                let mut x_buf_fix = [0_i16; 2 * MAX_FRAME_LENGTH + LA_SHAPE_MAX];
+                let mut x_buf_api_fs_hz =
+                    [0_i16; (2 * MAX_FRAME_LENGTH_MS + LA_SHAPE_MS) * MAX_API_FS_KHZ];
+
+                let buf_length_ms = ((self.nb_subfr * SUB_FRAME_LENGTH_MS) << 1) + LA_SHAPE_MS;
+                let old_buf_samples = buf_length_ms * self.fs_khz;
+
+                x_buf_fix
+                    .iter_mut()
+                    .zip(self.x_buf.iter())
+                    .take(old_buf_samples)
+                    .for_each(|(out, &x)| *out = sat16(float2int(x)));
+
+                // Initialize resampler for temporary resampling of x_buf data to api_fs_hz.
+                let mut temp_resampler = Resampler::default();
+                temp_resampler.init(self.fs_khz * 1000, self.api_fs_hz, false)?;
+
+                // Calculate number of samples to temporarily upsample.
+                let api_buf_samples = buf_length_ms * (self.api_fs_hz / 1000);
+
+                // Temporary resampling of x_buf data to api_fs_hz.
+                temp_resampler.resample(
+                    &mut x_buf_api_fs_hz[..api_buf_samples],
+                    &x_buf_fix[..old_buf_samples],
+                );
+
+                // Initialize the resampler for enc_API.c preparing resampling from api_fs_hz to fs_khz.
+                self.resampler.init(self.api_fs_hz, fs_khz * 1000, true)?;
+
+                // Correct resampler state by resampling buffered data from api_fs_hz to fs_khz.
+                let new_buf_samples = buf_length_ms * fs_khz;
+                self.resampler.resample(
+                    &mut x_buf_fix[..new_buf_samples],
+                    &x_buf_api_fs_hz[..api_buf_samples],
+                );
+
+                self.x_buf
+                    .iter_mut()
+                    .zip(x_buf_fix.iter())
+                    .take(new_buf_samples)
+                    .for_each(|(out, &x)| *out = f32::from(x));
+            }
+        }
+
+        self.prev_api_fs_hz = self.api_fs_hz;
+
+        Ok(())
+    }
+
+    /// Sets the internal sampling frequency and the packet size.
+    fn setup_fs(&mut self, fs_khz: usize, packet_size_ms: usize) {
+        debug_assert!(fs_khz == 8 || fs_khz == 12 || fs_khz == 16);
+
+        // Set packet size.
+        if packet_size_ms != self.packet_size_ms {
+            if packet_size_ms <= 10 {
+                self.n_frames_per_packet = 1;
+                self.nb_subfr = MAX_NB_SUBFR / 2;
+                self.frame_length = packet_size_ms * fs_khz;
+                self.pitch_lpc_win_length = FIND_PITCH_LPC_WIN_MS_2_SF * fs_khz;
+                if self.fs_khz == 8 {
+                    self.pitch_contour_icdf = PITCH_CONTOUR_10_MS_NB_ICDF;
+                } else {
+                    self.pitch_contour_icdf = PITCH_CONTOUR_10_MS_ICDF;
+                }
+            } else {
+                self.n_frames_per_packet = packet_size_ms / MAX_FRAME_LENGTH_MS;
+                self.nb_subfr = MAX_NB_SUBFR;
+                self.frame_length = MAX_FRAME_LENGTH_MS * fs_khz;
+                self.pitch_lpc_win_length = FIND_PITCH_LPC_WIN_MS * fs_khz;
+                if self.fs_khz == 8 {
+                    self.pitch_contour_icdf = PITCH_CONTOUR_NB_ICDF;
+                } else {
+                    self.pitch_contour_icdf = PITCH_CONTOUR_ICDF;
+                }
+            }
+            self.packet_size_ms = packet_size_ms;
+        }
+
+        // Set internal sampling frequency.
+        if self.fs_khz != fs_khz {
+            // Reset part of the state.
+            self.shape = ShapeState::default();
+            self.nsq.reset();
+            self.prev_nlsf_q15 = [0; MAX_LPC_ORDER];
+            self.lp.in_lp_state = [0; 2];
+            self.input_buf_ix = 0;
+            self.n_frames_encoded = 0;
+
+            // Initialize non-zero parameters.
+            self.prev_lag = 100;
+            self.first_frame_after_reset = true;
+            self.shape.last_gain_index = 10;
+            self.prev_signal_type = TYPE_NO_VOICE_ACTIVITY;
+
+            self.fs_khz = fs_khz;
+            if fs_khz == 8 {
+                if self.nb_subfr == MAX_NB_SUBFR {
+                    self.pitch_contour_icdf = PITCH_CONTOUR_NB_ICDF;
+                } else {
+                    self.pitch_contour_icdf = PITCH_CONTOUR_10_MS_NB_ICDF;
+                }
+            } else if self.nb_subfr == MAX_NB_SUBFR {
+                self.pitch_contour_icdf = PITCH_CONTOUR_ICDF;
+            } else {
+                self.pitch_contour_icdf = PITCH_CONTOUR_10_MS_ICDF;
+            }
+
+            if fs_khz == 8 || fs_khz == 12 {
+                self.predict_lpc_order = MIN_LPC_ORDER;
+                self.nlsf_cb = NLSF_CB_NB_MB;
+            } else {
+                self.predict_lpc_order = MAX_LPC_ORDER;
+                self.nlsf_cb = NLSF_CB_WB;
+            }
+
+            self.subfr_length = SUB_FRAME_LENGTH_MS * fs_khz;
+            self.frame_length = self.subfr_length * self.nb_subfr;
+            self.ltp_mem_length = LTP_MEM_LENGTH_MS * fs_khz;
+            self.la_pitch = LA_PITCH_MS * fs_khz;
+            if self.nb_subfr == MAX_NB_SUBFR {
+                self.pitch_lpc_win_length = FIND_PITCH_LPC_WIN_MS * fs_khz;
+            } else {
+                self.pitch_lpc_win_length = FIND_PITCH_LPC_WIN_MS_2_SF * fs_khz;
+            }
+
+            self.pitch_lag_low_bits_icdf = match fs_khz {
+                16 => UNIFORM8_ICDF,
+                12 => UNIFORM6_ICDF,
+                _ => UNIFORM4_ICDF,
+            };
+        }
+
+        debug_assert!(self.subfr_length * self.nb_subfr == self.frame_length);
+    }
+
+    /// Sets the complexity of the encoder.
+    fn setup_complexity(&mut self, complexity: usize) {
+        debug_assert!(complexity <= 10);
+
+        let fs_khz = self.fs_khz;
+        let warping_q16 = fs_khz as i32 * WARPING_MULTIPLIER_Q16;
+
+        // Set encoding complexity.
+        let (
+            pitch_estimation_complexity,
+            pitch_estimation_threshold_q16,
+            pitch_estimation_lpc_order,
+            shaping_lpc_order,
+            la_shape,
+            n_states_delayed_decision,
+            use_interpolated_nlsfs,
+            nlsf_msvq_survivors,
+            warping_q16,
+        ) = match complexity {
+            0 => (
+                PE_MIN_COMPLEX,
+                PITCH_EST_THRESHOLD_MIN_Q16,
+                6,
+                12,
+                3 * fs_khz,
+                1,
+                false,
+                2,
+                0,
+            ),
+            1 => (
+                PE_MID_COMPLEX,
+                PITCH_EST_THRESHOLD_LOW_Q16,
+                8,
+                14,
+                5 * fs_khz,
+                1,
+                false,
+                3,
+                0,
+            ),
+            2 => (
+                PE_MIN_COMPLEX,
+                PITCH_EST_THRESHOLD_MIN_Q16,
+                6,
+                12,
+                3 * fs_khz,
+                2,
+                false,
+                2,
+                0,
+            ),
+            3 => (
+                PE_MID_COMPLEX,
+                PITCH_EST_THRESHOLD_LOW_Q16,
+                8,
+                14,
+                5 * fs_khz,
+                2,
+                false,
+                4,
+                0,
+            ),
+            4 | 5 => (
+                PE_MID_COMPLEX,
+                PITCH_EST_THRESHOLD_MID_Q16,
+                10,
+                16,
+                5 * fs_khz,
+                2,
+                true,
+                6,
+                warping_q16,
+            ),
+            6 | 7 => (
+                PE_MID_COMPLEX,
+                PITCH_EST_THRESHOLD_HIGH_Q16,
+                12,
+                20,
+                5 * fs_khz,
+                3,
+                true,
+                8,
+                warping_q16,
+            ),
+            _ => (
+                PE_MAX_COMPLEX,
+                PITCH_EST_THRESHOLD_MAX_Q16,
+                16,
+                24,
+                5 * fs_khz,
+                MAX_DEL_DEC_STATES,
+                true,
+                16,
+                warping_q16,
+            ),
+        };
+
+        self.pitch_estimation_complexity = pitch_estimation_complexity;
+        self.pitch_estimation_threshold_q16 = pitch_estimation_threshold_q16;
+        self.shaping_lpc_order = shaping_lpc_order;
+        self.la_shape = la_shape;
+        self.n_states_delayed_decision = n_states_delayed_decision;
+        self.use_interpolated_nlsfs = use_interpolated_nlsfs;
+        self.nlsf_msvq_survivors = nlsf_msvq_survivors;
+        self.warping_q16 = warping_q16;
+
+        // Do not allow higher pitch estimation LPC order than predict LPC order.
+        self.pitch_estimation_lpc_order =
+            usize::min(pitch_estimation_lpc_order, self.predict_lpc_order);
+        self.shape_win_length = SUB_FRAME_LENGTH_MS * fs_khz + 2 * self.la_shape;
+        self.complexity = complexity;
+
+        debug_assert!(self.pitch_estimation_lpc_order <= MAX_FIND_PITCH_LPC_ORDER);
+        debug_assert!(self.shaping_lpc_order <= MAX_SHAPE_LPC_ORDER);
+        debug_assert!(self.n_states_delayed_decision <= MAX_DEL_DEC_STATES);
+        debug_assert!(self.warping_q16 <= 32767);
+        debug_assert!(self.la_shape <= LA_SHAPE_MAX);
+        debug_assert!(self.shape_win_length <= SHAPE_LPC_WIN_MAX);
+    }
+
+    /// Controls the SNR of the encoder based on the target bitrate.
+    fn control_snr(&mut self, mut target_rate_bps: i32) {
+        // Reduce bitrate for 10 ms modes in these calculations.
+        if self.nb_subfr == 2 {
+            target_rate_bps -= 2000 + self.fs_khz as i32 / 16;
+        }
+
+        // Find bitrate interval in table and interpolate.
+        let snr_table: &[u8] = match self.fs_khz {
+            8 => TARGET_RATE_NB_21,
+            12 => TARGET_RATE_MB_21,
+            _ => TARGET_RATE_WB_21,
+        };
+        let id = i32::min(
+            (target_rate_bps + 200) / 400 - 10,
+            snr_table.len() as i32 - 1,
+        );
+        self.snr_db_q7 = if id <= 0 {
+            0
+        } else {
+            i32::from(snr_table[id as usize]) * 21
+        };
+    }
+
+    /// Adapts the cutoff frequency of the high pass filter to the pitch of the signal.
+    fn hp_variable_cutoff(&mut self) {
+        // Adaptive cutoff frequency: estimate low end of pitch frequency range.
+        if self.prev_signal_type == TYPE_VOICED {
+            // Difference, in log domain.
+            let pitch_freq_hz_q16 = ((self.fs_khz as i32 * 1000) << 16) / self.prev_lag;
+            let mut pitch_freq_log_q7 = lin2log(pitch_freq_hz_q16) - (16 << 7);
+
+            // Adjustment based on quality.
+            let quality_q15 = self.input_quality_bands_q15[0];
+            pitch_freq_log_q7 = smlawb(
+                pitch_freq_log_q7,
+                smulwb((-quality_q15) << 2, quality_q15),
+                pitch_freq_log_q7 - (lin2log(VARIABLE_HP_MIN_CUTOFF_HZ << 16) - (16 << 7)),
+            );
+
+            // Delta_freq = pitch_freq_log - psEnc->variable_HP_smth1.
+            let mut delta_freq_q7 = pitch_freq_log_q7 - (self.variable_hp_smth1_q15 >> 8);
+            if delta_freq_q7 < 0 {
+                // Less smoothing for decreasing pitch frequency, to track something close to the minimum.
+                delta_freq_q7 *= 3;
+            }
+
+            // Limit delta, to reduce impact of outliers in pitch estimation.
+            delta_freq_q7 = delta_freq_q7.clamp(
+                -VARIABLE_HP_MAX_DELTA_FREQ_Q7,
+                VARIABLE_HP_MAX_DELTA_FREQ_Q7,
+            );
+
+            // Update smoother.
+            self.variable_hp_smth1_q15 = smlawb(
+                self.variable_hp_smth1_q15,
+                smulbb(self.speech_activity_q8, delta_freq_q7),
+                VARIABLE_HP_SMTH_COEF1_Q16,
+            );
+
+            // Limit frequency range.
+            self.variable_hp_smth1_q15 = self.variable_hp_smth1_q15.clamp(
+                lin2log(VARIABLE_HP_MIN_CUTOFF_HZ) << 8,
+                lin2log(VARIABLE_HP_MAX_CUTOFF_HZ) << 8,
+            );
+        }
+    }
+
+    /// Runs the voice activity detection and sets the VAD and DTX flags of the current frame.
+    fn encode_do_vad(&mut self, activity: Option<bool>) {
+        let result = self
+            .vad
+            .speech_activity(&self.input_buf[1..], self.frame_length, self.fs_khz);
+        self.speech_activity_q8 = result.speech_activity_q8;
+        self.input_tilt_q15 = result.input_tilt_q15;
+        self.input_quality_bands_q15 = result.input_quality_bands_q15;
+
+        // If the Opus VAD is inactive and the Silk VAD is active: lower the Silk VAD to just under the threshold.
+        if activity == Some(false) && self.speech_activity_q8 >= SPEECH_ACTIVITY_DTX_THRES_Q8 {
+            self.speech_activity_q8 = SPEECH_ACTIVITY_DTX_THRES_Q8 - 1;
+        }
+
+        // Convert speech activity into VAD and DTX flags.
+        if self.speech_activity_q8 < SPEECH_ACTIVITY_DTX_THRES_Q8 {
+            self.indices.signal_type = TYPE_NO_VOICE_ACTIVITY;
+            self.no_speech_counter += 1;
+            if self.no_speech_counter <= NB_SPEECH_FRAMES_BEFORE_DTX {
+                self.in_dtx = false;
+            } else if self.no_speech_counter > MAX_CONSECUTIVE_DTX + NB_SPEECH_FRAMES_BEFORE_DTX {
+                self.no_speech_counter = NB_SPEECH_FRAMES_BEFORE_DTX;
+                self.in_dtx = false;
+            }
+            self.vad_flags[self.n_frames_encoded] = false;
+        } else {
+            self.no_speech_counter = 0;
+            self.in_dtx = false;
+            self.indices.signal_type = TYPE_UNVOICED;
+            self.vad_flags[self.n_frames_encoded] = true;
+        }
+    }
+
+    /// Encodes a frame.
+    fn encode_frame(
+        &mut self,
+        n_bytes_out: &mut usize,
+        enc: &mut RangeEncoder,
+        cond_coding: CondCoding,
+        max_bits: i32,
+        use_cbr: bool,
+    ) -> Result<(), OpusError> {
+        let mut ctrl = FrameControl::default();
+        let mut res_pitch = [0.0_f32; 2 * MAX_FRAME_LENGTH + LA_PITCH_MAX];
+
+        self.indices.seed = (self.frame_counter & 3) as i8;
+        self.frame_counter += 1;
+
+        // Ensure smooth bandwidth transitions.
+        let frame_length = self.frame_length;
+        lp_variable_cutoff(&mut self.lp, &mut self.input_buf[1..=frame_length]);
+
+        // Copy new frame to front of input buffer.
+        let x_frame = self.ltp_mem_length;
+        let la_shape = LA_SHAPE_MS * self.fs_khz;
+        self.x_buf[x_frame + la_shape..x_frame + la_shape + frame_length]
+            .iter_mut()
+            .zip(self.input_buf[1..].iter())
+            .for_each(|(x, &input)| *x = f32::from(input));
+
+        // Add tiny signal to avoid high CPU load from denormalized floating point numbers.
+        (0..8).for_each(|i| {
+            self.x_buf[x_frame + la_shape + i * (frame_length >> 3)] +=
+                (1 - (i & 2) as i32) as f32 * 1e-6;
+        });
+
+        if self.prefill_flag {
+            // Update input buffer and exit without entropy coding.
+            self.x_buf.copy_within(
+                frame_length..frame_length + self.ltp_mem_length + la_shape,
+                0,
+            );
+            *n_bytes_out = 0;
+            return Ok(());
+        }
+
+        // Find pitch lags, initial LPC analysis.
+        self.find_pitch_lags(&mut ctrl, &mut res_pitch);
+
+        // Noise shape analysis.
+        self.noise_shape_analysis(&mut ctrl, &res_pitch[x_frame..]);
+
+        // Find linear prediction coefficients (LPC + LTP).
+        self.find_pred_coefs(&mut ctrl, &res_pitch, cond_coding);
+
+        // Process gains.
+        self.process_gains(&mut ctrl, cond_coding);
+
+        // Low bitrate redundant encoding.
+        self.lbrr_encode(&mut ctrl, cond_coding);
+
+        // Loop over quantizer and entropy coding to control bitrate.
+        let max_iter = 6;
+        let mut gain_mult_q8: i16 = 1 << 8;
+        let mut found_lower = false;
+        let mut found_upper = false;
+        let mut gains_id = gain::gains_id(&self.indices.gains_indices, self.nb_subfr);
+        let mut gains_id_lower = -1;
+        let mut gains_id_upper = -1;
+        let mut n_bits_lower = 0;
+        let mut n_bits_upper = 0;
+        let mut gain_mult_lower = 0;
+        let mut gain_mult_upper = 0;
+        let mut gain_lock = [false; MAX_NB_SUBFR];
+        let mut best_gain_mult = [0_i16; MAX_NB_SUBFR];
+        let mut best_sum = [0_i32; MAX_NB_SUBFR];
+
+        // Copy part of the input state.
+        let range_enc_copy = enc.state();
+        let nsq_copy = self.nsq.clone();
+        let seed_copy = self.indices.seed;
+        let ec_prev_lag_index_copy = self.ec_prev_lag_index;
+        let ec_prev_signal_type_copy = self.ec_prev_signal_type;
+
+        // Copy of the output state of the last iteration that met the bitrate budget.
+        let mut range_enc_copy2: RangeEncoderState = range_enc_copy;
+        let mut ec_buf_copy: Vec<u8> = Vec::new();
+        let mut nsq_copy2 = nsq_copy.clone();
+        let mut last_gain_index_copy2 = 0;
+
+        let mut iter = 0;
+        loop {
+            let n_bits = if gains_id == gains_id_lower {
+                n_bits_lower
+            } else if gains_id == gains_id_upper {
+                n_bits_upper
+            } else {
+                // Restore part of the input state.
+                if iter > 0 {
+                    enc.set_state(&range_enc_copy);
+                    self.nsq = nsq_copy.clone();
+                    self.indices.seed = seed_copy;
+                    self.ec_prev_lag_index = ec_prev_lag_index_copy;
+                    self.ec_prev_signal_type = ec_prev_signal_type_copy;
+                }
+
+                // Noise shaping quantization.
+                self.noise_shape_quantize(&ctrl);
+
+                if iter == max_iter && !found_lower {
+                    range_enc_copy2 = enc.state();
+                }
+
+                // Encode parameters.
+                self.encode_indices(enc, self.n_frames_encoded, false, cond_coding)?;
+
+                // Encode excitation signal.
+                encode_pulses(
+                    enc,
+                    self.indices.signal_type,
+                    self.indices.quant_offset_type,
+                    &mut self.pulses,
+                    frame_length,
+                )?;
+
+                let mut n_bits = enc.tell() as i32;
+
+                // If we still bust after the last iteration, do some damage control.
+                if iter == max_iter && !found_lower && n_bits > max_bits {
+                    enc.set_state(&range_enc_copy2);
+
+                    // Keep gains the same as the last frame.
+                    self.shape.last_gain_index = ctrl.last_gain_index_prev;
+                    self.indices.gains_indices[..self.nb_subfr]
+                        .iter_mut()
+                        .for_each(|x| *x = 4);
+                    if cond_coding != CondCoding::Conditionally {
+                        self.indices.gains_indices[0] = ctrl.last_gain_index_prev;
+                    }
+                    self.ec_prev_lag_index = ec_prev_lag_index_copy;
+                    self.ec_prev_signal_type = ec_prev_signal_type_copy;
+
+                    // Clear all pulses.
+                    self.pulses[..frame_length].iter_mut().for_each(|x| *x = 0);
+
+                    self.encode_indices(enc, self.n_frames_encoded, false, cond_coding)?;
+                    encode_pulses(
+                        enc,
+                        self.indices.signal_type,
+                        self.indices.quant_offset_type,
+                        &mut self.pulses,
+                        frame_length,
+                    )?;
+
+                    n_bits = enc.tell() as i32;
+                }
+
+                if !use_cbr && iter == 0 && n_bits <= max_bits {
+                    break;
+                }
+
+                n_bits
+            };
+
+            if iter == max_iter {
+                if found_lower && (gains_id == gains_id_lower || n_bits > max_bits) {
+                    // Restore output state from earlier iteration that did meet the bitrate budget.
+                    enc.set_state(&range_enc_copy2);
+                    enc.restore_bytes(&range_enc_copy, &ec_buf_copy);
+                    self.nsq = nsq_copy2;
+                    self.shape.last_gain_index = last_gain_index_copy2;
+                }
+                break;
+            }
+
+            if n_bits > max_bits {
+                if !found_lower && iter >= 2 {
+                    // Adjust the quantizer's rate/distortion tradeoff and discard previous "upper" results.
+                    ctrl.lambda = f32::max(ctrl.lambda * 1.5, 1.5);
+                    // Reducing dithering can help us hit the target.
+                    self.indices.quant_offset_type = 0;
+                    found_upper = false;
+                    gains_id_upper = -1;
+                } else {
+                    found_upper = true;
+                    n_bits_upper = n_bits;
+                    gain_mult_upper = i32::from(gain_mult_q8);
+                    gains_id_upper = gains_id;
+                }
+            } else if n_bits < max_bits - 5 {
+                found_lower = true;
+                n_bits_lower = n_bits;
+                gain_mult_lower = i32::from(gain_mult_q8);
+                if gains_id != gains_id_lower {
+                    gains_id_lower = gains_id;
+
+                    // Copy part of the output state.
+                    range_enc_copy2 = enc.state();
+                    ec_buf_copy = enc.bytes_since(&range_enc_copy).to_vec();
+                    nsq_copy2 = self.nsq.clone();
+                    last_gain_index_copy2 = self.shape.last_gain_index;
+                }
+            } else {
+                // Within 5 bits of budget: close enough.
+                break;
+            }
+
+            if !found_lower && n_bits > max_bits {
+                let subfr_length = self.subfr_length;
+                (0..self.nb_subfr).for_each(|i| {
+                    let sum = self.pulses[i * subfr_length..(i + 1) * subfr_length]
+                        .iter()
+                        .map(|&x| i32::from(x).abs())
+                        .sum();
+                    if iter == 0 || (sum < best_sum[i] && !gain_lock[i]) {
+                        best_sum[i] = sum;
+                        best_gain_mult[i] = gain_mult_q8;
+                    } else {
+                        gain_lock[i] = true;
+                    }
+                });
+            }
+
+            if !(found_lower && found_upper) {
+                // Adjust gain according to high-rate rate/distortion curve.
+                if n_bits > max_bits {
+                    if gain_mult_q8 < 16384 {
+                        gain_mult_q8 *= 2;
+                    } else {
+                        gain_mult_q8 = 32767;
+                    }
+                } else {
+                    let gain_factor_q16 =
+                        log2lin(((n_bits - max_bits) << 7) / frame_length as i32 + (16 << 7));
+                    gain_mult_q8 = smulwb(gain_factor_q16, i32::from(gain_mult_q8)) as i16;
+                }
+            } else {
+                // Adjust gain by interpolating.
+                let mut gain_mult = gain_mult_lower
+                    + ((gain_mult_upper - gain_mult_lower) * (max_bits - n_bits_lower))
+                        / (n_bits_upper - n_bits_lower);
+                // New gain multiplier must be between 25% and 75% of old range (note that gain_mult_upper < gain_mult_lower).
+                if gain_mult > gain_mult_lower + ((gain_mult_upper - gain_mult_lower) >> 2) {
+                    gain_mult = gain_mult_lower + ((gain_mult_upper - gain_mult_lower) >> 2);
+                } else if gain_mult < gain_mult_upper - ((gain_mult_upper - gain_mult_lower) >> 2) {
+                    gain_mult = gain_mult_upper - ((gain_mult_upper - gain_mult_lower) >> 2);
+                }
+                gain_mult_q8 = gain_mult as i16;
+            }
+
+            let mut gains_q16 = [0_i32; MAX_NB_SUBFR];
+            (0..self.nb_subfr).for_each(|i| {
+                let tmp = if gain_lock[i] {
+                    best_gain_mult[i]
+                } else {
+                    gain_mult_q8
+                };
+                gains_q16[i] = lshift_sat32(smulwb(ctrl.gains_unq_q16[i], i32::from(tmp)), 8);
+            });
+
+            // Quantize gains.
+            self.shape.last_gain_index = ctrl.last_gain_index_prev;
+            gains_quant(
+                &mut self.indices.gains_indices,
+                &mut gains_q16,
+                &mut self.shape.last_gain_index,
+                cond_coding == CondCoding::Conditionally,
+                self.nb_subfr,
+            );
+
+            // Unique identifier of gains vector.
+            gains_id = gain::gains_id(&self.indices.gains_indices, self.nb_subfr);
+
+            // Overwrite unquantized gains with quantized gains and convert back to Q0 from Q16.
+            ctrl.gains
+                .iter_mut()
+                .zip(gains_q16.iter())
+                .take(self.nb_subfr)
+                .for_each(|(gain, &gain_q16)| *gain = gain_q16 as f32 / 65536.0);
+
+            iter += 1;
+        }
+
+        // Update input buffer.
+        self.x_buf.copy_within(
+            frame_length..frame_length + self.ltp_mem_length + la_shape,
+            0,
+        );
+
+        // Parameters needed for next frame.
+        self.prev_lag = ctrl.pitch_l[self.nb_subfr - 1];
+        self.prev_signal_type = self.indices.signal_type;
+
+        // Finalize payload.
+        self.first_frame_after_reset = false;
+
+        // Payload size.
+        *n_bytes_out = ((enc.tell() + 7) >> 3) as usize;
+
+        Ok(())
+    }
+
+    /// Finds the pitch lags and performs the initial LPC analysis.
+    fn find_pitch_lags(&mut self, ctrl: &mut FrameControl, res: &mut [f32]) {
+        let mut wsig = [0.0_f32; FIND_PITCH_LPC_WIN_MAX];
+        let mut auto_corr = [0.0_f32; MAX_FIND_PITCH_LPC_ORDER + 1];
+        let mut refl_coef = [0.0_f32; MAX_FIND_PITCH_LPC_ORDER];
+        let mut a = [0.0_f32; MAX_FIND_PITCH_LPC_ORDER];
+
+        // Set up buffer lengths etc based on fs_khz.
+        let buf_len = self.la_pitch + self.frame_length + self.ltp_mem_length;
+        let win_length = self.pitch_lpc_win_length;
+        let la_pitch = self.la_pitch;
+        let order = self.pitch_estimation_lpc_order;
+
+        // Safety check.
+        debug_assert!(buf_len >= win_length);
+
+        // Estimate LPC AR coefficients.
+
+        // Calculate windowed signal.
+        let x_buf = &self.x_buf[buf_len - win_length..];
+
+        // First la_pitch samples.
+        apply_sine_window(&mut wsig[..la_pitch], x_buf, SineWindow::Rising);
+
+        // Middle non-windowed samples.
+        let middle = win_length - (la_pitch << 1);
+        wsig[la_pitch..la_pitch + middle].copy_from_slice(&x_buf[la_pitch..la_pitch + middle]);
+
+        // Last la_pitch samples.
+        let offset = la_pitch + middle;
+        apply_sine_window(
+            &mut wsig[offset..offset + la_pitch],
+            &x_buf[offset..],
+            SineWindow::Falling,
+        );
+
+        // Calculate autocorrelation sequence.
+        autocorrelation(&mut auto_corr[..=order], &wsig[..win_length]);
+
+        // Add white noise, as a fraction of the energy.
+        auto_corr[0] += auto_corr[0] * FIND_PITCH_WHITE_NOISE_FRACTION + 1.0;
+
+        // Calculate the reflection coefficients using Schur.
+        let res_nrg = schur(&mut refl_coef, &auto_corr, order);
+
+        // Prediction gain.
+        ctrl.pred_gain = auto_corr[0] / f32::max(res_nrg, 1.0);
+
+        // Convert reflection coefficients to prediction coefficients.
+        k2a(&mut a, &refl_coef, order);
+
+        // Bandwidth expansion.
+        bwexpander(&mut a[..order], FIND_PITCH_BANDWIDTH_EXPANSION);
+
+        // LPC analysis filtering.
+        lpc_analysis_filter(res, &a, &self.x_buf, buf_len, order);
+
+        if self.indices.signal_type != TYPE_NO_VOICE_ACTIVITY && !self.first_frame_after_reset {
+            // Threshold for pitch estimator.
+            let mut thrhld = 0.6_f32;
+            thrhld -= 0.004 * order as f32;
+            thrhld -= 0.1 * self.speech_activity_q8 as f32 * (1.0 / 256.0);
+            thrhld -= 0.15 * (self.prev_signal_type >> 1) as f32;
+            thrhld -= 0.1 * self.input_tilt_q15 as f32 * (1.0 / 32768.0);
+
+            // Call pitch estimator.
+            match pitch_analysis_core(
+                &res[..buf_len],
+                &mut self.ltp_corr,
+                self.prev_lag,
+                self.pitch_estimation_threshold_q16 as f32 / 65536.0,
+                thrhld,
+                self.fs_khz,
+                self.pitch_estimation_complexity,
+                self.nb_subfr,
+            ) {
+                Some(estimate) => {
+                    ctrl.pitch_l = estimate.pitch_lags;
+                    self.indices.lag_index = estimate.lag_index;
+                    self.indices.contour_index = estimate.contour_index;
+                    self.indices.signal_type = TYPE_VOICED;
+                }
+                None => {
+                    ctrl.pitch_l = [0; MAX_NB_SUBFR];
+                    self.indices.lag_index = 0;
+                    self.indices.contour_index = 0;
+                    self.indices.signal_type = TYPE_UNVOICED;
+                }
+            }
+        } else {
+            ctrl.pitch_l = [0; MAX_NB_SUBFR];
+            self.indices.lag_index = 0;
+            self.indices.contour_index = 0;
+            self.ltp_corr = 0.0;
+        }
+    }
+
+    /// Computes the noise shaping filter coefficients and gains.
+    ///
+    /// `pitch_res` holds the LPC residual from the pitch analysis, starting at the current frame.
+    fn noise_shape_analysis(&mut self, ctrl: &mut FrameControl, pitch_res: &[f32]) {
+        let mut x_windowed = [0.0_f32; SHAPE_LPC_WIN_MAX];
+        let mut auto_corr = [0.0_f32; MAX_SHAPE_LPC_ORDER + 1];
+        let mut rc = [0.0_f32; MAX_SHAPE_LPC_ORDER + 1];
+
+        let nb_subfr = self.nb_subfr;
+        let voiced = self.indices.signal_type == TYPE_VOICED;
+
+        // Point to start of first LPC analysis block.
+        let mut x_ptr = self.ltp_mem_length - self.la_shape;
+
+        // Gain control.
+        let mut snr_adj_db = self.snr_db_q7 as f32 * (1.0 / 128.0);
+
+        // Input quality is the average of the quality in the lowest two VAD bands.
+        ctrl.input_quality = 0.5
+            * (self.input_quality_bands_q15[0] + self.input_quality_bands_q15[1]) as f32
+            * (1.0 / 32768.0);
+
+        // Coding quality level, between 0.0 and 1.0.
+        ctrl.coding_quality = sigmoid(0.25 * (snr_adj_db - 20.0));
+
+        if !self.use_cbr {
+            // Reduce coding SNR during low speech activity.
+            let b = 1.0 - self.speech_activity_q8 as f32 * (1.0 / 256.0);
+            snr_adj_db -=
+                BG_SNR_DECR_DB * ctrl.coding_quality * (0.5 + 0.5 * ctrl.input_quality) * b * b;
+        }
+
+        if voiced {
+            // Reduce gains for periodic signals.
+            snr_adj_db += HARM_SNR_INCR_DB * self.ltp_corr;
+        } else {
+            // For unvoiced signals and low-quality input, adjust the quality slower than snr_db setting.
+            snr_adj_db +=
+                (-0.4 * self.snr_db_q7 as f32 * (1.0 / 128.0) + 6.0) * (1.0 - ctrl.input_quality);
+        }
+
+        // Sparseness processing.
+        if voiced {
+            // Initially set to 0; may be overruled in process_gains().
+            self.indices.quant_offset_type = 0;
+        } else {
+            // Sparseness measure, based on relative fluctuations of energy per 2 milliseconds.
+            let n_samples = 2 * self.fs_khz;
+            let n_segs = SUB_FRAME_LENGTH_MS * nb_subfr / 2;
+            let mut energy_variation = 0.0_f32;
+            let mut log_energy_prev = 0.0_f32;
+            (0..n_segs).for_each(|k| {
+                let nrg = n_samples as f32
+                    + energy(&pitch_res[k * n_samples..(k + 1) * n_samples]) as f32;
+                let log_energy = log2(f64::from(nrg));
+                if k > 0 {
+                    energy_variation += (log_energy - log_energy_prev).abs();
+                }
+                log_energy_prev = log_energy;
+            });
+
+            // Set quantization offset depending on sparseness measure.
+            if energy_variation > ENERGY_VARIATION_THRESHOLD_QNT_OFFSET * (n_segs - 1) as f32 {
+                self.indices.quant_offset_type = 0;
+            } else {
+                self.indices.quant_offset_type = 1;
+            }
+        }
+
+        // Control bandwidth expansion. More BWE for signals with high prediction gain.
+        let strength = FIND_PITCH_WHITE_NOISE_FRACTION * ctrl.pred_gain;
+        let bw_exp = BANDWIDTH_EXPANSION / (1.0 + strength * strength);
+
+        // Slightly more warping in analysis will move quantization noise up in frequency, where it's better masked.
+        let warping = self.warping_q16 as f32 / 65536.0 + 0.01 * ctrl.coding_quality;
+
+        // Compute noise shaping AR coefs and gains.
+        let order = self.shaping_lpc_order;
+        let shape_win_length = self.shape_win_length;
+        let flat_part = self.fs_khz * 3;
+        let slope_part = (shape_win_length - flat_part) / 2;
+        (0..nb_subfr).for_each(|k| {
+            // Apply window: sine slope followed by flat part followed by cosine slope.
+            let x = &self.x_buf[x_ptr..];
+            apply_sine_window(&mut x_windowed[..slope_part], x, SineWindow::Rising);
+            let mut shift = slope_part;
+            x_windowed[shift..shift + flat_part].copy_from_slice(&x[shift..shift + flat_part]);
+            shift += flat_part;
+            apply_sine_window(
+                &mut x_windowed[shift..shift + slope_part],
+                &x[shift..],
+                SineWindow::Falling,
+            );
+
+            // Update pointer: next LPC analysis block.
+            x_ptr += self.subfr_length;
+
+            if self.warping_q16 > 0 {
+                // Calculate warped auto correlation.
+                warped_autocorrelation(
+                    &mut auto_corr,
+                    &x_windowed[..shape_win_length],
+                    warping,
+                    order,
+                );
+            } else {
+                // Calculate regular auto correlation.
+                autocorrelation(&mut auto_corr[..=order], &x_windowed[..shape_win_length]);
+            }
+
+            // Add white noise, as a fraction of energy.
+            auto_corr[0] += auto_corr[0] * SHAPE_WHITE_NOISE_FRACTION + 1.0;
+
+            // Convert correlations to prediction coefficients, and compute residual energy.
+            let nrg = schur(&mut rc, &auto_corr, order);
+            let ar = &mut ctrl.ar[k * MAX_SHAPE_LPC_ORDER..(k + 1) * MAX_SHAPE_LPC_ORDER];
+            k2a(ar, &rc, order);
+            ctrl.gains[k] = nrg.sqrt();
+
+            if self.warping_q16 > 0 {
+                // Adjust gain for warping.
+                ctrl.gains[k] *= warped_gain(ar, warping, order);
+            }
+
+            // Bandwidth expansion.
+            bwexpander(&mut ar[..order], bw_exp);
+
+            if self.warping_q16 > 0 {
+                // Convert to monic warped prediction coefficients and limit absolute values.
+                warped_true2monic_coefs(&mut ar[..order], warping, 3.999);
+            } else {
+                // Limit absolute values of prediction coefficients.
+                limit_coefs(&mut ar[..order], 3.999);
+            }
+        });
+
+        // Gain tweaking: increase gains during low SNR.
+        let gain_mult = 2f64.powf(f64::from(-0.16 * snr_adj_db)) as f32;
+        let gain_add = 2f64.powf(f64::from(0.16 * MIN_QGAIN_DB as f32)) as f32;
+        ctrl.gains.iter_mut().take(nb_subfr).for_each(|gain| {
+            *gain *= gain_mult;
+            *gain += gain_add;
+        });
+
+        // Low-frequency shaping and noise tilt.
+
+        // Less low frequency shaping for noisy inputs.
+        let mut strength = LOW_FREQ_SHAPING
+            * (1.0
+                + LOW_QUALITY_LOW_FREQ_SHAPING_DECR
+                    * (self.input_quality_bands_q15[0] as f32 * (1.0 / 32768.0) - 1.0));
+        strength *= self.speech_activity_q8 as f32 * (1.0 / 256.0);
+        let tilt = if voiced {
+            // Reduce low frequencies quantization noise for periodic signals, depending on pitch lag.
+            (0..nb_subfr).for_each(|k| {
+                let b = 0.2 / self.fs_khz as f32 + 3.0 / ctrl.pitch_l[k] as f32;
+                ctrl.lf_ma_shp[k] = -1.0 + b;
+                ctrl.lf_ar_shp[k] = 1.0 - b - b * strength;
+            });
+            -HP_NOISE_COEF
+                - (1.0 - HP_NOISE_COEF)
+                    * HARM_HP_NOISE_COEF
+                    * self.speech_activity_q8 as f32
+                    * (1.0 / 256.0)
+        } else {
+            let b = 1.3 / self.fs_khz as f32;
+            ctrl.lf_ma_shp[0] = -1.0 + b;
+            ctrl.lf_ar_shp[0] = 1.0 - b - b * strength * 0.6;
+            (1..nb_subfr).for_each(|k| {
+                ctrl.lf_ma_shp[k] = ctrl.lf_ma_shp[0];
+                ctrl.lf_ar_shp[k] = ctrl.lf_ar_shp[0];
+            });
+            -HP_NOISE_COEF
+        };
+
+        // Harmonic shaping control.
+        let harm_shape_gain = if voiced {
+            // More harmonic noise shaping for high bitrates or noisy input.
+            let mut harm_shape_gain = HARMONIC_SHAPING
+                + HIGH_RATE_OR_LOW_QUALITY_HARMONIC_SHAPING
+                    * (1.0 - (1.0 - ctrl.coding_quality) * ctrl.input_quality);
+
+            // Less harmonic noise shaping for less periodic signals.
+            harm_shape_gain *= self.ltp_corr.sqrt();
+            harm_shape_gain
+        } else {
+            0.0
+        };
+
+        // Smooth over subframes.
+        (0..nb_subfr).for_each(|k| {
+            self.shape.harm_shape_gain_smth +=
+                SUBFR_SMTH_COEF * (harm_shape_gain - self.shape.harm_shape_gain_smth);
+            ctrl.harm_shape_gain[k] = self.shape.harm_shape_gain_smth;
+            self.shape.tilt_smth += SUBFR_SMTH_COEF * (tilt - self.shape.tilt_smth);
+            ctrl.tilt[k] = self.shape.tilt_smth;
+        });
+    }
+
+    /// Finds the LPC and LTP coefficients.
+    fn find_pred_coefs(
+        &mut self,
+        ctrl: &mut FrameControl,
+        res_pitch: &[f32],
+        cond_coding: CondCoding,
+    ) {
+        let mut xx_ltp = [0.0_f32; MAX_NB_SUBFR * LTP_ORDER * LTP_ORDER];
+        let mut x_x_ltp = [0.0_f32; MAX_NB_SUBFR * LTP_ORDER];
+        let mut inv_gains = [0.0_f32; MAX_NB_SUBFR];
+        let mut nlsf_q15 = [0_i16; MAX_LPC_ORDER];
+        let mut lpc_in_pre = [0.0_f32; MAX_NB_SUBFR * MAX_LPC_ORDER + MAX_FRAME_LENGTH];
+
+        let x_frame = self.ltp_mem_length;
+        let order = self.predict_lpc_order;
+        let subfr_length = self.subfr_length;
+        let nb_subfr = self.nb_subfr;
+
+        // Weighting for weighted least squares.
+        inv_gains
+            .iter_mut()
+            .zip(ctrl.gains.iter())
+            .take(nb_subfr)
+            .for_each(|(inv_gain, &gain)| {
+                debug_assert!(gain > 0.0);
+                *inv_gain = 1.0 / gain;
+            });
+
+        if self.indices.signal_type == TYPE_VOICED {
+            // Voiced.
+            debug_assert!(self.ltp_mem_length - order >= ctrl.pitch_l[0] as usize + LTP_ORDER / 2);
+
+            // LTP analysis.
+            find_ltp(
+                &mut xx_ltp,
+                &mut x_x_ltp,
+                res_pitch,
+                x_frame,
+                &ctrl.pitch_l,
+                subfr_length,
+                nb_subfr,
+            );
+
+            // Quantize LTP gain parameters.
+            ctrl.lt_pred_cod_gain = quant_ltp_gains_flp(
+                &mut ctrl.ltp_coef,
+                &mut self.indices.ltp_index,
+                &mut self.indices.per_index,
+                &mut self.sum_log_gain_q7,
+                &xx_ltp,
+                &x_x_ltp,
+                subfr_length,
+                nb_subfr,
+            );
+
+            // Control LTP scaling.
+            self.ltp_scale_ctrl(ctrl, cond_coding);
+
+            // Create LTP residual.
+            ltp_analysis_filter(
+                &mut lpc_in_pre,
+                &self.x_buf,
+                x_frame - order,
+                &ctrl.ltp_coef,
+                &ctrl.pitch_l,
+                &inv_gains,
+                subfr_length,
+                nb_subfr,
+                order,
+            );
+        } else {
+            // Unvoiced.
+
+            // Create signal with prepended subframes, scaled by inverse gains.
+            (0..nb_subfr).for_each(|i| {
+                let x_ptr = x_frame - order + i * subfr_length;
+                let pre = i * (subfr_length + order);
+                scale_copy_vector(
+                    &mut lpc_in_pre[pre..pre + subfr_length + order],
+                    &self.x_buf[x_ptr..],
+                    inv_gains[i],
+                );
+            });
+            ctrl.ltp_coef[..nb_subfr * LTP_ORDER]
+                .iter_mut()
+                .for_each(|x| *x = 0.0);
+            ctrl.lt_pred_cod_gain = 0.0;
+            self.sum_log_gain_q7 = 0;
+        }
+
+        // Limit on total predictive coding gain.
+        let min_inv_gain = if self.first_frame_after_reset {
+            1.0 / MAX_PREDICTION_POWER_GAIN_AFTER_RESET
+        } else {
+            let min_inv_gain = 2f64.powf(f64::from(ctrl.lt_pred_cod_gain / 3.0)) as f32
+                / MAX_PREDICTION_POWER_GAIN;
+            min_inv_gain / (0.25 + 0.75 * ctrl.coding_quality)
+        };
+
+        // LPC_in_pre contains the LTP-filtered input for voiced, and the unfiltered input for unvoiced.
+        self.find_lpc(&mut nlsf_q15, &lpc_in_pre, min_inv_gain);
+
+        // Quantize LSFs.
+        self.process_nlsfs(&mut ctrl.pred_coef, &mut nlsf_q15);
+
+        // Calculate residual energy using quantized LPC coefficients.
+        residual_energy(
+            &mut ctrl.res_nrg,
+            &lpc_in_pre,
+            &ctrl.pred_coef,
+            &ctrl.gains,
+            subfr_length,
+            nb_subfr,
+            order,
+        );
+
+        // Copy to prediction struct for use in next frame for interpolation.
+        self.prev_nlsf_q15 = nlsf_q15;
+    }
+
+    /// Sets the LTP state scaling.
+    fn ltp_scale_ctrl(&mut self, ctrl: &FrameControl, cond_coding: CondCoding) {
+        if cond_coding == CondCoding::Independently {
+            // Only scale if first frame in packet.
+            let round_loss = (self.packet_loss_perc + self.n_frames_per_packet as i32) as f32;
+            self.indices.ltp_scale_index =
+                (round_loss * ctrl.lt_pred_cod_gain * 0.1).clamp(0.0, 2.0) as i8;
+        } else {
+            // Default is minimum scaling.
+            self.indices.ltp_scale_index = 0;
+        }
+    }
+
+    /// Finds the NLSFs of the LPC analysis of the frame.
+    fn find_lpc(&mut self, nlsf_q15: &mut [i16; MAX_LPC_ORDER], x: &[f32], min_inv_gain: f32) {
+        let mut a = [0.0_f32; MAX_LPC_ORDER];
+        let mut a_tmp = [0.0_f32; MAX_LPC_ORDER];
+        let mut nlsf0_q15 = [0_i16; MAX_LPC_ORDER];
+        let mut lpc_res = [0.0_f32; MAX_FRAME_LENGTH + MAX_NB_SUBFR * MAX_LPC_ORDER];
+
+        let order = self.predict_lpc_order;
+        let subfr_length = self.subfr_length + order;
+
+        // Default: no interpolation.
+        self.indices.nlsf_interp_coef_q2 = 4;
+
+        // Burg AR analysis for the full frame.
+        let mut res_nrg =
+            burg_modified(&mut a, x, min_inv_gain, subfr_length, self.nb_subfr, order);
+
+        if self.use_interpolated_nlsfs
+            && !self.first_frame_after_reset
+            && self.nb_subfr == MAX_NB_SUBFR
+        {
+            // Optimal solution for last 10 ms; subtract residual energy here, as that's easier than
+            // adding it to the residual energy of the first 10 ms in each iteration of the search below.
+            res_nrg -= burg_modified(
+                &mut a_tmp,
+                &x[(MAX_NB_SUBFR / 2) * subfr_length..],
+                min_inv_gain,
+                subfr_length,
+                MAX_NB_SUBFR / 2,
+                order,
+            );
+
+            // Convert to NLSFs.
+            a2nlsf_flp(&mut nlsf_q15[..order], &a_tmp[..order]);
+
+            // Search over interpolation indices to find the one with lowest residual energy.
+            let mut res_nrg_2nd = f32::MAX;
+            for k in (0..4).rev() {
+                // Interpolate NLSFs for first half.
+                interpolate(
+                    &mut nlsf0_q15[..order],
+                    &self.prev_nlsf_q15[..order],
+                    &nlsf_q15[..order],
+                    k,
+                );
+
+                // Convert to LPC for residual energy evaluation.
+                nlsf2a_flp(&mut a_tmp[..order], &nlsf0_q15[..order]);
+
+                // Calculate residual energy with LSF interpolation.
+                lpc_analysis_filter(&mut lpc_res, &a_tmp, x, 2 * subfr_length, order);
+                let res_nrg_interp = (energy(&lpc_res[order..subfr_length])
+                    + energy(&lpc_res[order + subfr_length..2 * subfr_length]))
+                    as f32;
+
+                // Determine whether current interpolated NLSFs are best so far.
+                if res_nrg_interp < res_nrg {
+                    // Interpolation has lower residual energy.
+                    res_nrg = res_nrg_interp;
+                    self.indices.nlsf_interp_coef_q2 = k as i8;
+                } else if res_nrg_interp > res_nrg_2nd {
+                    // No reason to continue iterating - residual energies will continue to climb.
+                    break;
+                }
+                res_nrg_2nd = res_nrg_interp;
+            }
+        }
+
+        if self.indices.nlsf_interp_coef_q2 == 4 {
+            // NLSF interpolation is currently inactive, calculate NLSFs from full frame AR coefficients.
+            a2nlsf_flp(&mut nlsf_q15[..order], &a[..order]);
+        }
+
+        debug_assert!(
+            self.indices.nlsf_interp_coef_q2 == 4
+                || (self.use_interpolated_nlsfs
+                    && !self.first_frame_after_reset
+                    && self.nb_subfr == MAX_NB_SUBFR)
+        );
+    }
+
+    /// Limits, stabilizes, quantizes and interpolates the NLSFs.
+    fn process_nlsfs(
+        &mut self,
+        pred_coef: &mut [[f32; MAX_LPC_ORDER]; 2],
+        nlsf_q15: &mut [i16; MAX_LPC_ORDER],
+    ) {
+        let mut pred_coef_q12 = [[0_i16; MAX_LPC_ORDER]; 2];
+        let mut nlsf0_temp_q15 = [0_i16; MAX_LPC_ORDER];
+        let mut nlsf_w_qw = [0_i16; MAX_LPC_ORDER];
+        let mut nlsf_w0_temp_qw = [0_i16; MAX_LPC_ORDER];
+
+        let order = self.predict_lpc_order;
+
+        debug_assert!(self.use_interpolated_nlsfs || self.indices.nlsf_interp_coef_q2 == 4);
+
+        // NLSF_mu = 0.003 - 0.0015 * speech_activity.
+        let mut nlsf_mu_q20 = smlawb(3146, -268_434, self.speech_activity_q8);
+        if self.nb_subfr == 2 {
+            // Multiply by 1.5 for 10 ms packets.
+            nlsf_mu_q20 += nlsf_mu_q20 >> 1;
+        }
+
+        debug_assert!(nlsf_mu_q20 > 0);
+
+        // Calculate NLSF weights.
+        nlsf_vq_weights_laroia(&mut nlsf_w_qw[..order], &nlsf_q15[..order]);
+
+        // Update NLSF weights for interpolated NLSFs.
+        let do_interpolate = self.use_interpolated_nlsfs && self.indices.nlsf_interp_coef_q2 < 4;
+        if do_interpolate {
+            let interp_coef_q2 = i32::from(self.indices.nlsf_interp_coef_q2);
+
+            // Calculate the interpolated NLSF vector for the first half.
+            interpolate(
+                &mut nlsf0_temp_q15[..order],
+                &self.prev_nlsf_q15[..order],
+                &nlsf_q15[..order],
+                interp_coef_q2,
+            );
+
+            // Calculate first half NLSF weights for the interpolated NLSFs.
+            nlsf_vq_weights_laroia(&mut nlsf_w0_temp_qw[..order], &nlsf0_temp_q15[..order]);
+
+            // Update NLSF weights with contribution from first half.
+            let i_sqr_q15 = smulbb(interp_coef_q2, interp_coef_q2) << 11;
+            nlsf_w_qw
+                .iter_mut()
+                .zip(nlsf_w0_temp_qw.iter())
+                .take(order)
+                .for_each(|(w, &w0)| {
+                    *w = ((*w >> 1) as i32 + (smulbb(i32::from(w0), i_sqr_q15) >> 16)) as i16;
+                    debug_assert!(*w > 0);
+                });
+        }
+
+        nlsf_encode(
+            &mut self.indices.nlsf_indices,
+            &mut nlsf_q15[..order],
+            self.nlsf_cb,
+            &nlsf_w_qw[..order],
+            nlsf_mu_q20,
+            self.nlsf_msvq_survivors,
+            self.indices.signal_type,
+        );
+
+        // Convert quantized NLSFs back to LPC coefficients.
+        nlsf2a(&mut pred_coef_q12[1][..order], &nlsf_q15[..order]);
+
+        if do_interpolate {
+            // Calculate the interpolated, quantized LSF vector for the first half.
+            interpolate(
+                &mut nlsf0_temp_q15[..order],
+                &self.prev_nlsf_q15[..order],
+                &nlsf_q15[..order],
+                i32::from(self.indices.nlsf_interp_coef_q2),
+            );
+
+            // Convert back to LPC coefficients.
+            nlsf2a(&mut pred_coef_q12[0][..order], &nlsf0_temp_q15[..order]);
+        } else {
+            // Copy LPC coefficients for first half from second half.
+            pred_coef_q12[0] = pred_coef_q12[1];
+        }
+
+        pred_coef
+            .iter_mut()
+            .zip(pred_coef_q12.iter())
+            .for_each(|(pred_coef, pred_coef_q12)| {
+                pred_coef
+                    .iter_mut()
+                    .zip(pred_coef_q12.iter())
+                    .take(order)
+                    .for_each(|(x, &x_q12)| *x = f32::from(x_q12) * (1.0 / 4096.0));
+            });
+    }
+
+    /// Processes and quantizes the gains.
+    fn process_gains(&mut self, ctrl: &mut FrameControl, cond_coding: CondCoding) {
+        let mut gains_q16 = [0_i32; MAX_NB_SUBFR];
+        let nb_subfr = self.nb_subfr;
+
+        // Gain reduction when LTP coding gain is high.
+        if self.indices.signal_type == TYPE_VOICED {
+            let s = 1.0 - 0.5 * sigmoid(0.25 * (ctrl.lt_pred_cod_gain - 12.0));
+            ctrl.gains
+                .iter_mut()
+                .take(nb_subfr)
+                .for_each(|gain| *gain *= s);
+        }
+
+        // Limit the quantized signal.
+        let inv_max_sqr_val = (2f64.powf(f64::from(
+            0.33 * (21.0 - self.snr_db_q7 as f32 * (1.0 / 128.0)),
+        )) / self.subfr_length as f64) as f32;
+
+        ctrl.gains
+            .iter_mut()
+            .zip(ctrl.res_nrg.iter())
+            .take(nb_subfr)
+            .for_each(|(gain, &res_nrg)| {
+                // Soft limit on ratio residual energy and squared gains.
+                let g = (*gain * *gain + res_nrg * inv_max_sqr_val).sqrt();
+                *gain = f32::min(g, 32767.0);
+            });
+
+        // Prepare gains for noise shaping quantization.
+        gains_q16
+            .iter_mut()
+            .zip(ctrl.gains.iter())
+            .take(nb_subfr)
+            .for_each(|(gain_q16, &gain)| *gain_q16 = (gain * 65536.0) as i32);
+
+        // Save unquantized gains and gain index.
+        ctrl.gains_unq_q16 = gains_q16;
+        ctrl.last_gain_index_prev = self.shape.last_gain_index;
+
+        // Quantize gains.
+        gains_quant(
+            &mut self.indices.gains_indices,
+            &mut gains_q16,
+            &mut self.shape.last_gain_index,
+            cond_coding == CondCoding::Conditionally,
+            nb_subfr,
+        );
+
+        // Overwrite unquantized gains with quantized gains and convert back to Q0 from Q16.
+        ctrl.gains
+            .iter_mut()
+            .zip(gains_q16.iter())
+            .take(nb_subfr)
+            .for_each(|(gain, &gain_q16)| *gain = gain_q16 as f32 / 65536.0);
+
+        // Set quantizer offset for voiced signals. Larger offset when LTP coding gain is low or tilt is high (ie low-pass).
+        if self.indices.signal_type == TYPE_VOICED {
+            if ctrl.lt_pred_cod_gain + self.input_tilt_q15 as f32 * (1.0 / 32768.0) > 1.0 {
+                self.indices.quant_offset_type = 0;
+            } else {
+                self.indices.quant_offset_type = 1;
+            }
+        }
+
+        // Quantizer boundary adjustment.
+        let quant_offset = f32::from(
+            QUANTIZATION_OFFSETS_Q10[self.indices.signal_type >> 1][self.indices.quant_offset_type],
+        ) / 1024.0;
+        ctrl.lambda = LAMBDA_OFFSET
+            + LAMBDA_DELAYED_DECISIONS * self.n_states_delayed_decision as f32
+            + LAMBDA_SPEECH_ACT * self.speech_activity_q8 as f32 * (1.0 / 256.0)
+            + LAMBDA_INPUT_QUALITY * ctrl.input_quality
+            + LAMBDA_CODING_QUALITY * ctrl.coding_quality
+            + LAMBDA_QUANT_OFFSET * quant_offset;
+
+        debug_assert!(ctrl.lambda > 0.0 && ctrl.lambda < 2.0);
+    }
+
+    /// Converts the floating point parameters of a frame into the fixed-point noise shaping
+    /// quantization parameters.
+    fn nsq_parameters(&self, ctrl: &FrameControl) -> NsqParameters {
+        let mut params = NsqParameters {
+            ltp_mem_length: self.ltp_mem_length,
+            frame_length: self.frame_length,
+            subfr_length: self.subfr_length,
+            nb_subfr: self.nb_subfr,
+            predict_lpc_order: self.predict_lpc_order,
+            shaping_lpc_order: self.shaping_lpc_order,
+            warping_q16: self.warping_q16,
+            n_states_delayed_decision: self.n_states_delayed_decision,
+            pitch_l: ctrl.pitch_l,
+            ..Default::default()
+        };
+
+        // Noise shape parameters.
+        (0..self.nb_subfr).for_each(|i| {
+            let offset = i * MAX_SHAPE_LPC_ORDER;
+            (0..self.shaping_lpc_order).for_each(|j| {
+                params.ar_q13[offset + j] = float2int(ctrl.ar[offset + j] * 8192.0) as i16;
+            });
+
+            params.lf_shp_q14[i] = (float2int(ctrl.lf_ar_shp[i] * 16384.0) << 16)
+                | i32::from(float2int(ctrl.lf_ma_shp[i] * 16384.0) as u16);
+            params.tilt_q14[i] = float2int(ctrl.tilt[i] * 16384.0);
+            params.harm_shape_gain_q14[i] = float2int(ctrl.harm_shape_gain[i] * 16384.0);
+        });
+        params.lambda_q10 = float2int(ctrl.lambda * 1024.0);
+
+        // Prediction and coding parameters.
+        params
+            .ltp_coef_q14
+            .iter_mut()
+            .zip(ctrl.ltp_coef.iter())
+            .take(self.nb_subfr * LTP_ORDER)
+            .for_each(|(x_q14, &x)| *x_q14 = float2int(x * 16384.0) as i16);
+
+        params
+            .pred_coef_q12
+            .iter_mut()
+            .zip(ctrl.pred_coef.iter())
+            .for_each(|(pred_coef_q12, pred_coef)| {
+                pred_coef_q12
+                    .iter_mut()
+                    .zip(pred_coef.iter())
+                    .take(self.predict_lpc_order)
+                    .for_each(|(x_q12, &x)| *x_q12 = float2int(x * 4096.0) as i16);
+            });
+
+        params
+            .gains_q16
+            .iter_mut()
+            .zip(ctrl.gains.iter())
+            .take(self.nb_subfr)
+            .for_each(|(gain_q16, &gain)| *gain_q16 = float2int(gain * 65536.0));
+
+        if self.indices.signal_type == TYPE_VOICED {
+            params.ltp_scale_q14 =
+                i32::from(LTP_SCALES_TABLE_Q14[self.indices.ltp_scale_index as usize]);
+        } else {
+            params.ltp_scale_q14 = 0;
+        }
+
+        params
+    }
+
+    /// Converts the current frame of the analysis signal to fixed-point.
+    fn nsq_input(&self) -> [i16; MAX_FRAME_LENGTH] {
+        let mut x16 = [0_i16; MAX_FRAME_LENGTH];
+        let x_frame = self.ltp_mem_length;
+        x16.iter_mut()
+            .zip(self.x_buf[x_frame..x_frame + self.frame_length].iter())
+            .for_each(|(out, &x)| *out = float2int(x) as i16);
+        x16
+    }
+
+    /// Runs the noise shaping quantization of the current frame.
+    fn noise_shape_quantize(&mut self, ctrl: &FrameControl) {
+        let params = self.nsq_parameters(ctrl);
+        let x16 = self.nsq_input();
+
+        nsq_wrapper(
+            &mut self.nsq,
+            &params,
+            &mut self.indices,
+            &x16[..self.frame_length],
+            &mut self.pulses,
+        );
+    }
+
+    /// Encodes the current frame at a lower bitrate as LBRR data for the next packet.
+    ///
+    /// Reuses all parameters of the regular encoding but quantizes the excitation with higher gains.
+    fn lbrr_encode(&mut self, ctrl: &mut FrameControl, cond_coding: CondCoding) {
+        if !self.lbrr_enabled || self.speech_activity_q8 <= LBRR_SPEECH_ACTIVITY_THRES_Q8 {
+            return;
+        }
+
+        let n = self.n_frames_encoded;
+        self.lbrr_flags[n] = true;
+
+        // Copy noise shaping quantizer state and quantization indices from regular encoding.
+        let mut nsq_lbrr = self.nsq.clone();
+        let mut indices_lbrr = self.indices.clone();
+
+        // Save original gains.
+        let temp_gains = ctrl.gains;
+
+        if n == 0 || !self.lbrr_flags[n - 1] {
+            // First frame in packet or previous frame not LBRR coded.
+            self.lbrr_prev_last_gain_index = self.shape.last_gain_index;
+
+            // Increase gains to get target LBRR rate.
+            indices_lbrr.gains_indices[0] = i8::min(
+                indices_lbrr.gains_indices[0] + self.lbrr_gain_increases,
+                (N_LEVELS_QGAIN - 1) as i8,
+            );
+        }
+
+        // Decode to get gains in sync with decoder.
+        let mut gains_q16 = [0_i32; MAX_NB_SUBFR];
+        gains_dequant(
+            &mut gains_q16,
+            &indices_lbrr.gains_indices,
+            &mut self.lbrr_prev_last_gain_index,
+            cond_coding == CondCoding::Conditionally,
+            self.nb_subfr,
+        );
+
+        // Overwrite unquantized gains with quantized gains and convert back to Q0 from Q16.
+        ctrl.gains
+            .iter_mut()
+            .zip(gains_q16.iter())
+            .take(self.nb_subfr)
+            .for_each(|(gain, &gain_q16)| *gain = gain_q16 as f32 * (1.0 / 65536.0));
+
+        // Noise shaping quantization.
+        let params = self.nsq_parameters(ctrl);
+        let x16 = self.nsq_input();
+        nsq_wrapper(
+            &mut nsq_lbrr,
+            &params,
+            &mut indices_lbrr,
+            &x16[..self.frame_length],
+            &mut self.pulses_lbrr[n],
+        );
+        self.indices_lbrr[n] = indices_lbrr;
+
+        // Restore original gains.
+        ctrl.gains = temp_gains;
+    }
+
+    /// Encodes the side information parameters of the frame or of the LBRR frame `frame_index`.
+    fn encode_indices(
+        &mut self,
+        enc: &mut RangeEncoder,
+        frame_index: usize,
+        encode_lbrr: bool,
+        cond_coding: CondCoding,
+    ) -> Result<(), OpusError> {
+        let mut ec_ix = [0_i16; MAX_LPC_ORDER];
+        let mut pred_q8 = [0_u8; MAX_LPC_ORDER];
+        let indices = if encode_lbrr {
+            &self.indices_lbrr[frame_index]
+        } else {
+            &self.indices
+        };
+
+        // Encode signal type and quantizer offset.
+        let type_offset = 2 * indices.signal_type + indices.quant_offset_type;
+        debug_assert!(type_offset < 6);
+        debug_assert!(!encode_lbrr || type_offset >= 2);
+        if encode_lbrr || type_offset >= 2 {
+            enc.encode_icdf(type_offset - 2, TYPE_OFFSET_VAD_ICDF, 8)?;
+        } else {
+            enc.encode_icdf(type_offset, TYPE_OFFSET_NO_VAD_ICDF, 8)?;
+        }
+
+        // Encode gains.
+        // First subframe.
+        if cond_coding == CondCoding::Conditionally {
+            // Conditional coding.
+            enc.encode_icdf(indices.gains_indices[0] as usize, DELTA_GAIN_ICDF, 8)?;
+        } else {
+            // Independent coding, in two stages: MSB bits followed by 3 LSBs.
+            enc.encode_icdf(
+                (indices.gains_indices[0] >> 3) as usize,
+                &GAIN_ICDF[indices.signal_type],
+                8,
+            )?;
+            enc.encode_icdf((indices.gains_indices[0] & 7) as usize, UNIFORM8_ICDF, 8)?;
+        }
+
+        // Remaining subframes.
+        indices
+            .gains_indices
+            .iter()
+            .take(self.nb_subfr)
+            .skip(1)
+            .try_for_each(|&gain_index| enc.encode_icdf(gain_index as usize, DELTA_GAIN_ICDF, 8))?;
+
+        // Encode NLSFs.
+        let cb = self.nlsf_cb;
+        enc.encode_icdf(
+            indices.nlsf_indices[0] as usize,
+            &cb.cb1_icdf[(indices.signal_type >> 1) * cb.vectors..],
+            8,
+        )?;
+        nlsf_unpack(
+            &mut ec_ix,
+            &mut pred_q8,
+            cb,
+            indices.nlsf_indices[0] as usize,
+        );
+        debug_assert_eq!(cb.order, self.predict_lpc_order);
+        (0..cb.order).try_for_each(|i| {
+            let index = indices.nlsf_indices[i + 1];
+            let ec_icdf = &cb.ec_icdf[ec_ix[i] as usize..];
+            if index >= NLSF_QUANT_MAX_AMPLITUDE as i8 {
+                enc.encode_icdf(2 * NLSF_QUANT_MAX_AMPLITUDE, ec_icdf, 8)?;
+                enc.encode_icdf(
+                    (index - NLSF_QUANT_MAX_AMPLITUDE as i8) as usize,
+                    NLSF_EXT_ICDF,
+                    8,
+                )?;
+            } else if index <= -(NLSF_QUANT_MAX_AMPLITUDE as i8) {
+                enc.encode_icdf(0, ec_icdf, 8)?;
+                enc.encode_icdf(
+                    (-index - NLSF_QUANT_MAX_AMPLITUDE as i8) as usize,
+                    NLSF_EXT_ICDF,
+                    8,
+                )?;
+            } else {
+                enc.encode_icdf(
+                    (index + NLSF_QUANT_MAX_AMPLITUDE as i8) as usize,
+                    ec_icdf,
+                    8,
+                )?;
+            }
+            Ok(())
+        })?;
+
+        // Encode NLSF interpolation factor.
+        if self.nb_subfr == MAX_NB_SUBFR {
+            debug_assert!((0..5).contains(&indices.nlsf_interp_coef_q2));
+            enc.encode_icdf(
+                indices.nlsf_interp_coef_q2 as usize,
+                NLSF_INTERPOLATION_FACTOR_ICDF,
+                8,
+            )?;
+        }
+
+        if indices.signal_type == TYPE_VOICED {
+            // Encode pitch lags.
+            // Lag index.
+            let mut encode_absolute_lag_index = true;
+            if cond_coding == CondCoding::Conditionally && self.ec_prev_signal_type == TYPE_VOICED {
+                // Delta Encoding.
+                let mut delta_lag_index = indices.lag_index - self.ec_prev_lag_index;
+                if !(-8..=11).contains(&delta_lag_index) {
+                    delta_lag_index = 0;
+                } else {
+                    delta_lag_index += 9;
+                    encode_absolute_lag_index = false;
+                }
+                debug_assert!((0..21).contains(&delta_lag_index));
+                enc.encode_icdf(delta_lag_index as usize, PITCH_DELTA_ICDF, 8)?;
+            }
+            if encode_absolute_lag_index {
+                // Absolute encoding.
+                let half_fs_khz = (self.fs_khz >> 1) as i16;
+                let pitch_high_bits = indices.lag_index / half_fs_khz;
+                let pitch_low_bits = indices.lag_index - pitch_high_bits * half_fs_khz;
+                enc.encode_icdf(pitch_high_bits as usize, PITCH_LAG_ICDF, 8)?;
+                enc.encode_icdf(pitch_low_bits as usize, self.pitch_lag_low_bits_icdf, 8)?;
+            }
+            self.ec_prev_lag_index = indices.lag_index;
+
+            // Countour index.
+            enc.encode_icdf(indices.contour_index as usize, self.pitch_contour_icdf, 8)?;
+
+            // Encode LTP gains.
+            // PERIndex value.
+            enc.encode_icdf(indices.per_index as usize, LTP_PER_INDEX_ICDF, 8)?;
+
+            // Codebook indices.
+            let ltp_gain_icdf: &[u8] = match indices.per_index {
+                0 => LTP_GAIN_ICDF_0,
+                1 => LTP_GAIN_ICDF_1,
+                _ => LTP_GAIN_ICDF_2,
+            };
+            indices
+                .ltp_index
+                .iter()
+                .take(self.nb_subfr)
+                .try_for_each(|&ltp_index| enc.encode_icdf(ltp_index as usize, ltp_gain_icdf, 8))?;
+
+            // Encode LTP scaling.
+            if cond_coding == CondCoding::Independently {
+                debug_assert!((0..3).contains(&indices.ltp_scale_index));
+                enc.encode_icdf(indices.ltp_scale_index as usize, LTP_SCALE_ICDF, 8)?;
+            }
+        }
+
+        self.ec_prev_signal_type = indices.signal_type;
+
+        // Encode seed.
+        enc.encode_icdf(indices.seed as usize, UNIFORM4_ICDF, 8)?;
+
+        Ok(())
+    }
+}
+
+/// Runs the noise shaping quantizer that matches the complexity settings in `params`.
+fn nsq_wrapper(
+    nsq: &mut NsqState,
+    params: &NsqParameters,
+    indices: &mut SideInfoIndices,
+    x16: &[i16],
+    pulses: &mut [i8],
+) {
+    if params.n_states_delayed_decision > 1 || params.warping_q16 > 0 {
+        nsq.quantize_del_dec(params, indices, x16, pulses);
+    } else {
+        nsq.quantize(params, indices, x16, pulses);
+    }
+}
+
+/// Converts the AR filter coefficients to NLSFs.
+fn a2nlsf_flp(nlsf_q15: &mut [i16], a: &[f32]) {
+    let mut a_fix_q16 = [0_i32; MAX_LPC_ORDER];
+
+    a_fix_q16
+        .iter_mut()
+        .zip(a.iter())
+        .for_each(|(x_q16, &x)| *x_q16 = float2int(x * 65536.0));
+
+    a2nlsf(nlsf_q15, &mut a_fix_q16[..a.len()]);
+}
+
+/// Converts the NLSFs to AR filter coefficients.
+fn nlsf2a_flp(a: &mut [f32], nlsf_q15: &[i16]) {
+    let mut a_fix_q12 = [0_i16; MAX_LPC_ORDER];
+
+    nlsf2a(&mut a_fix_q12[..a.len()], nlsf_q15);
+
+    a.iter_mut()
+        .zip(a_fix_q12.iter())
+        .for_each(|(x, &x_q12)| *x = f32::from(x_q12) * (1.0 / 4096.0));
+}
+
+/// Computes the gain to make the warped filter coefficients have a zero mean log frequency response on a
+/// non-warped frequency scale. Note: this only holds for a minimum phase filter.
+fn warped_gain(coefs: &[f32], lambda: f32, order: usize) -> f32 {
+    let lambda = -lambda;
+    let mut gain = coefs[order - 1];
+    (0..order - 1).rev().for_each(|i| {
+        gain = lambda * gain + coefs[i];
+    });
+    1.0 / (1.0 - lambda * gain)
+}
+
+/// Converts warped filter coefficients to monic pseudo-warped coefficients and limits the maximum
+/// amplitude of the monic warped coefficients by using bandwidth expansion on the true coefficients.
+fn warped_true2monic_coefs(coefs: &mut [f32], lambda: f32, limit: f32) {
+    let order = coefs.len();
+
+    // Convert to monic coefficients.
+    (1..order).rev().for_each(|i| {
+        coefs[i - 1] -= lambda * coefs[i];
+    });
+    let mut gain = (1.0 - lambda * lambda) / (1.0 + lambda * coefs[0]);
+    coefs.iter_mut().for_each(|x| *x *= gain);
+
+    // Limit.
+    for iter in 0..10 {
+        // Find maximum absolute value.
+        let mut maxabs = -1.0;
+        let mut ind = 0;
+        coefs.iter().enumerate().for_each(|(i, &x)| {
+            let tmp = x.abs();
+            if tmp > maxabs {
+                maxabs = tmp;
+                ind = i;
+            }
+        });
+        if maxabs <= limit {
+            // Coefficients are within range - done.
+            return;
+        }
+
+        // Convert back to true warped coefficients.
+        (1..order).for_each(|i| {
+            coefs[i - 1] += lambda * coefs[i];
+        });
+        gain = 1.0 / gain;
+        coefs.iter_mut().for_each(|x| *x *= gain);
+
+        // Apply bandwidth expansion.
+        let chirp =
+            0.99 - (0.8 + 0.1 * iter as f32) * (maxabs - limit) / (maxabs * (ind + 1) as f32);
+        bwexpander(coefs, chirp);
+
+        // Convert to monic warped coefficients.
+        (1..order).rev().for_each(|i| {
+            coefs[i - 1] -= lambda * coefs[i];
+        });
+        gain = (1.0 - lambda * lambda) / (1.0 + lambda * coefs[0]);
+        coefs.iter_mut().for_each(|x| *x *= gain);
+    }
+}
+
+/// Limits the maximum amplitude of the coefficients by using bandwidth expansion.
+fn limit_coefs(coefs: &mut [f32], limit: f32) {
+    for iter in 0..10 {
+        // Find maximum absolute value.
+        let mut maxabs = -1.0;
+        let mut ind = 0;
+        coefs.iter().enumerate().for_each(|(i, &x)| {
+            let tmp = x.abs();
+            if tmp > maxabs {
+                maxabs = tmp;
+                ind = i;
+            }
+        });
+        if maxabs <= limit {
+            // Coefficients are within range - done.
+            return;
+        }
+
+        // Apply bandwidth expansion.
+        let chirp =
+            0.99 - (0.8 + 0.1 * iter as f32) * (maxabs - limit) / (maxabs * (ind + 1) as f32);
+        bwexpander(coefs, chirp);
+    }
+}
+
+/// Interpolates the filter taps of the transition low pass filter.
+fn lp_interpolate_filter_taps(
+    ind: usize,
+    fac_q16: i32,
+) -> ([i32; TRANSITION_NB], [i32; TRANSITION_NA]) {
+    if ind < TRANSITION_INT_NUM - 1 {
+        if fac_q16 > 0 {
+            let mut b_q28 = [0; TRANSITION_NB];
+            let mut a_q28 = [0; TRANSITION_NA];
+            if fac_q16 < 32768 {
+                // fac_q16 is in range of a 16-bit int.
+                // Piece-wise linear interpolation of B and A.
+                (0..TRANSITION_NB).for_each(|nb| {
+                    b_q28[nb] = smlawb(
+                        TRANSITION_LP_B_Q28[ind][nb],
+                        TRANSITION_LP_B_Q28[ind + 1][nb] - TRANSITION_LP_B_Q28[ind][nb],
+                        fac_q16,
+                    );
+                });
+                (0..TRANSITION_NA).for_each(|na| {
+                    a_q28[na] = smlawb(
+                        TRANSITION_LP_A_Q28[ind][na],
+                        TRANSITION_LP_A_Q28[ind + 1][na] - TRANSITION_LP_A_Q28[ind][na],
+                        fac_q16,
+                    );
+                });
+            } else {
+                // (fac_q16 - (1 << 16)) is in range of a 16-bit int.
+                (0..TRANSITION_NB).for_each(|nb| {
+                    b_q28[nb] = smlawb(
+                        TRANSITION_LP_B_Q28[ind + 1][nb],
+                        TRANSITION_LP_B_Q28[ind + 1][nb] - TRANSITION_LP_B_Q28[ind][nb],
+                        fac_q16 - (1 << 16),
+                    );
+                });
+                (0..TRANSITION_NA).for_each(|na| {
+                    a_q28[na] = smlawb(
+                        TRANSITION_LP_A_Q28[ind + 1][na],
+                        TRANSITION_LP_A_Q28[ind + 1][na] - TRANSITION_LP_A_Q28[ind][na],
+                        fac_q16 - (1 << 16),
+                    );
+                });
+            }
+            (b_q28, a_q28)
+        } else {
+            (TRANSITION_LP_B_Q28[ind], TRANSITION_LP_A_Q28[ind])
+        }
+    } else {
+        (
+            TRANSITION_LP_B_Q28[TRANSITION_INT_NUM - 1],
+            TRANSITION_LP_A_Q28[TRANSITION_INT_NUM - 1],
+        )
+    }
+}
+
+/// Low-pass filters the frame with a variable cutoff frequency, to smoothly
+/// switch between the internal sampling rates.
+fn lp_variable_cutoff(lp: &mut LpState, frame: &mut [i16]) {
+    debug_assert!((0..=TRANSITION_FRAMES).contains(&lp.transition_frame_no));
+
+    // Run filter if needed.
+    if lp.mode != 0 {
+        // Calculate index and interpolation factor for interpolation.
+        let mut fac_q16 = (TRANSITION_FRAMES - lp.transition_frame_no) << (16 - 6);
+        let ind = fac_q16 >> 16;
+        fac_q16 -= ind << 16;
+
+        debug_assert!(ind >= 0 && ind < TRANSITION_INT_NUM as i32);
+
+        // Interpolate filter coefficients.
+        let (b_q28, a_q28) = lp_interpolate_filter_taps(ind as usize, fac_q16);
+
+        // Update transition frame number for next frame.
+        lp.transition_frame_no = limit(lp.transition_frame_no + lp.mode, 0, TRANSITION_FRAMES);
+
+        // ARMA low-pass filtering.
+        biquad_alt_stride1(frame, &b_q28, &a_q28, &mut lp.in_lp_state);
+    }
+}
+
+/// Second order ARMA filter, alternative implementation. Filters the signal in place.
+fn biquad_alt_stride1(x: &mut [i16], b_q28: &[i32; 3], a_q28: &[i32; 2], s: &mut [i32; 2]) {
+    // Negate A_Q28 values and split in two parts.
+    let a0_l_q28 = (-a_q28[0]) & 0x0000_3FFF;
+    let a0_u_q28 = (-a_q28[0]) >> 14;
+    let a1_l_q28 = (-a_q28[1]) & 0x0000_3FFF;
+    let a1_u_q28 = (-a_q28[1]) >> 14;
+
+    x.iter_mut().for_each(|x| {
+        // S[ 0 ], S[ 1 ]: Q12.
+        let inval = i32::from(*x);
+        let out32_q14 = smlawb(s[0], b_q28[0], inval) << 2;
+
+        s[0] = s[1] + rshift_round(smulwb(out32_q14, a0_l_q28), 14);
+        s[0] = smlawb(s[0], out32_q14, a0_u_q28);
+        s[0] = smlawb(s[0], b_q28[1], inval);
+
+        s[1] = rshift_round(smulwb(out32_q14, a1_l_q28), 14);
+        s[1] = smlawb(s[1], out32_q14, a1_u_q28);
+        s[1] = smlawb(s[1], b_q28[2], inval);
+
+        // Scale back to Q0 and saturate.
+        *x = sat16((out32_q14 + (1 << 14) - 1) >> 14);
+    });
+}
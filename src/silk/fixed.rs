@@ -0,0 +1,355 @@
+//! Fixed-point arithmetic used by the Silk codec.
+//!
+//! Silk is specified in fixed-point arithmetic, so these helpers need to match the reference
+//! implementation bit by bit. Operations that are allowed to overflow in the reference wrap around.
+
+const RAND_MULTIPLIER: i32 = 196_314_165;
+const RAND_INCREMENT: i32 = 907_633_515;
+
+/// (a32 * (i32)((i16)b32)) >> 16
+#[inline(always)]
+pub(crate) fn smulwb(a32: i32, b32: i32) -> i32 {
+    ((i64::from(a32) * i64::from(b32 as i16)) >> 16) as i32
+}
+
+/// a32 + ((b32 * (i32)((i16)c32)) >> 16)
+#[inline(always)]
+pub(crate) fn smlawb(a32: i32, b32: i32, c32: i32) -> i32 {
+    a32.wrapping_add(smulwb(b32, c32))
+}
+
+/// (a32 * (b32 >> 16)) >> 16
+#[inline(always)]
+pub(crate) fn smulwt(a32: i32, b32: i32) -> i32 {
+    ((i64::from(a32) * i64::from(b32 >> 16)) >> 16) as i32
+}
+
+/// a32 + ((b32 * (c32 >> 16)) >> 16)
+#[inline(always)]
+pub(crate) fn smlawt(a32: i32, b32: i32, c32: i32) -> i32 {
+    a32.wrapping_add(smulwt(b32, c32))
+}
+
+/// (i32)((i16)a32) * (i32)((i16)b32)
+#[inline(always)]
+pub(crate) fn smulbb(a32: i32, b32: i32) -> i32 {
+    i32::from(a32 as i16) * i32::from(b32 as i16)
+}
+
+/// a32 + (i32)((i16)b32) * (i32)((i16)c32)
+#[inline(always)]
+pub(crate) fn smlabb(a32: i32, b32: i32, c32: i32) -> i32 {
+    a32.wrapping_add(smulbb(b32, c32))
+}
+
+/// (a32 >> 16) * (b32 >> 16)
+#[inline(always)]
+pub(crate) fn smultt(a32: i32, b32: i32) -> i32 {
+    (a32 >> 16) * (b32 >> 16)
+}
+
+/// (a32 * b32) >> 16
+#[inline(always)]
+pub(crate) fn smulww(a32: i32, b32: i32) -> i32 {
+    ((i64::from(a32) * i64::from(b32)) >> 16) as i32
+}
+
+/// a32 + ((b32 * c32) >> 16)
+#[inline(always)]
+pub(crate) fn smlaww(a32: i32, b32: i32, c32: i32) -> i32 {
+    a32.wrapping_add(smulww(b32, c32))
+}
+
+/// (a32 * b32) >> 32
+#[inline(always)]
+pub(crate) fn smmul(a32: i32, b32: i32) -> i32 {
+    ((i64::from(a32) * i64::from(b32)) >> 32) as i32
+}
+
+/// a32 + b32 * c32
+#[inline(always)]
+pub(crate) fn mla(a32: i32, b32: i32, c32: i32) -> i32 {
+    a32.wrapping_add(b32.wrapping_mul(c32))
+}
+
+/// Right shift with rounding.
+#[inline(always)]
+pub(crate) fn rshift_round(a: i32, shift: u32) -> i32 {
+    if shift == 1 {
+        (a >> 1) + (a & 1)
+    } else {
+        ((a >> (shift - 1)) + 1) >> 1
+    }
+}
+
+/// Right shift with rounding on 64 bit values.
+#[inline(always)]
+pub(crate) fn rshift_round64(a: i64, shift: u32) -> i64 {
+    if shift == 1 {
+        (a >> 1) + (a & 1)
+    } else {
+        ((a >> (shift - 1)) + 1) >> 1
+    }
+}
+
+/// Saturates to the i16 range.
+#[inline(always)]
+pub(crate) fn sat16(a: i32) -> i16 {
+    a.clamp(i32::from(i16::MIN), i32::from(i16::MAX)) as i16
+}
+
+/// Saturating addition of two i16 values.
+#[inline(always)]
+pub(crate) fn add_sat16(a: i16, b: i16) -> i16 {
+    sat16(i32::from(a) + i32::from(b))
+}
+
+/// Saturating addition of two i32 values.
+#[inline(always)]
+pub(crate) fn add_sat32(a: i32, b: i32) -> i32 {
+    a.saturating_add(b)
+}
+
+/// Saturating subtraction of two i32 values.
+#[inline(always)]
+pub(crate) fn sub_sat32(a: i32, b: i32) -> i32 {
+    a.saturating_sub(b)
+}
+
+/// Left shift with saturation.
+#[inline(always)]
+pub(crate) fn lshift_sat32(a: i32, shift: u32) -> i32 {
+    limit(a, i32::MIN >> shift, i32::MAX >> shift) << shift
+}
+
+/// Limits `a` to the range spanned by `limit1` and `limit2`, which may be given in any order.
+#[inline(always)]
+pub(crate) fn limit(a: i32, limit1: i32, limit2: i32) -> i32 {
+    if limit1 > limit2 {
+        if a > limit1 {
+            limit1
+        } else if a < limit2 {
+            limit2
+        } else {
+            a
+        }
+    } else if a > limit2 {
+        limit2
+    } else if a < limit1 {
+        limit1
+    } else {
+        a
+    }
+}
+
+/// Pseudo random number generator of the Silk codec.
+#[inline(always)]
+pub(crate) fn rand(seed: i32) -> i32 {
+    RAND_INCREMENT.wrapping_add(seed.wrapping_mul(RAND_MULTIPLIER))
+}
+
+/// Counts the leading zeros of a 32 bit value.
+#[inline(always)]
+pub(crate) fn clz32(a: i32) -> i32 {
+    a.leading_zeros() as i32
+}
+
+/// Returns the number of leading zeros and the 7 bits right after the leading one.
+#[inline(always)]
+pub(crate) fn clz_frac(a: i32) -> (i32, i32) {
+    let lz = clz32(a);
+    let frac_q7 = ror32(a, 24 - lz) & 0x7F;
+    (lz, frac_q7)
+}
+
+/// Rotates a 32 bit value to the right. Negative values rotate to the left.
+#[inline(always)]
+fn ror32(a: i32, rot: i32) -> i32 {
+    let x = a as u32;
+    if rot >= 0 {
+        x.rotate_right(rot as u32) as i32
+    } else {
+        x.rotate_left(-rot as u32) as i32
+    }
+}
+
+/// Approximation of the square root. Accurate to +/- 10% for outputs > 15
+/// and +/- 2.5% for outputs > 120.
+pub(crate) fn sqrt_approx(x: i32) -> i32 {
+    if x <= 0 {
+        return 0;
+    }
+
+    let (lz, frac_q7) = clz_frac(x);
+    let mut y = if lz & 1 != 0 { 32768 } else { 46214 };
+    y >>= lz >> 1;
+    smlawb(y, y, smulbb(213, frac_q7))
+}
+
+/// Computes the sum of squares of a vector together with the number of bits the energy
+/// needed to be shifted to the right to fit into an i32. Returns `(energy, shift)`.
+pub(crate) fn sum_sqr_shift(x: &[i16]) -> (i32, i32) {
+    let sum = |shift: i32| {
+        x.chunks(2).fold(0_u32, |nrg, pair| {
+            let nrg_tmp = pair.iter().fold(0_u32, |acc, &v| {
+                acc.wrapping_add(smulbb(i32::from(v), i32::from(v)) as u32)
+            });
+            nrg.wrapping_add(nrg_tmp >> shift)
+        })
+    };
+
+    // Do a first run with the maximum shift we could have.
+    let len = x.len() as i32;
+    let shift = 31 - clz32(len);
+
+    // Let's be conservative with rounding and start with nrg = len.
+    let nrg = (len as u32).wrapping_add(sum(shift)) as i32;
+
+    // Make sure the result will fit in a 32-bit signed integer with two bits of headroom.
+    let shift = i32::max(0, shift + 3 - clz32(nrg));
+    (sum(shift) as i32, shift)
+}
+
+/// Returns a good approximation of "(a32 << q_res) / b32".
+pub(crate) fn div32_varq(a32: i32, b32: i32, q_res: i32) -> i32 {
+    debug_assert!(b32 != 0);
+    debug_assert!(q_res >= 0);
+
+    // Compute number of bits head room and normalize inputs.
+    let a_headrm = clz32(a32.wrapping_abs()) - 1;
+    let mut a32_nrm = a32 << a_headrm;
+    let b_headrm = clz32(b32.wrapping_abs()) - 1;
+    let b32_nrm = b32 << b_headrm;
+
+    // Inverse of b32, with 14 bits of precision.
+    let b32_inv = (i32::MAX >> 2) / (b32_nrm >> 16);
+
+    // First approximation.
+    let mut result = smulwb(a32_nrm, b32_inv);
+
+    // Compute residual by subtracting product of denominator and first approximation.
+    // It's OK to overflow because the final value of a32_nrm should always be small.
+    a32_nrm = a32_nrm.wrapping_sub(smmul(b32_nrm, result).wrapping_shl(3));
+
+    // Refinement.
+    result = smlawb(result, a32_nrm, b32_inv);
+
+    // Convert to q_res domain.
+    let lshift = 29 + a_headrm - b_headrm - q_res;
+    if lshift < 0 {
+        lshift_sat32(result, -lshift as u32)
+    } else if lshift < 32 {
+        result >> lshift
+    } else {
+        0
+    }
+}
+
+/// Returns a good approximation of "(1 << q_res) / b32".
+pub(crate) fn inverse32_varq(b32: i32, q_res: i32) -> i32 {
+    debug_assert!(b32 != 0);
+    debug_assert!(q_res > 0);
+
+    // Compute number of bits head room and normalize input.
+    let b_headrm = clz32(b32.wrapping_abs()) - 1;
+    let b32_nrm = b32 << b_headrm;
+
+    // Inverse of b32, with 14 bits of precision.
+    let b32_inv = (i32::MAX >> 2) / (b32_nrm >> 16);
+
+    // First approximation.
+    let mut result = b32_inv << 16;
+
+    // Compute residual by subtracting product of denominator and first approximation from one.
+    let err_q32 = ((1 << 29) - smulwb(b32_nrm, b32_inv)) << 3;
+
+    // Refinement.
+    result = smlaww(result, err_q32, b32_inv);
+
+    // Convert to q_res domain.
+    let lshift = 61 - b_headrm - q_res;
+    if lshift <= 0 {
+        lshift_sat32(result, -lshift as u32)
+    } else if lshift < 32 {
+        result >> lshift
+    } else {
+        0
+    }
+}
+
+/// Approximation of 2^() (very close inverse of `lin2log()`).
+/// Converts a Q7 log value to a linear value.
+pub(crate) fn log2lin(in_log_q7: i32) -> i32 {
+    if in_log_q7 < 0 {
+        return 0;
+    } else if in_log_q7 >= 3967 {
+        return i32::MAX;
+    }
+
+    let out = 1 << (in_log_q7 >> 7);
+    let frac_q7 = in_log_q7 & 0x7F;
+    let frac = smlawb(frac_q7, smulbb(frac_q7, 128 - frac_q7), -174);
+    if in_log_q7 < 2048 {
+        // Piece-wise parabolic approximation.
+        out + ((out * frac) >> 7)
+    } else {
+        // Piece-wise parabolic approximation.
+        mla(out, out >> 7, frac)
+    }
+}
+
+/// Approximation of 128 * log2() (very close inverse of `log2lin()`).
+/// Converts a linear value to a Q7 log value.
+pub(crate) fn lin2log(in_lin: i32) -> i32 {
+    let (lz, frac_q7) = clz_frac(in_lin);
+
+    // Piece-wise parabolic approximation.
+    smlawb(frac_q7, frac_q7 * (128 - frac_q7), 179) + ((31 - lz) << 7)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sum_sqr_shift() {
+        let (energy, shift) = sum_sqr_shift(&[3, -4, 5]);
+        assert_eq!(energy, 50);
+        assert_eq!(shift, 0);
+
+        let x = [i16::MIN; 320];
+        let (energy, shift) = sum_sqr_shift(&x);
+        assert!(energy > 0);
+        assert_eq!(i64::from(energy) << shift, 320_i64 << 30);
+    }
+
+    #[test]
+    fn test_log2lin_lin2log() {
+        (1024..3967).step_by(7).for_each(|x| {
+            let y = lin2log(log2lin(x));
+            assert!((x - y).abs() <= 4, "{} != {}", x, y);
+        });
+    }
+
+    #[test]
+    fn test_inverse32_varq() {
+        [(1 << 16, 32), (1 << 10, 20), (12345, 40), (-777, 30)]
+            .iter()
+            .for_each(|&(b, q)| {
+                let expected = (1_i64 << q) / i64::from(b);
+                let result = i64::from(inverse32_varq(b, q));
+                assert!((expected - result).abs() <= 1 + (expected.abs() >> 12));
+            });
+    }
+
+    #[test]
+    fn test_div32_varq() {
+        [(1 << 20, 1 << 10, 0), (3000, 1000, 16), (-4567, 89, 10)]
+            .iter()
+            .for_each(|&(a, b, q)| {
+                let expected = (i64::from(a) << q) / i64::from(b);
+                let result = i64::from(div32_varq(a, b, q));
+                assert!((expected - result).abs() <= 1 + (expected.abs() >> 12));
+            });
+    }
+}
@@ -0,0 +1,576 @@
+//! Implements the floating point signal processing functions of the Silk encoder.
+
+use crate::silk::{MAX_FRAME_LENGTH, MAX_LPC_ORDER, MAX_NB_SUBFR};
+
+/// Maximal order of the linear prediction in the floating point functions.
+const MAX_ORDER_LPC: usize = 24;
+/// Maximal length of the signal of the modified Burg's method.
+const MAX_FRAME_SIZE: usize = 384;
+/// Maximal order of the noise shaping filters.
+pub(crate) const MAX_SHAPE_LPC_ORDER: usize = 24;
+/// Conditioning factor for the LPC analysis.
+pub(crate) const FIND_LPC_COND_FAC: f32 = 1e-5;
+
+/// Pi as used by the Silk encoder.
+pub(crate) const PI: f32 = std::f32::consts::PI;
+
+/// Converts a float to an integer, rounding to the nearest integer (ties to even).
+#[inline(always)]
+pub(crate) fn float2int(x: f32) -> i32 {
+    x.round_ties_even() as i32
+}
+
+/// Approximates the base 2 logarithm.
+#[inline(always)]
+#[allow(clippy::approx_constant)]
+pub(crate) fn log2(x: f64) -> f32 {
+    (3.321_928_094_887_36 * x.log10()) as f32
+}
+
+/// Approximates the sigmoid function.
+#[inline(always)]
+pub(crate) fn sigmoid(x: f32) -> f32 {
+    (1.0 / (1.0 + (-f64::from(x)).exp())) as f32
+}
+
+/// Sum of squares of a float array, with result as double.
+pub(crate) fn energy(data: &[f32]) -> f64 {
+    // 4x unrolled loop.
+    let mut chunks = data.chunks_exact(4);
+    let mut result = chunks.by_ref().fold(0.0_f64, |result, x| {
+        result
+            + (f64::from(x[0]) * f64::from(x[0])
+                + f64::from(x[1]) * f64::from(x[1])
+                + f64::from(x[2]) * f64::from(x[2])
+                + f64::from(x[3]) * f64::from(x[3]))
+    });
+
+    // Add any remaining products.
+    chunks
+        .remainder()
+        .iter()
+        .for_each(|x| result += f64::from(*x) * f64::from(*x));
+
+    result
+}
+
+/// Inner product of two float arrays, with result as double.
+pub(crate) fn inner_product(data1: &[f32], data2: &[f32], len: usize) -> f64 {
+    let data1 = &data1[..len];
+    let data2 = &data2[..len];
+
+    // 4x unrolled loop.
+    let mut chunks1 = data1.chunks_exact(4);
+    let mut chunks2 = data2.chunks_exact(4);
+    let mut result = chunks1
+        .by_ref()
+        .zip(chunks2.by_ref())
+        .fold(0.0_f64, |result, (x, y)| {
+            result
+                + (f64::from(x[0]) * f64::from(y[0])
+                    + f64::from(x[1]) * f64::from(y[1])
+                    + f64::from(x[2]) * f64::from(y[2])
+                    + f64::from(x[3]) * f64::from(y[3]))
+        });
+
+    // Add any remaining products.
+    chunks1
+        .remainder()
+        .iter()
+        .zip(chunks2.remainder().iter())
+        .for_each(|(x, y)| result += f64::from(*x) * f64::from(*y));
+
+    result
+}
+
+/// Computes the autocorrelation of `input` for `results.len()` lags.
+pub(crate) fn autocorrelation(results: &mut [f32], input: &[f32]) {
+    let correlation_count = usize::min(results.len(), input.len());
+    (0..correlation_count).for_each(|i| {
+        results[i] = inner_product(input, &input[i..], input.len() - i) as f32;
+    });
+}
+
+/// Computes the reflection coefficients from the autocorrelation sequence.
+///
+/// Returns the residual energy.
+pub(crate) fn schur(refl_coef: &mut [f32], auto_corr: &[f32], order: usize) -> f32 {
+    let mut c = [[0.0_f64; 2]; MAX_ORDER_LPC + 1];
+
+    // Copy correlations.
+    (0..=order).for_each(|k| {
+        c[k][0] = f64::from(auto_corr[k]);
+        c[k][1] = f64::from(auto_corr[k]);
+    });
+
+    (0..order).for_each(|k| {
+        // Get reflection coefficient.
+        let rc_tmp = -c[k + 1][0] / f64::max(c[0][1], f64::from(1e-9_f32));
+
+        // Save the output.
+        refl_coef[k] = rc_tmp as f32;
+
+        // Update correlations.
+        (0..order - k).for_each(|n| {
+            let ctmp1 = c[n + k + 1][0];
+            let ctmp2 = c[n][1];
+            c[n + k + 1][0] = ctmp1 + ctmp2 * rc_tmp;
+            c[n][1] = ctmp2 + ctmp1 * rc_tmp;
+        });
+    });
+
+    // Return residual energy.
+    c[0][1] as f32
+}
+
+/// Step up function, converts reflection coefficients to prediction coefficients.
+pub(crate) fn k2a(a: &mut [f32], rc: &[f32], order: usize) {
+    (0..order).for_each(|k| {
+        let rck = rc[k];
+        (0..(k + 1) >> 1).for_each(|n| {
+            let tmp1 = a[n];
+            let tmp2 = a[k - n - 1];
+            a[n] = tmp1 + tmp2 * rck;
+            a[k - n - 1] = tmp2 + tmp1 * rck;
+        });
+        a[k] = -rck;
+    });
+}
+
+/// Chirp (bandwidth expand) LP AR filter.
+pub(crate) fn bwexpander(ar: &mut [f32], chirp: f32) {
+    let d = ar.len();
+    let mut cfac = chirp;
+    ar[..d - 1].iter_mut().for_each(|x| {
+        *x *= cfac;
+        cfac *= chirp;
+    });
+    ar[d - 1] *= cfac;
+}
+
+/// The type of a sine window.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum SineWindow {
+    /// Sine window from 0 to pi/2.
+    Rising,
+    /// Sine window from pi/2 to pi.
+    Falling,
+}
+
+/// Applies a sine window to a signal vector. The length must be a multiple of 4.
+pub(crate) fn apply_sine_window(px_win: &mut [f32], px: &[f32], win_type: SineWindow) {
+    let length = px_win.len();
+    debug_assert_eq!(length & 3, 0);
+
+    let freq = PI / (length + 1) as f32;
+
+    // Approximation of 2 * cos(f).
+    let c = 2.0 - freq * freq;
+
+    let (mut s0, mut s1) = match win_type {
+        // Start from 0 and approximate sin(f).
+        SineWindow::Rising => (0.0, freq),
+        // Start from 1 and approximate cos(f).
+        SineWindow::Falling => (1.0, 0.5 * c),
+    };
+
+    // Uses the recursive equation: sin(n*f) = 2 * cos(f) * sin((n-1)*f) - sin((n-2)*f)
+    // 4 samples at a time.
+    px_win
+        .chunks_exact_mut(4)
+        .zip(px.chunks_exact(4))
+        .for_each(|(w, x)| {
+            w[0] = x[0] * 0.5 * (s0 + s1);
+            w[1] = x[1] * s1;
+            s0 = c * s1 - s0;
+            w[2] = x[2] * 0.5 * (s1 + s0);
+            w[3] = x[3] * s0;
+            s1 = c * s0 - s1;
+        });
+}
+
+/// LPC analysis filter. The filter always starts with zero state and
+/// the first `order` output samples are set to zero.
+pub(crate) fn lpc_analysis_filter(
+    r_lpc: &mut [f32],
+    pred_coef: &[f32],
+    s: &[f32],
+    length: usize,
+    order: usize,
+) {
+    debug_assert!(matches!(order, 6 | 8 | 10 | 12 | 16));
+    debug_assert!(order <= length);
+
+    (order..length).for_each(|ix| {
+        // Short-term prediction.
+        let lpc_pred = (0..order).fold(0.0_f32, |acc, k| acc + s[ix - 1 - k] * pred_coef[k]);
+
+        // Prediction error.
+        r_lpc[ix] = s[ix] - lpc_pred;
+    });
+
+    // Set first order output samples to zero.
+    r_lpc[..order].iter_mut().for_each(|x| *x = 0.0);
+}
+
+/// Computes the prediction coefficients from the input signal with the modified Burg's method.
+///
+/// `x` contains `nb_subfr` stacked subframes of length `subfr_length` (including the `d`
+/// preceding samples). Returns the residual energy.
+pub(crate) fn burg_modified(
+    a: &mut [f32],
+    x: &[f32],
+    min_inv_gain: f32,
+    subfr_length: usize,
+    nb_subfr: usize,
+    d: usize,
+) -> f32 {
+    debug_assert!(subfr_length * nb_subfr <= MAX_FRAME_SIZE);
+
+    let mut c_first_row = [0.0_f64; MAX_ORDER_LPC];
+    let mut caf = [0.0_f64; MAX_ORDER_LPC + 1];
+    let mut cab = [0.0_f64; MAX_ORDER_LPC + 1];
+    let mut af = [0.0_f64; MAX_ORDER_LPC];
+    let min_inv_gain = f64::from(min_inv_gain);
+
+    // Compute autocorrelations, added over subframes.
+    let mut c0 = energy(&x[..nb_subfr * subfr_length]);
+    (0..nb_subfr).for_each(|s| {
+        let x_ptr = &x[s * subfr_length..];
+        (1..d + 1).for_each(|n| {
+            c_first_row[n - 1] += inner_product(x_ptr, &x_ptr[n..], subfr_length - n);
+        });
+    });
+    let mut c_last_row = c_first_row;
+
+    // Initialize.
+    caf[0] = c0 + f64::from(FIND_LPC_COND_FAC) * c0 + f64::from(1e-9_f32);
+    cab[0] = caf[0];
+    let mut inv_gain = 1.0_f64;
+    let mut reached_max_gain = false;
+
+    for n in 0..d {
+        // Update first row of correlation matrix (without first element).
+        // Update last row of correlation matrix (without last element, stored in reversed order).
+        // Update C * Af.
+        // Update C * flipud(Af) (stored in reversed order).
+        (0..nb_subfr).for_each(|s| {
+            let x_ptr = &x[s * subfr_length..];
+            let mut tmp1 = f64::from(x_ptr[n]);
+            let mut tmp2 = f64::from(x_ptr[subfr_length - n - 1]);
+            (0..n).for_each(|k| {
+                c_first_row[k] -= f64::from(x_ptr[n] * x_ptr[n - k - 1]);
+                c_last_row[k] -=
+                    f64::from(x_ptr[subfr_length - n - 1] * x_ptr[subfr_length - n + k]);
+                let atmp = af[k];
+                tmp1 += f64::from(x_ptr[n - k - 1]) * atmp;
+                tmp2 += f64::from(x_ptr[subfr_length - n + k]) * atmp;
+            });
+            (0..=n).for_each(|k| {
+                caf[k] -= tmp1 * f64::from(x_ptr[n - k]);
+                cab[k] -= tmp2 * f64::from(x_ptr[subfr_length - n + k - 1]);
+            });
+        });
+
+        let mut tmp1 = c_first_row[n];
+        let mut tmp2 = c_last_row[n];
+        (0..n).for_each(|k| {
+            let atmp = af[k];
+            tmp1 += c_last_row[n - k - 1] * atmp;
+            tmp2 += c_first_row[n - k - 1] * atmp;
+        });
+        caf[n + 1] = tmp1;
+        cab[n + 1] = tmp2;
+
+        // Calculate nominator and denominator for the next order reflection (parcor) coefficient.
+        let mut num = cab[n + 1];
+        let mut nrg_b = cab[0];
+        let mut nrg_f = caf[0];
+        (0..n).for_each(|k| {
+            let atmp = af[k];
+            num += cab[n - k] * atmp;
+            nrg_b += cab[k + 1] * atmp;
+            nrg_f += caf[k + 1] * atmp;
+        });
+
+        // Calculate the next order reflection (parcor) coefficient.
+        let mut rc = -2.0 * num / (nrg_f + nrg_b);
+
+        // Update inverse prediction gain.
+        let tmp1 = inv_gain * (1.0 - rc * rc);
+        if tmp1 <= min_inv_gain {
+            // Max prediction gain exceeded; set reflection coefficient such that max prediction gain is exactly hit.
+            rc = (1.0 - min_inv_gain / inv_gain).sqrt();
+            if num > 0.0 {
+                // Ensure adjusted reflection coefficients has the original sign.
+                rc = -rc;
+            }
+            inv_gain = min_inv_gain;
+            reached_max_gain = true;
+        } else {
+            inv_gain = tmp1;
+        }
+
+        // Update the AR coefficients.
+        (0..(n + 1) >> 1).for_each(|k| {
+            let tmp1 = af[k];
+            let tmp2 = af[n - k - 1];
+            af[k] = tmp1 + rc * tmp2;
+            af[n - k - 1] = tmp2 + rc * tmp1;
+        });
+        af[n] = rc;
+
+        if reached_max_gain {
+            // Reached max prediction gain; set remaining coefficients to zero and exit loop.
+            af[n + 1..d].iter_mut().for_each(|x| *x = 0.0);
+            break;
+        }
+
+        // Update C * Af and C * Ab.
+        (0..=n + 1).for_each(|k| {
+            let tmp1 = caf[k];
+            caf[k] += rc * cab[n + 1 - k];
+            cab[n + 1 - k] += rc * tmp1;
+        });
+    }
+
+    let nrg_f = if reached_max_gain {
+        // Convert to float.
+        (0..d).for_each(|k| a[k] = (-af[k]) as f32);
+
+        // Subtract energy of preceding samples from C0.
+        (0..nb_subfr).for_each(|s| {
+            c0 -= energy(&x[s * subfr_length..s * subfr_length + d]);
+        });
+
+        // Approximate residual energy.
+        c0 * inv_gain
+    } else {
+        // Compute residual energy and store coefficients as float.
+        let mut nrg_f = caf[0];
+        let mut tmp1 = 1.0;
+        (0..d).for_each(|k| {
+            let atmp = af[k];
+            nrg_f += caf[k + 1] * atmp;
+            tmp1 += atmp * atmp;
+            a[k] = (-atmp) as f32;
+        });
+        nrg_f - f64::from(FIND_LPC_COND_FAC) * c0 * tmp1
+    };
+
+    // Return residual energy.
+    nrg_f as f32
+}
+
+/// Computes the autocorrelations for a warped frequency axis.
+pub(crate) fn warped_autocorrelation(corr: &mut [f32], input: &[f32], warping: f32, order: usize) {
+    debug_assert_eq!(order & 1, 0);
+
+    let mut state = [0.0_f64; MAX_SHAPE_LPC_ORDER + 1];
+    let mut c = [0.0_f64; MAX_SHAPE_LPC_ORDER + 1];
+    let warping = f64::from(warping);
+
+    // Loop over samples.
+    input.iter().for_each(|x| {
+        let mut tmp1 = f64::from(*x);
+
+        // Loop over allpass sections.
+        (0..order).step_by(2).for_each(|i| {
+            // Output of allpass section.
+            let tmp2 = state[i] + warping * (state[i + 1] - tmp1);
+            state[i] = tmp1;
+            c[i] += state[0] * tmp1;
+
+            // Output of allpass section.
+            tmp1 = state[i + 1] + warping * (state[i + 2] - tmp2);
+            state[i + 1] = tmp2;
+            c[i + 1] += state[0] * tmp2;
+        });
+        state[order] = tmp1;
+        c[order] += state[0] * tmp1;
+    });
+
+    // Copy correlations in float output format.
+    corr[..=order]
+        .iter_mut()
+        .zip(c.iter())
+        .for_each(|(corr, c)| *corr = *c as f32);
+}
+
+/// Calculates the correlation vector X'*t.
+///
+/// `x` holds `l + order - 1` samples used to create X.
+pub(crate) fn corr_vector(x: &[f32], t: &[f32], l: usize, order: usize, xt: &mut [f32]) {
+    // The column `lag` of X starts at `order - 1 - lag`.
+    (0..order).for_each(|lag| {
+        xt[lag] = inner_product(&x[order - 1 - lag..], t, l) as f32;
+    });
+}
+
+/// Calculates the correlation matrix X'*X of size `order x order`.
+///
+/// `x` holds `l + order - 1` samples used to create X.
+pub(crate) fn corr_matrix(x: &[f32], l: usize, order: usize, xx: &mut [f32]) {
+    // First sample of column 0 of X.
+    let p1 = order - 1;
+
+    // X[:,0]'*X[:,0]
+    let mut energy = energy(&x[p1..p1 + l]);
+    xx[0] = energy as f32;
+    (1..order).for_each(|j| {
+        // Calculate X[:,j]'*X[:,j].
+        energy += f64::from(x[p1 - j] * x[p1 - j] - x[p1 + l - j] * x[p1 + l - j]);
+        xx[j * order + j] = energy as f32;
+    });
+
+    (1..order).for_each(|lag| {
+        // First sample of column `lag` of X.
+        let p2 = order - 1 - lag;
+
+        // Calculate X[:,0]'*X[:,lag].
+        let mut energy = inner_product(&x[p1..], &x[p2..], l);
+        xx[lag * order] = energy as f32;
+        xx[lag] = energy as f32;
+
+        // Calculate X[:,j]'*X[:,j + lag].
+        (1..order - lag).for_each(|j| {
+            energy += f64::from(x[p1 - j] * x[p2 - j] - x[p1 + l - j] * x[p2 + l - j]);
+            xx[(lag + j) * order + j] = energy as f32;
+            xx[j * order + lag + j] = energy as f32;
+        });
+    });
+}
+
+/// Calculates the residual energies of the input subframes where all subframes
+/// have `lpc_order` of preceding samples.
+pub(crate) fn residual_energy(
+    nrgs: &mut [f32; MAX_NB_SUBFR],
+    x: &[f32],
+    a: &[[f32; MAX_LPC_ORDER]; 2],
+    gains: &[f32],
+    subfr_length: usize,
+    nb_subfr: usize,
+    lpc_order: usize,
+) {
+    let mut lpc_res = [0.0_f32; (MAX_FRAME_LENGTH + MAX_NB_SUBFR * MAX_LPC_ORDER) / 2];
+    let shift = lpc_order + subfr_length;
+
+    // Filter input to create the LPC residual for each frame half, and measure subframe energies.
+    (0..nb_subfr >> 1).for_each(|half| {
+        lpc_analysis_filter(
+            &mut lpc_res,
+            &a[half],
+            &x[2 * half * shift..],
+            2 * shift,
+            lpc_order,
+        );
+        (0..2).for_each(|i| {
+            let k = 2 * half + i;
+            let start = lpc_order + i * shift;
+            nrgs[k] = (f64::from(gains[k] * gains[k])
+                * energy(&lpc_res[start..start + subfr_length])) as f32;
+        });
+    });
+}
+
+/// Copies and multiplies a vector by a constant.
+pub(crate) fn scale_copy_vector(data_out: &mut [f32], data_in: &[f32], gain: f32) {
+    data_out
+        .iter_mut()
+        .zip(data_in.iter())
+        .for_each(|(out, x)| *out = gain * x);
+}
+
+/// Multiplies a vector by a constant.
+pub(crate) fn scale_vector(data: &mut [f32], gain: f32) {
+    data.iter_mut().for_each(|x| *x *= gain);
+}
+
+/// Insertion sort of the `k` largest values of `a` in decreasing order.
+///
+/// Writes the original indices of the sorted values into `idx`.
+pub(crate) fn insertion_sort_decreasing(a: &mut [f32], idx: &mut [usize], k: usize) {
+    let l = a.len();
+    debug_assert!(k > 0);
+    debug_assert!(l >= k);
+
+    // Write start indices in index vector.
+    idx[..k].iter_mut().enumerate().for_each(|(i, x)| *x = i);
+
+    // Sort vector elements by value, decreasing order.
+    (1..k).for_each(|i| {
+        let value = a[i];
+        let mut j = i;
+        while j > 0 && value > a[j - 1] {
+            // Shift value and index.
+            a[j] = a[j - 1];
+            idx[j] = idx[j - 1];
+            j -= 1;
+        }
+        a[j] = value;
+        idx[j] = i;
+    });
+
+    // If less than L values are asked check the remaining values,
+    // but only spend CPU to ensure that the K first values are correct.
+    (k..l).for_each(|i| {
+        let value = a[i];
+        if value > a[k - 1] {
+            let mut j = k - 1;
+            while j > 0 && value > a[j - 1] {
+                // Shift value and index.
+                a[j] = a[j - 1];
+                idx[j] = idx[j - 1];
+                j -= 1;
+            }
+            a[j] = value;
+            idx[j] = i;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_float2int() {
+        assert_eq!(float2int(0.5), 0);
+        assert_eq!(float2int(1.5), 2);
+        assert_eq!(float2int(-2.5), -2);
+        assert_eq!(float2int(2.6), 3);
+    }
+
+    #[test]
+    fn test_energy_and_inner_product() {
+        let x: Vec<f32> = (0..11).map(|i| i as f32 * 0.5).collect();
+        let expected: f64 = x.iter().map(|x| f64::from(*x) * f64::from(*x)).sum();
+        assert!((energy(&x) - expected).abs() < 1e-9);
+        assert!((inner_product(&x, &x, x.len()) - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_schur_k2a() {
+        // Autocorrelation of a first order AR process with coefficient 0.5.
+        let auto_corr = [1.0, 0.5, 0.25];
+        let mut rc = [0.0_f32; 2];
+        let nrg = schur(&mut rc, &auto_corr, 2);
+        assert!((rc[0] + 0.5).abs() < 1e-6);
+        assert!(rc[1].abs() < 1e-6);
+        assert!((nrg - 0.75).abs() < 1e-6);
+
+        let mut a = [0.0_f32; 2];
+        k2a(&mut a, &rc, 2);
+        assert!((a[0] - 0.5).abs() < 1e-6);
+        assert!(a[1].abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_insertion_sort_decreasing() {
+        let mut a = [0.1, 0.7, 0.3, 0.9, 0.5, 0.8];
+        let mut idx = [0_usize; 3];
+        insertion_sort_decreasing(&mut a, &mut idx, 3);
+        assert_eq!(&a[..3], &[0.9, 0.8, 0.7]);
+        assert_eq!(idx, [3, 5, 1]);
+    }
+}
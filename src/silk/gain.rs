@@ -0,0 +1,141 @@
+//! Implements the gain quantization.
+
+use crate::silk::fixed::{limit, lin2log, log2lin, smulwb};
+use crate::silk::{
+    MAX_DELTA_GAIN_QUANT, MAX_QGAIN_DB, MIN_DELTA_GAIN_QUANT, MIN_QGAIN_DB, N_LEVELS_QGAIN,
+};
+
+const OFFSET: i32 = (MIN_QGAIN_DB * 128) / 6 + 16 * 128;
+const SCALE_Q16: i32 = (65536 * (N_LEVELS_QGAIN - 1)) / (((MAX_QGAIN_DB - MIN_QGAIN_DB) * 128) / 6);
+const INV_SCALE_Q16: i32 = ((65536_i64 * (((MAX_QGAIN_DB - MIN_QGAIN_DB) * 128) / 6) as i64)
+    / (N_LEVELS_QGAIN - 1) as i64) as i32;
+
+/// Gain scalar quantization with hysteresis, uniform on log scale.
+pub(crate) fn gains_quant(
+    ind: &mut [i8],
+    gain_q16: &mut [i32],
+    prev_ind: &mut i8,
+    conditional: bool,
+    nb_subfr: usize,
+) {
+    (0..nb_subfr).for_each(|k| {
+        let mut prev = i32::from(*prev_ind);
+
+        // Convert to log scale, scale, floor().
+        let mut index = i32::from(smulwb(SCALE_Q16, lin2log(gain_q16[k]) - OFFSET) as i8);
+
+        // Round towards previous quantized gain (hysteresis).
+        if index < prev {
+            index += 1;
+        }
+        index = limit(index, 0, N_LEVELS_QGAIN - 1);
+
+        // Compute delta indices and limit.
+        if k == 0 && !conditional {
+            // Full index.
+            index = limit(index, prev + MIN_DELTA_GAIN_QUANT, N_LEVELS_QGAIN - 1);
+            prev = index;
+        } else {
+            // Delta index.
+            index -= prev;
+
+            // Double the quantization step size for large gain increases,
+            // so that the max gain level can be reached.
+            let double_step_size_threshold = 2 * MAX_DELTA_GAIN_QUANT - N_LEVELS_QGAIN + prev;
+            if index > double_step_size_threshold {
+                index =
+                    double_step_size_threshold + ((index - double_step_size_threshold + 1) >> 1);
+            }
+            index = limit(index, MIN_DELTA_GAIN_QUANT, MAX_DELTA_GAIN_QUANT);
+
+            // Accumulate deltas.
+            if index > double_step_size_threshold {
+                prev += (index << 1) - double_step_size_threshold;
+                prev = i32::min(prev, N_LEVELS_QGAIN - 1);
+            } else {
+                prev += index;
+            }
+
+            // Shift to make non-negative.
+            index -= MIN_DELTA_GAIN_QUANT;
+        }
+        ind[k] = index as i8;
+        *prev_ind = prev as i8;
+
+        // Scale and convert to linear scale.
+        gain_q16[k] = log2lin(i32::min(smulwb(INV_SCALE_Q16, prev) + OFFSET, 3967));
+    });
+}
+
+/// Gains scalar dequantization, uniform on log scale.
+pub(crate) fn gains_dequant(
+    gain_q16: &mut [i32],
+    ind: &[i8],
+    prev_ind: &mut i8,
+    conditional: bool,
+    nb_subfr: usize,
+) {
+    (0..nb_subfr).for_each(|k| {
+        let mut prev = i32::from(*prev_ind);
+        if k == 0 && !conditional {
+            // Gain index is not allowed to go down more than 16 steps (~21.8 dB).
+            prev = i32::max(i32::from(ind[k]), prev - 16);
+        } else {
+            // Delta index.
+            let ind_tmp = i32::from(ind[k]) + MIN_DELTA_GAIN_QUANT;
+
+            // Accumulate deltas.
+            let double_step_size_threshold = 2 * MAX_DELTA_GAIN_QUANT - N_LEVELS_QGAIN + prev;
+            if ind_tmp > double_step_size_threshold {
+                prev += (ind_tmp << 1) - double_step_size_threshold;
+            } else {
+                prev += ind_tmp;
+            }
+        }
+        prev = limit(prev, 0, N_LEVELS_QGAIN - 1);
+        *prev_ind = prev as i8;
+
+        // Scale and convert to linear scale.
+        gain_q16[k] = log2lin(i32::min(smulwb(INV_SCALE_Q16, prev) + OFFSET, 3967));
+    });
+}
+
+/// Computes a unique identifier of a gain indices vector.
+pub(crate) fn gains_id(ind: &[i8], nb_subfr: usize) -> i32 {
+    ind.iter()
+        .take(nb_subfr)
+        .fold(0, |id, &x| i32::from(x).wrapping_add(id << 8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gains_dequant() {
+        let mut gain_q16 = [0_i32; 4];
+        let mut prev_ind = 10_i8;
+        gains_dequant(&mut gain_q16, &[40, 4, 8, 0], &mut prev_ind, false, 4);
+
+        // A delta index of 4 keeps the gain constant.
+        assert_eq!(gain_q16[0], gain_q16[1]);
+        assert!(gain_q16[2] > gain_q16[1]);
+        assert!(gain_q16[3] < gain_q16[2]);
+        assert_eq!(prev_ind, 40);
+    }
+
+    #[test]
+    fn test_gains_quant_roundtrip() {
+        let mut gain_q16 = [1 << 20, 1 << 21, 3 << 19, 1 << 16];
+        let mut ind = [0_i8; 4];
+        let mut prev_ind = 10_i8;
+        gains_quant(&mut ind, &mut gain_q16, &mut prev_ind, false, 4);
+
+        let mut dequant_q16 = [0_i32; 4];
+        let mut dequant_prev_ind = 10_i8;
+        gains_dequant(&mut dequant_q16, &ind, &mut dequant_prev_ind, false, 4);
+
+        assert_eq!(gain_q16, dequant_q16);
+        assert_eq!(prev_ind, dequant_prev_ind);
+    }
+}
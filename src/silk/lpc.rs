@@ -0,0 +1,241 @@
+//! Implements the linear prediction helpers.
+
+use crate::silk::fixed::{
+    clz32, inverse32_varq, rshift_round, rshift_round64, sat16, smlabb, smmul, smulww, sub_sat32,
+};
+use crate::silk::{MAX_LPC_ORDER, MAX_PREDICTION_POWER_GAIN};
+
+const QA: u32 = 24;
+const A_LIMIT: i32 = ((0.99975 * (1 << QA) as f64) + 0.5) as i32;
+
+/// Chirp (bandwidth expand) a LP AR filter.
+pub(crate) fn bwexpander(ar: &mut [i16], mut chirp_q16: i32) {
+    let d = ar.len();
+    let chirp_minus_one_q16 = chirp_q16 - 65536;
+
+    ar.iter_mut().take(d - 1).for_each(|x| {
+        *x = rshift_round(chirp_q16 * i32::from(*x), 16) as i16;
+        chirp_q16 += rshift_round(chirp_q16 * chirp_minus_one_q16, 16);
+    });
+    ar[d - 1] = rshift_round(chirp_q16 * i32::from(ar[d - 1]), 16) as i16;
+}
+
+/// Chirp (bandwidth expand) a LP AR filter with 32 bit coefficients.
+pub(crate) fn bwexpander_32(ar: &mut [i32], mut chirp_q16: i32) {
+    let d = ar.len();
+    let chirp_minus_one_q16 = chirp_q16 - 65536;
+
+    ar.iter_mut().take(d - 1).for_each(|x| {
+        *x = smulww(chirp_q16, *x);
+        chirp_q16 += rshift_round(chirp_q16 * chirp_minus_one_q16, 16);
+    });
+    ar[d - 1] = smulww(chirp_q16, ar[d - 1]);
+}
+
+/// Converts the i32 coefficients to i16 coefficients and makes sure there's no wrap-around.
+pub(crate) fn lpc_fit(a_qout: &mut [i16], a_qin: &mut [i32], qout: u32, qin: u32) {
+    let d = a_qout.len();
+    let mut idx = 0;
+    let mut fitted = false;
+
+    // Limit the maximum absolute value of the prediction coefficients, so that they'll fit in i16.
+    for _ in 0..10 {
+        // Find maximum absolute value and its index.
+        let mut maxabs = 0;
+        a_qin.iter().take(d).enumerate().for_each(|(k, x)| {
+            let absval = x.wrapping_abs();
+            if absval > maxabs {
+                maxabs = absval;
+                idx = k;
+            }
+        });
+        maxabs = rshift_round(maxabs, qin - qout);
+
+        if maxabs > i32::from(i16::MAX) {
+            // Reduce magnitude of prediction coefficients.
+            // ( i32::MAX >> 14 ) + i16::MAX = 163838
+            maxabs = i32::min(maxabs, 163838);
+            let chirp_q16 =
+                65470 - ((maxabs - i32::from(i16::MAX)) << 14) / ((maxabs * (idx as i32 + 1)) >> 2);
+            bwexpander_32(&mut a_qin[..d], chirp_q16);
+        } else {
+            fitted = true;
+            break;
+        }
+    }
+
+    if fitted {
+        a_qout
+            .iter_mut()
+            .zip(a_qin.iter())
+            .for_each(|(out, x)| *out = rshift_round(*x, qin - qout) as i16);
+    } else {
+        // Reached the last iteration, clip the coefficients.
+        a_qout
+            .iter_mut()
+            .zip(a_qin.iter_mut())
+            .for_each(|(out, x)| {
+                *out = sat16(rshift_round(*x, qin - qout));
+                *x = i32::from(*out) << (qin - qout);
+            });
+    }
+}
+
+/// Computes the inverse of the LPC prediction gain and tests if the LPC coefficients are stable
+/// (all poles within unit circle).
+///
+/// Returns the inverse prediction gain in energy domain, Q30 or 0 if unstable.
+pub(crate) fn lpc_inverse_pred_gain(a_q12: &[i16]) -> i32 {
+    let mut atmp_qa = [0_i32; MAX_LPC_ORDER];
+    let mut dc_resp = 0;
+
+    a_q12.iter().zip(atmp_qa.iter_mut()).for_each(|(a, x)| {
+        dc_resp += i32::from(*a);
+        *x = i32::from(*a) << (QA - 12);
+    });
+
+    // If the DC is unstable, we don't even need to do the full calculations.
+    if dc_resp >= 4096 {
+        return 0;
+    }
+
+    lpc_inverse_pred_gain_qa(&mut atmp_qa[..a_q12.len()])
+}
+
+fn mul32_frac_q(a32: i32, b32: i32, q: u32) -> i32 {
+    rshift_round64(i64::from(a32) * i64::from(b32), q) as i32
+}
+
+fn lpc_inverse_pred_gain_qa(a_qa: &mut [i32]) -> i32 {
+    const ONE_Q30: i32 = 1 << 30;
+    let min_inv_gain_q30 = ((1.0 / MAX_PREDICTION_POWER_GAIN) * (1_i64 << 30) as f32 + 0.5) as i32;
+
+    let order = a_qa.len();
+    let mut inv_gain_q30 = ONE_Q30;
+
+    for k in (1..order).rev() {
+        // Check for stability.
+        if a_qa[k] > A_LIMIT || a_qa[k] < -A_LIMIT {
+            return 0;
+        }
+
+        // Set RC equal to negated AR coef.
+        let rc_q31 = -(a_qa[k] << (31 - QA));
+
+        // rc_mult1_Q30 range: [ 1 : 2^30 ]
+        let rc_mult1_q30 = ONE_Q30 - smmul(rc_q31, rc_q31);
+        debug_assert!(rc_mult1_q30 > (1 << 15));
+        debug_assert!(rc_mult1_q30 <= (1 << 30));
+
+        // Update inverse gain, inv_gain_q30 range: [ 0 : 2^30 ]
+        inv_gain_q30 = smmul(inv_gain_q30, rc_mult1_q30) << 2;
+        debug_assert!(inv_gain_q30 >= 0);
+        debug_assert!(inv_gain_q30 <= (1 << 30));
+        if inv_gain_q30 < min_inv_gain_q30 {
+            return 0;
+        }
+
+        // rc_mult2 range: [ 2^30 : i32::MAX ]
+        let mult2q = 32 - clz32(rc_mult1_q30.abs());
+        let rc_mult2 = inverse32_varq(rc_mult1_q30, mult2q + 30);
+
+        // Update AR coefficients.
+        for n in 0..(k + 1) >> 1 {
+            let tmp1 = a_qa[n];
+            let tmp2 = a_qa[k - n - 1];
+
+            let tmp64 = rshift_round64(
+                i64::from(sub_sat32(tmp1, mul32_frac_q(tmp2, rc_q31, 31))) * i64::from(rc_mult2),
+                mult2q as u32,
+            );
+            if tmp64 > i64::from(i32::MAX) || tmp64 < i64::from(i32::MIN) {
+                return 0;
+            }
+            a_qa[n] = tmp64 as i32;
+
+            let tmp64 = rshift_round64(
+                i64::from(sub_sat32(tmp2, mul32_frac_q(tmp1, rc_q31, 31))) * i64::from(rc_mult2),
+                mult2q as u32,
+            );
+            if tmp64 > i64::from(i32::MAX) || tmp64 < i64::from(i32::MIN) {
+                return 0;
+            }
+            a_qa[k - n - 1] = tmp64 as i32;
+        }
+    }
+
+    // Check for stability.
+    if a_qa[0] > A_LIMIT || a_qa[0] < -A_LIMIT {
+        return 0;
+    }
+
+    // Set RC equal to negated AR coef.
+    let rc_q31 = -(a_qa[0] << (31 - QA));
+
+    // Range: [ 1 : 2^30 ]
+    let rc_mult1_q30 = ONE_Q30 - smmul(rc_q31, rc_q31);
+
+    // Update inverse gain, range: [ 0 : 2^30 ]
+    inv_gain_q30 = smmul(inv_gain_q30, rc_mult1_q30) << 2;
+    debug_assert!(inv_gain_q30 >= 0);
+    debug_assert!(inv_gain_q30 <= (1 << 30));
+    if inv_gain_q30 < min_inv_gain_q30 {
+        return 0;
+    }
+
+    inv_gain_q30
+}
+
+/// LPC analysis filter.
+///
+/// The first `d` output samples are set to zero.
+pub(crate) fn lpc_analysis_filter(out: &mut [i16], input: &[i16], b: &[i16], len: usize, d: usize) {
+    debug_assert!(d >= 6);
+    debug_assert!(d & 1 == 0);
+    debug_assert!(d <= len);
+
+    (d..len).for_each(|ix| {
+        // Allowing wrap around so that two wraps can cancel each other. The rare
+        // cases where the result wraps around can only be triggered by invalid streams.
+        let mut out32_q12 = 0_i32;
+        (0..d).for_each(|j| {
+            out32_q12 = smlabb(out32_q12, i32::from(input[ix - 1 - j]), i32::from(b[j]));
+        });
+
+        // Subtract prediction.
+        out32_q12 = (i32::from(input[ix]) << 12).wrapping_sub(out32_q12);
+
+        // Scale to Q0 and saturate.
+        out[ix] = sat16(rshift_round(out32_q12, 12));
+    });
+
+    // Set first d output samples to zero.
+    out[..d].iter_mut().for_each(|x| *x = 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bwexpander() {
+        let mut ar = [4096_i16, 2048, 1024, 512];
+        bwexpander(&mut ar, 1 << 16);
+        assert_eq!(ar, [4096, 2048, 1024, 512]);
+
+        bwexpander(&mut ar, 1 << 15);
+        assert_eq!(ar, [2048, 512, 128, 32]);
+    }
+
+    #[test]
+    fn test_lpc_inverse_pred_gain() {
+        // A single pole at 0.5 has a prediction gain of 1 / (1 - 0.5^2).
+        let a_q12 = [2048_i16, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let inv_gain_q30 = lpc_inverse_pred_gain(&a_q12);
+        assert_eq!(inv_gain_q30, 3 << 28);
+
+        // Unstable filter.
+        let a_q12 = [4096_i16, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(lpc_inverse_pred_gain(&a_q12), 0);
+    }
+}
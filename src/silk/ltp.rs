@@ -0,0 +1,308 @@
+//! Implements the long-term prediction analysis and quantization of the encoder.
+
+use crate::silk::fixed::{lin2log, log2lin, mla, smlawb, smulbb};
+use crate::silk::float::{corr_matrix, corr_vector, energy, float2int, scale_vector};
+use crate::silk::tables::{
+    LTP_GAIN_BITS_Q5_0, LTP_GAIN_BITS_Q5_1, LTP_GAIN_BITS_Q5_2, LTP_GAIN_VQ_0, LTP_GAIN_VQ_0_GAIN,
+    LTP_GAIN_VQ_1, LTP_GAIN_VQ_1_GAIN, LTP_GAIN_VQ_2, LTP_GAIN_VQ_2_GAIN,
+};
+use crate::silk::{LTP_ORDER, MAX_NB_SUBFR};
+
+/// Maximal cumulative prediction gain of the LTP filters in dB.
+const MAX_SUM_LOG_GAIN_DB: f32 = 250.0;
+/// Regularization of the LTP correlations.
+const LTP_CORR_INV_MAX: f32 = 0.03;
+
+/// Returns the codebook, the effective gains and the code lengths of a periodicity index.
+fn ltp_codebook(k: usize) -> (&'static [[i8; LTP_ORDER]], &'static [u8], &'static [u8]) {
+    match k {
+        0 => (LTP_GAIN_VQ_0, LTP_GAIN_VQ_0_GAIN, LTP_GAIN_BITS_Q5_0),
+        1 => (LTP_GAIN_VQ_1, LTP_GAIN_VQ_1_GAIN, LTP_GAIN_BITS_Q5_1),
+        _ => (LTP_GAIN_VQ_2, LTP_GAIN_VQ_2_GAIN, LTP_GAIN_BITS_Q5_2),
+    }
+}
+
+/// Result of the entropy constrained matrix-weighted VQ.
+struct VqResult {
+    /// Index of the best codebook vector.
+    ind: i8,
+    /// Best residual energy.
+    res_nrg_q15: i32,
+    /// Best total bitrate.
+    rate_dist_q8: i32,
+    /// Sum of absolute LTP coefficients.
+    gain_q7: i32,
+}
+
+/// Entropy constrained matrix-weighted VQ, hard-coded to 5-element vectors,
+/// for a single input data vector.
+fn vq_wmat_ec(
+    xx_q17: &[i32],
+    x_x_q17: &[i32],
+    cb_q7: &[[i8; LTP_ORDER]],
+    cb_gain_q7: &[u8],
+    cl_q5: &[u8],
+    subfr_len: usize,
+    max_gain_q7: i32,
+) -> VqResult {
+    // Negate and convert to new Q domain.
+    let mut neg_x_x_q24 = [0_i32; LTP_ORDER];
+    neg_x_x_q24
+        .iter_mut()
+        .zip(x_x_q17.iter())
+        .for_each(|(out, &x)| *out = (x << 7).wrapping_neg());
+
+    // In case things go really bad, at least the index is set to something safe.
+    let mut result = VqResult {
+        ind: 0,
+        res_nrg_q15: i32::MAX,
+        rate_dist_q8: i32::MAX,
+        gain_q7: 0,
+    };
+
+    // Loop over codebook.
+    cb_q7
+        .iter()
+        .zip(cb_gain_q7.iter())
+        .zip(cl_q5.iter())
+        .enumerate()
+        .for_each(|(k, ((cb_row_q7, &gain_tmp_q7), &cl_q5))| {
+            let cb_row_q7 = cb_row_q7.map(i32::from);
+            let gain_tmp_q7 = i32::from(gain_tmp_q7);
+
+            // Weighted rate.
+            // Quantization error: 1 - 2 * xX * cb + cb' * XX * cb.
+            let mut sum1_q15 = (1.001 * 32768.0 + 0.5) as i32;
+
+            // Penalty for too large gain.
+            let penalty = i32::max(gain_tmp_q7 - max_gain_q7, 0) << 11;
+
+            // Upper triangle of the matrix, row by row.
+            (0..LTP_ORDER).for_each(|i| {
+                let mut sum2_q24 = neg_x_x_q24[i];
+                (i + 1..LTP_ORDER).for_each(|j| {
+                    sum2_q24 = mla(sum2_q24, xx_q17[i * LTP_ORDER + j], cb_row_q7[j]);
+                });
+                sum2_q24 <<= 1;
+                sum2_q24 = mla(sum2_q24, xx_q17[i * LTP_ORDER + i], cb_row_q7[i]);
+                sum1_q15 = smlawb(sum1_q15, sum2_q24, cb_row_q7[i]);
+            });
+
+            // Find best.
+            if sum1_q15 >= 0 {
+                // Translate residual energy to bits using high-rate assumption
+                // (6 dB ==> 1 bit/sample).
+                let bits_res_q8 = smulbb(subfr_len as i32, lin2log(sum1_q15 + penalty) - (15 << 7));
+                // Reduce the codelength component by half; seems to slightly improve quality.
+                let bits_tot_q8 = bits_res_q8 + (i32::from(cl_q5) << (3 - 1));
+                if bits_tot_q8 <= result.rate_dist_q8 {
+                    result.rate_dist_q8 = bits_tot_q8;
+                    result.res_nrg_q15 = sum1_q15 + penalty;
+                    result.ind = k as i8;
+                    result.gain_q7 = gain_tmp_q7;
+                }
+            }
+        });
+
+    result
+}
+
+/// Adds two positive values and saturates on overflow.
+#[inline(always)]
+fn add_pos_sat32(a: i32, b: i32) -> i32 {
+    let sum = (a as u32).wrapping_add(b as u32);
+    if sum & 0x8000_0000 != 0 {
+        i32::MAX
+    } else {
+        sum as i32
+    }
+}
+
+/// Quantizes the LTP gains. Returns the LTP prediction gain in dB, Q7.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn quant_ltp_gains(
+    b_q14: &mut [i16; MAX_NB_SUBFR * LTP_ORDER],
+    cbk_index: &mut [i8; MAX_NB_SUBFR],
+    periodicity_index: &mut i8,
+    sum_log_gain_q7: &mut i32,
+    xx_q17: &[i32],
+    x_x_q17: &[i32],
+    subfr_len: usize,
+    nb_subfr: usize,
+) -> i32 {
+    let mut temp_idx = [0_i8; MAX_NB_SUBFR];
+    let mut min_rate_dist_q7 = i32::MAX;
+    let mut best_sum_log_gain_q7 = 0;
+    let mut res_nrg_q15 = 0;
+
+    // Iterate over different codebooks with different rates/distortions, and choose best.
+    (0..3).for_each(|k| {
+        // Safety margin for pitch gain control, to take into account factors
+        // such as state rescaling/rewhitening.
+        let gain_safety = (0.4 * 128.0 + 0.5) as i32;
+        let (cbk_q7, cbk_gain_q7, cl_q5) = ltp_codebook(k);
+
+        let mut res_nrg_q15_cbk = 0;
+        let mut rate_dist_q7 = 0;
+        let mut sum_log_gain_tmp_q7 = *sum_log_gain_q7;
+        (0..nb_subfr).for_each(|j| {
+            let max_gain_q7 = log2lin(
+                ((MAX_SUM_LOG_GAIN_DB / 6.0) * 128.0 + 0.5) as i32 - sum_log_gain_tmp_q7 + (7 << 7),
+            ) - gain_safety;
+
+            let vq = vq_wmat_ec(
+                &xx_q17[j * LTP_ORDER * LTP_ORDER..],
+                &x_x_q17[j * LTP_ORDER..],
+                cbk_q7,
+                cbk_gain_q7,
+                cl_q5,
+                subfr_len,
+                max_gain_q7,
+            );
+            temp_idx[j] = vq.ind;
+
+            res_nrg_q15_cbk = add_pos_sat32(res_nrg_q15_cbk, vq.res_nrg_q15);
+            rate_dist_q7 = add_pos_sat32(rate_dist_q7, vq.rate_dist_q8);
+            sum_log_gain_tmp_q7 = i32::max(
+                0,
+                sum_log_gain_tmp_q7 + lin2log(gain_safety + vq.gain_q7) - (7 << 7),
+            );
+        });
+
+        if rate_dist_q7 <= min_rate_dist_q7 {
+            min_rate_dist_q7 = rate_dist_q7;
+            *periodicity_index = k as i8;
+            cbk_index[..nb_subfr].copy_from_slice(&temp_idx[..nb_subfr]);
+            best_sum_log_gain_q7 = sum_log_gain_tmp_q7;
+        }
+        // The residual energy is taken from the last searched codebook.
+        res_nrg_q15 = res_nrg_q15_cbk;
+    });
+
+    let (cbk_q7, _, _) = ltp_codebook(*periodicity_index as usize);
+    (0..nb_subfr).for_each(|j| {
+        let row = &cbk_q7[cbk_index[j] as usize];
+        (0..LTP_ORDER).for_each(|k| {
+            b_q14[j * LTP_ORDER + k] = i16::from(row[k]) << 7;
+        });
+    });
+
+    if nb_subfr == 2 {
+        res_nrg_q15 >>= 1;
+    } else {
+        res_nrg_q15 >>= 2;
+    }
+
+    *sum_log_gain_q7 = best_sum_log_gain_q7;
+    smulbb(-3, lin2log(res_nrg_q15) - (15 << 7))
+}
+
+/// Quantizes the floating point LTP gains. Returns the LTP prediction gain in dB.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn quant_ltp_gains_flp(
+    b: &mut [f32; MAX_NB_SUBFR * LTP_ORDER],
+    cbk_index: &mut [i8; MAX_NB_SUBFR],
+    periodicity_index: &mut i8,
+    sum_log_gain_q7: &mut i32,
+    xx: &[f32],
+    x_x: &[f32],
+    subfr_len: usize,
+    nb_subfr: usize,
+) -> f32 {
+    let mut b_q14 = [0_i16; MAX_NB_SUBFR * LTP_ORDER];
+    let mut xx_q17 = [0_i32; MAX_NB_SUBFR * LTP_ORDER * LTP_ORDER];
+    let mut x_x_q17 = [0_i32; MAX_NB_SUBFR * LTP_ORDER];
+
+    xx_q17
+        .iter_mut()
+        .zip(xx.iter())
+        .take(nb_subfr * LTP_ORDER * LTP_ORDER)
+        .for_each(|(out, &x)| *out = float2int(x * 131_072.0));
+    x_x_q17
+        .iter_mut()
+        .zip(x_x.iter())
+        .take(nb_subfr * LTP_ORDER)
+        .for_each(|(out, &x)| *out = float2int(x * 131_072.0));
+
+    let pred_gain_db_q7 = quant_ltp_gains(
+        &mut b_q14,
+        cbk_index,
+        periodicity_index,
+        sum_log_gain_q7,
+        &xx_q17,
+        &x_x_q17,
+        subfr_len,
+        nb_subfr,
+    );
+
+    b.iter_mut()
+        .zip(b_q14.iter())
+        .take(nb_subfr * LTP_ORDER)
+        .for_each(|(b, &b_q14)| *b = f32::from(b_q14) * (1.0 / 16384.0));
+
+    pred_gain_db_q7 as f32 * (1.0 / 128.0)
+}
+
+/// Calculates the correlation matrices and vectors used for the LTP quantization.
+///
+/// The residual of the first subframe starts at `r[r_offset]`, the preceding samples are used
+/// as the LTP state.
+pub(crate) fn find_ltp(
+    xx: &mut [f32; MAX_NB_SUBFR * LTP_ORDER * LTP_ORDER],
+    x_x: &mut [f32; MAX_NB_SUBFR * LTP_ORDER],
+    r: &[f32],
+    r_offset: usize,
+    lag: &[i32; MAX_NB_SUBFR],
+    subfr_length: usize,
+    nb_subfr: usize,
+) {
+    (0..nb_subfr).for_each(|k| {
+        let r_ptr = r_offset + k * subfr_length;
+        let lag_ptr = r_ptr - (lag[k] as usize + LTP_ORDER / 2);
+        let xx = &mut xx[k * LTP_ORDER * LTP_ORDER..(k + 1) * LTP_ORDER * LTP_ORDER];
+        let x_x = &mut x_x[k * LTP_ORDER..(k + 1) * LTP_ORDER];
+
+        corr_matrix(&r[lag_ptr..], subfr_length, LTP_ORDER, xx);
+        corr_vector(&r[lag_ptr..], &r[r_ptr..], subfr_length, LTP_ORDER, x_x);
+        let energy = energy(&r[r_ptr..r_ptr + subfr_length + LTP_ORDER]) as f32;
+        let temp = 1.0 / f32::max(energy, LTP_CORR_INV_MAX * 0.5 * (xx[0] + xx[24]) + 1.0);
+        scale_vector(xx, temp);
+        scale_vector(x_x, temp);
+    });
+}
+
+/// LTP analysis filter.
+///
+/// The first subframe of the input starts at `x[x_offset]`, the output holds
+/// `nb_subfr * (pre_length + subfr_length)` samples.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn ltp_analysis_filter(
+    ltp_res: &mut [f32],
+    x: &[f32],
+    x_offset: usize,
+    b: &[f32; MAX_NB_SUBFR * LTP_ORDER],
+    pitch_l: &[i32; MAX_NB_SUBFR],
+    inv_gains: &[f32; MAX_NB_SUBFR],
+    subfr_length: usize,
+    nb_subfr: usize,
+    pre_length: usize,
+) {
+    (0..nb_subfr).for_each(|k| {
+        let x_ptr = x_offset + k * subfr_length;
+        let x_lag_ptr = x_ptr - pitch_l[k] as usize;
+        let inv_gain = inv_gains[k];
+        let b_tmp = &b[k * LTP_ORDER..(k + 1) * LTP_ORDER];
+        let out = &mut ltp_res[k * (subfr_length + pre_length)..];
+
+        // LTP analysis FIR filter.
+        (0..subfr_length + pre_length).for_each(|i| {
+            let mut res = x[x_ptr + i];
+
+            // Subtract long-term prediction.
+            b_tmp.iter().enumerate().for_each(|(j, &b)| {
+                res -= b * x[x_lag_ptr + i + LTP_ORDER / 2 - j];
+            });
+            out[i] = res * inv_gain;
+        });
+    });
+}
@@ -1,3 +1,118 @@
 pub(crate) use decoder::{LostFlag, SilkDecoder};
+pub(crate) use encoder::{EncoderControl, Prefill, SilkEncoder};
 
 mod decoder;
+mod encoder;
+pub(crate) mod fixed;
+mod float;
+mod gain;
+mod lpc;
+mod ltp;
+mod nlsf;
+mod nsq;
+mod pitch;
+mod pulses;
+mod resampler;
+mod stereo;
+mod tables;
+mod vad;
+
+/// Maximum number of subframes of a frame.
+pub(crate) const MAX_NB_SUBFR: usize = 4;
+/// Maximum number of frames in a packet.
+pub(crate) const MAX_FRAMES_PER_PACKET: usize = 3;
+/// Length of a subframe in ms.
+pub(crate) const SUB_FRAME_LENGTH_MS: usize = 5;
+/// Maximal sampling frequency of the internal signal in kHz.
+pub(crate) const MAX_FS_KHZ: usize = 16;
+/// Maximal sampling frequency of the API in kHz.
+pub(crate) const MAX_API_FS_KHZ: usize = 48;
+/// Maximal length of a subframe.
+pub(crate) const MAX_SUB_FRAME_LENGTH: usize = SUB_FRAME_LENGTH_MS * MAX_FS_KHZ;
+/// Maximal length of a frame.
+pub(crate) const MAX_FRAME_LENGTH: usize = MAX_SUB_FRAME_LENGTH * MAX_NB_SUBFR;
+/// Length of the LTP memory in ms.
+pub(crate) const LTP_MEM_LENGTH_MS: usize = 20;
+/// Interpolation length of the stereo prediction in ms.
+pub(crate) const STEREO_INTERP_LEN_MS: usize = 8;
+/// Look-ahead for noise shape analysis in ms.
+pub(crate) const LA_SHAPE_MS: usize = 5;
+
+/// Minimal order of the linear prediction.
+pub(crate) const MIN_LPC_ORDER: usize = 10;
+/// Maximal order of the linear prediction.
+pub(crate) const MAX_LPC_ORDER: usize = 16;
+/// Order of the long term prediction.
+pub(crate) const LTP_ORDER: usize = 5;
+/// Maximal amount of iterations to stabilize the LPC coefficients.
+pub(crate) const MAX_LPC_STABILIZE_ITERATIONS: usize = 16;
+/// Maximal prediction power gain of the LPC filter.
+pub(crate) const MAX_PREDICTION_POWER_GAIN: f32 = 1e4;
+/// Bandwidth expansion of the LPC coefficients after a packet loss.
+pub(crate) const BWE_AFTER_LOSS_Q16: i32 = 63570;
+
+/// Maximal amplitude of the NLSF residuals.
+pub(crate) const NLSF_QUANT_MAX_AMPLITUDE: usize = 4;
+/// Adjustment of the NLSF quantization levels (0.1 in Q10).
+pub(crate) const NLSF_QUANT_LEVEL_ADJ_Q10: i32 = 102;
+
+/// Number of gain quantization levels.
+pub(crate) const N_LEVELS_QGAIN: i32 = 64;
+/// Minimal delta of a gain index.
+pub(crate) const MIN_DELTA_GAIN_QUANT: i32 = -4;
+/// Maximal delta of a gain index.
+pub(crate) const MAX_DELTA_GAIN_QUANT: i32 = 36;
+/// Minimal quantized gain in dB.
+pub(crate) const MIN_QGAIN_DB: i32 = 2;
+/// Maximal quantized gain in dB.
+pub(crate) const MAX_QGAIN_DB: i32 = 88;
+
+/// Number of pulses of a shell code frame.
+pub(crate) const SHELL_CODEC_FRAME_LENGTH: usize = 16;
+/// Maximal number of shell blocks of a frame.
+pub(crate) const MAX_NB_SHELL_BLOCKS: usize = MAX_FRAME_LENGTH / SHELL_CODEC_FRAME_LENGTH;
+/// Number of rate levels of the pulse coding.
+pub(crate) const N_RATE_LEVELS: usize = 10;
+/// Maximal number of pulses per shell block.
+pub(crate) const SILK_MAX_PULSES: usize = 16;
+/// Adjustment of the quantization levels of the excitation in Q10.
+pub(crate) const QUANT_LEVEL_ADJUST_Q10: i32 = 80;
+
+/// Number of consecutive inactive frames before DTX starts.
+pub(crate) const NB_SPEECH_FRAMES_BEFORE_DTX: i32 = 10;
+/// Maximal number of consecutive DTX frames.
+pub(crate) const MAX_CONSECUTIVE_DTX: i32 = 20;
+
+/// Signal type without voice activity.
+pub(crate) const TYPE_NO_VOICE_ACTIVITY: usize = 0;
+/// Unvoiced signal type.
+pub(crate) const TYPE_UNVOICED: usize = 1;
+/// Voiced signal type.
+pub(crate) const TYPE_VOICED: usize = 2;
+
+/// The type of conditional coding used by a frame.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum CondCoding {
+    /// Independent coding.
+    Independently,
+    /// Independent coding without LTP scaling.
+    IndependentlyNoLtpScaling,
+    /// Conditional coding, based on the previous frame.
+    Conditionally,
+}
+
+/// The quantization indices of the side information of a frame.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SideInfoIndices {
+    pub(crate) gains_indices: [i8; MAX_NB_SUBFR],
+    pub(crate) ltp_index: [i8; MAX_NB_SUBFR],
+    pub(crate) nlsf_indices: [i8; MAX_LPC_ORDER + 1],
+    pub(crate) lag_index: i16,
+    pub(crate) contour_index: i8,
+    pub(crate) signal_type: usize,
+    pub(crate) quant_offset_type: usize,
+    pub(crate) nlsf_interp_coef_q2: i8,
+    pub(crate) per_index: i8,
+    pub(crate) ltp_scale_index: i8,
+    pub(crate) seed: i8,
+}
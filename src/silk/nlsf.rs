@@ -0,0 +1,879 @@
+//! Implements the normalized line spectral frequencies.
+
+use crate::silk::fixed::{
+    add_sat16, div32_varq, limit, lin2log, mla, rshift_round, rshift_round64, smlabb, smlawb,
+    smlaww, smulbb,
+};
+use crate::silk::lpc::{bwexpander_32, lpc_fit, lpc_inverse_pred_gain};
+use crate::silk::tables::{NlsfCodebook, LSF_COS_TAB_FIX_Q12};
+use crate::silk::{
+    MAX_LPC_ORDER, MAX_LPC_STABILIZE_ITERATIONS, NLSF_QUANT_LEVEL_ADJ_Q10, NLSF_QUANT_MAX_AMPLITUDE,
+};
+
+const QA: u32 = 16;
+const MAX_LOOPS: usize = 20;
+const NLSF_W_Q: u32 = 2;
+const NLSF_QUANT_MAX_AMPLITUDE_EXT: i32 = 10;
+const NLSF_QUANT_DEL_DEC_STATES_LOG2: usize = 2;
+const NLSF_QUANT_DEL_DEC_STATES: usize = 1 << NLSF_QUANT_DEL_DEC_STATES_LOG2;
+const BIN_DIV_STEPS_A2NLSF: i32 = 3;
+const MAX_ITERATIONS_A2NLSF: i32 = 16;
+const LSF_COS_TAB_SZ: usize = 128;
+
+/// This ordering was found to maximize quality. It improves numerical accuracy of
+/// `nlsf2a_find_poly()` compared to "standard" ordering.
+const ORDERING16: &[usize; 16] = &[0, 15, 8, 7, 4, 11, 12, 3, 2, 13, 10, 5, 6, 9, 14, 1];
+const ORDERING10: &[usize; 10] = &[0, 9, 6, 3, 4, 5, 8, 1, 2, 7];
+
+/// Unpacks the predictor values and indices for the entropy coding tables.
+pub(crate) fn nlsf_unpack(
+    ec_ix: &mut [i16; MAX_LPC_ORDER],
+    pred_q8: &mut [u8; MAX_LPC_ORDER],
+    cb: &NlsfCodebook,
+    cb1_index: usize,
+) {
+    let order = cb.order;
+    let ec_sel = &cb.ec_sel[cb1_index * order / 2..];
+
+    (0..order).step_by(2).for_each(|i| {
+        let entry = ec_sel[i / 2] as usize;
+        ec_ix[i] = (((entry >> 1) & 7) * (2 * NLSF_QUANT_MAX_AMPLITUDE + 1)) as i16;
+        pred_q8[i] = cb.pred_q8[i + (entry & 1) * (order - 1)];
+        ec_ix[i + 1] = (((entry >> 5) & 7) * (2 * NLSF_QUANT_MAX_AMPLITUDE + 1)) as i16;
+        pred_q8[i + 1] = cb.pred_q8[i + ((entry >> 4) & 1) * (order - 1) + 1];
+    });
+}
+
+/// Predictive dequantizer for the NLSF residuals.
+fn nlsf_residual_dequant(
+    x_q10: &mut [i16; MAX_LPC_ORDER],
+    indices: &[i8],
+    pred_coef_q8: &[u8; MAX_LPC_ORDER],
+    quant_step_size_q16: i32,
+    order: usize,
+) {
+    let mut out_q10 = 0_i32;
+    (0..order).rev().for_each(|i| {
+        let pred_q10 = smulbb(out_q10, i32::from(pred_coef_q8[i])) >> 8;
+        out_q10 = i32::from(indices[i]) << 10;
+        if out_q10 > 0 {
+            out_q10 -= NLSF_QUANT_LEVEL_ADJ_Q10;
+        } else if out_q10 < 0 {
+            out_q10 += NLSF_QUANT_LEVEL_ADJ_Q10;
+        }
+        out_q10 = smlawb(pred_q10, out_q10, quant_step_size_q16);
+        x_q10[i] = out_q10 as i16;
+    });
+}
+
+/// Decodes the NLSF vector from the codebook path vector.
+pub(crate) fn nlsf_decode(nlsf_q15: &mut [i16], nlsf_indices: &[i8], cb: &NlsfCodebook) {
+    let mut pred_q8 = [0_u8; MAX_LPC_ORDER];
+    let mut ec_ix = [0_i16; MAX_LPC_ORDER];
+    let mut res_q10 = [0_i16; MAX_LPC_ORDER];
+
+    let order = cb.order;
+    let cb1_index = nlsf_indices[0] as usize;
+
+    // Unpack entropy table indices and predictor for current CB1 index.
+    nlsf_unpack(&mut ec_ix, &mut pred_q8, cb, cb1_index);
+
+    // Predictive residual dequantizer.
+    nlsf_residual_dequant(
+        &mut res_q10,
+        &nlsf_indices[1..],
+        &pred_q8,
+        cb.quant_step_size_q16,
+        order,
+    );
+
+    // Apply inverse square-rooted weights to first stage and add to output.
+    let cb_element = &cb.cb1_nlsf_q8[cb1_index * order..];
+    let cb_wght_q9 = &cb.cb1_wght_q9[cb1_index * order..];
+    (0..order).for_each(|i| {
+        let nlsf_q15_tmp = ((i32::from(res_q10[i]) << 14) / i32::from(cb_wght_q9[i]))
+            + (i32::from(cb_element[i]) << 7);
+        nlsf_q15[i] = limit(nlsf_q15_tmp, 0, 32767) as i16;
+    });
+
+    // NLSF stabilization.
+    nlsf_stabilize(&mut nlsf_q15[..order], cb.delta_min_q15);
+}
+
+/// NLSF stabilizer:
+///
+/// - Moves NLSFs further apart if they are too close
+/// - Moves NLSFs away from borders if they are too close
+/// - High effort to achieve a modification with minimum Euclidean distance to input vector
+/// - Output are sorted NLSF coefficients
+pub(crate) fn nlsf_stabilize(nlsf_q15: &mut [i16], delta_min_q15: &[i16]) {
+    let l = nlsf_q15.len();
+
+    // This is necessary to ensure an output within range of a i16.
+    debug_assert!(delta_min_q15[l] >= 1);
+
+    for _ in 0..MAX_LOOPS {
+        // Find smallest distance.
+
+        // First element.
+        let mut min_diff_q15 = i32::from(nlsf_q15[0]) - i32::from(delta_min_q15[0]);
+        let mut min_i = 0;
+
+        // Middle elements.
+        (1..l).for_each(|i| {
+            let diff_q15 =
+                i32::from(nlsf_q15[i]) - (i32::from(nlsf_q15[i - 1]) + i32::from(delta_min_q15[i]));
+            if diff_q15 < min_diff_q15 {
+                min_diff_q15 = diff_q15;
+                min_i = i;
+            }
+        });
+
+        // Last element.
+        let diff_q15 = (1 << 15) - (i32::from(nlsf_q15[l - 1]) + i32::from(delta_min_q15[l]));
+        if diff_q15 < min_diff_q15 {
+            min_diff_q15 = diff_q15;
+            min_i = l;
+        }
+
+        // Now check if the smallest distance non-negative.
+        if min_diff_q15 >= 0 {
+            return;
+        }
+
+        if min_i == 0 {
+            // Move away from lower limit.
+            nlsf_q15[0] = delta_min_q15[0];
+        } else if min_i == l {
+            // Move away from higher limit.
+            nlsf_q15[l - 1] = ((1 << 15) - i32::from(delta_min_q15[l])) as i16;
+        } else {
+            // Find the lower extreme for the location of the current center frequency.
+            let mut min_center_q15 = 0;
+            (0..min_i).for_each(|k| {
+                min_center_q15 += i32::from(delta_min_q15[k]);
+            });
+            min_center_q15 += i32::from(delta_min_q15[min_i]) >> 1;
+
+            // Find the upper extreme for the location of the current center frequency.
+            let mut max_center_q15 = 1 << 15;
+            ((min_i + 1)..=l).for_each(|k| {
+                max_center_q15 -= i32::from(delta_min_q15[k]);
+            });
+            max_center_q15 -= i32::from(delta_min_q15[min_i]) >> 1;
+
+            // Move apart, sorted by value, keeping the same center frequency.
+            let center_freq_q15 = limit(
+                rshift_round(
+                    i32::from(nlsf_q15[min_i - 1]) + i32::from(nlsf_q15[min_i]),
+                    1,
+                ),
+                min_center_q15,
+                max_center_q15,
+            ) as i16;
+            nlsf_q15[min_i - 1] = center_freq_q15 - (delta_min_q15[min_i] >> 1);
+            nlsf_q15[min_i] = nlsf_q15[min_i - 1] + delta_min_q15[min_i];
+        }
+    }
+
+    // Safe and simple fall back method, which is less ideal than the above.
+
+    // Insertion sort (fast for already almost sorted arrays).
+    insertion_sort_increasing_all_values(nlsf_q15);
+
+    // First NLSF should be no less than NDeltaMin[0].
+    nlsf_q15[0] = i16::max(nlsf_q15[0], delta_min_q15[0]);
+
+    // Keep delta_min distance between the NLSFs.
+    (1..l).for_each(|i| {
+        nlsf_q15[i] = i16::max(nlsf_q15[i], add_sat16(nlsf_q15[i - 1], delta_min_q15[i]));
+    });
+
+    // Last NLSF should be no higher than 1 - NDeltaMin[L].
+    nlsf_q15[l - 1] = i32::min(
+        i32::from(nlsf_q15[l - 1]),
+        (1 << 15) - i32::from(delta_min_q15[l]),
+    ) as i16;
+
+    // Keep NDeltaMin distance between the NLSFs.
+    (0..l - 1).rev().for_each(|i| {
+        nlsf_q15[i] = i32::min(
+            i32::from(nlsf_q15[i]),
+            i32::from(nlsf_q15[i + 1]) - i32::from(delta_min_q15[i + 1]),
+        ) as i16;
+    });
+}
+
+/// Sorts the values in increasing order.
+fn insertion_sort_increasing_all_values(a: &mut [i16]) {
+    (1..a.len()).for_each(|i| {
+        let value = a[i];
+        let mut j = i;
+        while j > 0 && value < a[j - 1] {
+            a[j] = a[j - 1];
+            j -= 1;
+        }
+        a[j] = value;
+    });
+}
+
+/// Sorts the first `k` values in increasing order and writes their original position to `idx`.
+///
+/// Only the first `k` values are guaranteed to be correct.
+fn insertion_sort_increasing(a: &mut [i32], idx: &mut [usize], k: usize) {
+    debug_assert!(k > 0 && a.len() >= k);
+
+    // Write start indices in index vector.
+    (0..k).for_each(|i| idx[i] = i);
+
+    // Sort vector elements by value, increasing order.
+    (1..k).for_each(|i| {
+        let value = a[i];
+        let mut j = i;
+        while j > 0 && value < a[j - 1] {
+            a[j] = a[j - 1];
+            idx[j] = idx[j - 1];
+            j -= 1;
+        }
+        a[j] = value;
+        idx[j] = i;
+    });
+
+    // If less than L values are asked for, check the remaining values,
+    // but only spend CPU to ensure that the K first values are correct.
+    (k..a.len()).for_each(|i| {
+        let value = a[i];
+        if value < a[k - 1] {
+            let mut j = k - 1;
+            while j > 0 && value < a[j - 1] {
+                a[j] = a[j - 1];
+                idx[j] = idx[j - 1];
+                j -= 1;
+            }
+            a[j] = value;
+            idx[j] = i;
+        }
+    });
+}
+
+/// Interpolates two vectors with an interpolation factor in Q2 (weight on the second vector).
+pub(crate) fn interpolate(xi: &mut [i16], x0: &[i16], x1: &[i16], ifact_q2: i32) {
+    debug_assert!((0..=4).contains(&ifact_q2));
+    xi.iter_mut()
+        .zip(x0.iter().zip(x1.iter()))
+        .for_each(|(xi, (&x0, &x1))| {
+            *xi = (i32::from(x0) + (smulbb(i32::from(x1) - i32::from(x0), ifact_q2) >> 2)) as i16;
+        });
+}
+
+/// Laroia low complexity NLSF weights.
+///
+/// R. Laroia, N. Phamdo and N. Farvardin, "Robust and Efficient Quantization of Speech LSP
+/// Parameters Using Structured Vector Quantization", Proc. IEEE Int. Conf. Acoust., Speech,
+/// Signal Processing, pp. 641-644, 1991.
+pub(crate) fn nlsf_vq_weights_laroia(nlsfw_q_out: &mut [i16], nlsf_q15: &[i16]) {
+    let d = nlsf_q15.len();
+    debug_assert!(d > 0 && d & 1 == 0);
+
+    let weight = |diff: i32| (1 << (15 + NLSF_W_Q)) / i32::max(diff, 1);
+
+    let mut tmp1_int = weight(i32::from(nlsf_q15[0]));
+    let mut tmp2_int = weight(i32::from(nlsf_q15[1]) - i32::from(nlsf_q15[0]));
+    nlsfw_q_out[0] = i32::min(tmp1_int + tmp2_int, i32::from(i16::MAX)) as i16;
+
+    (1..d - 1).step_by(2).for_each(|k| {
+        tmp1_int = weight(i32::from(nlsf_q15[k + 1]) - i32::from(nlsf_q15[k]));
+        nlsfw_q_out[k] = i32::min(tmp1_int + tmp2_int, i32::from(i16::MAX)) as i16;
+
+        tmp2_int = weight(i32::from(nlsf_q15[k + 2]) - i32::from(nlsf_q15[k + 1]));
+        nlsfw_q_out[k + 1] = i32::min(tmp1_int + tmp2_int, i32::from(i16::MAX)) as i16;
+    });
+
+    tmp1_int = weight((1 << 15) - i32::from(nlsf_q15[d - 1]));
+    nlsfw_q_out[d - 1] = i32::min(tmp1_int + tmp2_int, i32::from(i16::MAX)) as i16;
+}
+
+/// Computes the quantization errors of the first stage vector quantizer.
+fn nlsf_vq(err_q24: &mut [i32], in_q15: &[i16], cb: &NlsfCodebook) {
+    let order = cb.order;
+    debug_assert!(order & 1 == 0);
+
+    err_q24
+        .iter_mut()
+        .zip(
+            cb.cb1_nlsf_q8
+                .chunks_exact(order)
+                .zip(cb.cb1_wght_q9.chunks_exact(order)),
+        )
+        .for_each(|(err, (cb_q8, w_q9))| {
+            let mut sum_error_q24 = 0_i32;
+            let mut pred_q24 = 0_i32;
+            (0..order).rev().for_each(|m| {
+                let diff_q15 = i32::from(in_q15[m]) - (i32::from(cb_q8[m]) << 7);
+                let diffw_q24 = smulbb(diff_q15, i32::from(w_q9[m]));
+                sum_error_q24 = sum_error_q24.wrapping_add((diffw_q24 - (pred_q24 >> 1)).abs());
+                pred_q24 = diffw_q24;
+            });
+            *err = sum_error_q24;
+        });
+}
+
+/// Delayed-decision quantizer for the NLSF residuals. Returns the RD value in Q25.
+#[allow(clippy::too_many_arguments)]
+fn nlsf_del_dec_quant(
+    indices: &mut [i8],
+    x_q10: &[i16; MAX_LPC_ORDER],
+    w_q5: &[i16; MAX_LPC_ORDER],
+    pred_coef_q8: &[u8; MAX_LPC_ORDER],
+    ec_ix: &[i16; MAX_LPC_ORDER],
+    ec_rates_q5: &[u8],
+    quant_step_size_q16: i32,
+    inv_quant_step_size_q6: i32,
+    mu_q20: i32,
+    order: usize,
+) -> i32 {
+    const TABLE_SIZE: usize = 2 * NLSF_QUANT_MAX_AMPLITUDE_EXT as usize;
+    const MAX_AMPLITUDE: i32 = NLSF_QUANT_MAX_AMPLITUDE as i32;
+
+    let mut ind_sort = [0_usize; NLSF_QUANT_DEL_DEC_STATES];
+    let mut ind = [[0_i8; MAX_LPC_ORDER]; NLSF_QUANT_DEL_DEC_STATES];
+    let mut prev_out_q10 = [0_i16; 2 * NLSF_QUANT_DEL_DEC_STATES];
+    let mut rd_q25 = [0_i32; 2 * NLSF_QUANT_DEL_DEC_STATES];
+    let mut rd_min_q25 = [0_i32; NLSF_QUANT_DEL_DEC_STATES];
+    let mut rd_max_q25 = [0_i32; NLSF_QUANT_DEL_DEC_STATES];
+    let mut out0_q10_table = [0_i32; TABLE_SIZE];
+    let mut out1_q10_table = [0_i32; TABLE_SIZE];
+
+    (-NLSF_QUANT_MAX_AMPLITUDE_EXT..NLSF_QUANT_MAX_AMPLITUDE_EXT).for_each(|i| {
+        let mut out0_q10 = i << 10;
+        let mut out1_q10 = out0_q10 + 1024;
+        if i > 0 {
+            out0_q10 -= NLSF_QUANT_LEVEL_ADJ_Q10;
+            out1_q10 -= NLSF_QUANT_LEVEL_ADJ_Q10;
+        } else if i == 0 {
+            out1_q10 -= NLSF_QUANT_LEVEL_ADJ_Q10;
+        } else if i == -1 {
+            out0_q10 += NLSF_QUANT_LEVEL_ADJ_Q10;
+        } else {
+            out0_q10 += NLSF_QUANT_LEVEL_ADJ_Q10;
+            out1_q10 += NLSF_QUANT_LEVEL_ADJ_Q10;
+        }
+        let index = (i + NLSF_QUANT_MAX_AMPLITUDE_EXT) as usize;
+        out0_q10_table[index] = smulbb(out0_q10, quant_step_size_q16) >> 16;
+        out1_q10_table[index] = smulbb(out1_q10, quant_step_size_q16) >> 16;
+    });
+
+    let mut n_states = 1;
+    (0..order).rev().for_each(|i| {
+        let rates_q5 = &ec_rates_q5[ec_ix[i] as usize..];
+        let in_q10 = i32::from(x_q10[i]);
+
+        (0..n_states).for_each(|j| {
+            let pred_q10 = smulbb(i32::from(pred_coef_q8[i]), i32::from(prev_out_q10[j])) >> 8;
+            let res_q10 = in_q10 - pred_q10;
+            let ind_tmp = limit(
+                smulbb(inv_quant_step_size_q6, res_q10) >> 16,
+                -NLSF_QUANT_MAX_AMPLITUDE_EXT,
+                NLSF_QUANT_MAX_AMPLITUDE_EXT - 1,
+            );
+            ind[j][i] = ind_tmp as i8;
+
+            // Compute outputs for ind_tmp and ind_tmp + 1.
+            let index = (ind_tmp + NLSF_QUANT_MAX_AMPLITUDE_EXT) as usize;
+            let out0_q10 = (out0_q10_table[index] + pred_q10) as i16;
+            let out1_q10 = (out1_q10_table[index] + pred_q10) as i16;
+            prev_out_q10[j] = out0_q10;
+            prev_out_q10[j + n_states] = out1_q10;
+
+            // Compute RD for ind_tmp and ind_tmp + 1.
+            let (rate0_q5, rate1_q5) = if ind_tmp + 1 >= MAX_AMPLITUDE {
+                if ind_tmp + 1 == MAX_AMPLITUDE {
+                    (i32::from(rates_q5[(ind_tmp + MAX_AMPLITUDE) as usize]), 280)
+                } else {
+                    let rate0_q5 = smlabb(280 - 43 * MAX_AMPLITUDE, 43, ind_tmp);
+                    (rate0_q5, rate0_q5 + 43)
+                }
+            } else if ind_tmp <= -MAX_AMPLITUDE {
+                if ind_tmp == -MAX_AMPLITUDE {
+                    (
+                        280,
+                        i32::from(rates_q5[(ind_tmp + 1 + MAX_AMPLITUDE) as usize]),
+                    )
+                } else {
+                    let rate0_q5 = smlabb(280 - 43 * MAX_AMPLITUDE, -43, ind_tmp);
+                    (rate0_q5, rate0_q5 - 43)
+                }
+            } else {
+                (
+                    i32::from(rates_q5[(ind_tmp + MAX_AMPLITUDE) as usize]),
+                    i32::from(rates_q5[(ind_tmp + 1 + MAX_AMPLITUDE) as usize]),
+                )
+            };
+
+            let rd_tmp_q25 = rd_q25[j];
+            let w = i32::from(w_q5[i]);
+            let diff_q10 = in_q10 - i32::from(out0_q10);
+            rd_q25[j] = smlabb(
+                mla(rd_tmp_q25, smulbb(diff_q10, diff_q10), w),
+                mu_q20,
+                rate0_q5,
+            );
+            let diff_q10 = in_q10 - i32::from(out1_q10);
+            rd_q25[j + n_states] = smlabb(
+                mla(rd_tmp_q25, smulbb(diff_q10, diff_q10), w),
+                mu_q20,
+                rate1_q5,
+            );
+        });
+
+        if n_states <= NLSF_QUANT_DEL_DEC_STATES / 2 {
+            // Double number of states and copy.
+            (0..n_states).for_each(|j| {
+                ind[j + n_states][i] = ind[j][i] + 1;
+            });
+            n_states <<= 1;
+            (n_states..NLSF_QUANT_DEL_DEC_STATES).for_each(|j| {
+                ind[j][i] = ind[j - n_states][i];
+            });
+        } else {
+            // Sort lower and upper half of RD_Q25, pairwise.
+            (0..NLSF_QUANT_DEL_DEC_STATES).for_each(|j| {
+                if rd_q25[j] > rd_q25[j + NLSF_QUANT_DEL_DEC_STATES] {
+                    rd_max_q25[j] = rd_q25[j];
+                    rd_min_q25[j] = rd_q25[j + NLSF_QUANT_DEL_DEC_STATES];
+                    rd_q25.swap(j, j + NLSF_QUANT_DEL_DEC_STATES);
+                    prev_out_q10.swap(j, j + NLSF_QUANT_DEL_DEC_STATES);
+                    ind_sort[j] = j + NLSF_QUANT_DEL_DEC_STATES;
+                } else {
+                    rd_min_q25[j] = rd_q25[j];
+                    rd_max_q25[j] = rd_q25[j + NLSF_QUANT_DEL_DEC_STATES];
+                    ind_sort[j] = j;
+                }
+            });
+
+            // Compare the highest RD values of the winning half with the lowest one in the
+            // losing half, and copy if necessary. Afterwards ind_sort[] will contain the
+            // indices of the NLSF_QUANT_DEL_DEC_STATES winning RD values.
+            loop {
+                let mut min_max_q25 = i32::MAX;
+                let mut max_min_q25 = 0;
+                let mut ind_min_max = 0;
+                let mut ind_max_min = 0;
+                (0..NLSF_QUANT_DEL_DEC_STATES).for_each(|j| {
+                    if min_max_q25 > rd_max_q25[j] {
+                        min_max_q25 = rd_max_q25[j];
+                        ind_min_max = j;
+                    }
+                    if max_min_q25 < rd_min_q25[j] {
+                        max_min_q25 = rd_min_q25[j];
+                        ind_max_min = j;
+                    }
+                });
+                if min_max_q25 >= max_min_q25 {
+                    break;
+                }
+
+                // Copy ind_min_max to ind_max_min.
+                ind_sort[ind_max_min] = ind_sort[ind_min_max] ^ NLSF_QUANT_DEL_DEC_STATES;
+                rd_q25[ind_max_min] = rd_q25[ind_min_max + NLSF_QUANT_DEL_DEC_STATES];
+                prev_out_q10[ind_max_min] = prev_out_q10[ind_min_max + NLSF_QUANT_DEL_DEC_STATES];
+                rd_min_q25[ind_max_min] = 0;
+                rd_max_q25[ind_min_max] = i32::MAX;
+                ind[ind_max_min] = ind[ind_min_max];
+            }
+
+            // Increment index if it comes from the upper half.
+            (0..NLSF_QUANT_DEL_DEC_STATES).for_each(|j| {
+                ind[j][i] += (ind_sort[j] >> NLSF_QUANT_DEL_DEC_STATES_LOG2) as i8;
+            });
+        }
+    });
+
+    // Last sample: find winner, copy indices and return RD value.
+    let mut ind_tmp = 0;
+    let mut min_q25 = i32::MAX;
+    (0..2 * NLSF_QUANT_DEL_DEC_STATES).for_each(|j| {
+        if min_q25 > rd_q25[j] {
+            min_q25 = rd_q25[j];
+            ind_tmp = j;
+        }
+    });
+    indices[..order].copy_from_slice(&ind[ind_tmp & (NLSF_QUANT_DEL_DEC_STATES - 1)][..order]);
+    indices[0] += (ind_tmp >> NLSF_QUANT_DEL_DEC_STATES_LOG2) as i8;
+    debug_assert!(min_q25 >= 0);
+
+    min_q25
+}
+
+/// Encodes the NLSF vector with a multi-stage vector quantizer and returns the RD value in Q25.
+///
+/// The quantized NLSF vector is written back into `nlsf_q15`.
+pub(crate) fn nlsf_encode(
+    nlsf_indices: &mut [i8; MAX_LPC_ORDER + 1],
+    nlsf_q15: &mut [i16],
+    cb: &NlsfCodebook,
+    w_q2: &[i16],
+    nlsf_mu_q20: i32,
+    n_survivors: usize,
+    signal_type: usize,
+) -> i32 {
+    const MAX_VECTORS: usize = 32;
+    const MAX_SURVIVORS: usize = 16;
+
+    debug_assert!(signal_type <= 2);
+    debug_assert!((0..=32767).contains(&nlsf_mu_q20));
+    debug_assert!(cb.vectors <= MAX_VECTORS && n_survivors <= MAX_SURVIVORS);
+
+    let order = cb.order;
+    let mut err_q24 = [0_i32; MAX_VECTORS];
+    let mut temp_indices1 = [0_usize; MAX_VECTORS];
+    let mut temp_indices2 = [[0_i8; MAX_LPC_ORDER]; MAX_SURVIVORS];
+    let mut rd_q25 = [0_i32; MAX_SURVIVORS];
+    let mut res_q10 = [0_i16; MAX_LPC_ORDER];
+    let mut w_adj_q5 = [0_i16; MAX_LPC_ORDER];
+    let mut pred_q8 = [0_u8; MAX_LPC_ORDER];
+    let mut ec_ix = [0_i16; MAX_LPC_ORDER];
+
+    // NLSF stabilization.
+    nlsf_stabilize(&mut nlsf_q15[..order], cb.delta_min_q15);
+
+    // First stage: VQ.
+    nlsf_vq(&mut err_q24[..cb.vectors], &nlsf_q15[..order], cb);
+
+    // Sort the quantization errors.
+    insertion_sort_increasing(&mut err_q24[..cb.vectors], &mut temp_indices1, n_survivors);
+
+    // Loop over survivors.
+    (0..n_survivors).for_each(|s| {
+        let ind1 = temp_indices1[s];
+
+        // Residual after first stage.
+        let cb_element = &cb.cb1_nlsf_q8[ind1 * order..];
+        let cb_wght_q9 = &cb.cb1_wght_q9[ind1 * order..];
+        (0..order).for_each(|i| {
+            let nlsf_tmp_q15 = i32::from(cb_element[i]) << 7;
+            let w_tmp_q9 = i32::from(cb_wght_q9[i]);
+            res_q10[i] = (smulbb(i32::from(nlsf_q15[i]) - nlsf_tmp_q15, w_tmp_q9) >> 14) as i16;
+            w_adj_q5[i] = div32_varq(i32::from(w_q2[i]), smulbb(w_tmp_q9, w_tmp_q9), 21) as i16;
+        });
+
+        // Unpack entropy table indices and predictor for current CB1 index.
+        nlsf_unpack(&mut ec_ix, &mut pred_q8, cb, ind1);
+
+        // Trellis quantizer.
+        rd_q25[s] = nlsf_del_dec_quant(
+            &mut temp_indices2[s],
+            &res_q10,
+            &w_adj_q5,
+            &pred_q8,
+            &ec_ix,
+            cb.ec_rates_q5,
+            cb.quant_step_size_q16,
+            cb.inv_quant_step_size_q6,
+            nlsf_mu_q20,
+            order,
+        );
+
+        // Add rate for first stage.
+        let icdf = &cb.cb1_icdf[(signal_type >> 1) * cb.vectors..];
+        let prob_q8 = if ind1 == 0 {
+            256 - i32::from(icdf[ind1])
+        } else {
+            i32::from(icdf[ind1 - 1]) - i32::from(icdf[ind1])
+        };
+        let bits_q7 = (8 << 7) - lin2log(prob_q8);
+        rd_q25[s] = smlabb(rd_q25[s], bits_q7, nlsf_mu_q20 >> 2);
+    });
+
+    // Find the lowest rate-distortion error.
+    let mut best_index = [0_usize; 1];
+    insertion_sort_increasing(&mut rd_q25[..n_survivors], &mut best_index, 1);
+
+    nlsf_indices[0] = temp_indices1[best_index[0]] as i8;
+    nlsf_indices[1..=order].copy_from_slice(&temp_indices2[best_index[0]][..order]);
+
+    // Decode.
+    nlsf_decode(nlsf_q15, &nlsf_indices[..], cb);
+
+    rd_q25[0]
+}
+
+/// Transforms polynomials from cos(n*f) to cos(f)^n.
+fn a2nlsf_trans_poly(p: &mut [i32], dd: usize) {
+    (2..=dd).for_each(|k| {
+        ((k + 1)..=dd).rev().for_each(|n| {
+            p[n - 2] -= p[n];
+        });
+        p[k - 2] -= p[k] << 1;
+    });
+}
+
+/// Evaluates the polynomial in Q16 at the evaluation point in Q12.
+fn a2nlsf_eval_poly(p: &[i32], x: i32, dd: usize) -> i32 {
+    let x_q16 = x << 4;
+    (0..dd).rev().fold(p[dd], |y32, n| smlaww(p[n], y32, x_q16))
+}
+
+/// Converts the filter coefficients to the even and odd polynomials.
+fn a2nlsf_init(a_q16: &[i32], p: &mut [i32], q: &mut [i32], dd: usize) {
+    // Convert filter coefs to even and odd polynomials.
+    p[dd] = 1 << 16;
+    q[dd] = 1 << 16;
+    (0..dd).for_each(|k| {
+        p[k] = -a_q16[dd - k - 1] - a_q16[dd + k];
+        q[k] = -a_q16[dd - k - 1] + a_q16[dd + k];
+    });
+
+    // Divide out zeros as we have that for even filter orders,
+    // z =  1 is always a root in Q, and
+    // z = -1 is always a root in P.
+    (1..=dd).rev().for_each(|k| {
+        p[k - 1] -= p[k];
+        q[k - 1] += q[k];
+    });
+
+    // Transform polynomials from cos(n*f) to cos(f)^n.
+    a2nlsf_trans_poly(p, dd);
+    a2nlsf_trans_poly(q, dd);
+}
+
+/// Computes the normalized line spectral frequencies in Q15 from the whitening filter
+/// coefficients in Q16.
+///
+/// If not all roots are found, the coefficients are bandwidth expanded until convergence.
+pub(crate) fn a2nlsf(nlsf: &mut [i16], a_q16: &mut [i32]) {
+    let d = nlsf.len();
+    let dd = d >> 1;
+
+    let mut pq = [[0_i32; MAX_LPC_ORDER / 2 + 1]; 2];
+
+    {
+        let (p, q) = pq.split_at_mut(1);
+        a2nlsf_init(a_q16, &mut p[0], &mut q[0], dd);
+    }
+
+    // Find roots, alternating between P and Q.
+    let mut p = 0;
+    let mut xlo = i32::from(LSF_COS_TAB_FIX_Q12[0]);
+    let mut ylo = a2nlsf_eval_poly(&pq[p], xlo, dd);
+
+    let mut root_ix = if ylo < 0 {
+        // Set the first NLSF to zero and move on to the next.
+        nlsf[0] = 0;
+        p = 1;
+        ylo = a2nlsf_eval_poly(&pq[p], xlo, dd);
+        1
+    } else {
+        0
+    };
+
+    let mut k = 1;
+    let mut i = 0;
+    let mut thr = 0;
+    loop {
+        // Evaluate polynomial.
+        let mut xhi = i32::from(LSF_COS_TAB_FIX_Q12[k]);
+        let mut yhi = a2nlsf_eval_poly(&pq[p], xhi, dd);
+
+        // Detect zero crossing.
+        if (ylo <= 0 && yhi >= thr) || (ylo >= 0 && yhi <= -thr) {
+            // If the root lies exactly at the end of the current
+            // interval, look for the next root in the next interval.
+            thr = if yhi == 0 { 1 } else { 0 };
+
+            // Binary division.
+            let mut ffrac = -256;
+            (0..BIN_DIV_STEPS_A2NLSF).for_each(|m| {
+                // Evaluate polynomial.
+                let xmid = rshift_round(xlo + xhi, 1);
+                let ymid = a2nlsf_eval_poly(&pq[p], xmid, dd);
+
+                // Detect zero crossing.
+                if (ylo <= 0 && ymid >= 0) || (ylo >= 0 && ymid <= 0) {
+                    // Reduce frequency.
+                    xhi = xmid;
+                    yhi = ymid;
+                } else {
+                    // Increase frequency.
+                    xlo = xmid;
+                    ylo = ymid;
+                    ffrac += 128 >> m;
+                }
+            });
+
+            // Interpolate.
+            if ylo.abs() < 65536 {
+                // Avoid dividing by zero.
+                let den = ylo - yhi;
+                let nom = (ylo << (8 - BIN_DIV_STEPS_A2NLSF)) + (den >> 1);
+                if den != 0 {
+                    ffrac += nom / den;
+                }
+            } else {
+                // No risk of dividing by zero because abs(ylo - yhi) >= abs(ylo) >= 65536.
+                ffrac += ylo / ((ylo - yhi) >> (8 - BIN_DIV_STEPS_A2NLSF));
+            }
+            nlsf[root_ix] = i32::min(((k as i32) << 8) + ffrac, i32::from(i16::MAX)) as i16;
+            debug_assert!(nlsf[root_ix] >= 0);
+
+            // Next root.
+            root_ix += 1;
+            if root_ix >= d {
+                // Found all roots.
+                break;
+            }
+
+            // Alternate pointer to polynomial.
+            p = root_ix & 1;
+
+            // Evaluate polynomial.
+            xlo = i32::from(LSF_COS_TAB_FIX_Q12[k - 1]);
+            ylo = (1 - (root_ix as i32 & 2)) << 12;
+        } else {
+            // Increment loop counter.
+            k += 1;
+            xlo = xhi;
+            ylo = yhi;
+            thr = 0;
+
+            if k > LSF_COS_TAB_SZ {
+                i += 1;
+                if i > MAX_ITERATIONS_A2NLSF {
+                    // Set NLSFs to white spectrum and exit.
+                    nlsf[0] = ((1 << 15) / (d + 1)) as i16;
+                    (1..d).for_each(|k| {
+                        nlsf[k] = nlsf[k - 1] + nlsf[0];
+                    });
+                    return;
+                }
+
+                // Error: Apply progressively more bandwidth expansion and run again.
+                bwexpander_32(a_q16, 65536 - (1 << i));
+
+                {
+                    let (p, q) = pq.split_at_mut(1);
+                    a2nlsf_init(a_q16, &mut p[0], &mut q[0], dd);
+                }
+                p = 0;
+                xlo = i32::from(LSF_COS_TAB_FIX_Q12[0]);
+                ylo = a2nlsf_eval_poly(&pq[p], xlo, dd);
+                if ylo < 0 {
+                    // Set the first NLSF to zero and move on to the next.
+                    nlsf[0] = 0;
+                    p = 1;
+                    ylo = a2nlsf_eval_poly(&pq[p], xlo, dd);
+                    root_ix = 1;
+                } else {
+                    root_ix = 0;
+                }
+                k = 1;
+            }
+        }
+    }
+}
+
+/// Helper function for `nlsf2a()`.
+fn nlsf2a_find_poly(out: &mut [i32], c_lsf: &[i32], dd: usize) {
+    out[0] = 1 << QA;
+    out[1] = -c_lsf[0];
+    (1..dd).for_each(|k| {
+        let ftmp = c_lsf[2 * k];
+        out[k + 1] =
+            (out[k - 1] << 1) - rshift_round64(i64::from(ftmp) * i64::from(out[k]), QA) as i32;
+        (2..=k).rev().for_each(|n| {
+            out[n] +=
+                out[n - 2] - rshift_round64(i64::from(ftmp) * i64::from(out[n - 1]), QA) as i32;
+        });
+        out[1] -= ftmp;
+    });
+}
+
+/// Computes the whitening filter coefficients in Q12 from the normalized line spectral
+/// frequencies in Q15.
+pub(crate) fn nlsf2a(a_q12: &mut [i16], nlsf: &[i16]) {
+    let d = nlsf.len();
+    debug_assert!(d == 10 || d == 16);
+
+    let mut cos_lsf_qa = [0_i32; MAX_LPC_ORDER];
+    let mut p = [0_i32; MAX_LPC_ORDER / 2 + 1];
+    let mut q = [0_i32; MAX_LPC_ORDER / 2 + 1];
+    let mut a32_qa1 = [0_i32; MAX_LPC_ORDER];
+
+    // Convert LSFs to 2*cos(LSF), using piecewise linear curve from table.
+    let ordering: &[usize] = if d == 16 { ORDERING16 } else { ORDERING10 };
+    (0..d).for_each(|k| {
+        debug_assert!(nlsf[k] >= 0);
+
+        // f_int on a scale 0-127 (rounded down).
+        let f_int = (nlsf[k] >> (15 - 7)) as usize;
+
+        // f_frac, range: 0..255.
+        let f_frac = i32::from(nlsf[k]) - ((f_int as i32) << (15 - 7));
+
+        // Read start and end value from table.
+        let cos_val = i32::from(LSF_COS_TAB_FIX_Q12[f_int]);
+        let delta = i32::from(LSF_COS_TAB_FIX_Q12[f_int + 1]) - cos_val;
+
+        // Linear interpolation.
+        cos_lsf_qa[ordering[k]] = rshift_round((cos_val << 8) + delta * f_frac, 20 - QA);
+    });
+
+    let dd = d >> 1;
+
+    // Generate even and odd polynomials using convolution.
+    nlsf2a_find_poly(&mut p, &cos_lsf_qa, dd);
+    nlsf2a_find_poly(&mut q, &cos_lsf_qa[1..], dd);
+
+    // Convert even and odd polynomials to i32 Q12 filter coefficients.
+    (0..dd).for_each(|k| {
+        let ptmp = p[k + 1] + p[k];
+        let qtmp = q[k + 1] - q[k];
+
+        // The ptmp and qtmp values at this stage need to fit in i32.
+        a32_qa1[k] = -qtmp - ptmp;
+        a32_qa1[d - k - 1] = qtmp - ptmp;
+    });
+
+    // Convert i32 coefficients to Q12 i16 coefficients.
+    lpc_fit(&mut a_q12[..d], &mut a32_qa1[..d], 12, QA + 1);
+
+    let mut i = 0;
+    while lpc_inverse_pred_gain(&a_q12[..d]) == 0 && i < MAX_LPC_STABILIZE_ITERATIONS {
+        // Prediction coefficients are (too close to) unstable; apply bandwidth expansion
+        // on the unscaled coefficients, convert to Q12 and measure again.
+        bwexpander_32(&mut a32_qa1[..d], 65536 - (2 << i));
+        (0..d).for_each(|k| {
+            a_q12[k] = rshift_round(a32_qa1[k], QA + 1 - 12) as i16;
+        });
+        i += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nlsf_stabilize() {
+        let delta_min_q15 = [100_i16, 100, 100, 100, 100];
+        let mut nlsf_q15 = [50_i16, 120, 20000, 19900];
+        nlsf_stabilize(&mut nlsf_q15, &delta_min_q15);
+
+        assert!(nlsf_q15[0] >= delta_min_q15[0]);
+        (1..nlsf_q15.len()).for_each(|i| {
+            assert!(nlsf_q15[i] - nlsf_q15[i - 1] >= delta_min_q15[i]);
+        });
+        assert!(i32::from(nlsf_q15[3]) <= (1 << 15) - i32::from(delta_min_q15[4]));
+    }
+
+    #[test]
+    fn test_nlsf2a_is_stable() {
+        // Evenly spaced NLSFs correspond to a flat spectrum.
+        let nlsf: Vec<i16> = (1..=16).map(|i| (i * 32768 / 17) as i16).collect();
+        let mut a_q12 = [0_i16; 16];
+        nlsf2a(&mut a_q12, &nlsf);
+
+        assert!(lpc_inverse_pred_gain(&a_q12) > 0);
+        a_q12.iter().for_each(|a| assert!(a.abs() < 1024));
+    }
+}
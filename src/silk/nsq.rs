@@ -0,0 +1,1210 @@
+//! Implements the noise shaping quantization.
+
+use crate::silk::fixed::{
+    add_sat32, div32_varq, inverse32_varq, limit, rand, rshift_round, sat16, smlabb, smlawb,
+    smlawt, smulbb, smulwb, smulww, sub_sat32,
+};
+use crate::silk::float::MAX_SHAPE_LPC_ORDER;
+use crate::silk::lpc::lpc_analysis_filter;
+use crate::silk::tables::QUANTIZATION_OFFSETS_Q10;
+use crate::silk::{
+    SideInfoIndices, LTP_ORDER, MAX_FRAME_LENGTH, MAX_LPC_ORDER, MAX_NB_SUBFR,
+    MAX_SUB_FRAME_LENGTH, QUANT_LEVEL_ADJUST_Q10, TYPE_VOICED,
+};
+
+/// Maximal number of delayed decision states.
+pub(crate) const MAX_DEL_DEC_STATES: usize = 4;
+
+const NSQ_LPC_BUF_LENGTH: usize = MAX_LPC_ORDER;
+const HARM_SHAPE_FIR_TAPS: usize = 3;
+const DECISION_DELAY: usize = 40;
+
+/// The fixed-point parameters of the noise shaping quantization of a frame.
+#[derive(Clone, Debug)]
+pub(crate) struct NsqParameters {
+    /// Length of the LTP memory.
+    pub(crate) ltp_mem_length: usize,
+    /// Length of a frame.
+    pub(crate) frame_length: usize,
+    /// Length of a subframe.
+    pub(crate) subfr_length: usize,
+    /// Number of subframes.
+    pub(crate) nb_subfr: usize,
+    /// Order of the prediction filter.
+    pub(crate) predict_lpc_order: usize,
+    /// Order of the noise shaping filter.
+    pub(crate) shaping_lpc_order: usize,
+    /// Warping parameter of the noise shaping filter.
+    pub(crate) warping_q16: i32,
+    /// Number of states of the delayed decision quantizer.
+    pub(crate) n_states_delayed_decision: usize,
+    /// Short term prediction coefficients.
+    pub(crate) pred_coef_q12: [[i16; MAX_LPC_ORDER]; 2],
+    /// Long term prediction coefficients.
+    pub(crate) ltp_coef_q14: [i16; LTP_ORDER * MAX_NB_SUBFR],
+    /// Noise shaping coefficients.
+    pub(crate) ar_q13: [i16; MAX_NB_SUBFR * MAX_SHAPE_LPC_ORDER],
+    /// Long term shaping coefficients.
+    pub(crate) harm_shape_gain_q14: [i32; MAX_NB_SUBFR],
+    /// Spectral tilt.
+    pub(crate) tilt_q14: [i32; MAX_NB_SUBFR],
+    /// Low frequency shaping coefficients, two packed i16 values.
+    pub(crate) lf_shp_q14: [i32; MAX_NB_SUBFR],
+    /// Quantization step sizes.
+    pub(crate) gains_q16: [i32; MAX_NB_SUBFR],
+    /// Pitch lags.
+    pub(crate) pitch_l: [i32; MAX_NB_SUBFR],
+    /// Rate/distortion tradeoff.
+    pub(crate) lambda_q10: i32,
+    /// LTP state scaling.
+    pub(crate) ltp_scale_q14: i32,
+}
+
+impl Default for NsqParameters {
+    fn default() -> Self {
+        Self {
+            ltp_mem_length: 0,
+            frame_length: 0,
+            subfr_length: 0,
+            nb_subfr: 0,
+            predict_lpc_order: 0,
+            shaping_lpc_order: 0,
+            warping_q16: 0,
+            n_states_delayed_decision: 0,
+            pred_coef_q12: [[0; MAX_LPC_ORDER]; 2],
+            ltp_coef_q14: [0; LTP_ORDER * MAX_NB_SUBFR],
+            ar_q13: [0; MAX_NB_SUBFR * MAX_SHAPE_LPC_ORDER],
+            harm_shape_gain_q14: [0; MAX_NB_SUBFR],
+            tilt_q14: [0; MAX_NB_SUBFR],
+            lf_shp_q14: [0; MAX_NB_SUBFR],
+            gains_q16: [0; MAX_NB_SUBFR],
+            pitch_l: [0; MAX_NB_SUBFR],
+            lambda_q10: 0,
+            ltp_scale_q14: 0,
+        }
+    }
+}
+
+/// The state of the noise shaping quantizer.
+#[derive(Clone, Debug)]
+pub(crate) struct NsqState {
+    /// Buffer for quantized output signal.
+    pub(crate) xq: [i16; 2 * MAX_FRAME_LENGTH],
+    pub(crate) s_ltp_shp_q14: [i32; 2 * MAX_FRAME_LENGTH],
+    pub(crate) s_lpc_q14: [i32; MAX_SUB_FRAME_LENGTH + NSQ_LPC_BUF_LENGTH],
+    pub(crate) s_ar2_q14: [i32; MAX_SHAPE_LPC_ORDER],
+    pub(crate) s_lf_ar_shp_q14: i32,
+    pub(crate) s_diff_shp_q14: i32,
+    pub(crate) lag_prev: i32,
+    pub(crate) s_ltp_buf_idx: usize,
+    pub(crate) s_ltp_shp_buf_idx: usize,
+    pub(crate) rand_seed: i32,
+    pub(crate) prev_gain_q16: i32,
+    pub(crate) rewhite_flag: bool,
+}
+
+impl Default for NsqState {
+    fn default() -> Self {
+        Self {
+            xq: [0; 2 * MAX_FRAME_LENGTH],
+            s_ltp_shp_q14: [0; 2 * MAX_FRAME_LENGTH],
+            s_lpc_q14: [0; MAX_SUB_FRAME_LENGTH + NSQ_LPC_BUF_LENGTH],
+            s_ar2_q14: [0; MAX_SHAPE_LPC_ORDER],
+            s_lf_ar_shp_q14: 0,
+            s_diff_shp_q14: 0,
+            lag_prev: 100,
+            s_ltp_buf_idx: 0,
+            s_ltp_shp_buf_idx: 0,
+            rand_seed: 0,
+            prev_gain_q16: 65536,
+            rewhite_flag: false,
+        }
+    }
+}
+
+/// A delayed decision state.
+#[derive(Clone, Debug)]
+struct DelDecState {
+    s_lpc_q14: [i32; MAX_SUB_FRAME_LENGTH + NSQ_LPC_BUF_LENGTH],
+    rand_state: [i32; DECISION_DELAY],
+    q_q10: [i32; DECISION_DELAY],
+    xq_q14: [i32; DECISION_DELAY],
+    pred_q15: [i32; DECISION_DELAY],
+    shape_q14: [i32; DECISION_DELAY],
+    s_ar2_q14: [i32; MAX_SHAPE_LPC_ORDER],
+    lf_ar_q14: i32,
+    diff_q14: i32,
+    seed: i32,
+    seed_init: i32,
+    rd_q10: i32,
+}
+
+impl Default for DelDecState {
+    fn default() -> Self {
+        Self {
+            s_lpc_q14: [0; MAX_SUB_FRAME_LENGTH + NSQ_LPC_BUF_LENGTH],
+            rand_state: [0; DECISION_DELAY],
+            q_q10: [0; DECISION_DELAY],
+            xq_q14: [0; DECISION_DELAY],
+            pred_q15: [0; DECISION_DELAY],
+            shape_q14: [0; DECISION_DELAY],
+            s_ar2_q14: [0; MAX_SHAPE_LPC_ORDER],
+            lf_ar_q14: 0,
+            diff_q14: 0,
+            seed: 0,
+            seed_init: 0,
+            rd_q10: 0,
+        }
+    }
+}
+
+impl DelDecState {
+    /// Copies the state of `other`, but keeps the first `offset` LPC state values.
+    fn copy_from(&mut self, other: &DelDecState, offset: usize) {
+        self.s_lpc_q14[offset..].copy_from_slice(&other.s_lpc_q14[offset..]);
+        self.rand_state = other.rand_state;
+        self.q_q10 = other.q_q10;
+        self.xq_q14 = other.xq_q14;
+        self.pred_q15 = other.pred_q15;
+        self.shape_q14 = other.shape_q14;
+        self.s_ar2_q14 = other.s_ar2_q14;
+        self.lf_ar_q14 = other.lf_ar_q14;
+        self.diff_q14 = other.diff_q14;
+        self.seed = other.seed;
+        self.seed_init = other.seed_init;
+        self.rd_q10 = other.rd_q10;
+    }
+}
+
+/// A sample state of the delayed decision quantizer.
+#[derive(Clone, Copy, Debug, Default)]
+struct SampleState {
+    q_q10: i32,
+    rd_q10: i32,
+    xq_q14: i32,
+    lf_ar_q14: i32,
+    diff_q14: i32,
+    s_ltp_shp_q14: i32,
+    lpc_exc_q14: i32,
+}
+
+/// Short-term prediction of the noise shaping quantizer.
+///
+/// `buf` ends with the most recent sample.
+#[inline(always)]
+fn short_prediction(buf: &[i32], coef: &[i16], order: usize) -> i32 {
+    debug_assert!(order == 10 || order == 16);
+    let last = buf.len() - 1;
+
+    // Avoids introducing a bias because smlawb() always rounds to -inf.
+    (0..order).fold((order >> 1) as i32, |out, j| {
+        smlawb(out, buf[last - j], i32::from(coef[j]))
+    })
+}
+
+/// Noise shaping feedback loop of the noise shaping quantizer.
+#[inline(always)]
+fn noise_shape_feedback_loop(data0: i32, data1: &mut [i32], coef: &[i16], order: usize) -> i32 {
+    let mut tmp2 = data0;
+    let mut out = (order >> 1) as i32;
+    (0..order).for_each(|j| {
+        let tmp1 = data1[j];
+        data1[j] = tmp2;
+        out = smlawb(out, tmp2, i32::from(coef[j]));
+        tmp2 = tmp1;
+    });
+
+    // Q11 -> Q12.
+    out << 1
+}
+
+/// Finds the two quantization level candidates and measures their rate-distortion.
+///
+/// Returns (q1_Q10, q2_Q10, rd1, rd2), with the rate-distortions in Q20.
+#[inline(always)]
+fn quantization_candidates(r_q10: i32, offset_q10: i32, lambda_q10: i32) -> (i32, i32, i32, i32) {
+    let mut q1_q10 = r_q10 - offset_q10;
+    let mut q1_q0 = q1_q10 >> 10;
+    if lambda_q10 > 2048 {
+        // For aggressive RDO, the bias becomes more than one pulse.
+        let rdo_offset = lambda_q10 / 2 - 512;
+        if q1_q10 > rdo_offset {
+            q1_q0 = (q1_q10 - rdo_offset) >> 10;
+        } else if q1_q10 < -rdo_offset {
+            q1_q0 = (q1_q10 + rdo_offset) >> 10;
+        } else if q1_q10 < 0 {
+            q1_q0 = -1;
+        } else {
+            q1_q0 = 0;
+        }
+    }
+
+    let (q1_q10, q2_q10, rd1_q20, rd2_q20) = if q1_q0 > 0 {
+        q1_q10 = (q1_q0 << 10) - QUANT_LEVEL_ADJUST_Q10 + offset_q10;
+        let q2_q10 = q1_q10 + 1024;
+        (
+            q1_q10,
+            q2_q10,
+            smulbb(q1_q10, lambda_q10),
+            smulbb(q2_q10, lambda_q10),
+        )
+    } else if q1_q0 == 0 {
+        q1_q10 = offset_q10;
+        let q2_q10 = q1_q10 + 1024 - QUANT_LEVEL_ADJUST_Q10;
+        (
+            q1_q10,
+            q2_q10,
+            smulbb(q1_q10, lambda_q10),
+            smulbb(q2_q10, lambda_q10),
+        )
+    } else if q1_q0 == -1 {
+        let q2_q10 = offset_q10;
+        q1_q10 = q2_q10 - (1024 - QUANT_LEVEL_ADJUST_Q10);
+        (
+            q1_q10,
+            q2_q10,
+            smulbb(-q1_q10, lambda_q10),
+            smulbb(q2_q10, lambda_q10),
+        )
+    } else {
+        q1_q10 = (q1_q0 << 10) + QUANT_LEVEL_ADJUST_Q10 + offset_q10;
+        let q2_q10 = q1_q10 + 1024;
+        (
+            q1_q10,
+            q2_q10,
+            smulbb(-q1_q10, lambda_q10),
+            smulbb(-q2_q10, lambda_q10),
+        )
+    };
+
+    let rr_q10 = r_q10 - q1_q10;
+    let rd1_q20 = smlabb(rd1_q20, rr_q10, rr_q10);
+    let rr_q10 = r_q10 - q2_q10;
+    let rd2_q20 = smlabb(rd2_q20, rr_q10, rr_q10);
+
+    (q1_q10, q2_q10, rd1_q20, rd2_q20)
+}
+
+/// Long-term prediction of the noise shaping quantizer in Q13.
+#[inline(always)]
+fn ltp_prediction(s_ltp_q15: &[i32], idx: usize, b_q14: &[i16]) -> i32 {
+    // Avoids introducing a bias because smlawb() always rounds to -inf.
+    (0..LTP_ORDER).fold(2, |pred, j| {
+        smlawb(pred, s_ltp_q15[idx - j], i32::from(b_q14[j]))
+    })
+}
+
+impl NsqState {
+    /// Resets the state.
+    pub(crate) fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Noise shaping quantization of a frame.
+    pub(crate) fn quantize(
+        &mut self,
+        params: &NsqParameters,
+        indices: &mut SideInfoIndices,
+        x16: &[i16],
+        pulses: &mut [i8],
+    ) {
+        let mut s_ltp_q15 = [0_i32; 2 * MAX_FRAME_LENGTH];
+        let mut s_ltp = [0_i16; 2 * MAX_FRAME_LENGTH];
+        let mut x_sc_q10 = [0_i32; MAX_SUB_FRAME_LENGTH];
+
+        self.rand_seed = i32::from(indices.seed);
+
+        // Set unvoiced lag to the previous one, overwrite later for voiced.
+        let mut lag = self.lag_prev;
+        debug_assert!(self.prev_gain_q16 != 0);
+
+        let offset_q10 = i32::from(
+            QUANTIZATION_OFFSETS_Q10[indices.signal_type >> 1][indices.quant_offset_type],
+        );
+        let lsf_interpolation_flag = indices.nlsf_interp_coef_q2 != 4;
+
+        // Set up pointers to start of sub frame.
+        self.s_ltp_shp_buf_idx = params.ltp_mem_length;
+        self.s_ltp_buf_idx = params.ltp_mem_length;
+
+        (0..params.nb_subfr).for_each(|k| {
+            let a_q12 = &params.pred_coef_q12[(k >> 1) | usize::from(!lsf_interpolation_flag)];
+            let b_q14 = &params.ltp_coef_q14[k * LTP_ORDER..];
+            let ar_shp_q13 = &params.ar_q13[k * MAX_SHAPE_LPC_ORDER..];
+
+            // Noise shape parameters.
+            debug_assert!(params.harm_shape_gain_q14[k] >= 0);
+            let harm_shape_fir_packed_q14 =
+                (params.harm_shape_gain_q14[k] >> 2) | ((params.harm_shape_gain_q14[k] >> 1) << 16);
+
+            self.rewhite_flag = false;
+            if indices.signal_type == TYPE_VOICED {
+                // Voiced.
+                lag = params.pitch_l[k];
+
+                // Re-whitening.
+                if k & (3 - (usize::from(lsf_interpolation_flag) << 1)) == 0 {
+                    // Rewhiten with new A coefs.
+                    let start_idx = params.ltp_mem_length
+                        - lag as usize
+                        - params.predict_lpc_order
+                        - LTP_ORDER / 2;
+                    debug_assert!(start_idx > 0);
+
+                    lpc_analysis_filter(
+                        &mut s_ltp[start_idx..],
+                        &self.xq[start_idx + k * params.subfr_length..],
+                        a_q12,
+                        params.ltp_mem_length - start_idx,
+                        params.predict_lpc_order,
+                    );
+
+                    self.rewhite_flag = true;
+                    self.s_ltp_buf_idx = params.ltp_mem_length;
+                }
+            }
+
+            let x16 = &x16[k * params.subfr_length..];
+            self.scale_states(
+                params,
+                x16,
+                &mut x_sc_q10,
+                &s_ltp,
+                &mut s_ltp_q15,
+                k,
+                indices.signal_type,
+            );
+
+            self.noise_shape_quantizer(
+                params,
+                indices.signal_type,
+                &x_sc_q10,
+                &mut pulses[k * params.subfr_length..],
+                params.ltp_mem_length + k * params.subfr_length,
+                &mut s_ltp_q15,
+                a_q12,
+                b_q14,
+                ar_shp_q13,
+                lag,
+                harm_shape_fir_packed_q14,
+                params.tilt_q14[k],
+                params.lf_shp_q14[k],
+                params.gains_q16[k],
+                offset_q10,
+            );
+        });
+
+        // Update lag_prev for next frame.
+        self.lag_prev = params.pitch_l[params.nb_subfr - 1];
+
+        // Save quantized speech and noise shaping signals.
+        self.xq.copy_within(
+            params.frame_length..params.frame_length + params.ltp_mem_length,
+            0,
+        );
+        self.s_ltp_shp_q14.copy_within(
+            params.frame_length..params.frame_length + params.ltp_mem_length,
+            0,
+        );
+    }
+
+    /// Noise shaping quantization of a subframe.
+    #[allow(clippy::too_many_arguments)]
+    fn noise_shape_quantizer(
+        &mut self,
+        params: &NsqParameters,
+        signal_type: usize,
+        x_sc_q10: &[i32],
+        pulses: &mut [i8],
+        xq_offset: usize,
+        s_ltp_q15: &mut [i32],
+        a_q12: &[i16],
+        b_q14: &[i16],
+        ar_shp_q13: &[i16],
+        lag: i32,
+        harm_shape_fir_packed_q14: i32,
+        tilt_q14: i32,
+        lf_shp_q14: i32,
+        gain_q16: i32,
+        offset_q10: i32,
+    ) {
+        let length = params.subfr_length;
+        let lambda_q10 = params.lambda_q10;
+        let shaping_lpc_order = params.shaping_lpc_order;
+        let predict_lpc_order = params.predict_lpc_order;
+
+        let mut shp_lag_idx =
+            (self.s_ltp_shp_buf_idx as i32 - lag + HARM_SHAPE_FIR_TAPS as i32 / 2) as usize;
+        let mut pred_lag_idx = (self.s_ltp_buf_idx as i32 - lag + LTP_ORDER as i32 / 2) as usize;
+        let gain_q10 = gain_q16 >> 6;
+
+        (0..length).for_each(|i| {
+            // Generate dither.
+            self.rand_seed = rand(self.rand_seed);
+
+            // Short-term prediction.
+            let lpc_pred_q10 = short_prediction(
+                &self.s_lpc_q14[..NSQ_LPC_BUF_LENGTH + i],
+                a_q12,
+                predict_lpc_order,
+            );
+
+            // Long-term prediction.
+            let ltp_pred_q13 = if signal_type == TYPE_VOICED {
+                let pred = ltp_prediction(s_ltp_q15, pred_lag_idx, b_q14);
+                pred_lag_idx += 1;
+                pred
+            } else {
+                0
+            };
+
+            // Noise shape feedback.
+            debug_assert!(shaping_lpc_order & 1 == 0);
+            let mut n_ar_q12 = noise_shape_feedback_loop(
+                self.s_diff_shp_q14,
+                &mut self.s_ar2_q14,
+                ar_shp_q13,
+                shaping_lpc_order,
+            );
+            n_ar_q12 = smlawb(n_ar_q12, self.s_lf_ar_shp_q14, tilt_q14);
+
+            let mut n_lf_q12 = smulwb(self.s_ltp_shp_q14[self.s_ltp_shp_buf_idx - 1], lf_shp_q14);
+            n_lf_q12 = smlawt(n_lf_q12, self.s_lf_ar_shp_q14, lf_shp_q14);
+
+            debug_assert!(lag > 0 || signal_type != TYPE_VOICED);
+
+            // Combine prediction and noise shaping signals.
+            let mut tmp1 = (lpc_pred_q10 << 2).wrapping_sub(n_ar_q12);
+            tmp1 = tmp1.wrapping_sub(n_lf_q12);
+            if lag > 0 {
+                // Symmetric, packed FIR coefficients.
+                let mut n_ltp_q13 = smulwb(
+                    self.s_ltp_shp_q14[shp_lag_idx]
+                        .wrapping_add(self.s_ltp_shp_q14[shp_lag_idx - 2]),
+                    harm_shape_fir_packed_q14,
+                );
+                n_ltp_q13 = smlawt(
+                    n_ltp_q13,
+                    self.s_ltp_shp_q14[shp_lag_idx - 1],
+                    harm_shape_fir_packed_q14,
+                );
+                n_ltp_q13 <<= 1;
+                shp_lag_idx += 1;
+
+                let tmp2 = ltp_pred_q13.wrapping_sub(n_ltp_q13);
+                tmp1 = tmp2.wrapping_add(tmp1 << 1);
+                tmp1 = rshift_round(tmp1, 3);
+            } else {
+                tmp1 = rshift_round(tmp1, 2);
+            }
+
+            // Residual error.
+            let mut r_q10 = x_sc_q10[i].wrapping_sub(tmp1);
+
+            // Flip sign depending on dither.
+            if self.rand_seed < 0 {
+                r_q10 = -r_q10;
+            }
+            r_q10 = limit(r_q10, -(31 << 10), 30 << 10);
+
+            // Find two quantization level candidates and measure their rate-distortion.
+            let (mut q1_q10, q2_q10, rd1_q20, rd2_q20) =
+                quantization_candidates(r_q10, offset_q10, lambda_q10);
+            if rd2_q20 < rd1_q20 {
+                q1_q10 = q2_q10;
+            }
+
+            pulses[i] = rshift_round(q1_q10, 10) as i8;
+
+            // Excitation.
+            let mut exc_q14 = q1_q10 << 4;
+            if self.rand_seed < 0 {
+                exc_q14 = -exc_q14;
+            }
+
+            // Add predictions.
+            let lpc_exc_q14 = exc_q14.wrapping_add(ltp_pred_q13 << 1);
+            let xq_q14 = lpc_exc_q14.wrapping_add(lpc_pred_q10 << 4);
+
+            // Scale XQ back to normal level before saving.
+            self.xq[xq_offset + i] = sat16(rshift_round(smulww(xq_q14, gain_q10), 8));
+
+            // Update states.
+            self.s_lpc_q14[NSQ_LPC_BUF_LENGTH + i] = xq_q14;
+            self.s_diff_shp_q14 = xq_q14.wrapping_sub(x_sc_q10[i] << 4);
+            let s_lf_ar_shp_q14 = self.s_diff_shp_q14.wrapping_sub(n_ar_q12 << 2);
+            self.s_lf_ar_shp_q14 = s_lf_ar_shp_q14;
+            self.s_ltp_shp_q14[self.s_ltp_shp_buf_idx] =
+                s_lf_ar_shp_q14.wrapping_sub(n_lf_q12 << 2);
+            s_ltp_q15[self.s_ltp_buf_idx] = lpc_exc_q14 << 1;
+            self.s_ltp_shp_buf_idx += 1;
+            self.s_ltp_buf_idx += 1;
+
+            // Make dither dependent on quantized signal.
+            self.rand_seed = self.rand_seed.wrapping_add(i32::from(pulses[i]));
+        });
+
+        // Update LPC synth buffer.
+        self.s_lpc_q14
+            .copy_within(length..length + NSQ_LPC_BUF_LENGTH, 0);
+    }
+
+    /// Scales the states of the quantizer to the gain of the subframe.
+    #[allow(clippy::too_many_arguments)]
+    fn scale_states(
+        &mut self,
+        params: &NsqParameters,
+        x16: &[i16],
+        x_sc_q10: &mut [i32],
+        s_ltp: &[i16],
+        s_ltp_q15: &mut [i32],
+        subfr: usize,
+        signal_type: usize,
+    ) {
+        let lag = params.pitch_l[subfr] as usize;
+        let gain_q16 = params.gains_q16[subfr];
+        let mut inv_gain_q31 = inverse32_varq(i32::max(gain_q16, 1), 47);
+        debug_assert!(inv_gain_q31 != 0);
+
+        // Scale input.
+        let inv_gain_q26 = rshift_round(inv_gain_q31, 5);
+        (0..params.subfr_length).for_each(|i| {
+            x_sc_q10[i] = smulww(i32::from(x16[i]), inv_gain_q26);
+        });
+
+        // After rewhitening the LTP state is un-scaled, so scale with inv_gain_Q16.
+        if self.rewhite_flag {
+            if subfr == 0 {
+                // Do LTP downscaling.
+                inv_gain_q31 = smulwb(inv_gain_q31, params.ltp_scale_q14) << 2;
+            }
+            (self.s_ltp_buf_idx - lag - LTP_ORDER / 2..self.s_ltp_buf_idx).for_each(|i| {
+                s_ltp_q15[i] = smulwb(inv_gain_q31, i32::from(s_ltp[i]));
+            });
+        }
+
+        // Adjust for changing gain.
+        if gain_q16 != self.prev_gain_q16 {
+            let gain_adj_q16 = div32_varq(self.prev_gain_q16, gain_q16, 16);
+
+            // Scale long-term shaping state.
+            (self.s_ltp_shp_buf_idx - params.ltp_mem_length..self.s_ltp_shp_buf_idx).for_each(
+                |i| {
+                    self.s_ltp_shp_q14[i] = smulww(gain_adj_q16, self.s_ltp_shp_q14[i]);
+                },
+            );
+
+            // Scale long-term prediction state.
+            if signal_type == TYPE_VOICED && !self.rewhite_flag {
+                (self.s_ltp_buf_idx - lag - LTP_ORDER / 2..self.s_ltp_buf_idx).for_each(|i| {
+                    s_ltp_q15[i] = smulww(gain_adj_q16, s_ltp_q15[i]);
+                });
+            }
+
+            self.s_lf_ar_shp_q14 = smulww(gain_adj_q16, self.s_lf_ar_shp_q14);
+            self.s_diff_shp_q14 = smulww(gain_adj_q16, self.s_diff_shp_q14);
+
+            // Scale short-term prediction and shaping states.
+            self.s_lpc_q14
+                .iter_mut()
+                .take(NSQ_LPC_BUF_LENGTH)
+                .for_each(|x| *x = smulww(gain_adj_q16, *x));
+            self.s_ar2_q14
+                .iter_mut()
+                .for_each(|x| *x = smulww(gain_adj_q16, *x));
+
+            // Save inverse gain.
+            self.prev_gain_q16 = gain_q16;
+        }
+    }
+
+    /// Noise shaping quantization of a frame with delayed decisions.
+    pub(crate) fn quantize_del_dec(
+        &mut self,
+        params: &NsqParameters,
+        indices: &mut SideInfoIndices,
+        x16: &[i16],
+        pulses: &mut [i8],
+    ) {
+        let n_states = params.n_states_delayed_decision;
+        let mut s_ltp_q15 = [0_i32; 2 * MAX_FRAME_LENGTH];
+        let mut s_ltp = [0_i16; 2 * MAX_FRAME_LENGTH];
+        let mut x_sc_q10 = [0_i32; MAX_SUB_FRAME_LENGTH];
+        let mut delayed_gain_q10 = [0_i32; DECISION_DELAY];
+        let mut del_dec: [DelDecState; MAX_DEL_DEC_STATES] = Default::default();
+
+        // Set unvoiced lag to the previous one, overwrite later for voiced.
+        let mut lag = self.lag_prev;
+        debug_assert!(self.prev_gain_q16 != 0);
+
+        // Initialize delayed decision states.
+        del_dec
+            .iter_mut()
+            .take(n_states)
+            .enumerate()
+            .for_each(|(k, dd)| {
+                dd.seed = (k as i32 + i32::from(indices.seed)) & 3;
+                dd.seed_init = dd.seed;
+                dd.rd_q10 = 0;
+                dd.lf_ar_q14 = self.s_lf_ar_shp_q14;
+                dd.diff_q14 = self.s_diff_shp_q14;
+                dd.shape_q14[0] = self.s_ltp_shp_q14[params.ltp_mem_length - 1];
+                dd.s_lpc_q14[..NSQ_LPC_BUF_LENGTH]
+                    .copy_from_slice(&self.s_lpc_q14[..NSQ_LPC_BUF_LENGTH]);
+                dd.s_ar2_q14 = self.s_ar2_q14;
+            });
+
+        let offset_q10 = i32::from(
+            QUANTIZATION_OFFSETS_Q10[indices.signal_type >> 1][indices.quant_offset_type],
+        );
+        // Index of oldest samples.
+        let mut smpl_buf_idx = 0;
+
+        let mut decision_delay = usize::min(DECISION_DELAY, params.subfr_length) as i32;
+
+        // For voiced frames limit the decision delay to lower than the pitch lag.
+        if indices.signal_type == TYPE_VOICED {
+            (0..params.nb_subfr).for_each(|k| {
+                decision_delay =
+                    i32::min(decision_delay, params.pitch_l[k] - LTP_ORDER as i32 / 2 - 1);
+            });
+        } else if lag > 0 {
+            decision_delay = i32::min(decision_delay, lag - LTP_ORDER as i32 / 2 - 1);
+        }
+        let decision_delay = decision_delay as usize;
+
+        let lsf_interpolation_flag = indices.nlsf_interp_coef_q2 != 4;
+
+        // Set up pointers to start of sub frame.
+        let mut xq_offset = params.ltp_mem_length;
+        self.s_ltp_shp_buf_idx = params.ltp_mem_length;
+        self.s_ltp_buf_idx = params.ltp_mem_length;
+
+        let mut subfr = 0;
+        (0..params.nb_subfr).for_each(|k| {
+            let a_q12 = &params.pred_coef_q12[(k >> 1) | usize::from(!lsf_interpolation_flag)];
+            let b_q14 = &params.ltp_coef_q14[k * LTP_ORDER..];
+            let ar_shp_q13 = &params.ar_q13[k * MAX_SHAPE_LPC_ORDER..];
+
+            // Noise shape parameters.
+            debug_assert!(params.harm_shape_gain_q14[k] >= 0);
+            let harm_shape_fir_packed_q14 =
+                (params.harm_shape_gain_q14[k] >> 2) | ((params.harm_shape_gain_q14[k] >> 1) << 16);
+
+            self.rewhite_flag = false;
+            if indices.signal_type == TYPE_VOICED {
+                // Voiced.
+                lag = params.pitch_l[k];
+
+                // Re-whitening.
+                if k & (3 - (usize::from(lsf_interpolation_flag) << 1)) == 0 {
+                    if k == 2 {
+                        // Reset delayed decisions.
+                        let winner_ind = find_winner(&del_dec[..n_states]);
+                        del_dec
+                            .iter_mut()
+                            .take(n_states)
+                            .enumerate()
+                            .filter(|(i, _)| *i != winner_ind)
+                            .for_each(|(_, dd)| {
+                                dd.rd_q10 = dd.rd_q10.wrapping_add(i32::MAX >> 4);
+                                debug_assert!(dd.rd_q10 >= 0);
+                            });
+
+                        // Copy final part of signals from winner state to output and
+                        // long-term filter states.
+                        let dd = &del_dec[winner_ind];
+                        let mut last_smple_idx = smpl_buf_idx + decision_delay;
+                        (0..decision_delay).for_each(|i| {
+                            last_smple_idx = (last_smple_idx + DECISION_DELAY - 1) % DECISION_DELAY;
+                            let out_idx = k * params.subfr_length + i - decision_delay;
+                            pulses[out_idx] = rshift_round(dd.q_q10[last_smple_idx], 10) as i8;
+                            self.xq[xq_offset + i - decision_delay] = sat16(rshift_round(
+                                smulww(dd.xq_q14[last_smple_idx], params.gains_q16[1]),
+                                14,
+                            ));
+                            self.s_ltp_shp_q14[self.s_ltp_shp_buf_idx - decision_delay + i] =
+                                dd.shape_q14[last_smple_idx];
+                        });
+
+                        subfr = 0;
+                    }
+
+                    // Rewhiten with new A coefs.
+                    let start_idx = params.ltp_mem_length
+                        - lag as usize
+                        - params.predict_lpc_order
+                        - LTP_ORDER / 2;
+                    debug_assert!(start_idx > 0);
+
+                    lpc_analysis_filter(
+                        &mut s_ltp[start_idx..],
+                        &self.xq[start_idx + k * params.subfr_length..],
+                        a_q12,
+                        params.ltp_mem_length - start_idx,
+                        params.predict_lpc_order,
+                    );
+
+                    self.s_ltp_buf_idx = params.ltp_mem_length;
+                    self.rewhite_flag = true;
+                }
+            }
+
+            let x16 = &x16[k * params.subfr_length..];
+            self.del_dec_scale_states(
+                params,
+                &mut del_dec[..n_states],
+                x16,
+                &mut x_sc_q10,
+                &s_ltp,
+                &mut s_ltp_q15,
+                k,
+                indices.signal_type,
+                decision_delay,
+            );
+
+            self.noise_shape_quantizer_del_dec(
+                params,
+                &mut del_dec[..n_states],
+                indices.signal_type,
+                &x_sc_q10,
+                pulses,
+                k * params.subfr_length,
+                xq_offset,
+                &mut s_ltp_q15,
+                &mut delayed_gain_q10,
+                a_q12,
+                b_q14,
+                ar_shp_q13,
+                lag,
+                harm_shape_fir_packed_q14,
+                params.tilt_q14[k],
+                params.lf_shp_q14[k],
+                params.gains_q16[k],
+                offset_q10,
+                subfr,
+                &mut smpl_buf_idx,
+                decision_delay,
+            );
+            subfr += 1;
+
+            xq_offset += params.subfr_length;
+        });
+
+        // Find winner.
+        let winner_ind = find_winner(&del_dec[..n_states]);
+
+        // Copy final part of signals from winner state to output and long-term filter states.
+        let dd = &del_dec[winner_ind];
+        indices.seed = dd.seed_init as i8;
+        let mut last_smple_idx = smpl_buf_idx + decision_delay;
+        let gain_q10 = params.gains_q16[params.nb_subfr - 1] >> 6;
+        (0..decision_delay).for_each(|i| {
+            last_smple_idx = (last_smple_idx + DECISION_DELAY - 1) % DECISION_DELAY;
+            pulses[params.frame_length + i - decision_delay] =
+                rshift_round(dd.q_q10[last_smple_idx], 10) as i8;
+            self.xq[xq_offset + i - decision_delay] =
+                sat16(rshift_round(smulww(dd.xq_q14[last_smple_idx], gain_q10), 8));
+            self.s_ltp_shp_q14[self.s_ltp_shp_buf_idx - decision_delay + i] =
+                dd.shape_q14[last_smple_idx];
+        });
+        self.s_lpc_q14[..NSQ_LPC_BUF_LENGTH].copy_from_slice(
+            &dd.s_lpc_q14[params.subfr_length..params.subfr_length + NSQ_LPC_BUF_LENGTH],
+        );
+        self.s_ar2_q14 = dd.s_ar2_q14;
+
+        // Update states.
+        self.s_lf_ar_shp_q14 = dd.lf_ar_q14;
+        self.s_diff_shp_q14 = dd.diff_q14;
+        self.lag_prev = params.pitch_l[params.nb_subfr - 1];
+
+        // Save quantized speech signal.
+        self.xq.copy_within(
+            params.frame_length..params.frame_length + params.ltp_mem_length,
+            0,
+        );
+        self.s_ltp_shp_q14.copy_within(
+            params.frame_length..params.frame_length + params.ltp_mem_length,
+            0,
+        );
+    }
+
+    /// Noise shaping quantization of a subframe with delayed decisions.
+    #[allow(clippy::too_many_arguments)]
+    fn noise_shape_quantizer_del_dec(
+        &mut self,
+        params: &NsqParameters,
+        del_dec: &mut [DelDecState],
+        signal_type: usize,
+        x_q10: &[i32],
+        pulses: &mut [i8],
+        pulses_offset: usize,
+        xq_offset: usize,
+        s_ltp_q15: &mut [i32],
+        delayed_gain_q10: &mut [i32; DECISION_DELAY],
+        a_q12: &[i16],
+        b_q14: &[i16],
+        ar_shp_q13: &[i16],
+        lag: i32,
+        harm_shape_fir_packed_q14: i32,
+        tilt_q14: i32,
+        lf_shp_q14: i32,
+        gain_q16: i32,
+        offset_q10: i32,
+        subfr: usize,
+        smpl_buf_idx: &mut usize,
+        decision_delay: usize,
+    ) {
+        let length = params.subfr_length;
+        let lambda_q10 = params.lambda_q10;
+        let shaping_lpc_order = params.shaping_lpc_order;
+        let predict_lpc_order = params.predict_lpc_order;
+        let warping_q16 = params.warping_q16;
+        let n_states = del_dec.len();
+        debug_assert!(n_states > 0);
+
+        let mut sample_states = [[SampleState::default(); 2]; MAX_DEL_DEC_STATES];
+
+        let mut shp_lag_idx =
+            (self.s_ltp_shp_buf_idx as i32 - lag + HARM_SHAPE_FIR_TAPS as i32 / 2) as usize;
+        let mut pred_lag_idx = (self.s_ltp_buf_idx as i32 - lag + LTP_ORDER as i32 / 2) as usize;
+        let gain_q10 = gain_q16 >> 6;
+
+        (0..length).for_each(|i| {
+            // Perform common calculations used in all states.
+
+            // Long-term prediction.
+            let ltp_pred_q14 = if signal_type == TYPE_VOICED {
+                let pred = ltp_prediction(s_ltp_q15, pred_lag_idx, b_q14) << 1;
+                pred_lag_idx += 1;
+                pred
+            } else {
+                0
+            };
+
+            // Long-term shaping.
+            let n_ltp_q14 = if lag > 0 {
+                // Symmetric, packed FIR coefficients.
+                let mut n_ltp_q14 = smulwb(
+                    add_sat32(
+                        self.s_ltp_shp_q14[shp_lag_idx],
+                        self.s_ltp_shp_q14[shp_lag_idx - 2],
+                    ),
+                    harm_shape_fir_packed_q14,
+                );
+                n_ltp_q14 = smlawt(
+                    n_ltp_q14,
+                    self.s_ltp_shp_q14[shp_lag_idx - 1],
+                    harm_shape_fir_packed_q14,
+                );
+                shp_lag_idx += 1;
+                ltp_pred_q14.wrapping_sub(n_ltp_q14 << 2)
+            } else {
+                0
+            };
+
+            del_dec
+                .iter_mut()
+                .zip(sample_states.iter_mut())
+                .for_each(|(dd, ss)| {
+                    // Generate dither.
+                    dd.seed = rand(dd.seed);
+
+                    // Short-term prediction.
+                    let lpc_pred_q14 = short_prediction(
+                        &dd.s_lpc_q14[..NSQ_LPC_BUF_LENGTH + i],
+                        a_q12,
+                        predict_lpc_order,
+                    ) << 4;
+
+                    // Noise shape feedback.
+                    debug_assert!(shaping_lpc_order & 1 == 0);
+
+                    // Output of lowpass section.
+                    let mut tmp2 = smlawb(dd.diff_q14, dd.s_ar2_q14[0], warping_q16);
+                    // Output of allpass section.
+                    let mut tmp1 = smlawb(
+                        dd.s_ar2_q14[0],
+                        dd.s_ar2_q14[1].wrapping_sub(tmp2),
+                        warping_q16,
+                    );
+                    dd.s_ar2_q14[0] = tmp2;
+                    let mut n_ar_q14 = (shaping_lpc_order >> 1) as i32;
+                    n_ar_q14 = smlawb(n_ar_q14, tmp2, i32::from(ar_shp_q13[0]));
+
+                    // Loop over allpass sections.
+                    (2..shaping_lpc_order).step_by(2).for_each(|j| {
+                        // Output of allpass section.
+                        tmp2 = smlawb(
+                            dd.s_ar2_q14[j - 1],
+                            dd.s_ar2_q14[j].wrapping_sub(tmp1),
+                            warping_q16,
+                        );
+                        dd.s_ar2_q14[j - 1] = tmp1;
+                        n_ar_q14 = smlawb(n_ar_q14, tmp1, i32::from(ar_shp_q13[j - 1]));
+                        // Output of allpass section.
+                        tmp1 = smlawb(
+                            dd.s_ar2_q14[j],
+                            dd.s_ar2_q14[j + 1].wrapping_sub(tmp2),
+                            warping_q16,
+                        );
+                        dd.s_ar2_q14[j] = tmp2;
+                        n_ar_q14 = smlawb(n_ar_q14, tmp2, i32::from(ar_shp_q13[j]));
+                    });
+                    dd.s_ar2_q14[shaping_lpc_order - 1] = tmp1;
+                    n_ar_q14 = smlawb(n_ar_q14, tmp1, i32::from(ar_shp_q13[shaping_lpc_order - 1]));
+
+                    // Q11 -> Q12.
+                    n_ar_q14 <<= 1;
+                    n_ar_q14 = smlawb(n_ar_q14, dd.lf_ar_q14, tilt_q14);
+                    // Q12 -> Q14.
+                    n_ar_q14 <<= 2;
+
+                    let mut n_lf_q14 = smulwb(dd.shape_q14[*smpl_buf_idx], lf_shp_q14);
+                    n_lf_q14 = smlawt(n_lf_q14, dd.lf_ar_q14, lf_shp_q14);
+                    // Q12 -> Q14.
+                    n_lf_q14 <<= 2;
+
+                    // Input minus prediction plus noise feedback
+                    // r = x[ i ] - LTP_pred - LPC_pred + n_AR + n_Tilt + n_LF + n_LTP
+                    let tmp1 = add_sat32(n_ar_q14, n_lf_q14);
+                    let tmp2 = n_ltp_q14.wrapping_add(lpc_pred_q14);
+                    let tmp1 = sub_sat32(tmp2, tmp1);
+                    let tmp1 = rshift_round(tmp1, 4);
+
+                    // Residual error.
+                    let mut r_q10 = x_q10[i].wrapping_sub(tmp1);
+
+                    // Flip sign depending on dither.
+                    if dd.seed < 0 {
+                        r_q10 = -r_q10;
+                    }
+                    r_q10 = limit(r_q10, -(31 << 10), 30 << 10);
+
+                    // Find two quantization level candidates and measure their rate-distortion.
+                    let (q1_q10, q2_q10, rd1_q20, rd2_q20) =
+                        quantization_candidates(r_q10, offset_q10, lambda_q10);
+                    let rd1_q10 = rd1_q20 >> 10;
+                    let rd2_q10 = rd2_q20 >> 10;
+
+                    if rd1_q10 < rd2_q10 {
+                        ss[0].rd_q10 = dd.rd_q10.wrapping_add(rd1_q10);
+                        ss[1].rd_q10 = dd.rd_q10.wrapping_add(rd2_q10);
+                        ss[0].q_q10 = q1_q10;
+                        ss[1].q_q10 = q2_q10;
+                    } else {
+                        ss[0].rd_q10 = dd.rd_q10.wrapping_add(rd2_q10);
+                        ss[1].rd_q10 = dd.rd_q10.wrapping_add(rd1_q10);
+                        ss[0].q_q10 = q2_q10;
+                        ss[1].q_q10 = q1_q10;
+                    }
+
+                    // Update states for best and second best quantization.
+                    ss.iter_mut().for_each(|s| {
+                        // Quantized excitation.
+                        let mut exc_q14 = s.q_q10 << 4;
+                        if dd.seed < 0 {
+                            exc_q14 = -exc_q14;
+                        }
+
+                        // Add predictions.
+                        let lpc_exc_q14 = exc_q14.wrapping_add(ltp_pred_q14);
+                        let xq_q14 = lpc_exc_q14.wrapping_add(lpc_pred_q14);
+
+                        // Update states.
+                        s.diff_q14 = xq_q14.wrapping_sub(x_q10[i] << 4);
+                        let s_lf_ar_shp_q14 = s.diff_q14.wrapping_sub(n_ar_q14);
+                        s.s_ltp_shp_q14 = sub_sat32(s_lf_ar_shp_q14, n_lf_q14);
+                        s.lf_ar_q14 = s_lf_ar_shp_q14;
+                        s.lpc_exc_q14 = lpc_exc_q14;
+                        s.xq_q14 = xq_q14;
+                    });
+                });
+
+            *smpl_buf_idx = (*smpl_buf_idx + DECISION_DELAY - 1) % DECISION_DELAY;
+            let last_smple_idx = (*smpl_buf_idx + decision_delay) % DECISION_DELAY;
+
+            // Find winner.
+            let mut rd_min_q10 = sample_states[0][0].rd_q10;
+            let mut winner_ind = 0;
+            (1..n_states).for_each(|k| {
+                if sample_states[k][0].rd_q10 < rd_min_q10 {
+                    rd_min_q10 = sample_states[k][0].rd_q10;
+                    winner_ind = k;
+                }
+            });
+
+            // Increase RD values of expired states.
+            let winner_rand_state = del_dec[winner_ind].rand_state[last_smple_idx];
+            (0..n_states).for_each(|k| {
+                if del_dec[k].rand_state[last_smple_idx] != winner_rand_state {
+                    sample_states[k][0].rd_q10 =
+                        sample_states[k][0].rd_q10.wrapping_add(i32::MAX >> 4);
+                    sample_states[k][1].rd_q10 =
+                        sample_states[k][1].rd_q10.wrapping_add(i32::MAX >> 4);
+                    debug_assert!(sample_states[k][0].rd_q10 >= 0);
+                }
+            });
+
+            // Find worst in first set and best in second set.
+            let mut rd_max_q10 = sample_states[0][0].rd_q10;
+            let mut rd_min_q10 = sample_states[0][1].rd_q10;
+            let mut rd_max_ind = 0;
+            let mut rd_min_ind = 0;
+            (1..n_states).for_each(|k| {
+                // Find worst in first set.
+                if sample_states[k][0].rd_q10 > rd_max_q10 {
+                    rd_max_q10 = sample_states[k][0].rd_q10;
+                    rd_max_ind = k;
+                }
+                // Find best in second set.
+                if sample_states[k][1].rd_q10 < rd_min_q10 {
+                    rd_min_q10 = sample_states[k][1].rd_q10;
+                    rd_min_ind = k;
+                }
+            });
+
+            // Replace a state if best from second set outperforms worst in first set.
+            if rd_min_q10 < rd_max_q10 {
+                let source = del_dec[rd_min_ind].clone();
+                del_dec[rd_max_ind].copy_from(&source, i);
+                sample_states[rd_max_ind][0] = sample_states[rd_min_ind][1];
+            }
+
+            // Write samples from winner to output and long-term filter states.
+            let dd = &del_dec[winner_ind];
+            if subfr > 0 || i >= decision_delay {
+                pulses[pulses_offset + i - decision_delay] =
+                    rshift_round(dd.q_q10[last_smple_idx], 10) as i8;
+                self.xq[xq_offset + i - decision_delay] = sat16(rshift_round(
+                    smulww(dd.xq_q14[last_smple_idx], delayed_gain_q10[last_smple_idx]),
+                    8,
+                ));
+                self.s_ltp_shp_q14[self.s_ltp_shp_buf_idx - decision_delay] =
+                    dd.shape_q14[last_smple_idx];
+                s_ltp_q15[self.s_ltp_buf_idx - decision_delay] = dd.pred_q15[last_smple_idx];
+            }
+            self.s_ltp_shp_buf_idx += 1;
+            self.s_ltp_buf_idx += 1;
+
+            // Update states.
+            del_dec
+                .iter_mut()
+                .zip(sample_states.iter())
+                .for_each(|(dd, ss)| {
+                    let ss = &ss[0];
+                    dd.lf_ar_q14 = ss.lf_ar_q14;
+                    dd.diff_q14 = ss.diff_q14;
+                    dd.s_lpc_q14[NSQ_LPC_BUF_LENGTH + i] = ss.xq_q14;
+                    dd.xq_q14[*smpl_buf_idx] = ss.xq_q14;
+                    dd.q_q10[*smpl_buf_idx] = ss.q_q10;
+                    dd.pred_q15[*smpl_buf_idx] = ss.lpc_exc_q14 << 1;
+                    dd.shape_q14[*smpl_buf_idx] = ss.s_ltp_shp_q14;
+                    dd.seed = dd.seed.wrapping_add(rshift_round(ss.q_q10, 10));
+                    dd.rand_state[*smpl_buf_idx] = dd.seed;
+                    dd.rd_q10 = ss.rd_q10;
+                });
+            delayed_gain_q10[*smpl_buf_idx] = gain_q10;
+        });
+
+        // Update LPC states.
+        del_dec.iter_mut().for_each(|dd| {
+            dd.s_lpc_q14
+                .copy_within(length..length + NSQ_LPC_BUF_LENGTH, 0);
+        });
+    }
+
+    /// Scales the states of the delayed decision quantizer to the gain of the subframe.
+    #[allow(clippy::too_many_arguments)]
+    fn del_dec_scale_states(
+        &mut self,
+        params: &NsqParameters,
+        del_dec: &mut [DelDecState],
+        x16: &[i16],
+        x_sc_q10: &mut [i32],
+        s_ltp: &[i16],
+        s_ltp_q15: &mut [i32],
+        subfr: usize,
+        signal_type: usize,
+        decision_delay: usize,
+    ) {
+        let lag = params.pitch_l[subfr] as usize;
+        let gain_q16 = params.gains_q16[subfr];
+        let mut inv_gain_q31 = inverse32_varq(i32::max(gain_q16, 1), 47);
+        debug_assert!(inv_gain_q31 != 0);
+
+        // Scale input.
+        let inv_gain_q26 = rshift_round(inv_gain_q31, 5);
+        (0..params.subfr_length).for_each(|i| {
+            x_sc_q10[i] = smulww(i32::from(x16[i]), inv_gain_q26);
+        });
+
+        // After rewhitening the LTP state is un-scaled, so scale with inv_gain_Q16.
+        if self.rewhite_flag {
+            if subfr == 0 {
+                // Do LTP downscaling.
+                inv_gain_q31 = smulwb(inv_gain_q31, params.ltp_scale_q14) << 2;
+            }
+            (self.s_ltp_buf_idx - lag - LTP_ORDER / 2..self.s_ltp_buf_idx).for_each(|i| {
+                s_ltp_q15[i] = smulwb(inv_gain_q31, i32::from(s_ltp[i]));
+            });
+        }
+
+        // Adjust for changing gain.
+        if gain_q16 != self.prev_gain_q16 {
+            let gain_adj_q16 = div32_varq(self.prev_gain_q16, gain_q16, 16);
+
+            // Scale long-term shaping state.
+            (self.s_ltp_shp_buf_idx - params.ltp_mem_length..self.s_ltp_shp_buf_idx).for_each(
+                |i| {
+                    self.s_ltp_shp_q14[i] = smulww(gain_adj_q16, self.s_ltp_shp_q14[i]);
+                },
+            );
+
+            // Scale long-term prediction state.
+            if signal_type == TYPE_VOICED && !self.rewhite_flag {
+                (self.s_ltp_buf_idx - lag - LTP_ORDER / 2..self.s_ltp_buf_idx - decision_delay)
+                    .for_each(|i| {
+                        s_ltp_q15[i] = smulww(gain_adj_q16, s_ltp_q15[i]);
+                    });
+            }
+
+            del_dec.iter_mut().for_each(|dd| {
+                // Scale scalar states.
+                dd.lf_ar_q14 = smulww(gain_adj_q16, dd.lf_ar_q14);
+                dd.diff_q14 = smulww(gain_adj_q16, dd.diff_q14);
+
+                // Scale short-term prediction and shaping states.
+                dd.s_lpc_q14
+                    .iter_mut()
+                    .take(NSQ_LPC_BUF_LENGTH)
+                    .for_each(|x| *x = smulww(gain_adj_q16, *x));
+                dd.s_ar2_q14
+                    .iter_mut()
+                    .for_each(|x| *x = smulww(gain_adj_q16, *x));
+                dd.pred_q15
+                    .iter_mut()
+                    .for_each(|x| *x = smulww(gain_adj_q16, *x));
+                dd.shape_q14
+                    .iter_mut()
+                    .for_each(|x| *x = smulww(gain_adj_q16, *x));
+            });
+
+            // Save inverse gain.
+            self.prev_gain_q16 = gain_q16;
+        }
+    }
+}
+
+/// Returns the index of the delayed decision state with the lowest rate-distortion.
+fn find_winner(del_dec: &[DelDecState]) -> usize {
+    let mut rd_min_q10 = del_dec[0].rd_q10;
+    let mut winner_ind = 0;
+    (1..del_dec.len()).for_each(|i| {
+        if del_dec[i].rd_q10 < rd_min_q10 {
+            rd_min_q10 = del_dec[i].rd_q10;
+            winner_ind = i;
+        }
+    });
+    winner_ind
+}
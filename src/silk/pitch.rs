@@ -0,0 +1,620 @@
+//! Implements the pitch analysis and the pitch lag decoding.
+
+use crate::celt::pitch_xcorr;
+use crate::silk::fixed::{add_sat16, limit, rshift_round, sat16, smulbb};
+use crate::silk::float::{energy, float2int, inner_product, insertion_sort_decreasing, log2};
+use crate::silk::resampler::{resampler_down2, resampler_down2_3};
+use crate::silk::tables::{
+    CB_LAGS_STAGE2, CB_LAGS_STAGE2_10_MS, CB_LAGS_STAGE3, CB_LAGS_STAGE3_10_MS, LAG_RANGE_STAGE3,
+    LAG_RANGE_STAGE3_10_MS, NB_CBK_SEARCHS_STAGE3,
+};
+use crate::silk::MAX_NB_SUBFR;
+
+pub(crate) const PE_MIN_LAG_MS: i32 = 2;
+pub(crate) const PE_MAX_LAG_MS: i32 = 18;
+
+const PE_MAX_FS_KHZ: usize = 16;
+const PE_SUBFR_LENGTH_MS: usize = 5;
+const PE_LTP_MEM_LENGTH_MS: usize = 4 * PE_SUBFR_LENGTH_MS;
+const PE_MAX_FRAME_LENGTH_MS: usize = PE_LTP_MEM_LENGTH_MS + MAX_NB_SUBFR * PE_SUBFR_LENGTH_MS;
+const PE_MAX_LAG: usize = PE_MAX_LAG_MS as usize * PE_MAX_FS_KHZ;
+const PE_D_SRCH_LENGTH: usize = 24;
+const PE_NB_STAGE3_LAGS: usize = 5;
+const PE_NB_CBKS_STAGE2: usize = 3;
+const PE_NB_CBKS_STAGE2_EXT: usize = 11;
+const PE_NB_CBKS_STAGE3_MAX: usize = 34;
+const PE_NB_CBKS_STAGE3_10MS: usize = 12;
+const PE_NB_CBKS_STAGE2_10MS: usize = 3;
+
+/// For logarithmic weighting.
+const PE_SHORTLAG_BIAS: f32 = 0.2;
+/// For logarithmic weighting.
+const PE_PREVLAG_BIAS: f32 = 0.2;
+const PE_FLATCONTOUR_BIAS: f32 = 0.05;
+
+/// Minimal complexity of the pitch estimation.
+pub(crate) const PE_MIN_COMPLEX: usize = 0;
+/// Medium complexity of the pitch estimation.
+pub(crate) const PE_MID_COMPLEX: usize = 1;
+/// Maximal complexity of the pitch estimation.
+pub(crate) const PE_MAX_COMPLEX: usize = 2;
+
+const SCRATCH_SIZE: usize = 22;
+
+/// Decodes the pitch lags of all subframes.
+pub(crate) fn decode_pitch(
+    lag_index: i16,
+    contour_index: usize,
+    pitch_lags: &mut [i32],
+    fs_khz: usize,
+    nb_subfr: usize,
+) {
+    let min_lag = PE_MIN_LAG_MS * fs_khz as i32;
+    let max_lag = PE_MAX_LAG_MS * fs_khz as i32;
+    let lag = min_lag + i32::from(lag_index);
+
+    (0..nb_subfr).for_each(|k| {
+        let offset = if fs_khz == 8 {
+            if nb_subfr == MAX_NB_SUBFR {
+                CB_LAGS_STAGE2[k][contour_index]
+            } else {
+                CB_LAGS_STAGE2_10_MS[k][contour_index]
+            }
+        } else if nb_subfr == MAX_NB_SUBFR {
+            CB_LAGS_STAGE3[k][contour_index]
+        } else {
+            CB_LAGS_STAGE3_10_MS[k][contour_index]
+        };
+        pitch_lags[k] = limit(lag + i32::from(offset), min_lag, max_lag);
+    });
+}
+
+/// The results of the pitch analysis of a voiced frame.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct PitchEstimate {
+    /// Pitch lag values of each subframe.
+    pub(crate) pitch_lags: [i32; MAX_NB_SUBFR],
+    /// Lag index.
+    pub(crate) lag_index: i16,
+    /// Pitch contour index.
+    pub(crate) contour_index: i8,
+}
+
+/// Returns the lag offset of the given subframe and codebook vector.
+fn lag_cb(stage3: bool, nb_subfr: usize, k: usize, j: usize) -> i32 {
+    let offset = match (stage3, nb_subfr == MAX_NB_SUBFR) {
+        (false, true) => CB_LAGS_STAGE2[k][j],
+        (false, false) => CB_LAGS_STAGE2_10_MS[k][j],
+        (true, true) => CB_LAGS_STAGE3[k][j],
+        (true, false) => CB_LAGS_STAGE3_10_MS[k][j],
+    };
+    i32::from(offset)
+}
+
+/// Core pitch analysis function.
+///
+/// `frame` holds the signal of length `(PE_LTP_MEM_LENGTH_MS + nb_subfr * PE_SUBFR_LENGTH_MS) * fs_khz`.
+/// `ltp_corr` holds the normalized correlation of the previous frame on input. `prev_lag` is the last
+/// lag of the previous frame and zero if it was unvoiced. `complexity` must be 0 to 2, where 2 is
+/// the highest.
+///
+/// Returns the pitch estimate if the frame is voiced.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn pitch_analysis_core(
+    frame: &[f32],
+    ltp_corr: &mut f32,
+    prev_lag: i32,
+    search_thres1: f32,
+    search_thres2: f32,
+    fs_khz: usize,
+    complexity: usize,
+    nb_subfr: usize,
+) -> Option<PitchEstimate> {
+    debug_assert!(fs_khz == 8 || fs_khz == 12 || fs_khz == 16);
+    debug_assert!(complexity <= PE_MAX_COMPLEX);
+
+    let mut frame_8khz = [0.0_f32; PE_MAX_FRAME_LENGTH_MS * 8];
+    let mut frame_4khz = [0.0_f32; PE_MAX_FRAME_LENGTH_MS * 4];
+    let mut frame_8_fix = [0_i16; PE_MAX_FRAME_LENGTH_MS * 8];
+    let mut frame_4_fix = [0_i16; PE_MAX_FRAME_LENGTH_MS * 4];
+    let mut c = [[0.0_f32; (PE_MAX_LAG >> 1) + 5]; MAX_NB_SUBFR];
+    let mut xcorr = [0.0_f32; (PE_MAX_LAG_MS as usize - PE_MIN_LAG_MS as usize) * 4 + 1];
+    let mut cc = [0.0_f32; PE_NB_CBKS_STAGE2_EXT];
+    let mut d_srch = [0_usize; PE_D_SRCH_LENGTH];
+    let mut d_comp = [0_i16; (PE_MAX_LAG >> 1) + 5];
+
+    // Set up frame lengths max / min lag for the sampling frequency.
+    let frame_length = (PE_LTP_MEM_LENGTH_MS + nb_subfr * PE_SUBFR_LENGTH_MS) * fs_khz;
+    let frame_length_4khz = (PE_LTP_MEM_LENGTH_MS + nb_subfr * PE_SUBFR_LENGTH_MS) * 4;
+    let frame_length_8khz = (PE_LTP_MEM_LENGTH_MS + nb_subfr * PE_SUBFR_LENGTH_MS) * 8;
+    let sf_length = PE_SUBFR_LENGTH_MS * fs_khz;
+    let sf_length_4khz = PE_SUBFR_LENGTH_MS * 4;
+    let sf_length_8khz = PE_SUBFR_LENGTH_MS * 8;
+    let min_lag = PE_MIN_LAG_MS as usize * fs_khz;
+    let min_lag_4khz = PE_MIN_LAG_MS as usize * 4;
+    let min_lag_8khz = PE_MIN_LAG_MS as usize * 8;
+    let max_lag = PE_MAX_LAG_MS as usize * fs_khz - 1;
+    let max_lag_4khz = PE_MAX_LAG_MS as usize * 4;
+    let max_lag_8khz = PE_MAX_LAG_MS as usize * 8 - 1;
+
+    // Resample from input sampled at fs_khz to 8 kHz.
+    if fs_khz == 8 {
+        float2short_array(&mut frame_8_fix[..frame_length_8khz], frame);
+    } else {
+        let mut frame_fix = [0_i16; PE_MAX_FS_KHZ * PE_MAX_FRAME_LENGTH_MS];
+        float2short_array(&mut frame_fix[..frame_length], frame);
+        if fs_khz == 16 {
+            // Resample to 16 -> 8 khz.
+            let mut filt_state = [0_i32; 2];
+            resampler_down2(
+                &mut filt_state,
+                &mut frame_8_fix,
+                &frame_fix[..frame_length],
+            );
+        } else {
+            // Resample to 12 -> 8 khz.
+            let mut filt_state = [0_i32; 6];
+            resampler_down2_3(
+                &mut filt_state,
+                &mut frame_8_fix,
+                &frame_fix[..frame_length],
+            );
+        }
+        short2float_array(&mut frame_8khz[..frame_length_8khz], &frame_8_fix);
+    }
+
+    // Decimate again to 4 kHz.
+    let mut filt_state = [0_i32; 2];
+    resampler_down2(
+        &mut filt_state,
+        &mut frame_4_fix,
+        &frame_8_fix[..frame_length_8khz],
+    );
+    short2float_array(&mut frame_4khz[..frame_length_4khz], &frame_4_fix);
+
+    // Low-pass filter.
+    (1..frame_length_4khz).rev().for_each(|i| {
+        frame_4khz[i] = f32::from(add_sat16(frame_4khz[i] as i16, frame_4khz[i - 1] as i16));
+    });
+
+    // FIRST STAGE, operating in 4 khz.
+    let mut target_ptr = sf_length_4khz << 2;
+    (0..nb_subfr >> 1).for_each(|_| {
+        let target = &frame_4khz[target_ptr..];
+        let mut basis_ptr = target_ptr - min_lag_4khz;
+
+        pitch_xcorr(
+            target,
+            &frame_4khz[target_ptr - max_lag_4khz..],
+            &mut xcorr,
+            sf_length_8khz,
+            max_lag_4khz - min_lag_4khz + 1,
+        );
+
+        // Calculate first vector products before loop.
+        let cross_corr = f64::from(xcorr[max_lag_4khz - min_lag_4khz]);
+        let mut normalizer = energy(&target[..sf_length_8khz])
+            + energy(&frame_4khz[basis_ptr..basis_ptr + sf_length_8khz])
+            + f64::from(sf_length_8khz as f32 * 4000.0);
+        c[0][min_lag_4khz] += (2.0 * cross_corr / normalizer) as f32;
+
+        // From now on normalizer is computed recursively.
+        (min_lag_4khz + 1..=max_lag_4khz).for_each(|d| {
+            basis_ptr -= 1;
+
+            let cross_corr = f64::from(xcorr[max_lag_4khz - d]);
+
+            // Add contribution of new sample and remove contribution from oldest sample.
+            let new = f64::from(frame_4khz[basis_ptr]);
+            let old = f64::from(frame_4khz[basis_ptr + sf_length_8khz]);
+            normalizer += new * new - old * old;
+            c[0][d] += (2.0 * cross_corr / normalizer) as f32;
+        });
+
+        // Update target pointer.
+        target_ptr += sf_length_8khz;
+    });
+
+    // Apply short-lag bias.
+    (min_lag_4khz..=max_lag_4khz).rev().for_each(|i| {
+        c[0][i] -= c[0][i] * i as f32 / 4096.0;
+    });
+
+    // Sort.
+    let mut length_d_srch = 4 + 2 * complexity;
+    debug_assert!(3 * length_d_srch <= PE_D_SRCH_LENGTH);
+    insertion_sort_decreasing(
+        &mut c[0][min_lag_4khz..=max_lag_4khz],
+        &mut d_srch,
+        length_d_srch,
+    );
+
+    // Escape if correlation is very low already here.
+    let cmax = c[0][min_lag_4khz];
+    if cmax < 0.2 {
+        *ltp_corr = 0.0;
+        return None;
+    }
+
+    let threshold = search_thres1 * cmax;
+    if let Some(i) = (0..length_d_srch).find(|&i| c[0][min_lag_4khz + i] <= threshold) {
+        length_d_srch = i;
+    }
+    // Convert to 8 kHz indices for the sorted correlation that exceeds the threshold.
+    d_srch[..length_d_srch]
+        .iter_mut()
+        .for_each(|d| *d = (*d + min_lag_4khz) << 1);
+    debug_assert!(length_d_srch > 0);
+
+    (min_lag_8khz - 5..max_lag_8khz + 5).for_each(|i| d_comp[i] = 0);
+    d_srch[..length_d_srch].iter().for_each(|&d| d_comp[d] = 1);
+
+    // Convolution.
+    (min_lag_8khz..=max_lag_8khz + 3)
+        .rev()
+        .for_each(|i| d_comp[i] += d_comp[i - 1] + d_comp[i - 2]);
+
+    length_d_srch = 0;
+    (min_lag_8khz..max_lag_8khz + 1).for_each(|i| {
+        if d_comp[i + 1] > 0 {
+            d_srch[length_d_srch] = i;
+            length_d_srch += 1;
+        }
+    });
+
+    // Convolution.
+    (min_lag_8khz..=max_lag_8khz + 3)
+        .rev()
+        .for_each(|i| d_comp[i] += d_comp[i - 1] + d_comp[i - 2] + d_comp[i - 3]);
+
+    let mut length_d_comp = 0;
+    (min_lag_8khz..max_lag_8khz + 4).for_each(|i| {
+        if d_comp[i] > 0 {
+            d_comp[length_d_comp] = i as i16 - 2;
+            length_d_comp += 1;
+        }
+    });
+
+    // SECOND STAGE, operating at 8 kHz, on lag sections with high correlation.
+
+    // Find energy of each subframe projected onto its history, for a range of delays.
+    c.iter_mut()
+        .for_each(|c| c.iter_mut().for_each(|x| *x = 0.0));
+
+    let frame_8 = if fs_khz == 8 { frame } else { &frame_8khz[..] };
+    let mut target_ptr = PE_LTP_MEM_LENGTH_MS * 8;
+    (0..nb_subfr).for_each(|k| {
+        let target = &frame_8[target_ptr..target_ptr + sf_length_8khz];
+        let energy_tmp = energy(target) + 1.0;
+        d_comp[..length_d_comp].iter().for_each(|&d| {
+            let d = d as usize;
+            let basis = &frame_8[target_ptr - d..target_ptr - d + sf_length_8khz];
+            let cross_corr = inner_product(basis, target, sf_length_8khz);
+            c[k][d] = if cross_corr > 0.0 {
+                let energy = energy(basis);
+                (2.0 * cross_corr / (energy + energy_tmp)) as f32
+            } else {
+                0.0
+            };
+        });
+        target_ptr += sf_length_8khz;
+    });
+
+    // Search over lag range and lags codebook.
+    // Scale factor for lag codebook, as a function of center lag.
+    let mut cc_max = 0.0_f32; // This value doesn't matter.
+    let mut cc_max_b = -1000.0_f32;
+    let mut cb_imax = 0; // To avoid returning undefined lag values.
+    let mut lag: i32 = -1; // To check if lag with strong enough correlation has been found.
+
+    let mut prev_lag = prev_lag;
+    let prev_lag_log2 = if prev_lag > 0 {
+        if fs_khz == 12 {
+            prev_lag = (prev_lag << 1) / 3;
+        } else if fs_khz == 16 {
+            prev_lag >>= 1;
+        }
+        log2(f64::from(prev_lag))
+    } else {
+        0.0
+    };
+
+    // Set up stage 2 codebook based on number of subframes.
+    let nb_cbk_search = if nb_subfr == MAX_NB_SUBFR {
+        if fs_khz == 8 && complexity > PE_MIN_COMPLEX {
+            // If input is 8 khz use a larger codebook here because it is last stage.
+            PE_NB_CBKS_STAGE2_EXT
+        } else {
+            PE_NB_CBKS_STAGE2
+        }
+    } else {
+        PE_NB_CBKS_STAGE2_10MS
+    };
+
+    d_srch[..length_d_srch].iter().for_each(|&d| {
+        (0..nb_cbk_search).for_each(|j| {
+            cc[j] = 0.0;
+            (0..nb_subfr).for_each(|i| {
+                // Try all codebooks.
+                cc[j] += c[i][(d as i32 + lag_cb(false, nb_subfr, i, j)) as usize];
+            });
+        });
+
+        // Find best codebook.
+        let mut cc_max_new = -1000.0_f32;
+        let mut cb_imax_new = 0;
+        (0..nb_cbk_search).for_each(|i| {
+            if cc[i] > cc_max_new {
+                cc_max_new = cc[i];
+                cb_imax_new = i;
+            }
+        });
+
+        // Bias towards shorter lags.
+        let lag_log2 = log2(d as f64);
+        let mut cc_max_new_b = cc_max_new - PE_SHORTLAG_BIAS * nb_subfr as f32 * lag_log2;
+
+        // Bias towards previous lag.
+        if prev_lag > 0 {
+            let mut delta_lag_log2_sqr = lag_log2 - prev_lag_log2;
+            delta_lag_log2_sqr *= delta_lag_log2_sqr;
+            cc_max_new_b -= PE_PREVLAG_BIAS * nb_subfr as f32 * *ltp_corr * delta_lag_log2_sqr
+                / (delta_lag_log2_sqr + 0.5);
+        }
+
+        if cc_max_new_b > cc_max_b // Find maximum biased correlation.
+            && cc_max_new > nb_subfr as f32 * search_thres2
+        // Correlation needs to be high enough to be voiced.
+        {
+            cc_max_b = cc_max_new_b;
+            cc_max = cc_max_new;
+            lag = d as i32;
+            cb_imax = cb_imax_new;
+        }
+    });
+
+    if lag == -1 {
+        // No suitable candidate found.
+        *ltp_corr = 0.0;
+        return None;
+    }
+
+    // Output normalized correlation.
+    *ltp_corr = cc_max / nb_subfr as f32;
+
+    let mut estimate = PitchEstimate::default();
+    if fs_khz > 8 {
+        // Search in original signal.
+
+        // Compensate for decimation.
+        if fs_khz == 12 {
+            lag = rshift_round(smulbb(lag, 3), 1);
+        } else {
+            lag <<= 1;
+        }
+
+        let lag = limit(lag, min_lag as i32, max_lag as i32) as usize;
+        let start_lag = usize::max(lag - 2, min_lag);
+        let end_lag = usize::min(lag + 2, max_lag);
+        let mut lag_new = lag; // To avoid undefined lag.
+        let mut cb_imax = 0; // To avoid undefined lag.
+
+        let mut cc_max = -1000.0_f32;
+
+        // Calculate the correlations and energies needed in stage 3.
+        let mut energies_st3 =
+            [[[0.0_f32; PE_NB_STAGE3_LAGS]; PE_NB_CBKS_STAGE3_MAX]; MAX_NB_SUBFR];
+        let mut cross_corr_st3 =
+            [[[0.0_f32; PE_NB_STAGE3_LAGS]; PE_NB_CBKS_STAGE3_MAX]; MAX_NB_SUBFR];
+        calc_corr_st3(
+            &mut cross_corr_st3,
+            frame,
+            start_lag,
+            sf_length,
+            nb_subfr,
+            complexity,
+        );
+        calc_energy_st3(
+            &mut energies_st3,
+            frame,
+            start_lag,
+            sf_length,
+            nb_subfr,
+            complexity,
+        );
+
+        let contour_bias = PE_FLATCONTOUR_BIAS / lag as f32;
+
+        // Set up cbk parameters according to complexity setting and frame length.
+        let nb_cbk_search = if nb_subfr == MAX_NB_SUBFR {
+            NB_CBK_SEARCHS_STAGE3[complexity]
+        } else {
+            PE_NB_CBKS_STAGE3_10MS
+        };
+
+        let target_ptr = PE_LTP_MEM_LENGTH_MS * fs_khz;
+        let energy_tmp = energy(&frame[target_ptr..target_ptr + nb_subfr * sf_length]) + 1.0;
+        (start_lag..=end_lag)
+            .enumerate()
+            .for_each(|(lag_counter, d)| {
+                (0..nb_cbk_search).for_each(|j| {
+                    let mut cross_corr = 0.0_f64;
+                    let mut energy = energy_tmp;
+                    (0..nb_subfr).for_each(|k| {
+                        cross_corr += f64::from(cross_corr_st3[k][j][lag_counter]);
+                        energy += f64::from(energies_st3[k][j][lag_counter]);
+                    });
+                    let cc_max_new = if cross_corr > 0.0 {
+                        // Reduce depending on flatness of contour.
+                        (2.0 * cross_corr / energy) as f32 * (1.0 - contour_bias * j as f32)
+                    } else {
+                        0.0
+                    };
+
+                    if cc_max_new > cc_max
+                        && (d as i32 + i32::from(CB_LAGS_STAGE3[0][j])) <= max_lag as i32
+                    {
+                        cc_max = cc_max_new;
+                        lag_new = d;
+                        cb_imax = j;
+                    }
+                });
+            });
+
+        (0..nb_subfr).for_each(|k| {
+            estimate.pitch_lags[k] = limit(
+                lag_new as i32 + lag_cb(true, nb_subfr, k, cb_imax),
+                min_lag as i32,
+                PE_MAX_LAG_MS * fs_khz as i32,
+            );
+        });
+        estimate.lag_index = (lag_new - min_lag) as i16;
+        estimate.contour_index = cb_imax as i8;
+    } else {
+        // Save Lags.
+        (0..nb_subfr).for_each(|k| {
+            estimate.pitch_lags[k] = limit(
+                lag + lag_cb(false, nb_subfr, k, cb_imax),
+                min_lag_8khz as i32,
+                PE_MAX_LAG_MS * 8,
+            );
+        });
+        estimate.lag_index = (lag as usize - min_lag_8khz) as i16;
+        estimate.contour_index = cb_imax as i8;
+    }
+    debug_assert!(estimate.lag_index >= 0);
+
+    Some(estimate)
+}
+
+/// Returns the lag range of stage 3 of the given subframe.
+fn lag_range_st3(nb_subfr: usize, complexity: usize, k: usize) -> (i32, i32) {
+    let range = if nb_subfr == MAX_NB_SUBFR {
+        LAG_RANGE_STAGE3[complexity][k]
+    } else {
+        LAG_RANGE_STAGE3_10_MS[k]
+    };
+    (i32::from(range[0]), i32::from(range[1]))
+}
+
+/// Returns the number of codebook vectors searched in stage 3.
+fn nb_cbk_search_st3(nb_subfr: usize, complexity: usize) -> usize {
+    if nb_subfr == MAX_NB_SUBFR {
+        NB_CBK_SEARCHS_STAGE3[complexity]
+    } else {
+        PE_NB_CBKS_STAGE3_10MS
+    }
+}
+
+/// Calculates the correlations used in stage 3 search. In order to cover
+/// the whole lag codebook for all the searched offset lags (lag +- 2),
+/// the following correlations are needed in each sub frame:
+///
+/// * sf1: lag range [-8,...,7] total 16 correlations
+/// * sf2: lag range [-4,...,4] total 9 correlations
+/// * sf3: lag range [-3,....4] total 8 correltions
+/// * sf4: lag range [-6,....8] total 15 correlations
+///
+/// In total 48 correlations. The direct implementation computed in worst
+/// case 4*12*5 = 240 correlations, but more likely around 120.
+fn calc_corr_st3(
+    cross_corr_st3: &mut [[[f32; PE_NB_STAGE3_LAGS]; PE_NB_CBKS_STAGE3_MAX]; MAX_NB_SUBFR],
+    frame: &[f32],
+    start_lag: usize,
+    sf_length: usize,
+    nb_subfr: usize,
+    complexity: usize,
+) {
+    let mut scratch_mem = [0.0_f32; SCRATCH_SIZE];
+    let mut xcorr = [0.0_f32; SCRATCH_SIZE];
+    let nb_cbk_search = nb_cbk_search_st3(nb_subfr, complexity);
+
+    // Pointer to middle of frame.
+    let mut target_ptr = sf_length << 2;
+    (0..nb_subfr).for_each(|k| {
+        // Calculate the correlations for each subframe.
+        let (lag_low, lag_high) = lag_range_st3(nb_subfr, complexity, k);
+        let count = (lag_high - lag_low + 1) as usize;
+        debug_assert!(count <= SCRATCH_SIZE);
+
+        let basis_ptr = (target_ptr as i32 - start_lag as i32 - lag_high) as usize;
+        pitch_xcorr(
+            &frame[target_ptr..],
+            &frame[basis_ptr..],
+            &mut xcorr,
+            sf_length,
+            count,
+        );
+        (0..count).for_each(|i| {
+            scratch_mem[i] = xcorr[count - 1 - i];
+        });
+
+        (0..nb_cbk_search).for_each(|i| {
+            // Fill out the 3 dim array that stores the correlations for
+            // each code_book vector for each start lag.
+            let idx = (lag_cb(true, nb_subfr, k, i) - lag_low) as usize;
+            cross_corr_st3[k][i].copy_from_slice(&scratch_mem[idx..idx + PE_NB_STAGE3_LAGS]);
+        });
+
+        target_ptr += sf_length;
+    });
+}
+
+/// Calculates the energies for the stage 3 search. The energies are calculated recursively.
+fn calc_energy_st3(
+    energies_st3: &mut [[[f32; PE_NB_STAGE3_LAGS]; PE_NB_CBKS_STAGE3_MAX]; MAX_NB_SUBFR],
+    frame: &[f32],
+    start_lag: usize,
+    sf_length: usize,
+    nb_subfr: usize,
+    complexity: usize,
+) {
+    let mut scratch_mem = [0.0_f32; SCRATCH_SIZE];
+    let nb_cbk_search = nb_cbk_search_st3(nb_subfr, complexity);
+
+    let mut target_ptr = sf_length << 2;
+    (0..nb_subfr).for_each(|k| {
+        let (lag_low, lag_high) = lag_range_st3(nb_subfr, complexity, k);
+
+        // Calculate the energy for first lag.
+        let basis_ptr = (target_ptr as i32 - (start_lag as i32 + lag_low)) as usize;
+        let mut energy = energy(&frame[basis_ptr..basis_ptr + sf_length]) + 1e-3;
+        scratch_mem[0] = energy as f32;
+
+        let lag_diff = (lag_high - lag_low + 1) as usize;
+        (1..lag_diff).for_each(|i| {
+            // Remove part outside new window.
+            let old = f64::from(frame[basis_ptr + sf_length - i]);
+            energy -= old * old;
+
+            // Add part that comes into window.
+            let new = f64::from(frame[basis_ptr - i]);
+            energy += new * new;
+
+            scratch_mem[i] = energy as f32;
+        });
+
+        (0..nb_cbk_search).for_each(|i| {
+            // Fill out the 3 dim array that stores the correlations for
+            // each code_book vector for each start lag.
+            let idx = (lag_cb(true, nb_subfr, k, i) - lag_low) as usize;
+            energies_st3[k][i].copy_from_slice(&scratch_mem[idx..idx + PE_NB_STAGE3_LAGS]);
+        });
+
+        target_ptr += sf_length;
+    });
+}
+
+/// Converts a float array into a 16 bit integer array with rounding and saturation.
+fn float2short_array(out: &mut [i16], input: &[f32]) {
+    out.iter_mut()
+        .zip(input.iter())
+        .for_each(|(out, x)| *out = sat16(float2int(*x)));
+}
+
+/// Converts a 16 bit integer array into a float array.
+fn short2float_array(out: &mut [f32], input: &[i16]) {
+    out.iter_mut()
+        .zip(input.iter())
+        .for_each(|(out, x)| *out = f32::from(*x));
+}
@@ -0,0 +1,496 @@
+//! Implements the coding of the excitation pulses.
+
+use crate::range_coder::{RangeDecoder, RangeEncoder};
+use crate::silk::tables::{
+    LSB_ICDF, MAX_PULSES_TABLE, PULSES_PER_BLOCK_BITS_Q5, PULSES_PER_BLOCK_ICDF,
+    RATE_LEVELS_BITS_Q5, RATE_LEVELS_ICDF, SHELL_CODE_TABLE0, SHELL_CODE_TABLE1, SHELL_CODE_TABLE2,
+    SHELL_CODE_TABLE3, SHELL_CODE_TABLE_OFFSETS, SIGN_ICDF,
+};
+use crate::silk::{MAX_NB_SHELL_BLOCKS, N_RATE_LEVELS, SHELL_CODEC_FRAME_LENGTH, SILK_MAX_PULSES};
+use crate::OpusError;
+
+const LOG2_SHELL_CODEC_FRAME_LENGTH: usize = 4;
+
+/// Decodes the quantization indices of the excitation.
+///
+/// `pulses` needs to be a multiple of the shell codec frame length and at least `frame_length` long.
+pub(crate) fn decode_pulses(
+    dec: &mut RangeDecoder,
+    pulses: &mut [i16],
+    signal_type: usize,
+    quant_offset_type: usize,
+    frame_length: usize,
+) {
+    let mut sum_pulses = [0_usize; MAX_NB_SHELL_BLOCKS];
+    let mut n_lshifts = [0_usize; MAX_NB_SHELL_BLOCKS];
+
+    // Decode rate level.
+    let rate_level_index = dec.decode_icdf(&RATE_LEVELS_ICDF[signal_type >> 1], 8) as usize;
+
+    // Calculate number of shell blocks.
+    let mut iter = frame_length >> LOG2_SHELL_CODEC_FRAME_LENGTH;
+    if iter * SHELL_CODEC_FRAME_LENGTH < frame_length {
+        // Only happens for 10 ms @ 12 kHz.
+        debug_assert_eq!(frame_length, 12 * 10);
+        iter += 1;
+    }
+
+    // Sum-weighted-pulses decoding.
+    let cdf = &PULSES_PER_BLOCK_ICDF[rate_level_index];
+    (0..iter).for_each(|i| {
+        n_lshifts[i] = 0;
+        sum_pulses[i] = dec.decode_icdf(cdf, 8) as usize;
+
+        // LSB indication.
+        while sum_pulses[i] == SILK_MAX_PULSES + 1 {
+            n_lshifts[i] += 1;
+            // When we've already got 10 LSBs, we shift the table to not allow (SILK_MAX_PULSES + 1).
+            let offset = usize::from(n_lshifts[i] == 10);
+            sum_pulses[i] =
+                dec.decode_icdf(&PULSES_PER_BLOCK_ICDF[N_RATE_LEVELS - 1][offset..], 8) as usize;
+        }
+    });
+
+    // Shell decoding.
+    (0..iter).for_each(|i| {
+        let block = &mut pulses[i * SHELL_CODEC_FRAME_LENGTH..(i + 1) * SHELL_CODEC_FRAME_LENGTH];
+        if sum_pulses[i] > 0 {
+            shell_decoder(block, dec, sum_pulses[i]);
+        } else {
+            block.iter_mut().for_each(|x| *x = 0);
+        }
+    });
+
+    // LSB decoding.
+    (0..iter).for_each(|i| {
+        if n_lshifts[i] > 0 {
+            let n_ls = n_lshifts[i];
+            let block =
+                &mut pulses[i * SHELL_CODEC_FRAME_LENGTH..(i + 1) * SHELL_CODEC_FRAME_LENGTH];
+            block.iter_mut().for_each(|x| {
+                let mut abs_q = i32::from(*x);
+                (0..n_ls).for_each(|_| {
+                    abs_q <<= 1;
+                    abs_q += dec.decode_icdf(LSB_ICDF, 8) as i32;
+                });
+                *x = abs_q as i16;
+            });
+
+            // Mark the number of pulses non-zero for sign decoding.
+            sum_pulses[i] |= n_ls << 5;
+        }
+    });
+
+    // Decode and add signs to pulse signal.
+    decode_signs(
+        dec,
+        pulses,
+        frame_length,
+        signal_type,
+        quant_offset_type,
+        &sum_pulses,
+    );
+}
+
+/// Shell decoder, operates on one shell code frame of 16 pulses.
+fn shell_decoder(pulses0: &mut [i16], dec: &mut RangeDecoder, pulses4: usize) {
+    let mut pulses3 = [0_i16; 2];
+    let mut pulses2 = [0_i16; 4];
+    let mut pulses1 = [0_i16; 8];
+
+    decode_split(&mut pulses3[0..2], dec, pulses4, SHELL_CODE_TABLE3);
+    decode_split(
+        &mut pulses2[0..2],
+        dec,
+        pulses3[0] as usize,
+        SHELL_CODE_TABLE2,
+    );
+    decode_split(
+        &mut pulses1[0..2],
+        dec,
+        pulses2[0] as usize,
+        SHELL_CODE_TABLE1,
+    );
+    decode_split(
+        &mut pulses0[0..2],
+        dec,
+        pulses1[0] as usize,
+        SHELL_CODE_TABLE0,
+    );
+    decode_split(
+        &mut pulses0[2..4],
+        dec,
+        pulses1[1] as usize,
+        SHELL_CODE_TABLE0,
+    );
+    decode_split(
+        &mut pulses1[2..4],
+        dec,
+        pulses2[1] as usize,
+        SHELL_CODE_TABLE1,
+    );
+    decode_split(
+        &mut pulses0[4..6],
+        dec,
+        pulses1[2] as usize,
+        SHELL_CODE_TABLE0,
+    );
+    decode_split(
+        &mut pulses0[6..8],
+        dec,
+        pulses1[3] as usize,
+        SHELL_CODE_TABLE0,
+    );
+    decode_split(
+        &mut pulses2[2..4],
+        dec,
+        pulses3[1] as usize,
+        SHELL_CODE_TABLE2,
+    );
+    decode_split(
+        &mut pulses1[4..6],
+        dec,
+        pulses2[2] as usize,
+        SHELL_CODE_TABLE1,
+    );
+    decode_split(
+        &mut pulses0[8..10],
+        dec,
+        pulses1[4] as usize,
+        SHELL_CODE_TABLE0,
+    );
+    decode_split(
+        &mut pulses0[10..12],
+        dec,
+        pulses1[5] as usize,
+        SHELL_CODE_TABLE0,
+    );
+    decode_split(
+        &mut pulses1[6..8],
+        dec,
+        pulses2[3] as usize,
+        SHELL_CODE_TABLE1,
+    );
+    decode_split(
+        &mut pulses0[12..14],
+        dec,
+        pulses1[6] as usize,
+        SHELL_CODE_TABLE0,
+    );
+    decode_split(
+        &mut pulses0[14..16],
+        dec,
+        pulses1[7] as usize,
+        SHELL_CODE_TABLE0,
+    );
+}
+
+/// Splits the pulses `p` of the current subframe into its two child subframes.
+#[inline(always)]
+fn decode_split(children: &mut [i16], dec: &mut RangeDecoder, p: usize, shell_table: &[u8]) {
+    if p > 0 {
+        let offset = usize::from(SHELL_CODE_TABLE_OFFSETS[p]);
+        let child1 = dec.decode_icdf(&shell_table[offset..], 8) as usize;
+        children[0] = child1 as i16;
+        children[1] = (p - child1) as i16;
+    } else {
+        children[0] = 0;
+        children[1] = 0;
+    }
+}
+
+/// Decodes the signs of the excitation.
+fn decode_signs(
+    dec: &mut RangeDecoder,
+    pulses: &mut [i16],
+    length: usize,
+    signal_type: usize,
+    quant_offset_type: usize,
+    sum_pulses: &[usize; MAX_NB_SHELL_BLOCKS],
+) {
+    let mut icdf = [0_u8; 2];
+    let icdf_offset = 7 * (quant_offset_type + (signal_type << 1));
+    let icdf_table = &SIGN_ICDF[icdf_offset..];
+    let length = (length + SHELL_CODEC_FRAME_LENGTH / 2) >> LOG2_SHELL_CODEC_FRAME_LENGTH;
+
+    pulses
+        .chunks_exact_mut(SHELL_CODEC_FRAME_LENGTH)
+        .zip(sum_pulses.iter())
+        .take(length)
+        .for_each(|(q, &p)| {
+            if p > 0 {
+                icdf[0] = icdf_table[usize::min(p & 0x1F, 6)];
+                q.iter_mut().for_each(|x| {
+                    if *x > 0 {
+                        // Attach sign.
+                        if dec.decode_icdf(&icdf, 8) == 0 {
+                            *x = -*x;
+                        }
+                    }
+                });
+            }
+        });
+}
+
+/// Encodes the quantization indices of the excitation.
+///
+/// `pulses` needs to hold at least `frame_length` rounded up to the shell codec frame length.
+pub(crate) fn encode_pulses(
+    enc: &mut RangeEncoder,
+    signal_type: usize,
+    quant_offset_type: usize,
+    pulses: &mut [i8],
+    frame_length: usize,
+) -> Result<(), OpusError> {
+    let mut abs_pulses = [0_usize; MAX_NB_SHELL_BLOCKS * SHELL_CODEC_FRAME_LENGTH];
+    let mut sum_pulses = [0_usize; MAX_NB_SHELL_BLOCKS];
+    let mut n_rshifts = [0_usize; MAX_NB_SHELL_BLOCKS];
+    let mut pulses_comb = [0_usize; 8];
+
+    // Calculate number of shell blocks.
+    let mut iter = frame_length >> LOG2_SHELL_CODEC_FRAME_LENGTH;
+    if iter * SHELL_CODEC_FRAME_LENGTH < frame_length {
+        // Only happens for 10 ms @ 12 kHz.
+        debug_assert_eq!(frame_length, 12 * 10);
+        iter += 1;
+        pulses[frame_length..frame_length + SHELL_CODEC_FRAME_LENGTH]
+            .iter_mut()
+            .for_each(|x| *x = 0);
+    }
+
+    // Take the absolute value of the pulses.
+    abs_pulses
+        .iter_mut()
+        .zip(pulses.iter())
+        .take(iter * SHELL_CODEC_FRAME_LENGTH)
+        .for_each(|(a, &p)| *a = i32::from(p).unsigned_abs() as usize);
+
+    // Calc sum pulses per shell code frame.
+    abs_pulses
+        .chunks_exact_mut(SHELL_CODEC_FRAME_LENGTH)
+        .take(iter)
+        .enumerate()
+        .for_each(|(i, block)| {
+            n_rshifts[i] = 0;
+            loop {
+                // 1+1 -> 2
+                let mut scale_down = !combine_and_check(
+                    &mut pulses_comb,
+                    block,
+                    usize::from(MAX_PULSES_TABLE[0]),
+                    8,
+                );
+                // 2+2 -> 4
+                let input = pulses_comb;
+                scale_down |= !combine_and_check(
+                    &mut pulses_comb,
+                    &input,
+                    usize::from(MAX_PULSES_TABLE[1]),
+                    4,
+                );
+                // 4+4 -> 8
+                let input = pulses_comb;
+                scale_down |= !combine_and_check(
+                    &mut pulses_comb,
+                    &input,
+                    usize::from(MAX_PULSES_TABLE[2]),
+                    2,
+                );
+                // 8+8 -> 16
+                scale_down |= !combine_and_check(
+                    &mut sum_pulses[i..],
+                    &pulses_comb,
+                    usize::from(MAX_PULSES_TABLE[3]),
+                    1,
+                );
+
+                if scale_down {
+                    // We need to downscale the quantization signal.
+                    n_rshifts[i] += 1;
+                    block.iter_mut().for_each(|x| *x >>= 1);
+                } else {
+                    // Jump out of loop and go to next shell coding frame.
+                    break;
+                }
+            }
+        });
+
+    // Find rate level that leads to fewest bits for coding of pulses per block info.
+    let mut min_sum_bits_q5 = u32::MAX;
+    let mut rate_level_index = 0;
+    (0..N_RATE_LEVELS - 1).for_each(|k| {
+        let n_bits = &PULSES_PER_BLOCK_BITS_Q5[k];
+        let mut sum_bits_q5 = u32::from(RATE_LEVELS_BITS_Q5[signal_type >> 1][k]);
+        (0..iter).for_each(|i| {
+            if n_rshifts[i] > 0 {
+                sum_bits_q5 += u32::from(n_bits[SILK_MAX_PULSES + 1]);
+            } else {
+                sum_bits_q5 += u32::from(n_bits[sum_pulses[i]]);
+            }
+        });
+        if sum_bits_q5 < min_sum_bits_q5 {
+            min_sum_bits_q5 = sum_bits_q5;
+            rate_level_index = k;
+        }
+    });
+    enc.encode_icdf(rate_level_index, &RATE_LEVELS_ICDF[signal_type >> 1], 8)?;
+
+    // Sum-weighted-pulses encoding.
+    let cdf = &PULSES_PER_BLOCK_ICDF[rate_level_index];
+    (0..iter).try_for_each(|i| {
+        if n_rshifts[i] == 0 {
+            enc.encode_icdf(sum_pulses[i], cdf, 8)
+        } else {
+            enc.encode_icdf(SILK_MAX_PULSES + 1, cdf, 8)?;
+            (0..n_rshifts[i] - 1).try_for_each(|_| {
+                enc.encode_icdf(
+                    SILK_MAX_PULSES + 1,
+                    &PULSES_PER_BLOCK_ICDF[N_RATE_LEVELS - 1],
+                    8,
+                )
+            })?;
+            enc.encode_icdf(sum_pulses[i], &PULSES_PER_BLOCK_ICDF[N_RATE_LEVELS - 1], 8)
+        }
+    })?;
+
+    // Shell encoding.
+    (0..iter).try_for_each(|i| {
+        if sum_pulses[i] > 0 {
+            shell_encoder(
+                enc,
+                &abs_pulses[i * SHELL_CODEC_FRAME_LENGTH..(i + 1) * SHELL_CODEC_FRAME_LENGTH],
+            )?;
+        }
+        Ok(())
+    })?;
+
+    // LSB encoding.
+    (0..iter).try_for_each(|i| {
+        if n_rshifts[i] > 0 {
+            let block = &pulses[i * SHELL_CODEC_FRAME_LENGTH..(i + 1) * SHELL_CODEC_FRAME_LENGTH];
+            let n_ls = n_rshifts[i] - 1;
+            block.iter().try_for_each(|&q| {
+                let abs_q = i32::from(q).abs();
+                (1..=n_ls).rev().try_for_each(|j| {
+                    let bit = ((abs_q >> j) & 1) as usize;
+                    enc.encode_icdf(bit, LSB_ICDF, 8)
+                })?;
+                let bit = (abs_q & 1) as usize;
+                enc.encode_icdf(bit, LSB_ICDF, 8)
+            })?;
+        }
+        Ok(())
+    })?;
+
+    // Encode signs.
+    encode_signs(
+        enc,
+        pulses,
+        frame_length,
+        signal_type,
+        quant_offset_type,
+        &sum_pulses,
+    )
+}
+
+/// Combines pairs of pulses. Returns false if a sum exceeds `max_pulses`.
+#[inline(always)]
+fn combine_and_check(
+    pulses_comb: &mut [usize],
+    pulses_in: &[usize],
+    max_pulses: usize,
+    len: usize,
+) -> bool {
+    (0..len).all(|k| {
+        let sum = pulses_in[2 * k] + pulses_in[2 * k + 1];
+        if sum > max_pulses {
+            return false;
+        }
+        pulses_comb[k] = sum;
+        true
+    })
+}
+
+/// Combines pairs of pulses into their parent subframe.
+#[inline(always)]
+fn combine_pulses(out: &mut [usize], input: &[usize]) {
+    out.iter_mut()
+        .zip(input.chunks_exact(2))
+        .for_each(|(o, i)| *o = i[0] + i[1]);
+}
+
+/// Shell encoder, operates on one shell code frame of 16 pulses.
+fn shell_encoder(enc: &mut RangeEncoder, pulses0: &[usize]) -> Result<(), OpusError> {
+    let mut pulses1 = [0_usize; 8];
+    let mut pulses2 = [0_usize; 4];
+    let mut pulses3 = [0_usize; 2];
+    let mut pulses4 = [0_usize; 1];
+
+    // Tree representation per pulse-subframe.
+    combine_pulses(&mut pulses1, pulses0);
+    combine_pulses(&mut pulses2, &pulses1);
+    combine_pulses(&mut pulses3, &pulses2);
+    combine_pulses(&mut pulses4, &pulses3);
+
+    encode_split(enc, pulses3[0], pulses4[0], SHELL_CODE_TABLE3)?;
+    encode_split(enc, pulses2[0], pulses3[0], SHELL_CODE_TABLE2)?;
+    encode_split(enc, pulses1[0], pulses2[0], SHELL_CODE_TABLE1)?;
+    encode_split(enc, pulses0[0], pulses1[0], SHELL_CODE_TABLE0)?;
+    encode_split(enc, pulses0[2], pulses1[1], SHELL_CODE_TABLE0)?;
+    encode_split(enc, pulses1[2], pulses2[1], SHELL_CODE_TABLE1)?;
+    encode_split(enc, pulses0[4], pulses1[2], SHELL_CODE_TABLE0)?;
+    encode_split(enc, pulses0[6], pulses1[3], SHELL_CODE_TABLE0)?;
+    encode_split(enc, pulses2[2], pulses3[1], SHELL_CODE_TABLE2)?;
+    encode_split(enc, pulses1[4], pulses2[2], SHELL_CODE_TABLE1)?;
+    encode_split(enc, pulses0[8], pulses1[4], SHELL_CODE_TABLE0)?;
+    encode_split(enc, pulses0[10], pulses1[5], SHELL_CODE_TABLE0)?;
+    encode_split(enc, pulses1[6], pulses2[3], SHELL_CODE_TABLE1)?;
+    encode_split(enc, pulses0[12], pulses1[6], SHELL_CODE_TABLE0)?;
+    encode_split(enc, pulses0[14], pulses1[7], SHELL_CODE_TABLE0)
+}
+
+/// Encodes the pulses `p_child1` of the first child subframe of the current subframe with
+/// pulses `p`.
+#[inline(always)]
+fn encode_split(
+    enc: &mut RangeEncoder,
+    p_child1: usize,
+    p: usize,
+    shell_table: &[u8],
+) -> Result<(), OpusError> {
+    if p > 0 {
+        let offset = usize::from(SHELL_CODE_TABLE_OFFSETS[p]);
+        enc.encode_icdf(p_child1, &shell_table[offset..], 8)?;
+    }
+    Ok(())
+}
+
+/// Encodes the signs of the excitation.
+fn encode_signs(
+    enc: &mut RangeEncoder,
+    pulses: &[i8],
+    length: usize,
+    signal_type: usize,
+    quant_offset_type: usize,
+    sum_pulses: &[usize; MAX_NB_SHELL_BLOCKS],
+) -> Result<(), OpusError> {
+    let mut icdf = [0_u8; 2];
+    let icdf_offset = 7 * (quant_offset_type + (signal_type << 1));
+    let icdf_table = &SIGN_ICDF[icdf_offset..];
+    let length = (length + SHELL_CODEC_FRAME_LENGTH / 2) >> LOG2_SHELL_CODEC_FRAME_LENGTH;
+
+    pulses
+        .chunks_exact(SHELL_CODEC_FRAME_LENGTH)
+        .zip(sum_pulses.iter())
+        .take(length)
+        .try_for_each(|(q, &p)| {
+            if p > 0 {
+                icdf[0] = icdf_table[usize::min(p & 0x1F, 6)];
+                q.iter().filter(|&&x| x != 0).try_for_each(|&x| {
+                    // Positive values map to 1, negative to 0.
+                    enc.encode_icdf(usize::from(x > 0), &icdf, 8)
+                })?;
+            }
+            Ok(())
+        })
+}
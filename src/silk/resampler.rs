@@ -0,0 +1,706 @@
+//! Implements the resampler of the Silk codec.
+//!
+//! Matrix of resampling methods used:
+//!
+//! ```text
+//!                                  Fs_out (kHz)
+//!                         8      12     16     24     48
+//!
+//!                8        C      UF     U      UF     UF
+//!               12        AF     C      UF     U      UF
+//!  Fs_in (kHz)  16        D      AF     C      UF     UF
+//!               24        AF     D      AF     C      U
+//!               48        AF     AF     AF     D      C
+//! ```
+//!
+//! * C   -> Copy (no resampling)
+//! * D   -> Allpass-based 2x downsampling
+//! * U   -> Allpass-based 2x upsampling
+//! * UF  -> Allpass-based 2x upsampling followed by FIR interpolation
+//! * AF  -> AR2 filter followed by FIR interpolation
+
+use crate::silk::fixed::{rshift_round, sat16, smlabb, smlawb, smulbb, smulwb, smulww};
+use crate::OpusError;
+
+const RESAMPLER_MAX_BATCH_SIZE_MS: usize = 10;
+const RESAMPLER_MAX_FIR_ORDER: usize = 36;
+const RESAMPLER_MAX_IIR_ORDER: usize = 6;
+const RESAMPLER_DOWN_ORDER_FIR0: usize = 18;
+const RESAMPLER_DOWN_ORDER_FIR1: usize = 24;
+const RESAMPLER_DOWN_ORDER_FIR2: usize = 36;
+const RESAMPLER_ORDER_FIR_12: usize = 8;
+const RESAMPLER_MAX_BATCH_SIZE_IN: usize = RESAMPLER_MAX_BATCH_SIZE_MS * 48;
+const RESAMPLER_DOWN2_ORDER_FIR: usize = 4;
+
+/// Delay compensation values to equalize total delay for different modes of the encoder.
+const DELAY_MATRIX_ENC: &[[u8; 3]; 5] = &[
+    // in \ out   8  12  16
+    /*  8 */ [6, 0, 3],
+    /* 12 */ [0, 7, 3],
+    /* 16 */ [0, 1, 10],
+    /* 24 */ [0, 2, 6],
+    /* 48 */ [18, 10, 12],
+];
+
+/// Delay compensation values to equalize total delay for different modes of the decoder.
+const DELAY_MATRIX_DEC: &[[u8; 5]; 3] = &[
+    // in \ out   8  12  16  24  48
+    /*  8 */ [4, 0, 2, 0, 0],
+    /* 12 */ [0, 9, 4, 7, 4],
+    /* 16 */ [0, 3, 12, 7, 7],
+];
+
+/// Coefficients for 2x upsampler, high quality.
+const UP2_HQ_0: &[i16; 3] = &[1746, 14986, (39083 - 65536) as i16];
+const UP2_HQ_1: &[i16; 3] = &[6854, 25769, (55542 - 65536) as i16];
+
+/// IIR and FIR coefficients for the fractional downsamplers.
+const RESAMPLER_3_4_COEFS: &[i16; 2 + 3 * RESAMPLER_DOWN_ORDER_FIR0 / 2] = &[
+    -20694, -13867, -49, 64, 17, -157, 353, -496, 163, 11047, 22205, -39, 6, 91, -170, 186, 23,
+    -896, 6336, 19928, -19, -36, 102, -89, -24, 328, -951, 2568, 15909,
+];
+
+const RESAMPLER_2_3_COEFS: &[i16; 2 + 2 * RESAMPLER_DOWN_ORDER_FIR0 / 2] = &[
+    -14457, -14019, 64, 128, -122, 36, 310, -768, 584, 9267, 17733, 12, 128, 18, -142, 288, -117,
+    -865, 4123, 14459,
+];
+
+const RESAMPLER_1_2_COEFS: &[i16; 2 + RESAMPLER_DOWN_ORDER_FIR1 / 2] = &[
+    616, -14323, -10, 39, 58, -46, -84, 120, 184, -315, -541, 1284, 5380, 9024,
+];
+
+const RESAMPLER_1_3_COEFS: &[i16; 2 + RESAMPLER_DOWN_ORDER_FIR2 / 2] = &[
+    16102, -15162, -13, 0, 20, 26, 5, -31, -43, -4, 65, 90, 7, -157, -248, -44, 593, 1583, 2612,
+    3271,
+];
+
+const RESAMPLER_1_4_COEFS: &[i16; 2 + RESAMPLER_DOWN_ORDER_FIR2 / 2] = &[
+    22500, -15099, 3, -14, -20, -15, 2, 25, 37, 25, -16, -71, -107, -79, 50, 292, 623, 982, 1288,
+    1464,
+];
+
+const RESAMPLER_1_6_COEFS: &[i16; 2 + RESAMPLER_DOWN_ORDER_FIR2 / 2] = &[
+    27540, -15257, 17, 12, 8, 1, -10, -22, -30, -32, -22, 3, 44, 100, 168, 243, 317, 381, 429, 455,
+];
+
+/// Interpolation fractions of 1/24, 3/24, 5/24, ... , 23/24.
+const RESAMPLER_FRAC_FIR_12: &[[i16; RESAMPLER_ORDER_FIR_12 / 2]; 12] = &[
+    [189, -600, 617, 30567],
+    [117, -159, -1070, 29704],
+    [52, 221, -2392, 28276],
+    [-4, 529, -3350, 26341],
+    [-48, 758, -3956, 23973],
+    [-80, 905, -4235, 21254],
+    [-99, 972, -4222, 18278],
+    [-107, 967, -3957, 15143],
+    [-103, 896, -3487, 11950],
+    [-91, 773, -2865, 8798],
+    [-71, 611, -2143, 5784],
+    [-46, 425, -1375, 2996],
+];
+
+/// Coefficients of the allpass filters of the 2x downsampler.
+const RESAMPLER_DOWN2_0: i16 = 9872;
+const RESAMPLER_DOWN2_1: i16 = (39809 - 65536) as i16;
+
+/// Low quality coefficients of the 2/3 downsampler.
+const RESAMPLER_2_3_COEFS_LQ: &[i16; 6] = &[-2797, -6507, 4697, 10739, 1567, 8276];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ResamplerFunction {
+    /// Input and output sampling rates are equal.
+    Copy,
+    /// Allpass-based 2x upsampling.
+    Up2Hq,
+    /// Allpass-based 2x upsampling followed by FIR interpolation.
+    IirFir,
+    /// AR2 filter followed by FIR interpolation.
+    DownFir,
+}
+
+/// The Silk resampler.
+#[derive(Clone, Debug)]
+pub(crate) struct Resampler {
+    s_iir: [i32; RESAMPLER_MAX_IIR_ORDER],
+    s_fir_i32: [i32; RESAMPLER_MAX_FIR_ORDER],
+    s_fir_i16: [i16; RESAMPLER_MAX_FIR_ORDER],
+    delay_buf: [i16; 48],
+    function: ResamplerFunction,
+    batch_size: usize,
+    inv_ratio_q16: i32,
+    fir_order: usize,
+    fir_fracs: usize,
+    fs_in_khz: usize,
+    fs_out_khz: usize,
+    input_delay: usize,
+    coefs: &'static [i16],
+}
+
+impl Default for Resampler {
+    fn default() -> Self {
+        Self {
+            s_iir: [0; RESAMPLER_MAX_IIR_ORDER],
+            s_fir_i32: [0; RESAMPLER_MAX_FIR_ORDER],
+            s_fir_i16: [0; RESAMPLER_MAX_FIR_ORDER],
+            delay_buf: [0; 48],
+            function: ResamplerFunction::Copy,
+            batch_size: 0,
+            inv_ratio_q16: 0,
+            fir_order: 0,
+            fir_fracs: 0,
+            fs_in_khz: 0,
+            fs_out_khz: 0,
+            input_delay: 0,
+            coefs: &[],
+        }
+    }
+}
+
+/// Maps [8000, 12000, 16000, 24000, 48000] to [0, 1, 2, 3, 4].
+fn rate_id(r: usize) -> usize {
+    (((r >> 12) - usize::from(r > 16000)) >> usize::from(r > 24000)) - 1
+}
+
+impl Resampler {
+    /// Initializes / resets the resampler state for a given pair of input / output sampling rates.
+    pub(crate) fn init(
+        &mut self,
+        fs_hz_in: usize,
+        fs_hz_out: usize,
+        for_encoder: bool,
+    ) -> Result<(), OpusError> {
+        // Clear state.
+        *self = Self::default();
+
+        // Input checking.
+        if for_encoder {
+            if !matches!(fs_hz_in, 8000 | 12000 | 16000 | 24000 | 48000)
+                || !matches!(fs_hz_out, 8000 | 12000 | 16000)
+            {
+                return Err(OpusError::BadArguments(
+                    "unsupported sampling rates for the resampler",
+                ));
+            }
+            self.input_delay = usize::from(DELAY_MATRIX_ENC[rate_id(fs_hz_in)][rate_id(fs_hz_out)]);
+        } else {
+            if !matches!(fs_hz_in, 8000 | 12000 | 16000)
+                || !matches!(fs_hz_out, 8000 | 12000 | 16000 | 24000 | 48000)
+            {
+                return Err(OpusError::BadArguments(
+                    "unsupported sampling rates for the resampler",
+                ));
+            }
+            self.input_delay = usize::from(DELAY_MATRIX_DEC[rate_id(fs_hz_in)][rate_id(fs_hz_out)]);
+        }
+
+        self.fs_in_khz = fs_hz_in / 1000;
+        self.fs_out_khz = fs_hz_out / 1000;
+
+        // Number of samples processed per batch.
+        self.batch_size = self.fs_in_khz * RESAMPLER_MAX_BATCH_SIZE_MS;
+
+        // Find resampler with the right sampling ratio.
+        let mut up2x = 0;
+        if fs_hz_out > fs_hz_in {
+            // Upsample.
+            if fs_hz_out == fs_hz_in * 2 {
+                // Special case: directly use 2x upsampler.
+                self.function = ResamplerFunction::Up2Hq;
+            } else {
+                // Default resampler.
+                self.function = ResamplerFunction::IirFir;
+                up2x = 1;
+            }
+        } else if fs_hz_out < fs_hz_in {
+            // Downsample.
+            self.function = ResamplerFunction::DownFir;
+            if fs_hz_out * 4 == fs_hz_in * 3 {
+                self.fir_fracs = 3;
+                self.fir_order = RESAMPLER_DOWN_ORDER_FIR0;
+                self.coefs = RESAMPLER_3_4_COEFS;
+            } else if fs_hz_out * 3 == fs_hz_in * 2 {
+                self.fir_fracs = 2;
+                self.fir_order = RESAMPLER_DOWN_ORDER_FIR0;
+                self.coefs = RESAMPLER_2_3_COEFS;
+            } else if fs_hz_out * 2 == fs_hz_in {
+                self.fir_fracs = 1;
+                self.fir_order = RESAMPLER_DOWN_ORDER_FIR1;
+                self.coefs = RESAMPLER_1_2_COEFS;
+            } else if fs_hz_out * 3 == fs_hz_in {
+                self.fir_fracs = 1;
+                self.fir_order = RESAMPLER_DOWN_ORDER_FIR2;
+                self.coefs = RESAMPLER_1_3_COEFS;
+            } else if fs_hz_out * 4 == fs_hz_in {
+                self.fir_fracs = 1;
+                self.fir_order = RESAMPLER_DOWN_ORDER_FIR2;
+                self.coefs = RESAMPLER_1_4_COEFS;
+            } else if fs_hz_out * 6 == fs_hz_in {
+                self.fir_fracs = 1;
+                self.fir_order = RESAMPLER_DOWN_ORDER_FIR2;
+                self.coefs = RESAMPLER_1_6_COEFS;
+            } else {
+                return Err(OpusError::BadArguments(
+                    "unsupported sampling ratio for the resampler",
+                ));
+            }
+        } else {
+            // Input and output sampling rates are equal: copy.
+            self.function = ResamplerFunction::Copy;
+        }
+
+        // Ratio of input/output samples.
+        let fs_in = fs_hz_in as i32;
+        let fs_out = fs_hz_out as i32;
+        self.inv_ratio_q16 = ((fs_in << (14 + up2x)) / fs_out) << 2;
+
+        // Make sure the ratio is rounded up.
+        while smulww(self.inv_ratio_q16, fs_out) < (fs_in << up2x) {
+            self.inv_ratio_q16 += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Resamples the input signal. Needs at least 1 ms of input data.
+    ///
+    /// Input and output sampling rates are at most 48000 Hz.
+    pub(crate) fn resample(&mut self, out: &mut [i16], input: &[i16]) {
+        let in_len = input.len();
+
+        // Need at least 1 ms of input data.
+        debug_assert!(in_len >= self.fs_in_khz);
+        // Delay can't exceed the 1 ms of buffering.
+        debug_assert!(self.input_delay <= self.fs_in_khz);
+
+        let n_samples = self.fs_in_khz - self.input_delay;
+
+        // Copy to delay buffer.
+        self.delay_buf[self.input_delay..self.fs_in_khz].copy_from_slice(&input[..n_samples]);
+
+        let fs_in_khz = self.fs_in_khz;
+        let fs_out_khz = self.fs_out_khz;
+        let delay_buf = self.delay_buf;
+        match self.function {
+            ResamplerFunction::Up2Hq => {
+                up2_hq(&mut self.s_iir, out, &delay_buf[..fs_in_khz]);
+                up2_hq(
+                    &mut self.s_iir,
+                    &mut out[fs_out_khz..],
+                    &input[n_samples..in_len - self.input_delay],
+                );
+            }
+            ResamplerFunction::IirFir => {
+                self.iir_fir(out, &delay_buf[..fs_in_khz]);
+                self.iir_fir(
+                    &mut out[fs_out_khz..],
+                    &input[n_samples..in_len - self.input_delay],
+                );
+            }
+            ResamplerFunction::DownFir => {
+                self.down_fir(out, &delay_buf[..fs_in_khz]);
+                self.down_fir(
+                    &mut out[fs_out_khz..],
+                    &input[n_samples..in_len - self.input_delay],
+                );
+            }
+            ResamplerFunction::Copy => {
+                out[..fs_in_khz].copy_from_slice(&delay_buf[..fs_in_khz]);
+                out[fs_out_khz..fs_out_khz + in_len - fs_in_khz]
+                    .copy_from_slice(&input[n_samples..in_len - self.input_delay]);
+            }
+        }
+
+        // Copy to delay buffer.
+        self.delay_buf[..self.input_delay].copy_from_slice(&input[in_len - self.input_delay..]);
+    }
+
+    /// Upsamples using a combination of allpass-based 2x upsampling and FIR interpolation.
+    fn iir_fir(&mut self, out: &mut [i16], input: &[i16]) {
+        let mut buf = vec![0_i16; 2 * self.batch_size + RESAMPLER_ORDER_FIR_12];
+
+        // Copy buffered samples to start of buffer.
+        buf[..RESAMPLER_ORDER_FIR_12].copy_from_slice(&self.s_fir_i16[..RESAMPLER_ORDER_FIR_12]);
+
+        // Iterate over blocks of frame_size_in input samples.
+        let index_increment_q16 = self.inv_ratio_q16;
+        let mut in_offset = 0;
+        let mut out_offset = 0;
+        let mut in_len = input.len();
+        let mut n_samples_in;
+        loop {
+            n_samples_in = usize::min(in_len, self.batch_size);
+
+            // Upsample 2x.
+            up2_hq(
+                &mut self.s_iir,
+                &mut buf[RESAMPLER_ORDER_FIR_12..],
+                &input[in_offset..in_offset + n_samples_in],
+            );
+
+            // + 1 because 2x upsampling.
+            let max_index_q16 = (n_samples_in as i32) << (16 + 1);
+            out_offset += iir_fir_interpol(
+                &mut out[out_offset..],
+                &buf,
+                max_index_q16,
+                index_increment_q16,
+            );
+            in_offset += n_samples_in;
+            in_len -= n_samples_in;
+
+            if in_len > 0 {
+                // More iterations to do; copy last part of filtered signal to beginning of buffer.
+                buf.copy_within(
+                    n_samples_in << 1..(n_samples_in << 1) + RESAMPLER_ORDER_FIR_12,
+                    0,
+                );
+            } else {
+                break;
+            }
+        }
+
+        // Copy last part of filtered signal to the state for the next call.
+        self.s_fir_i16[..RESAMPLER_ORDER_FIR_12]
+            .copy_from_slice(&buf[n_samples_in << 1..(n_samples_in << 1) + RESAMPLER_ORDER_FIR_12]);
+    }
+
+    /// Resamples with a 2nd order AR filter followed by FIR interpolation.
+    fn down_fir(&mut self, out: &mut [i16], input: &[i16]) {
+        let fir_order = self.fir_order;
+        let mut buf = vec![0_i32; self.batch_size + fir_order];
+
+        // Copy buffered samples to start of buffer.
+        buf[..fir_order].copy_from_slice(&self.s_fir_i32[..fir_order]);
+
+        let fir_coefs = &self.coefs[2..];
+
+        // Iterate over blocks of frame_size_in input samples.
+        let index_increment_q16 = self.inv_ratio_q16;
+        let mut in_offset = 0;
+        let mut out_offset = 0;
+        let mut in_len = input.len();
+        let mut n_samples_in;
+        loop {
+            n_samples_in = usize::min(in_len, self.batch_size);
+
+            // Second-order AR filter (output in Q8).
+            ar2(
+                &mut self.s_iir,
+                &mut buf[fir_order..],
+                &input[in_offset..in_offset + n_samples_in],
+                self.coefs,
+            );
+
+            let max_index_q16 = (n_samples_in as i32) << 16;
+
+            // Interpolate filtered signal.
+            out_offset += down_fir_interpol(
+                &mut out[out_offset..],
+                &buf,
+                fir_coefs,
+                fir_order,
+                self.fir_fracs,
+                max_index_q16,
+                index_increment_q16,
+            );
+            in_offset += n_samples_in;
+            in_len -= n_samples_in;
+
+            if in_len > 1 {
+                // More iterations to do; copy last part of filtered signal to beginning of buffer.
+                buf.copy_within(n_samples_in..n_samples_in + fir_order, 0);
+            } else {
+                break;
+            }
+        }
+
+        // Copy last part of filtered signal to the state for the next call.
+        self.s_fir_i32[..fir_order].copy_from_slice(&buf[n_samples_in..n_samples_in + fir_order]);
+    }
+}
+
+/// Upsamples by a factor 2, high quality.
+///
+/// Uses 2nd order allpass filters for the 2x upsampling, followed by a
+/// notch filter just above Nyquist.
+fn up2_hq(s: &mut [i32; RESAMPLER_MAX_IIR_ORDER], out: &mut [i16], input: &[i16]) {
+    // Internal variables and state are in Q10 format.
+    input.iter().enumerate().for_each(|(k, x)| {
+        // Convert to Q10.
+        let in32 = i32::from(*x) << 10;
+
+        // First all-pass section for even output sample.
+        let y = in32.wrapping_sub(s[0]);
+        let x = smulwb(y, i32::from(UP2_HQ_0[0]));
+        let out32_1 = s[0].wrapping_add(x);
+        s[0] = in32.wrapping_add(x);
+
+        // Second all-pass section for even output sample.
+        let y = out32_1.wrapping_sub(s[1]);
+        let x = smulwb(y, i32::from(UP2_HQ_0[1]));
+        let out32_2 = s[1].wrapping_add(x);
+        s[1] = out32_1.wrapping_add(x);
+
+        // Third all-pass section for even output sample.
+        let y = out32_2.wrapping_sub(s[2]);
+        let x = smlawb(y, y, i32::from(UP2_HQ_0[2]));
+        let out32_1 = s[2].wrapping_add(x);
+        s[2] = out32_2.wrapping_add(x);
+
+        // Apply gain in Q15, convert back to i16 and store to output.
+        out[2 * k] = sat16(rshift_round(out32_1, 10));
+
+        // First all-pass section for odd output sample.
+        let y = in32.wrapping_sub(s[3]);
+        let x = smulwb(y, i32::from(UP2_HQ_1[0]));
+        let out32_1 = s[3].wrapping_add(x);
+        s[3] = in32.wrapping_add(x);
+
+        // Second all-pass section for odd output sample.
+        let y = out32_1.wrapping_sub(s[4]);
+        let x = smulwb(y, i32::from(UP2_HQ_1[1]));
+        let out32_2 = s[4].wrapping_add(x);
+        s[4] = out32_1.wrapping_add(x);
+
+        // Third all-pass section for odd output sample.
+        let y = out32_2.wrapping_sub(s[5]);
+        let x = smlawb(y, y, i32::from(UP2_HQ_1[2]));
+        let out32_1 = s[5].wrapping_add(x);
+        s[5] = out32_2.wrapping_add(x);
+
+        // Apply gain in Q15, convert back to i16 and store to output.
+        out[2 * k + 1] = sat16(rshift_round(out32_1, 10));
+    });
+}
+
+/// Interpolates the upsampled signal and stores it in the output array.
+///
+/// Returns the number of written samples.
+fn iir_fir_interpol(
+    out: &mut [i16],
+    buf: &[i16],
+    max_index_q16: i32,
+    index_increment_q16: i32,
+) -> usize {
+    let mut written = 0;
+    let mut index_q16 = 0;
+    while index_q16 < max_index_q16 {
+        let table_index = smulwb(index_q16 & 0xFFFF, 12) as usize;
+        let b = &buf[(index_q16 >> 16) as usize..];
+
+        let fir = &RESAMPLER_FRAC_FIR_12[table_index];
+        let fir_rev = &RESAMPLER_FRAC_FIR_12[11 - table_index];
+
+        let mut res_q15 = smulbb(i32::from(b[0]), i32::from(fir[0]));
+        res_q15 = smlabb(res_q15, i32::from(b[1]), i32::from(fir[1]));
+        res_q15 = smlabb(res_q15, i32::from(b[2]), i32::from(fir[2]));
+        res_q15 = smlabb(res_q15, i32::from(b[3]), i32::from(fir[3]));
+        res_q15 = smlabb(res_q15, i32::from(b[4]), i32::from(fir_rev[3]));
+        res_q15 = smlabb(res_q15, i32::from(b[5]), i32::from(fir_rev[2]));
+        res_q15 = smlabb(res_q15, i32::from(b[6]), i32::from(fir_rev[1]));
+        res_q15 = smlabb(res_q15, i32::from(b[7]), i32::from(fir_rev[0]));
+        out[written] = sat16(rshift_round(res_q15, 15));
+
+        written += 1;
+        index_q16 += index_increment_q16;
+    }
+    written
+}
+
+/// Interpolates the filtered signal and stores it in the output array.
+///
+/// Returns the number of written samples.
+fn down_fir_interpol(
+    out: &mut [i16],
+    buf: &[i32],
+    fir_coefs: &[i16],
+    fir_order: usize,
+    fir_fracs: usize,
+    max_index_q16: i32,
+    index_increment_q16: i32,
+) -> usize {
+    let mut written = 0;
+    let mut index_q16 = 0;
+    while index_q16 < max_index_q16 {
+        // Integer part gives pointer to buffered input.
+        let b = &buf[(index_q16 >> 16) as usize..];
+
+        let res_q6 = match fir_order {
+            RESAMPLER_DOWN_ORDER_FIR0 => {
+                // Fractional part gives interpolation coefficients.
+                let interpol_ind = smulwb(index_q16 & 0xFFFF, fir_fracs as i32) as usize;
+
+                // Inner product.
+                let half = RESAMPLER_DOWN_ORDER_FIR0 / 2;
+                let interpol = &fir_coefs[half * interpol_ind..];
+                let mut res_q6 = smulwb(b[0], i32::from(interpol[0]));
+                (1..half).for_each(|i| {
+                    res_q6 = smlawb(res_q6, b[i], i32::from(interpol[i]));
+                });
+                let interpol = &fir_coefs[half * (fir_fracs - 1 - interpol_ind)..];
+                (0..half).for_each(|i| {
+                    res_q6 = smlawb(res_q6, b[17 - i], i32::from(interpol[i]));
+                });
+                res_q6
+            }
+            RESAMPLER_DOWN_ORDER_FIR1 | RESAMPLER_DOWN_ORDER_FIR2 => {
+                // Inner product.
+                let half = fir_order / 2;
+                let mut res_q6 =
+                    smulwb(b[0].wrapping_add(b[fir_order - 1]), i32::from(fir_coefs[0]));
+                (1..half).for_each(|i| {
+                    res_q6 = smlawb(
+                        res_q6,
+                        b[i].wrapping_add(b[fir_order - 1 - i]),
+                        i32::from(fir_coefs[i]),
+                    );
+                });
+                res_q6
+            }
+            _ => unreachable!(),
+        };
+
+        // Scale down, saturate and store in output array.
+        out[written] = sat16(rshift_round(res_q6, 6));
+
+        written += 1;
+        index_q16 += index_increment_q16;
+    }
+    written
+}
+
+/// Downsamples by a factor 2.
+pub(crate) fn resampler_down2(s: &mut [i32; 2], out: &mut [i16], input: &[i16]) {
+    // Internal variables and state are in Q10 format.
+    (0..input.len() >> 1).for_each(|k| {
+        // Convert to Q10.
+        let in32 = i32::from(input[2 * k]) << 10;
+
+        // All-pass section for even input sample.
+        let y = in32.wrapping_sub(s[0]);
+        let x = smlawb(y, y, i32::from(RESAMPLER_DOWN2_1));
+        let out32 = s[0].wrapping_add(x);
+        s[0] = in32.wrapping_add(x);
+
+        // Convert to Q10.
+        let in32 = i32::from(input[2 * k + 1]) << 10;
+
+        // All-pass section for odd input sample, and add to output of previous section.
+        let y = in32.wrapping_sub(s[1]);
+        let x = smulwb(y, i32::from(RESAMPLER_DOWN2_0));
+        let out32 = out32.wrapping_add(s[1]).wrapping_add(x);
+        s[1] = in32.wrapping_add(x);
+
+        // Add, convert back to int16 and store to output.
+        out[k] = sat16(rshift_round(out32, 11));
+    });
+}
+
+/// Downsamples by a factor 2/3, low quality.
+pub(crate) fn resampler_down2_3(s: &mut [i32; 6], out: &mut [i16], input: &[i16]) {
+    let mut buf = [0_i32; RESAMPLER_MAX_BATCH_SIZE_IN + RESAMPLER_DOWN2_ORDER_FIR];
+
+    // Copy buffered samples to start of buffer.
+    buf[..RESAMPLER_DOWN2_ORDER_FIR].copy_from_slice(&s[..RESAMPLER_DOWN2_ORDER_FIR]);
+
+    let mut in_offset = 0;
+    let mut out_offset = 0;
+    let mut n_samples_in;
+    loop {
+        n_samples_in = usize::min(input.len() - in_offset, RESAMPLER_MAX_BATCH_SIZE_IN);
+
+        // Second-order AR filter (output in Q8).
+        ar2(
+            &mut s[RESAMPLER_DOWN2_ORDER_FIR..],
+            &mut buf[RESAMPLER_DOWN2_ORDER_FIR..],
+            &input[in_offset..in_offset + n_samples_in],
+            RESAMPLER_2_3_COEFS_LQ,
+        );
+
+        // Interpolate filtered signal.
+        let mut buf_offset = 0;
+        let mut counter = n_samples_in as i32;
+        while counter > 2 {
+            let b = &buf[buf_offset..];
+
+            // Inner product.
+            let res_q6 = smulwb(b[0], i32::from(RESAMPLER_2_3_COEFS_LQ[2]));
+            let res_q6 = smlawb(res_q6, b[1], i32::from(RESAMPLER_2_3_COEFS_LQ[3]));
+            let res_q6 = smlawb(res_q6, b[2], i32::from(RESAMPLER_2_3_COEFS_LQ[5]));
+            let res_q6 = smlawb(res_q6, b[3], i32::from(RESAMPLER_2_3_COEFS_LQ[4]));
+
+            // Scale down, saturate and store in output array.
+            out[out_offset] = sat16(rshift_round(res_q6, 6));
+
+            let res_q6 = smulwb(b[1], i32::from(RESAMPLER_2_3_COEFS_LQ[4]));
+            let res_q6 = smlawb(res_q6, b[2], i32::from(RESAMPLER_2_3_COEFS_LQ[5]));
+            let res_q6 = smlawb(res_q6, b[3], i32::from(RESAMPLER_2_3_COEFS_LQ[3]));
+            let res_q6 = smlawb(res_q6, b[4], i32::from(RESAMPLER_2_3_COEFS_LQ[2]));
+
+            // Scale down, saturate and store in output array.
+            out[out_offset + 1] = sat16(rshift_round(res_q6, 6));
+
+            out_offset += 2;
+            buf_offset += 3;
+            counter -= 3;
+        }
+
+        in_offset += n_samples_in;
+
+        if in_offset < input.len() {
+            // More iterations to do; copy last part of filtered signal to beginning of buffer.
+            buf.copy_within(n_samples_in..n_samples_in + RESAMPLER_DOWN2_ORDER_FIR, 0);
+        } else {
+            break;
+        }
+    }
+
+    // Copy last part of filtered signal to the state for the next call.
+    s[..RESAMPLER_DOWN2_ORDER_FIR]
+        .copy_from_slice(&buf[n_samples_in..n_samples_in + RESAMPLER_DOWN2_ORDER_FIR]);
+}
+
+/// Second order AR filter with single delay elements.
+fn ar2(s: &mut [i32], out_q8: &mut [i32], input: &[i16], a_q14: &[i16]) {
+    input.iter().zip(out_q8.iter_mut()).for_each(|(x, out)| {
+        let out32 = s[0].wrapping_add(i32::from(*x) << 8);
+        *out = out32;
+        let out32 = out32 << 2;
+        s[0] = smlawb(s[1], out32, i32::from(a_q14[0]));
+        s[1] = smulwb(out32, i32::from(a_q14[1]));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+
+    use super::*;
+
+    #[test]
+    fn test_rate_id() {
+        assert_eq!(rate_id(8000), 0);
+        assert_eq!(rate_id(12000), 1);
+        assert_eq!(rate_id(16000), 2);
+        assert_eq!(rate_id(24000), 3);
+        assert_eq!(rate_id(48000), 4);
+    }
+
+    #[test]
+    fn test_resampler_output_length() {
+        [8000, 12000, 16000].iter().for_each(|&fs_in| {
+            [8000, 12000, 16000, 24000, 48000]
+                .iter()
+                .for_each(|&fs_out| {
+                    let mut resampler = Resampler::default();
+                    resampler.init(fs_in, fs_out, false).unwrap();
+
+                    let input: Vec<i16> = (0..fs_in / 50)
+                        .map(|i| ((i * 1000) % 16000) as i16 - 8000)
+                        .collect();
+                    let mut out = vec![0_i16; fs_out / 50];
+                    resampler.resample(&mut out, &input);
+                });
+        });
+    }
+}
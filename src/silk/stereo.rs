@@ -0,0 +1,503 @@
+//! Implements the stereo prediction.
+
+use crate::range_coder::{RangeDecoder, RangeEncoder};
+use crate::silk::fixed::{
+    div32_varq, limit, rshift_round, sat16, smlabb, smlawb, smulbb, smulwb, sqrt_approx,
+    sum_sqr_shift,
+};
+use crate::silk::tables::{
+    STEREO_ONLY_CODE_MID_ICDF, STEREO_PRED_JOINT_ICDF, STEREO_PRED_QUANT_Q13, UNIFORM3_ICDF,
+    UNIFORM5_ICDF,
+};
+use crate::silk::{LA_SHAPE_MS, MAX_FRAMES_PER_PACKET, MAX_FRAME_LENGTH, STEREO_INTERP_LEN_MS};
+use crate::OpusError;
+
+const STEREO_QUANT_SUB_STEPS: i32 = 5;
+const STEREO_QUANT_TAB_SIZE: usize = 16;
+const STEREO_RATIO_SMOOTH_COEF: f64 = 0.01;
+
+/// State of the stereo decoder.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct StereoDecoderState {
+    pub(crate) pred_prev_q13: [i16; 2],
+    pub(crate) s_mid: [i16; 2],
+    pub(crate) s_side: [i16; 2],
+}
+
+/// State of the stereo encoder.
+#[derive(Clone, Debug)]
+pub(crate) struct StereoEncoderState {
+    pub(crate) pred_prev_q13: [i16; 2],
+    pub(crate) s_mid: [i16; 2],
+    pub(crate) s_side: [i16; 2],
+    pub(crate) mid_side_amp_q0: [i32; 4],
+    pub(crate) smth_width_q14: i16,
+    pub(crate) width_prev_q14: i16,
+    pub(crate) silent_side_len: i16,
+    pub(crate) pred_ix: [[[i8; 3]; 2]; MAX_FRAMES_PER_PACKET],
+    pub(crate) mid_only_flags: [bool; MAX_FRAMES_PER_PACKET],
+}
+
+impl Default for StereoEncoderState {
+    fn default() -> Self {
+        Self {
+            pred_prev_q13: [0; 2],
+            s_mid: [0; 2],
+            s_side: [0; 2],
+            mid_side_amp_q0: [0; 4],
+            smth_width_q14: 1 << 14,
+            width_prev_q14: 0,
+            silent_side_len: 0,
+            pred_ix: [[[0; 3]; 2]; MAX_FRAMES_PER_PACKET],
+            mid_only_flags: [false; MAX_FRAMES_PER_PACKET],
+        }
+    }
+}
+
+/// Decodes the mid / side prediction weights.
+pub(crate) fn stereo_decode_pred(dec: &mut RangeDecoder, pred_q13: &mut [i32; 2]) {
+    let mut ix = [[0_usize; 3]; 2];
+
+    // Entropy decoding.
+    let n = dec.decode_icdf(STEREO_PRED_JOINT_ICDF, 8) as usize;
+    ix[0][2] = n / 5;
+    ix[1][2] = n - 5 * ix[0][2];
+    ix.iter_mut().for_each(|ix| {
+        ix[0] = dec.decode_icdf(UNIFORM3_ICDF, 8) as usize;
+        ix[1] = dec.decode_icdf(UNIFORM5_ICDF, 8) as usize;
+    });
+
+    // Dequantize.
+    ix.iter_mut()
+        .zip(pred_q13.iter_mut())
+        .for_each(|(ix, pred)| {
+            ix[0] += 3 * ix[2];
+            let low_q13 = i32::from(STEREO_PRED_QUANT_Q13[ix[0]]);
+            let step_q13 = smulwb(
+                i32::from(STEREO_PRED_QUANT_Q13[ix[0] + 1]) - low_q13,
+                ((0.5 / STEREO_QUANT_SUB_STEPS as f64) * 65536.0 + 0.5) as i32,
+            );
+            *pred = smlabb(low_q13, step_q13, 2 * ix[1] as i32 + 1);
+        });
+
+    // Subtract second from first predictor (helps when actually applying these).
+    pred_q13[0] -= pred_q13[1];
+}
+
+/// Decodes the mid-only flag.
+pub(crate) fn stereo_decode_mid_only(dec: &mut RangeDecoder) -> bool {
+    dec.decode_icdf(STEREO_ONLY_CODE_MID_ICDF, 8) != 0
+}
+
+/// Converts the adaptive mid / side representation to a left / right stereo signal.
+///
+/// Both signals start with two samples of history and contain `frame_length` samples after that.
+pub(crate) fn stereo_ms_to_lr(
+    state: &mut StereoDecoderState,
+    x1: &mut [i16],
+    x2: &mut [i16],
+    pred_q13: &[i32; 2],
+    fs_khz: usize,
+    frame_length: usize,
+) {
+    // Buffering.
+    x1[..2].copy_from_slice(&state.s_mid);
+    x2[..2].copy_from_slice(&state.s_side);
+    state
+        .s_mid
+        .copy_from_slice(&x1[frame_length..frame_length + 2]);
+    state
+        .s_side
+        .copy_from_slice(&x2[frame_length..frame_length + 2]);
+
+    // Interpolate predictors and add prediction to side channel.
+    let mut pred0_q13 = i32::from(state.pred_prev_q13[0]);
+    let mut pred1_q13 = i32::from(state.pred_prev_q13[1]);
+    let interp_len = STEREO_INTERP_LEN_MS * fs_khz;
+    let denom_q16 = (1 << 16) / interp_len as i32;
+    let delta0_q13 = rshift_round(
+        smulbb(pred_q13[0] - i32::from(state.pred_prev_q13[0]), denom_q16),
+        16,
+    );
+    let delta1_q13 = rshift_round(
+        smulbb(pred_q13[1] - i32::from(state.pred_prev_q13[1]), denom_q16),
+        16,
+    );
+
+    (0..frame_length).for_each(|n| {
+        if n < interp_len {
+            pred0_q13 += delta0_q13;
+            pred1_q13 += delta1_q13;
+        } else if n == interp_len {
+            pred0_q13 = pred_q13[0];
+            pred1_q13 = pred_q13[1];
+        }
+
+        // Q11
+        let sum = (i32::from(x1[n]) + i32::from(x1[n + 2]) + (i32::from(x1[n + 1]) << 1)) << 9;
+        // Q8
+        let sum = smlawb(i32::from(x2[n + 1]) << 8, sum, pred0_q13);
+        // Q8
+        let sum = smlawb(sum, i32::from(x1[n + 1]) << 11, pred1_q13);
+        x2[n + 1] = sat16(rshift_round(sum, 8));
+    });
+    state.pred_prev_q13[0] = pred_q13[0] as i16;
+    state.pred_prev_q13[1] = pred_q13[1] as i16;
+
+    // Convert to left/right signals.
+    (1..=frame_length).for_each(|n| {
+        let sum = i32::from(x1[n]) + i32::from(x2[n]);
+        let diff = i32::from(x1[n]) - i32::from(x2[n]);
+        x1[n] = sat16(sum);
+        x2[n] = sat16(diff);
+    });
+}
+
+/// Entropy codes the mid / side quantization indices.
+pub(crate) fn stereo_encode_pred(
+    enc: &mut RangeEncoder,
+    ix: &[[i8; 3]; 2],
+) -> Result<(), OpusError> {
+    let n = 5 * ix[0][2] as usize + ix[1][2] as usize;
+    debug_assert!(n < 25);
+    enc.encode_icdf(n, STEREO_PRED_JOINT_ICDF, 8)?;
+    ix.iter().try_for_each(|ix| {
+        enc.encode_icdf(ix[0] as usize, UNIFORM3_ICDF, 8)?;
+        enc.encode_icdf(ix[1] as usize, UNIFORM5_ICDF, 8)
+    })
+}
+
+/// Entropy codes the mid-only flag.
+pub(crate) fn stereo_encode_mid_only(
+    enc: &mut RangeEncoder,
+    mid_only_flag: bool,
+) -> Result<(), OpusError> {
+    enc.encode_icdf(usize::from(mid_only_flag), STEREO_ONLY_CODE_MID_ICDF, 8)
+}
+
+/// Quantizes the mid / side predictors.
+pub(crate) fn stereo_quant_pred(pred_q13: &mut [i32; 2], ix: &mut [[i8; 3]; 2]) {
+    pred_q13
+        .iter_mut()
+        .zip(ix.iter_mut())
+        .for_each(|(pred, ix)| {
+            // Brute-force search over quantization levels.
+            let mut err_min_q13 = i32::MAX;
+            let mut quant_pred_q13 = 0;
+            'search: for i in 0..STEREO_QUANT_TAB_SIZE - 1 {
+                let low_q13 = i32::from(STEREO_PRED_QUANT_Q13[i]);
+                let step_q13 = smulwb(
+                    i32::from(STEREO_PRED_QUANT_Q13[i + 1]) - low_q13,
+                    ((0.5 / STEREO_QUANT_SUB_STEPS as f64) * 65536.0 + 0.5) as i32,
+                );
+                for j in 0..STEREO_QUANT_SUB_STEPS {
+                    let lvl_q13 = smlabb(low_q13, step_q13, 2 * j + 1);
+                    let err_q13 = (*pred - lvl_q13).abs();
+                    if err_q13 < err_min_q13 {
+                        err_min_q13 = err_q13;
+                        quant_pred_q13 = lvl_q13;
+                        ix[0] = i as i8;
+                        ix[1] = j as i8;
+                    } else {
+                        // Error increasing, so we're past the optimum.
+                        break 'search;
+                    }
+                }
+            }
+            ix[2] = ix[0] / 3;
+            ix[0] -= ix[2] * 3;
+            *pred = quant_pred_q13;
+        });
+
+    // Subtract second from first predictor (helps when actually applying these).
+    pred_q13[0] -= pred_q13[1];
+}
+
+/// Finds the least-squares prediction gain for one signal based on another and quantizes it.
+///
+/// Returns the predictor in Q13 and the ratio of the residual and mid energies in Q14.
+fn stereo_find_predictor(
+    x: &[i16],
+    y: &[i16],
+    mid_res_amp_q0: &mut [i32],
+    smooth_coef_q16: i32,
+) -> (i32, i32) {
+    // Find predictor.
+    let (nrgx, scale1) = sum_sqr_shift(x);
+    let (nrgy, scale2) = sum_sqr_shift(y);
+    let mut scale = i32::max(scale1, scale2);
+    // Make even.
+    scale += scale & 1;
+    let mut nrgy = nrgy >> (scale - scale2);
+    let nrgx = i32::max(nrgx >> (scale - scale1), 1);
+    let corr = x.iter().zip(y.iter()).fold(0_i32, |sum, (&x, &y)| {
+        sum.wrapping_add(smulbb(i32::from(x), i32::from(y)) >> scale)
+    });
+    let pred_q13 = limit(div32_varq(corr, nrgx, 13), -(1 << 14), 1 << 14);
+    let pred2_q10 = smulwb(pred_q13, pred_q13);
+
+    // Faster update for signals with large prediction parameters.
+    let smooth_coef_q16 = i32::max(smooth_coef_q16, pred2_q10.abs());
+
+    // Smoothed mid and residual norms.
+    debug_assert!(smooth_coef_q16 < 32768);
+    let scale = scale >> 1;
+    mid_res_amp_q0[0] = smlawb(
+        mid_res_amp_q0[0],
+        (sqrt_approx(nrgx) << scale) - mid_res_amp_q0[0],
+        smooth_coef_q16,
+    );
+
+    // Residual energy = nrgy - 2 * pred * corr + pred^2 * nrgx.
+    nrgy = nrgy.wrapping_sub(smulwb(corr, pred_q13) << (3 + 1));
+    nrgy = nrgy.wrapping_add(smulwb(nrgx, pred2_q10) << 6);
+    mid_res_amp_q0[1] = smlawb(
+        mid_res_amp_q0[1],
+        (sqrt_approx(nrgy) << scale) - mid_res_amp_q0[1],
+        smooth_coef_q16,
+    );
+
+    // Ratio of smoothed residual and mid norms.
+    let ratio_q14 = div32_varq(mid_res_amp_q0[1], i32::max(mid_res_amp_q0[0], 1), 14);
+    let ratio_q14 = limit(ratio_q14, 0, 32767);
+
+    (pred_q13, ratio_q14)
+}
+
+/// Converts a left / right stereo signal to an adaptive mid / side representation.
+///
+/// Both signals start with two samples of history and contain `frame_length` samples after that.
+/// The left signal becomes the mid signal, the right signal becomes the side signal.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn stereo_lr_to_ms(
+    state: &mut StereoEncoderState,
+    x1: &mut [i16],
+    x2: &mut [i16],
+    ix: &mut [[i8; 3]; 2],
+    mid_only_flag: &mut bool,
+    mid_side_rates_bps: &mut [i32; 2],
+    mut total_rate_bps: i32,
+    prev_speech_act_q8: i32,
+    to_mono: bool,
+    fs_khz: usize,
+    frame_length: usize,
+) {
+    let mut side = [0_i16; MAX_FRAME_LENGTH + 2];
+    let mut lp_mid = [0_i16; MAX_FRAME_LENGTH];
+    let mut hp_mid = [0_i16; MAX_FRAME_LENGTH];
+    let mut lp_side = [0_i16; MAX_FRAME_LENGTH];
+    let mut hp_side = [0_i16; MAX_FRAME_LENGTH];
+    let mid = x1;
+
+    // Convert to basic mid/side signals.
+    (0..frame_length + 2).for_each(|n| {
+        let sum = i32::from(mid[n]) + i32::from(x2[n]);
+        let diff = i32::from(mid[n]) - i32::from(x2[n]);
+        mid[n] = rshift_round(sum, 1) as i16;
+        side[n] = sat16(rshift_round(diff, 1));
+    });
+
+    // Buffering.
+    mid[..2].copy_from_slice(&state.s_mid);
+    side[..2].copy_from_slice(&state.s_side);
+    state
+        .s_mid
+        .copy_from_slice(&mid[frame_length..frame_length + 2]);
+    state
+        .s_side
+        .copy_from_slice(&side[frame_length..frame_length + 2]);
+
+    // LP and HP filter mid signal.
+    let lp_hp_filter = |lp: &mut [i16], hp: &mut [i16], x: &[i16]| {
+        (0..frame_length).for_each(|n| {
+            let sum = rshift_round(
+                i32::from(x[n]) + i32::from(x[n + 2]) + (i32::from(x[n + 1]) << 1),
+                2,
+            );
+            lp[n] = sum as i16;
+            hp[n] = (i32::from(x[n + 1]) - sum) as i16;
+        });
+    };
+    lp_hp_filter(&mut lp_mid, &mut hp_mid, mid);
+
+    // LP and HP filter side signal.
+    lp_hp_filter(&mut lp_side, &mut hp_side, &side);
+
+    // Find energies and predictors.
+    let is_10ms_frame = frame_length == 10 * fs_khz;
+    let smooth_coef_q16 = if is_10ms_frame {
+        ((STEREO_RATIO_SMOOTH_COEF / 2.0) * 65536.0 + 0.5) as i32
+    } else {
+        (STEREO_RATIO_SMOOTH_COEF * 65536.0 + 0.5) as i32
+    };
+    let smooth_coef_q16 = smulwb(
+        smulbb(prev_speech_act_q8, prev_speech_act_q8),
+        smooth_coef_q16,
+    );
+
+    let mut pred_q13 = [0_i32; 2];
+    let (pred, lp_ratio_q14) = stereo_find_predictor(
+        &lp_mid[..frame_length],
+        &lp_side[..frame_length],
+        &mut state.mid_side_amp_q0[0..2],
+        smooth_coef_q16,
+    );
+    pred_q13[0] = pred;
+    let (pred, hp_ratio_q14) = stereo_find_predictor(
+        &hp_mid[..frame_length],
+        &hp_side[..frame_length],
+        &mut state.mid_side_amp_q0[2..4],
+        smooth_coef_q16,
+    );
+    pred_q13[1] = pred;
+
+    // Ratio of the norms of residual and mid signals.
+    let frac_q16 = smlabb(hp_ratio_q14, lp_ratio_q14, 3);
+    let frac_q16 = i32::min(frac_q16, 1 << 16);
+
+    // Determine bitrate distribution between mid and side, and possibly reduce stereo width.
+    // Subtract approximate bitrate for coding stereo parameters.
+    total_rate_bps -= if is_10ms_frame { 1200 } else { 600 };
+    if total_rate_bps < 1 {
+        total_rate_bps = 1;
+    }
+    let min_mid_rate_bps = smlabb(2000, fs_khz as i32, 600);
+    debug_assert!(min_mid_rate_bps < 32767);
+
+    // Default bitrate distribution: 8 parts for Mid and (5+3*frac) parts for Side.
+    // So: mid_rate = ( 8 / ( 13 + 3 * frac ) ) * total_ rate.
+    let frac_3_q16 = 3 * frac_q16;
+    mid_side_rates_bps[0] = div32_varq(total_rate_bps, ((8 + 5) << 16) + frac_3_q16, 16 + 3);
+
+    // If Mid bitrate below minimum, reduce stereo width.
+    let mut width_q14 = if mid_side_rates_bps[0] < min_mid_rate_bps {
+        mid_side_rates_bps[0] = min_mid_rate_bps;
+        mid_side_rates_bps[1] = total_rate_bps - mid_side_rates_bps[0];
+        // width = 4 * ( 2 * side_rate - min_rate ) / ( ( 1 + 3 * frac ) * min_rate ).
+        let width_q14 = div32_varq(
+            (mid_side_rates_bps[1] << 1) - min_mid_rate_bps,
+            smulwb((1 << 16) + frac_3_q16, min_mid_rate_bps),
+            14 + 2,
+        );
+        limit(width_q14, 0, 1 << 14)
+    } else {
+        mid_side_rates_bps[1] = total_rate_bps - mid_side_rates_bps[0];
+        1 << 14
+    };
+
+    // Smoother.
+    state.smth_width_q14 = smlawb(
+        i32::from(state.smth_width_q14),
+        width_q14 - i32::from(state.smth_width_q14),
+        smooth_coef_q16,
+    ) as i16;
+    let smth_width_q14 = i32::from(state.smth_width_q14);
+    let scale_down = |pred_q13: &mut [i32; 2]| {
+        pred_q13[0] = smulbb(smth_width_q14, pred_q13[0]) >> 14;
+        pred_q13[1] = smulbb(smth_width_q14, pred_q13[1]) >> 14;
+    };
+
+    // At very low bitrates or for inputs that are nearly amplitude panned,
+    // switch to panned-mono coding.
+    *mid_only_flag = false;
+    if to_mono {
+        // Last frame before stereo->mono transition; collapse stereo width.
+        width_q14 = 0;
+        pred_q13 = [0; 2];
+        stereo_quant_pred(&mut pred_q13, ix);
+    } else if state.width_prev_q14 == 0
+        && (8 * total_rate_bps < 13 * min_mid_rate_bps
+            || smulwb(frac_q16, smth_width_q14) < (0.05 * 16384.0 + 0.5) as i32)
+    {
+        // Code as panned-mono; previous frame already had zero width.
+        // Scale down and quantize predictors.
+        scale_down(&mut pred_q13);
+        stereo_quant_pred(&mut pred_q13, ix);
+
+        // Collapse stereo width.
+        width_q14 = 0;
+        pred_q13 = [0; 2];
+        mid_side_rates_bps[0] = total_rate_bps;
+        mid_side_rates_bps[1] = 0;
+        *mid_only_flag = true;
+    } else if state.width_prev_q14 != 0
+        && (8 * total_rate_bps < 11 * min_mid_rate_bps
+            || smulwb(frac_q16, smth_width_q14) < (0.02 * 16384.0 + 0.5) as i32)
+    {
+        // Transition to zero-width stereo.
+        // Scale down and quantize predictors.
+        scale_down(&mut pred_q13);
+        stereo_quant_pred(&mut pred_q13, ix);
+
+        // Collapse stereo width.
+        width_q14 = 0;
+        pred_q13 = [0; 2];
+    } else if smth_width_q14 > (0.95 * 16384.0 + 0.5) as i32 {
+        // Full-width stereo coding.
+        stereo_quant_pred(&mut pred_q13, ix);
+        width_q14 = 1 << 14;
+    } else {
+        // Reduced-width stereo coding; scale down and quantize predictors.
+        scale_down(&mut pred_q13);
+        stereo_quant_pred(&mut pred_q13, ix);
+        width_q14 = smth_width_q14;
+    }
+
+    // Make sure to keep on encoding until the tapered output has been transmitted.
+    if *mid_only_flag {
+        state.silent_side_len += (frame_length - STEREO_INTERP_LEN_MS * fs_khz) as i16;
+        if (state.silent_side_len as usize) < LA_SHAPE_MS * fs_khz {
+            *mid_only_flag = false;
+        } else {
+            // Limit to avoid wrapping around.
+            state.silent_side_len = 10000;
+        }
+    } else {
+        state.silent_side_len = 0;
+    }
+
+    if !*mid_only_flag && mid_side_rates_bps[1] < 1 {
+        mid_side_rates_bps[1] = 1;
+        mid_side_rates_bps[0] = i32::max(1, total_rate_bps - mid_side_rates_bps[1]);
+    }
+
+    // Interpolate predictors and subtract prediction from side channel.
+    let mut pred0_q13 = -i32::from(state.pred_prev_q13[0]);
+    let mut pred1_q13 = -i32::from(state.pred_prev_q13[1]);
+    let mut w_q24 = i32::from(state.width_prev_q14) << 10;
+    let interp_len = STEREO_INTERP_LEN_MS * fs_khz;
+    let denom_q16 = (1 << 16) / interp_len as i32;
+    let delta0_q13 = -rshift_round(
+        smulbb(pred_q13[0] - i32::from(state.pred_prev_q13[0]), denom_q16),
+        16,
+    );
+    let delta1_q13 = -rshift_round(
+        smulbb(pred_q13[1] - i32::from(state.pred_prev_q13[1]), denom_q16),
+        16,
+    );
+    let deltaw_q24 = smulwb(width_q14 - i32::from(state.width_prev_q14), denom_q16) << 10;
+
+    (0..frame_length).for_each(|n| {
+        if n < interp_len {
+            pred0_q13 += delta0_q13;
+            pred1_q13 += delta1_q13;
+            w_q24 += deltaw_q24;
+        } else if n == interp_len {
+            pred0_q13 = -pred_q13[0];
+            pred1_q13 = -pred_q13[1];
+            w_q24 = width_q14 << 10;
+        }
+
+        // Q11
+        let sum = (i32::from(mid[n]) + i32::from(mid[n + 2]) + (i32::from(mid[n + 1]) << 1)) << 9;
+        // Q8
+        let sum = smlawb(smulwb(w_q24, i32::from(side[n + 1])), sum, pred0_q13);
+        // Q8
+        let sum = smlawb(sum, i32::from(mid[n + 1]) << 11, pred1_q13);
+        x2[n + 1] = sat16(rshift_round(sum, 8));
+    });
+
+    state.pred_prev_q13[0] = pred_q13[0] as i16;
+    state.pred_prev_q13[1] = pred_q13[1] as i16;
+    state.width_prev_q14 = width_q14 as i16;
+}
@@ -0,0 +1,436 @@
+//! Implements the voice activity detection of the Silk encoder.
+
+use crate::silk::fixed::{
+    add_sat32, lin2log, rshift_round, sat16, smlabb, smlawb, smulbb, smulwb, smulww, sqrt_approx,
+};
+
+/// Number of frequency bands of the VAD.
+pub(crate) const VAD_N_BANDS: usize = 4;
+
+const VAD_INTERNAL_SUBFRAMES_LOG2: usize = 2;
+const VAD_INTERNAL_SUBFRAMES: usize = 1 << VAD_INTERNAL_SUBFRAMES_LOG2;
+/// Must be < 4096.
+const VAD_NOISE_LEVEL_SMOOTH_COEF_Q16: i32 = 1024;
+const VAD_NOISE_LEVELS_BIAS: i32 = 50;
+/// Sigmoid settings.
+const VAD_NEGATIVE_OFFSET_Q5: i32 = 128;
+const VAD_SNR_FACTOR_Q16: i32 = 45000;
+/// Smoothing for SNR measurement.
+const VAD_SNR_SMOOTH_COEF_Q18: i32 = 4096;
+
+/// Weighting factors for tilt measure.
+const TILT_WEIGHTS: &[i32; VAD_N_BANDS] = &[30000, 6000, -12000, -12000];
+
+/// Coefficients for 2-band filter bank based on first-order allpass filters.
+const A_FB1_20: i32 = 5394 << 1;
+const A_FB1_21: i32 = -24290;
+
+const SIGM_LUT_SLOPE_Q10: &[i32; 6] = &[237, 153, 73, 30, 12, 7];
+const SIGM_LUT_POS_Q15: &[i32; 6] = &[16384, 23955, 28861, 31213, 32178, 32548];
+const SIGM_LUT_NEG_Q15: &[i32; 6] = &[16384, 8812, 3906, 1554, 589, 219];
+
+/// The result of the voice activity detection of a frame.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct VadResult {
+    /// Speech activity in Q8.
+    pub(crate) speech_activity_q8: i32,
+    /// Input tilt in Q15.
+    pub(crate) input_tilt_q15: i32,
+    /// Input quality of each band in Q15.
+    pub(crate) input_quality_bands_q15: [i32; VAD_N_BANDS],
+}
+
+/// State of the voice activity detection.
+#[derive(Clone, Debug)]
+pub(crate) struct VadState {
+    /// Analysis filterbank state: 0-8 kHz.
+    ana_state: [i32; 2],
+    /// Analysis filterbank state: 0-4 kHz.
+    ana_state1: [i32; 2],
+    /// Analysis filterbank state: 0-2 kHz.
+    ana_state2: [i32; 2],
+    /// Subframe energies.
+    xnrg_subfr: [i32; VAD_N_BANDS],
+    /// Smoothed energy level in each band.
+    nrg_ratio_smth_q8: [i32; VAD_N_BANDS],
+    /// State of differentiator in the lowest band.
+    hp_state: i16,
+    /// Noise energy level in each band.
+    nl: [i32; VAD_N_BANDS],
+    /// Inverse noise energy level in each band.
+    inv_nl: [i32; VAD_N_BANDS],
+    /// Noise level estimator bias/offset.
+    noise_level_bias: [i32; VAD_N_BANDS],
+    /// Frame counter used in the initial phase.
+    counter: i32,
+}
+
+impl Default for VadState {
+    fn default() -> Self {
+        let mut state = Self {
+            ana_state: [0; 2],
+            ana_state1: [0; 2],
+            ana_state2: [0; 2],
+            xnrg_subfr: [0; VAD_N_BANDS],
+            nrg_ratio_smth_q8: [0; VAD_N_BANDS],
+            hp_state: 0,
+            nl: [0; VAD_N_BANDS],
+            inv_nl: [0; VAD_N_BANDS],
+            noise_level_bias: [0; VAD_N_BANDS],
+            counter: 15,
+        };
+
+        // Initialize array with approx pink noise levels (psd proportional to inverse of frequency).
+        (0..VAD_N_BANDS).for_each(|b| {
+            state.noise_level_bias[b] = i32::max(VAD_NOISE_LEVELS_BIAS / (b as i32 + 1), 1);
+            state.nl[b] = 100 * state.noise_level_bias[b];
+            state.inv_nl[b] = i32::MAX / state.nl[b];
+            // 100 * 256 --> 20 dB SNR
+            state.nrg_ratio_smth_q8[b] = 100 * 256;
+        });
+
+        state
+    }
+}
+
+impl VadState {
+    /// Calculates the speech activity level of a frame of `frame_length` samples.
+    pub(crate) fn speech_activity(
+        &mut self,
+        input: &[i16],
+        frame_length: usize,
+        fs_khz: usize,
+    ) -> VadResult {
+        debug_assert!(frame_length <= 512);
+        debug_assert_eq!(frame_length, 8 * (frame_length >> 3));
+
+        // Filter and Decimate.
+        let decimated_framelength1 = frame_length >> 1;
+        let decimated_framelength2 = frame_length >> 2;
+        let decimated_framelength = frame_length >> 3;
+
+        // Decimate into 4 bands:
+        //     0       L      3L       L              3L                             5L
+        //             -      --       -              --                             --
+        //             8       8       2               4                              4
+        //
+        //     [0-1 kHz| temp. |1-2 kHz|    2-4 kHz    |            4-8 kHz           |
+        //
+        // They're arranged to allow the minimal ( frame_length / 4 ) extra
+        // scratch space during the downsampling process.
+        let mut x_offset = [0_usize; VAD_N_BANDS];
+        x_offset[1] = decimated_framelength + decimated_framelength2;
+        x_offset[2] = x_offset[1] + decimated_framelength;
+        x_offset[3] = x_offset[2] + decimated_framelength2;
+        let mut x = vec![0_i16; x_offset[3] + decimated_framelength1];
+
+        // 0-8 kHz to 0-4 kHz and 4-8 kHz.
+        {
+            let (low, high) = x.split_at_mut(x_offset[3]);
+            ana_filt_bank_1(input, &mut self.ana_state, low, high, frame_length);
+        }
+
+        // 0-4 kHz to 0-2 kHz and 2-4 kHz.
+        {
+            let tmp = x[..decimated_framelength1].to_vec();
+            let (low, high) = x.split_at_mut(x_offset[2]);
+            ana_filt_bank_1(
+                &tmp,
+                &mut self.ana_state1,
+                low,
+                high,
+                decimated_framelength1,
+            );
+        }
+
+        // 0-2 kHz to 0-1 kHz and 1-2 kHz.
+        {
+            let tmp = x[..decimated_framelength2].to_vec();
+            let (low, high) = x.split_at_mut(x_offset[1]);
+            ana_filt_bank_1(
+                &tmp,
+                &mut self.ana_state2,
+                low,
+                high,
+                decimated_framelength2,
+            );
+        }
+
+        // HP filter on lowest band (differentiator).
+        x[decimated_framelength - 1] >>= 1;
+        let hp_state_tmp = x[decimated_framelength - 1];
+        (1..decimated_framelength).rev().for_each(|i| {
+            x[i - 1] >>= 1;
+            x[i] = x[i].wrapping_sub(x[i - 1]);
+        });
+        x[0] = x[0].wrapping_sub(self.hp_state);
+        self.hp_state = hp_state_tmp;
+
+        // Calculate the energy in each band.
+        let mut xnrg = [0_i32; VAD_N_BANDS];
+        (0..VAD_N_BANDS).for_each(|b| {
+            // Find the decimated framelength in the non-uniformly divided bands.
+            let decimated_framelength =
+                frame_length >> usize::min(VAD_N_BANDS - b, VAD_N_BANDS - 1);
+
+            // Split length into subframe lengths.
+            let dec_subframe_length = decimated_framelength >> VAD_INTERNAL_SUBFRAMES_LOG2;
+            let mut dec_subframe_offset = 0;
+
+            // Compute energy per sub-frame, initialize with summed energy of last subframe.
+            xnrg[b] = self.xnrg_subfr[b];
+            let mut sum_squared = 0;
+            (0..VAD_INTERNAL_SUBFRAMES).for_each(|s| {
+                sum_squared = 0;
+                (0..dec_subframe_length).for_each(|i| {
+                    // The energy will be less than dec_subframe_length * ( i16::MIN / 8 ) ^ 2.
+                    // Therefore we can accumulate with no risk of overflow (unless dec_subframe_length > 128).
+                    let x_tmp = i32::from(x[x_offset[b] + i + dec_subframe_offset]) >> 3;
+                    sum_squared = smlabb(sum_squared, x_tmp, x_tmp);
+                });
+
+                // Add/saturate summed energy of current subframe.
+                if s < VAD_INTERNAL_SUBFRAMES - 1 {
+                    xnrg[b] = add_sat32(xnrg[b], sum_squared);
+                } else {
+                    // Look-ahead subframe.
+                    xnrg[b] = add_sat32(xnrg[b], sum_squared >> 1);
+                }
+
+                dec_subframe_offset += dec_subframe_length;
+            });
+            self.xnrg_subfr[b] = sum_squared;
+        });
+
+        // Noise estimation.
+        self.update_noise_levels(&xnrg);
+
+        // Signal-plus-noise to noise ratio estimation.
+        let mut sum_squared = 0;
+        let mut input_tilt = 0;
+        let mut nrg_to_noise_ratio_q8 = [0_i32; VAD_N_BANDS];
+        (0..VAD_N_BANDS).for_each(|b| {
+            let speech_nrg = xnrg[b] - self.nl[b];
+            if speech_nrg > 0 {
+                // Divide, with sufficient resolution.
+                if (xnrg[b] as u32 & 0xFF80_0000) == 0 {
+                    nrg_to_noise_ratio_q8[b] = (xnrg[b] << 8) / (self.nl[b] + 1);
+                } else {
+                    nrg_to_noise_ratio_q8[b] = xnrg[b] / ((self.nl[b] >> 8) + 1);
+                }
+
+                // Convert to log domain.
+                let mut snr_q7 = lin2log(nrg_to_noise_ratio_q8[b]) - 8 * 128;
+
+                // Sum-of-squares.
+                sum_squared = smlabb(sum_squared, snr_q7, snr_q7);
+
+                // Tilt measure.
+                if speech_nrg < (1 << 20) {
+                    // Scale down SNR value for small subband speech energies.
+                    snr_q7 = smulwb(sqrt_approx(speech_nrg) << 6, snr_q7);
+                }
+                input_tilt = smlawb(input_tilt, TILT_WEIGHTS[b], snr_q7);
+            } else {
+                nrg_to_noise_ratio_q8[b] = 256;
+            }
+        });
+
+        // Mean-of-squares.
+        let sum_squared = sum_squared / VAD_N_BANDS as i32;
+
+        // Root-mean-square approximation, scale to dBs.
+        let snr_db_q7 = i32::from((3 * sqrt_approx(sum_squared)) as i16);
+
+        // Speech Probability Estimation.
+        let mut sa_q15 = sigm_q15(smulwb(VAD_SNR_FACTOR_Q16, snr_db_q7) - VAD_NEGATIVE_OFFSET_Q5);
+
+        // Frequency Tilt Measure.
+        let input_tilt_q15 = (sigm_q15(input_tilt) - 16384) << 1;
+
+        // Scale the sigmoid output based on power levels.
+        let mut speech_nrg = 0;
+        (0..VAD_N_BANDS).for_each(|b| {
+            // Accumulate signal-without-noise energies, higher frequency bands have more weight.
+            speech_nrg += (b as i32 + 1) * ((xnrg[b] - self.nl[b]) >> 4);
+        });
+
+        if frame_length == 20 * fs_khz {
+            speech_nrg >>= 1;
+        }
+
+        // Power scaling.
+        if speech_nrg <= 0 {
+            sa_q15 >>= 1;
+        } else if speech_nrg < 16384 {
+            let speech_nrg = sqrt_approx(speech_nrg << 16);
+            sa_q15 = smulwb(32768 + speech_nrg, sa_q15);
+        }
+
+        // Copy the resulting speech activity in Q8.
+        let speech_activity_q8 = i32::min(sa_q15 >> 7, u8::MAX as i32);
+
+        // Energy Level and SNR estimation.
+        let mut smooth_coef_q16 = smulwb(VAD_SNR_SMOOTH_COEF_Q18, smulwb(sa_q15, sa_q15));
+        if frame_length == 10 * fs_khz {
+            smooth_coef_q16 >>= 1;
+        }
+
+        let mut input_quality_bands_q15 = [0_i32; VAD_N_BANDS];
+        (0..VAD_N_BANDS).for_each(|b| {
+            // Compute smoothed energy-to-noise ratio per band.
+            self.nrg_ratio_smth_q8[b] = smlawb(
+                self.nrg_ratio_smth_q8[b],
+                nrg_to_noise_ratio_q8[b] - self.nrg_ratio_smth_q8[b],
+                smooth_coef_q16,
+            );
+
+            // Signal to noise ratio in dB per band.
+            let snr_q7 = 3 * (lin2log(self.nrg_ratio_smth_q8[b]) - 8 * 128);
+
+            // quality = sigmoid( 0.25 * ( SNR_dB - 16 ) );
+            input_quality_bands_q15[b] = sigm_q15((snr_q7 - 16 * 128) >> 4);
+        });
+
+        VadResult {
+            speech_activity_q8,
+            input_tilt_q15,
+            input_quality_bands_q15,
+        }
+    }
+
+    /// Noise level estimation.
+    fn update_noise_levels(&mut self, px: &[i32; VAD_N_BANDS]) {
+        // Initially faster smoothing.
+        let min_coef = if self.counter < 1000 {
+            // 1000 = 20 sec
+            let min_coef = i16::MAX as i32 / ((self.counter >> 4) + 1);
+            self.counter += 1;
+            min_coef
+        } else {
+            0
+        };
+
+        (0..VAD_N_BANDS).for_each(|k| {
+            // Get old noise level estimate for current band.
+            let nl = self.nl[k];
+
+            // Add bias.
+            let nrg = add_sat32(px[k], self.noise_level_bias[k]);
+
+            // Invert energies.
+            let inv_nrg = i32::MAX / nrg;
+
+            // Less update when subband energy is high.
+            let coef = if nrg > nl << 3 {
+                VAD_NOISE_LEVEL_SMOOTH_COEF_Q16 >> 3
+            } else if nrg < nl {
+                VAD_NOISE_LEVEL_SMOOTH_COEF_Q16
+            } else {
+                smulwb(smulww(inv_nrg, nl), VAD_NOISE_LEVEL_SMOOTH_COEF_Q16 << 1)
+            };
+
+            // Initially faster smoothing.
+            let coef = i32::max(coef, min_coef);
+
+            // Smooth inverse energies.
+            self.inv_nl[k] = smlawb(self.inv_nl[k], inv_nrg - self.inv_nl[k], coef);
+
+            // Compute noise level by inverting again and limit noise
+            // levels (guarantee 7 bits of head room).
+            self.nl[k] = i32::min(i32::MAX / self.inv_nl[k], 0x00FF_FFFF);
+        });
+    }
+}
+
+/// Splits a signal into two decimated bands using first-order allpass filters.
+fn ana_filt_bank_1(
+    input: &[i16],
+    s: &mut [i32; 2],
+    out_l: &mut [i16],
+    out_h: &mut [i16],
+    n: usize,
+) {
+    // Internal variables and state are in Q10 format.
+    (0..n >> 1).for_each(|k| {
+        // Convert to Q10.
+        let in32 = i32::from(input[2 * k]) << 10;
+
+        // All-pass section for even input sample.
+        let y = in32.wrapping_sub(s[0]);
+        let x = smlawb(y, y, A_FB1_21);
+        let out_1 = s[0].wrapping_add(x);
+        s[0] = in32.wrapping_add(x);
+
+        // Convert to Q10.
+        let in32 = i32::from(input[2 * k + 1]) << 10;
+
+        // All-pass section for odd input sample, and add to output of previous section.
+        let y = in32.wrapping_sub(s[1]);
+        let x = smulwb(y, A_FB1_20);
+        let out_2 = s[1].wrapping_add(x);
+        s[1] = in32.wrapping_add(x);
+
+        // Add/subtract, convert back to int16 and store to output.
+        out_l[k] = sat16(rshift_round(out_2.wrapping_add(out_1), 11));
+        out_h[k] = sat16(rshift_round(out_2.wrapping_sub(out_1), 11));
+    });
+}
+
+/// Approximates the sigmoid function.
+pub(crate) fn sigm_q15(in_q5: i32) -> i32 {
+    if in_q5 < 0 {
+        // Negative input.
+        let in_q5 = -in_q5;
+        if in_q5 >= 6 * 32 {
+            0
+        } else {
+            // Linear interpolation of look up table.
+            let ind = (in_q5 >> 5) as usize;
+            SIGM_LUT_NEG_Q15[ind] - smulbb(SIGM_LUT_SLOPE_Q10[ind], in_q5 & 0x1F)
+        }
+    } else if in_q5 >= 6 * 32 {
+        32767
+    } else {
+        // Linear interpolation of look up table.
+        let ind = (in_q5 >> 5) as usize;
+        SIGM_LUT_POS_Q15[ind] + smulbb(SIGM_LUT_SLOPE_Q10[ind], in_q5 & 0x1F)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sigm_q15() {
+        assert_eq!(sigm_q15(0), 16384);
+        assert_eq!(sigm_q15(1000), 32767);
+        assert_eq!(sigm_q15(-1000), 0);
+        assert_eq!(sigm_q15(32), 23955);
+        assert_eq!(sigm_q15(-32), 8812);
+        assert_eq!(sigm_q15(16), 16384 + 237 * 16);
+    }
+
+    #[test]
+    fn test_speech_activity() {
+        let mut silence_state = VadState::default();
+        let mut speech_state = VadState::default();
+
+        let silence = vec![0_i16; 320];
+        let tone: Vec<i16> = (0..320)
+            .map(|i| (8000.0 * (i as f32 * 0.15).sin()) as i16)
+            .collect();
+
+        let (silence_result, tone_result) =
+            (0..20).fold((VadResult::default(), VadResult::default()), |_, _| {
+                (
+                    silence_state.speech_activity(&silence, 320, 16),
+                    speech_state.speech_activity(&tone, 320, 16),
+                )
+            });
+
+        assert!(silence_result.speech_activity_q8 < 64);
+        assert!(tone_result.speech_activity_q8 > 200);
+    }
+}